@@ -3,9 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use libc::{ioctl, S_IFBLK, S_IFMT};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::convert::TryInto;
 use std::fs::File;
-use std::os::unix::io::AsRawFd;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::{ioctl_expr, ioctl_io_nr, ioctl_ioc_nr};
@@ -18,6 +21,24 @@ pub enum DiskFileError {
     /// Failed creating a new AsyncIo.
     #[error("Failed creating a new AsyncIo: {0}")]
     NewAsyncIo(#[source] std::io::Error),
+    /// Internal snapshots are not supported by the disk image format.
+    #[error("Internal snapshots are not supported by the disk image format")]
+    SnapshotNotSupported,
+    /// Failed creating an internal snapshot.
+    #[error("Failed creating an internal snapshot: {0}")]
+    CreateSnapshot(#[source] std::io::Error),
+    /// Failed deleting an internal snapshot.
+    #[error("Failed deleting an internal snapshot: {0}")]
+    DeleteSnapshot(#[source] std::io::Error),
+    /// Resizing is not supported by the disk image format.
+    #[error("Resizing is not supported by the disk image format")]
+    ResizeNotSupported,
+    /// Failed resizing the disk image.
+    #[error("Failed resizing the disk image: {0}")]
+    Resize(#[source] std::io::Error),
+    /// Failed getting the backing files of the disk image.
+    #[error("Failed getting the backing files of the disk image: {0}")]
+    BackingFiles(#[source] std::io::Error),
 }
 
 #[derive(Debug)]
@@ -110,6 +131,32 @@ pub trait DiskFile: Send + Sync {
     fn topology(&mut self) -> DiskTopology {
         DiskTopology::default()
     }
+    fn create_snapshot(&mut self, _name: &str) -> DiskFileResult<()> {
+        Err(DiskFileError::SnapshotNotSupported)
+    }
+    fn delete_snapshot(&mut self, _name: &str) -> DiskFileResult<()> {
+        Err(DiskFileError::SnapshotNotSupported)
+    }
+    fn resize(&mut self, _size: u64) -> DiskFileResult<()> {
+        Err(DiskFileError::ResizeNotSupported)
+    }
+    /// Returns the backing files opened along with the image, which are only
+    /// read, along with their path.
+    fn backing_files(&self) -> DiskFileResult<Vec<(PathBuf, File)>> {
+        Ok(Vec::new())
+    }
+}
+
+// Clones the backing files of an image, to be returned by
+// `DiskFile::backing_files()`.
+pub(crate) fn clone_backing_files(
+    files: Vec<(&Path, &File)>,
+) -> DiskFileResult<Vec<(PathBuf, File)>> {
+    files
+        .into_iter()
+        .map(|(path, file)| Ok((path.to_owned(), file.try_clone()?)))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(DiskFileError::BackingFiles)
 }
 
 #[derive(Error, Debug)]
@@ -123,6 +170,12 @@ pub enum AsyncIoError {
     /// Failed synchronizing file.
     #[error("Failed synchronizing file: {0}")]
     Fsync(#[source] std::io::Error),
+    /// Failed punching hole in file.
+    #[error("Failed punching hole in file: {0}")]
+    PunchHole(#[source] std::io::Error),
+    /// Failed writing zeroes to file.
+    #[error("Failed writing zeroes to file: {0}")]
+    WriteZeroes(#[source] std::io::Error),
 }
 
 pub type AsyncIoResult<T> = std::result::Result<T, AsyncIoError>;
@@ -142,5 +195,92 @@ pub trait AsyncIo: Send + Sync {
         user_data: u64,
     ) -> AsyncIoResult<()>;
     fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()>;
+    fn punch_hole(&mut self, offset: libc::off_t, length: u64, user_data: u64)
+        -> AsyncIoResult<()>;
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()>;
     fn complete(&mut self) -> Vec<(u64, i32)>;
+    /// Returns the file descriptor signaling that `reconnect()` must be
+    /// called, for images reached through a connection which can be lost.
+    fn reconnect_fd(&self) -> Option<RawFd> {
+        None
+    }
+    /// Establishes the connection to the image again. The requests fail
+    /// until then.
+    fn reconnect(&mut self) {}
+}
+
+// Alignment of the buffers used for I/O outside of the guest requests,
+// suitable for O_DIRECT.
+const BUFFER_ALIGNMENT: usize = 4096;
+
+pub(crate) struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    pub(crate) fn new(size: usize) -> io::Result<Self> {
+        let layout = Layout::from_size_align(size, BUFFER_ALIGNMENT)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        // Safe because the layout has a non-zero size.
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "Failed allocating aligned buffer",
+            ));
+        }
+        Ok(AlignedBuffer { ptr, layout })
+    }
+
+    pub(crate) fn as_mut_slice(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.layout.size());
+        // Safe because the buffer was allocated with at least `len` bytes.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, len) }
+    }
+}
+
+// Safe because the buffer is owned by the structure, and only accessed through
+// it.
+unsafe impl Send for AlignedBuffer {}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // Safe because the buffer was allocated with this layout.
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+// Waits for the only request in flight on `async_io` to complete, returning
+// its result.
+pub(crate) fn wait_for_completion(async_io: &mut dyn AsyncIo) -> io::Result<i32> {
+    loop {
+        if let Some((_, result)) = async_io.complete().into_iter().next() {
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            return Ok(result);
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd: async_io.notifier().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because pollfd is valid for the duration of the call.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, -1) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        // The event is only used to wake up, the completions are polled.
+        let _ = async_io.notifier().read();
+    }
 }
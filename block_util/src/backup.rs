@@ -0,0 +1,427 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Disk backups are streams of extents, each holding the content of a range
+//! of the disk. A full backup holds the whole disk, the ranges it doesn't
+//! cover being zeroed. An incremental backup holds the ranges written since
+//! the previous backup, which it applies on top of.
+//!
+//! All the fields are little-endian. The stream starts with a header:
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 8    | Magic, `CHBACKUP`                       |
+//! | 8      | 4    | Version, 1                              |
+//! | 12     | 4    | Flags, bit 0 is set for full backups    |
+//! | 16     | 8    | Size of the disk in bytes               |
+//!
+//! Each extent is made of its offset and length on 8 bytes each, followed by
+//! its content. An extent of length 0 ends the stream. The extents aren't
+//! necessarily ordered.
+//!
+//! A backup is written while the guest keeps using the disk. The extents are
+//! copied in order in the background, while a guest write first copies the
+//! extents it overwrites which weren't saved yet. The backup then holds the
+//! content the disk had when it started.
+
+use crate::async_io::{wait_for_completion, AlignedBuffer, AsyncIo};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const BACKUP_MAGIC: &[u8; 8] = b"CHBACKUP";
+const BACKUP_VERSION: u32 = 1;
+const BACKUP_FULL: u32 = 1;
+/// Maximum length of the extents of a backup stream.
+pub const BACKUP_EXTENT_MAX_SIZE: u64 = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupHeader {
+    pub disk_size: u64,
+    pub full: bool,
+}
+
+impl BackupHeader {
+    pub fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(24);
+        buf.extend_from_slice(BACKUP_MAGIC);
+        buf.extend_from_slice(&BACKUP_VERSION.to_le_bytes());
+        let flags = if self.full { BACKUP_FULL } else { 0 };
+        buf.extend_from_slice(&flags.to_le_bytes());
+        buf.extend_from_slice(&self.disk_size.to_le_bytes());
+        writer.write_all(&buf)
+    }
+
+    pub fn read_from(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut buf = [0u8; 24];
+        reader.read_exact(&mut buf)?;
+        if &buf[0..8] != BACKUP_MAGIC
+            || u32::from_le_bytes(buf[8..12].try_into().unwrap()) != BACKUP_VERSION
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a disk backup stream",
+            ));
+        }
+
+        Ok(BackupHeader {
+            full: u32::from_le_bytes(buf[12..16].try_into().unwrap()) & BACKUP_FULL != 0,
+            disk_size: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+        })
+    }
+}
+
+// Reads `buf.len()` bytes at `offset` through `disk`, waiting for the
+// request to complete.
+fn read_disk(disk: &mut dyn AsyncIo, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let iovec = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    disk.read_vectored(offset as libc::off_t, vec![iovec], 0)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    if wait_for_completion(disk)? as usize != buf.len() {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    Ok(())
+}
+
+struct BackupJob {
+    source: Box<dyn AsyncIo>,
+    writer: Box<dyn Write + Send>,
+    full: bool,
+    // Extents left to copy, as lengths indexed by offset.
+    pending: BTreeMap<u64, u64>,
+    buffer: AlignedBuffer,
+}
+
+impl BackupJob {
+    // Copies the extent at `offset` to the stream. Zeroed extents are left
+    // out of full backups.
+    fn copy_extent(&mut self, offset: u64) -> io::Result<()> {
+        let length = match self.pending.remove(&offset) {
+            Some(length) => length,
+            None => return Ok(()),
+        };
+        let buf = self.buffer.as_mut_slice(length as usize);
+        read_disk(self.source.as_mut(), offset, buf)?;
+        if !self.full || buf.iter().any(|b| *b != 0) {
+            self.writer.write_all(&offset.to_le_bytes())?;
+            self.writer.write_all(&length.to_le_bytes())?;
+            self.writer.write_all(buf)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A backup being written, shared by the thread copying the disk and the
+/// handlers saving the extents the guest overwrites.
+pub struct DiskBackup {
+    job: Mutex<BackupJob>,
+    cancelled: AtomicBool,
+}
+
+impl fmt::Debug for DiskBackup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiskBackup")
+            .field("pending", &self.job.lock().unwrap().pending.len())
+            .finish()
+    }
+}
+
+impl DiskBackup {
+    /// Starts a backup of the disk read through `source` to `writer`,
+    /// holding the `ranges` of the disk given as `(offset, length)` pairs.
+    /// The disk is flushed first, so that `source` reads the writes completed
+    /// so far.
+    pub fn new(
+        mut source: Box<dyn AsyncIo>,
+        header: BackupHeader,
+        ranges: &[(u64, u64)],
+        mut writer: Box<dyn Write + Send>,
+    ) -> io::Result<Self> {
+        source
+            .fsync(Some(0))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        wait_for_completion(source.as_mut())?;
+
+        header.write_to(writer.as_mut())?;
+
+        let mut pending = BTreeMap::new();
+        for (offset, length) in ranges {
+            let end = std::cmp::min(offset.saturating_add(*length), header.disk_size);
+            let mut offset = *offset;
+            while offset < end {
+                let count = std::cmp::min(end - offset, BACKUP_EXTENT_MAX_SIZE);
+                pending.insert(offset, count);
+                offset += count;
+            }
+        }
+
+        Ok(DiskBackup {
+            job: Mutex::new(BackupJob {
+                source,
+                writer,
+                full: header.full,
+                pending,
+                buffer: AlignedBuffer::new(BACKUP_EXTENT_MAX_SIZE as usize)?,
+            }),
+            cancelled: AtomicBool::new(false),
+        })
+    }
+
+    /// Stops the backup, the copy returning with an `Interrupted` error.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Copies the extents left to the stream, then ends it.
+    pub fn copy(&self) -> io::Result<()> {
+        loop {
+            if self.is_cancelled() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "Disk backup cancelled",
+                ));
+            }
+
+            let mut job = self.job.lock().unwrap();
+            let offset = match job.pending.keys().next() {
+                Some(offset) => *offset,
+                None => break,
+            };
+            job.copy_extent(offset)?;
+        }
+
+        let mut job = self.job.lock().unwrap();
+        job.writer.write_all(&0u64.to_le_bytes())?;
+        job.writer.write_all(&0u64.to_le_bytes())?;
+        job.writer.flush()
+    }
+
+    /// Copies the extents overlapping the `length` bytes at `offset` which
+    /// weren't saved yet, before the guest overwrites them.
+    pub fn before_write(&self, offset: u64, length: u64) -> io::Result<()> {
+        if length == 0 || self.is_cancelled() {
+            return Ok(());
+        }
+
+        let end = offset.saturating_add(length);
+        let mut job = self.job.lock().unwrap();
+        let extents: Vec<u64> = job
+            .pending
+            .range(offset.saturating_sub(BACKUP_EXTENT_MAX_SIZE - 1)..end)
+            .filter(|(extent_offset, extent_length)| *extent_offset + *extent_length > offset)
+            .map(|(extent_offset, _)| *extent_offset)
+            .collect();
+        for extent_offset in extents {
+            job.copy_extent(extent_offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the extents of a backup stream.
+pub struct BackupReader<R: Read> {
+    reader: R,
+    header: BackupHeader,
+    done: bool,
+}
+
+impl<R: Read> BackupReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let header = BackupHeader::read_from(&mut reader)?;
+        Ok(BackupReader {
+            reader,
+            header,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> BackupHeader {
+        self.header
+    }
+
+    /// Reads the next extent into `buf`, which must hold at least
+    /// `BACKUP_EXTENT_MAX_SIZE` bytes, and returns its offset and length.
+    pub fn next_extent(&mut self, buf: &mut [u8]) -> io::Result<Option<(u64, usize)>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut extent = [0u8; 16];
+        self.reader.read_exact(&mut extent)?;
+        let offset = u64::from_le_bytes(extent[0..8].try_into().unwrap());
+        let length = u64::from_le_bytes(extent[8..16].try_into().unwrap());
+        if length == 0 {
+            self.done = true;
+            return Ok(None);
+        }
+        if length > BACKUP_EXTENT_MAX_SIZE
+            || length > buf.len() as u64
+            || offset
+                .checked_add(length)
+                .map_or(true, |end| end > self.header.disk_size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid backup extent at {} of {} bytes", offset, length),
+            ));
+        }
+
+        self.reader.read_exact(&mut buf[..length as usize])?;
+        Ok(Some((offset, length as usize)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_sync::RawFileSync;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Arc;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Shares the stream written by a backup with the test.
+    #[derive(Clone, Default)]
+    struct Stream(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write_backup(file: &File, header: BackupHeader, ranges: &[(u64, u64)]) -> Vec<u8> {
+        let stream = Stream::default();
+        DiskBackup::new(
+            Box::new(RawFileSync::new(file.as_raw_fd())),
+            header,
+            ranges,
+            Box::new(stream.clone()),
+        )
+        .unwrap()
+        .copy()
+        .unwrap();
+        let stream = stream.0.lock().unwrap().clone();
+        stream
+    }
+
+    #[test]
+    fn test_backup_stream() {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x30_0000).unwrap();
+        file.write_all_at(&[0xaa; 0x1000], 0x10_0000).unwrap();
+        file.write_all_at(&[0x55; 0x200], 0x2f_fe00).unwrap();
+
+        // Full backups leave zeroed chunks out.
+        let header = BackupHeader {
+            disk_size: 0x30_0000,
+            full: true,
+        };
+        let stream = write_backup(&file, header, &[(0, 0x30_0000)]);
+
+        let mut reader = BackupReader::new(Cursor::new(&stream)).unwrap();
+        assert_eq!(reader.header(), header);
+        let mut buf = vec![0u8; BACKUP_EXTENT_MAX_SIZE as usize];
+        assert_eq!(
+            reader.next_extent(&mut buf).unwrap(),
+            Some((0x10_0000, 0x10_0000))
+        );
+        assert!(buf[..0x1000].iter().all(|b| *b == 0xaa));
+        assert!(buf[0x1000..].iter().all(|b| *b == 0));
+        assert_eq!(
+            reader.next_extent(&mut buf).unwrap(),
+            Some((0x20_0000, 0x10_0000))
+        );
+        assert!(buf[0xf_fe00..].iter().all(|b| *b == 0x55));
+        assert_eq!(reader.next_extent(&mut buf).unwrap(), None);
+
+        // Incremental backups hold every range, zeroed or not.
+        let header = BackupHeader {
+            disk_size: 0x30_0000,
+            full: false,
+        };
+        let stream = write_backup(&file, header, &[(0, 0x1000)]);
+
+        let mut reader = BackupReader::new(Cursor::new(&stream)).unwrap();
+        assert_eq!(reader.header(), header);
+        assert_eq!(reader.next_extent(&mut buf).unwrap(), Some((0, 0x1000)));
+        assert!(buf[..0x1000].iter().all(|b| *b == 0));
+        assert_eq!(reader.next_extent(&mut buf).unwrap(), None);
+
+        // Extents are checked against the disk size.
+        let mut stream = Vec::new();
+        BackupHeader {
+            disk_size: 0x1000,
+            full: false,
+        }
+        .write_to(&mut stream)
+        .unwrap();
+        stream.extend_from_slice(&0x800u64.to_le_bytes());
+        stream.extend_from_slice(&0x1000u64.to_le_bytes());
+        stream.extend_from_slice(&[0; 0x1000]);
+        let mut reader = BackupReader::new(Cursor::new(&stream)).unwrap();
+        assert!(reader.next_extent(&mut buf).is_err());
+
+        assert!(BackupReader::new(Cursor::new(&[0u8; 24])).is_err());
+    }
+
+    #[test]
+    fn test_backup_copy_before_write() {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x30_0000).unwrap();
+        file.write_all_at(&[0xaa; 0x30_0000], 0).unwrap();
+
+        let stream = Stream::default();
+        let header = BackupHeader {
+            disk_size: 0x30_0000,
+            full: false,
+        };
+        let backup = DiskBackup::new(
+            Box::new(RawFileSync::new(file.as_raw_fd())),
+            header,
+            &[(0, 0x30_0000)],
+            Box::new(stream.clone()),
+        )
+        .unwrap();
+
+        // The extent overwritten is saved first, and only once.
+        backup.before_write(0x20_0200, 0x200).unwrap();
+        file.write_all_at(&[0x55; 0x200], 0x20_0200).unwrap();
+        backup.before_write(0x20_0000, 0x10).unwrap();
+        file.write_all_at(&[0x55; 0x10], 0x20_0000).unwrap();
+        backup.copy().unwrap();
+        // Writes made once the backup completed don't change it.
+        backup.before_write(0, 0x30_0000).unwrap();
+
+        let stream = stream.0.lock().unwrap().clone();
+        let mut reader = BackupReader::new(Cursor::new(&stream)).unwrap();
+        let mut buf = vec![0u8; BACKUP_EXTENT_MAX_SIZE as usize];
+        for offset in [0x20_0000, 0, 0x10_0000] {
+            assert_eq!(
+                reader.next_extent(&mut buf).unwrap(),
+                Some((offset, 0x10_0000))
+            );
+            assert!(buf.iter().all(|b| *b == 0xaa));
+        }
+        assert_eq!(reader.next_extent(&mut buf).unwrap(), None);
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Mutex;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+/// Number of bytes of the disk tracked by each bit of a dirty bitmap.
+pub const DIRTY_BITMAP_GRANULARITY: u64 = 64 << 10;
+
+const DIRTY_BITMAP_MAGIC: &[u8; 8] = b"CHDIRTY\0";
+const DIRTY_BITMAP_VERSION: u32 = 1;
+const DIRTY_BITMAP_HEADER_SIZE: usize = 32;
+// Set while a VM uses the bitmap. Finding it when opening the file means the
+// VMM didn't exit cleanly, and that the last writes may not have been saved.
+const DIRTY_BITMAP_IN_USE: u32 = 1;
+
+#[derive(Clone, Debug, Default, Versionize)]
+pub struct DirtyBitmapState {
+    pub disk_size: u64,
+    pub words: Vec<u64>,
+}
+
+struct Bits {
+    disk_size: u64,
+    words: Vec<u64>,
+}
+
+impl Bits {
+    fn new(disk_size: u64) -> Self {
+        let nbits = div_round_up(disk_size, DIRTY_BITMAP_GRANULARITY);
+        Bits {
+            disk_size,
+            words: vec![0; div_round_up(nbits, 64) as usize],
+        }
+    }
+
+    // Sets the bits `first` to `last` included.
+    fn set(&mut self, first: u64, last: u64) {
+        let last = std::cmp::min(last, self.words.len() as u64 * 64 - 1);
+        let mut bit = first;
+        while bit <= last {
+            let shift = bit % 64;
+            let count = std::cmp::min(64 - shift, last - bit + 1);
+            let mask = if count == 64 {
+                u64::MAX
+            } else {
+                ((1u64 << count) - 1) << shift
+            };
+            self.words[(bit / 64) as usize] |= mask;
+            bit += count;
+        }
+    }
+
+    fn mark(&mut self, offset: u64, length: u64) {
+        if length == 0 || offset >= self.disk_size {
+            return;
+        }
+        let end = std::cmp::min(offset.saturating_add(length), self.disk_size);
+        self.set(
+            offset / DIRTY_BITMAP_GRANULARITY,
+            (end - 1) / DIRTY_BITMAP_GRANULARITY,
+        );
+    }
+
+    // The area added to the disk is dirty, it isn't part of any backup yet.
+    fn resize(&mut self, disk_size: u64) {
+        let old_size = self.disk_size;
+        let nbits = div_round_up(disk_size, DIRTY_BITMAP_GRANULARITY);
+        self.words.resize(div_round_up(nbits, 64) as usize, 0);
+        self.disk_size = disk_size;
+        if disk_size > old_size {
+            self.mark(old_size, disk_size - old_size);
+        }
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        let nbits = div_round_up(self.disk_size, DIRTY_BITMAP_GRANULARITY);
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for bit in 0..nbits {
+            if self.words[(bit / 64) as usize] & (1 << (bit % 64)) == 0 {
+                continue;
+            }
+            let offset = bit * DIRTY_BITMAP_GRANULARITY;
+            let length = std::cmp::min(DIRTY_BITMAP_GRANULARITY, self.disk_size - offset);
+            match ranges.last_mut() {
+                Some((start, len)) if *start + *len == offset => *len += length,
+                _ => ranges.push((offset, length)),
+            }
+        }
+        ranges
+    }
+}
+
+fn div_round_up(value: u64, divisor: u64) -> u64 {
+    (value + divisor - 1) / divisor
+}
+
+/// Ranges of a disk written since the last checkpoint, each bit covering
+/// `DIRTY_BITMAP_GRANULARITY` bytes. The bitmap can be kept in a file so that
+/// it survives the VMM, in which case it's saved when dropped.
+pub struct DirtyBitmap {
+    bits: Mutex<Bits>,
+    file: Option<File>,
+}
+
+impl fmt::Debug for DirtyBitmap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DirtyBitmap")
+            .field("disk_size", &self.bits.lock().unwrap().disk_size)
+            .finish()
+    }
+}
+
+impl DirtyBitmap {
+    /// Creates a bitmap for a disk of `disk_size` bytes. Nothing was backed
+    /// up yet, the whole disk is dirty.
+    pub fn new(disk_size: u64) -> Self {
+        let mut bits = Bits::new(disk_size);
+        bits.mark(0, disk_size);
+        DirtyBitmap {
+            bits: Mutex::new(bits),
+            file: None,
+        }
+    }
+
+    /// Opens the bitmap saved in the file at `path`, which is created if it
+    /// doesn't exist yet. The file is flagged as used until the bitmap is
+    /// dropped, a bitmap which wasn't saved cleanly is entirely dirty.
+    pub fn open(path: &Path, disk_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        let bits = if file.metadata()?.len() == 0 {
+            let mut bits = Bits::new(disk_size);
+            bits.mark(0, disk_size);
+            bits
+        } else {
+            let mut header = [0u8; DIRTY_BITMAP_HEADER_SIZE];
+            file.read_exact_at(&mut header, 0)?;
+            if &header[0..8] != DIRTY_BITMAP_MAGIC
+                || u32::from_le_bytes(header[8..12].try_into().unwrap()) != DIRTY_BITMAP_VERSION
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a dirty bitmap", path.display()),
+                ));
+            }
+            let flags = u32::from_le_bytes(header[12..16].try_into().unwrap());
+            let granularity = u64::from_le_bytes(header[16..24].try_into().unwrap());
+            if granularity != DIRTY_BITMAP_GRANULARITY {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unsupported dirty bitmap granularity {}", granularity),
+                ));
+            }
+
+            let mut bits = Bits::new(u64::from_le_bytes(header[24..32].try_into().unwrap()));
+            let mut words = vec![0u8; bits.words.len() * 8];
+            file.read_exact_at(&mut words, DIRTY_BITMAP_HEADER_SIZE as u64)?;
+            for (word, bytes) in bits.words.iter_mut().zip(words.chunks_exact(8)) {
+                *word = u64::from_le_bytes(bytes.try_into().unwrap());
+            }
+            if flags & DIRTY_BITMAP_IN_USE != 0 {
+                warn!(
+                    "Dirty bitmap {} wasn't saved cleanly, the whole disk is considered dirty",
+                    path.display()
+                );
+                bits.mark(0, bits.disk_size);
+            }
+            bits.resize(disk_size);
+            bits
+        };
+
+        let dirty_bitmap = DirtyBitmap {
+            bits: Mutex::new(bits),
+            file: Some(file),
+        };
+        dirty_bitmap.save(true)?;
+        Ok(dirty_bitmap)
+    }
+
+    /// Marks the `length` bytes at `offset` as written.
+    pub fn mark(&self, offset: u64, length: u64) {
+        self.bits.lock().unwrap().mark(offset, length)
+    }
+
+    /// Tracks a disk grown or shrunk to `disk_size` bytes.
+    pub fn resize(&self, disk_size: u64) {
+        self.bits.lock().unwrap().resize(disk_size)
+    }
+
+    /// Returns the dirty ranges as `(offset, length)` pairs, and starts
+    /// tracking the writes from this point on.
+    pub fn checkpoint(&self) -> Vec<(u64, u64)> {
+        let mut bits = self.bits.lock().unwrap();
+        let ranges = bits.ranges();
+        bits.words.iter_mut().for_each(|word| *word = 0);
+        ranges
+    }
+
+    /// Marks `ranges` as dirty again, when the backup which went through the
+    /// last checkpoint failed.
+    pub fn restore_ranges(&self, ranges: &[(u64, u64)]) {
+        let mut bits = self.bits.lock().unwrap();
+        for (offset, length) in ranges {
+            bits.mark(*offset, *length);
+        }
+    }
+
+    pub fn state(&self) -> DirtyBitmapState {
+        let bits = self.bits.lock().unwrap();
+        DirtyBitmapState {
+            disk_size: bits.disk_size,
+            words: bits.words.clone(),
+        }
+    }
+
+    /// Adds the ranges dirty in `state` to the bitmap. The writes tracked
+    /// since the state was saved are kept.
+    pub fn merge_state(&self, state: &DirtyBitmapState) {
+        let mut bits = self.bits.lock().unwrap();
+        if state.disk_size > bits.disk_size {
+            let disk_size = state.disk_size;
+            bits.resize(disk_size);
+        }
+        for (word, state_word) in bits.words.iter_mut().zip(state.words.iter()) {
+            *word |= state_word;
+        }
+    }
+
+    /// Writes the bitmap to its file, if it has one.
+    pub fn sync(&self) -> io::Result<()> {
+        self.save(true)
+    }
+
+    fn save(&self, in_use: bool) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let bits = self.bits.lock().unwrap();
+        let mut buf = Vec::with_capacity(DIRTY_BITMAP_HEADER_SIZE + bits.words.len() * 8);
+        buf.extend_from_slice(DIRTY_BITMAP_MAGIC);
+        buf.extend_from_slice(&DIRTY_BITMAP_VERSION.to_le_bytes());
+        let flags = if in_use { DIRTY_BITMAP_IN_USE } else { 0 };
+        buf.extend_from_slice(&flags.to_le_bytes());
+        buf.extend_from_slice(&DIRTY_BITMAP_GRANULARITY.to_le_bytes());
+        buf.extend_from_slice(&bits.disk_size.to_le_bytes());
+        for word in bits.words.iter() {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+
+        file.write_all_at(&buf, 0)?;
+        file.set_len(buf.len() as u64)?;
+        file.sync_data()
+    }
+}
+
+impl Drop for DirtyBitmap {
+    fn drop(&mut self) {
+        if let Err(e) = self.save(false) {
+            error!("Failed saving dirty bitmap: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const G: u64 = DIRTY_BITMAP_GRANULARITY;
+
+    #[test]
+    fn test_dirty_bitmap_ranges() {
+        let bitmap = DirtyBitmap::new(100 * G + 512);
+        assert_eq!(bitmap.checkpoint(), vec![(0, 100 * G + 512)]);
+        assert!(bitmap.checkpoint().is_empty());
+
+        bitmap.mark(G + 1, 1);
+        bitmap.mark(2 * G, G);
+        bitmap.mark(63 * G, 2 * G);
+        bitmap.mark(100 * G, G);
+        bitmap.mark(200 * G, G);
+        assert_eq!(
+            bitmap.checkpoint(),
+            vec![(G, 2 * G), (63 * G, 2 * G), (100 * G, 512)]
+        );
+
+        bitmap.restore_ranges(&[(3 * G, 70 * G)]);
+        assert_eq!(bitmap.checkpoint(), vec![(3 * G, 70 * G)]);
+    }
+
+    #[test]
+    fn test_dirty_bitmap_resize() {
+        let bitmap = DirtyBitmap::new(G);
+        bitmap.checkpoint();
+
+        bitmap.resize(130 * G);
+        assert_eq!(bitmap.checkpoint(), vec![(G, 129 * G)]);
+    }
+
+    #[test]
+    fn test_dirty_bitmap_state() {
+        let bitmap = DirtyBitmap::new(4 * G);
+        bitmap.checkpoint();
+        bitmap.mark(0, 1);
+        let state = bitmap.state();
+
+        let restored = DirtyBitmap::new(4 * G);
+        restored.checkpoint();
+        restored.mark(3 * G, 1);
+        restored.merge_state(&state);
+        assert_eq!(restored.checkpoint(), vec![(0, G), (3 * G, G)]);
+    }
+
+    #[test]
+    fn test_dirty_bitmap_file() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_path_buf();
+
+        // New bitmaps are dirty.
+        let bitmap = DirtyBitmap::open(&path, 8 * G).unwrap();
+        assert_eq!(bitmap.checkpoint(), vec![(0, 8 * G)]);
+        bitmap.mark(5 * G, 1);
+        drop(bitmap);
+
+        // The bitmap is kept across VMM runs, and follows the disk size.
+        let bitmap = DirtyBitmap::open(&path, 10 * G).unwrap();
+        assert_eq!(bitmap.checkpoint(), vec![(5 * G, G), (8 * G, 2 * G)]);
+        bitmap.sync().unwrap();
+
+        // A bitmap which wasn't saved cleanly can't be trusted.
+        std::mem::forget(bitmap);
+        let bitmap = DirtyBitmap::open(&path, 10 * G).unwrap();
+        assert_eq!(bitmap.checkpoint(), vec![(0, 10 * G)]);
+    }
+}
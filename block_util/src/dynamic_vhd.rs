@@ -0,0 +1,857 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::vhd::VhdFooter;
+use qcow::{BackingFilePolicy, RawFile};
+use std::cmp;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+const SECTOR_SIZE: u64 = 512;
+const FOOTER_SIZE: u64 = 512;
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+
+// "conectix"
+const VHD_COOKIE: u64 = 0x636f_6e65_6374_6978;
+// "cxsparse"
+const DYNAMIC_HEADER_COOKIE: u64 = 0x6378_7370_6172_7365;
+
+const DISK_TYPE_FIXED: u32 = 2;
+const DISK_TYPE_DYNAMIC: u32 = 3;
+const DISK_TYPE_DIFFERENCING: u32 = 4;
+
+const BAT_ENTRY_UNALLOCATED: u32 = 0xffff_ffff;
+// Limits avoiding huge allocations when opening corrupt images. 256 MiB blocks need 64 KiB
+// bitmaps, 16M entries cover the 2040 GiB maximum VHD size with 128 KiB blocks.
+const MAX_BLOCK_SIZE: u64 = 256 << 20;
+const MAX_TABLE_ENTRIES: u32 = 1 << 24;
+
+const PARENT_UNIQUE_ID_OFFSET: usize = 40;
+const PARENT_UNICODE_NAME_OFFSET: usize = 64;
+const PARENT_UNICODE_NAME_SIZE: usize = 512;
+const PARENT_LOCATORS_OFFSET: usize = 576;
+const PARENT_LOCATOR_ENTRY_SIZE: usize = 24;
+const PARENT_LOCATOR_COUNT: usize = 8;
+// Relative and absolute Windows paths, stored as UTF-16LE.
+const PLATFORM_CODE_W2RU: u32 = 0x5732_7275;
+const PLATFORM_CODE_W2KU: u32 = 0x5732_6b75;
+// Mac OS X file URL, stored as UTF-8.
+const PLATFORM_CODE_MACX: u32 = 0x4d61_6358;
+const MAX_PARENT_LOCATOR_SIZE: u32 = 4096;
+
+// Maximum length of a chain of differencing images.
+const MAX_NESTING_DEPTH: u32 = 10;
+
+fn invalid_image(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn bitmap_test(bitmap: &[u8], sector: u64) -> bool {
+    bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) != 0
+}
+
+fn bitmap_set(bitmap: &mut [u8], sector: u64) {
+    bitmap[(sector / 8) as usize] |= 0x80 >> (sector % 8);
+}
+
+fn decode_utf16(data: &[u8], big_endian: bool) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| {
+            if big_endian {
+                u16::from_be_bytes([c[0], c[1]])
+            } else {
+                u16::from_le_bytes([c[0], c[1]])
+            }
+        })
+        .take_while(|u| *u != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Read-only parent of a differencing VHD. Sectors that aren't present in the image are read from
+/// it.
+enum ParentDisk {
+    Fixed { file: RawFile, size: u64 },
+    Dynamic(Box<DynamicVhd>),
+}
+
+impl ParentDisk {
+    // Opens the image at `path` read-only and returns it along with its unique identifier.
+    // `depth` is the position of the image in the chain of differencing images.
+    fn open(path: &Path, depth: u32) -> io::Result<(ParentDisk, u128)> {
+        if depth > MAX_NESTING_DEPTH {
+            return Err(invalid_image(format!(
+                "More than {} nested differencing VHDs",
+                MAX_NESTING_DEPTH
+            )));
+        }
+
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let footer = VhdFooter::new(&mut file)?;
+        if footer.cookie() != VHD_COOKIE {
+            return Err(invalid_image(format!(
+                "Parent {} is not a VHD",
+                path.display()
+            )));
+        }
+
+        let parent = match footer.disk_type() {
+            DISK_TYPE_FIXED => ParentDisk::Fixed {
+                file: RawFile::new(file, false),
+                size: footer.current_size(),
+            },
+            DISK_TYPE_DYNAMIC | DISK_TYPE_DIFFERENCING => ParentDisk::Dynamic(Box::new(
+                DynamicVhd::open(file, false, &BackingFilePolicy::Header, depth)?,
+            )),
+            t => {
+                return Err(invalid_image(format!(
+                    "Parent {} has unsupported VHD disk type {}",
+                    path.display(),
+                    t
+                )))
+            }
+        };
+
+        Ok((parent, footer.unique_id()))
+    }
+
+    // Reads at `offset` of the virtual disk, the part of `buf` beyond its end is filled with
+    // zeroes.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            ParentDisk::Fixed { file, size } => {
+                let len = cmp::min(buf.len() as u64, size.saturating_sub(offset)) as usize;
+                if len > 0 {
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.read_exact(&mut buf[..len])?;
+                }
+                buf[len..].fill(0);
+                Ok(())
+            }
+            ParentDisk::Dynamic(vhd) => vhd.read_at(offset, buf),
+        }
+    }
+}
+
+enum WriteSource<'a> {
+    Data(&'a [u8]),
+    Zeroes,
+    Hole,
+}
+
+/// Dynamic or differencing VHD image.
+///
+/// Space is allocated block by block, the block allocation table (BAT) giving the location of
+/// each allocated block in the file. Blocks are made of a sector bitmap followed by the data. For
+/// differencing images the bitmap tells which sectors are present in the image, the other ones
+/// being read from the parent image.
+pub struct DynamicVhd {
+    file: RawFile,
+    // Copy of the footer, written again at the end of the file each time a block is allocated.
+    footer: Vec<u8>,
+    // Offset of the footer, which is where the next block gets allocated.
+    footer_offset: u64,
+    virtual_size: u64,
+    block_size: u64,
+    bitmap_size: u64,
+    table_offset: u64,
+    bat: Vec<u32>,
+    parent: Option<ParentDisk>,
+    parent_path: Option<PathBuf>,
+    current_offset: u64,
+}
+
+impl DynamicVhd {
+    /// Opens a dynamic or differencing VHD. The parent of a differencing image is opened
+    /// according to `backing_file_policy`, using the parent locators of the image for
+    /// `BackingFilePolicy::Header`.
+    pub fn new(
+        file: File,
+        direct_io: bool,
+        backing_file_policy: &BackingFilePolicy,
+    ) -> io::Result<DynamicVhd> {
+        Self::open(file, direct_io, backing_file_policy, 0)
+    }
+
+    fn open(
+        mut file: File,
+        direct_io: bool,
+        backing_file_policy: &BackingFilePolicy,
+        depth: u32,
+    ) -> io::Result<DynamicVhd> {
+        let vhd_footer = VhdFooter::new(&mut file)?;
+        if vhd_footer.cookie() != VHD_COOKIE
+            || (vhd_footer.disk_type() != DISK_TYPE_DYNAMIC
+                && vhd_footer.disk_type() != DISK_TYPE_DIFFERENCING)
+        {
+            return Err(invalid_image(format!(
+                "Not a dynamic VHD (disk type {})",
+                vhd_footer.disk_type()
+            )));
+        }
+
+        let mut file = RawFile::new(file, direct_io);
+        let footer_offset = file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        if footer_offset % SECTOR_SIZE != 0 {
+            return Err(invalid_image(format!(
+                "VHD footer at unaligned offset {}",
+                footer_offset
+            )));
+        }
+        let mut footer = vec![0u8; FOOTER_SIZE as usize];
+        file.read_exact(&mut footer)?;
+
+        let mut header = vec![0u8; DYNAMIC_HEADER_SIZE];
+        file.seek(SeekFrom::Start(vhd_footer.data_offset()))?;
+        file.read_exact(&mut header)?;
+        if be_u64(&header, 0) != DYNAMIC_HEADER_COOKIE {
+            return Err(invalid_image(
+                "Invalid VHD dynamic header cookie".to_string(),
+            ));
+        }
+
+        let table_offset = be_u64(&header, 16);
+        let max_table_entries = be_u32(&header, 28);
+        let block_size = be_u32(&header, 32) as u64;
+        if !block_size.is_power_of_two() || !(SECTOR_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(invalid_image(format!(
+                "Invalid VHD block size {}",
+                block_size
+            )));
+        }
+
+        let virtual_size = vhd_footer.current_size();
+        let nb_blocks = (virtual_size + block_size - 1) / block_size;
+        if max_table_entries > MAX_TABLE_ENTRIES || (max_table_entries as u64) < nb_blocks {
+            return Err(invalid_image(format!(
+                "Invalid VHD block allocation table size {}",
+                max_table_entries
+            )));
+        }
+
+        let mut table = vec![0u8; max_table_entries as usize * 4];
+        file.seek(SeekFrom::Start(table_offset))?;
+        file.read_exact(&mut table)?;
+        let bat = table
+            .chunks_exact(4)
+            .map(|e| u32::from_be_bytes(e.try_into().unwrap()))
+            .collect();
+
+        // One bit per sector, padded to a sector boundary.
+        let bitmap_bytes = block_size / SECTOR_SIZE / 8;
+        let bitmap_size = (bitmap_bytes + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+
+        let mut vhd = DynamicVhd {
+            file,
+            footer,
+            footer_offset,
+            virtual_size,
+            block_size,
+            bitmap_size,
+            table_offset,
+            bat,
+            parent: None,
+            parent_path: None,
+            current_offset: 0,
+        };
+
+        if vhd_footer.disk_type() == DISK_TYPE_DIFFERENCING {
+            let parent_path = match backing_file_policy {
+                BackingFilePolicy::Forbid => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "Differencing VHDs require backing files to be allowed",
+                    ))
+                }
+                BackingFilePolicy::Header => vhd.find_parent(&header)?,
+                BackingFilePolicy::Override(path) => path.clone(),
+            };
+
+            let (parent, parent_unique_id) = ParentDisk::open(&parent_path, depth + 1)?;
+            let expected_unique_id = u128::from_be_bytes(
+                header[PARENT_UNIQUE_ID_OFFSET..PARENT_UNIQUE_ID_OFFSET + 16]
+                    .try_into()
+                    .unwrap(),
+            );
+            if parent_unique_id != expected_unique_id {
+                return Err(invalid_image(format!(
+                    "Parent {} doesn't match the identifier recorded in the differencing VHD",
+                    parent_path.display()
+                )));
+            }
+            vhd.parent = Some(parent);
+            vhd.parent_path = Some(parent_path);
+        }
+
+        Ok(vhd)
+    }
+
+    /// Returns the size of the virtual disk.
+    pub fn virtual_disk_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    /// Returns the path of the parent opened along with a differencing image.
+    pub fn parent_path(&self) -> Option<&Path> {
+        self.parent_path.as_deref()
+    }
+
+    /// Returns the chain of parents opened along with a differencing image, from its parent to
+    /// the last one, along with their path.
+    pub fn parent_files(&self) -> Vec<(&Path, &File)> {
+        let mut files = Vec::new();
+        let mut vhd = self;
+        while let (Some(path), Some(parent)) = (&vhd.parent_path, &vhd.parent) {
+            match parent {
+                ParentDisk::Fixed { file, .. } => {
+                    files.push((path.as_path(), file.file()));
+                    break;
+                }
+                ParentDisk::Dynamic(parent_vhd) => {
+                    files.push((path.as_path(), parent_vhd.file.file()));
+                    vhd = parent_vhd;
+                }
+            }
+        }
+        files
+    }
+
+    // Looks for the parent of a differencing image, trying the paths of the parent locators and
+    // then the parent name recorded in the header. Relative paths are resolved against the
+    // directory holding the image rather than the current directory.
+    fn find_parent(&mut self, header: &[u8]) -> io::Result<PathBuf> {
+        let image_dir = std::fs::read_link(format!("/proc/self/fd/{}", self.file.as_raw_fd()))
+            .ok()
+            .and_then(|p| p.parent().map(Path::to_path_buf))
+            .unwrap_or_default();
+
+        let mut candidates = Vec::new();
+        for code in [PLATFORM_CODE_W2RU, PLATFORM_CODE_W2KU, PLATFORM_CODE_MACX] {
+            for i in 0..PARENT_LOCATOR_COUNT {
+                let entry = &header[PARENT_LOCATORS_OFFSET + i * PARENT_LOCATOR_ENTRY_SIZE..];
+                if be_u32(entry, 0) != code {
+                    continue;
+                }
+
+                let data_length = be_u32(entry, 8);
+                if data_length == 0 || data_length > MAX_PARENT_LOCATOR_SIZE {
+                    continue;
+                }
+                let mut data = vec![0u8; data_length as usize];
+                self.file.seek(SeekFrom::Start(be_u64(entry, 16)))?;
+                self.file.read_exact(&mut data)?;
+
+                let path = if code == PLATFORM_CODE_MACX {
+                    let url = String::from_utf8_lossy(&data);
+                    let url = url.trim_end_matches('\0');
+                    url.strip_prefix("file://").unwrap_or(url).to_string()
+                } else {
+                    decode_utf16(&data, false).replace('\\', "/")
+                };
+                candidates.push(image_dir.join(path));
+            }
+        }
+
+        let name = decode_utf16(
+            &header
+                [PARENT_UNICODE_NAME_OFFSET..PARENT_UNICODE_NAME_OFFSET + PARENT_UNICODE_NAME_SIZE],
+            true,
+        );
+        if !name.is_empty() {
+            candidates.push(image_dir.join(name));
+        }
+
+        candidates
+            .iter()
+            .find(|path| path.is_file())
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "Can't find the parent of differencing VHD in {:?}",
+                        candidates
+                    ),
+                )
+            })
+    }
+
+    // Offset in the file of the data of `block`, if it is allocated.
+    fn block_data_offset(&self, block: usize) -> Option<u64> {
+        match self.bat[block] {
+            BAT_ENTRY_UNALLOCATED => None,
+            entry => Some(entry as u64 * SECTOR_SIZE + self.bitmap_size),
+        }
+    }
+
+    fn read_bitmap(&mut self, block: usize) -> io::Result<Vec<u8>> {
+        let mut bitmap = vec![0u8; self.bitmap_size as usize];
+        self.file
+            .seek(SeekFrom::Start(self.bat[block] as u64 * SECTOR_SIZE))?;
+        self.file.read_exact(&mut bitmap)?;
+        Ok(bitmap)
+    }
+
+    fn write_bitmap(&mut self, block: usize, bitmap: &[u8]) -> io::Result<()> {
+        self.file
+            .seek(SeekFrom::Start(self.bat[block] as u64 * SECTOR_SIZE))?;
+        self.file.write_all(bitmap)
+    }
+
+    fn read_file(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn read_parent(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self.parent.as_mut() {
+            Some(parent) => parent.read_at(offset, buf),
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    // Reads at `offset` of the virtual disk, the part of `buf` beyond its end is filled with
+    // zeroes.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let len = cmp::min(buf.len() as u64, self.virtual_size.saturating_sub(offset)) as usize;
+        let (data, tail) = buf.split_at_mut(len);
+        tail.fill(0);
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let block = (pos / self.block_size) as usize;
+            let in_block = pos % self.block_size;
+            let count = cmp::min((len - done) as u64, self.block_size - in_block);
+            let chunk = &mut data[done..done + count as usize];
+
+            match self.block_data_offset(block) {
+                None => self.read_parent(pos, chunk)?,
+                Some(data_offset) if self.parent.is_none() => {
+                    self.read_file(data_offset + in_block, chunk)?
+                }
+                Some(data_offset) => {
+                    // Read runs of sectors from the image or the parent, depending on whether
+                    // they have been written to the image.
+                    let bitmap = self.read_bitmap(block)?;
+                    let mut start = 0;
+                    while start < count {
+                        let present = bitmap_test(&bitmap, (in_block + start) / SECTOR_SIZE);
+                        let mut end = start;
+                        while end < count
+                            && bitmap_test(&bitmap, (in_block + end) / SECTOR_SIZE) == present
+                        {
+                            end = cmp::min(
+                                count,
+                                ((in_block + end) / SECTOR_SIZE + 1) * SECTOR_SIZE - in_block,
+                            );
+                        }
+
+                        let part = &mut chunk[start as usize..end as usize];
+                        if present {
+                            self.read_file(data_offset + in_block + start, part)?;
+                        } else {
+                            self.read_parent(pos + start, part)?;
+                        }
+                        start = end;
+                    }
+                }
+            }
+
+            done += count as usize;
+        }
+
+        Ok(())
+    }
+
+    // Appends a new block where the footer is, and moves the footer after it. Returns the offset
+    // of the data of the block.
+    fn allocate_block(&mut self, block: usize) -> io::Result<u64> {
+        let block_offset = self.footer_offset;
+        let entry: u32 = (block_offset / SECTOR_SIZE)
+            .try_into()
+            .ok()
+            .filter(|e| *e != BAT_ENTRY_UNALLOCATED)
+            .ok_or_else(|| invalid_image("VHD file is too large".to_string()))?;
+
+        // Write the new footer first, the data of the block is left sparse.
+        let new_footer_offset = block_offset + self.bitmap_size + self.block_size;
+        self.file.seek(SeekFrom::Start(new_footer_offset))?;
+        self.file.write_all(&self.footer)?;
+
+        // The bitmap overwrites the previous footer. Only differencing images need to track which
+        // sectors have been written, all the sectors of a dynamic image are present.
+        let fill = if self.parent.is_some() { 0 } else { 0xff };
+        self.file.seek(SeekFrom::Start(block_offset))?;
+        self.file
+            .write_all(&vec![fill; self.bitmap_size as usize])?;
+
+        self.file
+            .seek(SeekFrom::Start(self.table_offset + block as u64 * 4))?;
+        self.file.write_all(&entry.to_be_bytes())?;
+        self.bat[block] = entry;
+        self.footer_offset = new_footer_offset;
+
+        Ok(block_offset + self.bitmap_size)
+    }
+
+    // Copies `sector` of `block` from the parent if it isn't present in the image yet.
+    fn copy_up_sector(
+        &mut self,
+        block: usize,
+        data_offset: u64,
+        bitmap: &mut [u8],
+        sector: u64,
+    ) -> io::Result<()> {
+        if bitmap_test(bitmap, sector) {
+            return Ok(());
+        }
+
+        let mut data = vec![0u8; SECTOR_SIZE as usize];
+        self.read_parent(
+            block as u64 * self.block_size + sector * SECTOR_SIZE,
+            &mut data,
+        )?;
+        self.file
+            .seek(SeekFrom::Start(data_offset + sector * SECTOR_SIZE))?;
+        self.file.write_all(&data)?;
+        bitmap_set(bitmap, sector);
+
+        Ok(())
+    }
+
+    fn write_range(&mut self, offset: u64, length: u64, source: WriteSource) -> io::Result<()> {
+        if offset
+            .checked_add(length)
+            .map_or(true, |end| end > self.virtual_size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Write beyond the end of the VHD",
+            ));
+        }
+
+        let mut done = 0;
+        while done < length {
+            let pos = offset + done;
+            let block = (pos / self.block_size) as usize;
+            let in_block = pos % self.block_size;
+            let count = cmp::min(length - done, self.block_size - in_block);
+
+            let data_offset = match self.block_data_offset(block) {
+                Some(data_offset) => data_offset,
+                // Unallocated blocks of images without a parent already read as zeroes.
+                None if self.parent.is_none() && !matches!(source, WriteSource::Data(_)) => {
+                    done += count;
+                    continue;
+                }
+                None => self.allocate_block(block)?,
+            };
+
+            // The sectors partially covered by the write keep the rest of their content from the
+            // parent.
+            let mut bitmap = None;
+            if self.parent.is_some() {
+                let mut b = self.read_bitmap(block)?;
+                let first_sector = in_block / SECTOR_SIZE;
+                let last_sector = (in_block + count - 1) / SECTOR_SIZE;
+                if in_block % SECTOR_SIZE != 0 {
+                    self.copy_up_sector(block, data_offset, &mut b, first_sector)?;
+                }
+                if (in_block + count) % SECTOR_SIZE != 0 {
+                    self.copy_up_sector(block, data_offset, &mut b, last_sector)?;
+                }
+                bitmap = Some((b, first_sector..=last_sector));
+            }
+
+            let file_offset = data_offset + in_block;
+            match source {
+                WriteSource::Data(buf) => {
+                    self.file.seek(SeekFrom::Start(file_offset))?;
+                    self.file
+                        .write_all(&buf[done as usize..(done + count) as usize])?;
+                }
+                WriteSource::Zeroes => {
+                    self.file.write_all_zeroes_at(file_offset, count as usize)?
+                }
+                WriteSource::Hole => self.file.punch_hole(file_offset, count)?,
+            }
+
+            if let Some((mut bitmap, sectors)) = bitmap {
+                for sector in sectors {
+                    bitmap_set(&mut bitmap, sector);
+                }
+                self.write_bitmap(block, &bitmap)?;
+            }
+
+            done += count;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for DynamicVhd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.virtual_size.saturating_sub(self.current_offset),
+        ) as usize;
+        self.read_at(self.current_offset, &mut buf[..len])?;
+        self.current_offset += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for DynamicVhd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_range(
+            self.current_offset,
+            buf.len() as u64,
+            WriteSource::Data(buf),
+        )?;
+        self.current_offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for DynamicVhd {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(off) => off as i128,
+            SeekFrom::End(off) => self.virtual_size as i128 + off as i128,
+            SeekFrom::Current(off) => self.current_offset as i128 + off as i128,
+        };
+
+        if new_offset < 0 || new_offset > self.virtual_size as i128 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek beyond the end of the VHD",
+            ));
+        }
+
+        self.current_offset = new_offset as u64;
+        Ok(self.current_offset)
+    }
+}
+
+impl WriteZeroesAt for DynamicVhd {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        self.write_range(offset, length as u64, WriteSource::Zeroes)?;
+        Ok(length)
+    }
+}
+
+impl PunchHole for DynamicVhd {
+    // VHD has no way to release a block once allocated, the data is punched out of the file
+    // instead.
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.write_range(offset, length, WriteSource::Hole)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhd::is_dynamic_vhd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const BLOCK_SIZE: u32 = 0x1000;
+    const DISK_SIZE: u64 = 0x4000;
+    const TABLE_OFFSET: u64 = 1536;
+    const LOCATOR_OFFSET: u64 = 2048;
+
+    // Writes an empty dynamic VHD to `file`, or a differencing one when given the name and unique
+    // identifier of its parent, which is referenced through a relative parent locator.
+    fn write_vhd(file: &mut File, unique_id: u128, parent: Option<(&str, u128)>) {
+        let disk_type = if parent.is_some() {
+            DISK_TYPE_DIFFERENCING
+        } else {
+            DISK_TYPE_DYNAMIC
+        };
+
+        let mut footer = vec![0u8; FOOTER_SIZE as usize];
+        footer[0..8].copy_from_slice(&VHD_COOKIE.to_be_bytes());
+        footer[8..12].copy_from_slice(&2u32.to_be_bytes());
+        footer[12..16].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        footer[16..24].copy_from_slice(&512u64.to_be_bytes());
+        footer[40..48].copy_from_slice(&DISK_SIZE.to_be_bytes());
+        footer[48..56].copy_from_slice(&DISK_SIZE.to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+        footer[68..84].copy_from_slice(&unique_id.to_be_bytes());
+
+        let mut header = vec![0u8; DYNAMIC_HEADER_SIZE];
+        header[0..8].copy_from_slice(&DYNAMIC_HEADER_COOKIE.to_be_bytes());
+        header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        header[16..24].copy_from_slice(&TABLE_OFFSET.to_be_bytes());
+        header[24..28].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        header[28..32].copy_from_slice(&((DISK_SIZE / BLOCK_SIZE as u64) as u32).to_be_bytes());
+        header[32..36].copy_from_slice(&BLOCK_SIZE.to_be_bytes());
+
+        let mut end = TABLE_OFFSET + 512;
+        let mut locator = Vec::new();
+        if let Some((name, parent_unique_id)) = parent {
+            header[40..56].copy_from_slice(&parent_unique_id.to_be_bytes());
+            locator = format!(".\\{}", name)
+                .encode_utf16()
+                .flat_map(|u| u.to_le_bytes())
+                .collect();
+            let entry = &mut header[PARENT_LOCATORS_OFFSET..];
+            entry[0..4].copy_from_slice(&PLATFORM_CODE_W2RU.to_be_bytes());
+            entry[4..8].copy_from_slice(&512u32.to_be_bytes());
+            entry[8..12].copy_from_slice(&(locator.len() as u32).to_be_bytes());
+            entry[16..24].copy_from_slice(&LOCATOR_OFFSET.to_be_bytes());
+            end = LOCATOR_OFFSET + 512;
+        }
+
+        file.set_len(0).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&footer).unwrap();
+        file.write_all(&header).unwrap();
+        file.write_all(&[0xff; 512]).unwrap();
+        file.write_all(&vec![0; (end - TABLE_OFFSET - 512) as usize])
+            .unwrap();
+        file.seek(SeekFrom::Start(LOCATOR_OFFSET)).unwrap();
+        file.write_all(&locator).unwrap();
+        file.seek(SeekFrom::Start(end)).unwrap();
+        file.write_all(&footer).unwrap();
+    }
+
+    fn read_disk(vhd: &mut DynamicVhd, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        vhd.seek(SeekFrom::Start(offset)).unwrap();
+        vhd.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_disk(vhd: &mut DynamicVhd, offset: u64, data: &[u8]) {
+        vhd.seek(SeekFrom::Start(offset)).unwrap();
+        vhd.write_all(data).unwrap();
+    }
+
+    #[test]
+    fn dynamic_vhd_read_write() {
+        let tmp = TempFile::new().unwrap();
+        let mut file = tmp.as_file().try_clone().unwrap();
+        write_vhd(&mut file, 1, None);
+
+        let mut vhd =
+            DynamicVhd::new(file.try_clone().unwrap(), false, &BackingFilePolicy::Forbid).unwrap();
+        assert_eq!(vhd.virtual_disk_size(), DISK_SIZE);
+        assert_eq!(
+            read_disk(&mut vhd, 0, DISK_SIZE as usize),
+            vec![0; DISK_SIZE as usize]
+        );
+
+        // Write across two blocks.
+        write_disk(&mut vhd, 0x1f00, &[0x55; 0x200]);
+        write_disk(&mut vhd, 0x3000, &[0xaa; 0x10]);
+        assert!(vhd.seek(SeekFrom::Start(DISK_SIZE + 1)).is_err());
+        assert!(vhd.write_zeroes_at(DISK_SIZE - 0x10, 0x20).is_err());
+        drop(vhd);
+
+        // The image is still valid after the allocations.
+        assert!(is_dynamic_vhd(&mut file).unwrap());
+        let mut vhd = DynamicVhd::new(file, false, &BackingFilePolicy::Forbid).unwrap();
+        assert_eq!(read_disk(&mut vhd, 0x1f00, 0x200), vec![0x55; 0x200]);
+        assert_eq!(read_disk(&mut vhd, 0x2100, 0x100), vec![0; 0x100]);
+        assert_eq!(read_disk(&mut vhd, 0x3000, 0x20)[..0x10], [0xaa; 0x10]);
+        assert_eq!(read_disk(&mut vhd, 0x3010, 0x10), vec![0; 0x10]);
+
+        vhd.punch_hole(0x1f80, 0x100).unwrap();
+        assert_eq!(read_disk(&mut vhd, 0x1f80, 0x100), vec![0; 0x100]);
+        assert_eq!(read_disk(&mut vhd, 0x1f00, 0x80), vec![0x55; 0x80]);
+    }
+
+    #[test]
+    fn differencing_vhd_read_write() {
+        let parent_tmp = TempFile::new().unwrap();
+        let mut parent_file = parent_tmp.as_file().try_clone().unwrap();
+        write_vhd(&mut parent_file, 1, None);
+        let mut parent = DynamicVhd::new(parent_file, false, &BackingFilePolicy::Forbid).unwrap();
+        write_disk(&mut parent, 0, &[0x11; DISK_SIZE as usize]);
+        drop(parent);
+
+        let parent_name = parent_tmp.as_path().file_name().unwrap().to_str().unwrap();
+        let child_tmp = TempFile::new().unwrap();
+        let mut child_file = child_tmp.as_file().try_clone().unwrap();
+        write_vhd(&mut child_file, 2, Some((parent_name, 1)));
+
+        let mut child = DynamicVhd::new(
+            child_file.try_clone().unwrap(),
+            false,
+            &BackingFilePolicy::Header,
+        )
+        .unwrap();
+        assert_eq!(read_disk(&mut child, 0, 0x20), vec![0x11; 0x20]);
+
+        // Partially written sectors keep the rest of their content from the parent.
+        write_disk(&mut child, 0x1010, &[0x22; 0x10]);
+        child.write_zeroes_at(0x2000, 0x200).unwrap();
+        let data = read_disk(&mut child, 0x1000, 0x400);
+        assert_eq!(data[..0x10], [0x11; 0x10]);
+        assert_eq!(data[0x10..0x20], [0x22; 0x10]);
+        assert_eq!(data[0x20..], [0x11; 0x3e0]);
+        assert_eq!(read_disk(&mut child, 0x2000, 0x200), vec![0; 0x200]);
+        assert_eq!(read_disk(&mut child, 0x2200, 0x200), vec![0x11; 0x200]);
+
+        // The parent is left untouched.
+        let parent_file = OpenOptions::new()
+            .read(true)
+            .open(parent_tmp.as_path())
+            .unwrap();
+        let mut parent = DynamicVhd::new(parent_file, false, &BackingFilePolicy::Forbid).unwrap();
+        assert_eq!(read_disk(&mut parent, 0x1000, 0x20), vec![0x11; 0x20]);
+        assert_eq!(read_disk(&mut parent, 0x2000, 0x20), vec![0x11; 0x20]);
+
+        // Backing files can be forbidden or overridden.
+        assert!(DynamicVhd::new(
+            child_file.try_clone().unwrap(),
+            false,
+            &BackingFilePolicy::Forbid
+        )
+        .is_err());
+        let mut child = DynamicVhd::new(
+            child_file,
+            false,
+            &BackingFilePolicy::Override(parent_tmp.as_path().to_path_buf()),
+        )
+        .unwrap();
+        assert_eq!(read_disk(&mut child, 0x1010, 0x10), vec![0x22; 0x10]);
+    }
+
+    #[test]
+    fn differencing_vhd_parent_mismatch() {
+        let parent_tmp = TempFile::new().unwrap();
+        let mut parent_file = parent_tmp.as_file().try_clone().unwrap();
+        write_vhd(&mut parent_file, 1, None);
+
+        let parent_name = parent_tmp.as_path().file_name().unwrap().to_str().unwrap();
+        let child_tmp = TempFile::new().unwrap();
+        let mut child_file = child_tmp.as_file().try_clone().unwrap();
+        write_vhd(&mut child_file, 2, Some((parent_name, 3)));
+
+        assert!(DynamicVhd::new(child_file, false, &BackingFilePolicy::Header).is_err());
+    }
+}
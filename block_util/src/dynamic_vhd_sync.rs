@@ -0,0 +1,148 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{
+    clone_backing_files, AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use crate::dynamic_vhd::DynamicVhd;
+use crate::AsyncAdaptor;
+use qcow::BackingFilePolicy;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use vmm_sys_util::eventfd::EventFd;
+
+pub struct DynamicVhdDiskSync {
+    vhd_file: Arc<Mutex<DynamicVhd>>,
+}
+
+impl DynamicVhdDiskSync {
+    pub fn new(
+        f: File,
+        direct_io: bool,
+        backing_file_policy: &BackingFilePolicy,
+    ) -> std::io::Result<Self> {
+        Ok(DynamicVhdDiskSync {
+            vhd_file: Arc::new(Mutex::new(DynamicVhd::new(
+                f,
+                direct_io,
+                backing_file_policy,
+            )?)),
+        })
+    }
+}
+
+impl DiskFile for DynamicVhdDiskSync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.vhd_file.lock().unwrap().virtual_disk_size())
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(
+            Box::new(DynamicVhdSync::new(self.vhd_file.clone()).map_err(DiskFileError::NewAsyncIo)?)
+                as Box<dyn AsyncIo>,
+        )
+    }
+
+    fn backing_files(&self) -> DiskFileResult<Vec<(PathBuf, File)>> {
+        clone_backing_files(self.vhd_file.lock().unwrap().parent_files())
+    }
+}
+
+pub struct DynamicVhdSync {
+    vhd_file: Arc<Mutex<DynamicVhd>>,
+    eventfd: EventFd,
+    completion_list: Vec<(u64, i32)>,
+}
+
+impl DynamicVhdSync {
+    pub fn new(vhd_file: Arc<Mutex<DynamicVhd>>) -> std::io::Result<Self> {
+        Ok(DynamicVhdSync {
+            vhd_file,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+            completion_list: Vec::new(),
+        })
+    }
+}
+
+impl AsyncAdaptor<DynamicVhd> for Arc<Mutex<DynamicVhd>> {
+    fn file(&mut self) -> MutexGuard<DynamicVhd> {
+        self.lock().unwrap()
+    }
+}
+
+impl AsyncIo for DynamicVhdSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.vhd_file.read_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.vhd_file.write_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.vhd_file
+            .fsync_sync(user_data, &self.eventfd, &mut self.completion_list)
+    }
+
+    fn punch_hole(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.vhd_file.punch_hole_sync(
+            offset,
+            length,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.vhd_file.write_zeroes_sync(
+            offset,
+            length,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        self.completion_list.drain(..).collect()
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Image of a disk without media, such as an ejected CD-ROM.
+//!
+//! The disk has a capacity of zero, so requests reading or writing data are
+//! rejected before reaching the image, leaving only flushes to complete.
+
+use crate::async_io::{AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileResult};
+use std::io;
+use vmm_sys_util::eventfd::EventFd;
+
+#[derive(Default)]
+pub struct EmptyDisk {}
+
+impl EmptyDisk {
+    pub fn new() -> Self {
+        EmptyDisk {}
+    }
+}
+
+impl DiskFile for EmptyDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(0)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(EmptyDiskIo::new()) as Box<dyn AsyncIo>)
+    }
+}
+
+fn no_medium() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOMEDIUM)
+}
+
+pub struct EmptyDiskIo {
+    eventfd: EventFd,
+    completion_list: Vec<(u64, i32)>,
+}
+
+impl EmptyDiskIo {
+    pub fn new() -> Self {
+        EmptyDiskIo {
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)
+                .expect("Failed creating EventFd for EmptyDisk"),
+            completion_list: Vec::new(),
+        }
+    }
+}
+
+impl Default for EmptyDiskIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsyncIo for EmptyDiskIo {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        _offset: libc::off_t,
+        _iovecs: Vec<libc::iovec>,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::ReadVectored(no_medium()))
+    }
+
+    fn write_vectored(
+        &mut self,
+        _offset: libc::off_t,
+        _iovecs: Vec<libc::iovec>,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::WriteVectored(no_medium()))
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        // There is nothing to flush.
+        if let Some(user_data) = user_data {
+            self.completion_list.push((user_data, 0));
+            self.eventfd.write(1).unwrap();
+        }
+
+        Ok(())
+    }
+
+    fn punch_hole(
+        &mut self,
+        _offset: libc::off_t,
+        _length: u64,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::PunchHole(no_medium()))
+    }
+
+    fn write_zeroes(
+        &mut self,
+        _offset: libc::off_t,
+        _length: u64,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::WriteZeroes(no_medium()))
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        self.completion_list.drain(..).collect()
+    }
+}
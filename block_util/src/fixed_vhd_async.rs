@@ -54,6 +54,24 @@ impl FixedVhdAsync {
             size,
         })
     }
+
+    // The footer is stored right after the data, it must never be discarded.
+    fn check_range(&self, offset: libc::off_t, length: u64) -> std::io::Result<()> {
+        if (offset as u64)
+            .checked_add(length)
+            .map_or(true, |end| end > self.size)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid range {}+{}, can't go beyond file size {}",
+                    offset, length, self.size
+                ),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 impl AsyncIo for FixedVhdAsync {
@@ -104,6 +122,28 @@ impl AsyncIo for FixedVhdAsync {
         self.raw_file_async.fsync(user_data)
     }
 
+    fn punch_hole(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.check_range(offset, length)
+            .map_err(AsyncIoError::PunchHole)?;
+        self.raw_file_async.punch_hole(offset, length, user_data)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.check_range(offset, length)
+            .map_err(AsyncIoError::WriteZeroes)?;
+        self.raw_file_async.write_zeroes(offset, length, user_data)
+    }
+
     fn complete(&mut self) -> Vec<(u64, i32)> {
         self.raw_file_async.complete()
     }
@@ -0,0 +1,287 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Locks preventing several users from opening the same image.
+//!
+//! The locks are open file description (OFD) locks, which conflict with the
+//! locks taken through any other open of the same file, whether it happens in
+//! another process or in the same one. They are released once all the file
+//! descriptors referring to the open file description are closed, meaning
+//! the lock is held as long as the image is.
+//!
+//! Writable images are locked exclusively, while read-only ones are shared
+//! between any number of readers.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("The file is already in use by another process or device")]
+    AlreadyLocked,
+    #[error("Failed locking the file: {0}")]
+    Lock(#[source] io::Error),
+}
+
+/// Kind of lock taken on an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockType {
+    /// Lock for a read-only use, compatible with other shared locks.
+    Shared,
+    /// Lock for a writable use, incompatible with any other lock.
+    Exclusive,
+}
+
+impl LockType {
+    /// Returns the kind of lock to take on an image opened read-only or not.
+    pub fn new(readonly: bool) -> Self {
+        if readonly {
+            LockType::Shared
+        } else {
+            LockType::Exclusive
+        }
+    }
+}
+
+/// Locks `len` bytes of `file` starting at `start`, or up to the end of the
+/// file, however large it grows, if `len` is 0. An exclusive lock requires
+/// the file to be open for writing.
+///
+/// # Errors
+///
+/// If the range is already locked in a conflicting way,
+/// `LockError::AlreadyLocked` is returned.
+pub fn lock_range(file: &File, lock_type: LockType, start: u64, len: u64) -> Result<(), LockError> {
+    // Safe because the structure only holds integers.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = match lock_type {
+        LockType::Shared => libc::F_RDLCK,
+        LockType::Exclusive => libc::F_WRLCK,
+    } as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = start as libc::off_t;
+    flock.l_len = len as libc::off_t;
+
+    // Safe because the file descriptor is valid, and the kernel only reads
+    // the structure.
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &flock) };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => LockError::AlreadyLocked,
+            _ => LockError::Lock(e),
+        });
+    }
+
+    Ok(())
+}
+
+/// Locks the whole `file`.
+///
+/// # Errors
+///
+/// If the file is already locked in a conflicting way,
+/// `LockError::AlreadyLocked` is returned.
+pub fn lock(file: &File, lock_type: LockType) -> Result<(), LockError> {
+    lock_range(file, lock_type, 0, 0)
+}
+
+/// Releases the lock held on `file` through its open file description, if
+/// any, for the file to be locked again through another open.
+pub fn unlock(file: &File) -> Result<(), LockError> {
+    // Safe because the structure only holds integers.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = libc::F_UNLCK as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+
+    // Safe because the file descriptor is valid, and the kernel only reads
+    // the structure.
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &flock) };
+    if ret < 0 {
+        return Err(LockError::Lock(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+/// Lock which couldn't be taken yet, as the file is still in use, and which
+/// is to be taken again later on.
+pub struct PendingLock {
+    // Refers to the same open file description as the file to lock.
+    file: File,
+    path: PathBuf,
+    lock_type: LockType,
+    start: u64,
+    len: u64,
+}
+
+impl PendingLock {
+    /// Records the lock of `len` bytes of `file` starting at `start`, as
+    /// described for `lock_range()`, `path` naming the file.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be cloned, an error is returned.
+    pub fn new(
+        file: &File,
+        path: &Path,
+        lock_type: LockType,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Self> {
+        Ok(PendingLock {
+            file: file.try_clone()?,
+            path: path.to_owned(),
+            lock_type,
+            start,
+            len,
+        })
+    }
+
+    /// Returns the path of the file to lock.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Attempts to take the lock, which is then held as long as the file it
+    /// was recorded for is open.
+    ///
+    /// # Errors
+    ///
+    /// If the file is still locked in a conflicting way,
+    /// `LockError::AlreadyLocked` is returned.
+    pub fn lock(&self) -> Result<(), LockError> {
+        lock_range(&self.file, self.lock_type, self.start, self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn reopen(temp_file: &TempFile) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp_file.as_path())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lock_exclusive() {
+        let temp_file = TempFile::new().unwrap();
+        let file0 = reopen(&temp_file);
+        let file1 = reopen(&temp_file);
+
+        lock(&file0, LockType::Exclusive).unwrap();
+        assert!(matches!(
+            lock(&file1, LockType::Exclusive),
+            Err(LockError::AlreadyLocked)
+        ));
+        assert!(matches!(
+            lock(&file1, LockType::Shared),
+            Err(LockError::AlreadyLocked)
+        ));
+
+        // The lock is released when the file is closed.
+        drop(file0);
+        lock(&file1, LockType::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn test_lock_shared() {
+        let temp_file = TempFile::new().unwrap();
+        let file0 = reopen(&temp_file);
+        let file1 = reopen(&temp_file);
+        let file2 = reopen(&temp_file);
+
+        lock(&file0, LockType::new(true)).unwrap();
+        lock(&file1, LockType::new(true)).unwrap();
+        assert!(matches!(
+            lock(&file2, LockType::new(false)),
+            Err(LockError::AlreadyLocked)
+        ));
+
+        // A clone refers to the same open file description, and the lock
+        // is held as long as one of them is open.
+        let file0_clone = file0.try_clone().unwrap();
+        drop(file0);
+        drop(file1);
+        assert!(matches!(
+            lock(&file2, LockType::Exclusive),
+            Err(LockError::AlreadyLocked)
+        ));
+        drop(file0_clone);
+        lock(&file2, LockType::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn test_unlock() {
+        let temp_file = TempFile::new().unwrap();
+        let file0 = reopen(&temp_file);
+        let file1 = reopen(&temp_file);
+
+        lock(&file0, LockType::Exclusive).unwrap();
+        assert!(matches!(
+            lock(&file1, LockType::Exclusive),
+            Err(LockError::AlreadyLocked)
+        ));
+
+        // The file stays open, but isn't locked anymore.
+        unlock(&file0).unwrap();
+        lock(&file1, LockType::Exclusive).unwrap();
+        assert!(matches!(
+            lock(&file0, LockType::Shared),
+            Err(LockError::AlreadyLocked)
+        ));
+    }
+
+    #[test]
+    fn test_lock_range() {
+        let temp_file = TempFile::new().unwrap();
+        let file0 = reopen(&temp_file);
+        let file1 = reopen(&temp_file);
+
+        lock_range(&file0, LockType::Exclusive, 0, 0x1000).unwrap();
+        lock_range(&file1, LockType::Exclusive, 0x1000, 0x1000).unwrap();
+        assert!(matches!(
+            lock_range(&file1, LockType::Exclusive, 0x800, 0x1000),
+            Err(LockError::AlreadyLocked)
+        ));
+        assert!(matches!(
+            lock(&file0, LockType::Shared),
+            Err(LockError::AlreadyLocked)
+        ));
+    }
+
+    #[test]
+    fn test_pending_lock() {
+        let temp_file = TempFile::new().unwrap();
+        let file0 = reopen(&temp_file);
+        let file1 = reopen(&temp_file);
+
+        lock(&file0, LockType::Exclusive).unwrap();
+        let pending =
+            PendingLock::new(&file1, temp_file.as_path(), LockType::Exclusive, 0, 0).unwrap();
+        assert_eq!(pending.path(), temp_file.as_path());
+        assert!(matches!(pending.lock(), Err(LockError::AlreadyLocked)));
+
+        // The lock taken later on applies to the original file.
+        drop(file0);
+        pending.lock().unwrap();
+        drop(pending);
+        let file2 = reopen(&temp_file);
+        assert!(matches!(
+            lock(&file2, LockType::Shared),
+            Err(LockError::AlreadyLocked)
+        ));
+        drop(file1);
+        lock(&file2, LockType::Shared).unwrap();
+    }
+}
@@ -12,15 +12,34 @@
 extern crate log;
 
 pub mod async_io;
+pub mod backup;
+pub mod dirty_bitmap;
+pub mod dynamic_vhd;
+pub mod dynamic_vhd_sync;
+pub mod empty_disk;
 pub mod fixed_vhd_async;
 pub mod fixed_vhd_sync;
+pub mod image_lock;
+pub mod luks;
+pub mod luks_disk;
+pub mod mapped_async;
+pub mod mirror;
+pub mod nbd;
+pub mod nbd_sync;
+pub mod qcow_async;
 pub mod qcow_sync;
 pub mod raw_async;
 pub mod raw_sync;
+pub mod scsi;
+pub mod scsi_reservations;
 pub mod vhd;
+pub mod vhdx_async;
 pub mod vhdx_sync;
 
 use crate::async_io::{AsyncIo, AsyncIoError, AsyncIoResult};
+use crate::backup::DiskBackup;
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::mirror::DiskMirror;
 use io_uring::{opcode, IoUring, Probe};
 use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::cmp;
@@ -42,6 +61,7 @@ use vm_memory::{
 };
 use vm_virtio::{AccessPlatform, Translatable};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 
@@ -68,6 +88,8 @@ pub enum Error {
     InvalidOffset,
     /// The requested operation does not support multiple descriptors.
     TooManyDescriptors,
+    /// The request holds more segments than advertised.
+    TooManySegments,
 }
 
 fn build_device_id(disk_path: &Path) -> result::Result<String, Error> {
@@ -115,6 +137,10 @@ pub enum ExecuteError {
     AsyncRead(AsyncIoError),
     AsyncWrite(AsyncIoError),
     AsyncFlush(AsyncIoError),
+    AsyncDiscard(AsyncIoError),
+    AsyncWriteZeroes(AsyncIoError),
+    Discard(io::Error),
+    WriteZeroes(io::Error),
     /// Failed allocating a temporary buffer.
     TemporaryBufferAllocation(io::Error),
 }
@@ -133,9 +159,31 @@ impl ExecuteError {
             ExecuteError::AsyncRead(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::AsyncWrite(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::AsyncFlush(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncDiscard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncWriteZeroes(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::WriteZeroes(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::TemporaryBufferAllocation(_) => VIRTIO_BLK_S_IOERR,
         }
     }
+
+    /// Returns whether the request failed on the disk image, rather than
+    /// being invalid.
+    pub fn is_disk_error(&self) -> bool {
+        matches!(
+            self,
+            ExecuteError::Flush(_)
+                | ExecuteError::Seek(_)
+                | ExecuteError::SubmitIoUring(_)
+                | ExecuteError::AsyncRead(_)
+                | ExecuteError::AsyncWrite(_)
+                | ExecuteError::AsyncFlush(_)
+                | ExecuteError::AsyncDiscard(_)
+                | ExecuteError::AsyncWriteZeroes(_)
+                | ExecuteError::Discard(_)
+                | ExecuteError::WriteZeroes(_)
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -144,6 +192,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceId,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -157,6 +207,8 @@ pub fn request_type(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceId),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -171,6 +223,18 @@ fn sector(mem: &GuestMemoryMmap, desc_addr: GuestAddress) -> result::Result<u64,
     mem.read_obj(addr).map_err(Error::GuestMemory)
 }
 
+// Range of sectors a DISCARD or WRITE_ZEROES request applies to.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// SAFETY: DiscardWriteZeroesSegment only contains a series of integers
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
 #[derive(Debug)]
 pub struct AlignedOperation {
     origin_ptr: u64,
@@ -187,6 +251,8 @@ pub struct Request {
     pub status_addr: GuestAddress,
     pub writeback: bool,
     pub aligned_operations: Vec<AlignedOperation>,
+    pub dirty_bitmap: Option<Arc<DirtyBitmap>>,
+    pub backup: Option<Arc<DiskBackup>>,
 }
 
 impl Request {
@@ -218,6 +284,8 @@ impl Request {
             status_addr: GuestAddress(0),
             writeback: true,
             aligned_operations: Vec::new(),
+            dirty_bitmap: None,
+            backup: None,
         };
 
         let status_desc;
@@ -238,7 +306,12 @@ impl Request {
             }
         } else {
             while desc.has_next() {
-                if desc.is_write_only() && req.request_type == RequestType::Out {
+                if desc.is_write_only()
+                    && matches!(
+                        req.request_type,
+                        RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+                    )
+                {
                     return Err(Error::UnexpectedWriteOnlyDescriptor);
                 }
                 if !desc.is_write_only() && req.request_type == RequestType::In {
@@ -280,7 +353,61 @@ impl Request {
         Ok(req)
     }
 
-    pub fn execute<T: Seek + Read + Write>(
+    // Returns the offset and length in bytes of the range a DISCARD or
+    // WRITE_ZEROES request applies to, along with whether the range may be
+    // deallocated.
+    fn discard_write_zeroes_range(
+        &self,
+        mem: &GuestMemoryMmap,
+        disk_nsectors: u64,
+    ) -> result::Result<(u64, u64, bool), ExecuteError> {
+        let (data_addr, data_len) = if self.data_descriptors.len() == 1 {
+            (self.data_descriptors[0].0, self.data_descriptors[0].1)
+        } else {
+            return Err(ExecuteError::BadRequest(Error::TooManyDescriptors));
+        };
+
+        // A single segment is advertised through max_discard_seg and
+        // max_write_zeroes_seg.
+        let segment_size = std::mem::size_of::<DiscardWriteZeroesSegment>();
+        if (data_len as usize) < segment_size {
+            return Err(ExecuteError::BadRequest(Error::DescriptorLengthTooSmall));
+        }
+        if (data_len as usize) > segment_size {
+            return Err(ExecuteError::BadRequest(Error::TooManySegments));
+        }
+
+        let segment: DiscardWriteZeroesSegment =
+            mem.read_obj(data_addr).map_err(ExecuteError::Read)?;
+
+        let (request_type, valid_flags) = if self.request_type == RequestType::Discard {
+            (VIRTIO_BLK_T_DISCARD, 0)
+        } else {
+            (
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+            )
+        };
+        if segment.flags & !valid_flags != 0 {
+            return Err(ExecuteError::Unsupported(request_type));
+        }
+
+        let top = segment
+            .sector
+            .checked_add(u64::from(segment.num_sectors))
+            .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+        if top > disk_nsectors {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+
+        Ok((
+            segment.sector << SECTOR_SHIFT,
+            u64::from(segment.num_sectors) << SECTOR_SHIFT,
+            segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0,
+        ))
+    }
+
+    pub fn execute<T: Seek + Read + Write + PunchHole + WriteZeroesAt>(
         &self,
         disk: &mut T,
         disk_nsectors: u64,
@@ -309,6 +436,10 @@ impl Request {
                     len += data_len;
                 }
                 RequestType::Out => {
+                    self.track_write(
+                        disk.stream_position().map_err(ExecuteError::Seek)?,
+                        u64::from(*data_len),
+                    );
                     mem.write_all_to(*data_addr, disk, *data_len as usize)
                         .map_err(ExecuteError::Write)?;
                     if !self.writeback {
@@ -323,6 +454,25 @@ impl Request {
                     mem.write_slice(disk_id, *data_addr)
                         .map_err(ExecuteError::Write)?;
                 }
+                RequestType::Discard => {
+                    let (offset, length, _) =
+                        self.discard_write_zeroes_range(mem, disk_nsectors)?;
+                    self.track_write(offset, length);
+                    disk.punch_hole(offset, length)
+                        .map_err(ExecuteError::Discard)?;
+                }
+                RequestType::WriteZeroes => {
+                    let (offset, length, unmap) =
+                        self.discard_write_zeroes_range(mem, disk_nsectors)?;
+                    self.track_write(offset, length);
+                    if unmap {
+                        disk.punch_hole(offset, length)
+                            .map_err(ExecuteError::WriteZeroes)?;
+                    } else {
+                        disk.write_all_zeroes_at(offset, length as usize)
+                            .map_err(ExecuteError::WriteZeroes)?;
+                    }
+                }
                 RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
             };
         }
@@ -343,7 +493,14 @@ impl Request {
 
         let mut iovecs = Vec::new();
         for (data_addr, data_len) in &self.data_descriptors {
-            if *data_len == 0 {
+            // The descriptor of DISCARD and WRITE_ZEROES requests describes
+            // the range to operate on, there is no data to transfer.
+            if *data_len == 0
+                || matches!(
+                    request_type,
+                    RequestType::Discard | RequestType::WriteZeroes
+                )
+            {
                 continue;
             }
             let mut top: u64 = u64::from(*data_len) / SECTOR_SIZE;
@@ -422,6 +579,8 @@ impl Request {
                     .map_err(ExecuteError::AsyncRead)?;
             }
             RequestType::Out => {
+                let length = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+                self.track_write(offset as u64, length);
                 disk_image
                     .write_vectored(offset, iovecs, user_data)
                     .map_err(ExecuteError::AsyncWrite)?;
@@ -444,6 +603,27 @@ impl Request {
                     .map_err(ExecuteError::Write)?;
                 return Ok(false);
             }
+            RequestType::Discard => {
+                let (offset, length, _) = self.discard_write_zeroes_range(mem, disk_nsectors)?;
+                self.track_write(offset, length);
+                disk_image
+                    .punch_hole(offset as libc::off_t, length, user_data)
+                    .map_err(ExecuteError::AsyncDiscard)?;
+            }
+            RequestType::WriteZeroes => {
+                let (offset, length, unmap) =
+                    self.discard_write_zeroes_range(mem, disk_nsectors)?;
+                self.track_write(offset, length);
+                if unmap {
+                    disk_image
+                        .punch_hole(offset as libc::off_t, length, user_data)
+                        .map_err(ExecuteError::AsyncWriteZeroes)?;
+                } else {
+                    disk_image
+                        .write_zeroes(offset as libc::off_t, length, user_data)
+                        .map_err(ExecuteError::AsyncWriteZeroes)?;
+                }
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         }
 
@@ -483,6 +663,79 @@ impl Request {
     pub fn set_writeback(&mut self, writeback: bool) {
         self.writeback = writeback
     }
+
+    pub fn set_dirty_bitmap(&mut self, dirty_bitmap: Option<Arc<DirtyBitmap>>) {
+        self.dirty_bitmap = dirty_bitmap
+    }
+
+    pub fn set_backup(&mut self, backup: Option<Arc<DiskBackup>>) {
+        self.backup = backup
+    }
+
+    /// Replays the write made by this request on `mirror`, once it completed
+    /// on the disk. This must happen before `complete_async()`, which
+    /// releases the aligned copies of the buffers.
+    pub fn mirror(
+        &self,
+        mem: &GuestMemoryMmap,
+        disk_nsectors: u64,
+        mirror: &DiskMirror,
+    ) -> io::Result<()> {
+        match self.request_type {
+            RequestType::Out => {
+                let mut iovecs = Vec::with_capacity(self.data_descriptors.len());
+                for (data_addr, data_len) in &self.data_descriptors {
+                    let origin_ptr = mem
+                        .get_slice(*data_addr, *data_len as usize)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                        .as_ptr() as u64;
+                    // Unaligned buffers were written from their aligned copy.
+                    let ptr = self
+                        .aligned_operations
+                        .iter()
+                        .find(|op| op.origin_ptr == origin_ptr)
+                        .map_or(origin_ptr, |op| op.aligned_ptr);
+                    iovecs.push(libc::iovec {
+                        iov_base: ptr as *mut libc::c_void,
+                        iov_len: *data_len as libc::size_t,
+                    });
+                }
+                mirror.write(self.sector << SECTOR_SHIFT, iovecs)?;
+            }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                let (offset, length, _) = self
+                    .discard_write_zeroes_range(mem, disk_nsectors)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+                mirror.write_zeroes(offset, length)?;
+            }
+            RequestType::Flush => return mirror.flush(),
+            _ => return Ok(()),
+        }
+
+        if !self.writeback {
+            mirror.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // Records the range as written before submitting it, so that a backup
+    // running concurrently can't miss it. The content of the range is saved
+    // first if the backup running didn't copy it yet.
+    fn track_write(&self, offset: u64, length: u64) {
+        if let Some(dirty_bitmap) = &self.dirty_bitmap {
+            dirty_bitmap.mark(offset, length);
+        }
+        if let Some(backup) = &self.backup {
+            if let Err(e) = backup.before_write(offset, length) {
+                error!(
+                    "Failed saving range before write, stopping the backup: {}",
+                    e
+                );
+                backup.cancel();
+            }
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default, Versionize)]
@@ -569,9 +822,28 @@ pub fn block_io_uring_is_supported() -> bool {
     true
 }
 
+/// Check if the io_uring instance `io_uring` supports IORING_OP_FALLOCATE.
+/// This is not required for using io_uring, as discard and write zeroes
+/// requests can be completed synchronously when it is not supported.
+pub fn block_io_uring_fallocate_is_supported(io_uring: &IoUring) -> bool {
+    let mut probe = Probe::new();
+
+    if let Err(e) = io_uring.submitter().register_probe(&mut probe) {
+        info!("io_uring: failed to register a probe: {}", e);
+        return false;
+    }
+
+    if !probe.is_supported(opcode::Fallocate::CODE) {
+        info!("io_uring: IORING_OP_FALLOCATE operation not supported, falling back to synchronous fallocate");
+        return false;
+    }
+
+    true
+}
+
 pub trait AsyncAdaptor<F>
 where
-    F: Read + Write + Seek,
+    F: Read + Write + Seek + PunchHole + WriteZeroesAt,
 {
     fn read_vectored_sync(
         &mut self,
@@ -660,10 +932,55 @@ where
         Ok(())
     }
 
+    fn punch_hole_sync(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+        eventfd: &EventFd,
+        completion_list: &mut Vec<(u64, i32)>,
+    ) -> AsyncIoResult<()> {
+        {
+            let mut file = self.file();
+
+            // Deallocate the range
+            file.punch_hole(offset as u64, length)
+                .map_err(AsyncIoError::PunchHole)?;
+        }
+
+        completion_list.push((user_data, 0));
+        eventfd.write(1).unwrap();
+
+        Ok(())
+    }
+
+    fn write_zeroes_sync(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+        eventfd: &EventFd,
+        completion_list: &mut Vec<(u64, i32)>,
+    ) -> AsyncIoResult<()> {
+        {
+            let mut file = self.file();
+
+            // Zero the range
+            file.write_all_zeroes_at(offset as u64, length as usize)
+                .map_err(AsyncIoError::WriteZeroes)?;
+        }
+
+        completion_list.push((user_data, 0));
+        eventfd.write(1).unwrap();
+
+        Ok(())
+    }
+
     fn file(&mut self) -> MutexGuard<F>;
 }
 
 pub enum ImageType {
+    DynamicVhd,
     FixedVhd,
     Qcow2,
     Raw,
@@ -690,6 +1007,8 @@ pub fn detect_image_type(f: &mut File) -> std::io::Result<ImageType> {
         ImageType::Qcow2
     } else if vhd::is_fixed_vhd(f)? {
         ImageType::FixedVhd
+    } else if vhd::is_dynamic_vhd(f)? {
+        ImageType::DynamicVhd
     } else if u64::from_le_bytes(s.data[0..8].try_into().unwrap()) == VHDX_SIGN {
         ImageType::Vhdx
     } else {
@@ -698,3 +1017,230 @@ pub fn detect_image_type(f: &mut File) -> std::io::Result<ImageType> {
 
     Ok(image_type)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const SEGMENT_ADDR: GuestAddress = GuestAddress(0x1000);
+    const DISK_NSECTORS: u64 = 128;
+    const SEGMENT_SIZE: u32 = std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+
+    fn create_guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap()
+    }
+
+    // Builds a DISCARD or WRITE_ZEROES request whose data descriptor holds
+    // `data_len` bytes from the segment written to guest memory.
+    fn segment_request(
+        mem: &GuestMemoryMmap,
+        request_type: RequestType,
+        segment: DiscardWriteZeroesSegment,
+        data_len: u32,
+    ) -> Request {
+        mem.write_obj(segment, SEGMENT_ADDR).unwrap();
+        Request {
+            request_type,
+            sector: 0,
+            data_descriptors: vec![(SEGMENT_ADDR, data_len)],
+            status_addr: GuestAddress(0),
+            writeback: true,
+            aligned_operations: Vec::new(),
+            dirty_bitmap: None,
+            backup: None,
+        }
+    }
+
+    fn segment(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_valid_segment() {
+        let mem = create_guest_memory();
+
+        let req = segment_request(&mem, RequestType::Discard, segment(8, 16, 0), SEGMENT_SIZE);
+        assert_eq!(
+            req.discard_write_zeroes_range(&mem, DISK_NSECTORS).unwrap(),
+            (8 << SECTOR_SHIFT, 16 << SECTOR_SHIFT, false)
+        );
+
+        // The segment may end at the end of the disk.
+        let req = segment_request(
+            &mem,
+            RequestType::WriteZeroes,
+            segment(DISK_NSECTORS - 16, 16, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            SEGMENT_SIZE,
+        );
+        assert_eq!(
+            req.discard_write_zeroes_range(&mem, DISK_NSECTORS).unwrap(),
+            (
+                (DISK_NSECTORS - 16) << SECTOR_SHIFT,
+                16 << SECTOR_SHIFT,
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_invalid_length() {
+        let mem = create_guest_memory();
+
+        // Shorter than a segment
+        let req = segment_request(&mem, RequestType::Discard, segment(0, 1, 0), 8);
+        let err = req
+            .discard_write_zeroes_range(&mem, DISK_NSECTORS)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::BadRequest(Error::DescriptorLengthTooSmall)
+        ));
+        assert_eq!(err.status(), VIRTIO_BLK_S_IOERR);
+
+        // Not a multiple of the segment size
+        let req = segment_request(&mem, RequestType::Discard, segment(0, 1, 0), 20);
+        let err = req
+            .discard_write_zeroes_range(&mem, DISK_NSECTORS)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::BadRequest(Error::TooManySegments)
+        ));
+        assert_eq!(err.status(), VIRTIO_BLK_S_IOERR);
+
+        // More than the single segment advertised
+        let req = segment_request(
+            &mem,
+            RequestType::WriteZeroes,
+            segment(0, 1, 0),
+            2 * SEGMENT_SIZE,
+        );
+        let err = req
+            .discard_write_zeroes_range(&mem, DISK_NSECTORS)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::BadRequest(Error::TooManySegments)
+        ));
+        assert_eq!(err.status(), VIRTIO_BLK_S_IOERR);
+
+        // Segment split across descriptors
+        let mut req = segment_request(&mem, RequestType::Discard, segment(0, 1, 0), 8);
+        req.data_descriptors
+            .push((GuestAddress(SEGMENT_ADDR.0 + 8), 8));
+        let err = req
+            .discard_write_zeroes_range(&mem, DISK_NSECTORS)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::BadRequest(Error::TooManyDescriptors)
+        ));
+        assert_eq!(err.status(), VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_unknown_flags() {
+        let mem = create_guest_memory();
+
+        // The unmap flag only applies to WRITE_ZEROES.
+        let req = segment_request(
+            &mem,
+            RequestType::Discard,
+            segment(0, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            SEGMENT_SIZE,
+        );
+        let err = req
+            .discard_write_zeroes_range(&mem, DISK_NSECTORS)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD)
+        ));
+        assert_eq!(err.status(), VIRTIO_BLK_S_UNSUPP);
+
+        // Reserved flags
+        let req = segment_request(
+            &mem,
+            RequestType::WriteZeroes,
+            segment(0, 1, 1 << 1),
+            SEGMENT_SIZE,
+        );
+        let err = req
+            .discard_write_zeroes_range(&mem, DISK_NSECTORS)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES)
+        ));
+        assert_eq!(err.status(), VIRTIO_BLK_S_UNSUPP);
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_out_of_range() {
+        let mem = create_guest_memory();
+
+        // Past the end of the disk
+        let req = segment_request(
+            &mem,
+            RequestType::Discard,
+            segment(DISK_NSECTORS - 8, 16, 0),
+            SEGMENT_SIZE,
+        );
+        let err = req
+            .discard_write_zeroes_range(&mem, DISK_NSECTORS)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::BadRequest(Error::InvalidOffset)
+        ));
+        assert_eq!(err.status(), VIRTIO_BLK_S_IOERR);
+
+        // Overflowing the sector number
+        let req = segment_request(
+            &mem,
+            RequestType::WriteZeroes,
+            segment(u64::MAX, 1, 0),
+            SEGMENT_SIZE,
+        );
+        let err = req
+            .discard_write_zeroes_range(&mem, DISK_NSECTORS)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExecuteError::BadRequest(Error::InvalidOffset)
+        ));
+        assert_eq!(err.status(), VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn test_execute_write_zeroes() {
+        let mem = create_guest_memory();
+        let disk_size = DISK_NSECTORS << SECTOR_SHIFT;
+        let mut disk = TempFile::new().unwrap().into_file();
+
+        for flags in [0, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP] {
+            disk.write_all_at(&vec![0xffu8; disk_size as usize], 0)
+                .unwrap();
+            let req = segment_request(
+                &mem,
+                RequestType::WriteZeroes,
+                segment(8, 16, flags),
+                SEGMENT_SIZE,
+            );
+            req.execute(&mut disk, DISK_NSECTORS, &mem, &[]).unwrap();
+
+            let mut buf = vec![0u8; disk_size as usize];
+            disk.read_exact_at(&mut buf, 0).unwrap();
+            let (start, end) = ((8 << SECTOR_SHIFT) as usize, (24 << SECTOR_SHIFT) as usize);
+            assert!(buf[..start].iter().all(|b| *b == 0xff));
+            assert!(buf[start..end].iter().all(|b| *b == 0));
+            assert!(buf[end..].iter().all(|b| *b == 0xff));
+        }
+    }
+}
@@ -0,0 +1,1326 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Disk images encrypted with LUKS, in the version 1 or 2 format created by
+//! cryptsetup.
+//!
+//! The header is read and the volume key unlocked with the passphrase when
+//! the image is opened. The payload following the header is then encrypted
+//! and decrypted sector by sector on the I/O paths. The only cipher supported
+//! is AES in XTS mode with the plain64 IV, cryptsetup's default.
+//!
+//! The header is never written: key slots are managed with cryptsetup while
+//! the image isn't in use.
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::KeyInit;
+use aes::{Aes128, Aes256};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::Hmac;
+use serde::Deserialize;
+use sha2::Digest;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use thiserror::Error;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+use xts_mode::{get_tweak_default, Xts128};
+
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+const LUKS2_SECONDARY_MAGIC: &[u8] = b"SKUL\xba\xbe";
+
+// The key material and the LUKS1 payload are always encrypted by sectors of
+// 512 bytes.
+const LUKS_SECTOR_SIZE: u64 = 512;
+
+const LUKS1_HEADER_SIZE: usize = 592;
+const LUKS1_NUM_KEYS: usize = 8;
+const LUKS1_KEY_ENABLED: u32 = 0x00ac_71f3;
+const LUKS1_KEY_SLOT_OFFSET: usize = 208;
+const LUKS1_KEY_SLOT_SIZE: usize = 48;
+
+const LUKS2_BINARY_HEADER_SIZE: usize = 4096;
+const LUKS2_MAX_HEADER_SIZE: u64 = 4 << 20;
+const LUKS2_CHECKSUM_OFFSET: usize = 448;
+const LUKS2_CHECKSUM_SIZE: usize = 64;
+// Offsets the secondary LUKS2 header can be found at, right after the
+// primary one whose size depends on the size of the metadata.
+const LUKS2_SECONDARY_HEADER_OFFSETS: [u64; 9] = [
+    0x4000, 0x8000, 0x1_0000, 0x2_0000, 0x4_0000, 0x8_0000, 0x10_0000, 0x20_0000, 0x40_0000,
+];
+
+// Maximum number of bytes read or written at once by LuksFile.
+const LUKS_FILE_MAX_CHUNK_SIZE: u64 = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum LuksError {
+    #[error("Failed reading LUKS header: {0}")]
+    ReadHeader(#[source] io::Error),
+    #[error("Not a LUKS image")]
+    InvalidMagic,
+    #[error("Unsupported LUKS version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Invalid LUKS2 header checksum")]
+    InvalidChecksum,
+    #[error("Failed parsing LUKS2 metadata: {0}")]
+    ParseMetadata(#[source] serde_json::Error),
+    #[error("Invalid LUKS metadata: {0}")]
+    InvalidMetadata(String),
+    #[error("Unsupported LUKS hash: {0}")]
+    UnsupportedHash(String),
+    #[error("Unsupported LUKS encryption: {0}")]
+    UnsupportedEncryption(String),
+    #[error("Unsupported LUKS key size: {0}")]
+    UnsupportedKeySize(usize),
+    #[error("Failed deriving key with Argon2: {0}")]
+    Argon2(argon2::Error),
+    #[error("Failed reading LUKS key material: {0}")]
+    ReadKeyMaterial(#[source] io::Error),
+    #[error("No LUKS key slot can be unlocked with the passphrase")]
+    InvalidPassphrase,
+}
+
+pub type LuksResult<T> = std::result::Result<T, LuksError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn from_name(name: &str) -> LuksResult<Self> {
+        match name.to_lowercase().as_str() {
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            _ => Err(LuksError::UnsupportedHash(name.to_string())),
+        }
+    }
+
+    fn digest(self, data: &[&[u8]]) -> Vec<u8> {
+        fn digest<D: Digest>(data: &[&[u8]]) -> Vec<u8> {
+            let mut hasher = D::new();
+            for d in data {
+                hasher.update(d);
+            }
+            hasher.finalize().to_vec()
+        }
+
+        match self {
+            HashAlgorithm::Sha1 => digest::<sha1::Sha1>(data),
+            HashAlgorithm::Sha256 => digest::<sha2::Sha256>(data),
+            HashAlgorithm::Sha512 => digest::<sha2::Sha512>(data),
+        }
+    }
+
+    fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+        match self {
+            HashAlgorithm::Sha1 => {
+                pbkdf2::pbkdf2::<Hmac<sha1::Sha1>>(password, salt, iterations, out)
+            }
+            HashAlgorithm::Sha256 => {
+                pbkdf2::pbkdf2::<Hmac<sha2::Sha256>>(password, salt, iterations, out)
+            }
+            HashAlgorithm::Sha512 => {
+                pbkdf2::pbkdf2::<Hmac<sha2::Sha512>>(password, salt, iterations, out)
+            }
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+enum XtsCipher {
+    Aes128(Xts128<Aes128>),
+    Aes256(Xts128<Aes256>),
+}
+
+impl XtsCipher {
+    fn new(encryption: &str, key: &[u8]) -> LuksResult<Self> {
+        if encryption != "aes-xts-plain64" {
+            return Err(LuksError::UnsupportedEncryption(encryption.to_string()));
+        }
+
+        // The first half of the key encrypts the data, the second half the
+        // tweak.
+        let (data_key, tweak_key) = key.split_at(key.len() / 2);
+        match key.len() {
+            32 => Ok(XtsCipher::Aes128(Xts128::new(
+                Aes128::new(GenericArray::from_slice(data_key)),
+                Aes128::new(GenericArray::from_slice(tweak_key)),
+            ))),
+            64 => Ok(XtsCipher::Aes256(Xts128::new(
+                Aes256::new(GenericArray::from_slice(data_key)),
+                Aes256::new(GenericArray::from_slice(tweak_key)),
+            ))),
+            len => Err(LuksError::UnsupportedKeySize(len)),
+        }
+    }
+
+    fn encrypt(&self, data: &mut [u8], sector_size: u64, first_sector: u64) {
+        match self {
+            XtsCipher::Aes128(xts) => xts.encrypt_area(
+                data,
+                sector_size as usize,
+                first_sector as u128,
+                get_tweak_default,
+            ),
+            XtsCipher::Aes256(xts) => xts.encrypt_area(
+                data,
+                sector_size as usize,
+                first_sector as u128,
+                get_tweak_default,
+            ),
+        }
+    }
+
+    fn decrypt(&self, data: &mut [u8], sector_size: u64, first_sector: u64) {
+        match self {
+            XtsCipher::Aes128(xts) => xts.decrypt_area(
+                data,
+                sector_size as usize,
+                first_sector as u128,
+                get_tweak_default,
+            ),
+            XtsCipher::Aes256(xts) => xts.decrypt_area(
+                data,
+                sector_size as usize,
+                first_sector as u128,
+                get_tweak_default,
+            ),
+        }
+    }
+}
+
+enum Kdf {
+    Pbkdf2 {
+        hash: HashAlgorithm,
+        iterations: u32,
+        salt: Vec<u8>,
+    },
+    Argon2 {
+        algorithm: Algorithm,
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: Vec<u8>,
+    },
+}
+
+impl Kdf {
+    fn derive(&self, passphrase: &[u8], key_size: usize) -> LuksResult<Vec<u8>> {
+        let mut key = vec![0u8; key_size];
+        match self {
+            Kdf::Pbkdf2 {
+                hash,
+                iterations,
+                salt,
+            } => hash.pbkdf2(passphrase, salt, *iterations, &mut key),
+            Kdf::Argon2 {
+                algorithm,
+                time,
+                memory,
+                cpus,
+                salt,
+            } => {
+                let params = Params::new(*memory, *time, *cpus, Some(key_size))
+                    .map_err(LuksError::Argon2)?;
+                Argon2::new(*algorithm, Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, &mut key)
+                    .map_err(LuksError::Argon2)?;
+            }
+        }
+        Ok(key)
+    }
+}
+
+struct KeySlot {
+    id: String,
+    key_size: usize,
+    stripes: usize,
+    af_hash: HashAlgorithm,
+    area_offset: u64,
+    area_encryption: String,
+    area_key_size: usize,
+    kdf: Kdf,
+}
+
+impl KeySlot {
+    // Decrypts the volume key stored in the slot with the key derived from
+    // the passphrase.
+    fn unlock<F>(&self, read_at: &mut F, passphrase: &[u8]) -> LuksResult<Vec<u8>>
+    where
+        F: FnMut(u64, &mut [u8]) -> io::Result<()>,
+    {
+        let area_key = self.kdf.derive(passphrase, self.area_key_size)?;
+        let cipher = XtsCipher::new(&self.area_encryption, &area_key)?;
+
+        let material_size = self.key_size * self.stripes;
+        let mut material = vec![0u8; align_up(material_size as u64, LUKS_SECTOR_SIZE) as usize];
+        read_at(self.area_offset, &mut material).map_err(LuksError::ReadKeyMaterial)?;
+        cipher.decrypt(&mut material, LUKS_SECTOR_SIZE, 0);
+
+        Ok(af_merge(
+            &material[..material_size],
+            self.key_size,
+            self.af_hash,
+        ))
+    }
+}
+
+struct KeyDigest {
+    keyslots: Vec<String>,
+    hash: HashAlgorithm,
+    iterations: u32,
+    salt: Vec<u8>,
+    digest: Vec<u8>,
+}
+
+impl KeyDigest {
+    fn verify(&self, key: &[u8]) -> bool {
+        let mut digest = vec![0u8; self.digest.len()];
+        self.hash
+            .pbkdf2(key, &self.salt, self.iterations, &mut digest);
+        digest == self.digest
+    }
+}
+
+struct Segment {
+    offset: u64,
+    size: Option<u64>,
+    iv_tweak: u64,
+    encryption: String,
+    sector_size: u64,
+}
+
+struct LuksHeader {
+    keyslots: Vec<KeySlot>,
+    digests: Vec<KeyDigest>,
+    segment: Segment,
+}
+
+#[derive(Deserialize)]
+struct Luks2Metadata {
+    keyslots: BTreeMap<String, serde_json::Value>,
+    segments: BTreeMap<String, serde_json::Value>,
+    digests: BTreeMap<String, Luks2Digest>,
+}
+
+#[derive(Deserialize)]
+struct Luks2KeySlot {
+    key_size: usize,
+    #[serde(default)]
+    priority: Option<u32>,
+    af: Luks2AntiForensic,
+    area: Luks2Area,
+    kdf: Luks2Kdf,
+}
+
+#[derive(Deserialize)]
+struct Luks2AntiForensic {
+    stripes: usize,
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct Luks2Area {
+    offset: String,
+    encryption: String,
+    key_size: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Luks2Kdf {
+    Pbkdf2 {
+        hash: String,
+        iterations: u32,
+        salt: String,
+    },
+    Argon2i {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+    Argon2id {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct Luks2Segment {
+    offset: String,
+    size: String,
+    iv_tweak: String,
+    encryption: String,
+    sector_size: u64,
+}
+
+#[derive(Deserialize)]
+struct Luks2Digest {
+    #[serde(rename = "type")]
+    digest_type: String,
+    keyslots: Vec<String>,
+    segments: Vec<String>,
+    hash: String,
+    iterations: u32,
+    salt: String,
+    digest: String,
+}
+
+impl LuksHeader {
+    fn read<F>(read_at: &mut F) -> LuksResult<Self>
+    where
+        F: FnMut(u64, &mut [u8]) -> io::Result<()>,
+    {
+        let mut header = vec![0u8; LUKS2_BINARY_HEADER_SIZE];
+        read_at(0, &mut header).map_err(LuksError::ReadHeader)?;
+        if &header[..LUKS_MAGIC.len()] != LUKS_MAGIC {
+            return Err(LuksError::InvalidMagic);
+        }
+
+        match be_u16(&header, 6) {
+            1 => Self::parse_luks1(&header[..LUKS1_HEADER_SIZE]),
+            2 => {
+                // The secondary header is only used when the primary one is
+                // damaged.
+                let metadata = match Self::read_luks2_metadata(read_at, 0, header) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        warn!("Invalid primary LUKS2 header: {}", e);
+                        LUKS2_SECONDARY_HEADER_OFFSETS
+                            .iter()
+                            .find_map(|offset| {
+                                let mut header = vec![0u8; LUKS2_BINARY_HEADER_SIZE];
+                                read_at(*offset, &mut header).ok()?;
+                                Self::read_luks2_metadata(read_at, *offset, header).ok()
+                            })
+                            .ok_or(e)?
+                    }
+                };
+                Self::parse_luks2(&metadata)
+            }
+            version => Err(LuksError::UnsupportedVersion(version)),
+        }
+    }
+
+    fn parse_luks1(header: &[u8]) -> LuksResult<Self> {
+        let encryption = format!("{}-{}", c_string(&header[8..40]), c_string(&header[40..72]));
+        let hash = HashAlgorithm::from_name(&c_string(&header[72..104]))?;
+        let key_size = be_u32(header, 108) as usize;
+
+        let mut keyslots = Vec::new();
+        for i in 0..LUKS1_NUM_KEYS {
+            let slot =
+                &header[LUKS1_KEY_SLOT_OFFSET + i * LUKS1_KEY_SLOT_SIZE..][..LUKS1_KEY_SLOT_SIZE];
+            if be_u32(slot, 0) != LUKS1_KEY_ENABLED {
+                continue;
+            }
+            keyslots.push(KeySlot {
+                id: i.to_string(),
+                key_size,
+                stripes: be_u32(slot, 44) as usize,
+                af_hash: hash,
+                area_offset: be_u32(slot, 40) as u64 * LUKS_SECTOR_SIZE,
+                area_encryption: encryption.clone(),
+                area_key_size: key_size,
+                kdf: Kdf::Pbkdf2 {
+                    hash,
+                    iterations: be_u32(slot, 4),
+                    salt: slot[8..40].to_vec(),
+                },
+            });
+        }
+
+        let digest = KeyDigest {
+            keyslots: keyslots.iter().map(|k| k.id.clone()).collect(),
+            hash,
+            iterations: be_u32(header, 164),
+            salt: header[132..164].to_vec(),
+            digest: header[112..132].to_vec(),
+        };
+
+        Self::new(
+            keyslots,
+            vec![digest],
+            Segment {
+                offset: be_u32(header, 104) as u64 * LUKS_SECTOR_SIZE,
+                size: None,
+                iv_tweak: 0,
+                encryption,
+                sector_size: LUKS_SECTOR_SIZE,
+            },
+        )
+    }
+
+    // Returns the JSON metadata of the LUKS2 header whose binary header at
+    // `offset` is `header`, after checking the checksum of the whole.
+    fn read_luks2_metadata<F>(read_at: &mut F, offset: u64, header: Vec<u8>) -> LuksResult<Vec<u8>>
+    where
+        F: FnMut(u64, &mut [u8]) -> io::Result<()>,
+    {
+        let magic = if offset == 0 {
+            LUKS_MAGIC
+        } else {
+            LUKS2_SECONDARY_MAGIC
+        };
+        if &header[..magic.len()] != magic {
+            return Err(LuksError::InvalidMagic);
+        }
+        if be_u16(&header, 6) != 2 {
+            return Err(LuksError::UnsupportedVersion(be_u16(&header, 6)));
+        }
+        if be_u64(&header, 256) != offset {
+            return Err(LuksError::InvalidMetadata(format!(
+                "header found at {} instead of {}",
+                offset,
+                be_u64(&header, 256)
+            )));
+        }
+        let header_size = be_u64(&header, 8);
+        if header_size <= LUKS2_BINARY_HEADER_SIZE as u64 || header_size > LUKS2_MAX_HEADER_SIZE {
+            return Err(LuksError::InvalidMetadata(format!(
+                "invalid header size {}",
+                header_size
+            )));
+        }
+        let checksum_algorithm = c_string(&header[72..104]);
+        if checksum_algorithm != "sha256" {
+            return Err(LuksError::UnsupportedHash(checksum_algorithm));
+        }
+
+        let mut data = header;
+        data.resize(header_size as usize, 0);
+        read_at(
+            offset + LUKS2_BINARY_HEADER_SIZE as u64,
+            &mut data[LUKS2_BINARY_HEADER_SIZE..],
+        )
+        .map_err(LuksError::ReadHeader)?;
+
+        // The checksum covers the binary header and the JSON area, with the
+        // checksum field zeroed.
+        let checksum =
+            data[LUKS2_CHECKSUM_OFFSET..LUKS2_CHECKSUM_OFFSET + LUKS2_CHECKSUM_SIZE].to_vec();
+        data[LUKS2_CHECKSUM_OFFSET..LUKS2_CHECKSUM_OFFSET + LUKS2_CHECKSUM_SIZE].fill(0);
+        let digest = HashAlgorithm::Sha256.digest(&[&data]);
+        if checksum[..digest.len()] != digest[..] {
+            return Err(LuksError::InvalidChecksum);
+        }
+
+        let mut metadata = data.split_off(LUKS2_BINARY_HEADER_SIZE);
+        if let Some(end) = metadata.iter().position(|b| *b == 0) {
+            metadata.truncate(end);
+        }
+        Ok(metadata)
+    }
+
+    fn parse_luks2(metadata: &[u8]) -> LuksResult<Self> {
+        let metadata: Luks2Metadata =
+            serde_json::from_slice(metadata).map_err(LuksError::ParseMetadata)?;
+
+        // Only the images with a single encrypted segment are supported,
+        // which isn't the case while reencryption is in progress.
+        let segment = match (metadata.segments.len(), metadata.segments.get("0")) {
+            (1, Some(segment)) if segment["type"] == "crypt" => {
+                serde_json::from_value::<Luks2Segment>(segment.clone())
+                    .map_err(LuksError::ParseMetadata)?
+            }
+            _ => {
+                return Err(LuksError::InvalidMetadata(
+                    "expected a single crypt segment".to_string(),
+                ))
+            }
+        };
+
+        let mut keyslots = Vec::new();
+        for (id, keyslot) in metadata.keyslots.iter() {
+            if keyslot["type"] != "luks2" || keyslot["af"]["type"] != "luks1" {
+                continue;
+            }
+            let keyslot: Luks2KeySlot = match serde_json::from_value(keyslot.clone()) {
+                Ok(keyslot) => keyslot,
+                Err(e) => {
+                    warn!("Ignoring LUKS2 key slot {}: {}", id, e);
+                    continue;
+                }
+            };
+            // Slots with priority 0 are only used when explicitly requested.
+            if keyslot.priority == Some(0) {
+                continue;
+            }
+
+            let kdf = match keyslot.kdf {
+                Luks2Kdf::Pbkdf2 {
+                    hash,
+                    iterations,
+                    salt,
+                } => Kdf::Pbkdf2 {
+                    hash: HashAlgorithm::from_name(&hash)?,
+                    iterations,
+                    salt: decode_base64(&salt)?,
+                },
+                Luks2Kdf::Argon2i {
+                    time,
+                    memory,
+                    cpus,
+                    salt,
+                } => Kdf::Argon2 {
+                    algorithm: Algorithm::Argon2i,
+                    time,
+                    memory,
+                    cpus,
+                    salt: decode_base64(&salt)?,
+                },
+                Luks2Kdf::Argon2id {
+                    time,
+                    memory,
+                    cpus,
+                    salt,
+                } => Kdf::Argon2 {
+                    algorithm: Algorithm::Argon2id,
+                    time,
+                    memory,
+                    cpus,
+                    salt: decode_base64(&salt)?,
+                },
+            };
+
+            keyslots.push(KeySlot {
+                id: id.clone(),
+                key_size: keyslot.key_size,
+                stripes: keyslot.af.stripes,
+                af_hash: HashAlgorithm::from_name(&keyslot.af.hash)?,
+                area_offset: parse_u64(&keyslot.area.offset)?,
+                area_encryption: keyslot.area.encryption,
+                area_key_size: keyslot.area.key_size,
+                kdf,
+            });
+        }
+
+        let mut digests = Vec::new();
+        for digest in metadata.digests.into_values() {
+            if digest.digest_type != "pbkdf2" || !digest.segments.iter().any(|s| s == "0") {
+                continue;
+            }
+            digests.push(KeyDigest {
+                keyslots: digest.keyslots,
+                hash: HashAlgorithm::from_name(&digest.hash)?,
+                iterations: digest.iterations,
+                salt: decode_base64(&digest.salt)?,
+                digest: decode_base64(&digest.digest)?,
+            });
+        }
+
+        Self::new(
+            keyslots,
+            digests,
+            Segment {
+                offset: parse_u64(&segment.offset)?,
+                size: if segment.size == "dynamic" {
+                    None
+                } else {
+                    Some(parse_u64(&segment.size)?)
+                },
+                iv_tweak: parse_u64(&segment.iv_tweak)?,
+                encryption: segment.encryption,
+                sector_size: segment.sector_size,
+            },
+        )
+    }
+
+    fn new(keyslots: Vec<KeySlot>, digests: Vec<KeyDigest>, segment: Segment) -> LuksResult<Self> {
+        if !segment.sector_size.is_power_of_two()
+            || !(LUKS_SECTOR_SIZE..=4096).contains(&segment.sector_size)
+        {
+            return Err(LuksError::InvalidMetadata(format!(
+                "invalid sector size {}",
+                segment.sector_size
+            )));
+        }
+        if segment.offset % LUKS_SECTOR_SIZE != 0 {
+            return Err(LuksError::InvalidMetadata(format!(
+                "unaligned payload offset {}",
+                segment.offset
+            )));
+        }
+        if let Some(keyslot) = keyslots.iter().find(|k| k.stripes == 0 || k.key_size == 0) {
+            return Err(LuksError::InvalidMetadata(format!(
+                "invalid key slot {}",
+                keyslot.id
+            )));
+        }
+
+        Ok(LuksHeader {
+            keyslots,
+            digests,
+            segment,
+        })
+    }
+
+    // Returns the volume key, from the first key slot the passphrase unlocks.
+    fn unlock<F>(&self, read_at: &mut F, passphrase: &[u8]) -> LuksResult<Vec<u8>>
+    where
+        F: FnMut(u64, &mut [u8]) -> io::Result<()>,
+    {
+        for keyslot in self.keyslots.iter() {
+            let digests: Vec<&KeyDigest> = self
+                .digests
+                .iter()
+                .filter(|d| d.keyslots.contains(&keyslot.id))
+                .collect();
+            if digests.is_empty() {
+                continue;
+            }
+
+            let key = keyslot.unlock(read_at, passphrase)?;
+            if digests.iter().any(|d| d.verify(&key)) {
+                return Ok(key);
+            }
+        }
+
+        Err(LuksError::InvalidPassphrase)
+    }
+}
+
+/// Encryption of the payload of an unlocked LUKS image.
+pub struct LuksCrypt {
+    cipher: XtsCipher,
+    offset: u64,
+    size: Option<u64>,
+    sector_size: u64,
+    iv_tweak: u64,
+}
+
+impl LuksCrypt {
+    /// Reads the header of a LUKS image and unlocks the volume key with
+    /// `passphrase`. The image is read through `read_at`, filling the buffer
+    /// with the content of the image at the given offset.
+    pub fn open<F>(mut read_at: F, passphrase: &[u8]) -> LuksResult<Self>
+    where
+        F: FnMut(u64, &mut [u8]) -> io::Result<()>,
+    {
+        let header = LuksHeader::read(&mut read_at)?;
+        let key = header.unlock(&mut read_at, passphrase)?;
+        let segment = header.segment;
+
+        Ok(LuksCrypt {
+            cipher: XtsCipher::new(&segment.encryption, &key)?,
+            offset: segment.offset,
+            size: segment.size,
+            sector_size: segment.sector_size,
+            iv_tweak: segment.iv_tweak,
+        })
+    }
+
+    /// Returns the offset of the payload in the image.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the size of the payload of an image of `image_size` bytes.
+    pub fn size(&self, image_size: u64) -> u64 {
+        let available = image_size.saturating_sub(self.offset);
+        let size = self.size.map_or(available, |s| cmp::min(s, available));
+        size - size % self.sector_size
+    }
+
+    /// Returns whether the size of the payload is set in the header, instead
+    /// of following the size of the image.
+    pub fn has_fixed_size(&self) -> bool {
+        self.size.is_some()
+    }
+
+    /// Returns the size of the sectors the payload is encrypted by.
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    // Returns the IV of the payload sector at `offset`, counting the tweak
+    // in sectors of 512 bytes as dm-crypt does.
+    fn iv_sector(&self, offset: u64) -> u64 {
+        assert_eq!(offset % self.sector_size, 0);
+        (offset + self.iv_tweak * LUKS_SECTOR_SIZE) / self.sector_size
+    }
+
+    /// Encrypts in place the payload `data` at `offset`. Both the offset and
+    /// the length must be multiples of the sector size.
+    pub fn encrypt(&self, data: &mut [u8], offset: u64) {
+        assert_eq!(data.len() as u64 % self.sector_size, 0);
+        self.cipher
+            .encrypt(data, self.sector_size, self.iv_sector(offset))
+    }
+
+    /// Decrypts in place the payload `data` at `offset`. Both the offset and
+    /// the length must be multiples of the sector size.
+    pub fn decrypt(&self, data: &mut [u8], offset: u64) {
+        assert_eq!(data.len() as u64 % self.sector_size, 0);
+        self.cipher
+            .decrypt(data, self.sector_size, self.iv_sector(offset))
+    }
+}
+
+/// Reads the passphrase of a LUKS image from the file descriptor `fd`, which
+/// isn't closed. The whole content of the file is used, including a trailing
+/// newline, as cryptsetup does with `--key-file`. The file is read from the
+/// start so that the passphrase can be read again when the disk is reopened.
+pub fn read_key_fd(fd: RawFd) -> io::Result<Vec<u8>> {
+    // Safe because the file descriptor is valid, or the call fails.
+    let fd = unsafe { libc::dup(fd) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the file descriptor was just duplicated and is owned by
+    // the file.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    // Pipes can't be rewound, which is fine as long as they're read once.
+    let _ = file.seek(SeekFrom::Start(0));
+
+    let mut key = Vec::new();
+    file.read_to_end(&mut key)?;
+    Ok(key)
+}
+
+/// Synchronous access to the payload of a LUKS image.
+pub struct LuksFile<T: Read + Write + Seek> {
+    file: T,
+    crypt: LuksCrypt,
+    size: u64,
+    position: u64,
+}
+
+impl<T: Read + Write + Seek> LuksFile<T> {
+    pub fn new(mut file: T, passphrase: &[u8]) -> LuksResult<Self> {
+        let crypt = LuksCrypt::open(
+            |offset, buf| {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(buf)
+            },
+            passphrase,
+        )?;
+        let image_size = file.seek(SeekFrom::End(0)).map_err(LuksError::ReadHeader)?;
+        let size = crypt.size(image_size);
+
+        Ok(LuksFile {
+            file,
+            crypt,
+            size,
+            position: 0,
+        })
+    }
+
+    // Returns the number of bytes accessed next for a buffer of `len` bytes.
+    fn chunk_len(&self, len: usize) -> usize {
+        cmp::min(
+            cmp::min(len as u64, self.size - self.position),
+            LUKS_FILE_MAX_CHUNK_SIZE,
+        ) as usize
+    }
+
+    // Returns the offset of the sectors covering the next `len` bytes, along
+    // with their content, decrypted if `read` is set.
+    fn read_sectors(&mut self, len: usize, read: bool) -> io::Result<(u64, Vec<u8>)> {
+        let sector_size = self.crypt.sector_size();
+        let start = self.position - self.position % sector_size;
+        let end = align_up(self.position + len as u64, sector_size);
+
+        let mut data = vec![0u8; (end - start) as usize];
+        if read {
+            self.file
+                .seek(SeekFrom::Start(self.crypt.offset() + start))?;
+            self.file.read_exact(&mut data)?;
+            self.crypt.decrypt(&mut data, start);
+        }
+
+        Ok((start, data))
+    }
+}
+
+impl<T: Read + Write + Seek> Read for LuksFile<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+
+        let len = self.chunk_len(buf.len());
+        let (start, data) = self.read_sectors(len, true)?;
+        let skip = (self.position - start) as usize;
+        buf[..len].copy_from_slice(&data[skip..skip + len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<T: Read + Write + Seek> Write for LuksFile<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+
+        // The sectors partially written are read first.
+        let len = self.chunk_len(buf.len());
+        let sector_size = self.crypt.sector_size();
+        let partial =
+            self.position % sector_size != 0 || (self.position + len as u64) % sector_size != 0;
+        let (start, mut data) = self.read_sectors(len, partial)?;
+        let skip = (self.position - start) as usize;
+        data[skip..skip + len].copy_from_slice(&buf[..len]);
+
+        self.crypt.encrypt(&mut data, start);
+        self.file
+            .seek(SeekFrom::Start(self.crypt.offset() + start))?;
+        self.file.write_all(&data)?;
+        self.position += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<T: Read + Write + Seek> Seek for LuksFile<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::End(offset) => (self.size, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        let position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        self.position = position.ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        Ok(self.position)
+    }
+}
+
+impl<T: Read + Write + Seek> PunchHole for LuksFile<T> {
+    fn punch_hole(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        // A hole would read as garbage once decrypted.
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+}
+
+impl<T: Read + Write + Seek> WriteZeroesAt for LuksFile<T> {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        let length = cmp::min(length as u64, LUKS_FILE_MAX_CHUNK_SIZE) as usize;
+        self.seek(SeekFrom::Start(offset))?;
+        self.write(&vec![0u8; length])
+    }
+}
+
+// Recovers the key split into `stripes` by the anti-forensic splitter.
+fn af_merge(material: &[u8], key_size: usize, hash: HashAlgorithm) -> Vec<u8> {
+    let stripes = material.len() / key_size;
+    let mut key = vec![0u8; key_size];
+    for (i, stripe) in material.chunks_exact(key_size).enumerate() {
+        for (k, s) in key.iter_mut().zip(stripe) {
+            *k ^= s;
+        }
+        if i + 1 < stripes {
+            af_diffuse(&mut key, hash);
+        }
+    }
+    key
+}
+
+fn af_diffuse(data: &mut [u8], hash: HashAlgorithm) {
+    let digest_size = hash.digest(&[]).len();
+    for (i, block) in data.chunks_mut(digest_size).enumerate() {
+        let digest = hash.digest(&[&(i as u32).to_be_bytes(), block]);
+        let len = block.len();
+        block.copy_from_slice(&digest[..len]);
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+fn be_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn c_string(buf: &[u8]) -> String {
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+fn parse_u64(value: &str) -> LuksResult<u64> {
+    value
+        .parse()
+        .map_err(|_| LuksError::InvalidMetadata(format!("invalid number {}", value)))
+}
+
+fn decode_base64(value: &str) -> LuksResult<Vec<u8>> {
+    base64::decode(value)
+        .map_err(|e| LuksError::InvalidMetadata(format!("invalid base64 {}: {}", value, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PASSPHRASE: &[u8] = b"passphrase";
+    const PAYLOAD_SIZE: usize = 64 << 10;
+    const STRIPES: usize = 8;
+
+    fn pattern(len: usize, seed: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + seed * 13) as u8).collect()
+    }
+
+    // Splits `key` into stripes, the reverse of af_merge().
+    fn af_split(key: &[u8], hash: HashAlgorithm) -> Vec<u8> {
+        let mut material = Vec::new();
+        let mut merged = vec![0u8; key.len()];
+        for i in 0..STRIPES - 1 {
+            let stripe = pattern(key.len(), i);
+            for (m, s) in merged.iter_mut().zip(stripe.iter()) {
+                *m ^= s;
+            }
+            af_diffuse(&mut merged, hash);
+            material.extend_from_slice(&stripe);
+        }
+        material.extend(merged.iter().zip(key).map(|(m, k)| m ^ k));
+        material
+    }
+
+    // Returns the encrypted key material of a key slot unlocked by the key
+    // derived with `kdf`.
+    fn key_material(key: &[u8], kdf: &Kdf, passphrase: &[u8]) -> Vec<u8> {
+        let mut material = af_split(key, HashAlgorithm::Sha256);
+        material.resize(
+            align_up(material.len() as u64, LUKS_SECTOR_SIZE) as usize,
+            0,
+        );
+        let area_key = kdf.derive(passphrase, key.len()).unwrap();
+        XtsCipher::new("aes-xts-plain64", &area_key)
+            .unwrap()
+            .encrypt(&mut material, LUKS_SECTOR_SIZE, 0);
+        material
+    }
+
+    // Returns a LUKS1 image of `key`, with a key slot per passphrase.
+    fn luks1_image(key: &[u8], passphrases: &[&[u8]]) -> Vec<u8> {
+        let material_size = align_up((key.len() * STRIPES) as u64, 4096) as usize;
+        let payload_offset = 4096 + LUKS1_NUM_KEYS * material_size;
+        let mut image = vec![0u8; payload_offset + PAYLOAD_SIZE];
+
+        image[..6].copy_from_slice(LUKS_MAGIC);
+        image[6..8].copy_from_slice(&1u16.to_be_bytes());
+        image[8..11].copy_from_slice(b"aes");
+        image[40..51].copy_from_slice(b"xts-plain64");
+        image[72..78].copy_from_slice(b"sha256");
+        image[104..108].copy_from_slice(&((payload_offset / 512) as u32).to_be_bytes());
+        image[108..112].copy_from_slice(&(key.len() as u32).to_be_bytes());
+        let salt = pattern(32, 100);
+        let mut digest = [0u8; 20];
+        HashAlgorithm::Sha256.pbkdf2(key, &salt, 10, &mut digest);
+        image[112..132].copy_from_slice(&digest);
+        image[132..164].copy_from_slice(&salt);
+        image[164..168].copy_from_slice(&10u32.to_be_bytes());
+
+        for (i, passphrase) in passphrases.iter().enumerate() {
+            let slot = LUKS1_KEY_SLOT_OFFSET + i * LUKS1_KEY_SLOT_SIZE;
+            let salt = pattern(32, 200 + i);
+            let kdf = Kdf::Pbkdf2 {
+                hash: HashAlgorithm::Sha256,
+                iterations: 10,
+                salt: salt.clone(),
+            };
+            let material = key_material(key, &kdf, passphrase);
+            let material_offset = 4096 + i * material_size;
+            image[material_offset..material_offset + material.len()].copy_from_slice(&material);
+
+            image[slot..slot + 4].copy_from_slice(&LUKS1_KEY_ENABLED.to_be_bytes());
+            image[slot + 4..slot + 8].copy_from_slice(&10u32.to_be_bytes());
+            image[slot + 8..slot + 40].copy_from_slice(&salt);
+            image[slot + 40..slot + 44]
+                .copy_from_slice(&((material_offset / 512) as u32).to_be_bytes());
+            image[slot + 44..slot + 48].copy_from_slice(&(STRIPES as u32).to_be_bytes());
+        }
+
+        image
+    }
+
+    fn luks2_binary_header(metadata: &[u8], offset: u64) -> Vec<u8> {
+        let header_size = 0x4000;
+        let mut header = vec![0u8; header_size];
+        header[..6].copy_from_slice(if offset == 0 {
+            LUKS_MAGIC
+        } else {
+            LUKS2_SECONDARY_MAGIC
+        });
+        header[6..8].copy_from_slice(&2u16.to_be_bytes());
+        header[8..16].copy_from_slice(&(header_size as u64).to_be_bytes());
+        header[16..24].copy_from_slice(&1u64.to_be_bytes());
+        header[72..78].copy_from_slice(b"sha256");
+        header[256..264].copy_from_slice(&offset.to_be_bytes());
+        header[4096..4096 + metadata.len()].copy_from_slice(metadata);
+        let checksum = HashAlgorithm::Sha256.digest(&[&header]);
+        header[LUKS2_CHECKSUM_OFFSET..LUKS2_CHECKSUM_OFFSET + checksum.len()]
+            .copy_from_slice(&checksum);
+        header
+    }
+
+    // Returns a LUKS2 image of `key` with an Argon2id key slot.
+    fn luks2_image(key: &[u8], sector_size: u64) -> Vec<u8> {
+        let area_offset = 0x8000;
+        let payload_offset = 0x1_0000;
+        let salt = pattern(32, 300);
+        let kdf = Kdf::Argon2 {
+            algorithm: Algorithm::Argon2id,
+            time: 1,
+            memory: 32,
+            cpus: 1,
+            salt: salt.clone(),
+        };
+        let material = key_material(key, &kdf, PASSPHRASE);
+        let digest_salt = pattern(32, 400);
+        let mut digest = [0u8; 32];
+        HashAlgorithm::Sha256.pbkdf2(key, &digest_salt, 10, &mut digest);
+
+        let metadata = serde_json::json!({
+            "keyslots": {
+                "0": {
+                    "type": "luks2",
+                    "key_size": key.len(),
+                    "af": { "type": "luks1", "stripes": STRIPES, "hash": "sha256" },
+                    "area": {
+                        "type": "raw",
+                        "offset": area_offset.to_string(),
+                        "size": material.len().to_string(),
+                        "encryption": "aes-xts-plain64",
+                        "key_size": key.len(),
+                    },
+                    "kdf": {
+                        "type": "argon2id",
+                        "time": 1,
+                        "memory": 32,
+                        "cpus": 1,
+                        "salt": base64::encode(&salt),
+                    },
+                },
+            },
+            "tokens": {},
+            "segments": {
+                "0": {
+                    "type": "crypt",
+                    "offset": payload_offset.to_string(),
+                    "size": "dynamic",
+                    "iv_tweak": "0",
+                    "encryption": "aes-xts-plain64",
+                    "sector_size": sector_size,
+                },
+            },
+            "digests": {
+                "0": {
+                    "type": "pbkdf2",
+                    "keyslots": ["0"],
+                    "segments": ["0"],
+                    "hash": "sha256",
+                    "iterations": 10,
+                    "salt": base64::encode(&digest_salt),
+                    "digest": base64::encode(digest),
+                },
+            },
+            "config": { "json_size": "12288", "keyslots_size": "32768" },
+        })
+        .to_string();
+
+        let mut image = vec![0u8; payload_offset + PAYLOAD_SIZE];
+        let primary = luks2_binary_header(metadata.as_bytes(), 0);
+        image[..primary.len()].copy_from_slice(&primary);
+        let secondary = luks2_binary_header(metadata.as_bytes(), primary.len() as u64);
+        image[primary.len()..primary.len() + secondary.len()].copy_from_slice(&secondary);
+        image[area_offset..area_offset + material.len()].copy_from_slice(&material);
+
+        image
+    }
+
+    fn open(image: &[u8], passphrase: &[u8]) -> LuksResult<LuksCrypt> {
+        LuksCrypt::open(
+            |offset, buf| {
+                let offset = offset as usize;
+                buf.copy_from_slice(
+                    image
+                        .get(offset..offset + buf.len())
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                );
+                Ok(())
+            },
+            passphrase,
+        )
+    }
+
+    #[test]
+    fn test_xts_known_answer() {
+        // IEEE 1619 XTS-AES-128 vector 1.
+        let cipher = XtsCipher::new("aes-xts-plain64", &[0u8; 32]).unwrap();
+        let mut data = [0u8; 32];
+        cipher.encrypt(&mut data, 32, 0);
+        assert_eq!(
+            data[..],
+            [
+                0x91, 0x7c, 0xf6, 0x9e, 0xbd, 0x68, 0xb2, 0xec, 0x9b, 0x9f, 0xe9, 0xa3, 0xea, 0xdd,
+                0xa6, 0x92, 0xcd, 0x43, 0xd2, 0xf5, 0x95, 0x98, 0xed, 0x85, 0x8c, 0x02, 0xc2, 0x65,
+                0x2f, 0xbf, 0x92, 0x2e
+            ]
+        );
+
+        // The sector number is the plain64 IV.
+        let key: Vec<u8> = (0..64).collect();
+        let cipher = XtsCipher::new("aes-xts-plain64", &key).unwrap();
+        let plaintext: Vec<u8> = (0..=255).chain(0..=255).collect();
+        let mut data = plaintext.clone();
+        cipher.encrypt(&mut data, 512, 5);
+        assert_eq!(data[..8], [0xf8, 0x7c, 0xa2, 0xf2, 0x9b, 0x11, 0x7c, 0x1b]);
+        assert_eq!(
+            data[504..],
+            [0xca, 0xae, 0xf5, 0xe4, 0x57, 0xfe, 0xcc, 0x4b]
+        );
+        cipher.decrypt(&mut data, 512, 5);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn test_luks1_unlock() {
+        let key = pattern(64, 1);
+        let image = luks1_image(&key, &[b"other", PASSPHRASE]);
+
+        let crypt = open(&image, PASSPHRASE).unwrap();
+        assert_eq!(crypt.offset(), 4096 + 8 * 4096);
+        assert_eq!(crypt.sector_size(), 512);
+        assert_eq!(crypt.size(image.len() as u64 + 100), PAYLOAD_SIZE as u64);
+        assert!(!crypt.has_fixed_size());
+        assert!(open(&image, b"other").is_ok());
+        assert!(matches!(
+            open(&image, b"wrong"),
+            Err(LuksError::InvalidPassphrase)
+        ));
+
+        // The payload is encrypted with the volume key.
+        let plaintext = pattern(1024, 2);
+        let mut data = plaintext.clone();
+        crypt.encrypt(&mut data, 2048);
+        let mut expected = plaintext.clone();
+        XtsCipher::new("aes-xts-plain64", &key)
+            .unwrap()
+            .encrypt(&mut expected, 512, 4);
+        assert_eq!(data, expected);
+        crypt.decrypt(&mut data, 2048);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn test_luks2_unlock() {
+        let key = pattern(32, 3);
+        let mut image = luks2_image(&key, 4096);
+
+        let crypt = open(&image, PASSPHRASE).unwrap();
+        assert_eq!(crypt.offset(), 0x1_0000);
+        assert_eq!(crypt.sector_size(), 4096);
+        assert!(matches!(
+            open(&image, b"wrong"),
+            Err(LuksError::InvalidPassphrase)
+        ));
+
+        // The IV counts sectors of the segment sector size.
+        let plaintext = pattern(8192, 4);
+        let mut data = plaintext.clone();
+        crypt.encrypt(&mut data, 8192);
+        let mut expected = plaintext;
+        XtsCipher::new("aes-xts-plain64", &key)
+            .unwrap()
+            .encrypt(&mut expected, 4096, 2);
+        assert_eq!(data, expected);
+
+        // The secondary header is used when the primary one is damaged.
+        image[4096] ^= 1;
+        assert!(open(&image, PASSPHRASE).is_ok());
+        image[0x4000 + 4096] ^= 1;
+        assert!(matches!(
+            open(&image, PASSPHRASE),
+            Err(LuksError::InvalidChecksum)
+        ));
+    }
+
+    #[test]
+    fn test_luks_invalid_header() {
+        assert!(matches!(
+            open(&[0u8; 8192], PASSPHRASE),
+            Err(LuksError::InvalidMagic)
+        ));
+
+        let mut image = luks1_image(&pattern(64, 5), &[PASSPHRASE]);
+        image[40..51].copy_from_slice(b"cbc-essiv:s");
+        assert!(matches!(
+            open(&image, PASSPHRASE),
+            Err(LuksError::UnsupportedEncryption(_))
+        ));
+
+        let mut image = luks1_image(&pattern(64, 5), &[PASSPHRASE]);
+        image[6] = 3;
+        assert!(matches!(
+            open(&image, PASSPHRASE),
+            Err(LuksError::UnsupportedVersion(0x0301))
+        ));
+    }
+
+    #[test]
+    fn test_luks_file() {
+        let key = pattern(64, 6);
+        let image = luks2_image(&key, 4096);
+        let mut file = LuksFile::new(Cursor::new(image), PASSPHRASE).unwrap();
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), PAYLOAD_SIZE as u64);
+
+        let mut expected = vec![0u8; PAYLOAD_SIZE];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut expected).unwrap();
+
+        // Unaligned writes keep the rest of the sectors.
+        let data = pattern(10000, 7);
+        file.seek(SeekFrom::Start(3000)).unwrap();
+        file.write_all(&data).unwrap();
+        expected[3000..13000].copy_from_slice(&data);
+        file.write_all_zeroes_at(20000, 100).unwrap();
+        expected[20000..20100].fill(0);
+
+        let mut content = vec![0u8; PAYLOAD_SIZE];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut content).unwrap();
+        assert_eq!(content, expected);
+        assert_eq!(file.read(&mut content).unwrap(), 0);
+        assert!(file.punch_hole(0, 4096).is_err());
+
+        // The data is written encrypted.
+        let mut crypt = open(file.file.get_ref(), PASSPHRASE).unwrap();
+        let mut data = file.file.get_ref()[0x1_0000..].to_vec();
+        assert_ne!(data, expected);
+        crypt.decrypt(&mut data, 0);
+        assert_eq!(data, expected);
+        crypt.size = Some(4096);
+        assert!(crypt.has_fixed_size());
+        assert_eq!(crypt.size(1 << 30), 4096);
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{
+    wait_for_completion, AlignedBuffer, AsyncIo, AsyncIoError, AsyncIoResult, DiskFile,
+    DiskFileError, DiskFileResult, DiskTopology,
+};
+use crate::luks::{LuksCrypt, LuksError, LuksResult};
+use crate::mapped_async::{gather, iovecs_len, scatter};
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use vmm_sys_util::eventfd::EventFd;
+
+// Alignment of the header reads, suitable for O_DIRECT.
+const HEADER_READ_ALIGNMENT: u64 = 4096;
+
+/// LUKS image on top of a raw or QCOW2 disk, the guest seeing the decrypted
+/// payload.
+pub struct LuksDisk {
+    disk: Box<dyn DiskFile>,
+    crypt: Arc<LuksCrypt>,
+}
+
+impl LuksDisk {
+    pub fn new(disk: Box<dyn DiskFile>, passphrase: &[u8]) -> LuksResult<Self> {
+        let mut async_io = disk
+            .new_async_io(1)
+            .map_err(|e| LuksError::ReadHeader(io::Error::new(io::ErrorKind::Other, e)))?;
+        let crypt = LuksCrypt::open(
+            |offset, buf| read_at(async_io.as_mut(), offset, buf),
+            passphrase,
+        )?;
+
+        Ok(LuksDisk {
+            disk,
+            crypt: Arc::new(crypt),
+        })
+    }
+}
+
+// Reads `buf.len()` bytes at `offset` through a bounce buffer aligned for
+// O_DIRECT.
+fn read_at(async_io: &mut dyn AsyncIo, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let start = offset - offset % HEADER_READ_ALIGNMENT;
+    let end = offset + buf.len() as u64;
+    let len = ((end - start + HEADER_READ_ALIGNMENT - 1) / HEADER_READ_ALIGNMENT
+        * HEADER_READ_ALIGNMENT) as usize;
+
+    let mut buffer = AlignedBuffer::new(len)?;
+    let data = buffer.as_mut_slice(len);
+    let iovecs = vec![libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: len,
+    }];
+    async_io
+        .read_vectored(start as libc::off_t, iovecs, 0)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    // The end of the image isn't necessarily aligned.
+    if start + (wait_for_completion(async_io)? as u64) < end {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    let skip = (offset - start) as usize;
+    buf.copy_from_slice(&data[skip..skip + buf.len()]);
+    Ok(())
+}
+
+impl DiskFile for LuksDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.crypt.size(self.disk.size()?))
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(LuksAsyncIo {
+            async_io: self.disk.new_async_io(ring_depth)?,
+            crypt: self.crypt.clone(),
+            requests: HashMap::new(),
+        }) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        // The guest can't access less than an encryption sector.
+        let mut topology = self.disk.topology();
+        let sector_size = self.crypt.sector_size();
+        topology.logical_block_size = cmp::max(topology.logical_block_size, sector_size);
+        topology.physical_block_size =
+            cmp::max(topology.physical_block_size, topology.logical_block_size);
+        topology.minimum_io_size = cmp::max(topology.minimum_io_size, topology.logical_block_size);
+        topology
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> DiskFileResult<()> {
+        self.disk.create_snapshot(name)
+    }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        if self.crypt.has_fixed_size() {
+            return Err(DiskFileError::ResizeNotSupported);
+        }
+        self.disk.resize(size + self.crypt.offset())
+    }
+}
+
+struct LuksRequest {
+    buffer: AlignedBuffer,
+    offset: u64,
+    len: usize,
+    // Guest buffers the data read is decrypted to on completion, empty for
+    // writes.
+    iovecs: Vec<libc::iovec>,
+}
+
+// Safe because the buffers are only accessed from the AsyncIo methods, which
+// take a mutable reference.
+unsafe impl Send for LuksRequest {}
+unsafe impl Sync for LuksRequest {}
+
+pub struct LuksAsyncIo {
+    async_io: Box<dyn AsyncIo>,
+    crypt: Arc<LuksCrypt>,
+    requests: HashMap<u64, LuksRequest>,
+}
+
+impl LuksAsyncIo {
+    // Returns the request accessing `len` bytes of the payload at `offset`
+    // through a bounce buffer, along with the iovec describing the buffer.
+    fn new_request(&self, offset: u64, len: usize) -> io::Result<(LuksRequest, Vec<libc::iovec>)> {
+        let sector_size = self.crypt.sector_size();
+        if offset % sector_size != 0 || len as u64 % sector_size != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut buffer = AlignedBuffer::new(len)?;
+        let iovecs = vec![libc::iovec {
+            iov_base: buffer.as_mut_slice(len).as_mut_ptr() as *mut libc::c_void,
+            iov_len: len,
+        }];
+
+        Ok((
+            LuksRequest {
+                buffer,
+                offset,
+                len,
+                iovecs: Vec::new(),
+            },
+            iovecs,
+        ))
+    }
+}
+
+impl AsyncIo for LuksAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.async_io.notifier()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let len = iovecs_len(&iovecs) as usize;
+        if len == 0 {
+            return self.async_io.read_vectored(
+                offset + self.crypt.offset() as libc::off_t,
+                iovecs,
+                user_data,
+            );
+        }
+
+        let (mut request, buffer_iovecs) = self
+            .new_request(offset as u64, len)
+            .map_err(AsyncIoError::ReadVectored)?;
+        request.iovecs = iovecs;
+        self.async_io.read_vectored(
+            offset + self.crypt.offset() as libc::off_t,
+            buffer_iovecs,
+            user_data,
+        )?;
+        self.requests.insert(user_data, request);
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let len = iovecs_len(&iovecs) as usize;
+        if len == 0 {
+            return self.async_io.write_vectored(
+                offset + self.crypt.offset() as libc::off_t,
+                iovecs,
+                user_data,
+            );
+        }
+
+        let (mut request, buffer_iovecs) = self
+            .new_request(offset as u64, len)
+            .map_err(AsyncIoError::WriteVectored)?;
+        let data = request.buffer.as_mut_slice(len);
+        gather(&iovecs, data);
+        self.crypt.encrypt(data, offset as u64);
+        self.async_io.write_vectored(
+            offset + self.crypt.offset() as libc::off_t,
+            buffer_iovecs,
+            user_data,
+        )?;
+        self.requests.insert(user_data, request);
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.async_io.fsync(user_data)
+    }
+
+    fn punch_hole(
+        &mut self,
+        _offset: libc::off_t,
+        _length: u64,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        // A hole would read as garbage once decrypted.
+        Err(AsyncIoError::PunchHole(io::Error::from_raw_os_error(
+            libc::EOPNOTSUPP,
+        )))
+    }
+
+    fn write_zeroes(
+        &mut self,
+        _offset: libc::off_t,
+        _length: u64,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::WriteZeroes(io::Error::from_raw_os_error(
+            libc::EOPNOTSUPP,
+        )))
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        let completion_list = self.async_io.complete();
+        for (user_data, result) in completion_list.iter() {
+            if let Some(mut request) = self.requests.remove(user_data) {
+                if *result >= 0 && !request.iovecs.is_empty() {
+                    let data = request.buffer.as_mut_slice(request.len);
+                    self.crypt.decrypt(data, request.offset);
+                    scatter(data, &request.iovecs);
+                }
+            }
+        }
+        completion_list
+    }
+
+    fn reconnect_fd(&self) -> Option<RawFd> {
+        self.async_io.reconnect_fd()
+    }
+
+    fn reconnect(&mut self) {
+        self.async_io.reconnect()
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{AsyncIo, AsyncIoError, AsyncIoResult};
+use crate::AsyncAdaptor;
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+// Maximum number of iovecs a single io_uring operation can take
+const IOV_MAX: usize = 1024;
+
+/// Image formats able to tell where the guest data is stored in the image
+/// file, so that it can be accessed directly through io_uring. Data without a
+/// location in the file goes through the `Read` and `Write` implementations.
+pub trait MappedFile: Read + Write + Seek + PunchHole + WriteZeroesAt + AsRawFd + Send {
+    /// Returns the offset in the image file of the data at `offset`, along
+    /// with the number of bytes, up to `length`, stored contiguously from
+    /// there.
+    fn map_read(&mut self, offset: u64, length: u64) -> io::Result<(Option<u64>, u64)>;
+
+    /// Same as `map_read()`, for writing the data at `offset`. The image is
+    /// allocated as needed.
+    fn map_write(&mut self, offset: u64, length: u64) -> io::Result<(Option<u64>, u64)>;
+}
+
+// Guest request split into several io_uring operations
+struct PendingRequest {
+    user_data: u64,
+    pending: usize,
+    result: i32,
+}
+
+pub struct MappedFileAsync<F: MappedFile> {
+    file: Arc<Mutex<F>>,
+    fd: RawFd,
+    io_uring: IoUring,
+    eventfd: EventFd,
+    requests: HashMap<u64, PendingRequest>,
+    next_request_id: u64,
+    completion_list: Vec<(u64, i32)>,
+}
+
+impl<F: MappedFile> MappedFileAsync<F> {
+    pub fn new(file: Arc<Mutex<F>>, ring_depth: u32) -> std::io::Result<Self> {
+        let fd = file.lock().unwrap().as_raw_fd();
+        let io_uring = IoUring::new(ring_depth)?;
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK)?;
+
+        // Register the io_uring eventfd that will notify when something in
+        // the completion queue is ready.
+        io_uring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        Ok(MappedFileAsync {
+            file,
+            fd,
+            io_uring,
+            eventfd,
+            requests: HashMap::new(),
+            next_request_id: 0,
+            completion_list: Vec::new(),
+        })
+    }
+
+    // Split the request into operations on ranges of the image file, which
+    // are returned. The data without location is copied synchronously.
+    fn map(
+        &mut self,
+        offset: u64,
+        iovecs: &[libc::iovec],
+        write: bool,
+    ) -> io::Result<Vec<(u64, Vec<libc::iovec>)>> {
+        let length = iovecs_len(iovecs);
+        let mut operations: Vec<(u64, Vec<libc::iovec>)> = Vec::new();
+        let mut contiguous = false;
+        let mut file = self.file.lock().unwrap();
+
+        let mut done = 0;
+        while done < length {
+            let (file_offset, count) = if write {
+                file.map_write(offset + done, length - done)?
+            } else {
+                file.map_read(offset + done, length - done)?
+            };
+            if count == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            let slices = slice_iovecs(iovecs, done, count);
+
+            match file_offset {
+                Some(file_offset) => {
+                    match operations.last_mut() {
+                        Some((last_offset, last_iovecs))
+                            if contiguous
+                                && *last_offset + iovecs_len(last_iovecs) == file_offset
+                                && last_iovecs.len() + slices.len() <= IOV_MAX =>
+                        {
+                            append_iovecs(last_iovecs, slices)
+                        }
+                        _ => operations.push((file_offset, slices)),
+                    }
+                    contiguous = true;
+                }
+                None => {
+                    // Go through a bounce buffer, the image formats expect
+                    // whole sectors.
+                    let mut buffer = vec![0u8; count as usize];
+                    file.seek(SeekFrom::Start(offset + done))?;
+                    if write {
+                        gather(&slices, &mut buffer);
+                        file.write_all(&buffer)?;
+                    } else {
+                        file.read_exact(&mut buffer)?;
+                        scatter(&buffer, &slices);
+                    }
+                    contiguous = false;
+                }
+            }
+
+            done += count;
+        }
+
+        Ok(operations)
+    }
+
+    fn submit(
+        &mut self,
+        operations: Vec<(u64, Vec<libc::iovec>)>,
+        result: i32,
+        user_data: u64,
+        write: bool,
+    ) -> io::Result<()> {
+        if operations.is_empty() {
+            self.completion_list.push((user_data, result));
+            self.eventfd.write(1)?;
+            return Ok(());
+        }
+
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.requests.insert(
+            request_id,
+            PendingRequest {
+                user_data,
+                pending: operations.len(),
+                result,
+            },
+        );
+
+        let (submitter, mut sq, _) = self.io_uring.split();
+        for (file_offset, iovecs) in operations.iter() {
+            let entry = if write {
+                opcode::Writev::new(types::Fd(self.fd), iovecs.as_ptr(), iovecs.len() as u32)
+                    .offset(*file_offset as libc::off_t)
+                    .build()
+            } else {
+                opcode::Readv::new(types::Fd(self.fd), iovecs.as_ptr(), iovecs.len() as u32)
+                    .offset(*file_offset as libc::off_t)
+                    .build()
+            }
+            .flags(squeue::Flags::ASYNC)
+            .user_data(request_id);
+
+            // Safe because we know the file descriptor is valid and we
+            // relied on vm-memory to provide the buffer address.
+            while unsafe { sq.push(&entry) }.is_err() {
+                // The submission queue is full, submit the operations
+                // already queued to make room.
+                sq.sync();
+                submitter.submit()?;
+                sq.sync();
+            }
+        }
+
+        // Update the submission queue and submit new operations to the
+        // io_uring instance.
+        sq.sync();
+        submitter.submit()?;
+
+        Ok(())
+    }
+}
+
+impl<F: MappedFile> AsyncIo for MappedFileAsync<F>
+where
+    Arc<Mutex<F>>: AsyncAdaptor<F>,
+{
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let operations = self
+            .map(offset as u64, &iovecs, false)
+            .map_err(AsyncIoError::ReadVectored)?;
+        self.submit(operations, iovecs_len(&iovecs) as i32, user_data, false)
+            .map_err(AsyncIoError::ReadVectored)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let operations = self
+            .map(offset as u64, &iovecs, true)
+            .map_err(AsyncIoError::WriteVectored)?;
+        self.submit(operations, iovecs_len(&iovecs) as i32, user_data, true)
+            .map_err(AsyncIoError::WriteVectored)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        // The metadata cached by the image must be written as well, which
+        // can't be done through io_uring.
+        self.file
+            .fsync_sync(user_data, &self.eventfd, &mut self.completion_list)
+    }
+
+    fn punch_hole(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.file.punch_hole_sync(
+            offset,
+            length,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.file.write_zeroes_sync(
+            offset,
+            length,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        let mut completion_list: Vec<(u64, i32)> = self.completion_list.drain(..).collect();
+
+        let cq = self.io_uring.completion();
+        for cq_entry in cq {
+            let request_id = cq_entry.user_data();
+            if let Some(request) = self.requests.get_mut(&request_id) {
+                request.pending -= 1;
+                if cq_entry.result() < 0 {
+                    request.result = cq_entry.result();
+                }
+                if request.pending == 0 {
+                    completion_list.push((request.user_data, request.result));
+                    self.requests.remove(&request_id);
+                }
+            }
+        }
+
+        completion_list
+    }
+}
+
+pub(crate) fn iovecs_len(iovecs: &[libc::iovec]) -> u64 {
+    iovecs.iter().map(|iovec| iovec.iov_len as u64).sum()
+}
+
+// Returns the iovecs covering `length` bytes from `offset` of the buffers
+// described by `iovecs`.
+pub(crate) fn slice_iovecs(
+    iovecs: &[libc::iovec],
+    mut offset: u64,
+    mut length: u64,
+) -> Vec<libc::iovec> {
+    let mut slices = Vec::new();
+    for iovec in iovecs {
+        if length == 0 {
+            break;
+        }
+        if offset >= iovec.iov_len as u64 {
+            offset -= iovec.iov_len as u64;
+            continue;
+        }
+
+        let count = std::cmp::min(iovec.iov_len as u64 - offset, length);
+        slices.push(libc::iovec {
+            // Safe because the offset is within the buffer.
+            iov_base: unsafe { (iovec.iov_base as *mut u8).add(offset as usize) }
+                as *mut libc::c_void,
+            iov_len: count as usize,
+        });
+        offset = 0;
+        length -= count;
+    }
+
+    slices
+}
+
+// Appends `slices` to `iovecs`, merging the buffers which are contiguous.
+fn append_iovecs(iovecs: &mut Vec<libc::iovec>, slices: Vec<libc::iovec>) {
+    for slice in slices {
+        match iovecs.last_mut() {
+            Some(last) if last.iov_base as usize + last.iov_len == slice.iov_base as usize => {
+                last.iov_len += slice.iov_len
+            }
+            _ => iovecs.push(slice),
+        }
+    }
+}
+
+pub(crate) fn gather(iovecs: &[libc::iovec], buffer: &mut [u8]) {
+    let mut offset = 0;
+    for iovec in iovecs {
+        // Safe because we relied on vm-memory to provide the buffer address.
+        let slice =
+            unsafe { std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len) };
+        buffer[offset..offset + iovec.iov_len].copy_from_slice(slice);
+        offset += iovec.iov_len;
+    }
+}
+
+pub(crate) fn scatter(buffer: &[u8], iovecs: &[libc::iovec]) {
+    let mut offset = 0;
+    for iovec in iovecs {
+        // Safe because we relied on vm-memory to provide the buffer address.
+        let slice =
+            unsafe { std::slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len) };
+        slice.copy_from_slice(&buffer[offset..offset + iovec.iov_len]);
+        offset += iovec.iov_len;
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A disk mirror copies the content of a disk to another image while the
+//! guest keeps running. The writes completed by the guest are replayed on the
+//! destination, so that it stays in sync with the disk once the copy is done.
+//!
+//! The destination is locked while a chunk is copied, from the moment it is
+//! read from the disk until it's written. A guest write completed meanwhile is
+//! replayed after the chunk, overwriting the content the copy may have read
+//! before the write landed.
+
+use crate::async_io::{wait_for_completion, AlignedBuffer, AsyncIo};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Size of the chunks the disk is copied by.
+pub const MIRROR_CHUNK_SIZE: u64 = 1 << 20;
+
+pub struct DiskMirror {
+    destination: Mutex<Box<dyn AsyncIo>>,
+    size: u64,
+    copied: AtomicU64,
+    ready: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+impl DiskMirror {
+    /// Creates a mirror of a disk of `size` bytes to the `destination` image,
+    /// which must be zeroed.
+    pub fn new(destination: Box<dyn AsyncIo>, size: u64) -> Self {
+        DiskMirror {
+            destination: Mutex::new(destination),
+            size,
+            copied: AtomicU64::new(0),
+            ready: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the number of bytes copied so far.
+    pub fn copied(&self) -> u64 {
+        self.copied.load(Ordering::Acquire)
+    }
+
+    /// Returns whether the copy completed, the destination being in sync with
+    /// the disk.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Release)
+    }
+
+    /// Stops the copy, which returns with an `Interrupted` error.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Copies the disk read through `source` to the destination. Zeroed
+    /// chunks are skipped since the destination starts zeroed.
+    pub fn copy(&self, source: &mut dyn AsyncIo) -> io::Result<()> {
+        let mut buffer = AlignedBuffer::new(MIRROR_CHUNK_SIZE as usize)?;
+        let mut offset = 0;
+        while offset < self.size {
+            if self.is_cancelled() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "Disk mirror cancelled",
+                ));
+            }
+
+            let count = std::cmp::min(self.size - offset, MIRROR_CHUNK_SIZE) as usize;
+            let buf = buffer.as_mut_slice(count);
+            let iovecs = vec![libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: count,
+            }];
+
+            let mut destination = self.destination.lock().unwrap();
+            source
+                .read_vectored(offset as libc::off_t, iovecs.clone(), 0)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            if wait_for_completion(source)? as usize != count {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            if buf.iter().any(|b| *b != 0) {
+                write_destination(destination.as_mut(), offset, iovecs)?;
+            }
+            drop(destination);
+
+            offset += count as u64;
+            self.copied.store(offset, Ordering::Release);
+        }
+
+        self.flush()
+    }
+
+    /// Replays a guest write of the buffers described by `iovecs` at
+    /// `offset`.
+    pub fn write(&self, offset: u64, iovecs: Vec<libc::iovec>) -> io::Result<()> {
+        write_destination(self.destination.lock().unwrap().as_mut(), offset, iovecs)
+    }
+
+    /// Replays the zeroing of `length` bytes at `offset`, either discarded or
+    /// explicitly zeroed by the guest.
+    pub fn write_zeroes(&self, offset: u64, length: u64) -> io::Result<()> {
+        let mut destination = self.destination.lock().unwrap();
+        destination
+            .write_zeroes(offset as libc::off_t, length, 0)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        wait_for_completion(destination.as_mut()).map(|_| ())
+    }
+
+    /// Flushes the writes made to the destination to the storage.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut destination = self.destination.lock().unwrap();
+        destination
+            .fsync(Some(0))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        wait_for_completion(destination.as_mut()).map(|_| ())
+    }
+}
+
+fn write_destination(
+    destination: &mut dyn AsyncIo,
+    offset: u64,
+    iovecs: Vec<libc::iovec>,
+) -> io::Result<()> {
+    let length: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+    destination
+        .write_vectored(offset as libc::off_t, iovecs, 0)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    if wait_for_completion(destination)? as usize != length {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "Short write to the mirror destination",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_sync::RawFileSync;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_disk_mirror() {
+        let source_file = TempFile::new().unwrap().into_file();
+        source_file.set_len(0x28_0000).unwrap();
+        source_file
+            .write_all_at(&[0xaa; 0x1000], 0x10_0000)
+            .unwrap();
+        source_file.write_all_at(&[0x55; 0x200], 0x27_fe00).unwrap();
+        let destination_file = TempFile::new().unwrap().into_file();
+        destination_file.set_len(0x28_0000).unwrap();
+
+        let mirror = DiskMirror::new(
+            Box::new(RawFileSync::new(destination_file.as_raw_fd())),
+            0x28_0000,
+        );
+        let mut source = RawFileSync::new(source_file.as_raw_fd());
+        mirror.copy(&mut source).unwrap();
+        assert_eq!(mirror.copied(), 0x28_0000);
+
+        // Guest writes are replayed on the destination.
+        let mut buf = [0x33u8; 0x400];
+        mirror
+            .write(
+                0x800,
+                vec![libc::iovec {
+                    iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: buf.len(),
+                }],
+            )
+            .unwrap();
+        mirror.write_zeroes(0x10_0000, 0x800).unwrap();
+        mirror.flush().unwrap();
+
+        let mut content = vec![0u8; 0x28_0000];
+        destination_file.read_exact_at(&mut content, 0).unwrap();
+        assert!(content[..0x800].iter().all(|b| *b == 0));
+        assert!(content[0x800..0xc00].iter().all(|b| *b == 0x33));
+        assert!(content[0xc00..0x10_0800].iter().all(|b| *b == 0));
+        assert!(content[0x10_0800..0x10_1000].iter().all(|b| *b == 0xaa));
+        assert!(content[0x10_1000..0x27_fe00].iter().all(|b| *b == 0));
+        assert!(content[0x27_fe00..].iter().all(|b| *b == 0x55));
+
+        // A cancelled mirror stops copying.
+        mirror.cancel();
+        assert_eq!(
+            mirror.copy(&mut source).unwrap_err().kind(),
+            io::ErrorKind::Interrupted
+        );
+    }
+}
@@ -0,0 +1,1043 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client side of the NBD protocol, as described in
+//! https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+//!
+//! Only the fixed newstyle negotiation is supported. Structured replies are
+//! used when the server supports them. Requests are sent one at a time. They
+//! fail once the connection is lost, until it is established again.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+const NBD_INIT_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+// Handshake flags
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Options and their replies
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+const NBD_INFO_EXPORT: u16 = 0;
+const NBD_INFO_BLOCK_SIZE: u16 = 3;
+// Upper bound of the option replies accepted from the server.
+const NBD_MAX_OPTION_REPLY: u32 = 1 << 16;
+
+// Transmission flags
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+pub const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+// Commands
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+
+// Structured reply chunks
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+const NBD_REPLY_TYPE_ERROR_OFFSET: u16 = (1 << 15) | 2;
+
+/// Payload size the server must accept when it doesn't advertise any.
+const NBD_DEFAULT_MAX_PAYLOAD: u32 = 32 << 20;
+/// Delay between the attempts to connect again to the server.
+pub const NBD_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum NbdError {
+    #[error("Invalid NBD server address {0}, expected unix:<path> or tcp:<host>:<port>")]
+    InvalidAddress(String),
+    #[error("Failed resolving NBD server address {0}: {1}")]
+    ResolveAddress(String, #[source] io::Error),
+    #[error("Failed connecting to the NBD server: {0}")]
+    Connect(#[source] io::Error),
+    #[error("Failed negotiating with the NBD server: {0}")]
+    Negotiate(#[source] io::Error),
+    #[error("NBD server rejected export {0:?}: {1}")]
+    ExportRejected(String, String),
+}
+
+pub type NbdResult<T> = std::result::Result<T, NbdError>;
+
+/// Address of an NBD server, either `unix:<path>` or `tcp:<host>:<port>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NbdAddress {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl NbdAddress {
+    /// Parses the address, resolving the host name of TCP servers.
+    pub fn parse(address: &str) -> NbdResult<Self> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(NbdError::InvalidAddress(address.to_owned()));
+            }
+            Ok(NbdAddress::Unix(PathBuf::from(path)))
+        } else if let Some(host) = address.strip_prefix("tcp:") {
+            host.to_socket_addrs()
+                .map_err(|e| NbdError::ResolveAddress(host.to_owned(), e))?
+                .next()
+                .map(NbdAddress::Tcp)
+                .ok_or_else(|| {
+                    NbdError::ResolveAddress(
+                        host.to_owned(),
+                        io::Error::from(io::ErrorKind::NotFound),
+                    )
+                })
+        } else {
+            Err(NbdError::InvalidAddress(address.to_owned()))
+        }
+    }
+}
+
+impl fmt::Display for NbdAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NbdAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+            NbdAddress::Tcp(address) => write!(f, "tcp:{}", address),
+        }
+    }
+}
+
+enum NbdStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl NbdStream {
+    fn connect(address: &NbdAddress) -> io::Result<Self> {
+        match address {
+            NbdAddress::Unix(path) => Ok(NbdStream::Unix(UnixStream::connect(path)?)),
+            NbdAddress::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                Ok(NbdStream::Tcp(stream))
+            }
+        }
+    }
+}
+
+impl Read for NbdStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            NbdStream::Unix(stream) => stream.read(buf),
+            NbdStream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NbdStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            NbdStream::Unix(stream) => stream.write(buf),
+            NbdStream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            NbdStream::Unix(stream) => stream.flush(),
+            NbdStream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16(reader: &mut dyn Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(reader: &mut dyn Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn skip(reader: &mut dyn Read, length: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(length), &mut io::sink())?;
+    if skipped != length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    Ok(())
+}
+
+/// Properties of an export, as advertised by the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NbdExportInfo {
+    pub size: u64,
+    pub flags: u16,
+    pub min_block_size: u32,
+    pub preferred_block_size: u32,
+    pub max_payload: u32,
+}
+
+impl NbdExportInfo {
+    pub fn can_multi_conn(&self) -> bool {
+        self.flags & NBD_FLAG_CAN_MULTI_CONN != 0
+    }
+}
+
+struct Negotiated {
+    stream: NbdStream,
+    info: NbdExportInfo,
+    structured_replies: bool,
+}
+
+fn send_option(stream: &mut NbdStream, option: u32, data: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(16 + data.len());
+    buf.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)
+}
+
+// Reads an option reply, returning its type and data.
+fn read_option_reply(stream: &mut NbdStream, option: u32) -> io::Result<(u32, Vec<u8>)> {
+    if read_u64(stream)? != NBD_REP_MAGIC {
+        return Err(protocol_error("Invalid option reply magic".to_owned()));
+    }
+    if read_u32(stream)? != option {
+        return Err(protocol_error(format!(
+            "Option reply doesn't match option {}",
+            option
+        )));
+    }
+    let reply_type = read_u32(stream)?;
+    let length = read_u32(stream)?;
+    if length > NBD_MAX_OPTION_REPLY {
+        return Err(protocol_error(format!(
+            "Option reply of {} bytes is too large",
+            length
+        )));
+    }
+    let mut data = vec![0u8; length as usize];
+    stream.read_exact(&mut data)?;
+
+    Ok((reply_type, data))
+}
+
+fn option_error(reply_type: u32, data: &[u8]) -> String {
+    format!(
+        "error {:#x}: {}",
+        reply_type,
+        String::from_utf8_lossy(data).trim_end()
+    )
+}
+
+// Selects the export with NBD_OPT_GO. Returns None if the server doesn't
+// support it.
+fn go(stream: &mut NbdStream, export: &str) -> NbdResult<Option<NbdExportInfo>> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(export.len() as u32).to_be_bytes());
+    data.extend_from_slice(export.as_bytes());
+    data.extend_from_slice(&1u16.to_be_bytes());
+    data.extend_from_slice(&NBD_INFO_BLOCK_SIZE.to_be_bytes());
+    send_option(stream, NBD_OPT_GO, &data).map_err(NbdError::Negotiate)?;
+
+    let mut info = NbdExportInfo::default();
+    let mut has_export_info = false;
+    loop {
+        let (reply_type, data) =
+            read_option_reply(stream, NBD_OPT_GO).map_err(NbdError::Negotiate)?;
+        match reply_type {
+            NBD_REP_ACK => break,
+            NBD_REP_INFO if data.len() >= 2 => {
+                let info_type = u16::from_be_bytes(data[0..2].try_into().unwrap());
+                if info_type == NBD_INFO_EXPORT && data.len() >= 12 {
+                    info.size = u64::from_be_bytes(data[2..10].try_into().unwrap());
+                    info.flags = u16::from_be_bytes(data[10..12].try_into().unwrap());
+                    has_export_info = true;
+                } else if info_type == NBD_INFO_BLOCK_SIZE && data.len() >= 14 {
+                    info.min_block_size = u32::from_be_bytes(data[2..6].try_into().unwrap());
+                    info.preferred_block_size = u32::from_be_bytes(data[6..10].try_into().unwrap());
+                    info.max_payload = u32::from_be_bytes(data[10..14].try_into().unwrap());
+                }
+            }
+            NBD_REP_ERR_UNSUP => return Ok(None),
+            reply_type if reply_type & NBD_REP_FLAG_ERROR != 0 => {
+                return Err(NbdError::ExportRejected(
+                    export.to_owned(),
+                    option_error(reply_type, &data),
+                ))
+            }
+            // Replies the client doesn't know about are ignored.
+            _ => {}
+        }
+    }
+
+    if !has_export_info {
+        return Err(NbdError::Negotiate(protocol_error(
+            "Server didn't describe the export".to_owned(),
+        )));
+    }
+
+    Ok(Some(info))
+}
+
+fn negotiate(address: &NbdAddress, export: &str) -> NbdResult<Negotiated> {
+    let mut stream = NbdStream::connect(address).map_err(NbdError::Connect)?;
+
+    if read_u64(&mut stream).map_err(NbdError::Negotiate)? != NBD_INIT_MAGIC
+        || read_u64(&mut stream).map_err(NbdError::Negotiate)? != NBD_OPTS_MAGIC
+    {
+        return Err(NbdError::Negotiate(protocol_error(
+            "Server doesn't support the newstyle negotiation".to_owned(),
+        )));
+    }
+    let handshake_flags = read_u16(&mut stream).map_err(NbdError::Negotiate)?;
+    if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(NbdError::Negotiate(protocol_error(
+            "Server doesn't support the fixed newstyle negotiation".to_owned(),
+        )));
+    }
+    let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    stream
+        .write_all(&client_flags.to_be_bytes())
+        .map_err(NbdError::Negotiate)?;
+
+    send_option(&mut stream, NBD_OPT_STRUCTURED_REPLY, &[]).map_err(NbdError::Negotiate)?;
+    let (reply_type, _) =
+        read_option_reply(&mut stream, NBD_OPT_STRUCTURED_REPLY).map_err(NbdError::Negotiate)?;
+    let structured_replies = reply_type == NBD_REP_ACK;
+
+    let mut info = match go(&mut stream, export)? {
+        Some(info) => info,
+        None => {
+            // Servers not supporting NBD_OPT_GO close the connection when
+            // the export doesn't exist.
+            send_option(&mut stream, NBD_OPT_EXPORT_NAME, export.as_bytes())
+                .map_err(NbdError::Negotiate)?;
+            let info = NbdExportInfo {
+                size: read_u64(&mut stream)
+                    .map_err(|e| NbdError::ExportRejected(export.to_owned(), e.to_string()))?,
+                flags: read_u16(&mut stream).map_err(NbdError::Negotiate)?,
+                ..Default::default()
+            };
+            if !no_zeroes {
+                skip(&mut stream, 124).map_err(NbdError::Negotiate)?;
+            }
+            info
+        }
+    };
+    if info.max_payload == 0 {
+        info.max_payload = NBD_DEFAULT_MAX_PAYLOAD;
+    }
+
+    Ok(Negotiated {
+        stream,
+        info,
+        structured_replies,
+    })
+}
+
+// Calls `f` on the slices of the buffers described by `iovecs`, covering
+// `length` bytes from `offset` within these buffers.
+fn for_each_iovec_slice(
+    iovecs: &[libc::iovec],
+    mut offset: usize,
+    mut length: usize,
+    mut f: impl FnMut(&mut [u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    for iovec in iovecs {
+        if length == 0 {
+            break;
+        }
+        if offset >= iovec.iov_len {
+            offset -= iovec.iov_len;
+            continue;
+        }
+
+        let count = std::cmp::min(iovec.iov_len - offset, length);
+        // Safe because the iovecs describe buffers valid for the duration of
+        // the request, and the slice is within the bounds of this one.
+        let slice = unsafe {
+            std::slice::from_raw_parts_mut((iovec.iov_base as *mut u8).add(offset), count)
+        };
+        f(slice)?;
+        offset = 0;
+        length -= count;
+    }
+
+    if length != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Request larger than its buffers",
+        ));
+    }
+
+    Ok(())
+}
+
+struct NbdRequest<'a> {
+    command: u16,
+    offset: u64,
+    length: u32,
+    // Buffers the data is read into or written from, and the offset of the
+    // request data within them.
+    buffers: Option<(&'a [libc::iovec], usize)>,
+}
+
+/// Connection to an export, which can be established again once it failed.
+pub struct NbdConnection {
+    address: NbdAddress,
+    export: String,
+    stream: Option<NbdStream>,
+    info: NbdExportInfo,
+    structured_replies: bool,
+    handle: u64,
+}
+
+impl NbdConnection {
+    pub fn connect(address: &NbdAddress, export: &str) -> NbdResult<Self> {
+        let negotiated = negotiate(address, export)?;
+        Ok(NbdConnection {
+            address: address.clone(),
+            export: export.to_owned(),
+            stream: Some(negotiated.stream),
+            info: negotiated.info,
+            structured_replies: negotiated.structured_replies,
+            handle: 0,
+        })
+    }
+
+    pub fn info(&self) -> NbdExportInfo {
+        self.info
+    }
+
+    /// Returns whether the connection is established. Requests fail until
+    /// `reconnect()` succeeds once the connection was lost.
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Reads into the buffers described by `iovecs` from `offset`.
+    pub fn read(&mut self, offset: u64, iovecs: &[libc::iovec]) -> io::Result<()> {
+        self.transfer(NBD_CMD_READ, offset, iovecs)
+    }
+
+    /// Writes the buffers described by `iovecs` at `offset`.
+    pub fn write(&mut self, offset: u64, iovecs: &[libc::iovec]) -> io::Result<()> {
+        self.transfer(NBD_CMD_WRITE, offset, iovecs)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.info.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+
+        self.command(&NbdRequest {
+            command: NBD_CMD_FLUSH,
+            offset: 0,
+            length: 0,
+            buffers: None,
+        })
+    }
+
+    /// Discards `length` bytes at `offset`. Nothing is done if the server
+    /// doesn't support it, discarding being only a hint.
+    pub fn trim(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        if self.info.flags & NBD_FLAG_SEND_TRIM == 0 {
+            return Ok(());
+        }
+
+        self.for_each_range(offset, length, |connection, offset, length| {
+            connection.command(&NbdRequest {
+                command: NBD_CMD_TRIM,
+                offset,
+                length,
+                buffers: None,
+            })
+        })
+    }
+
+    /// Zeroes `length` bytes at `offset`, writing zeroed buffers if the server
+    /// doesn't support it.
+    pub fn write_zeroes(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        if self.info.flags & NBD_FLAG_SEND_WRITE_ZEROES != 0 {
+            return self.for_each_range(offset, length, |connection, offset, length| {
+                connection.command(&NbdRequest {
+                    command: NBD_CMD_WRITE_ZEROES,
+                    offset,
+                    length,
+                    buffers: None,
+                })
+            });
+        }
+
+        let mut zeroes = vec![0u8; std::cmp::min(length, self.info.max_payload as u64) as usize];
+        self.for_each_range(offset, length, |connection, offset, length| {
+            let iovec = libc::iovec {
+                iov_base: zeroes.as_mut_ptr() as *mut libc::c_void,
+                iov_len: length as usize,
+            };
+            connection.write(offset, &[iovec])
+        })
+    }
+
+    // Splits the range in requests of the largest payload the server accepts.
+    fn for_each_range(
+        &mut self,
+        offset: u64,
+        length: u64,
+        mut f: impl FnMut(&mut Self, u64, u32) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < length {
+            let count = std::cmp::min(length - pos, self.info.max_payload as u64);
+            f(self, offset + pos, count as u32)?;
+            pos += count;
+        }
+
+        Ok(())
+    }
+
+    fn transfer(&mut self, command: u16, offset: u64, iovecs: &[libc::iovec]) -> io::Result<()> {
+        let length = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+        self.for_each_range(offset, length, |connection, chunk_offset, chunk_length| {
+            connection.command(&NbdRequest {
+                command,
+                offset: chunk_offset,
+                length: chunk_length,
+                buffers: Some((iovecs, (chunk_offset - offset) as usize)),
+            })
+        })
+    }
+
+    // Sends the request and waits for its reply. The connection is dropped
+    // if it fails.
+    fn command(&mut self, request: &NbdRequest) -> io::Result<()> {
+        match self.transmit(request) {
+            Ok(result) => result,
+            Err(e) => {
+                if self.stream.take().is_some() {
+                    warn!("NBD connection to {} failed: {}", self.address, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Connects again to the server, once the connection was lost.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        let negotiated = negotiate(&self.address, &self.export)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if negotiated.info.size != self.info.size {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Export size changed from {} to {} bytes",
+                    self.info.size, negotiated.info.size
+                ),
+            ));
+        }
+
+        info!("Reconnected to NBD server {}", self.address);
+        self.stream = Some(negotiated.stream);
+        self.info = negotiated.info;
+        self.structured_replies = negotiated.structured_replies;
+
+        Ok(())
+    }
+
+    // Returns the result of the request, or an error if the connection
+    // failed.
+    fn transmit(&mut self, request: &NbdRequest) -> io::Result<io::Result<()>> {
+        if self.stream.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("Not connected to NBD server {}", self.address),
+            ));
+        }
+        self.handle = self.handle.wrapping_add(1);
+        let handle = self.handle;
+        let structured_replies = self.structured_replies;
+        let stream = self.stream.as_mut().unwrap();
+
+        let mut header = Vec::with_capacity(28);
+        header.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        header.extend_from_slice(&0u16.to_be_bytes());
+        header.extend_from_slice(&request.command.to_be_bytes());
+        header.extend_from_slice(&handle.to_be_bytes());
+        header.extend_from_slice(&request.offset.to_be_bytes());
+        header.extend_from_slice(&request.length.to_be_bytes());
+        stream.write_all(&header)?;
+        if let (NBD_CMD_WRITE, Some((iovecs, pos))) = (request.command, request.buffers) {
+            for_each_iovec_slice(iovecs, pos, request.length as usize, |slice| {
+                stream.write_all(slice)
+            })?;
+        }
+
+        let magic = read_u32(stream)?;
+        if magic == NBD_SIMPLE_REPLY_MAGIC {
+            let error = read_u32(stream)?;
+            if read_u64(stream)? != handle {
+                return Err(protocol_error("Reply doesn't match the request".to_owned()));
+            }
+            if error != 0 {
+                return Ok(Err(io::Error::from_raw_os_error(error as i32)));
+            }
+            if let (NBD_CMD_READ, Some((iovecs, pos))) = (request.command, request.buffers) {
+                for_each_iovec_slice(iovecs, pos, request.length as usize, |slice| {
+                    stream.read_exact(slice)
+                })?;
+            }
+            return Ok(Ok(()));
+        } else if magic != NBD_STRUCTURED_REPLY_MAGIC || !structured_replies {
+            return Err(protocol_error(format!("Invalid reply magic {:#x}", magic)));
+        }
+
+        let mut result = Ok(());
+        let mut first_chunk = true;
+        loop {
+            // Each chunk starts with the magic, already read for the first one.
+            if !first_chunk && read_u32(stream)? != NBD_STRUCTURED_REPLY_MAGIC {
+                return Err(protocol_error("Invalid reply chunk magic".to_owned()));
+            }
+            first_chunk = false;
+            let flags = read_u16(stream)?;
+            let chunk_type = read_u16(stream)?;
+            if read_u64(stream)? != handle {
+                return Err(protocol_error("Reply doesn't match the request".to_owned()));
+            }
+            let length = read_u32(stream)?;
+
+            match chunk_type {
+                NBD_REPLY_TYPE_NONE => {}
+                NBD_REPLY_TYPE_OFFSET_DATA | NBD_REPLY_TYPE_OFFSET_HOLE => {
+                    let (iovecs, pos) = match (request.command, request.buffers) {
+                        (NBD_CMD_READ, Some(buffers)) => buffers,
+                        _ => {
+                            return Err(protocol_error(
+                                "Data sent for a request not reading any".to_owned(),
+                            ))
+                        }
+                    };
+                    let chunk_offset = read_u64(stream)?;
+                    let chunk_length = if chunk_type == NBD_REPLY_TYPE_OFFSET_DATA {
+                        length
+                            .checked_sub(8)
+                            .ok_or_else(|| protocol_error("Invalid data chunk length".to_owned()))?
+                    } else {
+                        read_u32(stream)?
+                    };
+                    let start = chunk_offset.wrapping_sub(request.offset);
+                    if chunk_offset < request.offset
+                        || start + chunk_length as u64 > request.length as u64
+                    {
+                        return Err(protocol_error(format!(
+                            "Data chunk at {} of {} bytes outside of the request",
+                            chunk_offset, chunk_length
+                        )));
+                    }
+                    let pos = pos + start as usize;
+                    if chunk_type == NBD_REPLY_TYPE_OFFSET_DATA {
+                        for_each_iovec_slice(iovecs, pos, chunk_length as usize, |slice| {
+                            stream.read_exact(slice)
+                        })?;
+                    } else {
+                        for_each_iovec_slice(iovecs, pos, chunk_length as usize, |slice| {
+                            slice.fill(0);
+                            Ok(())
+                        })?;
+                    }
+                }
+                NBD_REPLY_TYPE_ERROR | NBD_REPLY_TYPE_ERROR_OFFSET => {
+                    if length < 6 {
+                        return Err(protocol_error("Invalid error chunk length".to_owned()));
+                    }
+                    let error = read_u32(stream)?;
+                    let mut message = vec![0u8; read_u16(stream)? as usize];
+                    stream.read_exact(&mut message)?;
+                    skip(
+                        stream,
+                        (length - 6).saturating_sub(message.len() as u32) as u64,
+                    )?;
+                    debug!(
+                        "NBD request failed: error {}: {}",
+                        error,
+                        String::from_utf8_lossy(&message)
+                    );
+                    if result.is_ok() {
+                        result = Err(io::Error::from_raw_os_error(if error != 0 {
+                            error as i32
+                        } else {
+                            libc::EIO
+                        }));
+                    }
+                }
+                chunk_type if chunk_type & (1 << 15) != 0 => {
+                    skip(stream, length as u64)?;
+                    if result.is_ok() {
+                        result = Err(io::Error::from_raw_os_error(libc::EIO));
+                    }
+                }
+                _ => skip(stream, length as u64)?,
+            }
+
+            if flags & NBD_REPLY_FLAG_DONE != 0 {
+                return Ok(result);
+            }
+        }
+    }
+}
+
+impl Drop for NbdConnection {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            let mut request = Vec::with_capacity(28);
+            request.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+            request.extend_from_slice(&0u16.to_be_bytes());
+            request.extend_from_slice(&NBD_CMD_DISC.to_be_bytes());
+            request.extend_from_slice(&[0u8; 20]);
+            // Ignore the result because there is nothing we can do about it.
+            let _ = stream.write_all(&request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+    use vmm_sys_util::tempdir::TempDir;
+
+    const DISK_SIZE: usize = 0x10_0000;
+
+    // Minimal NBD server exporting `disk` as "test", which drops each
+    // connection after `commands` requests if set.
+    struct TestServer {
+        disk: Arc<Mutex<Vec<u8>>>,
+        structured_replies: bool,
+        commands: Option<usize>,
+    }
+
+    fn write_reply_chunk(
+        stream: &mut UnixStream,
+        flags: u16,
+        chunk_type: u16,
+        handle: u64,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&chunk_type.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        stream.write_all(&buf)
+    }
+
+    fn write_simple_reply(
+        stream: &mut UnixStream,
+        error: u32,
+        handle: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&error.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf)
+    }
+
+    fn write_option_reply(
+        stream: &mut UnixStream,
+        option: u32,
+        reply_type: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_REP_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&option.to_be_bytes());
+        buf.extend_from_slice(&reply_type.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf)
+    }
+
+    impl TestServer {
+        fn start(self, listener: UnixListener) {
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let _ = self.serve(&mut stream.unwrap());
+                }
+            });
+        }
+
+        fn serve(&self, stream: &mut UnixStream) -> io::Result<()> {
+            stream.write_all(&NBD_INIT_MAGIC.to_be_bytes())?;
+            stream.write_all(&NBD_OPTS_MAGIC.to_be_bytes())?;
+            stream.write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())?;
+            read_u32(stream)?;
+
+            let mut structured_replies = false;
+            loop {
+                assert_eq!(read_u64(stream)?, NBD_OPTS_MAGIC);
+                let option = read_u32(stream)?;
+                let mut data = vec![0u8; read_u32(stream)? as usize];
+                stream.read_exact(&mut data)?;
+                match option {
+                    NBD_OPT_STRUCTURED_REPLY if self.structured_replies => {
+                        structured_replies = true;
+                        write_option_reply(stream, option, NBD_REP_ACK, &[])?;
+                    }
+                    NBD_OPT_GO if &data[4..data.len() - 4] == b"test" => {
+                        let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                        info.extend_from_slice(&(DISK_SIZE as u64).to_be_bytes());
+                        info.extend_from_slice(
+                            &(NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_WRITE_ZEROES).to_be_bytes(),
+                        );
+                        write_option_reply(stream, option, NBD_REP_INFO, &info)?;
+                        write_option_reply(stream, option, NBD_REP_ACK, &[])?;
+                        break;
+                    }
+                    NBD_OPT_GO => {
+                        write_option_reply(
+                            stream,
+                            option,
+                            NBD_REP_FLAG_ERROR | 6,
+                            b"Unknown export",
+                        )?;
+                    }
+                    _ => write_option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+                }
+            }
+
+            let mut commands = 0;
+            loop {
+                assert_eq!(read_u32(stream)?, NBD_REQUEST_MAGIC);
+                read_u16(stream)?;
+                let command = read_u16(stream)?;
+                let handle = read_u64(stream)?;
+                let offset = read_u64(stream)? as usize;
+                let length = read_u32(stream)? as usize;
+
+                commands += 1;
+                if self.commands.map_or(false, |limit| commands > limit) {
+                    return Ok(());
+                }
+
+                let mut disk = self.disk.lock().unwrap();
+                if offset + length > disk.len() {
+                    write_simple_reply(stream, libc::EINVAL as u32, handle, &[])?;
+                    continue;
+                }
+                match command {
+                    NBD_CMD_READ if structured_replies => {
+                        // The second half is sent as a hole if it's zeroed.
+                        let middle = offset + length / 2;
+                        let mut payload = (offset as u64).to_be_bytes().to_vec();
+                        payload.extend_from_slice(&disk[offset..middle]);
+                        write_reply_chunk(stream, 0, NBD_REPLY_TYPE_OFFSET_DATA, handle, &payload)?;
+                        let mut payload = (middle as u64).to_be_bytes().to_vec();
+                        if disk[middle..offset + length].iter().all(|b| *b == 0) {
+                            payload.extend_from_slice(
+                                &((offset + length - middle) as u32).to_be_bytes(),
+                            );
+                            write_reply_chunk(
+                                stream,
+                                NBD_REPLY_FLAG_DONE,
+                                NBD_REPLY_TYPE_OFFSET_HOLE,
+                                handle,
+                                &payload,
+                            )?;
+                        } else {
+                            payload.extend_from_slice(&disk[middle..offset + length]);
+                            write_reply_chunk(
+                                stream,
+                                NBD_REPLY_FLAG_DONE,
+                                NBD_REPLY_TYPE_OFFSET_DATA,
+                                handle,
+                                &payload,
+                            )?;
+                        }
+                    }
+                    NBD_CMD_READ => {
+                        write_simple_reply(stream, 0, handle, &disk[offset..offset + length])?
+                    }
+                    NBD_CMD_WRITE => {
+                        stream.read_exact(&mut disk[offset..offset + length])?;
+                        write_simple_reply(stream, 0, handle, &[])?;
+                    }
+                    NBD_CMD_WRITE_ZEROES => {
+                        disk[offset..offset + length].fill(0);
+                        write_simple_reply(stream, 0, handle, &[])?;
+                    }
+                    NBD_CMD_FLUSH => write_simple_reply(stream, 0, handle, &[])?,
+                    NBD_CMD_DISC => return Ok(()),
+                    _ => write_simple_reply(stream, libc::EINVAL as u32, handle, &[])?,
+                }
+            }
+        }
+    }
+
+    fn start_server(
+        structured_replies: bool,
+        commands: Option<usize>,
+    ) -> (TempDir, NbdAddress, Arc<Mutex<Vec<u8>>>) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("nbd.sock");
+        let disk = Arc::new(Mutex::new(vec![0u8; DISK_SIZE]));
+        TestServer {
+            disk: disk.clone(),
+            structured_replies,
+            commands,
+        }
+        .start(UnixListener::bind(&path).unwrap());
+
+        (dir, NbdAddress::Unix(path), disk)
+    }
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_nbd_address() {
+        assert_eq!(
+            NbdAddress::parse("unix:/run/nbd.sock").unwrap(),
+            NbdAddress::Unix(PathBuf::from("/run/nbd.sock"))
+        );
+        assert_eq!(
+            NbdAddress::parse("tcp:127.0.0.1:10809").unwrap(),
+            NbdAddress::Tcp("127.0.0.1:10809".parse().unwrap())
+        );
+        assert!(NbdAddress::parse("unix:").is_err());
+        assert!(NbdAddress::parse("tcp:127.0.0.1").is_err());
+        assert!(NbdAddress::parse("/run/nbd.sock").is_err());
+    }
+
+    #[test]
+    fn test_nbd_connection() {
+        for structured_replies in [true, false] {
+            let (_dir, address, disk) = start_server(structured_replies, None);
+            assert!(matches!(
+                NbdConnection::connect(&address, "missing"),
+                Err(NbdError::ExportRejected(..))
+            ));
+            let mut connection = NbdConnection::connect(&address, "test").unwrap();
+            assert_eq!(connection.info().size, DISK_SIZE as u64);
+            assert_eq!(connection.info().max_payload, NBD_DEFAULT_MAX_PAYLOAD);
+
+            let mut first = [0xaau8; 0x300];
+            let mut second = [0x55u8; 0x100];
+            connection
+                .write(0x1000, &[iovec(&mut first), iovec(&mut second)])
+                .unwrap();
+            connection.flush().unwrap();
+            assert!(disk.lock().unwrap()[0x1000..0x1300]
+                .iter()
+                .all(|b| *b == 0xaa));
+            assert!(disk.lock().unwrap()[0x1300..0x1400]
+                .iter()
+                .all(|b| *b == 0x55));
+
+            // Reads are scattered across the buffers, holes being zeroed.
+            let mut first = [0xffu8; 0x100];
+            let mut second = [0xffu8; 0x700];
+            connection
+                .read(0x1000, &[iovec(&mut first), iovec(&mut second)])
+                .unwrap();
+            assert!(first.iter().all(|b| *b == 0xaa));
+            assert!(second[..0x200].iter().all(|b| *b == 0xaa));
+            assert!(second[0x200..0x300].iter().all(|b| *b == 0x55));
+            assert!(second[0x300..].iter().all(|b| *b == 0));
+
+            connection.write_zeroes(0x1200, 0x100).unwrap();
+            assert!(disk.lock().unwrap()[0x1200..0x1300].iter().all(|b| *b == 0));
+
+            // The server doesn't support trimming, which is ignored.
+            connection.trim(0x1000, 0x100).unwrap();
+
+            let mut buf = [0u8; 0x200];
+            assert_eq!(
+                connection
+                    .read(DISK_SIZE as u64 - 0x100, &[iovec(&mut buf)])
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(libc::EINVAL)
+            );
+        }
+    }
+
+    #[test]
+    fn test_nbd_reconnect() {
+        let (_dir, address, disk) = start_server(true, Some(1));
+        let mut connection = NbdConnection::connect(&address, "test").unwrap();
+
+        // Each request after the first one fails on the current connection,
+        // and the following ones until it's established again.
+        let mut buf = [0x33u8; 0x200];
+        connection.write(0, &[iovec(&mut buf)]).unwrap();
+        assert!(connection.write(0x200, &[iovec(&mut buf)]).is_err());
+        assert!(!connection.is_connected());
+        assert_eq!(
+            connection
+                .write(0x200, &[iovec(&mut buf)])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotConnected
+        );
+        connection.reconnect().unwrap();
+        assert!(connection.is_connected());
+        connection.write(0x200, &[iovec(&mut buf)]).unwrap();
+        assert!(disk.lock().unwrap()[..0x400].iter().all(|b| *b == 0x33));
+
+        let mut buf = [0u8; 0x400];
+        assert!(connection.read(0, &[iovec(&mut buf)]).is_err());
+        connection.reconnect().unwrap();
+        connection.read(0, &[iovec(&mut buf)]).unwrap();
+        assert!(buf.iter().all(|b| *b == 0x33));
+    }
+}
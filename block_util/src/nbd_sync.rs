@@ -0,0 +1,231 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, DiskTopology,
+};
+use crate::nbd::{NbdAddress, NbdConnection, NbdResult, NBD_RECONNECT_DELAY};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+pub struct NbdDiskSync {
+    address: NbdAddress,
+    export: String,
+    // Connection shared by all the queues, unless the server allows each of
+    // them to have its own.
+    connection: Arc<Mutex<NbdConnection>>,
+}
+
+impl NbdDiskSync {
+    pub fn new(address: NbdAddress, export: &str) -> NbdResult<Self> {
+        let connection = NbdConnection::connect(&address, export)?;
+        info!(
+            "Connected to NBD server {}, export {:?}: {:?}",
+            address,
+            export,
+            connection.info()
+        );
+
+        Ok(NbdDiskSync {
+            address,
+            export: export.to_owned(),
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+}
+
+impl DiskFile for NbdDiskSync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.connection.lock().unwrap().info().size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        // The writes completed on a connection are only guaranteed to be
+        // seen by, and flushed from, the other ones if the server says so.
+        let connection = if self.connection.lock().unwrap().info().can_multi_conn() {
+            Arc::new(Mutex::new(
+                NbdConnection::connect(&self.address, &self.export).map_err(|e| {
+                    DiskFileError::NewAsyncIo(io::Error::new(io::ErrorKind::Other, e))
+                })?,
+            ))
+        } else {
+            self.connection.clone()
+        };
+
+        Ok(
+            Box::new(NbdSync::new(connection).map_err(DiskFileError::NewAsyncIo)?)
+                as Box<dyn AsyncIo>,
+        )
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        let info = self.connection.lock().unwrap().info();
+        let logical_block_size = std::cmp::max(info.min_block_size as u64, 512);
+        DiskTopology {
+            logical_block_size,
+            physical_block_size: std::cmp::max(
+                info.preferred_block_size as u64,
+                logical_block_size,
+            ),
+            minimum_io_size: logical_block_size,
+            optimal_io_size: 0,
+        }
+    }
+}
+
+pub struct NbdSync {
+    connection: Arc<Mutex<NbdConnection>>,
+    eventfd: EventFd,
+    completion_list: Vec<(u64, i32)>,
+    // Fires when the lost connection should be established again, which
+    // happens from the epoll loop rather than while a request is processed.
+    reconnect_timer: TimerFd,
+    reconnect_scheduled: bool,
+}
+
+impl NbdSync {
+    // The timer is created upfront, the epoll handlers being restricted by
+    // seccomp.
+    fn new(connection: Arc<Mutex<NbdConnection>>) -> io::Result<Self> {
+        Ok(NbdSync {
+            connection,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK).expect("Failed creating EventFd for NBD"),
+            completion_list: Vec::new(),
+            reconnect_timer: TimerFd::new()?,
+            reconnect_scheduled: false,
+        })
+    }
+
+    fn complete_request(&mut self, user_data: u64, result: i32) {
+        self.completion_list.push((user_data, result));
+        self.eventfd.write(1).unwrap();
+    }
+
+    // Runs `f` on the connection, scheduling a new connection if it was lost.
+    fn with_connection(
+        &mut self,
+        f: impl FnOnce(&mut NbdConnection) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let result = f(&mut connection);
+        let connected = connection.is_connected();
+        drop(connection);
+        if !connected && !self.reconnect_scheduled {
+            self.schedule_reconnect();
+        }
+        result
+    }
+
+    fn schedule_reconnect(&mut self) {
+        match self.reconnect_timer.reset(NBD_RECONNECT_DELAY, None) {
+            Ok(()) => self.reconnect_scheduled = true,
+            Err(e) => error!("Failed scheduling NBD reconnection: {}", e),
+        }
+    }
+}
+
+impl AsyncIo for NbdSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.with_connection(|connection| connection.read(offset as u64, &iovecs))
+            .map_err(AsyncIoError::ReadVectored)?;
+
+        let length: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.complete_request(user_data, length as i32);
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.with_connection(|connection| connection.write(offset as u64, &iovecs))
+            .map_err(AsyncIoError::WriteVectored)?;
+
+        let length: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.complete_request(user_data, length as i32);
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.with_connection(|connection| connection.flush())
+            .map_err(AsyncIoError::Fsync)?;
+
+        if let Some(user_data) = user_data {
+            self.complete_request(user_data, 0);
+        }
+
+        Ok(())
+    }
+
+    fn punch_hole(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.with_connection(|connection| connection.trim(offset as u64, length))
+            .map_err(AsyncIoError::PunchHole)?;
+
+        self.complete_request(user_data, 0);
+
+        Ok(())
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.with_connection(|connection| connection.write_zeroes(offset as u64, length))
+            .map_err(AsyncIoError::WriteZeroes)?;
+
+        self.complete_request(user_data, 0);
+
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        self.completion_list.drain(..).collect()
+    }
+
+    fn reconnect_fd(&self) -> Option<RawFd> {
+        Some(self.reconnect_timer.as_raw_fd())
+    }
+
+    fn reconnect(&mut self) {
+        if let Err(e) = self.reconnect_timer.wait() {
+            error!("Failed reading NBD reconnection timer: {}", e);
+        }
+        self.reconnect_scheduled = false;
+
+        // The connection may be shared with another queue which established
+        // it again already.
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_connected() {
+            return;
+        }
+        if let Err(e) = connection.reconnect() {
+            warn!("Failed reconnecting to NBD server: {}", e);
+            drop(connection);
+            self.schedule_reconnect();
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{clone_backing_files, AsyncIo, DiskFile, DiskFileError, DiskFileResult};
+use crate::mapped_async::{MappedFile, MappedFileAsync};
+use crate::qcow_sync::open_qcow_file;
+use qcow::{BackingFilePolicy, QcowFile, Result as QcowResult};
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub struct QcowDiskAsync {
+    qcow_file: Arc<Mutex<QcowFile>>,
+}
+
+impl QcowDiskAsync {
+    pub fn new(
+        file: File,
+        direct_io: bool,
+        backing_file_policy: &BackingFilePolicy,
+        repair: bool,
+    ) -> QcowResult<Self> {
+        Ok(QcowDiskAsync {
+            qcow_file: Arc::new(Mutex::new(open_qcow_file(
+                file,
+                direct_io,
+                backing_file_policy,
+                repair,
+            )?)),
+        })
+    }
+}
+
+impl DiskFile for QcowDiskAsync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        let mut file = self.qcow_file.lock().unwrap();
+
+        Ok(file.seek(SeekFrom::End(0)).map_err(DiskFileError::Size)? as u64)
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(
+            MappedFileAsync::new(self.qcow_file.clone(), ring_depth)
+                .map_err(DiskFileError::NewAsyncIo)?,
+        ) as Box<dyn AsyncIo>)
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> DiskFileResult<()> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .create_snapshot(name)
+            .map_err(|e| {
+                DiskFileError::CreateSnapshot(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e.to_string(),
+                ))
+            })
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> DiskFileResult<()> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .delete_snapshot(name)
+            .map_err(|e| {
+                DiskFileError::DeleteSnapshot(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e.to_string(),
+                ))
+            })
+    }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        self.qcow_file.lock().unwrap().resize(size).map_err(|e| {
+            DiskFileError::Resize(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e.to_string(),
+            ))
+        })
+    }
+
+    fn backing_files(&self) -> DiskFileResult<Vec<(PathBuf, File)>> {
+        clone_backing_files(self.qcow_file.lock().unwrap().backing_files())
+    }
+}
+
+impl MappedFile for QcowFile {
+    fn map_read(&mut self, offset: u64, length: u64) -> std::io::Result<(Option<u64>, u64)> {
+        let (file_offset, count) = QcowFile::map_read(self, offset, length as usize)?;
+        Ok((file_offset, count as u64))
+    }
+
+    fn map_write(&mut self, offset: u64, length: u64) -> std::io::Result<(Option<u64>, u64)> {
+        let (file_offset, count) = QcowFile::map_write(self, offset, length as usize)?;
+        Ok((Some(file_offset), count as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_io_uring_is_supported;
+    use qcow::RawFile;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn wait_completion(async_io: &mut dyn AsyncIo, user_data: u64) -> i32 {
+        loop {
+            if let Some((_, result)) = async_io
+                .complete()
+                .into_iter()
+                .find(|(completed, _)| *completed == user_data)
+            {
+                return result;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_qcow_async_read_write() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let file = TempFile::new().unwrap().into_file();
+        QcowFile::new(
+            RawFile::new(file.try_clone().unwrap(), false),
+            3,
+            0x100_0000,
+        )
+        .unwrap();
+        let disk = QcowDiskAsync::new(file, false, &BackingFilePolicy::Forbid, false).unwrap();
+        let mut async_io = disk.new_async_io(4).unwrap();
+
+        // Write across several clusters, which get allocated on the way.
+        let mut head = vec![0x55u8; 0x1_8000];
+        let mut tail = vec![0xaau8; 0x1_8000];
+        async_io
+            .write_vectored(0x8000, vec![iovec(&mut head), iovec(&mut tail)], 1)
+            .unwrap();
+        assert_eq!(wait_completion(async_io.as_mut(), 1), 0x3_0000);
+
+        // Read back, including ranges never written.
+        for user_data in 2..4 {
+            let mut buf = vec![0xffu8; 0x4_0000];
+            async_io
+                .read_vectored(0, vec![iovec(&mut buf)], user_data)
+                .unwrap();
+            assert_eq!(wait_completion(async_io.as_mut(), user_data), 0x4_0000);
+            assert!(buf[..0x8000].iter().all(|&b| b == 0));
+            assert!(buf[0x8000..0x2_0000].iter().all(|&b| b == 0x55));
+            assert!(buf[0x2_0000..0x3_8000].iter().all(|&b| b == 0xaa));
+            assert!(buf[0x3_8000..].iter().all(|&b| b == 0));
+        }
+
+        async_io.fsync(Some(4)).unwrap();
+        assert_eq!(wait_completion(async_io.as_mut(), 4), 0);
+    }
+
+    #[test]
+    fn test_qcow_async_resize() {
+        let file = TempFile::new().unwrap().into_file();
+        QcowFile::new(
+            RawFile::new(file.try_clone().unwrap(), false),
+            3,
+            0x100_0000,
+        )
+        .unwrap();
+        let mut disk = QcowDiskAsync::new(file, false, &BackingFilePolicy::Forbid, false).unwrap();
+
+        disk.resize(0x200_0000).unwrap();
+        assert_eq!(disk.size().unwrap(), 0x200_0000);
+        assert!(disk.resize(0x100_0000).is_err());
+        assert_eq!(disk.size().unwrap(), 0x200_0000);
+    }
+}
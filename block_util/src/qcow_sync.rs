@@ -2,11 +2,14 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult};
+use crate::async_io::{
+    clone_backing_files, AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
 use crate::AsyncAdaptor;
-use qcow::{QcowFile, RawFile, Result as QcowResult};
+use qcow::{BackingFilePolicy, QcowFile, RawFile, Result as QcowResult};
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, MutexGuard};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -15,13 +18,53 @@ pub struct QcowDiskSync {
 }
 
 impl QcowDiskSync {
-    pub fn new(file: File, direct_io: bool) -> QcowResult<Self> {
+    pub fn new(
+        file: File,
+        direct_io: bool,
+        backing_file_policy: &BackingFilePolicy,
+        repair: bool,
+    ) -> QcowResult<Self> {
         Ok(QcowDiskSync {
-            qcow_file: Arc::new(Mutex::new(QcowFile::from(RawFile::new(file, direct_io))?)),
+            qcow_file: Arc::new(Mutex::new(open_qcow_file(
+                file,
+                direct_io,
+                backing_file_policy,
+                repair,
+            )?)),
         })
     }
 }
 
+// Opens the qcow2 image held by `file`. With `repair`, images flagged as dirty or corrupt are
+// checked and repaired before being used.
+pub(crate) fn open_qcow_file(
+    file: File,
+    direct_io: bool,
+    backing_file_policy: &BackingFilePolicy,
+    repair: bool,
+) -> QcowResult<QcowFile> {
+    let mut qcow_file =
+        QcowFile::from_with_backing_file(RawFile::new(file, direct_io), backing_file_policy)?;
+    let header = qcow_file.header();
+    if !header.is_dirty() && !header.is_corrupt() {
+        return Ok(qcow_file);
+    }
+
+    if repair {
+        info!("Checking QCOW image flagged as dirty or corrupt");
+        let result = qcow_file.check(true)?;
+        if !result.is_clean() {
+            warn!(
+                "Repaired QCOW image: {} leaked clusters, {} corruptions, {} dangling pointers",
+                result.leaks, result.corruptions, result.dangling_pointers
+            );
+        }
+    } else {
+        warn!("QCOW image is flagged as dirty or corrupt, repair=on checks it when opening");
+    }
+    Ok(qcow_file)
+}
+
 impl DiskFile for QcowDiskSync {
     fn size(&mut self) -> DiskFileResult<u64> {
         let mut file = self.qcow_file.lock().unwrap();
@@ -32,6 +75,45 @@ impl DiskFile for QcowDiskSync {
     fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
         Ok(Box::new(QcowSync::new(self.qcow_file.clone())) as Box<dyn AsyncIo>)
     }
+
+    fn create_snapshot(&mut self, name: &str) -> DiskFileResult<()> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .create_snapshot(name)
+            .map_err(|e| {
+                DiskFileError::CreateSnapshot(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e.to_string(),
+                ))
+            })
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> DiskFileResult<()> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .delete_snapshot(name)
+            .map_err(|e| {
+                DiskFileError::DeleteSnapshot(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e.to_string(),
+                ))
+            })
+    }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        self.qcow_file.lock().unwrap().resize(size).map_err(|e| {
+            DiskFileError::Resize(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e.to_string(),
+            ))
+        })
+    }
+
+    fn backing_files(&self) -> DiskFileResult<Vec<(PathBuf, File)>> {
+        clone_backing_files(self.qcow_file.lock().unwrap().backing_files())
+    }
 }
 
 pub struct QcowSync {
@@ -97,6 +179,36 @@ impl AsyncIo for QcowSync {
             .fsync_sync(user_data, &self.eventfd, &mut self.completion_list)
     }
 
+    fn punch_hole(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.qcow_file.punch_hole_sync(
+            offset,
+            length,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.qcow_file.write_zeroes_sync(
+            offset,
+            length,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
     fn complete(&mut self) -> Vec<(u64, i32)> {
         self.completion_list.drain(..).collect()
     }
@@ -5,6 +5,7 @@
 use crate::async_io::{
     AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, DiskTopology,
 };
+use crate::block_io_uring_fallocate_is_supported;
 use io_uring::{opcode, squeue, types, IoUring};
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
@@ -44,12 +45,18 @@ impl DiskFile for RawFileDisk {
             DiskTopology::default()
         }
     }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        self.file.set_len(size).map_err(DiskFileError::Resize)
+    }
 }
 
 pub struct RawFileAsync {
     fd: RawFd,
     io_uring: IoUring,
     eventfd: EventFd,
+    fallocate_supported: bool,
+    completion_list: Vec<(u64, i32)>,
 }
 
 impl RawFileAsync {
@@ -61,12 +68,60 @@ impl RawFileAsync {
         // the completion queue is ready.
         io_uring.submitter().register_eventfd(eventfd.as_raw_fd())?;
 
+        let fallocate_supported = block_io_uring_fallocate_is_supported(&io_uring);
+
         Ok(RawFileAsync {
             fd,
             io_uring,
             eventfd,
+            fallocate_supported,
+            completion_list: Vec::new(),
         })
     }
+
+    fn fallocate(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        mode: libc::c_int,
+        user_data: u64,
+    ) -> std::io::Result<()> {
+        if !self.fallocate_supported {
+            // Older kernels don't support IORING_OP_FALLOCATE, so complete
+            // the request synchronously instead.
+            // Safe because we know the file descriptor is valid.
+            let ret = unsafe { libc::fallocate(self.fd, mode, offset, length as libc::off_t) };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            self.completion_list.push((user_data, 0));
+            self.eventfd.write(1)?;
+
+            return Ok(());
+        }
+
+        let (submitter, mut sq, _) = self.io_uring.split();
+
+        // Safe because we know the file descriptor is valid.
+        let _ = unsafe {
+            sq.push(
+                &opcode::Fallocate::new(types::Fd(self.fd), length as libc::off_t)
+                    .offset(offset)
+                    .mode(mode)
+                    .build()
+                    .flags(squeue::Flags::ASYNC)
+                    .user_data(user_data),
+            )
+        };
+
+        // Update the submission queue and submit new operations to the
+        // io_uring instance.
+        sq.sync();
+        submitter.submit()?;
+
+        Ok(())
+    }
 }
 
 impl AsyncIo for RawFileAsync {
@@ -155,8 +210,38 @@ impl AsyncIo for RawFileAsync {
         Ok(())
     }
 
+    fn punch_hole(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.fallocate(
+            offset,
+            length,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            user_data,
+        )
+        .map_err(AsyncIoError::PunchHole)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.fallocate(
+            offset,
+            length,
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+            user_data,
+        )
+        .map_err(AsyncIoError::WriteZeroes)
+    }
+
     fn complete(&mut self) -> Vec<(u64, i32)> {
-        let mut completion_list = Vec::new();
+        let mut completion_list = std::mem::take(&mut self.completion_list);
 
         let cq = self.io_uring.completion();
         for cq_entry in cq {
@@ -40,6 +40,10 @@ impl DiskFile for RawFileDiskSync {
             DiskTopology::default()
         }
     }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        self.file.set_len(size).map_err(DiskFileError::Resize)
+    }
 }
 
 pub struct RawFileSync {
@@ -56,6 +60,33 @@ impl RawFileSync {
             completion_list: Vec::new(),
         }
     }
+
+    fn fallocate(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        mode: libc::c_int,
+        user_data: u64,
+    ) -> std::io::Result<()> {
+        // SAFETY: FFI call with a valid file descriptor, fallocate64 doesn't
+        // access any memory of the process.
+        let result = unsafe {
+            libc::fallocate64(
+                self.fd as libc::c_int,
+                mode,
+                offset,
+                length as libc::off64_t,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        self.completion_list.push((user_data, result as i32));
+        self.eventfd.write(1).unwrap();
+
+        Ok(())
+    }
 }
 
 impl AsyncIo for RawFileSync {
@@ -125,6 +156,36 @@ impl AsyncIo for RawFileSync {
         Ok(())
     }
 
+    fn punch_hole(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.fallocate(
+            offset,
+            length,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            user_data,
+        )
+        .map_err(AsyncIoError::PunchHole)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.fallocate(
+            offset,
+            length,
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+            user_data,
+        )
+        .map_err(AsyncIoError::WriteZeroes)
+    }
+
     fn complete(&mut self) -> Vec<(u64, i32)> {
         self.completion_list.drain(..).collect()
     }
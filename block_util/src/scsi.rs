@@ -0,0 +1,1844 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! SCSI target emulating disk and CD-ROM logical units on top of disk images.
+//!
+//! The commands are executed from their CDB, the data being transferred to and
+//! from guest buffers described by iovecs. Commands accessing the medium are
+//! submitted to the `AsyncIo` of the logical unit and completed later, the
+//! other ones being executed right away.
+
+use crate::async_io::{AlignedBuffer, AsyncIo, DiskFile, DiskFileResult};
+use crate::mapped_async::{gather, iovecs_len, scatter, slice_iovecs};
+use crate::scsi_reservations::{Access, PersistentReservations, PersistentReservationsState};
+use crate::SECTOR_SIZE;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+// Status codes.
+pub const GOOD: u8 = 0x00;
+pub const CHECK_CONDITION: u8 = 0x02;
+pub const RESERVATION_CONFLICT: u8 = 0x18;
+
+// Operation codes.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const READ_6: u8 = 0x08;
+const WRITE_6: u8 = 0x0a;
+const INQUIRY: u8 = 0x12;
+const MODE_SELECT_6: u8 = 0x15;
+const RESERVE_6: u8 = 0x16;
+const RELEASE_6: u8 = 0x17;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const WRITE_AND_VERIFY_10: u8 = 0x2e;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const WRITE_SAME_10: u8 = 0x41;
+const UNMAP: u8 = 0x42;
+const READ_TOC: u8 = 0x43;
+const GET_CONFIGURATION: u8 = 0x46;
+const GET_EVENT_STATUS_NOTIFICATION: u8 = 0x4a;
+const READ_DISC_INFORMATION: u8 = 0x51;
+const MODE_SELECT_10: u8 = 0x55;
+const MODE_SENSE_10: u8 = 0x5a;
+const PERSISTENT_RESERVE_IN: u8 = 0x5e;
+const PERSISTENT_RESERVE_OUT: u8 = 0x5f;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const WRITE_AND_VERIFY_16: u8 = 0x8e;
+const VERIFY_16: u8 = 0x8f;
+const SYNCHRONIZE_CACHE_16: u8 = 0x91;
+const WRITE_SAME_16: u8 = 0x93;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+pub const REPORT_LUNS: u8 = 0xa0;
+const READ_12: u8 = 0xa8;
+const WRITE_12: u8 = 0xaa;
+const WRITE_AND_VERIFY_12: u8 = 0xae;
+const VERIFY_12: u8 = 0xaf;
+const MECHANISM_STATUS: u8 = 0xbd;
+
+// SERVICE ACTION IN(16) service actions.
+const READ_CAPACITY_16: u8 = 0x10;
+
+// Mode pages.
+const MODE_PAGE_CACHING: u8 = 0x08;
+const MODE_PAGE_CONTROL: u8 = 0x0a;
+const MODE_PAGE_CD_CAPABILITIES: u8 = 0x2a;
+const MODE_PAGE_ALL: u8 = 0x3f;
+// Write cache enabled bit of the caching mode page.
+const MODE_PAGE_CACHING_WCE: u8 = 0x04;
+
+// Force unit access bit of the write commands.
+const FUA: u8 = 0x08;
+
+const VENDOR: &[u8; 8] = b"CLOUDHV ";
+const REVISION: &[u8; 4] = b"1.0 ";
+
+const CDROM_BLOCK_SIZE: u64 = 2048;
+// Number of blocks of an 80 minutes CD, larger media being reported as DVDs.
+const CD_MAX_BLOCKS: u64 = 80 * 60 * 75;
+
+// Largest parameter list accepted.
+const MAX_PARAMETER_LIST_LENGTH: u64 = 1 << 20;
+const MAX_UNMAP_DESCRIPTORS: usize = 255;
+// Number of blocks zeroed or discarded by one command.
+const MAX_WRITE_SAME_BLOCKS: u64 = 1 << 22;
+
+/// Sense data reported along a CHECK CONDITION status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl Sense {
+    /// Returns the sense data in fixed format.
+    pub fn to_fixed_format(self) -> [u8; 18] {
+        let mut data = [0u8; 18];
+        data[0] = 0x70;
+        data[2] = self.key;
+        data[7] = 10;
+        data[12] = self.asc;
+        data[13] = self.ascq;
+        data
+    }
+}
+
+const fn sense(key: u8, asc: u8, ascq: u8) -> Sense {
+    Sense { key, asc, ascq }
+}
+
+pub(crate) const SENSE_NO_SENSE: Sense = sense(0x00, 0x00, 0x00);
+pub(crate) const SENSE_NO_MEDIUM: Sense = sense(0x02, 0x3a, 0x00);
+pub(crate) const SENSE_READ_ERROR: Sense = sense(0x03, 0x11, 0x00);
+pub(crate) const SENSE_WRITE_ERROR: Sense = sense(0x03, 0x0c, 0x00);
+pub(crate) const SENSE_INTERNAL_TARGET_FAILURE: Sense = sense(0x04, 0x44, 0x00);
+pub(crate) const SENSE_PARAMETER_LIST_LENGTH: Sense = sense(0x05, 0x1a, 0x00);
+pub(crate) const SENSE_INVALID_OPCODE: Sense = sense(0x05, 0x20, 0x00);
+pub(crate) const SENSE_LBA_OUT_OF_RANGE: Sense = sense(0x05, 0x21, 0x00);
+pub(crate) const SENSE_INVALID_FIELD: Sense = sense(0x05, 0x24, 0x00);
+pub(crate) const SENSE_LUN_NOT_SUPPORTED: Sense = sense(0x05, 0x25, 0x00);
+pub(crate) const SENSE_INVALID_PARAMETER: Sense = sense(0x05, 0x26, 0x00);
+pub(crate) const SENSE_INVALID_RELEASE: Sense = sense(0x05, 0x26, 0x04);
+pub(crate) const SENSE_SAVING_NOT_SUPPORTED: Sense = sense(0x05, 0x39, 0x00);
+pub(crate) const SENSE_REMOVAL_PREVENTED: Sense = sense(0x05, 0x53, 0x02);
+pub(crate) const SENSE_INSUFFICIENT_REGISTRATION_RESOURCES: Sense = sense(0x05, 0x55, 0x04);
+pub(crate) const SENSE_RESET: Sense = sense(0x06, 0x29, 0x00);
+pub(crate) const SENSE_WRITE_PROTECTED: Sense = sense(0x07, 0x27, 0x00);
+
+/// Outcome of a SCSI command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScsiCompletion {
+    pub status: u8,
+    pub sense: Option<Sense>,
+    /// Number of bytes transferred to or from the initiator.
+    pub transferred: u64,
+    /// The buffers provided by the initiator were too small for the
+    /// transfer, the command wasn't executed.
+    pub overrun: bool,
+}
+
+impl ScsiCompletion {
+    pub fn good(transferred: u64) -> Self {
+        ScsiCompletion {
+            status: GOOD,
+            sense: None,
+            transferred,
+            overrun: false,
+        }
+    }
+
+    pub fn check_condition(sense: Sense) -> Self {
+        ScsiCompletion {
+            status: CHECK_CONDITION,
+            sense: Some(sense),
+            transferred: 0,
+            overrun: false,
+        }
+    }
+
+    pub fn reservation_conflict() -> Self {
+        ScsiCompletion {
+            status: RESERVATION_CONFLICT,
+            sense: None,
+            transferred: 0,
+            overrun: false,
+        }
+    }
+
+    pub fn overrun() -> Self {
+        ScsiCompletion {
+            status: GOOD,
+            sense: None,
+            transferred: 0,
+            overrun: true,
+        }
+    }
+}
+
+impl From<Sense> for ScsiCompletion {
+    fn from(sense: Sense) -> Self {
+        ScsiCompletion::check_condition(sense)
+    }
+}
+
+type CommandResult<T> = std::result::Result<T, ScsiCompletion>;
+
+pub(crate) fn be16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes(bytes[0..2].try_into().unwrap())
+}
+
+pub(crate) fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[0..4].try_into().unwrap())
+}
+
+pub(crate) fn be64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[0..8].try_into().unwrap())
+}
+
+// Returns the length of the CDBs starting with `opcode`.
+fn cdb_length(opcode: u8) -> usize {
+    match opcode >> 5 {
+        0 => 6,
+        1 | 2 => 10,
+        4 => 16,
+        5 => 12,
+        _ => 6,
+    }
+}
+
+// Checks the CDB is complete, returning its operation code.
+fn check_cdb(cdb: &[u8]) -> CommandResult<u8> {
+    match cdb.first() {
+        Some(opcode) if cdb.len() >= cdb_length(*opcode) => Ok(*opcode),
+        _ => Err(SENSE_INVALID_OPCODE.into()),
+    }
+}
+
+// Copies `data` to the buffers of the initiator, truncated to their size.
+fn copy_to_initiator(data: &[u8], data_in: &[libc::iovec]) -> ScsiCompletion {
+    let len = cmp::min(data.len() as u64, iovecs_len(data_in));
+    scatter(&data[..len as usize], &slice_iovecs(data_in, 0, len));
+    ScsiCompletion::good(len)
+}
+
+// Returns the parameter list sent by the initiator.
+fn parameter_list(data_out: &[libc::iovec], length: u64) -> CommandResult<Vec<u8>> {
+    let length = cmp::min(length, iovecs_len(data_out));
+    if length > MAX_PARAMETER_LIST_LENGTH {
+        return Err(SENSE_PARAMETER_LIST_LENGTH.into());
+    }
+    let mut parameters = vec![0u8; length as usize];
+    gather(&slice_iovecs(data_out, 0, length), &mut parameters);
+    Ok(parameters)
+}
+
+// Returns the LBA and number of blocks a read, write or verify command
+// accesses.
+fn transfer_range(cdb: &[u8]) -> (u64, u64) {
+    match cdb_length(cdb[0]) {
+        6 => {
+            let lba = (be32(&cdb[0..4]) & 0x1f_ffff) as u64;
+            // A length of zero means 256 blocks.
+            let count = if cdb[4] == 0 { 256 } else { cdb[4] as u64 };
+            (lba, count)
+        }
+        10 => (be32(&cdb[2..6]) as u64, be16(&cdb[7..9]) as u64),
+        12 => (be32(&cdb[2..6]) as u64, be32(&cdb[6..10]) as u64),
+        _ => (be64(&cdb[2..10]), be32(&cdb[10..14]) as u64),
+    }
+}
+
+// Returns the access to the medium a command needs, as restricted by the
+// persistent reservations.
+fn access(opcode: u8) -> Access {
+    match opcode {
+        INQUIRY
+        | REPORT_LUNS
+        | REQUEST_SENSE
+        | TEST_UNIT_READY
+        | READ_CAPACITY_10
+        | SERVICE_ACTION_IN_16
+        | PERSISTENT_RESERVE_IN
+        | PERSISTENT_RESERVE_OUT => Access::Unrestricted,
+        READ_6 | READ_10 | READ_12 | READ_16 | VERIFY_10 | VERIFY_12 | VERIFY_16 | MODE_SENSE_6
+        | MODE_SENSE_10 => Access::Read,
+        _ => Access::Write,
+    }
+}
+
+// Converts a CD-ROM block address to minutes, seconds and frames.
+fn lba_to_msf(lba: u64) -> [u8; 4] {
+    let lba = lba + 150;
+    [
+        0,
+        (lba / (60 * 75)) as u8,
+        (lba / 75 % 60) as u8,
+        (lba % 75) as u8,
+    ]
+}
+
+// Derives a locally assigned NAA identifier from the serial number, which is
+// the same for all the VMs sharing a disk image.
+fn naa_identifier(serial: &str) -> u64 {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in serial.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100_0000_01b3);
+    }
+    (3 << 60) | (hash >> 4)
+}
+
+/// Type of device emulated by a logical unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScsiLunKind {
+    /// Direct access block device.
+    Disk,
+    /// CD-ROM drive, which is read-only.
+    Cdrom,
+}
+
+enum Action {
+    // Data returned to the initiator.
+    Data(Vec<u8>),
+    Read {
+        offset: u64,
+        len: u64,
+    },
+    Write {
+        offset: u64,
+        len: u64,
+        sync: bool,
+    },
+    Flush,
+    Zero {
+        ranges: VecDeque<(u64, u64)>,
+        unmap: bool,
+    },
+}
+
+/// Logical unit emulated on top of a disk image, shared by the queues
+/// submitting commands to it.
+pub struct ScsiLun {
+    kind: ScsiLunKind,
+    // None for a CD-ROM drive without medium.
+    disk: Option<Box<dyn DiskFile>>,
+    readonly: bool,
+    discard: bool,
+    serial: String,
+    block_size: u64,
+    physical_block_exp: u8,
+    nblocks: u64,
+    // Write cache enabled, as set through the caching mode page.
+    writeback: AtomicBool,
+    prevent_removal: AtomicBool,
+    unit_attention: Mutex<Option<Sense>>,
+    reservations: PersistentReservations,
+}
+
+impl ScsiLun {
+    pub fn new(
+        kind: ScsiLunKind,
+        mut disk: Option<Box<dyn DiskFile>>,
+        readonly: bool,
+        discard: bool,
+        serial: String,
+        reservations: PersistentReservations,
+    ) -> DiskFileResult<Self> {
+        let readonly = readonly || kind == ScsiLunKind::Cdrom;
+        let mut block_size = CDROM_BLOCK_SIZE;
+        let mut physical_block_exp = 0;
+        let mut nblocks = 0;
+        if let Some(disk) = disk.as_mut() {
+            let size = disk.size()?;
+            if kind == ScsiLunKind::Disk {
+                let topology = disk.topology();
+                block_size = cmp::max(topology.logical_block_size, SECTOR_SIZE);
+                while block_size << physical_block_exp < topology.physical_block_size {
+                    physical_block_exp += 1;
+                }
+            }
+            if size % block_size != 0 {
+                warn!(
+                    "SCSI disk image size is not a multiple of {} bytes, the last {} bytes won't be accessible",
+                    block_size,
+                    size % block_size
+                );
+            }
+            nblocks = size / block_size;
+        }
+
+        Ok(ScsiLun {
+            kind,
+            disk,
+            readonly,
+            discard: discard && kind == ScsiLunKind::Disk && !readonly,
+            serial,
+            block_size,
+            physical_block_exp,
+            nblocks,
+            writeback: AtomicBool::new(true),
+            prevent_removal: AtomicBool::new(false),
+            unit_attention: Mutex::new(None),
+            reservations,
+        })
+    }
+
+    pub fn kind(&self) -> ScsiLunKind {
+        self.kind
+    }
+
+    /// Returns the object executing the commands of a queue.
+    pub fn new_io(self: &Arc<Self>, ring_depth: u32) -> DiskFileResult<ScsiLunIo> {
+        let disk = match &self.disk {
+            Some(disk) => Some(disk.new_async_io(ring_depth)?),
+            None => None,
+        };
+        Ok(ScsiLunIo {
+            disk,
+            lun: self.clone(),
+            commands: HashMap::new(),
+        })
+    }
+
+    /// Returns the state of the persistent reservations only known to this
+    /// VM, if they aren't shared through a file.
+    pub fn reservations_state(&self) -> Option<PersistentReservationsState> {
+        self.reservations.get_state()
+    }
+
+    pub fn set_reservations_state(&self, state: &PersistentReservationsState) {
+        self.reservations.set_state(state)
+    }
+
+    /// Resets the logical unit, the next command being reported the reset.
+    pub fn reset(&self) {
+        self.prevent_removal.store(false, Ordering::Release);
+        *self.unit_attention.lock().unwrap() = Some(SENSE_RESET);
+    }
+
+    fn check_medium(&self) -> CommandResult<()> {
+        if self.disk.is_none() {
+            return Err(SENSE_NO_MEDIUM.into());
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, lba: u64, count: u64) -> CommandResult<()> {
+        match lba.checked_add(count) {
+            Some(end) if end <= self.nblocks => Ok(()),
+            _ => Err(SENSE_LBA_OUT_OF_RANGE.into()),
+        }
+    }
+
+    fn emulate(&self, cdb: &[u8], data_out: &[libc::iovec]) -> CommandResult<Action> {
+        let opcode = check_cdb(cdb)?;
+
+        if !matches!(opcode, INQUIRY | REPORT_LUNS | REQUEST_SENSE) {
+            if let Some(sense) = self.unit_attention.lock().unwrap().take() {
+                return Err(sense.into());
+            }
+        }
+        if !self.reservations.is_allowed(access(opcode)) {
+            return Err(ScsiCompletion::reservation_conflict());
+        }
+
+        let data = match (opcode, self.kind) {
+            (TEST_UNIT_READY, _) => {
+                self.check_medium()?;
+                Vec::new()
+            }
+            (REQUEST_SENSE, _) => {
+                let sense = self
+                    .unit_attention
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .unwrap_or(SENSE_NO_SENSE);
+                let mut data = sense.to_fixed_format().to_vec();
+                data.truncate(cdb[4] as usize);
+                data
+            }
+            (INQUIRY, _) => self.inquiry(cdb)?,
+            (MODE_SENSE_6 | MODE_SENSE_10, _) => self.mode_sense(cdb)?,
+            (MODE_SELECT_6 | MODE_SELECT_10, _) => {
+                self.mode_select(cdb, data_out)?;
+                Vec::new()
+            }
+            (START_STOP_UNIT, _) => {
+                // Loading or ejecting the medium, which stays in place.
+                if cdb[4] & 0x2 != 0
+                    && cdb[4] & 0x1 == 0
+                    && self.prevent_removal.load(Ordering::Acquire)
+                {
+                    return Err(SENSE_REMOVAL_PREVENTED.into());
+                }
+                Vec::new()
+            }
+            (PREVENT_ALLOW_MEDIUM_REMOVAL, _) => {
+                self.prevent_removal
+                    .store(cdb[4] & 0x1 != 0, Ordering::Release);
+                Vec::new()
+            }
+            (READ_CAPACITY_10, _) => {
+                self.check_medium()?;
+                let last_lba = cmp::min(self.nblocks.saturating_sub(1), u32::MAX as u64) as u32;
+                let mut data = last_lba.to_be_bytes().to_vec();
+                data.extend_from_slice(&(self.block_size as u32).to_be_bytes());
+                data
+            }
+            (SERVICE_ACTION_IN_16, ScsiLunKind::Disk) if cdb[1] & 0x1f == READ_CAPACITY_16 => {
+                self.read_capacity_16(cdb)?
+            }
+            (READ_6 | READ_10 | READ_12 | READ_16, _) => return self.read(cdb),
+            (
+                WRITE_6 | WRITE_10 | WRITE_12 | WRITE_16 | WRITE_AND_VERIFY_10
+                | WRITE_AND_VERIFY_12 | WRITE_AND_VERIFY_16,
+                ScsiLunKind::Disk,
+            ) => return self.write(cdb),
+            (VERIFY_10 | VERIFY_12 | VERIFY_16, ScsiLunKind::Disk) => {
+                // The data is always consistent, only check the range when
+                // the initiator doesn't send data to compare.
+                if cdb[1] & 0x6 != 0 {
+                    return Err(SENSE_INVALID_FIELD.into());
+                }
+                self.check_medium()?;
+                let (lba, count) = transfer_range(cdb);
+                self.check_range(lba, count)?;
+                Vec::new()
+            }
+            (SYNCHRONIZE_CACHE_10 | SYNCHRONIZE_CACHE_16, ScsiLunKind::Disk) => {
+                self.check_medium()?;
+                return Ok(Action::Flush);
+            }
+            (WRITE_SAME_10 | WRITE_SAME_16, ScsiLunKind::Disk) => {
+                return self.write_same(cdb, data_out)
+            }
+            (UNMAP, ScsiLunKind::Disk) => return self.unmap(cdb, data_out),
+            (RESERVE_6 | RELEASE_6, ScsiLunKind::Disk) => {
+                // Superseded by the persistent reservations, which are
+                // the only ones shared with other VMs.
+                return Err(SENSE_INVALID_OPCODE.into());
+            }
+            (PERSISTENT_RESERVE_IN, ScsiLunKind::Disk) => self.reservations.read(cdb)?,
+            (PERSISTENT_RESERVE_OUT, ScsiLunKind::Disk) => {
+                let parameters = parameter_list(data_out, be32(&cdb[5..9]) as u64)?;
+                self.reservations.update(cdb, &parameters)?;
+                Vec::new()
+            }
+            (READ_TOC, ScsiLunKind::Cdrom) => self.read_toc(cdb)?,
+            (GET_CONFIGURATION, ScsiLunKind::Cdrom) => self.get_configuration(cdb),
+            (GET_EVENT_STATUS_NOTIFICATION, ScsiLunKind::Cdrom) => {
+                self.get_event_status_notification(cdb)?
+            }
+            (READ_DISC_INFORMATION, ScsiLunKind::Cdrom) => self.read_disc_information(cdb)?,
+            (MECHANISM_STATUS, ScsiLunKind::Cdrom) => {
+                let mut data = vec![0u8; 8];
+                data.truncate(be16(&cdb[8..10]) as usize);
+                data
+            }
+            _ => {
+                debug!("Unsupported SCSI command {:#x}", opcode);
+                return Err(SENSE_INVALID_OPCODE.into());
+            }
+        };
+
+        Ok(Action::Data(data))
+    }
+
+    fn inquiry(&self, cdb: &[u8]) -> CommandResult<Vec<u8>> {
+        let allocation_length = be16(&cdb[3..5]) as usize;
+        let device_type = match self.kind {
+            ScsiLunKind::Disk => 0x00,
+            ScsiLunKind::Cdrom => 0x05,
+        };
+
+        let mut data = if cdb[1] & 0x1 == 0 {
+            if cdb[2] != 0 {
+                return Err(SENSE_INVALID_FIELD.into());
+            }
+            let product: &[u8; 16] = match self.kind {
+                ScsiLunKind::Disk => b"VIRTUAL DISK    ",
+                ScsiLunKind::Cdrom => b"VIRTUAL CDROM   ",
+            };
+            let mut data = vec![
+                device_type,
+                // Removable medium
+                if self.kind == ScsiLunKind::Cdrom {
+                    0x80
+                } else {
+                    0
+                },
+                // SPC-3
+                0x05,
+                // HiSup, response data format 2
+                0x12,
+                31,
+                0,
+                0,
+                // Command queuing
+                0x02,
+            ];
+            data.extend_from_slice(VENDOR);
+            data.extend_from_slice(product);
+            data.extend_from_slice(REVISION);
+            data
+        } else {
+            let page = self.vpd_page(cdb[2])?;
+            let mut data = vec![device_type, cdb[2]];
+            data.extend_from_slice(&(page.len() as u16).to_be_bytes());
+            data.extend_from_slice(&page);
+            data
+        };
+
+        data.truncate(allocation_length);
+        Ok(data)
+    }
+
+    // Returns the content of a vital product data page.
+    fn vpd_page(&self, page: u8) -> CommandResult<Vec<u8>> {
+        let data = match (page, self.kind) {
+            // Supported pages
+            (0x00, ScsiLunKind::Disk) => vec![0x00, 0x80, 0x83, 0xb0, 0xb1, 0xb2],
+            (0x00, ScsiLunKind::Cdrom) => vec![0x00, 0x80, 0x83],
+            // Unit serial number
+            (0x80, _) => self.serial.as_bytes().to_vec(),
+            // Device identification
+            (0x83, _) => {
+                // T10 vendor identification
+                let mut data = vec![0x02, 0x01, 0x00, (8 + self.serial.len()) as u8];
+                data.extend_from_slice(VENDOR);
+                data.extend_from_slice(self.serial.as_bytes());
+                // NAA
+                data.extend_from_slice(&[0x01, 0x03, 0x00, 0x08]);
+                data.extend_from_slice(&naa_identifier(&self.serial).to_be_bytes());
+                data
+            }
+            // Block limits
+            (0xb0, ScsiLunKind::Disk) => {
+                let mut data = vec![0u8; 0x3c];
+                // Write same with a length of zero isn't supported.
+                data[0] = 0x01;
+                if self.discard {
+                    data[16..20].copy_from_slice(&(MAX_WRITE_SAME_BLOCKS as u32).to_be_bytes());
+                    data[20..24].copy_from_slice(&(MAX_UNMAP_DESCRIPTORS as u32).to_be_bytes());
+                }
+                data[32..40].copy_from_slice(&MAX_WRITE_SAME_BLOCKS.to_be_bytes());
+                data
+            }
+            // Block device characteristics
+            (0xb1, ScsiLunKind::Disk) => {
+                let mut data = vec![0u8; 0x3c];
+                // Non-rotating medium
+                data[1] = 0x01;
+                data
+            }
+            // Logical block provisioning
+            (0xb2, ScsiLunKind::Disk) => {
+                let mut data = vec![0u8; 4];
+                if self.discard {
+                    // UNMAP, and WRITE SAME(16) and (10) with UNMAP
+                    data[1] = 0xe0;
+                }
+                data
+            }
+            _ => return Err(SENSE_INVALID_FIELD.into()),
+        };
+        Ok(data)
+    }
+
+    fn read_capacity_16(&self, cdb: &[u8]) -> CommandResult<Vec<u8>> {
+        self.check_medium()?;
+        let mut data = vec![0u8; 32];
+        data[0..8].copy_from_slice(&self.nblocks.saturating_sub(1).to_be_bytes());
+        data[8..12].copy_from_slice(&(self.block_size as u32).to_be_bytes());
+        data[13] = self.physical_block_exp;
+        if self.discard {
+            // Logical block provisioning management enabled
+            data[14] = 0x80;
+        }
+        data.truncate(be32(&cdb[10..14]) as usize);
+        Ok(data)
+    }
+
+    // Returns a mode page, with the current, changeable or default values
+    // as selected by `control`.
+    fn mode_page(&self, page: u8, control: u8) -> Vec<u8> {
+        let changeable = control == 1;
+        match page {
+            MODE_PAGE_CACHING => {
+                let mut data = vec![0u8; 20];
+                data[0] = MODE_PAGE_CACHING;
+                data[1] = 18;
+                if changeable || control == 2 || self.writeback.load(Ordering::Acquire) {
+                    data[2] = MODE_PAGE_CACHING_WCE;
+                }
+                data
+            }
+            MODE_PAGE_CONTROL => {
+                let mut data = vec![0u8; 12];
+                data[0] = MODE_PAGE_CONTROL;
+                data[1] = 10;
+                if !changeable {
+                    // No busy timeout
+                    data[8] = 0xff;
+                    data[9] = 0xff;
+                }
+                data
+            }
+            _ => {
+                let mut data = vec![0u8; 22];
+                data[0] = MODE_PAGE_CD_CAPABILITIES;
+                data[1] = 20;
+                if !changeable {
+                    // Reads CD-R, CD-RW and DVD-ROM media
+                    data[2] = 0x0b;
+                    // Mode 2 form 1 and 2, multi-session
+                    data[4] = 0x70;
+                    // Tray loading mechanism, eject, lock
+                    data[6] = 0x29;
+                    if self.prevent_removal.load(Ordering::Acquire) {
+                        data[6] |= 0x02;
+                    }
+                }
+                data
+            }
+        }
+    }
+
+    fn mode_sense(&self, cdb: &[u8]) -> CommandResult<Vec<u8>> {
+        let ten = cdb[0] == MODE_SENSE_10;
+        let disable_block_descriptors = cdb[1] & 0x08 != 0;
+        let long_lba = ten && cdb[1] & 0x10 != 0;
+        let control = cdb[2] >> 6;
+        let page = cdb[2] & 0x3f;
+        let allocation_length = if ten {
+            be16(&cdb[7..9]) as usize
+        } else {
+            cdb[4] as usize
+        };
+
+        if control == 3 {
+            return Err(SENSE_SAVING_NOT_SUPPORTED.into());
+        }
+        // Subpages aren't supported.
+        if cdb[3] != 0 && cdb[3] != 0xff {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+        let supported_pages: &[u8] = match self.kind {
+            ScsiLunKind::Disk => &[MODE_PAGE_CACHING, MODE_PAGE_CONTROL],
+            ScsiLunKind::Cdrom => &[MODE_PAGE_CONTROL, MODE_PAGE_CD_CAPABILITIES],
+        };
+        let pages: Vec<u8> = if page == MODE_PAGE_ALL {
+            supported_pages.to_vec()
+        } else if supported_pages.contains(&page) {
+            vec![page]
+        } else {
+            return Err(SENSE_INVALID_FIELD.into());
+        };
+
+        let mut block_descriptor = Vec::new();
+        if self.kind == ScsiLunKind::Disk && !disable_block_descriptors {
+            if long_lba {
+                block_descriptor.extend_from_slice(&self.nblocks.to_be_bytes());
+                block_descriptor.extend_from_slice(&[0, 0, 0, 0]);
+            } else {
+                let nblocks = cmp::min(self.nblocks, 0xff_ffff) as u32;
+                block_descriptor.extend_from_slice(&nblocks.to_be_bytes());
+            }
+            block_descriptor.extend_from_slice(&(self.block_size as u32).to_be_bytes());
+        }
+
+        let device_specific = match self.kind {
+            // Write protect, DPO and FUA supported
+            ScsiLunKind::Disk if self.readonly => 0x90,
+            ScsiLunKind::Disk => 0x10,
+            ScsiLunKind::Cdrom => 0x00,
+        };
+        let mut data = if ten {
+            vec![
+                0,
+                0,
+                0,
+                device_specific,
+                if long_lba { 0x1 } else { 0 },
+                0,
+                0,
+                block_descriptor.len() as u8,
+            ]
+        } else {
+            vec![0, 0, device_specific, block_descriptor.len() as u8]
+        };
+        data.extend_from_slice(&block_descriptor);
+        for page in pages {
+            data.extend_from_slice(&self.mode_page(page, control));
+        }
+
+        // The mode data length doesn't include itself.
+        if ten {
+            let length = (data.len() - 2) as u16;
+            data[0..2].copy_from_slice(&length.to_be_bytes());
+        } else {
+            data[0] = (data.len() - 1) as u8;
+        }
+        data.truncate(allocation_length);
+        Ok(data)
+    }
+
+    fn mode_select(&self, cdb: &[u8], data_out: &[libc::iovec]) -> CommandResult<()> {
+        let ten = cdb[0] == MODE_SELECT_10;
+        let (length, header_length) = if ten {
+            (be16(&cdb[7..9]) as u64, 8)
+        } else {
+            (cdb[4] as u64, 4)
+        };
+        // Saved pages aren't supported.
+        if cdb[1] & 0x1 != 0 {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+        let parameters = parameter_list(data_out, length)?;
+        if parameters.len() < header_length {
+            if parameters.is_empty() {
+                return Ok(());
+            }
+            return Err(SENSE_PARAMETER_LIST_LENGTH.into());
+        }
+
+        let block_descriptor_length = if ten {
+            be16(&parameters[6..8]) as usize
+        } else {
+            parameters[3] as usize
+        };
+        let mut offset = header_length + block_descriptor_length;
+        while offset < parameters.len() {
+            if offset + 2 > parameters.len() {
+                return Err(SENSE_PARAMETER_LIST_LENGTH.into());
+            }
+            let page = parameters[offset] & 0x3f;
+            let page_length = parameters[offset + 1] as usize;
+            if offset + 2 + page_length > parameters.len() {
+                return Err(SENSE_PARAMETER_LIST_LENGTH.into());
+            }
+            match page {
+                MODE_PAGE_CACHING if self.kind == ScsiLunKind::Disk && page_length >= 1 => {
+                    self.writeback.store(
+                        parameters[offset + 2] & MODE_PAGE_CACHING_WCE != 0,
+                        Ordering::Release,
+                    );
+                }
+                MODE_PAGE_CONTROL | MODE_PAGE_CD_CAPABILITIES => {}
+                _ => return Err(SENSE_INVALID_PARAMETER.into()),
+            }
+            offset += 2 + page_length;
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, cdb: &[u8]) -> CommandResult<Action> {
+        self.check_medium()?;
+        let (lba, count) = transfer_range(cdb);
+        self.check_range(lba, count)?;
+        if count == 0 {
+            return Ok(Action::Data(Vec::new()));
+        }
+        Ok(Action::Read {
+            offset: lba * self.block_size,
+            len: count * self.block_size,
+        })
+    }
+
+    fn write(&self, cdb: &[u8]) -> CommandResult<Action> {
+        self.check_medium()?;
+        if self.readonly {
+            return Err(SENSE_WRITE_PROTECTED.into());
+        }
+        let (lba, count) = transfer_range(cdb);
+        self.check_range(lba, count)?;
+        if count == 0 {
+            return Ok(Action::Data(Vec::new()));
+        }
+        let fua = match cdb[0] {
+            WRITE_6 => false,
+            // The data must be on the medium to be verified.
+            WRITE_AND_VERIFY_10 | WRITE_AND_VERIFY_12 | WRITE_AND_VERIFY_16 => true,
+            _ => cdb[1] & FUA != 0,
+        };
+        Ok(Action::Write {
+            offset: lba * self.block_size,
+            len: count * self.block_size,
+            sync: fua || !self.writeback.load(Ordering::Acquire),
+        })
+    }
+
+    fn write_same(&self, cdb: &[u8], data_out: &[libc::iovec]) -> CommandResult<Action> {
+        self.check_medium()?;
+        if self.readonly {
+            return Err(SENSE_WRITE_PROTECTED.into());
+        }
+        let unmap = cdb[1] & 0x08 != 0;
+        if unmap && !self.discard {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+        let (lba, count) = if cdb[0] == WRITE_SAME_10 {
+            (be32(&cdb[2..6]) as u64, be16(&cdb[7..9]) as u64)
+        } else {
+            (be64(&cdb[2..10]), be32(&cdb[10..14]) as u64)
+        };
+        if count == 0 || count > MAX_WRITE_SAME_BLOCKS {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+        self.check_range(lba, count)?;
+
+        // Only blocks of zeroes can be written, which is what the guests
+        // use this command for.
+        let block = parameter_list(data_out, self.block_size)?;
+        if block.len() as u64 != self.block_size {
+            return Err(SENSE_PARAMETER_LIST_LENGTH.into());
+        }
+        if block.iter().any(|byte| *byte != 0) {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+
+        Ok(Action::Zero {
+            ranges: VecDeque::from(vec![(lba * self.block_size, count * self.block_size)]),
+            unmap,
+        })
+    }
+
+    fn unmap(&self, cdb: &[u8], data_out: &[libc::iovec]) -> CommandResult<Action> {
+        self.check_medium()?;
+        if !self.discard {
+            return Err(SENSE_INVALID_OPCODE.into());
+        }
+        let parameters = parameter_list(data_out, be16(&cdb[7..9]) as u64)?;
+        if parameters.is_empty() {
+            return Ok(Action::Data(Vec::new()));
+        }
+        if parameters.len() < 8 {
+            return Err(SENSE_PARAMETER_LIST_LENGTH.into());
+        }
+
+        let descriptors_length = cmp::min(be16(&parameters[2..4]) as usize, parameters.len() - 8);
+        if descriptors_length / 16 > MAX_UNMAP_DESCRIPTORS {
+            return Err(SENSE_INVALID_PARAMETER.into());
+        }
+        let mut ranges = VecDeque::new();
+        for descriptor in parameters[8..8 + descriptors_length].chunks_exact(16) {
+            let lba = be64(&descriptor[0..8]);
+            let count = be32(&descriptor[8..12]) as u64;
+            self.check_range(lba, count)?;
+            if count != 0 {
+                ranges.push_back((lba * self.block_size, count * self.block_size));
+            }
+        }
+        if ranges.is_empty() {
+            return Ok(Action::Data(Vec::new()));
+        }
+
+        Ok(Action::Zero {
+            ranges,
+            unmap: true,
+        })
+    }
+
+    fn read_toc(&self, cdb: &[u8]) -> CommandResult<Vec<u8>> {
+        self.check_medium()?;
+        let msf = cdb[1] & 0x02 != 0;
+        let format = cdb[2] & 0xf;
+        let track = cdb[6];
+        let address = |lba: u64| -> [u8; 4] {
+            if msf {
+                lba_to_msf(lba)
+            } else {
+                (lba as u32).to_be_bytes()
+            }
+        };
+
+        let mut data = vec![0, 0, 1, 1];
+        match format {
+            // TOC
+            0 => {
+                if track > 1 && track != 0xaa {
+                    return Err(SENSE_INVALID_FIELD.into());
+                }
+                if track <= 1 {
+                    // Data track
+                    data.extend_from_slice(&[0, 0x14, 1, 0]);
+                    data.extend_from_slice(&address(0));
+                }
+                // Lead-out
+                data.extend_from_slice(&[0, 0x16, 0xaa, 0]);
+                data.extend_from_slice(&address(self.nblocks));
+            }
+            // Session information
+            1 => {
+                data.extend_from_slice(&[0, 0x14, 1, 0]);
+                data.extend_from_slice(&address(0));
+            }
+            _ => return Err(SENSE_INVALID_FIELD.into()),
+        }
+
+        let length = (data.len() - 2) as u16;
+        data[0..2].copy_from_slice(&length.to_be_bytes());
+        data.truncate(be16(&cdb[7..9]) as usize);
+        Ok(data)
+    }
+
+    fn get_configuration(&self, cdb: &[u8]) -> Vec<u8> {
+        let profile: u16 = match self.disk {
+            None => 0,
+            Some(_) if self.nblocks > CD_MAX_BLOCKS => 0x10,
+            Some(_) => 0x08,
+        };
+
+        let mut data = vec![0u8; 8];
+        data[6..8].copy_from_slice(&profile.to_be_bytes());
+        // Profile list, with DVD-ROM and CD-ROM
+        data.extend_from_slice(&[0x00, 0x00, 0x03, 0x08]);
+        data.extend_from_slice(&[0x00, 0x10, (profile == 0x10) as u8, 0]);
+        data.extend_from_slice(&[0x00, 0x08, (profile == 0x08) as u8, 0]);
+        // Core, with the ATAPI interface
+        data.extend_from_slice(&[0x00, 0x01, 0x03, 0x04, 0, 0, 0, 2]);
+        // Removable medium, with a tray which can be locked
+        data.extend_from_slice(&[0x00, 0x03, 0x03, 0x04, 0x29, 0, 0, 0]);
+
+        let length = (data.len() - 4) as u32;
+        data[0..4].copy_from_slice(&length.to_be_bytes());
+        data.truncate(be16(&cdb[7..9]) as usize);
+        data
+    }
+
+    fn get_event_status_notification(&self, cdb: &[u8]) -> CommandResult<Vec<u8>> {
+        // Asynchronous notifications aren't supported.
+        if cdb[1] & 0x1 == 0 {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+
+        let mut data = if cdb[4] & 0x10 != 0 {
+            // Media class, the medium being present without change
+            let media_present = self.disk.is_some() as u8 * 0x2;
+            vec![0, 6, 0x04, 0x10, 0, media_present, 0, 0]
+        } else {
+            // No event available
+            vec![0, 2, 0x80, 0x10]
+        };
+        data.truncate(be16(&cdb[7..9]) as usize);
+        Ok(data)
+    }
+
+    fn read_disc_information(&self, cdb: &[u8]) -> CommandResult<Vec<u8>> {
+        self.check_medium()?;
+        if cdb[1] & 0x7 != 0 {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+
+        let mut data = vec![0u8; 34];
+        data[1] = 32;
+        // Complete disc, complete last session
+        data[2] = 0x0e;
+        // First track, sessions and tracks of the last session
+        data[3] = 1;
+        data[4] = 1;
+        data[5] = 1;
+        data[6] = 1;
+        // Unrestricted use
+        data[7] = 0x20;
+        // No next session
+        data[16..24].fill(0xff);
+        data.truncate(be16(&cdb[7..9]) as usize);
+        Ok(data)
+    }
+}
+
+enum PendingCommand {
+    Read {
+        len: u64,
+        iovecs: Vec<libc::iovec>,
+        bounce: Option<AlignedBuffer>,
+    },
+    Write {
+        len: u64,
+        sync: bool,
+        _bounce: Option<AlignedBuffer>,
+    },
+    Flush,
+    // Ranges zeroed or discarded one after the other, the first one being
+    // in flight.
+    Zero {
+        ranges: VecDeque<(u64, u64)>,
+        unmap: bool,
+    },
+}
+
+// Safe because the guest buffers and bounce buffers are only accessed from
+// the ScsiLunIo methods, which take a mutable reference.
+unsafe impl Send for PendingCommand {}
+
+// Returns a bounce buffer when the buffers aren't suitable for O_DIRECT.
+fn bounce_buffer(iovecs: &[libc::iovec], len: u64) -> CommandResult<Option<AlignedBuffer>> {
+    if iovecs.iter().all(|iovec| {
+        iovec.iov_base as u64 % SECTOR_SIZE == 0 && iovec.iov_len as u64 % SECTOR_SIZE == 0
+    }) {
+        return Ok(None);
+    }
+    AlignedBuffer::new(len as usize).map(Some).map_err(|e| {
+        error!("Failed allocating SCSI bounce buffer: {}", e);
+        SENSE_INTERNAL_TARGET_FAILURE.into()
+    })
+}
+
+fn buffer_iovecs(buffer: &mut AlignedBuffer, len: u64) -> Vec<libc::iovec> {
+    vec![libc::iovec {
+        iov_base: buffer.as_mut_slice(len as usize).as_mut_ptr() as *mut libc::c_void,
+        iov_len: len as usize,
+    }]
+}
+
+/// Executes the commands submitted to a logical unit by one queue.
+pub struct ScsiLunIo {
+    // Dropped before the logical unit, which owns the disk image.
+    disk: Option<Box<dyn AsyncIo>>,
+    lun: Arc<ScsiLun>,
+    commands: HashMap<u64, PendingCommand>,
+}
+
+impl ScsiLunIo {
+    /// Returns the EventFd signaled when submitted commands complete.
+    pub fn notifier(&self) -> Option<&EventFd> {
+        self.disk.as_ref().map(|disk| disk.notifier())
+    }
+
+    pub fn has_pending_commands(&self) -> bool {
+        !self.commands.is_empty()
+    }
+
+    /// Executes the command from `cdb`, with the data sent by the initiator
+    /// in `data_out`, and the buffers for the data returned in `data_in`.
+    /// Returns the completion of the command unless it was submitted to the
+    /// disk image, in which case it's later returned by `complete()` along
+    /// `user_data`.
+    pub fn execute(
+        &mut self,
+        cdb: &[u8],
+        data_out: &[libc::iovec],
+        data_in: &[libc::iovec],
+        user_data: u64,
+    ) -> Option<ScsiCompletion> {
+        let action = match self.lun.emulate(cdb, data_out) {
+            Ok(action) => action,
+            Err(completion) => return Some(completion),
+        };
+
+        let result = match action {
+            Action::Data(data) => return Some(copy_to_initiator(&data, data_in)),
+            Action::Read { offset, len } => self.submit_read(offset, len, data_in, user_data),
+            Action::Write { offset, len, sync } => {
+                self.submit_write(offset, len, sync, data_out, user_data)
+            }
+            Action::Flush => self.submit_flush(user_data),
+            Action::Zero { mut ranges, unmap } => {
+                let range = ranges.pop_front().unwrap();
+                self.submit_range(range, unmap, user_data).map(|_| {
+                    self.commands
+                        .insert(user_data, PendingCommand::Zero { ranges, unmap });
+                })
+            }
+        };
+
+        result.err()
+    }
+
+    fn disk(&mut self) -> CommandResult<&mut Box<dyn AsyncIo>> {
+        self.disk.as_mut().ok_or_else(|| SENSE_NO_MEDIUM.into())
+    }
+
+    fn submit_read(
+        &mut self,
+        offset: u64,
+        len: u64,
+        data_in: &[libc::iovec],
+        user_data: u64,
+    ) -> CommandResult<()> {
+        if iovecs_len(data_in) < len {
+            return Err(ScsiCompletion::overrun());
+        }
+        let iovecs = slice_iovecs(data_in, 0, len);
+        let mut bounce = bounce_buffer(&iovecs, len)?;
+        let submitted = match bounce.as_mut() {
+            Some(buffer) => buffer_iovecs(buffer, len),
+            None => iovecs.clone(),
+        };
+
+        self.disk()?
+            .read_vectored(offset as libc::off_t, submitted, user_data)
+            .map_err(|e| {
+                error!("Failed submitting SCSI read: {}", e);
+                ScsiCompletion::from(SENSE_READ_ERROR)
+            })?;
+        self.commands.insert(
+            user_data,
+            PendingCommand::Read {
+                len,
+                iovecs,
+                bounce,
+            },
+        );
+        Ok(())
+    }
+
+    fn submit_write(
+        &mut self,
+        offset: u64,
+        len: u64,
+        sync: bool,
+        data_out: &[libc::iovec],
+        user_data: u64,
+    ) -> CommandResult<()> {
+        if iovecs_len(data_out) < len {
+            return Err(ScsiCompletion::overrun());
+        }
+        let iovecs = slice_iovecs(data_out, 0, len);
+        let mut bounce = bounce_buffer(&iovecs, len)?;
+        let submitted = match bounce.as_mut() {
+            Some(buffer) => {
+                gather(&iovecs, buffer.as_mut_slice(len as usize));
+                buffer_iovecs(buffer, len)
+            }
+            None => iovecs,
+        };
+
+        self.disk()?
+            .write_vectored(offset as libc::off_t, submitted, user_data)
+            .map_err(|e| {
+                error!("Failed submitting SCSI write: {}", e);
+                ScsiCompletion::from(SENSE_WRITE_ERROR)
+            })?;
+        self.commands.insert(
+            user_data,
+            PendingCommand::Write {
+                len,
+                sync,
+                _bounce: bounce,
+            },
+        );
+        Ok(())
+    }
+
+    fn submit_flush(&mut self, user_data: u64) -> CommandResult<()> {
+        self.disk()?.fsync(Some(user_data)).map_err(|e| {
+            error!("Failed submitting SCSI flush: {}", e);
+            ScsiCompletion::from(SENSE_WRITE_ERROR)
+        })?;
+        self.commands.insert(user_data, PendingCommand::Flush);
+        Ok(())
+    }
+
+    fn submit_range(
+        &mut self,
+        range: (u64, u64),
+        unmap: bool,
+        user_data: u64,
+    ) -> CommandResult<()> {
+        let (offset, len) = range;
+        let disk = self.disk()?;
+        let result = if unmap {
+            disk.punch_hole(offset as libc::off_t, len, user_data)
+        } else {
+            disk.write_zeroes(offset as libc::off_t, len, user_data)
+        };
+        result.map_err(|e| {
+            error!("Failed submitting SCSI write zeroes: {}", e);
+            ScsiCompletion::from(SENSE_WRITE_ERROR)
+        })
+    }
+
+    /// Returns the commands which completed since the last call.
+    pub fn complete(&mut self) -> Vec<(u64, ScsiCompletion)> {
+        let completion_list = match self.disk.as_mut() {
+            Some(disk) => disk.complete(),
+            None => return Vec::new(),
+        };
+
+        let mut completions = Vec::new();
+        for (user_data, result) in completion_list {
+            let command = match self.commands.remove(&user_data) {
+                Some(command) => command,
+                None => {
+                    error!("Unexpected completion of SCSI command {}", user_data);
+                    continue;
+                }
+            };
+            if let Some(completion) = self.command_done(user_data, command, result) {
+                completions.push((user_data, completion));
+            }
+        }
+        completions
+    }
+
+    fn command_done(
+        &mut self,
+        user_data: u64,
+        command: PendingCommand,
+        result: i32,
+    ) -> Option<ScsiCompletion> {
+        if result < 0 {
+            error!(
+                "SCSI command failed: {}",
+                io::Error::from_raw_os_error(-result)
+            );
+            let sense = match command {
+                PendingCommand::Read { .. } => SENSE_READ_ERROR,
+                _ => SENSE_WRITE_ERROR,
+            };
+            return Some(sense.into());
+        }
+
+        match command {
+            PendingCommand::Read {
+                len,
+                iovecs,
+                mut bounce,
+            } => {
+                if let Some(buffer) = bounce.as_mut() {
+                    scatter(buffer.as_mut_slice(len as usize), &iovecs);
+                }
+                Some(ScsiCompletion::good(len))
+            }
+            PendingCommand::Write { len, sync, .. } => {
+                if sync {
+                    if let Err(e) = self.disk.as_mut().unwrap().fsync(None) {
+                        error!("Failed synchronizing SCSI write: {}", e);
+                        return Some(SENSE_WRITE_ERROR.into());
+                    }
+                }
+                Some(ScsiCompletion::good(len))
+            }
+            PendingCommand::Flush => Some(ScsiCompletion::good(0)),
+            PendingCommand::Zero { mut ranges, unmap } => match ranges.pop_front() {
+                Some(range) => match self.submit_range(range, unmap, user_data) {
+                    Ok(()) => {
+                        self.commands
+                            .insert(user_data, PendingCommand::Zero { ranges, unmap });
+                        None
+                    }
+                    Err(completion) => Some(completion),
+                },
+                None => Some(ScsiCompletion::good(0)),
+            },
+        }
+    }
+}
+
+/// Executes a command addressed to a logical unit which doesn't exist on a
+/// target exposing `luns`.
+pub fn execute_target_command(cdb: &[u8], luns: &[u16], data_in: &[libc::iovec]) -> ScsiCompletion {
+    let result = check_cdb(cdb).and_then(|opcode| match opcode {
+        REPORT_LUNS => {
+            let mut luns = luns.to_vec();
+            luns.sort_unstable();
+            let mut data = ((luns.len() * 8) as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            for lun in luns {
+                // Peripheral addressing below 256, flat space above.
+                let address = if lun < 256 {
+                    [0, lun as u8]
+                } else {
+                    [0x40 | (lun >> 8) as u8, lun as u8]
+                };
+                data.extend_from_slice(&address);
+                data.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+            }
+            data.truncate(be32(&cdb[6..10]) as usize);
+            Ok(data)
+        }
+        INQUIRY => {
+            // No device is connected to the logical unit.
+            let mut data = vec![0x7f, 0, 0x05, 0x12, 31, 0, 0, 0];
+            data.resize(36, 0);
+            data.truncate(be16(&cdb[3..5]) as usize);
+            Ok(data)
+        }
+        REQUEST_SENSE => {
+            let mut data = SENSE_LUN_NOT_SUPPORTED.to_fixed_format().to_vec();
+            data.truncate(cdb[4] as usize);
+            Ok(data)
+        }
+        _ => Err(SENSE_LUN_NOT_SUPPORTED.into()),
+    });
+
+    match result {
+        Ok(data) => copy_to_initiator(&data, data_in),
+        Err(completion) => completion,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_sync::RawFileDiskSync;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const BLOCKS: u64 = 128;
+
+    fn new_lun(kind: ScsiLunKind, discard: bool) -> (Arc<ScsiLun>, TempFile) {
+        let file = TempFile::new().unwrap();
+        let block_size = if kind == ScsiLunKind::Cdrom {
+            CDROM_BLOCK_SIZE
+        } else {
+            SECTOR_SIZE
+        };
+        file.as_file().set_len(BLOCKS * block_size).unwrap();
+        let disk = RawFileDiskSync::new(file.as_file().try_clone().unwrap());
+        let lun = ScsiLun::new(
+            kind,
+            Some(Box::new(disk)),
+            false,
+            discard,
+            "serial".to_string(),
+            PersistentReservations::new(1),
+        )
+        .unwrap();
+        (Arc::new(lun), file)
+    }
+
+    fn iovec(buffer: &mut [u8]) -> Vec<libc::iovec> {
+        vec![libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        }]
+    }
+
+    // Executes a command, waiting for its completion.
+    fn run(io: &mut ScsiLunIo, cdb: &[u8], data_out: &[u8], data_in: &mut [u8]) -> ScsiCompletion {
+        let mut data_out = data_out.to_vec();
+        let completion = io.execute(cdb, &iovec(&mut data_out), &iovec(data_in), 7);
+        if let Some(completion) = completion {
+            return completion;
+        }
+        loop {
+            if let Some((user_data, completion)) = io.complete().into_iter().next() {
+                assert_eq!(user_data, 7);
+                return completion;
+            }
+        }
+    }
+
+    #[test]
+    fn test_scsi_inquiry() {
+        let (lun, _file) = new_lun(ScsiLunKind::Disk, true);
+        let mut io = lun.new_io(1).unwrap();
+        let mut data = [0u8; 64];
+
+        let completion = run(&mut io, &[INQUIRY, 0, 0, 0, 64, 0], &[], &mut data);
+        assert_eq!(completion, ScsiCompletion::good(36));
+        assert_eq!(data[0], 0);
+        assert_eq!(&data[8..16], VENDOR);
+        assert_eq!(&data[16..28], b"VIRTUAL DISK");
+
+        let completion = run(&mut io, &[INQUIRY, 1, 0x80, 0, 64, 0], &[], &mut data);
+        assert_eq!(completion, ScsiCompletion::good(10));
+        assert_eq!(&data[4..10], b"serial");
+
+        // Logical block provisioning
+        let completion = run(&mut io, &[INQUIRY, 1, 0xb2, 0, 64, 0], &[], &mut data);
+        assert_eq!(completion, ScsiCompletion::good(8));
+        assert_eq!(data[5], 0xe0);
+
+        let completion = run(&mut io, &[INQUIRY, 1, 0x42, 0, 64, 0], &[], &mut data);
+        assert_eq!(completion, SENSE_INVALID_FIELD.into());
+
+        let (lun, _file) = new_lun(ScsiLunKind::Cdrom, false);
+        let mut io = lun.new_io(1).unwrap();
+        run(&mut io, &[INQUIRY, 0, 0, 0, 64, 0], &[], &mut data);
+        assert_eq!(&data[0..2], &[0x05, 0x80]);
+    }
+
+    #[test]
+    fn test_scsi_read_capacity() {
+        let (lun, _file) = new_lun(ScsiLunKind::Disk, true);
+        let mut io = lun.new_io(1).unwrap();
+        let mut data = [0u8; 32];
+
+        let completion = run(
+            &mut io,
+            &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[],
+            &mut data,
+        );
+        assert_eq!(completion, ScsiCompletion::good(8));
+        assert_eq!(be32(&data[0..4]) as u64, BLOCKS - 1);
+        assert_eq!(be32(&data[4..8]) as u64, SECTOR_SIZE);
+
+        let mut cdb = [0u8; 16];
+        cdb[0] = SERVICE_ACTION_IN_16;
+        cdb[1] = READ_CAPACITY_16;
+        cdb[13] = 32;
+        let completion = run(&mut io, &cdb, &[], &mut data);
+        assert_eq!(completion, ScsiCompletion::good(32));
+        assert_eq!(be64(&data[0..8]), BLOCKS - 1);
+        assert_eq!(data[14], 0x80);
+    }
+
+    #[test]
+    fn test_scsi_read_write() {
+        let (lun, _file) = new_lun(ScsiLunKind::Disk, true);
+        let mut io = lun.new_io(1).unwrap();
+
+        // Unaligned buffers go through a bounce buffer.
+        let mut data: Vec<u8> = (0..2 * SECTOR_SIZE + 1).map(|i| i as u8).collect();
+        let cdb = [WRITE_10, FUA, 0, 0, 0, 4, 0, 0, 2, 0];
+        let completion = run(&mut io, &cdb, &data[1..], &mut []);
+        assert_eq!(completion, ScsiCompletion::good(2 * SECTOR_SIZE));
+
+        let mut read = vec![0u8; 2 * SECTOR_SIZE as usize + 1];
+        let completion = run(
+            &mut io,
+            &[READ_10, 0, 0, 0, 0, 4, 0, 0, 2, 0],
+            &[],
+            &mut read[1..],
+        );
+        assert_eq!(completion, ScsiCompletion::good(2 * SECTOR_SIZE));
+        assert_eq!(read[1..], data[1..]);
+
+        let completion = run(&mut io, &[READ_6, 0, 0, 4, 4, 0], &[], &mut read[1..]);
+        assert_eq!(completion, ScsiCompletion::overrun());
+
+        let completion = run(
+            &mut io,
+            &[READ_10, 0, 0, 0, 0, 127, 0, 0, 2, 0],
+            &[],
+            &mut read,
+        );
+        assert_eq!(completion, SENSE_LBA_OUT_OF_RANGE.into());
+
+        // Discarding the blocks zeroes them.
+        let mut parameters = vec![0, 22, 0, 16, 0, 0, 0, 0];
+        parameters.extend_from_slice(&5u64.to_be_bytes());
+        parameters.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+        let completion = run(
+            &mut io,
+            &[UNMAP, 0, 0, 0, 0, 0, 0, 0, 24, 0],
+            &parameters,
+            &mut [],
+        );
+        assert_eq!(completion, ScsiCompletion::good(0));
+        run(
+            &mut io,
+            &[READ_10, 0, 0, 0, 0, 4, 0, 0, 2, 0],
+            &[],
+            &mut read[1..],
+        );
+        assert_eq!(
+            read[1..SECTOR_SIZE as usize + 1],
+            data[1..SECTOR_SIZE as usize + 1]
+        );
+        assert!(read[SECTOR_SIZE as usize + 1..]
+            .iter()
+            .all(|byte| *byte == 0));
+
+        data.truncate(SECTOR_SIZE as usize);
+        data.fill(0);
+        let completion = run(
+            &mut io,
+            &[WRITE_SAME_10, 0, 0, 0, 0, 4, 0, 0, 1, 0],
+            &data,
+            &mut [],
+        );
+        assert_eq!(completion, ScsiCompletion::good(0));
+        run(
+            &mut io,
+            &[READ_10, 0, 0, 0, 0, 4, 0, 0, 1, 0],
+            &[],
+            &mut read[..SECTOR_SIZE as usize],
+        );
+        assert!(read[..SECTOR_SIZE as usize].iter().all(|byte| *byte == 0));
+
+        let completion = run(
+            &mut io,
+            &[SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[],
+            &mut [],
+        );
+        assert_eq!(completion, ScsiCompletion::good(0));
+    }
+
+    #[test]
+    fn test_scsi_mode_sense() {
+        let (lun, _file) = new_lun(ScsiLunKind::Disk, false);
+        let mut io = lun.new_io(1).unwrap();
+        let mut data = [0u8; 64];
+
+        let completion = run(
+            &mut io,
+            &[MODE_SENSE_6, 0x08, MODE_PAGE_CACHING, 0, 64, 0],
+            &[],
+            &mut data,
+        );
+        assert_eq!(completion, ScsiCompletion::good(24));
+        assert_eq!(&data[0..4], &[23, 0, 0x10, 0]);
+        assert_eq!(data[6], MODE_PAGE_CACHING_WCE);
+
+        // Disable the write cache.
+        let mut parameters = vec![0u8; 24];
+        parameters[4] = MODE_PAGE_CACHING;
+        parameters[5] = 18;
+        let completion = run(
+            &mut io,
+            &[MODE_SELECT_6, 0x10, 0, 0, 24, 0],
+            &parameters,
+            &mut [],
+        );
+        assert_eq!(completion, ScsiCompletion::good(0));
+        assert!(!lun.writeback.load(Ordering::Acquire));
+
+        let completion = run(
+            &mut io,
+            &[MODE_SENSE_6, 0, MODE_PAGE_ALL, 0, 64, 0],
+            &[],
+            &mut data,
+        );
+        assert_eq!(completion, ScsiCompletion::good(44));
+        assert_eq!(data[3], 8);
+        assert_eq!(data[14], 0);
+    }
+
+    #[test]
+    fn test_scsi_unit_attention() {
+        let (lun, _file) = new_lun(ScsiLunKind::Disk, false);
+        let mut io = lun.new_io(1).unwrap();
+        let tur = [TEST_UNIT_READY, 0, 0, 0, 0, 0];
+
+        lun.reset();
+        assert_eq!(run(&mut io, &tur, &[], &mut []), SENSE_RESET.into());
+        assert_eq!(run(&mut io, &tur, &[], &mut []), ScsiCompletion::good(0));
+    }
+
+    #[test]
+    fn test_scsi_cdrom() {
+        let (lun, _file) = new_lun(ScsiLunKind::Cdrom, false);
+        let mut io = lun.new_io(1).unwrap();
+        let mut data = [0u8; 64];
+
+        let completion = run(
+            &mut io,
+            &[READ_TOC, 0, 0, 0, 0, 0, 0, 0, 64, 0],
+            &[],
+            &mut data,
+        );
+        assert_eq!(completion, ScsiCompletion::good(20));
+        assert_eq!(&data[0..4], &[0, 18, 1, 1]);
+        assert_eq!(data[14], 0xaa);
+        assert_eq!(be32(&data[16..20]) as u64, BLOCKS);
+
+        let completion = run(
+            &mut io,
+            &[READ_TOC, 0x2, 0, 0, 0, 0, 0, 0, 64, 0],
+            &[],
+            &mut data,
+        );
+        assert_eq!(completion, ScsiCompletion::good(20));
+        assert_eq!(&data[8..12], &[0, 0, 2, 0]);
+
+        let completion = run(
+            &mut io,
+            &[WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0],
+            &[0; 2048],
+            &mut [],
+        );
+        assert_eq!(completion, SENSE_INVALID_OPCODE.into());
+
+        run(
+            &mut io,
+            &[PREVENT_ALLOW_MEDIUM_REMOVAL, 0, 0, 0, 1, 0],
+            &[],
+            &mut [],
+        );
+        let completion = run(&mut io, &[START_STOP_UNIT, 0, 0, 0, 2, 0], &[], &mut []);
+        assert_eq!(completion, SENSE_REMOVAL_PREVENTED.into());
+
+        let mut read = vec![0u8; CDROM_BLOCK_SIZE as usize];
+        let completion = run(
+            &mut io,
+            &[READ_10, 0, 0, 0, 0, 1, 0, 0, 1, 0],
+            &[],
+            &mut read,
+        );
+        assert_eq!(completion, ScsiCompletion::good(CDROM_BLOCK_SIZE));
+    }
+
+    fn pr_out(
+        io: &mut ScsiLunIo,
+        action: u8,
+        reservation_type: u8,
+        key: u64,
+        sa_key: u64,
+    ) -> ScsiCompletion {
+        let mut parameters = key.to_be_bytes().to_vec();
+        parameters.extend_from_slice(&sa_key.to_be_bytes());
+        parameters.resize(24, 0);
+        let cdb = [
+            PERSISTENT_RESERVE_OUT,
+            action,
+            reservation_type,
+            0,
+            0,
+            0,
+            0,
+            0,
+            24,
+            0,
+        ];
+        run(io, &cdb, &parameters, &mut [])
+    }
+
+    #[test]
+    fn test_scsi_persistent_reservations() {
+        let file = TempFile::new().unwrap();
+        let disk_file = TempFile::new().unwrap();
+        disk_file.as_file().set_len(BLOCKS * SECTOR_SIZE).unwrap();
+        let new_lun = |initiator| {
+            let disk = RawFileDiskSync::new(disk_file.as_file().try_clone().unwrap());
+            let reservations = PersistentReservations::open(file.as_path(), initiator).unwrap();
+            let lun = ScsiLun::new(
+                ScsiLunKind::Disk,
+                Some(Box::new(disk)),
+                false,
+                false,
+                String::new(),
+                reservations,
+            )
+            .unwrap();
+            Arc::new(lun).new_io(1).unwrap()
+        };
+        let mut first = new_lun(1);
+        let mut second = new_lun(2);
+        let write = [WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let read = [READ_10, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let block = vec![0u8; SECTOR_SIZE as usize];
+        let mut data = vec![0u8; SECTOR_SIZE as usize];
+
+        assert_eq!(pr_out(&mut first, 0, 0, 0, 0xa), ScsiCompletion::good(0));
+        assert_eq!(pr_out(&mut second, 0, 0, 0, 0xb), ScsiCompletion::good(0));
+        // Wrong key
+        assert_eq!(
+            pr_out(&mut second, 1, 1, 0xa, 0),
+            ScsiCompletion::reservation_conflict()
+        );
+
+        // Write exclusive
+        assert_eq!(pr_out(&mut first, 1, 1, 0xa, 0), ScsiCompletion::good(0));
+        assert_eq!(
+            run(&mut first, &write, &block, &mut []),
+            ScsiCompletion::good(SECTOR_SIZE)
+        );
+        assert_eq!(
+            run(&mut second, &write, &block, &mut []),
+            ScsiCompletion::reservation_conflict()
+        );
+        assert_eq!(
+            run(&mut second, &read, &[], &mut data),
+            ScsiCompletion::good(SECTOR_SIZE)
+        );
+
+        let cdb = [PERSISTENT_RESERVE_IN, 0, 0, 0, 0, 0, 0, 0, 64, 0];
+        let completion = run(&mut second, &cdb, &[], &mut data);
+        assert_eq!(completion, ScsiCompletion::good(24));
+        assert_eq!(be32(&data[0..4]), 2);
+        assert_eq!(be64(&data[8..16]), 0xa);
+        assert_eq!(be64(&data[16..24]), 0xb);
+
+        let cdb = [PERSISTENT_RESERVE_IN, 1, 0, 0, 0, 0, 0, 0, 64, 0];
+        let completion = run(&mut second, &cdb, &[], &mut data);
+        assert_eq!(completion, ScsiCompletion::good(24));
+        assert_eq!(be64(&data[8..16]), 0xa);
+        assert_eq!(data[21], 1);
+
+        // The second initiator takes over the reservation as exclusive
+        // access, removing the registration of the first one.
+        assert_eq!(pr_out(&mut second, 4, 3, 0xb, 0xa), ScsiCompletion::good(0));
+        assert_eq!(
+            run(&mut first, &read, &[], &mut data),
+            ScsiCompletion::reservation_conflict()
+        );
+        assert_eq!(
+            run(&mut second, &write, &block, &mut []),
+            ScsiCompletion::good(SECTOR_SIZE)
+        );
+        assert_eq!(
+            pr_out(&mut first, 0, 0, 0xa, 0xc),
+            ScsiCompletion::reservation_conflict()
+        );
+
+        // Releasing with the wrong type
+        assert_eq!(
+            pr_out(&mut second, 2, 1, 0xb, 0),
+            SENSE_INVALID_RELEASE.into()
+        );
+        assert_eq!(pr_out(&mut second, 2, 3, 0xb, 0), ScsiCompletion::good(0));
+        assert_eq!(
+            run(&mut first, &write, &block, &mut []),
+            ScsiCompletion::good(SECTOR_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_scsi_persistent_reservations_state() {
+        let (lun, _file) = new_lun(ScsiLunKind::Disk, false);
+        let mut io = lun.new_io(1).unwrap();
+        assert_eq!(pr_out(&mut io, 0, 0, 0, 0xa), ScsiCompletion::good(0));
+        assert_eq!(pr_out(&mut io, 1, 3, 0xa, 0), ScsiCompletion::good(0));
+        let state = lun.reservations_state().unwrap();
+
+        // The logical unit of the restored VM holds the same reservation.
+        let (restored_lun, _restored_file) = new_lun(ScsiLunKind::Disk, false);
+        restored_lun.set_reservations_state(&state);
+        assert_eq!(restored_lun.reservations_state(), Some(state));
+        let mut io = restored_lun.new_io(1).unwrap();
+        let mut data = vec![0u8; 64];
+        let cdb = [PERSISTENT_RESERVE_IN, 1, 0, 0, 0, 0, 0, 0, 64, 0];
+        let completion = run(&mut io, &cdb, &[], &mut data);
+        assert_eq!(completion, ScsiCompletion::good(24));
+        assert_eq!(be32(&data[0..4]), 1);
+        assert_eq!(be64(&data[8..16]), 0xa);
+        assert_eq!(data[21], 3);
+        assert_eq!(pr_out(&mut io, 2, 3, 0xa, 0), ScsiCompletion::good(0));
+
+        // Reservations shared through a file aren't part of the state.
+        let file = TempFile::new().unwrap();
+        let reservations = PersistentReservations::open(file.as_path(), 1).unwrap();
+        assert!(reservations.get_state().is_none());
+    }
+
+    #[test]
+    fn test_scsi_target_commands() {
+        let mut data = [0u8; 64];
+
+        let cdb = [REPORT_LUNS, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0, 0];
+        let completion = execute_target_command(&cdb, &[300, 1], &iovec(&mut data));
+        assert_eq!(completion, ScsiCompletion::good(24));
+        assert_eq!(be32(&data[0..4]), 16);
+        assert_eq!(&data[8..10], &[0, 1]);
+        assert_eq!(&data[16..18], &[0x41, 0x2c]);
+
+        let completion = execute_target_command(&[INQUIRY, 0, 0, 0, 36, 0], &[], &iovec(&mut data));
+        assert_eq!(completion, ScsiCompletion::good(36));
+        assert_eq!(data[0], 0x7f);
+
+        let completion = execute_target_command(&[TEST_UNIT_READY, 0, 0, 0, 0, 0], &[], &[]);
+        assert_eq!(completion, SENSE_LUN_NOT_SUPPORTED.into());
+    }
+}
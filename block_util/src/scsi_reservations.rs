@@ -0,0 +1,632 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! SCSI persistent reservations, as defined by SPC-4.
+//!
+//! The state of the reservations can be shared through a file by the VMs
+//! accessing the same disk image, each of them registering as an initiator
+//! identified by a unique number. Updates are serialized by locking the file,
+//! the I/O path only reading the state from the shared mapping of the file.
+
+use crate::scsi::{
+    be16, be32, be64, ScsiCompletion, SENSE_INSUFFICIENT_REGISTRATION_RESOURCES,
+    SENSE_INTERNAL_TARGET_FAILURE, SENSE_INVALID_FIELD, SENSE_INVALID_PARAMETER,
+    SENSE_INVALID_RELEASE, SENSE_PARAMETER_LIST_LENGTH,
+};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+const RESERVATIONS_MAGIC: u64 = u64::from_le_bytes(*b"CHSCSIPR");
+// Number of initiators which can register with the logical unit.
+const MAX_REGISTRANTS: usize = 64;
+// Size of the file holding the state shared between VMs.
+const RESERVATIONS_FILE_SIZE: usize = 4096;
+
+// Reservation types.
+const WRITE_EXCLUSIVE: u8 = 0x1;
+const EXCLUSIVE_ACCESS: u8 = 0x3;
+const WRITE_EXCLUSIVE_REGISTRANTS_ONLY: u8 = 0x5;
+const EXCLUSIVE_ACCESS_REGISTRANTS_ONLY: u8 = 0x6;
+const WRITE_EXCLUSIVE_ALL_REGISTRANTS: u8 = 0x7;
+const EXCLUSIVE_ACCESS_ALL_REGISTRANTS: u8 = 0x8;
+
+// PERSISTENT RESERVE IN service actions.
+const READ_KEYS: u8 = 0x0;
+const READ_RESERVATION: u8 = 0x1;
+const REPORT_CAPABILITIES: u8 = 0x2;
+
+// PERSISTENT RESERVE OUT service actions.
+const REGISTER: u8 = 0x0;
+const RESERVE: u8 = 0x1;
+const RELEASE: u8 = 0x2;
+const CLEAR: u8 = 0x3;
+const PREEMPT: u8 = 0x4;
+const PREEMPT_AND_ABORT: u8 = 0x5;
+const REGISTER_AND_IGNORE_EXISTING_KEY: u8 = 0x6;
+
+const PR_OUT_PARAMETER_LIST_LENGTH: usize = 24;
+const SPEC_I_PT: u8 = 0x08;
+
+/// Access to the medium a command needs, checked against the reservation
+/// held on the logical unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// Always allowed, whatever the reservation.
+    Unrestricted,
+    Read,
+    Write,
+}
+
+#[repr(C)]
+struct Registrant {
+    initiator: AtomicU64,
+    // Zero when the slot is free.
+    key: AtomicU64,
+}
+
+#[repr(C)]
+struct ReservationState {
+    magic: AtomicU64,
+    generation: AtomicU64,
+    // Type of the reservation in the low byte, and 1 + the slot of the
+    // registrant holding it above. Zero when the logical unit isn't reserved.
+    reservation: AtomicU64,
+    registrants: [Registrant; MAX_REGISTRANTS],
+}
+
+/// State of the reservations only known to this VM, which is saved along
+/// with the device. The ones shared through a file are kept in the file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Versionize)]
+pub struct PersistentReservationsState {
+    pub generation: u64,
+    pub reservation: u64,
+    // Initiator and key of each registrant slot
+    pub initiators: Vec<u64>,
+    pub keys: Vec<u64>,
+}
+
+enum Storage {
+    Local(Box<ReservationState>),
+    // Shared mapping of the file holding the state.
+    Shared {
+        file: File,
+        state: *const ReservationState,
+    },
+}
+
+/// Persistent reservations of a logical unit, as seen by one initiator.
+pub struct PersistentReservations {
+    storage: Storage,
+    initiator: u64,
+    // Serializes the updates made from the different queues.
+    lock: Mutex<()>,
+}
+
+// Safe because the shared state is only made of atomics, mapped for the
+// lifetime of the object.
+unsafe impl Send for PersistentReservations {}
+unsafe impl Sync for PersistentReservations {}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    // Safe because the file descriptor is valid.
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+struct UpdateGuard<'a> {
+    _lock: MutexGuard<'a, ()>,
+    file: Option<&'a File>,
+}
+
+impl Drop for UpdateGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(file) = self.file {
+            if let Err(e) = flock(file, libc::LOCK_UN) {
+                error!("Failed unlocking persistent reservations: {}", e);
+            }
+        }
+    }
+}
+
+fn is_valid_type(reservation_type: u8) -> bool {
+    matches!(
+        reservation_type,
+        WRITE_EXCLUSIVE
+            | EXCLUSIVE_ACCESS
+            | WRITE_EXCLUSIVE_REGISTRANTS_ONLY
+            | EXCLUSIVE_ACCESS_REGISTRANTS_ONLY
+            | WRITE_EXCLUSIVE_ALL_REGISTRANTS
+            | EXCLUSIVE_ACCESS_ALL_REGISTRANTS
+    )
+}
+
+fn is_all_registrants(reservation_type: u8) -> bool {
+    matches!(
+        reservation_type,
+        WRITE_EXCLUSIVE_ALL_REGISTRANTS | EXCLUSIVE_ACCESS_ALL_REGISTRANTS
+    )
+}
+
+fn encode(reservation_type: u8, slot: usize) -> u64 {
+    reservation_type as u64 | (slot as u64 + 1) << 8
+}
+
+fn type_of(reservation: u64) -> u8 {
+    reservation as u8
+}
+
+fn holder(reservation: u64) -> usize {
+    (reservation >> 8) as usize - 1
+}
+
+fn conflict<T>() -> Result<T, ScsiCompletion> {
+    Err(ScsiCompletion::reservation_conflict())
+}
+
+impl PersistentReservations {
+    /// Creates reservations only known to this VM, which registers as
+    /// `initiator`.
+    pub fn new(initiator: u64) -> Self {
+        // Safe because the state is only made of integers, for which zero is
+        // a valid value.
+        let state: Box<ReservationState> = Box::new(unsafe { std::mem::zeroed() });
+        state.magic.store(RESERVATIONS_MAGIC, Ordering::Release);
+        PersistentReservations {
+            storage: Storage::Local(state),
+            initiator,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Opens the reservations shared with other VMs through the file at
+    /// `path`, which is created if it doesn't exist yet. `initiator` must be
+    /// unique among the VMs sharing the file.
+    pub fn open(path: &Path, initiator: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        flock(&file, libc::LOCK_EX)?;
+        let state = Self::map(&file);
+        flock(&file, libc::LOCK_UN)?;
+        let state =
+            state.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+        Ok(PersistentReservations {
+            storage: Storage::Shared { file, state },
+            initiator,
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn map(file: &File) -> io::Result<*const ReservationState> {
+        if file.metadata()?.len() < RESERVATIONS_FILE_SIZE as u64 {
+            file.set_len(RESERVATIONS_FILE_SIZE as u64)?;
+        }
+
+        // Safe because the file is at least as large as the mapping, and the
+        // result is checked.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                RESERVATIONS_FILE_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let state = addr as *const ReservationState;
+        // Safe because the mapping is large enough for the state.
+        let magic = unsafe { &(*state).magic };
+        match magic.load(Ordering::Acquire) {
+            0 => magic.store(RESERVATIONS_MAGIC, Ordering::Release),
+            RESERVATIONS_MAGIC => {}
+            _ => {
+                // Safe because the mapping isn't used anymore.
+                unsafe { libc::munmap(addr, RESERVATIONS_FILE_SIZE) };
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Not a persistent reservations file",
+                ));
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Returns the state of the reservations, unless they are shared
+    /// through a file.
+    pub fn get_state(&self) -> Option<PersistentReservationsState> {
+        if let Storage::Shared { .. } = self.storage {
+            return None;
+        }
+
+        let state = self.state();
+        Some(PersistentReservationsState {
+            generation: state.generation.load(Ordering::Acquire),
+            reservation: state.reservation.load(Ordering::Acquire),
+            initiators: state
+                .registrants
+                .iter()
+                .map(|registrant| registrant.initiator.load(Ordering::Acquire))
+                .collect(),
+            keys: state
+                .registrants
+                .iter()
+                .map(|registrant| registrant.key.load(Ordering::Acquire))
+                .collect(),
+        })
+    }
+
+    /// Restores the state returned by `get_state()`. Reservations shared
+    /// through a file are left as the file holds them.
+    pub fn set_state(&self, saved: &PersistentReservationsState) {
+        if let Storage::Shared { .. } = self.storage {
+            return;
+        }
+
+        let _lock = self.lock.lock().unwrap();
+        let state = self.state();
+        state.generation.store(saved.generation, Ordering::Release);
+        state
+            .reservation
+            .store(saved.reservation, Ordering::Release);
+        for (i, registrant) in state.registrants.iter().enumerate() {
+            registrant.initiator.store(
+                saved.initiators.get(i).copied().unwrap_or_default(),
+                Ordering::Release,
+            );
+            registrant.key.store(
+                saved.keys.get(i).copied().unwrap_or_default(),
+                Ordering::Release,
+            );
+        }
+    }
+
+    fn state(&self) -> &ReservationState {
+        match &self.storage {
+            Storage::Local(state) => state,
+            // Safe because the state stays mapped until the object is dropped.
+            Storage::Shared { state, .. } => unsafe { &**state },
+        }
+    }
+
+    fn lock(&self) -> Result<UpdateGuard, ScsiCompletion> {
+        let lock = self.lock.lock().unwrap();
+        let file = match &self.storage {
+            Storage::Local(_) => None,
+            Storage::Shared { file, .. } => {
+                flock(file, libc::LOCK_EX).map_err(|e| {
+                    error!("Failed locking persistent reservations: {}", e);
+                    ScsiCompletion::from(SENSE_INTERNAL_TARGET_FAILURE)
+                })?;
+                Some(file)
+            }
+        };
+        Ok(UpdateGuard { _lock: lock, file })
+    }
+
+    // Returns the slot this initiator is registered in.
+    fn find_slot(&self) -> Option<usize> {
+        self.state().registrants.iter().position(|registrant| {
+            registrant.key.load(Ordering::Acquire) != 0
+                && registrant.initiator.load(Ordering::Acquire) == self.initiator
+        })
+    }
+
+    fn holds(&self, reservation: u64, slot: usize) -> bool {
+        is_all_registrants(type_of(reservation)) || holder(reservation) == slot
+    }
+
+    /// Returns whether a command needing `access` to the medium can be
+    /// executed by this initiator.
+    pub(crate) fn is_allowed(&self, access: Access) -> bool {
+        let reservation = self.state().reservation.load(Ordering::Acquire);
+        if reservation == 0 || access == Access::Unrestricted {
+            return true;
+        }
+
+        let reservation_type = type_of(reservation);
+        if let Some(slot) = self.find_slot() {
+            if self.holds(reservation, slot)
+                || matches!(
+                    reservation_type,
+                    WRITE_EXCLUSIVE_REGISTRANTS_ONLY | EXCLUSIVE_ACCESS_REGISTRANTS_ONLY
+                )
+            {
+                return true;
+            }
+        }
+
+        access == Access::Read
+            && !matches!(
+                reservation_type,
+                EXCLUSIVE_ACCESS
+                    | EXCLUSIVE_ACCESS_REGISTRANTS_ONLY
+                    | EXCLUSIVE_ACCESS_ALL_REGISTRANTS
+            )
+    }
+
+    /// Executes a PERSISTENT RESERVE IN command, returning the parameter
+    /// data.
+    pub(crate) fn read(&self, cdb: &[u8]) -> Result<Vec<u8>, ScsiCompletion> {
+        let state = self.state();
+        let generation = state.generation.load(Ordering::Acquire) as u32;
+        let mut data = generation.to_be_bytes().to_vec();
+
+        match cdb[1] & 0x1f {
+            READ_KEYS => {
+                let keys: Vec<u64> = state
+                    .registrants
+                    .iter()
+                    .map(|registrant| registrant.key.load(Ordering::Acquire))
+                    .filter(|key| *key != 0)
+                    .collect();
+                data.extend_from_slice(&(keys.len() as u32 * 8).to_be_bytes());
+                for key in keys {
+                    data.extend_from_slice(&key.to_be_bytes());
+                }
+            }
+            READ_RESERVATION => {
+                let reservation = state.reservation.load(Ordering::Acquire);
+                if reservation == 0 {
+                    data.extend_from_slice(&0u32.to_be_bytes());
+                } else {
+                    let reservation_type = type_of(reservation);
+                    // All registrants holding the reservation, no key is
+                    // reported for it.
+                    let key = if is_all_registrants(reservation_type) {
+                        0
+                    } else {
+                        state.registrants[holder(reservation)]
+                            .key
+                            .load(Ordering::Acquire)
+                    };
+                    data.extend_from_slice(&16u32.to_be_bytes());
+                    data.extend_from_slice(&key.to_be_bytes());
+                    data.extend_from_slice(&[0, 0, 0, 0, 0, reservation_type, 0, 0]);
+                }
+            }
+            REPORT_CAPABILITIES => {
+                // Type mask valid, with all the reservation types supported.
+                data = vec![0, 8, 0, 0x80, 0xea, 0x01, 0, 0];
+            }
+            _ => return Err(SENSE_INVALID_FIELD.into()),
+        }
+
+        data.truncate(be16(&cdb[7..9]) as usize);
+        Ok(data)
+    }
+
+    /// Executes a PERSISTENT RESERVE OUT command with its parameter list.
+    pub(crate) fn update(&self, cdb: &[u8], parameters: &[u8]) -> Result<(), ScsiCompletion> {
+        let service_action = cdb[1] & 0x1f;
+        if !matches!(
+            service_action,
+            REGISTER
+                | RESERVE
+                | RELEASE
+                | CLEAR
+                | PREEMPT
+                | PREEMPT_AND_ABORT
+                | REGISTER_AND_IGNORE_EXISTING_KEY
+        ) {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+        let scope = cdb[2] >> 4;
+        let reservation_type = cdb[2] & 0xf;
+        if matches!(
+            service_action,
+            RESERVE | RELEASE | PREEMPT | PREEMPT_AND_ABORT
+        ) && (scope != 0 || !is_valid_type(reservation_type))
+        {
+            return Err(SENSE_INVALID_FIELD.into());
+        }
+        if be32(&cdb[5..9]) as usize != PR_OUT_PARAMETER_LIST_LENGTH
+            || parameters.len() < PR_OUT_PARAMETER_LIST_LENGTH
+        {
+            return Err(SENSE_PARAMETER_LIST_LENGTH.into());
+        }
+        if parameters[20] & SPEC_I_PT != 0 {
+            return Err(SENSE_INVALID_PARAMETER.into());
+        }
+        let key = be64(&parameters[0..8]);
+        let service_action_key = be64(&parameters[8..16]);
+
+        let _guard = self.lock()?;
+        let state = self.state();
+        let slot = self.find_slot();
+
+        if matches!(service_action, REGISTER | REGISTER_AND_IGNORE_EXISTING_KEY) {
+            match slot {
+                None => {
+                    if service_action == REGISTER && key != 0 {
+                        return conflict();
+                    }
+                    if service_action_key != 0 {
+                        let free_slot = state
+                            .registrants
+                            .iter()
+                            .position(|registrant| registrant.key.load(Ordering::Acquire) == 0)
+                            .ok_or(SENSE_INSUFFICIENT_REGISTRATION_RESOURCES)?;
+                        let registrant = &state.registrants[free_slot];
+                        registrant
+                            .initiator
+                            .store(self.initiator, Ordering::Release);
+                        registrant.key.store(service_action_key, Ordering::Release);
+                    }
+                }
+                Some(slot) => {
+                    if service_action == REGISTER
+                        && key != state.registrants[slot].key.load(Ordering::Acquire)
+                    {
+                        return conflict();
+                    }
+                    if service_action_key == 0 {
+                        self.unregister(slot);
+                    } else {
+                        state.registrants[slot]
+                            .key
+                            .store(service_action_key, Ordering::Release);
+                    }
+                }
+            }
+            state.generation.fetch_add(1, Ordering::AcqRel);
+            return Ok(());
+        }
+
+        // Other service actions are only allowed to registrants, with their
+        // current key.
+        let slot = match slot {
+            Some(slot) if state.registrants[slot].key.load(Ordering::Acquire) == key => slot,
+            _ => return conflict(),
+        };
+        let reservation = state.reservation.load(Ordering::Acquire);
+
+        match service_action {
+            RESERVE => {
+                if reservation == 0 {
+                    state
+                        .reservation
+                        .store(encode(reservation_type, slot), Ordering::Release);
+                } else if !self.holds(reservation, slot) || type_of(reservation) != reservation_type
+                {
+                    return conflict();
+                }
+            }
+            RELEASE => {
+                if reservation == 0 || !self.holds(reservation, slot) {
+                    return Ok(());
+                }
+                if type_of(reservation) != reservation_type {
+                    return Err(SENSE_INVALID_RELEASE.into());
+                }
+                state.reservation.store(0, Ordering::Release);
+            }
+            CLEAR => {
+                state.reservation.store(0, Ordering::Release);
+                for registrant in state.registrants.iter() {
+                    registrant.key.store(0, Ordering::Release);
+                    registrant.initiator.store(0, Ordering::Release);
+                }
+                state.generation.fetch_add(1, Ordering::AcqRel);
+            }
+            _ => self.preempt(slot, reservation, reservation_type, service_action_key)?,
+        }
+
+        Ok(())
+    }
+
+    fn preempt(
+        &self,
+        slot: usize,
+        reservation: u64,
+        reservation_type: u8,
+        service_action_key: u64,
+    ) -> Result<(), ScsiCompletion> {
+        let state = self.state();
+        let current_type = type_of(reservation);
+
+        if reservation != 0 && is_all_registrants(current_type) {
+            // The reservation is held by all registrants, a zero key removes
+            // all of them but this initiator, which takes the reservation.
+            if service_action_key == 0 {
+                for (other, registrant) in state.registrants.iter().enumerate() {
+                    if other != slot {
+                        registrant.key.store(0, Ordering::Release);
+                    }
+                }
+                state
+                    .reservation
+                    .store(encode(reservation_type, slot), Ordering::Release);
+            } else {
+                self.remove_key(slot, service_action_key);
+            }
+        } else if reservation != 0
+            && state.registrants[holder(reservation)]
+                .key
+                .load(Ordering::Acquire)
+                == service_action_key
+        {
+            self.remove_key(slot, service_action_key);
+            state
+                .reservation
+                .store(encode(reservation_type, slot), Ordering::Release);
+        } else {
+            if service_action_key == 0 {
+                return Err(SENSE_INVALID_PARAMETER.into());
+            }
+            if !self.remove_key(slot, service_action_key) {
+                return conflict();
+            }
+        }
+
+        state.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    // Unregisters the initiators other than the one in `slot` registered
+    // with `key`, returning whether any was found.
+    fn remove_key(&self, slot: usize, key: u64) -> bool {
+        let mut found = false;
+        for (other, registrant) in self.state().registrants.iter().enumerate() {
+            if other != slot && registrant.key.load(Ordering::Acquire) == key {
+                self.unregister(other);
+                found = true;
+            }
+        }
+        found
+    }
+
+    fn unregister(&self, slot: usize) {
+        let state = self.state();
+        state.registrants[slot].key.store(0, Ordering::Release);
+        state.registrants[slot]
+            .initiator
+            .store(0, Ordering::Release);
+
+        // The reservation is released when its holder goes away, or the last
+        // registrant when held by all of them.
+        let reservation = state.reservation.load(Ordering::Acquire);
+        if reservation == 0 {
+            return;
+        }
+        let released = if is_all_registrants(type_of(reservation)) {
+            state
+                .registrants
+                .iter()
+                .all(|registrant| registrant.key.load(Ordering::Acquire) == 0)
+        } else {
+            holder(reservation) == slot
+        };
+        if released {
+            state.reservation.store(0, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for PersistentReservations {
+    fn drop(&mut self) {
+        if let Storage::Shared { state, .. } = self.storage {
+            // Safe because the state was mapped with this size and isn't
+            // used anymore.
+            unsafe { libc::munmap(state as *mut libc::c_void, RESERVATIONS_FILE_SIZE) };
+        }
+    }
+}
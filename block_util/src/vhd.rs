@@ -119,9 +119,19 @@ pub fn is_fixed_vhd(f: &mut File) -> std::io::Result<bool> {
         && footer.disk_type() == 0x2)
 }
 
+/// Determine if the image is a dynamic or differencing VHD.
+pub fn is_dynamic_vhd(f: &mut File) -> std::io::Result<bool> {
+    let footer = VhdFooter::new(f)?;
+
+    Ok(footer.cookie() == 0x636f6e6563746978
+        && footer.file_format_version() == 0x0001_0000
+        && footer.data_offset() != 0xffff_ffff_ffff_ffff
+        && (footer.disk_type() == 0x3 || footer.disk_type() == 0x4))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{is_fixed_vhd, VhdFooter};
+    use super::{is_dynamic_vhd, is_fixed_vhd, VhdFooter};
     use std::fs::File;
     use std::io::{Seek, SeekFrom, Write};
     use vmm_sys_util::tempfile::TempFile;
@@ -218,4 +228,14 @@ mod tests {
             assert!(!(is_fixed_vhd(&mut file).unwrap()));
         });
     }
+
+    #[test]
+    fn test_is_dynamic_vhd() {
+        with_file(&valid_dynamic_vhd_footer(), |mut file: File| {
+            assert!(is_dynamic_vhd(&mut file).unwrap());
+        });
+        with_file(&valid_fixed_vhd_footer(), |mut file: File| {
+            assert!(!(is_dynamic_vhd(&mut file).unwrap()));
+        });
+    }
 }
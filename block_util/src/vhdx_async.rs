@@ -0,0 +1,64 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{clone_backing_files, AsyncIo, DiskFile, DiskFileError, DiskFileResult};
+use crate::mapped_async::{MappedFile, MappedFileAsync};
+use crate::vhdx_sync::parent_policy;
+use qcow::BackingFilePolicy;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use vhdx::vhdx::{Result as VhdxResult, Vhdx};
+
+pub struct VhdxDiskAsync {
+    vhdx_file: Arc<Mutex<Vhdx>>,
+}
+
+impl VhdxDiskAsync {
+    pub fn new(f: File, backing_file_policy: &BackingFilePolicy) -> VhdxResult<Self> {
+        Ok(VhdxDiskAsync {
+            vhdx_file: Arc::new(Mutex::new(Vhdx::with_parent_policy(
+                f,
+                &parent_policy(backing_file_policy),
+            )?)),
+        })
+    }
+}
+
+impl DiskFile for VhdxDiskAsync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.vhdx_file.lock().unwrap().virtual_disk_size())
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(
+            MappedFileAsync::new(self.vhdx_file.clone(), ring_depth)
+                .map_err(DiskFileError::NewAsyncIo)?,
+        ) as Box<dyn AsyncIo>)
+    }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        self.vhdx_file
+            .lock()
+            .unwrap()
+            .resize(size)
+            .map_err(|e| DiskFileError::Resize(std::io::Error::new(std::io::ErrorKind::Other, e)))
+    }
+
+    fn backing_files(&self) -> DiskFileResult<Vec<(PathBuf, File)>> {
+        clone_backing_files(self.vhdx_file.lock().unwrap().parent_files())
+    }
+}
+
+impl MappedFile for Vhdx {
+    fn map_read(&mut self, offset: u64, length: u64) -> std::io::Result<(Option<u64>, u64)> {
+        Vhdx::map_read(self, offset, length)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn map_write(&mut self, offset: u64, length: u64) -> std::io::Result<(Option<u64>, u64)> {
+        Vhdx::map_write(self, offset, length)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+}
@@ -2,21 +2,38 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult};
+use crate::async_io::{
+    clone_backing_files, AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
 use crate::AsyncAdaptor;
+use qcow::BackingFilePolicy;
 use std::fs::File;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, MutexGuard};
-use vhdx::vhdx::{Result as VhdxResult, Vhdx};
+use vhdx::vhdx::{ParentPolicy, Result as VhdxResult, Vhdx};
 use vmm_sys_util::eventfd::EventFd;
 
+// The parent of a differencing image is found through its parent locator,
+// which plays the role of the backing file name of other formats.
+pub(crate) fn parent_policy(backing_file_policy: &BackingFilePolicy) -> ParentPolicy {
+    match backing_file_policy {
+        BackingFilePolicy::Forbid => ParentPolicy::Forbid,
+        BackingFilePolicy::Header => ParentPolicy::Locator,
+        BackingFilePolicy::Override(path) => ParentPolicy::Override(path.clone()),
+    }
+}
+
 pub struct VhdxDiskSync {
     vhdx_file: Arc<Mutex<Vhdx>>,
 }
 
 impl VhdxDiskSync {
-    pub fn new(f: File) -> VhdxResult<Self> {
+    pub fn new(f: File, backing_file_policy: &BackingFilePolicy) -> VhdxResult<Self> {
         Ok(VhdxDiskSync {
-            vhdx_file: Arc::new(Mutex::new(Vhdx::new(f)?)),
+            vhdx_file: Arc::new(Mutex::new(Vhdx::with_parent_policy(
+                f,
+                &parent_policy(backing_file_policy),
+            )?)),
         })
     }
 }
@@ -32,6 +49,18 @@ impl DiskFile for VhdxDiskSync {
                 as Box<dyn AsyncIo>,
         )
     }
+
+    fn resize(&mut self, size: u64) -> DiskFileResult<()> {
+        self.vhdx_file
+            .lock()
+            .unwrap()
+            .resize(size)
+            .map_err(|e| DiskFileError::Resize(std::io::Error::new(std::io::ErrorKind::Other, e)))
+    }
+
+    fn backing_files(&self) -> DiskFileResult<Vec<(PathBuf, File)>> {
+        clone_backing_files(self.vhdx_file.lock().unwrap().parent_files())
+    }
 }
 
 pub struct VhdxSync {
@@ -96,6 +125,36 @@ impl AsyncIo for VhdxSync {
             .fsync_sync(user_data, &self.eventfd, &mut self.completion_list)
     }
 
+    fn punch_hole(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.vhdx_file.punch_hole_sync(
+            offset,
+            length,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.vhdx_file.write_zeroes_sync(
+            offset,
+            length,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
     fn complete(&mut self) -> Vec<(u64, i32)> {
         self.completion_list.drain(..).collect()
     }
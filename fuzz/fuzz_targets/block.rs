@@ -14,7 +14,7 @@ use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::sync::Arc;
-use virtio_devices::{Block, VirtioDevice, VirtioInterrupt, VirtioInterruptType};
+use virtio_devices::{Block, ErrorPolicy, VirtioDevice, VirtioInterrupt, VirtioInterruptType};
 use virtio_queue::{Queue, QueueState};
 use vm_memory::{bitmap::AtomicBitmap, Bytes, GuestAddress, GuestMemoryAtomic};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
@@ -102,6 +102,12 @@ fuzz_target!(|bytes| {
         256,
         SeccompAction::Allow,
         None,
+        None,
+        EventFd::new(EFD_NONBLOCK).unwrap(),
+        false,
+        None,
+        ErrorPolicy::Report,
+        ErrorPolicy::Report,
         EventFd::new(EFD_NONBLOCK).unwrap(),
     )
     .unwrap();
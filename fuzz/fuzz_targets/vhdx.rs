@@ -8,7 +8,8 @@ use std::ffi;
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::unix::io::{FromRawFd, RawFd};
-use vhdx::vhdx::Vhdx;
+use std::path::PathBuf;
+use vhdx::vhdx::{ParentPolicy, Vhdx};
 
 // Populate the corpus directory with a test file:
 // truncate -s 16M /tmp/source
@@ -21,7 +22,15 @@ fuzz_target!(|bytes| {
     disk_file.write_all(&bytes[..]).unwrap();
     disk_file.seek(SeekFrom::Start(0)).unwrap();
 
-    if let Ok(mut vhdx) = Vhdx::new(disk_file) {
+    // Differencing images get a copy of the input as their parent, rather
+    // than whatever file their parent locator points to.
+    let parent_shm = memfd_create(&ffi::CString::new("fuzz_parent").unwrap(), 0).unwrap();
+    let mut parent_file: File = unsafe { File::from_raw_fd(parent_shm) };
+    parent_file.write_all(&bytes[..]).unwrap();
+    let parent_policy =
+        ParentPolicy::Override(PathBuf::from(format!("/proc/self/fd/{}", parent_shm)));
+
+    if let Ok(mut vhdx) = Vhdx::with_parent_policy(disk_file, &parent_policy) {
         if vhdx.seek(SeekFrom::Start(0)).is_ok() {
             let mut offset = 0;
             while offset < bytes.len() {
@@ -0,0 +1,500 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Capture of the frames of a virtio-net device to a pcapng file, as seen by
+//! the guest: frames received by the guest are inbound, and frames sent by
+//! the guest are outbound.
+
+use crate::queue_pair::read_frame_header;
+use crate::vnet_hdr_len;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Block types
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+// Options
+const OPT_ENDOFOPT: u16 = 0;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+// Number of files of a ring buffer, unless configured
+const DEFAULT_CAPTURE_FILES: u32 = 2;
+
+// Number of frames waiting to be written, past which frames are dropped
+const CAPTURE_QUEUE_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Direction {
+    /// Frame received by the guest.
+    Inbound,
+    /// Frame sent by the guest.
+    Outbound,
+}
+
+impl Direction {
+    // Direction bits of the epb_flags option
+    fn flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureConfig {
+    /// File the frames are captured to.
+    pub path: PathBuf,
+    /// Number of bytes of each frame captured, whole frames being captured
+    /// by default.
+    pub snaplen: Option<u32>,
+    /// Size from which the capture goes on in a new file, the current one
+    /// being kept as `<path>.1`, and older ones shifted up to `<path>.<n>`.
+    pub file_size: Option<u64>,
+    /// Number of files kept when rotating them, the current one included.
+    pub files: Option<u32>,
+}
+
+// Appends the option `code` holding `value`, padded to 32 bits.
+fn push_option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend_from_slice(&code.to_ne_bytes());
+    block.extend_from_slice(&(value.len() as u16).to_ne_bytes());
+    block.extend_from_slice(value);
+    block.resize(block.len() + padding(value.len()), 0);
+}
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+// Builds the block of type `block_type` around `body`, which must be padded
+// to 32 bits.
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&block_type.to_ne_bytes());
+    block.extend_from_slice(&len.to_ne_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&len.to_ne_bytes());
+    block
+}
+
+// Section header and description of the single interface of the section.
+fn file_header(if_name: &str, snaplen: u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_ne_bytes());
+    // Version 1.0
+    body.extend_from_slice(&1u16.to_ne_bytes());
+    body.extend_from_slice(&0u16.to_ne_bytes());
+    // Unknown section length
+    body.extend_from_slice(&(-1i64).to_ne_bytes());
+    let mut header = block(SECTION_HEADER_BLOCK, &body);
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_ETHERNET.to_ne_bytes());
+    body.extend_from_slice(&0u16.to_ne_bytes());
+    body.extend_from_slice(&snaplen.to_ne_bytes());
+    if !if_name.is_empty() {
+        push_option(&mut body, IF_NAME, if_name.as_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+    }
+    header.extend_from_slice(&block(INTERFACE_DESCRIPTION_BLOCK, &body));
+
+    header
+}
+
+// File being written, and its size
+struct CaptureFile {
+    config: CaptureConfig,
+    header: Vec<u8>,
+    file: File,
+    size: u64,
+}
+
+impl CaptureFile {
+    fn open(config: &CaptureConfig, if_name: &str) -> io::Result<Self> {
+        let header = file_header(if_name, config.snaplen.unwrap_or(0));
+        // Captures restarted on the same file, such as across a reboot,
+        // start a new section of the file.
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let mut capture_file = CaptureFile {
+            config: config.clone(),
+            header,
+            size: file.metadata()?.len(),
+            file,
+        };
+        capture_file.write_header()?;
+
+        Ok(capture_file)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.header)?;
+        self.size += self.header.len() as u64;
+        Ok(())
+    }
+
+    // Goes on in a new file, shifting the previous ones.
+    fn rotate(&mut self) -> io::Result<()> {
+        let files = self.config.files.unwrap_or(DEFAULT_CAPTURE_FILES);
+        let path = &self.config.path;
+        let rotated_path = |i: u32| {
+            let mut rotated_path = path.clone().into_os_string();
+            rotated_path.push(format!(".{}", i));
+            PathBuf::from(rotated_path)
+        };
+        for i in (1..files).rev() {
+            let from = if i == 1 {
+                path.clone()
+            } else {
+                rotated_path(i - 1)
+            };
+            match std::fs::rename(&from, rotated_path(i)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        self.file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        self.size = 0;
+        self.write_header()
+    }
+
+    fn write_frame(&mut self, frame: &CapturedFrame) -> io::Result<()> {
+        let data = &frame.data;
+        let mut body = Vec::with_capacity(data.len() + 40);
+        // Interface 0 of the section
+        body.extend_from_slice(&0u32.to_ne_bytes());
+        body.extend_from_slice(&((frame.timestamp >> 32) as u32).to_ne_bytes());
+        body.extend_from_slice(&(frame.timestamp as u32).to_ne_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+        body.extend_from_slice(&(frame.len as u32).to_ne_bytes());
+        body.extend_from_slice(data);
+        body.resize(body.len() + padding(data.len()), 0);
+        push_option(&mut body, EPB_FLAGS, &frame.direction.flags().to_ne_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        let block = block(ENHANCED_PACKET_BLOCK, &body);
+
+        // A file holding no frame yet goes on regardless of its size.
+        if let Some(file_size) = self.config.file_size {
+            if self.size > self.header.len() as u64 && self.size + block.len() as u64 > file_size {
+                self.rotate()?;
+            }
+        }
+
+        self.file.write_all(&block)?;
+        self.size += block.len() as u64;
+
+        Ok(())
+    }
+}
+
+// Frame captured by a queue pair, waiting to be written
+struct CapturedFrame {
+    direction: Direction,
+    // Microseconds since the epoch
+    timestamp: u64,
+    // Bytes of the frame kept, up to the snaplen
+    data: Vec<u8>,
+    // Length of the whole frame
+    len: usize,
+}
+
+// Thread writing the frames to the files, so that the queue pairs neither
+// wait for the files nor need to be allowed to rotate them.
+struct CaptureWriter {
+    config: CaptureConfig,
+    sender: SyncSender<CapturedFrame>,
+    thread: JoinHandle<()>,
+    // Frames dropped while the queue was full
+    dropped: AtomicU64,
+}
+
+impl CaptureWriter {
+    fn spawn(mut capture_file: CaptureFile) -> io::Result<Self> {
+        let config = capture_file.config.clone();
+        let (sender, receiver) = sync_channel::<CapturedFrame>(CAPTURE_QUEUE_LEN);
+        let thread = thread::Builder::new()
+            .name("net_capture".to_string())
+            .spawn(move || {
+                for frame in receiver {
+                    if let Err(e) = capture_file.write_frame(&frame) {
+                        error!(
+                            "Failed capturing frames to {:?}, stopping: {}",
+                            capture_file.config.path, e
+                        );
+                        return;
+                    }
+                }
+            })?;
+
+        Ok(CaptureWriter {
+            config,
+            sender,
+            thread,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    // Waits for the frames already captured to be written.
+    fn stop(self) {
+        drop(self.sender);
+        if self.thread.join().is_err() {
+            error!("Capture thread of {:?} panicked", self.config.path);
+        }
+
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                "Dropped {} frames which couldn't be written fast enough to {:?}",
+                dropped, self.config.path
+            );
+        }
+    }
+}
+
+/// Capture of the frames of a virtio-net device, shared by its queue pairs,
+/// which can be started and stopped at any time.
+#[derive(Default)]
+pub struct PacketCapture {
+    // Name of the interface recorded in the files
+    if_name: String,
+    active: AtomicBool,
+    writer: Mutex<Option<CaptureWriter>>,
+}
+
+impl PacketCapture {
+    /// Creates a capture of the interface `if_name`, which is stopped.
+    pub fn new(if_name: &str) -> Self {
+        PacketCapture {
+            if_name: if_name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Starts capturing the frames to the file of `config`, in place of any
+    /// capture in progress, which is stopped first.
+    pub fn start(&self, config: &CaptureConfig) -> io::Result<()> {
+        self.stop();
+
+        let capture_file = CaptureFile::open(config, &self.if_name)?;
+        *self.writer.lock().unwrap() = Some(CaptureWriter::spawn(capture_file)?);
+        self.active.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Stops the capture in progress, if any, once the frames already
+    /// captured are written.
+    pub fn stop(&self) {
+        self.active.store(false, Ordering::Release);
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            writer.stop();
+        }
+    }
+
+    /// File the frames are captured to, if any.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.writer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|writer| writer.config.path.clone())
+    }
+
+    /// Captures the frame held by the first `len` bytes of `iovecs`, past
+    /// its virtio-net header.
+    pub(crate) fn capture(&self, direction: Direction, iovecs: &[libc::iovec], len: usize) {
+        if !self.active.load(Ordering::Acquire) {
+            return;
+        }
+
+        let writer = self.writer.lock().unwrap();
+        let writer = match writer.as_ref() {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        let frame_len = len.saturating_sub(vnet_hdr_len());
+        let snaplen = writer
+            .config
+            .snaplen
+            .map_or(frame_len, |snaplen| snaplen as usize);
+        let mut data = vec![0u8; std::cmp::min(frame_len, snaplen)];
+        let captured = read_frame_header(iovecs, len, &mut data);
+        data.truncate(captured);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        // The capture can't get in the way of the traffic of the guest, the
+        // frames the writer thread can't keep up with are dropped.
+        match writer.sender.try_send(CapturedFrame {
+            direction,
+            timestamp,
+            data,
+            len: frame_len,
+        }) {
+            Err(TrySendError::Disconnected(_)) => {
+                // The writer thread failed and already reported why.
+                self.active.store(false, Ordering::Release);
+            }
+            Err(TrySendError::Full(_)) => {
+                writer.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Returns the type and body of each block of `data`.
+    fn blocks(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let block_type = read_u32(data, offset);
+            let len = read_u32(data, offset + 4) as usize;
+            assert_eq!(read_u32(data, offset + len - 4) as usize, len);
+            blocks.push((block_type, &data[offset + 8..offset + len - 4]));
+            offset += len;
+        }
+        blocks
+    }
+
+    fn capture_frame(capture: &PacketCapture, direction: Direction, frame: &[u8]) {
+        let mut buf = vec![0u8; vnet_hdr_len()];
+        buf.extend_from_slice(frame);
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        capture.capture(direction, &iovecs, buf.len());
+    }
+
+    #[test]
+    fn test_capture() {
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let path = dir.as_path().join("net0.pcapng");
+        let capture = PacketCapture::new("net0");
+
+        // Frames aren't captured until the capture starts.
+        capture_frame(&capture, Direction::Inbound, &[0xff; 14]);
+        capture
+            .start(&CaptureConfig {
+                path: path.clone(),
+                snaplen: Some(16),
+                file_size: None,
+                files: None,
+            })
+            .unwrap();
+        assert_eq!(capture.path(), Some(path.clone()));
+        capture_frame(&capture, Direction::Inbound, &[0xaa; 14]);
+        capture_frame(&capture, Direction::Outbound, &[0xbb; 20]);
+        capture.stop();
+        capture_frame(&capture, Direction::Outbound, &[0xcc; 14]);
+        assert_eq!(capture.path(), None);
+
+        let data = std::fs::read(&path).unwrap();
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0].0, SECTION_HEADER_BLOCK);
+        assert_eq!(read_u32(blocks[0].1, 0), BYTE_ORDER_MAGIC);
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(read_u32(blocks[1].1, 4), 16);
+        assert_eq!(&blocks[1].1[12..16], b"net0");
+
+        // Inbound frame, whole
+        assert_eq!(blocks[2].0, ENHANCED_PACKET_BLOCK);
+        assert_eq!(read_u32(blocks[2].1, 12), 14);
+        assert_eq!(read_u32(blocks[2].1, 16), 14);
+        assert_eq!(&blocks[2].1[20..34], &[0xaa; 14]);
+        assert_eq!(read_u32(blocks[2].1, 40), 1);
+
+        // Outbound frame, cut to the snaplen
+        assert_eq!(read_u32(blocks[3].1, 12), 16);
+        assert_eq!(read_u32(blocks[3].1, 16), 20);
+        assert_eq!(&blocks[3].1[20..36], &[0xbb; 16]);
+        assert_eq!(read_u32(blocks[3].1, 40), 2);
+
+        // Restarting the capture appends a new section.
+        capture
+            .start(&CaptureConfig {
+                path: path.clone(),
+                snaplen: None,
+                file_size: None,
+                files: None,
+            })
+            .unwrap();
+        capture_frame(&capture, Direction::Inbound, &[0xdd; 14]);
+        capture.stop();
+        let data = std::fs::read(&path).unwrap();
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 7);
+        assert_eq!(blocks[4].0, SECTION_HEADER_BLOCK);
+    }
+
+    #[test]
+    fn test_capture_rotation() {
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let path = dir.as_path().join("net0.pcapng");
+        let capture = PacketCapture::new("net0");
+        capture
+            .start(&CaptureConfig {
+                path: path.clone(),
+                snaplen: None,
+                file_size: Some(300),
+                files: Some(3),
+            })
+            .unwrap();
+
+        // Each file holds the headers and two frames.
+        for i in 0..7 {
+            capture_frame(&capture, Direction::Outbound, &[i; 60]);
+        }
+        capture.stop();
+
+        let frames = |path: &Path| -> Vec<u8> {
+            let data = std::fs::read(path).unwrap();
+            blocks(&data)
+                .iter()
+                .filter(|(block_type, _)| *block_type == ENHANCED_PACKET_BLOCK)
+                .map(|(_, body)| body[20])
+                .collect()
+        };
+        assert_eq!(frames(&path), vec![6]);
+        assert_eq!(frames(&dir.as_path().join("net0.pcapng.1")), vec![4, 5]);
+        assert_eq!(frames(&dir.as_path().join("net0.pcapng.2")), vec![2, 3]);
+        assert!(!dir.as_path().join("net0.pcapng.3").exists());
+    }
+}
@@ -3,15 +3,20 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use crate::GuestMemoryMmap;
-use crate::Tap;
+use crate::{MacAddr, RxFilter, Tap, MAC_ADDR_LEN};
 use libc::c_uint;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
 use virtio_bindings::bindings::virtio_net::{
-    VIRTIO_NET_CTRL_GUEST_OFFLOADS, VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET, VIRTIO_NET_CTRL_MQ,
-    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
-    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR, VIRTIO_NET_F_GUEST_CSUM,
+    VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK, VIRTIO_NET_CTRL_GUEST_OFFLOADS,
+    VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET, VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET,
+    VIRTIO_NET_CTRL_MAC_TABLE_SET, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_CTRL_RX,
+    VIRTIO_NET_CTRL_RX_ALLMULTI, VIRTIO_NET_CTRL_RX_PROMISC, VIRTIO_NET_CTRL_VLAN,
+    VIRTIO_NET_CTRL_VLAN_ADD, VIRTIO_NET_CTRL_VLAN_DEL, VIRTIO_NET_ERR, VIRTIO_NET_F_GUEST_CSUM,
     VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6,
-    VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_OK,
+    VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_OK, VIRTIO_NET_S_ANNOUNCE,
 };
 use virtio_queue::Queue;
 use vm_memory::{ByteValued, Bytes, GuestMemoryAtomic, GuestMemoryError};
@@ -23,8 +28,6 @@ pub enum Error {
     GuestMemory(GuestMemoryError),
     /// No control header descriptor
     NoControlHeaderDescriptor,
-    /// Missing the data descriptor in the chain.
-    NoDataDescriptor,
     /// No status descriptor
     NoStatusDescriptor,
     /// Failed adding used index
@@ -37,6 +40,10 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+// Largest command data accepted, enough for MAC tables well beyond the ones
+// the filter holds.
+const MAX_CTRL_DATA_LEN: usize = 64 << 10;
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ControlHeader {
@@ -49,11 +56,23 @@ unsafe impl ByteValued for ControlHeader {}
 
 pub struct CtrlQueue {
     pub taps: Vec<Tap>,
+    pub rx_filter: Option<Arc<RxFilter>>,
+    // Status field of the device configuration, in which the announce bit
+    // is cleared once the guest acknowledged the announce
+    pub status: Option<Arc<AtomicU16>>,
 }
 
 impl CtrlQueue {
-    pub fn new(taps: Vec<Tap>) -> Self {
-        CtrlQueue { taps }
+    pub fn new(
+        taps: Vec<Tap>,
+        rx_filter: Option<Arc<RxFilter>>,
+        status: Option<Arc<AtomicU16>>,
+    ) -> Self {
+        CtrlQueue {
+            taps,
+            rx_filter,
+            status,
+        }
     }
 
     pub fn process(
@@ -74,59 +93,40 @@ impl CtrlQueue {
                             .translate_gva(access_platform, ctrl_desc.len() as usize),
                     )
                     .map_err(Error::GuestMemory)?;
-                let data_desc = desc_chain.next().ok_or(Error::NoDataDescriptor)?;
-
-                let data_desc_addr = data_desc
-                    .addr()
-                    .translate_gva(access_platform, data_desc.len() as usize);
-
-                let status_desc = desc_chain.next().ok_or(Error::NoStatusDescriptor)?;
-
-                let ok = match u32::from(ctrl_hdr.class) {
-                    VIRTIO_NET_CTRL_MQ => {
-                        let queue_pairs = desc_chain
-                            .memory()
-                            .read_obj::<u16>(data_desc_addr)
-                            .map_err(Error::GuestMemory)?;
-                        if u32::from(ctrl_hdr.cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
-                            warn!("Unsupported command: {}", ctrl_hdr.cmd);
-                            false
-                        } else if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
-                            || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
-                        {
-                            warn!("Number of MQ pairs out of range: {}", queue_pairs);
-                            false
-                        } else {
-                            info!("Number of MQ pairs requested: {}", queue_pairs);
-                            true
-                        }
-                    }
-                    VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
-                        let features = desc_chain
-                            .memory()
-                            .read_obj::<u64>(data_desc_addr)
-                            .map_err(Error::GuestMemory)?;
-                        if u32::from(ctrl_hdr.cmd) != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET {
-                            warn!("Unsupported command: {}", ctrl_hdr.cmd);
-                            false
-                        } else {
-                            let mut ok = true;
-                            for tap in self.taps.iter_mut() {
-                                info!("Reprogramming tap offload with features: {}", features);
-                                tap.set_offload(virtio_features_to_tap_offload(features))
-                                    .map_err(|e| {
-                                        error!("Error programming tap offload: {:?}", e);
-                                        ok = false
-                                    })
-                                    .ok();
-                            }
-                            ok
-                        }
+
+                // The command data can be split across several descriptors,
+                // as the MAC tables are, followed by the status descriptor.
+                let mut data = Vec::new();
+                let mut data_too_large = false;
+                let mut len = ctrl_desc.len();
+                let status_desc = loop {
+                    let desc = desc_chain.next().ok_or(Error::NoStatusDescriptor)?;
+                    len += desc.len();
+                    if desc.is_write_only() {
+                        break desc;
                     }
-                    _ => {
-                        warn!("Unsupported command {:?}", ctrl_hdr);
-                        false
+
+                    let start = data.len();
+                    if start + desc.len() as usize > MAX_CTRL_DATA_LEN {
+                        data_too_large = true;
+                        continue;
                     }
+                    data.resize(start + desc.len() as usize, 0);
+                    desc_chain
+                        .memory()
+                        .read_slice(
+                            &mut data[start..],
+                            desc.addr()
+                                .translate_gva(access_platform, desc.len() as usize),
+                        )
+                        .map_err(Error::GuestMemory)?;
+                };
+
+                let ok = if data_too_large {
+                    warn!("Command data too large: {:?}", ctrl_hdr);
+                    false
+                } else {
+                    self.process_command(ctrl_hdr, &data)
                 };
 
                 desc_chain
@@ -138,7 +138,6 @@ impl CtrlQueue {
                             .translate_gva(access_platform, status_desc.len() as usize),
                     )
                     .map_err(Error::GuestMemory)?;
-                let len = ctrl_desc.len() + data_desc.len() + status_desc.len();
                 used_desc_heads.push((desc_chain.head_index(), len));
             }
 
@@ -158,6 +157,141 @@ impl CtrlQueue {
 
         Ok(())
     }
+
+    fn process_command(&mut self, ctrl_hdr: ControlHeader, data: &[u8]) -> bool {
+        match (u32::from(ctrl_hdr.class), u32::from(ctrl_hdr.cmd)) {
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => {
+                let queue_pairs = match read_le_u16(data) {
+                    Some(queue_pairs) => queue_pairs,
+                    None => return false,
+                };
+                if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
+                    || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
+                {
+                    warn!("Number of MQ pairs out of range: {}", queue_pairs);
+                    false
+                } else {
+                    info!("Number of MQ pairs requested: {}", queue_pairs);
+                    true
+                }
+            }
+            (VIRTIO_NET_CTRL_GUEST_OFFLOADS, VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET) => {
+                let features = match data.get(..8) {
+                    Some(features) => u64::from_le_bytes(features.try_into().unwrap()),
+                    None => return false,
+                };
+                let mut ok = true;
+                for tap in self.taps.iter_mut() {
+                    info!("Reprogramming tap offload with features: {}", features);
+                    tap.set_offload(virtio_features_to_tap_offload(features))
+                        .map_err(|e| {
+                            error!("Error programming tap offload: {:?}", e);
+                            ok = false
+                        })
+                        .ok();
+                }
+                ok
+            }
+            (VIRTIO_NET_CTRL_RX, cmd) if self.rx_filter.is_some() => {
+                let rx_filter = self.rx_filter.as_ref().unwrap();
+                let on = match data.first() {
+                    Some(on) => *on != 0,
+                    None => return false,
+                };
+                match cmd {
+                    VIRTIO_NET_CTRL_RX_PROMISC => rx_filter.set_promisc(on),
+                    VIRTIO_NET_CTRL_RX_ALLMULTI => rx_filter.set_allmulti(on),
+                    _ => {
+                        warn!("Unsupported command {:?}", ctrl_hdr);
+                        return false;
+                    }
+                }
+                true
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET) if self.rx_filter.is_some() => {
+                match data.get(..MAC_ADDR_LEN) {
+                    Some(mac) => {
+                        let mac = MacAddr::from_bytes_unchecked(mac);
+                        info!("Setting MAC address: {}", mac);
+                        self.rx_filter.as_ref().unwrap().set_mac(mac);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET) if self.rx_filter.is_some() => {
+                // The unicast table comes first, then the multicast one, each
+                // made of the number of entries followed by the entries.
+                let (unicast, data) = match read_mac_table(data) {
+                    Some(table) => table,
+                    None => return false,
+                };
+                let (multicast, data) = match read_mac_table(data) {
+                    Some(table) => table,
+                    None => return false,
+                };
+                if !data.is_empty() {
+                    warn!("Unexpected data after the MAC tables");
+                    return false;
+                }
+                self.rx_filter
+                    .as_ref()
+                    .unwrap()
+                    .set_mac_tables(unicast, multicast);
+                true
+            }
+            (VIRTIO_NET_CTRL_VLAN, cmd) if self.rx_filter.is_some() => {
+                let rx_filter = self.rx_filter.as_ref().unwrap();
+                let vid = match read_le_u16(data) {
+                    Some(vid) => vid,
+                    None => return false,
+                };
+                let ok = match cmd {
+                    VIRTIO_NET_CTRL_VLAN_ADD => rx_filter.add_vlan(vid),
+                    VIRTIO_NET_CTRL_VLAN_DEL => rx_filter.del_vlan(vid),
+                    _ => {
+                        warn!("Unsupported command {:?}", ctrl_hdr);
+                        return false;
+                    }
+                };
+                if !ok {
+                    warn!("VLAN identifier out of range: {}", vid);
+                }
+                ok
+            }
+            (VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK) if self.status.is_some() => {
+                info!("Guest announce acknowledged");
+                self.status
+                    .as_ref()
+                    .unwrap()
+                    .fetch_and(!(VIRTIO_NET_S_ANNOUNCE as u16), Ordering::AcqRel);
+                true
+            }
+            _ => {
+                warn!("Unsupported command {:?}", ctrl_hdr);
+                false
+            }
+        }
+    }
+}
+
+fn read_le_u16(data: &[u8]) -> Option<u16> {
+    data.get(..2)
+        .map(|value| u16::from_le_bytes(value.try_into().unwrap()))
+}
+
+// Reads a MAC table at the beginning of `data`, returning its entries and the
+// data following it.
+fn read_mac_table(data: &[u8]) -> Option<(Vec<[u8; MAC_ADDR_LEN]>, &[u8])> {
+    let entries = u32::from_le_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+    let table_len = entries.checked_mul(MAC_ADDR_LEN)?;
+    let table = data.get(4..4usize.checked_add(table_len)?)?;
+    let macs = table
+        .chunks_exact(MAC_ADDR_LEN)
+        .map(|mac| mac.try_into().unwrap())
+        .collect();
+
+    Some((macs, &data[4 + table_len..]))
 }
 
 pub fn virtio_features_to_tap_offload(features: u64) -> c_uint {
@@ -180,3 +314,141 @@ pub fn virtio_features_to_tap_offload(features: u64) -> c_uint {
 
     tap_offloads
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio_bindings::bindings::virtio_net::VIRTIO_NET_S_LINK_UP;
+
+    const ANNOUNCE_ACK: ControlHeader = ControlHeader {
+        class: VIRTIO_NET_CTRL_ANNOUNCE as u8,
+        cmd: VIRTIO_NET_CTRL_ANNOUNCE_ACK as u8,
+    };
+
+    const MAC_TABLE_SET: ControlHeader = ControlHeader {
+        class: VIRTIO_NET_CTRL_MAC as u8,
+        cmd: VIRTIO_NET_CTRL_MAC_TABLE_SET as u8,
+    };
+
+    const MAC: [u8; MAC_ADDR_LEN] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+    const MULTICAST_MAC: [u8; MAC_ADDR_LEN] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+
+    // Command data of a MAC table of `entries`.
+    fn mac_table(entries: &[[u8; MAC_ADDR_LEN]]) -> Vec<u8> {
+        let mut data = (entries.len() as u32).to_le_bytes().to_vec();
+        for mac in entries {
+            data.extend_from_slice(mac);
+        }
+        data
+    }
+
+    fn mac_tables(unicast: &[[u8; MAC_ADDR_LEN]], multicast: &[[u8; MAC_ADDR_LEN]]) -> Vec<u8> {
+        let mut data = mac_table(unicast);
+        data.extend_from_slice(&mac_table(multicast));
+        data
+    }
+
+    fn rx_filter_ctrl_queue() -> (CtrlQueue, Arc<RxFilter>) {
+        let rx_filter = Arc::new(RxFilter::new(None));
+        let ctrl_queue = CtrlQueue::new(Vec::new(), Some(rx_filter.clone()), None);
+        (ctrl_queue, rx_filter)
+    }
+
+    #[test]
+    fn test_announce_ack() {
+        let status = Arc::new(AtomicU16::new(
+            (VIRTIO_NET_S_ANNOUNCE | VIRTIO_NET_S_LINK_UP) as u16,
+        ));
+        let mut ctrl_queue = CtrlQueue::new(Vec::new(), None, Some(status.clone()));
+
+        assert!(ctrl_queue.process_command(ANNOUNCE_ACK, &[]));
+        assert_eq!(status.load(Ordering::Acquire), VIRTIO_NET_S_LINK_UP as u16);
+
+        // Acknowledging again is harmless.
+        assert!(ctrl_queue.process_command(ANNOUNCE_ACK, &[]));
+        assert_eq!(status.load(Ordering::Acquire), VIRTIO_NET_S_LINK_UP as u16);
+    }
+
+    #[test]
+    fn test_announce_ack_not_negotiated() {
+        let mut ctrl_queue = CtrlQueue::new(Vec::new(), None, None);
+
+        assert!(!ctrl_queue.process_command(ANNOUNCE_ACK, &[]));
+    }
+
+    #[test]
+    fn test_mac_table_set() {
+        let (mut ctrl_queue, rx_filter) = rx_filter_ctrl_queue();
+
+        assert!(ctrl_queue.process_command(MAC_TABLE_SET, &mac_tables(&[MAC], &[MULTICAST_MAC])));
+        let state = rx_filter.state();
+        assert_eq!(state.unicast, vec![MAC]);
+        assert_eq!(state.multicast, vec![MULTICAST_MAC]);
+
+        assert!(ctrl_queue.process_command(MAC_TABLE_SET, &mac_tables(&[], &[])));
+        let state = rx_filter.state();
+        assert!(state.unicast.is_empty());
+        assert!(state.multicast.is_empty());
+    }
+
+    #[test]
+    fn test_mac_table_set_truncated() {
+        let (mut ctrl_queue, rx_filter) = rx_filter_ctrl_queue();
+        let data = mac_tables(&[MAC], &[MULTICAST_MAC]);
+
+        // Whichever field is cut, the tables are left as they were.
+        for len in [0, 2, 4, 8, 10, 12, data.len() - 1] {
+            assert!(!ctrl_queue.process_command(MAC_TABLE_SET, &data[..len]));
+        }
+
+        // The number of entries can't be trusted either.
+        let mut data = mac_tables(&[MAC], &[]);
+        data[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(!ctrl_queue.process_command(MAC_TABLE_SET, &data));
+
+        let state = rx_filter.state();
+        assert!(state.unicast.is_empty());
+        assert!(state.multicast.is_empty());
+    }
+
+    #[test]
+    fn test_mac_table_set_oversized() {
+        let (mut ctrl_queue, rx_filter) = rx_filter_ctrl_queue();
+
+        // The largest tables the queue accepts overflow the filter, which
+        // then receives all the frames of their kind.
+        let entries = (MAX_CTRL_DATA_LEN - 8) / MAC_ADDR_LEN;
+        let data = mac_tables(&vec![MAC; entries], &[]);
+        assert!(data.len() <= MAX_CTRL_DATA_LEN);
+        assert!(ctrl_queue.process_command(MAC_TABLE_SET, &data));
+        let state = rx_filter.state();
+        assert!(state.unicast_overflow);
+        assert!(state.unicast.is_empty());
+        assert!(!state.multicast_overflow);
+
+        let data = mac_tables(&[], &vec![MULTICAST_MAC; entries]);
+        assert!(ctrl_queue.process_command(MAC_TABLE_SET, &data));
+        let state = rx_filter.state();
+        assert!(!state.unicast_overflow);
+        assert!(state.multicast_overflow);
+        assert!(state.multicast.is_empty());
+    }
+
+    #[test]
+    fn test_mac_table_set_trailing_data() {
+        let (mut ctrl_queue, rx_filter) = rx_filter_ctrl_queue();
+
+        let mut data = mac_tables(&[MAC], &[MULTICAST_MAC]);
+        data.push(0);
+        assert!(!ctrl_queue.process_command(MAC_TABLE_SET, &data));
+
+        // A third table is trailing data as well.
+        let mut data = mac_tables(&[MAC], &[MULTICAST_MAC]);
+        data.extend_from_slice(&mac_table(&[MAC]));
+        assert!(!ctrl_queue.process_command(MAC_TABLE_SET, &data));
+
+        let state = rx_filter.state();
+        assert!(state.unicast.is_empty());
+        assert!(state.multicast.is_empty());
+    }
+}
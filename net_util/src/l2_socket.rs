@@ -0,0 +1,766 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Socket carrying the Ethernet frames of a virtio-net device straight to a
+//! peer, such as another VM, with the framing of QEMU `-netdev stream` and
+//! `-netdev dgram`:
+//!
+//! - stream sockets prefix each frame with its length, as a 32-bit big endian
+//!   integer,
+//! - datagram and seqpacket sockets carry a frame per message.
+//!
+//! Frames go without their virtio-net header, hence no offload can be used.
+
+use crate::{vnet_hdr_len, Tap};
+use serde::de::{Deserialize, Deserializer, Error as SerdeError};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Length of the prefix of the frames on stream sockets
+const STREAM_HDR_LEN: usize = 4;
+// Largest frame accepted from a stream, as QEMU does.
+const MAX_FRAME_LEN: usize = 69632;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the socket.
+    CreateSocket(io::Error),
+    /// Failed to bind the socket.
+    Bind(io::Error),
+    /// Failed to connect to the peer.
+    Connect(io::Error),
+    /// Failed to accept the connection of the peer.
+    Accept(io::Error),
+    /// Failed to join the multicast group.
+    JoinMulticast(io::Error),
+    /// Failed to configure the socket.
+    ConfigureSocket(io::Error),
+    /// The local and remote addresses are of different families.
+    AddressMismatch,
+    /// A local address is needed to receive from a UNIX datagram socket.
+    LocalAddressMissing,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Address of a socket, written `unix:<path>` for UNIX sockets, and
+/// `<ip>:<port>` otherwise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum L2SocketAddr {
+    Unix(PathBuf),
+    Inet(SocketAddr),
+}
+
+#[derive(Debug)]
+pub enum L2SocketAddrParseError {
+    InvalidValue(String),
+}
+
+impl fmt::Display for L2SocketAddrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            L2SocketAddrParseError::InvalidValue(s) => write!(f, "invalid socket address: {}", s),
+        }
+    }
+}
+
+impl FromStr for L2SocketAddr {
+    type Err = L2SocketAddrParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(L2SocketAddr::Unix(PathBuf::from(path))),
+            Some(_) => Err(L2SocketAddrParseError::InvalidValue(s.to_owned())),
+            None => s
+                .parse()
+                .map(L2SocketAddr::Inet)
+                .map_err(|_| L2SocketAddrParseError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for L2SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            L2SocketAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            L2SocketAddr::Inet(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl Serialize for L2SocketAddr {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for L2SocketAddr {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<L2SocketAddr, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e| D::Error::custom(format!("{}", e)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum L2SocketConfig {
+    /// TCP or UNIX stream socket, connecting to the peer or waiting for its
+    /// connection as a server.
+    Stream { addr: L2SocketAddr, server: bool },
+    /// UNIX seqpacket socket, connecting to the peer or waiting for its
+    /// connection as a server.
+    SeqPacket { path: PathBuf, server: bool },
+    /// UDP or UNIX datagram socket, sending to `remote` and receiving on
+    /// `local`. Sending to a multicast group joins it, letting several VMs
+    /// share the segment.
+    Dgram {
+        remote: L2SocketAddr,
+        local: Option<L2SocketAddr>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Framing {
+    // Frames are prefixed with their length.
+    Stream,
+    // Each message holds a frame.
+    Message,
+}
+
+// Builds the address of the UNIX socket at `path`.
+fn unix_sockaddr(path: &Path) -> io::Result<(libc::sockaddr_storage, libc::socklen_t)> {
+    // SAFETY: all zeroes is a valid sockaddr_storage.
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    // SAFETY: sockaddr_storage is larger than, and aligned for, any socket
+    // address.
+    let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_un) };
+    let bytes = path.as_os_str().as_bytes();
+    // The path must be NUL terminated.
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+    let len = std::mem::size_of::<libc::sa_family_t>() + bytes.len() + 1;
+
+    Ok((storage, len as libc::socklen_t))
+}
+
+fn inet_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: all zeroes is a valid sockaddr_storage.
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: sockaddr_storage is larger than, and aligned for, any
+            // socket address.
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: sockaddr_storage is larger than, and aligned for, any
+            // socket address.
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+// Creates a socket of the given `family` and `socket_type`, returning its
+// file descriptor.
+fn create_socket(family: libc::c_int, socket_type: libc::c_int) -> io::Result<File> {
+    // SAFETY: FFI call with valid arguments, whose result is checked.
+    let fd = unsafe { libc::socket(family, socket_type | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just created, and is owned by nobody
+    // else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn remove_stale_socket(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Failed removing socket {:?}: {}", path, e);
+        }
+    }
+}
+
+fn open_seqpacket(path: &Path, server: bool) -> Result<File> {
+    let socket = create_socket(libc::AF_UNIX, libc::SOCK_SEQPACKET).map_err(Error::CreateSocket)?;
+    let (addr, len) = unix_sockaddr(path).map_err(Error::CreateSocket)?;
+    let addr = &addr as *const _ as *const libc::sockaddr;
+
+    if !server {
+        // SAFETY: the socket address is valid, and of the given size.
+        check(unsafe { libc::connect(socket.as_raw_fd(), addr, len) }).map_err(Error::Connect)?;
+        return Ok(socket);
+    }
+
+    // A socket left behind by a previous server can't be bound again.
+    remove_stale_socket(path);
+    // SAFETY: the socket address is valid, and of the given size.
+    check(unsafe { libc::bind(socket.as_raw_fd(), addr, len) }).map_err(Error::Bind)?;
+    // SAFETY: FFI call on a valid socket.
+    check(unsafe { libc::listen(socket.as_raw_fd(), 1) }).map_err(Error::Bind)?;
+    info!("Waiting for the peer to connect to {:?}", path);
+    // SAFETY: FFI call on a valid socket, the address of the peer isn't
+    // needed.
+    let fd = check(unsafe {
+        libc::accept4(
+            socket.as_raw_fd(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            libc::SOCK_CLOEXEC,
+        )
+    })
+    .map_err(Error::Accept)?;
+
+    // SAFETY: the file descriptor was just created, and is owned by nobody
+    // else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn open_stream(addr: &L2SocketAddr, server: bool) -> Result<File> {
+    let fd = match (addr, server) {
+        (L2SocketAddr::Unix(path), false) => UnixStream::connect(path)
+            .map_err(Error::Connect)?
+            .into_raw_fd(),
+        (L2SocketAddr::Unix(path), true) => {
+            remove_stale_socket(path);
+            let listener = UnixListener::bind(path).map_err(Error::Bind)?;
+            info!("Waiting for the peer to connect to {:?}", path);
+            listener.accept().map_err(Error::Accept)?.0.into_raw_fd()
+        }
+        (L2SocketAddr::Inet(addr), server) => {
+            let stream = if server {
+                let listener = TcpListener::bind(addr).map_err(Error::Bind)?;
+                info!("Waiting for the peer to connect to {}", addr);
+                listener.accept().map_err(Error::Accept)?.0
+            } else {
+                TcpStream::connect(addr).map_err(Error::Connect)?
+            };
+            // Frames are latency sensitive.
+            stream.set_nodelay(true).map_err(Error::ConfigureSocket)?;
+            stream.into_raw_fd()
+        }
+    };
+
+    // SAFETY: the file descriptor is owned by nobody else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn open_udp(remote: &SocketAddr, local: Option<&SocketAddr>) -> Result<UdpSocket> {
+    if let Some(local) = local {
+        if local.is_ipv4() != remote.is_ipv4() {
+            return Err(Error::AddressMismatch);
+        }
+    }
+
+    if !remote.ip().is_multicast() {
+        let local = local.copied().unwrap_or_else(|| match remote {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        });
+        return UdpSocket::bind(local).map_err(Error::Bind);
+    }
+
+    // All the members of the group listen on its port, which must be shared.
+    let family = match remote {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let socket = create_socket(family, libc::SOCK_DGRAM).map_err(Error::CreateSocket)?;
+    let enable: libc::c_int = 1;
+    // SAFETY: FFI call on a valid socket, with a valid option value.
+    check(unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &enable as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })
+    .map_err(Error::ConfigureSocket)?;
+    let bind_addr = match remote {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, remote.port())),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, remote.port())),
+    };
+    let (addr, len) = inet_sockaddr(&bind_addr);
+    // SAFETY: the socket address is valid, and of the given size.
+    check(unsafe { libc::bind(socket.as_raw_fd(), &addr as *const _ as *const _, len) })
+        .map_err(Error::Bind)?;
+
+    // SAFETY: the file descriptor is owned by nobody else.
+    let socket = unsafe { UdpSocket::from_raw_fd(socket.into_raw_fd()) };
+    // The local address picks the interface of the group, and the frames
+    // sent are looped back to the other VMs of the host.
+    match (remote, local) {
+        (SocketAddr::V4(remote), local) => {
+            let interface = match local {
+                Some(SocketAddr::V4(local)) => *local.ip(),
+                _ => Ipv4Addr::UNSPECIFIED,
+            };
+            socket
+                .join_multicast_v4(remote.ip(), &interface)
+                .map_err(Error::JoinMulticast)?;
+            socket
+                .set_multicast_loop_v4(true)
+                .map_err(Error::JoinMulticast)?;
+        }
+        (SocketAddr::V6(remote), _) => {
+            socket
+                .join_multicast_v6(remote.ip(), 0)
+                .map_err(Error::JoinMulticast)?;
+            socket
+                .set_multicast_loop_v6(true)
+                .map_err(Error::JoinMulticast)?;
+        }
+    }
+
+    Ok(socket)
+}
+
+fn iovecs_len(iovecs: &[libc::iovec]) -> usize {
+    iovecs.iter().map(|iovec| iovec.iov_len).sum()
+}
+
+// Returns the iovecs covering what follows the first `skip` bytes of
+// `iovecs`.
+fn skip_iovecs(iovecs: &[libc::iovec], mut skip: usize) -> Vec<libc::iovec> {
+    let mut skipped = Vec::with_capacity(iovecs.len());
+    for iovec in iovecs {
+        if skip >= iovec.iov_len {
+            skip -= iovec.iov_len;
+            continue;
+        }
+        skipped.push(libc::iovec {
+            // SAFETY: the offset is within the buffer of the iovec.
+            iov_base: unsafe { (iovec.iov_base as *mut u8).add(skip) } as *mut libc::c_void,
+            iov_len: iovec.iov_len - skip,
+        });
+        skip = 0;
+    }
+
+    skipped
+}
+
+// Copies `data` to the buffers of `iovecs`, up to their size, returning the
+// number of bytes copied.
+fn copy_to_iovecs(iovecs: &[libc::iovec], data: &[u8]) -> usize {
+    let mut copied = 0;
+    for iovec in iovecs {
+        if copied == data.len() {
+            break;
+        }
+        let count = std::cmp::min(iovec.iov_len, data.len() - copied);
+        // SAFETY: the iovec points to guest memory of at least iov_len
+        // bytes.
+        unsafe {
+            std::ptr::copy_nonoverlapping(data[copied..].as_ptr(), iovec.iov_base as *mut u8, count)
+        };
+        copied += count;
+    }
+
+    copied
+}
+
+fn copy_from_iovecs(iovecs: &[libc::iovec], data: &mut Vec<u8>) {
+    for iovec in iovecs {
+        // SAFETY: the iovec points to guest memory of at least iov_len
+        // bytes.
+        data.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+        });
+    }
+}
+
+fn peer_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "peer closed the link")
+}
+
+/// Socket standing for the tap interface of a virtio-net device, framing the
+/// frames of the queues for the peer.
+pub struct L2Socket {
+    socket: File,
+    framing: Framing,
+    // Address datagrams are sent to, unless the socket is connected
+    remote: Option<(libc::sockaddr_storage, libc::socklen_t)>,
+    // Length prefix and frame partially received from a stream
+    rx_buf: Vec<u8>,
+    // Rest of the frame partially written to a stream
+    tx_buf: Vec<u8>,
+}
+
+impl L2Socket {
+    /// Opens the socket described by `config`, which may wait for the peer
+    /// to connect.
+    pub fn open(config: &L2SocketConfig) -> Result<Self> {
+        let (socket, framing, remote) = match config {
+            L2SocketConfig::Stream { addr, server } => {
+                (open_stream(addr, *server)?, Framing::Stream, None)
+            }
+            L2SocketConfig::SeqPacket { path, server } => {
+                (open_seqpacket(path, *server)?, Framing::Message, None)
+            }
+            L2SocketConfig::Dgram { remote, local } => match (remote, local) {
+                (L2SocketAddr::Unix(remote), Some(L2SocketAddr::Unix(local))) => {
+                    let socket = UnixDatagram::bind(local).map_err(Error::Bind)?;
+                    (
+                        // SAFETY: the file descriptor is owned by nobody
+                        // else.
+                        unsafe { File::from_raw_fd(socket.into_raw_fd()) },
+                        Framing::Message,
+                        Some(unix_sockaddr(remote).map_err(Error::CreateSocket)?),
+                    )
+                }
+                (L2SocketAddr::Unix(_), None) => return Err(Error::LocalAddressMissing),
+                (L2SocketAddr::Inet(remote), None) => (
+                    // SAFETY: the file descriptor is owned by nobody else.
+                    unsafe { File::from_raw_fd(open_udp(remote, None)?.into_raw_fd()) },
+                    Framing::Message,
+                    Some(inet_sockaddr(remote)),
+                ),
+                (L2SocketAddr::Inet(remote), Some(L2SocketAddr::Inet(local))) => (
+                    // SAFETY: the file descriptor is owned by nobody else.
+                    unsafe { File::from_raw_fd(open_udp(remote, Some(local))?.into_raw_fd()) },
+                    Framing::Message,
+                    Some(inet_sockaddr(remote)),
+                ),
+                _ => return Err(Error::AddressMismatch),
+            },
+        };
+
+        // SAFETY: FFI calls on a valid file descriptor, whose result is
+        // checked.
+        check(unsafe {
+            let flags = libc::fcntl(socket.as_raw_fd(), libc::F_GETFL);
+            libc::fcntl(socket.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK)
+        })
+        .map_err(Error::ConfigureSocket)?;
+
+        Ok(L2Socket {
+            socket,
+            framing,
+            remote,
+            rx_buf: Vec::new(),
+            tx_buf: Vec::new(),
+        })
+    }
+
+    /// Returns a tap standing for the socket, to be polled in its place.
+    pub fn try_clone_tap(&self) -> io::Result<Tap> {
+        Ok(Tap::from_socket(self.socket.try_clone()?))
+    }
+
+    /// Receives a frame in the buffers of `iovecs`, after a virtio-net
+    /// header left empty. Returns the length of the header and the frame.
+    pub(crate) fn recv(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        let header_len = vnet_hdr_len();
+        if iovecs_len(iovecs) <= header_len {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        copy_to_iovecs(iovecs, &vec![0u8; header_len]);
+        let frame_iovecs = skip_iovecs(iovecs, header_len);
+
+        let len = match self.framing {
+            Framing::Message => loop {
+                // SAFETY: the iovecs point to guest memory of the given
+                // sizes.
+                let ret = unsafe {
+                    libc::readv(
+                        self.socket.as_raw_fd(),
+                        frame_iovecs.as_ptr(),
+                        frame_iovecs.len() as libc::c_int,
+                    )
+                };
+                match ret {
+                    ret if ret < 0 => return Err(io::Error::last_os_error()),
+                    // Empty datagrams are no frame, while connected sockets
+                    // read nothing once the peer is gone.
+                    0 if self.remote.is_some() => continue,
+                    0 => return Err(peer_closed()),
+                    ret => break ret as usize,
+                }
+            },
+            Framing::Stream => {
+                // Only the bytes of the current frame are read, the rest
+                // being left for the socket to be polled again.
+                while self.rx_buf.len() < STREAM_HDR_LEN {
+                    self.read_stream(STREAM_HDR_LEN)?;
+                }
+                let frame_len =
+                    u32::from_be_bytes(self.rx_buf[..STREAM_HDR_LEN].try_into().unwrap()) as usize;
+                if frame_len > MAX_FRAME_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("frame of {} bytes from the peer", frame_len),
+                    ));
+                }
+                while self.rx_buf.len() < STREAM_HDR_LEN + frame_len {
+                    self.read_stream(STREAM_HDR_LEN + frame_len)?;
+                }
+                let len = copy_to_iovecs(&frame_iovecs, &self.rx_buf[STREAM_HDR_LEN..]);
+                self.rx_buf.clear();
+                len
+            }
+        };
+
+        Ok(header_len + len)
+    }
+
+    // Reads from the stream until `rx_buf` holds `len` bytes.
+    fn read_stream(&mut self, len: usize) -> io::Result<()> {
+        let start = self.rx_buf.len();
+        self.rx_buf.resize(len, 0);
+        let result = match self.socket.read(&mut self.rx_buf[start..]) {
+            Ok(0) => Err(peer_closed()),
+            result => result,
+        };
+        self.rx_buf
+            .truncate(start + result.as_ref().map_or(0, |count| *count));
+        result.map(|_| ())
+    }
+
+    /// Sends the frame held in the buffers of `iovecs`, after its virtio-net
+    /// header. Returns the length of the header and the frame.
+    pub(crate) fn send(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        let len = iovecs_len(iovecs);
+        if len < vnet_hdr_len() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let frame_iovecs = skip_iovecs(iovecs, vnet_hdr_len());
+
+        match self.framing {
+            Framing::Message => {
+                // SAFETY: all zeroes is a valid msghdr.
+                let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+                if let Some((addr, addr_len)) = &self.remote {
+                    msg.msg_name = addr as *const _ as *mut libc::c_void;
+                    msg.msg_namelen = *addr_len;
+                }
+                msg.msg_iov = frame_iovecs.as_ptr() as *mut libc::iovec;
+                msg.msg_iovlen = frame_iovecs.len() as _;
+                // SAFETY: the message points to the address of the peer and
+                // to guest memory of the given sizes.
+                let ret = unsafe { libc::sendmsg(self.socket.as_raw_fd(), &msg, 0) };
+                if ret < 0 {
+                    let e = io::Error::last_os_error();
+                    // Datagrams are lost while the peer isn't there.
+                    match e.raw_os_error() {
+                        Some(libc::ECONNREFUSED) | Some(libc::ENOENT) if self.remote.is_some() => {}
+                        _ => return Err(e),
+                    }
+                }
+            }
+            Framing::Stream => {
+                // The frame in progress must be written first.
+                if !self.flush()? {
+                    return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                }
+                let frame_len = iovecs_len(&frame_iovecs) as u32;
+                self.tx_buf.extend_from_slice(&frame_len.to_be_bytes());
+                copy_from_iovecs(&frame_iovecs, &mut self.tx_buf);
+                // The frame is sent whatever is written now, the rest being
+                // written once the socket is writable again.
+                self.flush()?;
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Writes what's left of the frame partially written to a stream.
+    /// Returns whether the whole frame could be written.
+    pub(crate) fn flush(&mut self) -> io::Result<bool> {
+        while !self.tx_buf.is_empty() {
+            match self.socket.write(&self.tx_buf) {
+                Ok(0) => return Err(peer_closed()),
+                Ok(count) => {
+                    self.tx_buf.drain(..count);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Whether part of a frame waits for the stream to be writable.
+    pub(crate) fn tx_pending(&self) -> bool {
+        !self.tx_buf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `f` over iovecs covering `bufs`.
+    fn with_iovecs<R>(bufs: &mut [Vec<u8>], f: impl FnOnce(&[libc::iovec]) -> R) -> R {
+        let iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        f(&iovecs)
+    }
+
+    fn socket_pair(socket_type: libc::c_int) -> (File, File) {
+        let mut fds = [0; 2];
+        // SAFETY: FFI call with valid arguments, whose result is checked.
+        let ret = unsafe { libc::socketpair(libc::AF_UNIX, socket_type, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        // SAFETY: the file descriptors were just created, and are owned by
+        // nobody else.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    fn l2_socket(socket: File, framing: Framing) -> L2Socket {
+        L2Socket {
+            socket,
+            framing,
+            remote: None,
+            rx_buf: Vec::new(),
+            tx_buf: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_socket_addr_parse() {
+        assert_eq!(
+            "unix:/tmp/net.sock".parse::<L2SocketAddr>().unwrap(),
+            L2SocketAddr::Unix(PathBuf::from("/tmp/net.sock"))
+        );
+        let addr: L2SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        assert_eq!(addr, L2SocketAddr::Inet("127.0.0.1:5000".parse().unwrap()));
+        assert_eq!(addr.to_string(), "127.0.0.1:5000");
+        assert!("unix:".parse::<L2SocketAddr>().is_err());
+        assert!("/tmp/net.sock".parse::<L2SocketAddr>().is_err());
+    }
+
+    #[test]
+    fn test_stream_framing() {
+        let (a, mut b) = socket_pair(libc::SOCK_STREAM | libc::SOCK_NONBLOCK);
+        let mut socket = l2_socket(a, Framing::Stream);
+
+        // The virtio-net header is split across buffers.
+        let mut frame = vec![vec![0u8; 8], vec![0u8; 4], b"hello".to_vec()];
+        assert_eq!(
+            with_iovecs(&mut frame, |iovecs| socket.send(iovecs)).unwrap(),
+            17
+        );
+        let mut buf = [0u8; 9];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\0\0\0\x05hello");
+
+        // Frames received in pieces are delivered once complete.
+        b.write_all(b"\0\0\0\x05wor").unwrap();
+        let mut bufs = vec![vec![0xffu8; 10], vec![0xffu8; 10]];
+        let e = with_iovecs(&mut bufs, |iovecs| socket.recv(iovecs)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        b.write_all(b"ld").unwrap();
+        assert_eq!(
+            with_iovecs(&mut bufs, |iovecs| socket.recv(iovecs)).unwrap(),
+            17
+        );
+        assert_eq!(bufs[0], [0; 10]);
+        assert_eq!(bufs[1][..7], *b"\0\0world");
+
+        drop(b);
+        let e = with_iovecs(&mut bufs, |iovecs| socket.recv(iovecs)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn test_message_framing() {
+        let (a, b) = socket_pair(libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK);
+        let mut socket = l2_socket(a, Framing::Message);
+        let mut peer = l2_socket(b, Framing::Message);
+
+        let mut frame = vec![vec![0u8; 12], b"hello".to_vec()];
+        assert_eq!(
+            with_iovecs(&mut frame, |iovecs| socket.send(iovecs)).unwrap(),
+            17
+        );
+        let mut bufs = vec![vec![0xffu8; 32]];
+        assert_eq!(
+            with_iovecs(&mut bufs, |iovecs| peer.recv(iovecs)).unwrap(),
+            17
+        );
+        assert_eq!(bufs[0][..12], [0; 12]);
+        assert_eq!(bufs[0][12..17], *b"hello");
+
+        let e = with_iovecs(&mut bufs, |iovecs| peer.recv(iovecs)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_dgram() {
+        let local: L2SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut socket = L2Socket::open(&L2SocketConfig::Dgram {
+            remote: L2SocketAddr::Inet(peer.local_addr().unwrap()),
+            local: Some(local),
+        })
+        .unwrap();
+
+        let mut frame = vec![vec![0u8; 12], b"hello".to_vec()];
+        with_iovecs(&mut frame, |iovecs| socket.send(iovecs)).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        peer.send_to(b"world", from).unwrap();
+        let mut bufs = vec![vec![0u8; 32]];
+        let mut len = Err(io::Error::from(io::ErrorKind::WouldBlock));
+        for _ in 0..100 {
+            len = with_iovecs(&mut bufs, |iovecs| socket.recv(iovecs));
+            if len.is_ok() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(len.unwrap(), 17);
+        assert_eq!(bufs[0][12..17], *b"world");
+    }
+}
@@ -13,11 +13,15 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
+mod capture;
 mod ctrl_queue;
+mod l2_socket;
 mod mac;
 mod open_tap;
 mod queue_pair;
+mod rx_filter;
 mod tap;
+mod user_net;
 
 use std::io::Error as IoError;
 use std::os::raw::c_uint;
@@ -34,11 +38,20 @@ use vm_memory::{bitmap::AtomicBitmap, ByteValued};
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 
+pub use capture::{CaptureConfig, PacketCapture};
 pub use ctrl_queue::{CtrlQueue, Error as CtrlQueueError};
+pub use l2_socket::{
+    Error as L2SocketError, L2Socket, L2SocketAddr, L2SocketAddrParseError, L2SocketConfig,
+};
 pub use mac::{MacAddr, MAC_ADDR_LEN};
 pub use open_tap::{open_tap, Error as OpenTapError};
 pub use queue_pair::{NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio};
+pub use rx_filter::{RxFilter, RxFilterState};
 pub use tap::{Error as TapError, Tap};
+pub use user_net::{
+    Error as UserNetError, HostForward, HostForwardParseError, HostForwardProtocol, UserNet,
+    UserNetConfig,
+};
 
 #[derive(Debug)]
 pub enum Error {
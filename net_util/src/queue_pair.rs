@@ -3,13 +3,17 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::{register_listener, unregister_listener, vnet_hdr_len, Tap};
-use crate::GuestMemoryMmap;
-use rate_limiter::{RateLimiter, TokenType};
+use crate::capture::Direction;
+use crate::rx_filter::RX_FILTER_HEADER_LEN;
+use crate::{GuestMemoryMmap, L2Socket, PacketCapture, RxFilter};
+use rate_limiter::group::QueueRateLimiter;
+use rate_limiter::TokenType;
 use std::io;
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use virtio_bindings::bindings::virtio_net::VIRTIO_NET_S_LINK_UP;
 use virtio_queue::Queue;
 use vm_memory::{Bytes, GuestMemory, GuestMemoryAtomic};
 use vm_virtio::{AccessPlatform, Translatable};
@@ -34,16 +38,27 @@ impl TxVirtio {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn process_desc_chain(
         &mut self,
         tap: &mut Tap,
+        mut l2_socket: Option<&mut L2Socket>,
         queue: &mut Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
-        rate_limiter: &mut Option<RateLimiter>,
+        rate_limiter: &mut Option<QueueRateLimiter>,
+        capture: Option<&PacketCapture>,
+        link_up: bool,
         access_platform: Option<&Arc<dyn AccessPlatform>>,
     ) -> Result<bool, NetQueuePairError> {
         let mut retry_write = false;
         let mut rate_limit_reached = false;
 
+        // The frame partially written to the socket goes before any other.
+        if let Some(l2_socket) = l2_socket.as_mut() {
+            if !l2_socket.flush().map_err(NetQueuePairError::WriteTap)? {
+                return Ok(true);
+            }
+        }
+
         loop {
             let used_desc_head: (u16, u32);
             let mut avail_iter = queue
@@ -86,26 +101,49 @@ impl TxVirtio {
                     next_desc = desc_chain.next();
                 }
 
-                let len = if !iovecs.is_empty() {
-                    let result = unsafe {
-                        libc::writev(
-                            tap.as_raw_fd() as libc::c_int,
-                            iovecs.as_ptr() as *const libc::iovec,
-                            iovecs.len() as libc::c_int,
-                        )
+                // Frames sent while the link is down are dropped, as if the
+                // cable was pulled. They are still captured, as they reached
+                // the device.
+                if !iovecs.is_empty() && !link_up {
+                    if let Some(capture) = capture {
+                        let frame_len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+                        capture.capture(Direction::Outbound, &iovecs, frame_len);
+                    }
+                }
+                let len = if !iovecs.is_empty() && link_up {
+                    let result = if let Some(l2_socket) = l2_socket.as_mut() {
+                        l2_socket.send(&iovecs)
+                    } else {
+                        let result = unsafe {
+                            libc::writev(
+                                tap.as_raw_fd() as libc::c_int,
+                                iovecs.as_ptr() as *const libc::iovec,
+                                iovecs.len() as libc::c_int,
+                            )
+                        };
+                        if result < 0 {
+                            Err(std::io::Error::last_os_error())
+                        } else {
+                            Ok(result as usize)
+                        }
                     };
 
-                    if result < 0 {
-                        let e = std::io::Error::last_os_error();
-
-                        /* EAGAIN */
-                        if e.kind() == std::io::ErrorKind::WouldBlock {
-                            avail_iter.go_to_previous_position();
-                            retry_write = true;
-                            break;
+                    let result = match result {
+                        Ok(result) => result,
+                        Err(e) => {
+                            /* EAGAIN */
+                            if e.kind() == std::io::ErrorKind::WouldBlock {
+                                avail_iter.go_to_previous_position();
+                                retry_write = true;
+                                break;
+                            }
+                            error!("net: tx: failed writing to tap: {}", e);
+                            return Err(NetQueuePairError::WriteTap(e));
                         }
-                        error!("net: tx: failed writing to tap: {}", e);
-                        return Err(NetQueuePairError::WriteTap(e));
+                    };
+
+                    if let Some(capture) = capture {
+                        capture.capture(Direction::Outbound, &iovecs, result);
                     }
 
                     self.counter_bytes += Wrapping(result as u64 - vnet_hdr_len() as u64);
@@ -140,7 +178,10 @@ impl TxVirtio {
             }
         }
 
-        Ok(retry_write)
+        // The rest of the last frame is written once the socket is writable.
+        let tx_pending = l2_socket.map_or(false, |l2_socket| l2_socket.tx_pending());
+
+        Ok(retry_write || tx_pending)
     }
 }
 
@@ -164,11 +205,16 @@ impl RxVirtio {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn process_desc_chain(
         &mut self,
         tap: &mut Tap,
+        mut l2_socket: Option<&mut L2Socket>,
         queue: &mut Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
-        rate_limiter: &mut Option<RateLimiter>,
+        rate_limiter: &mut Option<QueueRateLimiter>,
+        rx_filter: Option<&Arc<RxFilter>>,
+        capture: Option<&PacketCapture>,
+        link_up: bool,
         access_platform: Option<&Arc<dyn AccessPlatform>>,
     ) -> Result<bool, NetQueuePairError> {
         let mut exhausted_descs = true;
@@ -230,25 +276,57 @@ impl RxVirtio {
                 }
 
                 let len = if !iovecs.is_empty() {
-                    let result = unsafe {
-                        libc::readv(
-                            tap.as_raw_fd() as libc::c_int,
-                            iovecs.as_ptr() as *const libc::iovec,
-                            iovecs.len() as libc::c_int,
-                        )
+                    let result = if let Some(l2_socket) = l2_socket.as_mut() {
+                        l2_socket.recv(&iovecs)
+                    } else {
+                        let result = unsafe {
+                            libc::readv(
+                                tap.as_raw_fd() as libc::c_int,
+                                iovecs.as_ptr() as *const libc::iovec,
+                                iovecs.len() as libc::c_int,
+                            )
+                        };
+                        if result < 0 {
+                            Err(std::io::Error::last_os_error())
+                        } else {
+                            Ok(result as usize)
+                        }
                     };
-                    if result < 0 {
-                        let e = std::io::Error::last_os_error();
-                        exhausted_descs = false;
-                        avail_iter.go_to_previous_position();
 
-                        /* EAGAIN */
-                        if e.kind() == std::io::ErrorKind::WouldBlock {
-                            break;
+                    let result = match result {
+                        Ok(result) => result,
+                        Err(e) => {
+                            exhausted_descs = false;
+                            avail_iter.go_to_previous_position();
+
+                            /* EAGAIN */
+                            if e.kind() == std::io::ErrorKind::WouldBlock {
+                                break;
+                            }
+
+                            error!("net: rx: failed reading from tap: {}", e);
+                            return Err(NetQueuePairError::ReadTap(e));
                         }
+                    };
 
-                        error!("net: rx: failed reading from tap: {}", e);
-                        return Err(NetQueuePairError::ReadTap(e));
+                    // Frames received while the link is down, or filtered
+                    // out by the guest, are dropped, the descriptor chain
+                    // being reused for the next frame. The frames filtered
+                    // out are still captured, as they reached the device.
+                    if !link_up {
+                        avail_iter.go_to_previous_position();
+                        continue;
+                    }
+                    if let Some(capture) = capture {
+                        capture.capture(Direction::Inbound, &iovecs, result);
+                    }
+                    if let Some(rx_filter) = rx_filter {
+                        let mut header = [0u8; RX_FILTER_HEADER_LEN];
+                        let header_len = read_frame_header(&iovecs, result, &mut header);
+                        if !rx_filter.accepts(&header[..header_len]) {
+                            avail_iter.go_to_previous_position();
+                            continue;
+                        }
                     }
 
                     // Write num_buffers to guest memory. We simply write 1 as we
@@ -295,6 +373,35 @@ impl RxVirtio {
     }
 }
 
+// Copies the beginning of the frame held by the first `len` bytes of
+// `iovecs`, past the virtio-net header, returning the number of bytes copied.
+pub(crate) fn read_frame_header(iovecs: &[libc::iovec], len: usize, header: &mut [u8]) -> usize {
+    let mut skip = vnet_hdr_len();
+    let mut remaining = len;
+    let mut copied = 0;
+    for iovec in iovecs {
+        if remaining == 0 || copied == header.len() {
+            break;
+        }
+        let iov_len = std::cmp::min(iovec.iov_len, remaining);
+        remaining -= iov_len;
+        if skip >= iov_len {
+            skip -= iov_len;
+            continue;
+        }
+        let count = std::cmp::min(iov_len - skip, header.len() - copied);
+        // SAFETY: the iovec points to guest memory of at least iov_len bytes,
+        // holding the frame.
+        let buf =
+            unsafe { std::slice::from_raw_parts((iovec.iov_base as *const u8).add(skip), count) };
+        header[copied..copied + count].copy_from_slice(buf);
+        copied += count;
+        skip = 0;
+    }
+
+    copied
+}
+
 #[derive(Default, Clone)]
 pub struct NetCounters {
     pub tx_bytes: Arc<AtomicU64>,
@@ -347,22 +454,42 @@ pub struct NetQueuePair {
     pub tap_rx_event_id: u16,
     pub tap_tx_event_id: u16,
     pub rx_desc_avail: bool,
-    pub rx_rate_limiter: Option<RateLimiter>,
-    pub tx_rate_limiter: Option<RateLimiter>,
+    pub rx_rate_limiter: Option<QueueRateLimiter>,
+    pub tx_rate_limiter: Option<QueueRateLimiter>,
+    pub rx_filter: Option<Arc<RxFilter>>,
+    // Socket carrying the frames in place of the tap, which then stands for
+    // the socket to be polled.
+    pub l2_socket: Option<Arc<Mutex<L2Socket>>>,
+    pub capture: Option<Arc<PacketCapture>>,
+    // Status field of the device configuration, no frame going through
+    // while the link is down
+    pub status: Option<Arc<AtomicU16>>,
     pub access_platform: Option<Arc<dyn AccessPlatform>>,
 }
 
 impl NetQueuePair {
+    fn link_up(&self) -> bool {
+        self.status.as_ref().map_or(true, |status| {
+            status.load(Ordering::Acquire) & VIRTIO_NET_S_LINK_UP as u16 != 0
+        })
+    }
+
     pub fn process_tx(
         &mut self,
         queue: &mut Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
     ) -> Result<bool, NetQueuePairError> {
+        let link_up = self.link_up();
+        let mut l2_socket = self.l2_socket.as_ref().map(|s| s.lock().unwrap());
         let tx_tap_retry = self.tx.process_desc_chain(
             &mut self.tap,
+            l2_socket.as_deref_mut(),
             queue,
             &mut self.tx_rate_limiter,
+            self.capture.as_deref(),
+            link_up,
             self.access_platform.as_ref(),
         )?;
+        drop(l2_socket);
 
         // We got told to try again when writing to the tap. Wait for the TAP to be writable
         if tx_tap_retry && !self.tx_tap_listening {
@@ -405,12 +532,19 @@ impl NetQueuePair {
         &mut self,
         queue: &mut Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
     ) -> Result<bool, NetQueuePairError> {
+        let link_up = self.link_up();
+        let mut l2_socket = self.l2_socket.as_ref().map(|s| s.lock().unwrap());
         self.rx_desc_avail = !self.rx.process_desc_chain(
             &mut self.tap,
+            l2_socket.as_deref_mut(),
             queue,
             &mut self.rx_rate_limiter,
+            self.rx_filter.as_ref(),
+            self.capture.as_deref(),
+            link_up,
             self.access_platform.as_ref(),
         )?;
+        drop(l2_socket);
         let rate_limit_reached = self
             .rx_rate_limiter
             .as_ref()
@@ -0,0 +1,287 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filtering of the frames received by a virtio-net device, as programmed by
+//! the guest through the control queue.
+//!
+//! The device starts promiscuous, receiving every frame until the guest
+//! driver sets up its receive mode. Frames are then received if they're sent
+//! to the MAC address of the device, or to an address of the unicast or
+//! multicast tables. Once the VLAN filtering is negotiated, the frames tagged
+//! with a VLAN the guest didn't add are dropped, even though the frames which
+//! aren't tagged are still received.
+
+use crate::{MacAddr, MAC_ADDR_LEN};
+use std::sync::RwLock;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+/// Number of addresses in each MAC table. The guest can set larger tables,
+/// in which case every frame of the kind of the table is received.
+pub const MAC_TABLE_ENTRIES: usize = 64;
+
+/// Number of VLAN identifiers.
+pub const MAX_VLAN: u16 = 4096;
+
+const ETH_HEADER_LEN: usize = 14;
+const ETH_P_8021Q: u16 = 0x8100;
+
+/// Length of the beginning of a frame needed to filter it.
+pub const RX_FILTER_HEADER_LEN: usize = ETH_HEADER_LEN + 4;
+
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+pub struct RxFilterState {
+    pub promisc: bool,
+    pub allmulti: bool,
+    pub mac: Option<[u8; MAC_ADDR_LEN]>,
+    pub unicast: Vec<[u8; MAC_ADDR_LEN]>,
+    pub unicast_overflow: bool,
+    pub multicast: Vec<[u8; MAC_ADDR_LEN]>,
+    pub multicast_overflow: bool,
+    pub vlan_filtering: bool,
+    // Bitmap of the VLAN identifiers added by the guest.
+    pub vlans: Vec<u32>,
+}
+
+impl RxFilterState {
+    fn new(mac: Option<MacAddr>) -> Self {
+        RxFilterState {
+            promisc: true,
+            allmulti: false,
+            mac: mac.map(|mac| {
+                let mut bytes = [0u8; MAC_ADDR_LEN];
+                bytes.copy_from_slice(mac.get_bytes());
+                bytes
+            }),
+            unicast: Vec::new(),
+            unicast_overflow: false,
+            multicast: Vec::new(),
+            multicast_overflow: false,
+            vlan_filtering: false,
+            vlans: vec![0; MAX_VLAN as usize / 32],
+        }
+    }
+
+    fn has_vlan(&self, vid: u16) -> bool {
+        self.vlans[vid as usize / 32] & (1 << (vid % 32)) != 0
+    }
+}
+
+pub struct RxFilter {
+    default_mac: Option<MacAddr>,
+    state: RwLock<RxFilterState>,
+}
+
+impl RxFilter {
+    /// Creates the filter of a device with the `mac` address, or without any
+    /// address known to the device, in which case all the unicast frames
+    /// are received.
+    pub fn new(mac: Option<MacAddr>) -> Self {
+        RxFilter {
+            default_mac: mac,
+            state: RwLock::new(RxFilterState::new(mac)),
+        }
+    }
+
+    /// Goes back to receiving every frame, with the initial MAC address of
+    /// the device, as on a device reset.
+    pub fn reset(&self) {
+        *self.state.write().unwrap() = RxFilterState::new(self.default_mac);
+    }
+
+    pub fn state(&self) -> RxFilterState {
+        self.state.read().unwrap().clone()
+    }
+
+    pub fn set_state(&self, state: &RxFilterState) {
+        *self.state.write().unwrap() = state.clone();
+    }
+
+    /// Drops the frames tagged with a VLAN the guest didn't add, which is
+    /// only the case when the guest negotiated VLAN filtering.
+    pub fn set_vlan_filtering(&self, vlan_filtering: bool) {
+        self.state.write().unwrap().vlan_filtering = vlan_filtering;
+    }
+
+    pub fn set_promisc(&self, promisc: bool) {
+        self.state.write().unwrap().promisc = promisc;
+    }
+
+    pub fn set_allmulti(&self, allmulti: bool) {
+        self.state.write().unwrap().allmulti = allmulti;
+    }
+
+    /// Returns the MAC address the guest set, or the initial one.
+    pub fn mac(&self) -> Option<MacAddr> {
+        self.state
+            .read()
+            .unwrap()
+            .mac
+            .map(|mac| MacAddr::from_bytes_unchecked(&mac))
+    }
+
+    pub fn set_mac(&self, mac: MacAddr) {
+        let mut bytes = [0u8; MAC_ADDR_LEN];
+        bytes.copy_from_slice(mac.get_bytes());
+        self.state.write().unwrap().mac = Some(bytes);
+    }
+
+    /// Replaces the unicast and multicast MAC tables.
+    pub fn set_mac_tables(
+        &self,
+        unicast: Vec<[u8; MAC_ADDR_LEN]>,
+        multicast: Vec<[u8; MAC_ADDR_LEN]>,
+    ) {
+        let mut state = self.state.write().unwrap();
+        state.unicast_overflow = unicast.len() > MAC_TABLE_ENTRIES;
+        state.unicast = if state.unicast_overflow {
+            Vec::new()
+        } else {
+            unicast
+        };
+        state.multicast_overflow = multicast.len() > MAC_TABLE_ENTRIES;
+        state.multicast = if state.multicast_overflow {
+            Vec::new()
+        } else {
+            multicast
+        };
+    }
+
+    /// Adds the VLAN `vid` to the ones received. Returns false if the
+    /// identifier is out of range.
+    pub fn add_vlan(&self, vid: u16) -> bool {
+        if vid >= MAX_VLAN {
+            return false;
+        }
+        self.state.write().unwrap().vlans[vid as usize / 32] |= 1 << (vid % 32);
+        true
+    }
+
+    /// Removes the VLAN `vid` from the ones received. Returns false if the
+    /// identifier is out of range.
+    pub fn del_vlan(&self, vid: u16) -> bool {
+        if vid >= MAX_VLAN {
+            return false;
+        }
+        self.state.write().unwrap().vlans[vid as usize / 32] &= !(1 << (vid % 32));
+        true
+    }
+
+    /// Returns whether the frame starting with `header`, holding up to
+    /// `RX_FILTER_HEADER_LEN` bytes, is to be received by the guest.
+    pub fn accepts(&self, header: &[u8]) -> bool {
+        let state = self.state.read().unwrap();
+        if state.promisc || header.len() < ETH_HEADER_LEN {
+            return true;
+        }
+
+        if state.vlan_filtering
+            && u16::from_be_bytes([header[12], header[13]]) == ETH_P_8021Q
+            && header.len() >= ETH_HEADER_LEN + 2
+        {
+            let vid = u16::from_be_bytes([header[14], header[15]]) & (MAX_VLAN - 1);
+            if !state.has_vlan(vid) {
+                return false;
+            }
+        }
+
+        let destination = &header[..MAC_ADDR_LEN];
+        if destination[0] & 1 != 0 {
+            // Broadcast frames are always received.
+            destination == [0xff; MAC_ADDR_LEN]
+                || state.allmulti
+                || state.multicast_overflow
+                || state.multicast.iter().any(|mac| mac == destination)
+        } else {
+            state.mac.map_or(true, |mac| mac == destination)
+                || state.unicast_overflow
+                || state.unicast.iter().any(|mac| mac == destination)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; MAC_ADDR_LEN] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+    const OTHER_MAC: [u8; MAC_ADDR_LEN] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd];
+    const MULTICAST_MAC: [u8; MAC_ADDR_LEN] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+
+    fn frame(destination: [u8; MAC_ADDR_LEN], vid: Option<u16>) -> Vec<u8> {
+        let mut frame = destination.to_vec();
+        frame.extend_from_slice(&OTHER_MAC);
+        if let Some(vid) = vid {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&vid.to_be_bytes());
+        }
+        frame.extend_from_slice(&0x0800u16.to_be_bytes());
+        frame
+    }
+
+    fn filter() -> RxFilter {
+        let filter = RxFilter::new(Some(MacAddr::from_bytes_unchecked(&MAC)));
+        filter.set_promisc(false);
+        filter
+    }
+
+    #[test]
+    fn test_rx_filter_unicast() {
+        let filter = filter();
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_mac_tables(vec![OTHER_MAC], Vec::new());
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_mac_tables(Vec::new(), Vec::new());
+        filter.set_mac(MacAddr::from_bytes_unchecked(&OTHER_MAC));
+        assert!(!filter.accepts(&frame(MAC, None)));
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_mac_tables(vec![MAC; MAC_TABLE_ENTRIES + 1], Vec::new());
+        assert!(filter.accepts(&frame([0x02, 0, 0, 0, 0, 1], None)));
+
+        filter.reset();
+        filter.set_promisc(false);
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+    }
+
+    #[test]
+    fn test_rx_filter_multicast() {
+        let filter = filter();
+        assert!(filter.accepts(&frame([0xff; MAC_ADDR_LEN], None)));
+        assert!(!filter.accepts(&frame(MULTICAST_MAC, None)));
+
+        filter.set_mac_tables(Vec::new(), vec![MULTICAST_MAC]);
+        assert!(filter.accepts(&frame(MULTICAST_MAC, None)));
+
+        filter.set_mac_tables(Vec::new(), Vec::new());
+        filter.set_allmulti(true);
+        assert!(filter.accepts(&frame(MULTICAST_MAC, None)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_promisc(true);
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+    }
+
+    #[test]
+    fn test_rx_filter_vlan() {
+        let filter = filter();
+        assert!(filter.accepts(&frame(MAC, Some(10))));
+
+        filter.set_vlan_filtering(true);
+        assert!(!filter.accepts(&frame(MAC, Some(10))));
+        assert!(filter.accepts(&frame(MAC, None)));
+
+        assert!(filter.add_vlan(10));
+        assert!(filter.accepts(&frame(MAC, Some(10))));
+        assert!(!filter.accepts(&frame(OTHER_MAC, Some(10))));
+
+        assert!(filter.del_vlan(10));
+        assert!(!filter.accepts(&frame(MAC, Some(10))));
+        assert!(!filter.add_vlan(MAX_VLAN));
+    }
+}
@@ -369,6 +369,15 @@ impl Tap {
     pub fn get_if_name(&self) -> Vec<u8> {
         self.if_name.clone()
     }
+
+    /// Wraps a socket standing in for a tap interface, which has no name and
+    /// supports no offload.
+    pub(crate) fn from_socket(socket: File) -> Tap {
+        Tap {
+            tap_file: socket,
+            if_name: Vec::new(),
+        }
+    }
 }
 
 impl Read for Tap {
@@ -0,0 +1,188 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal DHCP server leasing its single address to the guest.
+
+use std::net::Ipv4Addr;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+
+const CHADDR_OFFSET: usize = 28;
+const MAGIC_COOKIE_OFFSET: usize = 236;
+const OPTIONS_OFFSET: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Replies are padded to the minimal size of a BOOTP message.
+const MIN_MESSAGE_LEN: usize = 300;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const LEASE_TIME_SECS: u32 = 24 * 60 * 60;
+
+pub struct DhcpServer {
+    pub server: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub lease: Ipv4Addr,
+}
+
+impl DhcpServer {
+    /// Returns the reply to a DHCP `request`, if any is expected. The lease
+    /// is the same whatever the client, as the guest is the only one on the
+    /// network.
+    pub fn reply(&self, request: &[u8]) -> Option<Vec<u8>> {
+        if request.len() < OPTIONS_OFFSET
+            || request[0] != BOOTREQUEST
+            || request[1] != HTYPE_ETHERNET
+            || request[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut message_type = None;
+        let mut requested_ip = None;
+        let mut options = &request[OPTIONS_OFFSET..];
+        while let Some(&code) = options.first() {
+            match code {
+                OPT_END => break,
+                OPT_PAD => options = &options[1..],
+                _ => {
+                    let len = usize::from(*options.get(1)?);
+                    let value = options.get(2..2 + len)?;
+                    match (code, len) {
+                        (OPT_MESSAGE_TYPE, 1) => message_type = Some(value[0]),
+                        (OPT_REQUESTED_IP, 4) => {
+                            requested_ip = Some(Ipv4Addr::from(<[u8; 4]>::try_from(value).unwrap()))
+                        }
+                        _ => {}
+                    }
+                    options = &options[2 + len..];
+                }
+            }
+        }
+
+        // A client renewing its lease gives its address in ciaddr instead.
+        let client_ip = requested_ip
+            .unwrap_or_else(|| Ipv4Addr::from(<[u8; 4]>::try_from(&request[12..16]).unwrap()));
+        let reply_type = match message_type? {
+            DHCPDISCOVER => DHCPOFFER,
+            DHCPREQUEST if client_ip == self.lease => DHCPACK,
+            DHCPREQUEST => DHCPNAK,
+            _ => return None,
+        };
+
+        let mut reply = vec![0u8; OPTIONS_OFFSET];
+        reply[0] = BOOTREPLY;
+        reply[1] = HTYPE_ETHERNET;
+        reply[2] = 6;
+        // Transaction id
+        reply[4..8].copy_from_slice(&request[4..8]);
+        // Flags
+        reply[10..12].copy_from_slice(&request[10..12]);
+        if reply_type != DHCPNAK {
+            reply[16..20].copy_from_slice(&self.lease.octets());
+            reply[20..24].copy_from_slice(&self.server.octets());
+        }
+        reply[CHADDR_OFFSET..CHADDR_OFFSET + 16]
+            .copy_from_slice(&request[CHADDR_OFFSET..CHADDR_OFFSET + 16]);
+        reply[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+        reply.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, reply_type]);
+        reply.extend_from_slice(&[OPT_SERVER_ID, 4]);
+        reply.extend_from_slice(&self.server.octets());
+        if reply_type != DHCPNAK {
+            reply.extend_from_slice(&[OPT_LEASE_TIME, 4]);
+            reply.extend_from_slice(&LEASE_TIME_SECS.to_be_bytes());
+            reply.extend_from_slice(&[OPT_SUBNET_MASK, 4]);
+            reply.extend_from_slice(&self.netmask.octets());
+            reply.extend_from_slice(&[OPT_ROUTER, 4]);
+            reply.extend_from_slice(&self.server.octets());
+            reply.extend_from_slice(&[OPT_DNS, 4]);
+            reply.extend_from_slice(&self.server.octets());
+        }
+        reply.push(OPT_END);
+        if reply.len() < MIN_MESSAGE_LEN {
+            reply.resize(MIN_MESSAGE_LEN, OPT_PAD);
+        }
+
+        Some(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(message_type: u8, requested_ip: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut request = vec![0u8; OPTIONS_OFFSET];
+        request[0] = BOOTREQUEST;
+        request[1] = HTYPE_ETHERNET;
+        request[2] = 6;
+        request[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        request[CHADDR_OFFSET..CHADDR_OFFSET + 6].copy_from_slice(&[0x12, 0x34, 0x56, 0, 0, 1]);
+        request[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+        request.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, message_type]);
+        if let Some(ip) = requested_ip {
+            request.extend_from_slice(&[OPT_REQUESTED_IP, 4]);
+            request.extend_from_slice(&ip.octets());
+        }
+        request.push(OPT_END);
+        request
+    }
+
+    fn message_type(reply: &[u8]) -> u8 {
+        assert_eq!(reply[OPTIONS_OFFSET], OPT_MESSAGE_TYPE);
+        reply[OPTIONS_OFFSET + 2]
+    }
+
+    #[test]
+    fn test_dhcp_lease() {
+        let server = DhcpServer {
+            server: Ipv4Addr::new(192, 168, 249, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            lease: Ipv4Addr::new(192, 168, 249, 2),
+        };
+
+        let offer = server.reply(&request(DHCPDISCOVER, None)).unwrap();
+        assert_eq!(offer[0], BOOTREPLY);
+        assert_eq!(offer[4..8], [1, 2, 3, 4]);
+        assert_eq!(offer[16..20], [192, 168, 249, 2]);
+        assert_eq!(message_type(&offer), DHCPOFFER);
+        assert_eq!(
+            offer[CHADDR_OFFSET..CHADDR_OFFSET + 6],
+            [0x12, 0x34, 0x56, 0, 0, 1]
+        );
+
+        let ack = server
+            .reply(&request(DHCPREQUEST, Some(server.lease)))
+            .unwrap();
+        assert_eq!(message_type(&ack), DHCPACK);
+
+        let nak = server
+            .reply(&request(DHCPREQUEST, Some(Ipv4Addr::new(10, 0, 2, 15))))
+            .unwrap();
+        assert_eq!(message_type(&nak), DHCPNAK);
+        assert_eq!(nak[16..20], [0, 0, 0, 0]);
+
+        // Releases need no reply.
+        assert!(server.reply(&request(7, None)).is_none());
+    }
+}
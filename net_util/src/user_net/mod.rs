@@ -0,0 +1,1722 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! # User-mode networking
+//!
+//! Network backend of a virtio-net device implemented in userspace, which
+//! needs neither a tap interface nor privileges. The guest is alone on a
+//! network behind a gateway, its traffic being relayed through sockets of the
+//! host:
+//!
+//! - TCP connections are terminated by the stack, and opened again from the
+//!   host,
+//! - UDP datagrams and ICMP echo requests are sent from host sockets, which
+//!   receive the replies,
+//! - DHCP requests are answered by the gateway, leasing the address following
+//!   its own to the guest,
+//! - DNS queries sent to the gateway are forwarded to the first name server
+//!   of the host,
+//! - any other traffic sent to the gateway reaches the loopback interface of
+//!   the host.
+//!
+//! Ports of the host can also be forwarded to the guest.
+//!
+//! The stack is connected to the queues of the device by a socket pair, one
+//! end of which stands in for the tap interface. It is driven by the epoll
+//! thread of the queues, through its own epoll file descriptor.
+
+mod dhcp;
+mod packet;
+mod tcp;
+
+use self::dhcp::{DhcpServer, DHCP_CLIENT_PORT, DHCP_SERVER_PORT};
+use self::packet::*;
+use self::tcp::TcpConnection;
+use crate::{vnet_hdr_len, MacAddr, Tap};
+use serde::de::{Deserialize, Deserializer, Error as SerdeError};
+use serde::ser::{Serialize, Serializer};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use vmm_sys_util::timerfd::TimerFd;
+
+// MAC address of the gateway
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const DNS_PORT: u16 = 53;
+// Ports the gateway connects from, for the forwarded ports
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+const GUEST_TOKEN: u64 = 0;
+const TIMER_TOKEN: u64 = 1;
+const FIRST_ENDPOINT_TOKEN: u64 = 2;
+
+const EPOLL_EVENTS_LEN: usize = 64;
+// Frames read from the guest in one go, to let other events in
+const MAX_GUEST_FRAMES: usize = 64;
+const MAX_FRAME_LEN: usize = 65550;
+// Frames waiting for the guest to make room in its queue, beyond which
+// frames are dropped
+const MAX_PENDING_FRAMES: usize = 1024;
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+// UDP flows and ICMP echo flows without any traffic for that long are
+// forgotten.
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+// Flows the guest can have at once. Once reached, new TCP connections are
+// reset, while new UDP and ICMP echo flows replace the ones idle the longest.
+const MAX_FLOWS: usize = 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The network has no room for the address of the guest.
+    NoGuestAddress,
+    /// Failed to create the socket pair with the virtio-net device.
+    CreateSocketPair(io::Error),
+    /// Failed to create the epoll file descriptor.
+    CreateEpoll(io::Error),
+    /// Failed to create the timer.
+    CreateTimer(io::Error),
+    /// Failed to listen on a forwarded port.
+    ListenForward(HostForward, io::Error),
+    /// Failed to register a file descriptor with epoll.
+    RegisterListener(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Port of the host forwarded to the guest, following the syntax of QEMU:
+/// `[tcp|udp]:[host_addr]:host_port-[guest_addr]:guest_port`.
+///
+/// The port listens on all the addresses of the host unless one is given,
+/// and is forwarded to the address leased to the guest unless another is
+/// given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostForward {
+    pub protocol: HostForwardProtocol,
+    pub host_addr: Option<Ipv4Addr>,
+    pub host_port: u16,
+    pub guest_addr: Option<Ipv4Addr>,
+    pub guest_port: u16,
+}
+
+#[derive(Debug)]
+pub enum HostForwardParseError {
+    InvalidValue(String),
+}
+
+impl fmt::Display for HostForwardParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostForwardParseError::InvalidValue(s) => write!(f, "invalid host forward: {}", s),
+        }
+    }
+}
+
+fn parse_optional_addr(s: &str) -> std::result::Result<Option<Ipv4Addr>, ()> {
+    if s.is_empty() {
+        Ok(None)
+    } else {
+        s.parse().map(Some).map_err(|_| ())
+    }
+}
+
+impl FromStr for HostForward {
+    type Err = HostForwardParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse = || -> std::result::Result<HostForward, ()> {
+            let (host, guest) = s.split_once('-').ok_or(())?;
+            let mut host = host.splitn(3, ':');
+            let protocol = match host.next().ok_or(())? {
+                "" | "tcp" => HostForwardProtocol::Tcp,
+                "udp" => HostForwardProtocol::Udp,
+                _ => return Err(()),
+            };
+            let host_addr = parse_optional_addr(host.next().ok_or(())?)?;
+            let host_port = host.next().ok_or(())?.parse().map_err(|_| ())?;
+            let (guest_addr, guest_port) = guest.rsplit_once(':').ok_or(())?;
+
+            Ok(HostForward {
+                protocol,
+                host_addr,
+                host_port,
+                guest_addr: parse_optional_addr(guest_addr)?,
+                guest_port: guest_port.parse().map_err(|_| ())?,
+            })
+        };
+
+        parse().map_err(|_| HostForwardParseError::InvalidValue(s.to_owned()))
+    }
+}
+
+impl fmt::Display for HostForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            HostForwardProtocol::Tcp => "tcp",
+            HostForwardProtocol::Udp => "udp",
+        };
+        let addr = |addr: Option<Ipv4Addr>| addr.map(|a| a.to_string()).unwrap_or_default();
+        write!(
+            f,
+            "{}:{}:{}-{}:{}",
+            protocol,
+            addr(self.host_addr),
+            self.host_port,
+            addr(self.guest_addr),
+            self.guest_port
+        )
+    }
+}
+
+impl Serialize for HostForward {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HostForward {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<HostForward, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e| D::Error::custom(format!("{}", e)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserNetConfig {
+    /// Address of the gateway, through which the guest reaches the host.
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub host_forwards: Vec<HostForward>,
+    /// Whether the guest reaches the loopback interface of the host through
+    /// the gateway.
+    pub host_loopback: bool,
+}
+
+impl UserNetConfig {
+    /// Address leased to the guest, which follows the address of the gateway
+    /// on the network.
+    pub fn guest_addr(&self) -> Option<Ipv4Addr> {
+        let mask = u32::from(self.netmask);
+        let guest = u32::from(self.gateway).checked_add(1)?;
+        let network = u32::from(self.gateway) & mask;
+        // The guest address must not be the broadcast address.
+        if guest & mask != network || guest | mask == u32::MAX {
+            return None;
+        }
+
+        Some(Ipv4Addr::from(guest))
+    }
+}
+
+// Identifies a flow by the endpoints of the guest and of its peer, as seen by
+// the guest. ICMP echo flows are identified by their identifier instead of
+// the port of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FlowKey {
+    protocol: u8,
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+}
+
+enum Endpoint {
+    TcpForward {
+        listener: TcpListener,
+        forward: HostForward,
+    },
+    UdpForward {
+        socket: UdpSocket,
+        forward: HostForward,
+        // Ports of the gateway assigned to the peers of the host
+        peers: HashMap<SocketAddr, u16>,
+    },
+    Tcp {
+        key: FlowKey,
+        connection: TcpConnection,
+        // Events the socket is registered for, if any
+        interest: epoll::Events,
+    },
+    Udp {
+        key: FlowKey,
+        socket: UdpSocket,
+        // The forwarded port and the peer of the host, for replies of the
+        // guest to a forwarded port
+        forward: Option<(u64, SocketAddr)>,
+        last_used: Instant,
+    },
+    Icmp {
+        key: FlowKey,
+        socket: UdpSocket,
+        last_used: Instant,
+    },
+}
+
+pub struct UserNet {
+    gateway: Ipv4Addr,
+    netmask: Ipv4Addr,
+    host_loopback: bool,
+    guest_addr: Ipv4Addr,
+    guest_mac: MacAddr,
+    default_guest_mac: MacAddr,
+    nameserver: Option<Ipv4Addr>,
+    dhcp: DhcpServer,
+    epoll_file: File,
+    // Our end of the socket pair with the device
+    socket: File,
+    socket_interest: epoll::Events,
+    timer: TimerFd,
+    timer_armed: bool,
+    to_guest: VecDeque<Vec<u8>>,
+    endpoints: HashMap<u64, Endpoint>,
+    flows: HashMap<FlowKey, u64>,
+    max_flows: usize,
+    next_token: u64,
+    next_port: u16,
+    next_iss: u32,
+}
+
+// Returns the first IPv4 name server of the host.
+fn host_nameserver() -> Option<Ipv4Addr> {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(addr)) => addr.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+fn epoll_ctl(
+    epoll_fd: RawFd,
+    op: epoll::ControlOptions,
+    fd: RawFd,
+    events: epoll::Events,
+    token: u64,
+) -> io::Result<()> {
+    epoll::ctl(epoll_fd, op, fd, epoll::Event::new(events, token))
+}
+
+// Connects a non-blocking TCP socket to `addr`, the connection completing
+// once the socket is writable.
+fn connect_stream(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // SAFETY: FFI call with valid arguments, whose result is checked.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just created, and is owned by nobody
+    // else.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: the socket address is valid, and of the given size.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+
+    Ok(stream)
+}
+
+fn connect_udp(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_nonblocking(true)?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+// Opens an ICMP socket sending echo requests to `addr`, which doesn't need
+// any privilege when allowed by the net.ipv4.ping_group_range sysctl.
+fn connect_ping(addr: Ipv4Addr) -> io::Result<UdpSocket> {
+    // SAFETY: FFI call with valid arguments, whose result is checked.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            libc::IPPROTO_ICMP,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just created, and is owned by nobody
+    // else.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    socket.connect(SocketAddrV4::new(addr, 0))?;
+    Ok(socket)
+}
+
+impl UserNet {
+    /// Creates the stack for the guest using `guest_mac`, along with the tap
+    /// to hand over to the queues of the device.
+    pub fn new(config: &UserNetConfig, guest_mac: MacAddr) -> Result<(Self, Tap)> {
+        let guest_addr = config.guest_addr().ok_or(Error::NoGuestAddress)?;
+
+        let mut fds = [0; 2];
+        // SAFETY: FFI call with valid arguments, whose result is checked.
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::CreateSocketPair(io::Error::last_os_error()));
+        }
+        // SAFETY: the file descriptors were just created, and are owned by
+        // nobody else.
+        let (socket, tap) = unsafe {
+            (
+                File::from_raw_fd(fds[0]),
+                Tap::from_socket(File::from_raw_fd(fds[1])),
+            )
+        };
+
+        let epoll_fd = epoll::create(true).map_err(Error::CreateEpoll)?;
+        // SAFETY: the file descriptor was just created, and is owned by
+        // nobody else.
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+
+        let timer = TimerFd::new().map_err(|e| Error::CreateTimer(e.into()))?;
+        // SAFETY: FFI calls on a valid file descriptor, whose result is
+        // checked. The timer must not block when read.
+        let ret = unsafe {
+            let flags = libc::fcntl(timer.as_raw_fd(), libc::F_GETFL);
+            libc::fcntl(timer.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK)
+        };
+        if ret < 0 {
+            return Err(Error::CreateTimer(io::Error::last_os_error()));
+        }
+
+        let mut user_net = UserNet {
+            gateway: config.gateway,
+            netmask: config.netmask,
+            host_loopback: config.host_loopback,
+            guest_addr,
+            guest_mac,
+            default_guest_mac: guest_mac,
+            nameserver: host_nameserver(),
+            dhcp: DhcpServer {
+                server: config.gateway,
+                netmask: config.netmask,
+                lease: guest_addr,
+            },
+            epoll_file,
+            socket,
+            socket_interest: epoll::Events::EPOLLIN,
+            timer,
+            timer_armed: false,
+            to_guest: VecDeque::new(),
+            endpoints: HashMap::new(),
+            flows: HashMap::new(),
+            max_flows: MAX_FLOWS,
+            next_token: FIRST_ENDPOINT_TOKEN,
+            next_port: *EPHEMERAL_PORTS.start(),
+            next_iss: 0,
+        };
+        let mut iss = [0u8; 4];
+        if getrandom::getrandom(&mut iss).is_ok() {
+            user_net.next_iss = u32::from_ne_bytes(iss);
+        }
+
+        user_net
+            .register(
+                user_net.socket.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                GUEST_TOKEN,
+            )
+            .map_err(Error::RegisterListener)?;
+        user_net
+            .register(
+                user_net.timer.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                TIMER_TOKEN,
+            )
+            .map_err(Error::RegisterListener)?;
+
+        for forward in config.host_forwards.iter() {
+            let host_addr = SocketAddrV4::new(
+                forward.host_addr.unwrap_or(Ipv4Addr::UNSPECIFIED),
+                forward.host_port,
+            );
+            let (fd, endpoint) = match forward.protocol {
+                HostForwardProtocol::Tcp => {
+                    let listener = TcpListener::bind(host_addr)
+                        .and_then(|l| l.set_nonblocking(true).map(|_| l))
+                        .map_err(|e| Error::ListenForward(*forward, e))?;
+                    (
+                        listener.as_raw_fd(),
+                        Endpoint::TcpForward {
+                            listener,
+                            forward: *forward,
+                        },
+                    )
+                }
+                HostForwardProtocol::Udp => {
+                    let socket = UdpSocket::bind(host_addr)
+                        .and_then(|s| s.set_nonblocking(true).map(|_| s))
+                        .map_err(|e| Error::ListenForward(*forward, e))?;
+                    (
+                        socket.as_raw_fd(),
+                        Endpoint::UdpForward {
+                            socket,
+                            forward: *forward,
+                            peers: HashMap::new(),
+                        },
+                    )
+                }
+            };
+            let token = user_net.add_endpoint(endpoint);
+            user_net
+                .register(fd, epoll::Events::EPOLLIN, token)
+                .map_err(Error::RegisterListener)?;
+        }
+
+        Ok((user_net, tap))
+    }
+
+    /// Drops the traffic and the flows of the guest, keeping the forwarded
+    /// ports listening, such as when the device is reset.
+    pub fn reset(&mut self) {
+        self.endpoints.retain(|_, endpoint| {
+            matches!(
+                endpoint,
+                Endpoint::TcpForward { .. } | Endpoint::UdpForward { .. }
+            )
+        });
+        for endpoint in self.endpoints.values_mut() {
+            if let Endpoint::UdpForward { peers, .. } = endpoint {
+                peers.clear();
+            }
+        }
+        self.flows.clear();
+        self.to_guest.clear();
+        self.guest_mac = self.default_guest_mac;
+    }
+
+    /// Processes the pending events of the stack, which are notified through
+    /// its epoll file descriptor.
+    pub fn process(&mut self) -> io::Result<()> {
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+        let count = loop {
+            match epoll::wait(self.epoll_file.as_raw_fd(), 0, &mut events[..]) {
+                Ok(count) => break count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+
+        for event in events.iter().take(count) {
+            let event_set = epoll::Events::from_bits_truncate(event.events);
+            match event.data {
+                GUEST_TOKEN => {
+                    if event_set.intersects(epoll::Events::EPOLLIN) {
+                        self.guest_frames()?;
+                    }
+                }
+                TIMER_TOKEN => self.timer_expired()?,
+                token => self.host_event(token, event_set),
+            }
+        }
+
+        self.flush_to_guest()?;
+        self.update_timer()
+    }
+
+    fn register(&self, fd: RawFd, events: epoll::Events, token: u64) -> io::Result<()> {
+        epoll_ctl(
+            self.epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            events,
+            token,
+        )
+    }
+
+    fn add_endpoint(&mut self, endpoint: Endpoint) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        match &endpoint {
+            Endpoint::Tcp { key, .. } | Endpoint::Udp { key, .. } | Endpoint::Icmp { key, .. } => {
+                self.flows.insert(*key, token);
+            }
+            _ => {}
+        }
+        self.endpoints.insert(token, endpoint);
+        token
+    }
+
+    // Dropping the socket of the endpoint removes it from the epoll set.
+    fn remove_endpoint(&mut self, token: u64) {
+        match self.endpoints.remove(&token) {
+            Some(Endpoint::Tcp { key, .. }) | Some(Endpoint::Icmp { key, .. }) => {
+                self.flows.remove(&key);
+            }
+            Some(Endpoint::Udp { key, forward, .. }) => {
+                self.flows.remove(&key);
+                if let Some((forward_token, peer)) = forward {
+                    if let Some(Endpoint::UdpForward { peers, .. }) =
+                        self.endpoints.get_mut(&forward_token)
+                    {
+                        peers.remove(&peer);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Registers the socket of a TCP connection for the events it expects.
+    fn update_tcp_interest(&mut self, token: u64) -> io::Result<()> {
+        let epoll_fd = self.epoll_file.as_raw_fd();
+        if let Some(Endpoint::Tcp {
+            connection,
+            interest,
+            ..
+        }) = self.endpoints.get_mut(&token)
+        {
+            let events = connection.interest();
+            if events == *interest {
+                return Ok(());
+            }
+            // An idle socket is removed from the epoll set, not to be
+            // reported as hung up over and over.
+            let op = if interest.is_empty() {
+                epoll::ControlOptions::EPOLL_CTL_ADD
+            } else if events.is_empty() {
+                epoll::ControlOptions::EPOLL_CTL_DEL
+            } else {
+                epoll::ControlOptions::EPOLL_CTL_MOD
+            };
+            epoll_ctl(epoll_fd, op, connection.stream.as_raw_fd(), events, token)?;
+            *interest = events;
+        }
+
+        Ok(())
+    }
+
+    // Makes room for a new UDP or ICMP echo flow once the limit is reached,
+    // removing the one idle the longest. Returns false if there is none.
+    fn evict_idle_flow(&mut self) -> bool {
+        if self.flows.len() < self.max_flows {
+            return true;
+        }
+
+        let oldest = self
+            .endpoints
+            .iter()
+            .filter_map(|(token, endpoint)| match endpoint {
+                Endpoint::Udp { last_used, .. } | Endpoint::Icmp { last_used, .. } => {
+                    Some((*token, *last_used))
+                }
+                _ => None,
+            })
+            .min_by_key(|(_, last_used)| *last_used);
+        match oldest {
+            Some((token, _)) => {
+                debug!("Too many flows, dropping the one idle the longest");
+                self.remove_endpoint(token);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn allocate_port(&mut self, protocol: u8, guest: SocketAddrV4) -> u16 {
+        loop {
+            let port = self.next_port;
+            self.next_port = if port == *EPHEMERAL_PORTS.end() {
+                *EPHEMERAL_PORTS.start()
+            } else {
+                port + 1
+            };
+            let key = FlowKey {
+                protocol,
+                guest,
+                remote: SocketAddrV4::new(self.gateway, port),
+            };
+            if !self.flows.contains_key(&key) {
+                return port;
+            }
+        }
+    }
+
+    fn next_iss(&mut self) -> u32 {
+        self.next_iss = self.next_iss.wrapping_add(64000);
+        self.next_iss
+    }
+
+    // Address of the host to relay the traffic sent by the guest to
+    // `remote`, if any.
+    fn host_addr(&self, remote: SocketAddrV4) -> Option<SocketAddrV4> {
+        let ip = *remote.ip();
+        if ip == self.gateway {
+            return match (remote.port(), self.nameserver) {
+                (DNS_PORT, Some(nameserver)) => Some(SocketAddrV4::new(nameserver, DNS_PORT)),
+                (port, _) if self.host_loopback => {
+                    Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+                }
+                _ => None,
+            };
+        }
+
+        // Nobody else lives on the network of the guest.
+        let mask = u32::from(self.netmask);
+        if u32::from(ip) & mask == u32::from(self.gateway) & mask
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_unspecified()
+        {
+            return None;
+        }
+
+        Some(remote)
+    }
+
+    fn send_to_guest(&mut self, ethertype: u16, payload: &[u8]) {
+        if self.to_guest.len() >= MAX_PENDING_FRAMES {
+            debug!("Dropping frame to the guest, which doesn't receive");
+            return;
+        }
+
+        let mut frame = vec![0u8; vnet_hdr_len()];
+        frame.extend_from_slice(&ethernet_frame(
+            self.guest_mac,
+            MacAddr::from_bytes_unchecked(&GATEWAY_MAC),
+            ethertype,
+            payload,
+        ));
+        self.to_guest.push_back(frame);
+    }
+
+    fn send_packets(&mut self, packets: Vec<Vec<u8>>) {
+        for packet in packets {
+            self.send_to_guest(ETHERTYPE_IPV4, &packet);
+        }
+    }
+
+    fn flush_to_guest(&mut self) -> io::Result<()> {
+        while let Some(frame) = self.to_guest.front() {
+            match self.socket.write(frame) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => warn!("Failed sending frame to the guest: {}", e),
+            }
+            self.to_guest.pop_front();
+        }
+
+        // Wait for room in the socket when frames are left.
+        let events = if self.to_guest.is_empty() {
+            epoll::Events::EPOLLIN
+        } else {
+            epoll::Events::EPOLLIN | epoll::Events::EPOLLOUT
+        };
+        if events != self.socket_interest {
+            epoll_ctl(
+                self.epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_MOD,
+                self.socket.as_raw_fd(),
+                events,
+                GUEST_TOKEN,
+            )?;
+            self.socket_interest = events;
+        }
+
+        Ok(())
+    }
+
+    // Arms the timer only while there are flows to watch.
+    fn update_timer(&mut self) -> io::Result<()> {
+        let needed = !self.flows.is_empty();
+        if needed && !self.timer_armed {
+            self.timer
+                .reset(TIMER_INTERVAL, Some(TIMER_INTERVAL))
+                .map_err(io::Error::from)?;
+        } else if !needed && self.timer_armed {
+            self.timer.clear().map_err(io::Error::from)?;
+        }
+        self.timer_armed = needed;
+
+        Ok(())
+    }
+
+    fn timer_expired(&mut self) -> io::Result<()> {
+        match self.timer.wait() {
+            Ok(_) => {}
+            Err(e) if e.errno() == libc::EAGAIN => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+
+        let now = Instant::now();
+        let mut out = Vec::new();
+        let mut expired = Vec::new();
+        for (token, endpoint) in self.endpoints.iter_mut() {
+            let alive = match endpoint {
+                Endpoint::Tcp { connection, .. } => connection.timer(now, &mut out),
+                Endpoint::Udp { last_used, .. } | Endpoint::Icmp { last_used, .. } => {
+                    now.duration_since(*last_used) < FLOW_TIMEOUT
+                }
+                _ => true,
+            };
+            if !alive {
+                expired.push(*token);
+            }
+        }
+        for token in expired {
+            self.remove_endpoint(token);
+        }
+        self.send_packets(out);
+
+        Ok(())
+    }
+
+    fn guest_frames(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        for _ in 0..MAX_GUEST_FRAMES {
+            match self.socket.read(&mut buf) {
+                Ok(len) => {
+                    if let Some(frame) = buf[..len].get(vnet_hdr_len()..) {
+                        self.guest_frame(frame);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn guest_frame(&mut self, frame: &[u8]) {
+        let frame = match parse_ethernet(frame) {
+            Some(frame) => frame,
+            None => return,
+        };
+        // Only the gateway and the broadcast or multicast groups listen.
+        let dst = frame.dst.get_bytes();
+        if dst != GATEWAY_MAC && dst[0] & 1 == 0 {
+            return;
+        }
+        self.guest_mac = frame.src;
+
+        match frame.ethertype {
+            ETHERTYPE_ARP => {
+                if let Some(request) = parse_arp_request(frame.payload) {
+                    if request.target_ip == self.gateway {
+                        let reply =
+                            arp_reply(&request, MacAddr::from_bytes_unchecked(&GATEWAY_MAC));
+                        self.send_to_guest(ETHERTYPE_ARP, &reply);
+                    }
+                }
+            }
+            ETHERTYPE_IPV4 => {
+                if let Some(packet) = parse_ipv4(frame.payload) {
+                    match packet.protocol {
+                        IPPROTO_TCP => self.guest_tcp(&packet),
+                        IPPROTO_UDP => self.guest_udp(&packet),
+                        IPPROTO_ICMP => self.guest_icmp(&packet),
+                        _ => {}
+                    }
+                }
+            }
+            // IPv6 isn't supported.
+            _ => {}
+        }
+    }
+
+    fn guest_tcp(&mut self, packet: &Ipv4Packet) {
+        let segment = match parse_tcp(packet.payload) {
+            Some(segment) => segment,
+            None => return,
+        };
+        let key = FlowKey {
+            protocol: IPPROTO_TCP,
+            guest: SocketAddrV4::new(packet.src, segment.src_port),
+            remote: SocketAddrV4::new(packet.dst, segment.dst_port),
+        };
+
+        let mut out = Vec::new();
+        if let Some(&token) = self.flows.get(&key) {
+            if let Some(Endpoint::Tcp { connection, .. }) = self.endpoints.get_mut(&token) {
+                if connection.guest_segment(&segment, &mut out) {
+                    if let Err(e) = self.update_tcp_interest(token) {
+                        warn!("Failed listening to connection to {}: {}", key.remote, e);
+                        self.remove_endpoint(token);
+                    }
+                } else {
+                    self.remove_endpoint(token);
+                }
+            }
+        } else if segment.flags & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_SYN
+            && self.flows.len() >= self.max_flows
+        {
+            debug!("Too many flows, refusing connection to {}", key.remote);
+            out.push(reset_packet(&key, &segment));
+        } else if segment.flags & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_SYN {
+            let stream = self
+                .host_addr(key.remote)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::ENETUNREACH))
+                .and_then(connect_stream);
+            match stream {
+                Ok(stream) => {
+                    let iss = self.next_iss();
+                    let connection =
+                        TcpConnection::connect(stream, key.guest, key.remote, &segment, iss);
+                    let token = self.add_endpoint(Endpoint::Tcp {
+                        key,
+                        connection,
+                        interest: epoll::Events::empty(),
+                    });
+                    if let Err(e) = self.update_tcp_interest(token) {
+                        warn!("Failed listening to connection to {}: {}", key.remote, e);
+                        self.remove_endpoint(token);
+                        out.push(reset_packet(&key, &segment));
+                    }
+                }
+                Err(e) => {
+                    debug!("Failed connecting to {}: {}", key.remote, e);
+                    out.push(reset_packet(&key, &segment));
+                }
+            }
+        } else if segment.flags & TCP_RST == 0 {
+            out.push(reset_packet(&key, &segment));
+        }
+        self.send_packets(out);
+    }
+
+    fn guest_udp(&mut self, packet: &Ipv4Packet) {
+        let datagram = match parse_udp(packet.payload) {
+            Some(datagram) => datagram,
+            None => return,
+        };
+
+        if datagram.src_port == DHCP_CLIENT_PORT && datagram.dst_port == DHCP_SERVER_PORT {
+            if let Some(reply) = self.dhcp.reply(datagram.payload) {
+                let packet = udp_packet(
+                    self.gateway,
+                    DHCP_SERVER_PORT,
+                    Ipv4Addr::BROADCAST,
+                    DHCP_CLIENT_PORT,
+                    &reply,
+                );
+                self.send_to_guest(ETHERTYPE_IPV4, &packet);
+            }
+            return;
+        }
+
+        let key = FlowKey {
+            protocol: IPPROTO_UDP,
+            guest: SocketAddrV4::new(packet.src, datagram.src_port),
+            remote: SocketAddrV4::new(packet.dst, datagram.dst_port),
+        };
+        let token = match self.flows.get(&key) {
+            Some(token) => *token,
+            None => {
+                if !self.evict_idle_flow() {
+                    debug!("Too many flows, dropping datagram to {}", key.remote);
+                    return;
+                }
+                let socket = self
+                    .host_addr(key.remote)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ENETUNREACH))
+                    .and_then(connect_udp);
+                let socket = match socket {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        debug!("Failed opening UDP flow to {}: {}", key.remote, e);
+                        return;
+                    }
+                };
+                let fd = socket.as_raw_fd();
+                let token = self.add_endpoint(Endpoint::Udp {
+                    key,
+                    socket,
+                    forward: None,
+                    last_used: Instant::now(),
+                });
+                if let Err(e) = self.register(fd, epoll::Events::EPOLLIN, token) {
+                    warn!("Failed listening to UDP flow to {}: {}", key.remote, e);
+                    self.remove_endpoint(token);
+                    return;
+                }
+                token
+            }
+        };
+
+        if let Some(Endpoint::Udp {
+            socket,
+            forward,
+            last_used,
+            ..
+        }) = self.endpoints.get_mut(&token)
+        {
+            *last_used = Instant::now();
+            let result = match forward {
+                Some((_, peer)) => socket.send_to(datagram.payload, *peer),
+                None => socket.send(datagram.payload),
+            };
+            if let Err(e) = result {
+                debug!("Failed sending UDP datagram to {}: {}", key.remote, e);
+            }
+        }
+    }
+
+    fn guest_icmp(&mut self, packet: &Ipv4Packet) {
+        let echo = match parse_icmp_echo(packet.payload) {
+            Some(echo) if echo.kind == ICMP_ECHO_REQUEST => echo,
+            _ => return,
+        };
+
+        // The gateway answers by itself.
+        if packet.dst == self.gateway {
+            let reply = icmp_echo(ICMP_ECHO_REPLY, echo.id, echo.seq, echo.data);
+            let reply = ipv4_packet(self.gateway, packet.src, IPPROTO_ICMP, &reply);
+            self.send_to_guest(ETHERTYPE_IPV4, &reply);
+            return;
+        }
+
+        let key = FlowKey {
+            protocol: IPPROTO_ICMP,
+            guest: SocketAddrV4::new(packet.src, echo.id),
+            remote: SocketAddrV4::new(packet.dst, 0),
+        };
+        let token = match self.flows.get(&key) {
+            Some(token) => *token,
+            None => {
+                if !self.evict_idle_flow() {
+                    debug!("Too many flows, dropping echo request to {}", packet.dst);
+                    return;
+                }
+                let socket = self
+                    .host_addr(key.remote)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ENETUNREACH))
+                    .and_then(|addr| connect_ping(*addr.ip()));
+                let socket = match socket {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        debug!("Failed opening ICMP flow to {}: {}", packet.dst, e);
+                        return;
+                    }
+                };
+                let fd = socket.as_raw_fd();
+                let token = self.add_endpoint(Endpoint::Icmp {
+                    key,
+                    socket,
+                    last_used: Instant::now(),
+                });
+                if let Err(e) = self.register(fd, epoll::Events::EPOLLIN, token) {
+                    warn!("Failed listening to ICMP flow to {}: {}", packet.dst, e);
+                    self.remove_endpoint(token);
+                    return;
+                }
+                token
+            }
+        };
+
+        if let Some(Endpoint::Icmp {
+            socket, last_used, ..
+        }) = self.endpoints.get_mut(&token)
+        {
+            *last_used = Instant::now();
+            // The kernel picks the identifier of the request.
+            let request = icmp_echo(ICMP_ECHO_REQUEST, 0, echo.seq, echo.data);
+            if let Err(e) = socket.send(&request) {
+                debug!("Failed sending echo request to {}: {}", packet.dst, e);
+            }
+        }
+    }
+
+    fn host_event(&mut self, token: u64, events: epoll::Events) {
+        match self.endpoints.get(&token) {
+            Some(Endpoint::TcpForward { .. }) => self.tcp_forward_ready(token),
+            Some(Endpoint::UdpForward { .. }) => self.udp_forward_ready(token),
+            Some(Endpoint::Tcp { .. }) => self.tcp_ready(token, events),
+            Some(Endpoint::Udp { .. }) => self.udp_ready(token),
+            Some(Endpoint::Icmp { .. }) => self.icmp_ready(token),
+            // Removed while handling an earlier event
+            None => {}
+        }
+    }
+
+    fn tcp_ready(&mut self, token: u64, events: epoll::Events) {
+        let mut out = Vec::new();
+        let alive = match self.endpoints.get_mut(&token) {
+            Some(Endpoint::Tcp { connection, .. }) => connection.host_event(events, &mut out),
+            _ => return,
+        };
+        self.send_packets(out);
+
+        if !alive {
+            self.remove_endpoint(token);
+        } else if let Err(e) = self.update_tcp_interest(token) {
+            warn!("Failed listening to TCP connection: {}", e);
+            self.remove_endpoint(token);
+        }
+    }
+
+    fn tcp_forward_ready(&mut self, token: u64) {
+        let mut streams = Vec::new();
+        let forward = match self.endpoints.get(&token) {
+            Some(Endpoint::TcpForward { listener, forward }) => {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => streams.push(stream),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            warn!("Failed accepting connection on {}: {}", forward, e);
+                            break;
+                        }
+                    }
+                }
+                *forward
+            }
+            _ => return,
+        };
+
+        let guest = SocketAddrV4::new(
+            forward.guest_addr.unwrap_or(self.guest_addr),
+            forward.guest_port,
+        );
+        let mut out = Vec::new();
+        for stream in streams {
+            // Dropping the stream closes the connection.
+            if self.flows.len() >= self.max_flows {
+                debug!("Too many flows, refusing connection on {}", forward);
+                continue;
+            }
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!("Failed accepting connection on {}: {}", forward, e);
+                continue;
+            }
+            let remote = SocketAddrV4::new(self.gateway, self.allocate_port(IPPROTO_TCP, guest));
+            let iss = self.next_iss();
+            let connection = TcpConnection::accept(stream, guest, remote, iss, &mut out);
+            let token = self.add_endpoint(Endpoint::Tcp {
+                key: FlowKey {
+                    protocol: IPPROTO_TCP,
+                    guest,
+                    remote,
+                },
+                connection,
+                interest: epoll::Events::empty(),
+            });
+            if let Err(e) = self.update_tcp_interest(token) {
+                warn!("Failed listening to connection on {}: {}", forward, e);
+                self.remove_endpoint(token);
+            }
+        }
+        self.send_packets(out);
+    }
+
+    // Receives the datagrams waiting on `socket`, with their sender.
+    fn receive_datagrams(socket: &UdpSocket) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut datagrams = Vec::new();
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        for _ in 0..MAX_GUEST_FRAMES {
+            match socket.recv_from(&mut buf) {
+                Ok((len, peer)) => datagrams.push((buf[..len].to_vec(), peer)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Such as the ICMP errors reported on connected sockets
+                Err(e) => debug!("Failed receiving datagram: {}", e),
+            }
+        }
+        datagrams
+    }
+
+    fn udp_ready(&mut self, token: u64) {
+        let (key, datagrams) = match self.endpoints.get_mut(&token) {
+            Some(Endpoint::Udp {
+                key,
+                socket,
+                last_used,
+                ..
+            }) => {
+                *last_used = Instant::now();
+                (*key, Self::receive_datagrams(socket))
+            }
+            _ => return,
+        };
+
+        for (payload, _) in datagrams {
+            let packet = udp_packet(
+                *key.remote.ip(),
+                key.remote.port(),
+                *key.guest.ip(),
+                key.guest.port(),
+                &payload,
+            );
+            self.send_to_guest(ETHERTYPE_IPV4, &packet);
+        }
+    }
+
+    fn udp_forward_ready(&mut self, token: u64) {
+        let (forward, datagrams) = match self.endpoints.get(&token) {
+            Some(Endpoint::UdpForward {
+                socket, forward, ..
+            }) => (*forward, Self::receive_datagrams(socket)),
+            _ => return,
+        };
+
+        let guest = SocketAddrV4::new(
+            forward.guest_addr.unwrap_or(self.guest_addr),
+            forward.guest_port,
+        );
+        for (payload, peer) in datagrams {
+            let port = match self.endpoints.get(&token) {
+                Some(Endpoint::UdpForward { peers, .. }) => peers.get(&peer).copied(),
+                _ => return,
+            };
+            let port = match port {
+                Some(port) => port,
+                None => {
+                    if !self.evict_idle_flow() {
+                        debug!("Too many flows, dropping datagram from {}", peer);
+                        continue;
+                    }
+                    // Replies of the guest go through a clone of the
+                    // forwarded socket, which isn't listened to.
+                    let socket = match self.endpoints.get(&token) {
+                        Some(Endpoint::UdpForward { socket, .. }) => socket.try_clone(),
+                        _ => return,
+                    };
+                    let socket = match socket {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            warn!("Failed forwarding datagram from {}: {}", peer, e);
+                            continue;
+                        }
+                    };
+                    let port = self.allocate_port(IPPROTO_UDP, guest);
+                    self.add_endpoint(Endpoint::Udp {
+                        key: FlowKey {
+                            protocol: IPPROTO_UDP,
+                            guest,
+                            remote: SocketAddrV4::new(self.gateway, port),
+                        },
+                        socket,
+                        forward: Some((token, peer)),
+                        last_used: Instant::now(),
+                    });
+                    if let Some(Endpoint::UdpForward { peers, .. }) = self.endpoints.get_mut(&token)
+                    {
+                        peers.insert(peer, port);
+                    }
+                    port
+                }
+            };
+
+            let key = FlowKey {
+                protocol: IPPROTO_UDP,
+                guest,
+                remote: SocketAddrV4::new(self.gateway, port),
+            };
+            if let Some(flow_token) = self.flows.get(&key) {
+                if let Some(Endpoint::Udp { last_used, .. }) = self.endpoints.get_mut(flow_token) {
+                    *last_used = Instant::now();
+                }
+            }
+            let packet = udp_packet(self.gateway, port, *guest.ip(), guest.port(), &payload);
+            self.send_to_guest(ETHERTYPE_IPV4, &packet);
+        }
+    }
+
+    fn icmp_ready(&mut self, token: u64) {
+        let (key, datagrams) = match self.endpoints.get_mut(&token) {
+            Some(Endpoint::Icmp {
+                key,
+                socket,
+                last_used,
+            }) => {
+                *last_used = Instant::now();
+                (*key, Self::receive_datagrams(socket))
+            }
+            _ => return,
+        };
+
+        for (message, _) in datagrams {
+            if let Some(echo) = parse_icmp_echo(&message) {
+                if echo.kind == ICMP_ECHO_REPLY {
+                    let reply = icmp_echo(ICMP_ECHO_REPLY, key.guest.port(), echo.seq, echo.data);
+                    let packet =
+                        ipv4_packet(*key.remote.ip(), *key.guest.ip(), IPPROTO_ICMP, &reply);
+                    self.send_to_guest(ETHERTYPE_IPV4, &packet);
+                }
+            }
+        }
+    }
+}
+
+impl AsRawFd for UserNet {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll_file.as_raw_fd()
+    }
+}
+
+// Builds the reset answering a TCP `segment` of the guest which doesn't
+// belong to any connection.
+fn reset_packet(key: &FlowKey, segment: &TcpSegment) -> Vec<u8> {
+    let (seq, ack, flags) = if segment.flags & TCP_ACK != 0 {
+        (segment.ack, 0, TCP_RST)
+    } else {
+        let mut len = segment.payload.len() as u32;
+        if segment.flags & TCP_SYN != 0 {
+            len += 1;
+        }
+        if segment.flags & TCP_FIN != 0 {
+            len += 1;
+        }
+        (0, segment.seq.wrapping_add(len), TCP_RST | TCP_ACK)
+    };
+
+    tcp_packet(
+        *key.remote.ip(),
+        key.remote.port(),
+        *key.guest.ip(),
+        key.guest.port(),
+        seq,
+        ack,
+        flags,
+        0,
+        None,
+        &[],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const GUEST_MAC: [u8; 6] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+
+    fn config(host_forwards: Vec<HostForward>) -> UserNetConfig {
+        UserNetConfig {
+            gateway: Ipv4Addr::new(192, 168, 249, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            host_forwards,
+            host_loopback: true,
+        }
+    }
+
+    fn send(tap: &mut Tap, ethertype: u16, dst: [u8; 6], payload: &[u8]) {
+        let mut frame = vec![0u8; vnet_hdr_len()];
+        frame.extend_from_slice(&ethernet_frame(
+            MacAddr::from_bytes_unchecked(&dst),
+            MacAddr::from_bytes_unchecked(&GUEST_MAC),
+            ethertype,
+            payload,
+        ));
+        tap.write_all(&frame).unwrap();
+    }
+
+    // Runs the stack until it sends a frame to the guest, returning its
+    // Ethernet payload.
+    fn receive(user_net: &mut UserNet, tap: &mut Tap) -> Vec<u8> {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        for _ in 0..500 {
+            user_net.process().unwrap();
+            match tap.read(&mut buf) {
+                Ok(len) => {
+                    let frame = parse_ethernet(&buf[vnet_hdr_len()..len]).unwrap();
+                    assert_eq!(frame.dst.get_bytes(), GUEST_MAC);
+                    assert_eq!(frame.src.get_bytes(), GATEWAY_MAC);
+                    return frame.payload.to_vec();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        panic!("No frame sent to the guest");
+    }
+
+    fn receive_tcp(user_net: &mut UserNet, tap: &mut Tap) -> (u16, u32, u32, u8, Vec<u8>) {
+        let packet = receive(user_net, tap);
+        let packet = parse_ipv4(&packet).unwrap();
+        assert_eq!(packet.protocol, IPPROTO_TCP);
+        let segment = parse_tcp(packet.payload).unwrap();
+        (
+            segment.src_port,
+            segment.seq,
+            segment.ack,
+            segment.flags,
+            segment.payload.to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_host_forward_parse() {
+        let forward: HostForward = "tcp::2222-:22".parse().unwrap();
+        assert_eq!(
+            forward,
+            HostForward {
+                protocol: HostForwardProtocol::Tcp,
+                host_addr: None,
+                host_port: 2222,
+                guest_addr: None,
+                guest_port: 22,
+            }
+        );
+        assert_eq!(forward.to_string(), "tcp::2222-:22");
+
+        let forward: HostForward = "udp:127.0.0.1:5353-10.0.2.15:53".parse().unwrap();
+        assert_eq!(forward.protocol, HostForwardProtocol::Udp);
+        assert_eq!(forward.host_addr, Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(forward.guest_addr, Some(Ipv4Addr::new(10, 0, 2, 15)));
+        assert_eq!(forward.to_string(), "udp:127.0.0.1:5353-10.0.2.15:53");
+
+        assert!("sctp::1-:2".parse::<HostForward>().is_err());
+        assert!("tcp::2222".parse::<HostForward>().is_err());
+        assert!("tcp:2222-:22".parse::<HostForward>().is_err());
+        assert!("tcp::2222-:port".parse::<HostForward>().is_err());
+    }
+
+    #[test]
+    fn test_guest_addr() {
+        assert_eq!(
+            config(Vec::new()).guest_addr(),
+            Some(Ipv4Addr::new(192, 168, 249, 2))
+        );
+        let config = UserNetConfig {
+            gateway: Ipv4Addr::new(192, 168, 249, 254),
+            ..config(Vec::new())
+        };
+        assert_eq!(config.guest_addr(), None);
+    }
+
+    #[test]
+    fn test_gateway() {
+        let config = config(Vec::new());
+        let guest = config.guest_addr().unwrap();
+        let (mut user_net, mut tap) =
+            UserNet::new(&config, MacAddr::from_bytes_unchecked(&GUEST_MAC)).unwrap();
+
+        let mut request = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        request.extend_from_slice(&GUEST_MAC);
+        request.extend_from_slice(&guest.octets());
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&config.gateway.octets());
+        send(&mut tap, ETHERTYPE_ARP, [0xff; 6], &request);
+        let reply = receive(&mut user_net, &mut tap);
+        assert_eq!(reply[6..8], [0, 2]);
+        assert_eq!(reply[8..14], GATEWAY_MAC);
+        assert_eq!(reply[14..18], config.gateway.octets());
+
+        let echo = icmp_echo(ICMP_ECHO_REQUEST, 7, 1, b"ping");
+        let packet = ipv4_packet(guest, config.gateway, IPPROTO_ICMP, &echo);
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &packet);
+        let reply = receive(&mut user_net, &mut tap);
+        let reply = parse_ipv4(&reply).unwrap();
+        assert_eq!(reply.src, config.gateway);
+        assert_eq!(reply.dst, guest);
+        let reply = parse_icmp_echo(reply.payload).unwrap();
+        assert_eq!(reply.kind, ICMP_ECHO_REPLY);
+        assert_eq!((reply.id, reply.seq), (7, 1));
+        assert_eq!(reply.data, b"ping");
+    }
+
+    #[test]
+    fn test_udp() {
+        let config = config(Vec::new());
+        let guest = config.guest_addr().unwrap();
+        let (mut user_net, mut tap) =
+            UserNet::new(&config, MacAddr::from_bytes_unchecked(&GUEST_MAC)).unwrap();
+        let host = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = host.local_addr().unwrap().port();
+
+        // The gateway stands for the loopback interface of the host.
+        let packet = udp_packet(guest, 1234, config.gateway, port, b"hello");
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &packet);
+        user_net.process().unwrap();
+        let mut buf = [0u8; 16];
+        let (len, peer) = host.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        host.send_to(b"world", peer).unwrap();
+        let reply = receive(&mut user_net, &mut tap);
+        let reply = parse_ipv4(&reply).unwrap();
+        assert_eq!(reply.src, config.gateway);
+        let reply = parse_udp(reply.payload).unwrap();
+        assert_eq!((reply.src_port, reply.dst_port), (port, 1234));
+        assert_eq!(reply.payload, b"world");
+    }
+
+    #[test]
+    fn test_tcp() {
+        let config = config(Vec::new());
+        let guest = config.guest_addr().unwrap();
+        let (mut user_net, mut tap) =
+            UserNet::new(&config, MacAddr::from_bytes_unchecked(&GUEST_MAC)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let syn = tcp_packet(
+            guest,
+            1234,
+            config.gateway,
+            port,
+            1000,
+            0,
+            TCP_SYN,
+            0xffff,
+            Some(1460),
+            &[],
+        );
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &syn);
+        let (src_port, iss, ack, flags, _) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(src_port, port);
+        assert_eq!(flags, TCP_SYN | TCP_ACK);
+        assert_eq!(ack, 1001);
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let data = tcp_packet(
+            guest,
+            1234,
+            config.gateway,
+            port,
+            1001,
+            iss.wrapping_add(1),
+            TCP_ACK | TCP_PSH,
+            0xffff,
+            None,
+            b"hello",
+        );
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &data);
+        let (_, _, ack, flags, _) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(flags, TCP_ACK);
+        assert_eq!(ack, 1006);
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        stream.write_all(b"world").unwrap();
+        let (_, seq, _, _, payload) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(seq, iss.wrapping_add(1));
+        assert_eq!(payload, b"world");
+
+        // Closing the host side sends a FIN to the guest.
+        drop(stream);
+        let (_, seq, _, flags, _) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(seq, iss.wrapping_add(6));
+        assert_ne!(flags & TCP_FIN, 0);
+    }
+
+    #[test]
+    fn test_host_loopback() {
+        let config = UserNetConfig {
+            host_loopback: false,
+            ..config(Vec::new())
+        };
+        let guest = config.guest_addr().unwrap();
+        let (mut user_net, mut tap) =
+            UserNet::new(&config, MacAddr::from_bytes_unchecked(&GUEST_MAC)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Without host loopback, connections to the gateway are refused.
+        let syn = tcp_packet(
+            guest,
+            1234,
+            config.gateway,
+            port,
+            1000,
+            0,
+            TCP_SYN,
+            0xffff,
+            Some(1460),
+            &[],
+        );
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &syn);
+        let (src_port, _, ack, flags, _) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(src_port, port);
+        assert_eq!(ack, 1001);
+        assert_eq!(flags, TCP_RST | TCP_ACK);
+        assert!(user_net.flows.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_reset() {
+        let config = config(Vec::new());
+        let guest = config.guest_addr().unwrap();
+        let (mut user_net, mut tap) =
+            UserNet::new(&config, MacAddr::from_bytes_unchecked(&GUEST_MAC)).unwrap();
+
+        // Segments of unknown connections are reset.
+        let segment = tcp_packet(
+            guest,
+            1234,
+            Ipv4Addr::new(192, 0, 2, 1),
+            80,
+            1000,
+            2000,
+            TCP_ACK,
+            0xffff,
+            None,
+            &[],
+        );
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &segment);
+        let (src_port, seq, _, flags, _) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(src_port, 80);
+        assert_eq!(seq, 2000);
+        assert_eq!(flags, TCP_RST);
+    }
+
+    #[test]
+    fn test_flow_limit() {
+        let config = config(Vec::new());
+        let guest = config.guest_addr().unwrap();
+        let (mut user_net, mut tap) =
+            UserNet::new(&config, MacAddr::from_bytes_unchecked(&GUEST_MAC)).unwrap();
+        user_net.max_flows = 2;
+        let host = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = host.local_addr().unwrap().port();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_port = listener.local_addr().unwrap().port();
+
+        let udp_flow = |guest_port| FlowKey {
+            protocol: IPPROTO_UDP,
+            guest: SocketAddrV4::new(guest, guest_port),
+            remote: SocketAddrV4::new(config.gateway, port),
+        };
+        for guest_port in [1000, 1001] {
+            let packet = udp_packet(guest, guest_port, config.gateway, port, b"hello");
+            send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &packet);
+            user_net.process().unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(user_net.flows.len(), 2);
+
+        // A new UDP flow replaces the one idle the longest.
+        let packet = udp_packet(guest, 1002, config.gateway, port, b"hello");
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &packet);
+        user_net.process().unwrap();
+        assert_eq!(user_net.flows.len(), 2);
+        assert!(!user_net.flows.contains_key(&udp_flow(1000)));
+        assert!(user_net.flows.contains_key(&udp_flow(1001)));
+        assert!(user_net.flows.contains_key(&udp_flow(1002)));
+
+        // New TCP connections are reset once the limit is reached.
+        let syn = tcp_packet(
+            guest,
+            1234,
+            config.gateway,
+            tcp_port,
+            1000,
+            0,
+            TCP_SYN,
+            0xffff,
+            Some(1460),
+            &[],
+        );
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &syn);
+        let (src_port, _, ack, flags, _) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(src_port, tcp_port);
+        assert_eq!(ack, 1001);
+        assert_eq!(flags, TCP_RST | TCP_ACK);
+        assert_eq!(user_net.flows.len(), 2);
+
+        // TCP connections aren't evicted, leaving no room for UDP flows.
+        user_net.reset();
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &syn);
+        let (_, _, _, flags, _) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(flags, TCP_SYN | TCP_ACK);
+        let syn = tcp_packet(
+            guest,
+            1235,
+            config.gateway,
+            tcp_port,
+            1000,
+            0,
+            TCP_SYN,
+            0xffff,
+            Some(1460),
+            &[],
+        );
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &syn);
+        let (_, _, _, flags, _) = receive_tcp(&mut user_net, &mut tap);
+        assert_eq!(flags, TCP_SYN | TCP_ACK);
+        let packet = udp_packet(guest, 1000, config.gateway, port, b"hello");
+        send(&mut tap, ETHERTYPE_IPV4, GATEWAY_MAC, &packet);
+        user_net.process().unwrap();
+        assert_eq!(user_net.flows.len(), 2);
+        assert!(!user_net.flows.contains_key(&udp_flow(1000)));
+    }
+
+    #[test]
+    fn test_tcp_forward() {
+        let forward = HostForward {
+            protocol: HostForwardProtocol::Tcp,
+            host_addr: Some(Ipv4Addr::LOCALHOST),
+            host_port: 0,
+            guest_addr: None,
+            guest_port: 22,
+        };
+        let config = config(vec![forward]);
+        let guest = config.guest_addr().unwrap();
+        let (mut user_net, mut tap) =
+            UserNet::new(&config, MacAddr::from_bytes_unchecked(&GUEST_MAC)).unwrap();
+        let port = user_net
+            .endpoints
+            .values()
+            .find_map(|endpoint| match endpoint {
+                Endpoint::TcpForward { listener, .. } => Some(listener.local_addr().unwrap()),
+                _ => None,
+            })
+            .unwrap();
+
+        let _stream = TcpStream::connect(port).unwrap();
+        let packet = receive(&mut user_net, &mut tap);
+        let packet = parse_ipv4(&packet).unwrap();
+        assert_eq!(packet.src, config.gateway);
+        assert_eq!(packet.dst, guest);
+        let syn = parse_tcp(packet.payload).unwrap();
+        assert_eq!(syn.dst_port, 22);
+        assert_eq!(syn.flags, TCP_SYN);
+        assert!(EPHEMERAL_PORTS.contains(&syn.src_port));
+    }
+}
@@ -0,0 +1,461 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Parsing and building of the Ethernet, ARP, IPv4, UDP, TCP and ICMP headers
+//! exchanged with the guest.
+
+use crate::MacAddr;
+use std::convert::TryInto;
+use std::net::Ipv4Addr;
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const IPV4_HDR_LEN: usize = 20;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+pub const UDP_HDR_LEN: usize = 8;
+pub const TCP_HDR_LEN: usize = 20;
+pub const ICMP_HDR_LEN: usize = 8;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+const ARP_LEN: usize = 28;
+const ARP_HTYPE_ETHERNET: u16 = 1;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_ipv4(buf: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    )
+}
+
+// Folds the one's complement sum of `data` into `sum`.
+fn sum_words(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn fold_checksum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum of `data`, as used by the IPv4 and ICMP headers.
+pub fn checksum(data: &[u8]) -> u16 {
+    fold_checksum(sum_words(data, 0))
+}
+
+// Checksum of a TCP or UDP `segment`, covering the IPv4 pseudo header.
+fn transport_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut sum = sum_words(&src.octets(), 0);
+    sum = sum_words(&dst.octets(), sum);
+    sum += u32::from(protocol);
+    sum += segment.len() as u32;
+    fold_checksum(sum_words(segment, sum))
+}
+
+pub struct EthernetFrame<'a> {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_ethernet(frame: &[u8]) -> Option<EthernetFrame> {
+    if frame.len() < ETH_HDR_LEN {
+        return None;
+    }
+
+    Some(EthernetFrame {
+        dst: MacAddr::from_bytes(&frame[0..6]).ok()?,
+        src: MacAddr::from_bytes(&frame[6..12]).ok()?,
+        ethertype: read_u16(frame, 12),
+        payload: &frame[ETH_HDR_LEN..],
+    })
+}
+
+/// Builds an Ethernet frame around `payload`.
+pub fn ethernet_frame(dst: MacAddr, src: MacAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETH_HDR_LEN + payload.len());
+    frame.extend_from_slice(dst.get_bytes());
+    frame.extend_from_slice(src.get_bytes());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub struct ArpRequest {
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+/// Parses an ARP request for an IPv4 address, ignoring any other ARP packet.
+pub fn parse_arp_request(packet: &[u8]) -> Option<ArpRequest> {
+    if packet.len() < ARP_LEN
+        || read_u16(packet, 0) != ARP_HTYPE_ETHERNET
+        || read_u16(packet, 2) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+        || read_u16(packet, 6) != ARP_OP_REQUEST
+    {
+        return None;
+    }
+
+    Some(ArpRequest {
+        sender_mac: MacAddr::from_bytes(&packet[8..14]).ok()?,
+        sender_ip: read_ipv4(packet, 14),
+        target_ip: read_ipv4(packet, 24),
+    })
+}
+
+/// Builds the reply to `request`, telling `mac` owns the requested address.
+pub fn arp_reply(request: &ArpRequest, mac: MacAddr) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(ARP_LEN);
+    packet.extend_from_slice(&ARP_HTYPE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&ARP_OP_REPLY.to_be_bytes());
+    packet.extend_from_slice(mac.get_bytes());
+    packet.extend_from_slice(&request.target_ip.octets());
+    packet.extend_from_slice(request.sender_mac.get_bytes());
+    packet.extend_from_slice(&request.sender_ip.octets());
+    packet
+}
+
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+/// Parses an IPv4 packet. Fragments are not supported and ignored.
+pub fn parse_ipv4(packet: &[u8]) -> Option<Ipv4Packet> {
+    if packet.len() < IPV4_HDR_LEN || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = usize::from(packet[0] & 0xf) * 4;
+    // Segmentation offloads may leave the total length unset.
+    let total_len = match usize::from(read_u16(packet, 2)) {
+        0 => packet.len(),
+        len => std::cmp::min(len, packet.len()),
+    };
+    if header_len < IPV4_HDR_LEN || total_len < header_len {
+        return None;
+    }
+    // More fragments or fragment offset
+    if read_u16(packet, 6) & 0x3fff != 0 {
+        return None;
+    }
+
+    Some(Ipv4Packet {
+        src: read_ipv4(packet, 12),
+        dst: read_ipv4(packet, 16),
+        protocol: packet[9],
+        payload: &packet[header_len..total_len],
+    })
+}
+
+/// Builds an IPv4 packet around `payload`, which must not be fragmented.
+pub fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(IPV4_HDR_LEN + payload.len());
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&((IPV4_HDR_LEN + payload.len()) as u16).to_be_bytes());
+    // Identification, and the don't fragment flag
+    packet.extend_from_slice(&[0, 0, 0x40, 0]);
+    packet.extend_from_slice(&[64, protocol, 0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    let sum = checksum(&packet);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_udp(datagram: &[u8]) -> Option<UdpDatagram> {
+    if datagram.len() < UDP_HDR_LEN {
+        return None;
+    }
+    let len = usize::from(read_u16(datagram, 4));
+    if len < UDP_HDR_LEN || len > datagram.len() {
+        return None;
+    }
+
+    Some(UdpDatagram {
+        src_port: read_u16(datagram, 0),
+        dst_port: read_u16(datagram, 2),
+        payload: &datagram[UDP_HDR_LEN..len],
+    })
+}
+
+/// Builds a UDP datagram carrying `payload`, in its IPv4 packet.
+pub fn udp_packet(
+    src: Ipv4Addr,
+    src_port: u16,
+    dst: Ipv4Addr,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let len = UDP_HDR_LEN + payload.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&src_port.to_be_bytes());
+    datagram.extend_from_slice(&dst_port.to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let sum = match transport_checksum(src, dst, IPPROTO_UDP, &datagram) {
+        // A zero checksum means there is none.
+        0 => 0xffff,
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    ipv4_packet(src, dst, IPPROTO_UDP, &datagram)
+}
+
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_tcp(segment: &[u8]) -> Option<TcpSegment> {
+    if segment.len() < TCP_HDR_LEN {
+        return None;
+    }
+    let header_len = usize::from(segment[12] >> 4) * 4;
+    if header_len < TCP_HDR_LEN || header_len > segment.len() {
+        return None;
+    }
+
+    let mut mss = None;
+    let mut options = &segment[TCP_HDR_LEN..header_len];
+    while let Some(&kind) = options.first() {
+        match kind {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => options = &options[1..],
+            _ => {
+                let len = usize::from(*options.get(1)?);
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if kind == TCP_OPT_MSS && len == 4 {
+                    mss = Some(read_u16(options, 2));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+
+    Some(TcpSegment {
+        src_port: read_u16(segment, 0),
+        dst_port: read_u16(segment, 2),
+        seq: read_u32(segment, 4),
+        ack: read_u32(segment, 8),
+        flags: segment[13],
+        window: read_u16(segment, 14),
+        mss,
+        payload: &segment[header_len..],
+    })
+}
+
+/// Builds a TCP segment carrying `payload`, in its IPv4 packet. The maximum
+/// segment size is only announced with a SYN.
+#[allow(clippy::too_many_arguments)]
+pub fn tcp_packet(
+    src: Ipv4Addr,
+    src_port: u16,
+    dst: Ipv4Addr,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &[u8],
+) -> Vec<u8> {
+    let header_len = if mss.is_some() {
+        TCP_HDR_LEN + 4
+    } else {
+        TCP_HDR_LEN
+    };
+    let mut segment = Vec::with_capacity(header_len + payload.len());
+    segment.extend_from_slice(&src_port.to_be_bytes());
+    segment.extend_from_slice(&dst_port.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[(header_len as u8 / 4) << 4, flags]);
+    segment.extend_from_slice(&window.to_be_bytes());
+    // Checksum and urgent pointer
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = mss {
+        segment.extend_from_slice(&[TCP_OPT_MSS, 4]);
+        segment.extend_from_slice(&mss.to_be_bytes());
+    }
+    segment.extend_from_slice(payload);
+    let sum = transport_checksum(src, dst, IPPROTO_TCP, &segment);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+
+    ipv4_packet(src, dst, IPPROTO_TCP, &segment)
+}
+
+pub struct IcmpEcho<'a> {
+    pub kind: u8,
+    pub id: u16,
+    pub seq: u16,
+    pub data: &'a [u8],
+}
+
+/// Parses an ICMP echo request or reply, ignoring other ICMP messages.
+pub fn parse_icmp_echo(message: &[u8]) -> Option<IcmpEcho> {
+    if message.len() < ICMP_HDR_LEN
+        || (message[0] != ICMP_ECHO_REQUEST && message[0] != ICMP_ECHO_REPLY)
+        || message[1] != 0
+    {
+        return None;
+    }
+
+    Some(IcmpEcho {
+        kind: message[0],
+        id: read_u16(message, 4),
+        seq: read_u16(message, 6),
+        data: &message[ICMP_HDR_LEN..],
+    })
+}
+
+/// Builds an ICMP echo message, without its IPv4 header.
+pub fn icmp_echo(kind: u8, id: u16, seq: u16, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(ICMP_HDR_LEN + data.len());
+    message.extend_from_slice(&[kind, 0, 0, 0]);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&seq.to_be_bytes());
+    message.extend_from_slice(data);
+    let sum = checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // Commonly used example of an IPv4 header.
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0xb861);
+
+        // The checksum of a packet including its checksum is zero.
+        let packet = ipv4_packet(
+            Ipv4Addr::new(10, 0, 2, 2),
+            Ipv4Addr::new(10, 0, 2, 3),
+            IPPROTO_UDP,
+            &[1, 2, 3],
+        );
+        assert_eq!(checksum(&packet[..IPV4_HDR_LEN]), 0);
+    }
+
+    #[test]
+    fn test_tcp_roundtrip() {
+        let src = Ipv4Addr::new(192, 168, 249, 2);
+        let dst = Ipv4Addr::new(192, 168, 249, 1);
+        let packet = tcp_packet(
+            src,
+            40000,
+            dst,
+            22,
+            1,
+            2,
+            TCP_SYN | TCP_ACK,
+            1000,
+            Some(1460),
+            b"data",
+        );
+
+        let ip = parse_ipv4(&packet).unwrap();
+        assert_eq!(ip.src, src);
+        assert_eq!(ip.dst, dst);
+        assert_eq!(ip.protocol, IPPROTO_TCP);
+        assert_eq!(transport_checksum(src, dst, IPPROTO_TCP, ip.payload), 0);
+
+        let segment = parse_tcp(ip.payload).unwrap();
+        assert_eq!(segment.src_port, 40000);
+        assert_eq!(segment.dst_port, 22);
+        assert_eq!(segment.seq, 1);
+        assert_eq!(segment.ack, 2);
+        assert_eq!(segment.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(segment.window, 1000);
+        assert_eq!(segment.mss, Some(1460));
+        assert_eq!(segment.payload, b"data");
+    }
+
+    #[test]
+    fn test_udp_roundtrip() {
+        let src = Ipv4Addr::new(192, 168, 249, 2);
+        let dst = Ipv4Addr::new(192, 168, 249, 1);
+        let packet = udp_packet(src, 68, dst, 67, b"payload");
+
+        let ip = parse_ipv4(&packet).unwrap();
+        assert_eq!(transport_checksum(src, dst, IPPROTO_UDP, ip.payload), 0);
+        let datagram = parse_udp(ip.payload).unwrap();
+        assert_eq!(datagram.src_port, 68);
+        assert_eq!(datagram.dst_port, 67);
+        assert_eq!(datagram.payload, b"payload");
+
+        // Fragments are dropped.
+        let mut fragment = packet;
+        fragment[6] |= 0x20;
+        assert!(parse_ipv4(&fragment).is_none());
+    }
+}
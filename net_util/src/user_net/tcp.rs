@@ -0,0 +1,478 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! TCP connections of the guest, terminated by the stack and relayed through
+//! host sockets.
+//!
+//! The link with the guest never loses frames, unless the guest drops them
+//! itself, hence the simple go-back-N retransmission and the lack of
+//! congestion control.
+
+use super::packet::{tcp_packet, TcpSegment, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN};
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::time::{Duration, Instant};
+
+/// Maximum segment size announced to the guest, for a 1500 bytes MTU.
+pub const MSS: u16 = 1460;
+// Maximum segment size assumed when the guest announces none.
+const DEFAULT_MSS: u16 = 536;
+// Data received from the host, waiting to be acknowledged by the guest.
+const SEND_BUF_SIZE: usize = 256 << 10;
+// Data received from the guest, waiting to be written to the host. Window
+// scaling isn't negotiated, which limits the window to 64 KiB.
+const RECV_BUF_SIZE: usize = 0xffff;
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRANSMITS: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // The guest sent a SYN, the host socket is connecting.
+    Connecting,
+    // The host connected to a forwarded port, the SYN was sent to the guest.
+    SynSent,
+    // The host socket is connected, the SYN exchanged with the guest may
+    // still be unacknowledged.
+    Open,
+}
+
+pub struct TcpConnection {
+    pub stream: TcpStream,
+    // Endpoint of the guest
+    guest: SocketAddrV4,
+    // Endpoint of the host, as seen by the guest
+    remote: SocketAddrV4,
+    state: State,
+    iss: u32,
+    // First sequence number sent and not acknowledged by the guest
+    snd_una: u32,
+    snd_nxt: u32,
+    syn_acked: bool,
+    // Bytes from the host, starting at snd_una once the SYN is acknowledged
+    send_buf: VecDeque<u8>,
+    // Number of bytes of send_buf sent to the guest
+    sent: usize,
+    host_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    // Next sequence number expected from the guest
+    rcv_nxt: u32,
+    recv_buf: VecDeque<u8>,
+    guest_fin: bool,
+    host_shutdown: bool,
+    guest_window: u32,
+    mss: u16,
+    last_progress: Instant,
+    retransmits: u32,
+}
+
+impl TcpConnection {
+    fn new(
+        stream: TcpStream,
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        state: State,
+        iss: u32,
+    ) -> Self {
+        TcpConnection {
+            stream,
+            guest,
+            remote,
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            syn_acked: false,
+            send_buf: VecDeque::new(),
+            sent: 0,
+            host_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            rcv_nxt: 0,
+            recv_buf: VecDeque::new(),
+            guest_fin: false,
+            host_shutdown: false,
+            guest_window: 0,
+            mss: DEFAULT_MSS,
+            last_progress: Instant::now(),
+            retransmits: 0,
+        }
+    }
+
+    /// Creates the connection requested by the `syn` of the guest, which is
+    /// answered once the host `stream` is connected.
+    pub fn connect(
+        stream: TcpStream,
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        syn: &TcpSegment,
+        iss: u32,
+    ) -> Self {
+        let mut connection = Self::new(stream, guest, remote, State::Connecting, iss);
+        connection.rcv_nxt = syn.seq.wrapping_add(1);
+        connection.guest_window = u32::from(syn.window);
+        connection.mss = min(syn.mss.unwrap_or(DEFAULT_MSS), MSS);
+        connection
+    }
+
+    /// Creates the connection for the `stream` accepted on a forwarded port,
+    /// sending its SYN to the guest.
+    pub fn accept(
+        stream: TcpStream,
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        iss: u32,
+        out: &mut Vec<Vec<u8>>,
+    ) -> Self {
+        let mut connection = Self::new(stream, guest, remote, State::SynSent, iss);
+        connection.snd_nxt = iss.wrapping_add(1);
+        connection.send_syn(out);
+        connection
+    }
+
+    /// Events of the host socket to listen to.
+    pub fn interest(&self) -> epoll::Events {
+        let mut events = epoll::Events::empty();
+        if self.state == State::Connecting {
+            return epoll::Events::EPOLLOUT;
+        }
+        if !self.host_eof && self.send_buf.len() < SEND_BUF_SIZE {
+            events |= epoll::Events::EPOLLIN;
+        }
+        if !self.recv_buf.is_empty() {
+            events |= epoll::Events::EPOLLOUT;
+        }
+        events
+    }
+
+    /// Whether the connection is over, and can be dropped.
+    pub fn is_closed(&self) -> bool {
+        self.fin_acked && self.guest_fin && self.host_shutdown
+    }
+
+    fn window(&self) -> u16 {
+        (RECV_BUF_SIZE - self.recv_buf.len()) as u16
+    }
+
+    fn send(&self, out: &mut Vec<Vec<u8>>, seq: u32, flags: u8, payload: &[u8]) {
+        let mss = if flags & TCP_SYN != 0 {
+            Some(MSS)
+        } else {
+            None
+        };
+        out.push(tcp_packet(
+            *self.remote.ip(),
+            self.remote.port(),
+            *self.guest.ip(),
+            self.guest.port(),
+            seq,
+            if flags & TCP_ACK != 0 {
+                self.rcv_nxt
+            } else {
+                0
+            },
+            flags,
+            self.window(),
+            mss,
+            payload,
+        ));
+    }
+
+    fn send_syn(&self, out: &mut Vec<Vec<u8>>) {
+        let flags = if self.state == State::SynSent {
+            TCP_SYN
+        } else {
+            TCP_SYN | TCP_ACK
+        };
+        self.send(out, self.iss, flags, &[]);
+    }
+
+    /// Resets the connection on the side of the guest.
+    pub fn send_reset(&self, out: &mut Vec<Vec<u8>>) {
+        self.send(out, self.snd_nxt, TCP_RST | TCP_ACK, &[]);
+    }
+
+    // Sends the data from the host the window of the guest allows, followed
+    // by the FIN once the host closed its side.
+    fn send_pending(&mut self, out: &mut Vec<Vec<u8>>) {
+        if self.state != State::Open || !self.syn_acked {
+            return;
+        }
+
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let room = (self.guest_window as usize).saturating_sub(in_flight);
+            let len = min(
+                min(self.send_buf.len() - self.sent, room),
+                self.mss as usize,
+            );
+            if len == 0 {
+                break;
+            }
+            if in_flight == 0 {
+                self.last_progress = Instant::now();
+            }
+            let payload: Vec<u8> = self
+                .send_buf
+                .range(self.sent..self.sent + len)
+                .copied()
+                .collect();
+            self.send(out, self.snd_nxt, TCP_ACK | TCP_PSH, &payload);
+            self.sent += len;
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+
+        if self.host_eof && !self.fin_sent && self.sent == self.send_buf.len() {
+            if self.snd_nxt == self.snd_una {
+                self.last_progress = Instant::now();
+            }
+            self.send(out, self.snd_nxt, TCP_FIN | TCP_ACK, &[]);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+        }
+    }
+
+    // Writes the data from the guest to the host, returning how much could
+    // be written.
+    fn flush_to_host(&mut self) -> io::Result<usize> {
+        let mut written = 0;
+        while !self.recv_buf.is_empty() {
+            let (data, _) = self.recv_buf.as_slices();
+            match self.stream.write(data) {
+                Ok(count) => {
+                    self.recv_buf.drain(..count);
+                    written += count;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.guest_fin && self.recv_buf.is_empty() && !self.host_shutdown {
+            self.stream.shutdown(Shutdown::Write)?;
+            self.host_shutdown = true;
+        }
+
+        Ok(written)
+    }
+
+    // Handles the completion of the connection to the host.
+    fn host_connected(&mut self, out: &mut Vec<Vec<u8>>) -> bool {
+        match self.stream.take_error() {
+            Ok(None) => {}
+            Ok(Some(e)) | Err(e) => {
+                debug!("Failed connecting to {}: {}", self.remote, e);
+                self.send(out, 0, TCP_RST | TCP_ACK, &[]);
+                return false;
+            }
+        }
+
+        self.state = State::Open;
+        self.snd_nxt = self.iss.wrapping_add(1);
+        self.last_progress = Instant::now();
+        self.send_syn(out);
+        true
+    }
+
+    /// Handles a `segment` from the guest. Returns whether the connection is
+    /// still alive.
+    pub fn guest_segment(&mut self, segment: &TcpSegment, out: &mut Vec<Vec<u8>>) -> bool {
+        if segment.flags & TCP_RST != 0 {
+            return false;
+        }
+
+        match self.state {
+            // The guest retransmitted its SYN while the host connects.
+            State::Connecting => return true,
+            State::SynSent => {
+                if segment.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK
+                    && segment.ack == self.iss.wrapping_add(1)
+                {
+                    self.state = State::Open;
+                    self.syn_acked = true;
+                    self.snd_una = segment.ack;
+                    self.rcv_nxt = segment.seq.wrapping_add(1);
+                    self.guest_window = u32::from(segment.window);
+                    self.mss = min(segment.mss.unwrap_or(DEFAULT_MSS), MSS);
+                    self.retransmits = 0;
+                    self.send(out, self.snd_nxt, TCP_ACK, &[]);
+                    self.send_pending(out);
+                }
+                return true;
+            }
+            State::Open => {}
+        }
+
+        if segment.flags & TCP_SYN != 0 {
+            // Our SYN-ACK was lost.
+            if !self.syn_acked {
+                self.send_syn(out);
+            }
+            return true;
+        }
+        if segment.flags & TCP_ACK == 0 {
+            return true;
+        }
+
+        let acked = segment.ack.wrapping_sub(self.snd_una);
+        if acked > 0 && acked <= self.snd_nxt.wrapping_sub(self.snd_una) {
+            let mut acked = acked as usize;
+            if !self.syn_acked {
+                self.syn_acked = true;
+                acked -= 1;
+            }
+            let count = min(acked, self.sent);
+            self.send_buf.drain(..count);
+            self.sent -= count;
+            if acked > count && self.fin_sent {
+                self.fin_acked = true;
+            }
+            self.snd_una = segment.ack;
+            self.last_progress = Instant::now();
+            self.retransmits = 0;
+        }
+        self.guest_window = u32::from(segment.window);
+
+        let mut ack_needed = false;
+        if !segment.payload.is_empty() {
+            // Only data following what was received is accepted, the guest
+            // retransmits anything out of order.
+            let offset = self.rcv_nxt.wrapping_sub(segment.seq) as usize;
+            if !self.guest_fin && offset < segment.payload.len() {
+                let data = &segment.payload[offset..];
+                let count = min(data.len(), RECV_BUF_SIZE - self.recv_buf.len());
+                self.recv_buf.extend(&data[..count]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(count as u32);
+            }
+            ack_needed = true;
+        }
+        if segment.flags & TCP_FIN != 0 {
+            let fin_seq = segment.seq.wrapping_add(segment.payload.len() as u32);
+            if !self.guest_fin && fin_seq == self.rcv_nxt {
+                self.guest_fin = true;
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            }
+            ack_needed = true;
+        }
+
+        if let Err(e) = self.flush_to_host() {
+            debug!("Failed writing to {}: {}", self.remote, e);
+            self.send_reset(out);
+            return false;
+        }
+        if ack_needed {
+            self.send(out, self.snd_nxt, TCP_ACK, &[]);
+        }
+        self.send_pending(out);
+
+        !self.is_closed()
+    }
+
+    fn host_readable(&mut self, out: &mut Vec<Vec<u8>>) -> bool {
+        let mut buf = vec![0u8; SEND_BUF_SIZE - self.send_buf.len()];
+        while !self.host_eof && !buf.is_empty() {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.host_eof = true,
+                Ok(count) => {
+                    self.send_buf.extend(&buf[..count]);
+                    buf.truncate(buf.len() - count);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("Failed reading from {}: {}", self.remote, e);
+                    self.send_reset(out);
+                    return false;
+                }
+            }
+        }
+        self.send_pending(out);
+
+        !self.is_closed()
+    }
+
+    fn host_writable(&mut self, out: &mut Vec<Vec<u8>>) -> bool {
+        let window = self.window();
+        match self.flush_to_host() {
+            // Let the guest know it can send again.
+            Ok(written) if written > 0 && usize::from(window) < RECV_BUF_SIZE / 2 => {
+                self.send(out, self.snd_nxt, TCP_ACK, &[]);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!("Failed writing to {}: {}", self.remote, e);
+                self.send_reset(out);
+                return false;
+            }
+        }
+
+        !self.is_closed()
+    }
+
+    /// Handles the `events` of the host socket. Returns whether the
+    /// connection is still alive.
+    pub fn host_event(&mut self, events: epoll::Events, out: &mut Vec<Vec<u8>>) -> bool {
+        if self.state == State::Connecting {
+            return self.host_connected(out);
+        }
+
+        if events.contains(epoll::Events::EPOLLERR) {
+            if let Ok(Some(e)) | Err(e) = self.stream.take_error() {
+                debug!("Connection to {} failed: {}", self.remote, e);
+            }
+            self.send_reset(out);
+            return false;
+        }
+        if events.intersects(epoll::Events::EPOLLIN | epoll::Events::EPOLLHUP)
+            && !self.host_readable(out)
+        {
+            return false;
+        }
+        if events.contains(epoll::Events::EPOLLOUT) && !self.host_writable(out) {
+            return false;
+        }
+
+        !self.is_closed()
+    }
+
+    /// Retransmits what the guest didn't acknowledge in time, or probes its
+    /// closed window. Returns whether the connection is still alive.
+    pub fn timer(&mut self, now: Instant, out: &mut Vec<Vec<u8>>) -> bool {
+        let outstanding = self.snd_nxt != self.snd_una
+            || (self.guest_window == 0 && self.sent < self.send_buf.len());
+        if !outstanding || now.duration_since(self.last_progress) < RETRANSMIT_TIMEOUT {
+            return true;
+        }
+
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            debug!("Connection to {} timed out", self.remote);
+            self.send_reset(out);
+            return false;
+        }
+        self.last_progress = now;
+
+        if self.state != State::Open || !self.syn_acked {
+            self.send_syn(out);
+            return true;
+        }
+
+        self.snd_nxt = self.snd_una;
+        self.sent = 0;
+        self.fin_sent = self.fin_acked;
+        if self.guest_window == 0 && !self.send_buf.is_empty() {
+            let probe = [self.send_buf[0]];
+            self.send(out, self.snd_nxt, TCP_ACK, &probe);
+            self.sent = 1;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+        } else {
+            self.send_pending(out);
+        }
+
+        true
+    }
+}
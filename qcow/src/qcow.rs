@@ -8,36 +8,51 @@ extern crate log;
 mod qcow_raw_file;
 mod raw_file;
 mod refcount;
+mod snapshot;
 mod vec_cache;
 
 use crate::qcow_raw_file::QcowRawFile;
 use crate::refcount::RefCount;
+use crate::snapshot::{read_snapshot_table, snapshot_table_size, write_snapshot_table};
 use crate::vec_cache::{CacheMap, Cacheable, VecCache};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use flate2::{Decompress, FlushDecompress};
 use libc::{EINVAL, ENOSPC, ENOTSUP};
 use remain::sorted;
-use std::cmp::{max, min};
+use std::cmp::{max, min, Ordering};
+use std::ffi::OsString;
 use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use vmm_sys_util::{
     file_traits::FileSetLen, file_traits::FileSync, seek_hole::SeekHole, write_zeroes::PunchHole,
     write_zeroes::WriteZeroesAt,
 };
 
 pub use crate::raw_file::RawFile;
+pub use crate::snapshot::QcowSnapshot;
 
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
+    BackingFileIo(io::Error),
+    BackingFileOpen(Box<Error>),
     BackingFilesNotSupported,
-    CompressedBlocksNotSupported,
+    BackingFileTooLong(u32),
+    CannotShrink(u64),
+    CheckingImage(io::Error),
     EvictingCache(io::Error),
     FileTooBig(u64),
     GettingFileSize(io::Error),
     GettingRefcount(refcount::Error),
     InvalidClusterIndex,
     InvalidClusterSize,
+    InvalidHeaderExtension(u64),
     InvalidIndex,
     InvalidL1TableOffset,
     InvalidL1TableSize(u32),
@@ -45,6 +60,7 @@ pub enum Error {
     InvalidOffset(u64),
     InvalidRefcountTableOffset,
     InvalidRefcountTableSize(u64),
+    MaxNestingDepthExceeded,
     NoFreeClusters,
     NoRefcountClusters,
     NotEnoughSpaceForRefcounts,
@@ -54,19 +70,27 @@ pub enum Error {
     ReadingPointers(io::Error),
     ReadingRefCountBlock(refcount::Error),
     ReadingRefCounts(io::Error),
+    ReadingSnapshots(io::Error),
     RebuildingRefCounts(io::Error),
     RefcountTableOffEnd,
     RefcountTableTooLarge,
+    RepairIncomplete(CheckResult),
+    ResizingImage(io::Error),
     SeekingFile(io::Error),
     SettingFileSize(io::Error),
-    SettingRefcountRefcount(io::Error),
     SizeTooSmallForNumberOfClusters,
+    SnapshotExists(String),
+    SnapshotNotFound(String),
+    SnapshotSizeMismatch(u64),
     TooManyL1Entries(u64),
     TooManyRefcounts(u64),
+    TooManySnapshots(u32),
+    UnsupportedCompressionType(u8),
     UnsupportedRefcountOrder,
     UnsupportedVersion(u32),
     WritingData(io::Error),
     WritingHeader(io::Error),
+    WritingSnapshots(io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -78,8 +102,12 @@ impl Display for Error {
 
         #[sorted]
         match self {
+            BackingFileIo(e) => write!(f, "backing file io error: {}", e),
+            BackingFileOpen(e) => write!(f, "backing file open error: {}", e),
             BackingFilesNotSupported => write!(f, "backing files not supported"),
-            CompressedBlocksNotSupported => write!(f, "compressed blocks not supported"),
+            BackingFileTooLong(len) => write!(f, "backing file name is too long: {} bytes", len),
+            CannotShrink(size) => write!(f, "can't shrink image to {} bytes", size),
+            CheckingImage(e) => write!(f, "failed to check image: {}", e),
             EvictingCache(e) => write!(f, "failed to evict cache: {}", e),
             FileTooBig(size) => write!(
                 f,
@@ -90,6 +118,9 @@ impl Display for Error {
             GettingRefcount(e) => write!(f, "failed to get refcount: {}", e),
             InvalidClusterIndex => write!(f, "invalid cluster index"),
             InvalidClusterSize => write!(f, "invalid cluster size"),
+            InvalidHeaderExtension(offset) => {
+                write!(f, "invalid header extension at offset {}", offset)
+            }
             InvalidIndex => write!(f, "invalid index"),
             InvalidL1TableOffset => write!(f, "invalid L1 table offset"),
             InvalidL1TableSize(size) => write!(f, "invalid L1 table size {}", size),
@@ -97,6 +128,7 @@ impl Display for Error {
             InvalidOffset(_) => write!(f, "invalid offset"),
             InvalidRefcountTableOffset => write!(f, "invalid refcount table offset"),
             InvalidRefcountTableSize(size) => write!(f, "invalid refcount table size: {}", size),
+            MaxNestingDepthExceeded => write!(f, "backing file chain is too deep"),
             NoFreeClusters => write!(f, "no free clusters"),
             NoRefcountClusters => write!(f, "no refcount clusters"),
             NotEnoughSpaceForRefcounts => write!(f, "not enough space for refcounts"),
@@ -106,19 +138,38 @@ impl Display for Error {
             ReadingPointers(e) => write!(f, "failed to read pointers: {}", e),
             ReadingRefCountBlock(e) => write!(f, "failed to read ref count block: {}", e),
             ReadingRefCounts(e) => write!(f, "failed to read ref counts: {}", e),
+            ReadingSnapshots(e) => write!(f, "failed to read snapshot table: {}", e),
             RebuildingRefCounts(e) => write!(f, "failed to rebuild ref counts: {}", e),
             RefcountTableOffEnd => write!(f, "refcount table offset past file end"),
             RefcountTableTooLarge => write!(f, "too many clusters specified for refcount table"),
+            RepairIncomplete(r) => write!(
+                f,
+                "image still inconsistent after repair: {} leaked clusters, {} corruptions, {} \
+                 dangling pointers",
+                r.leaks, r.corruptions, r.dangling_pointers
+            ),
+            ResizingImage(e) => write!(f, "failed to resize image: {}", e),
             SeekingFile(e) => write!(f, "failed to seek file: {}", e),
             SettingFileSize(e) => write!(f, "failed to set file size: {}", e),
-            SettingRefcountRefcount(e) => write!(f, "failed to set refcount refcount: {}", e),
             SizeTooSmallForNumberOfClusters => write!(f, "size too small for number of clusters"),
+            SnapshotExists(name) => write!(f, "snapshot {} already exists", name),
+            SnapshotNotFound(name) => write!(f, "snapshot {} not found", name),
+            SnapshotSizeMismatch(size) => {
+                write!(
+                    f,
+                    "snapshot disk size {} differs from the current one",
+                    size
+                )
+            }
             TooManyL1Entries(count) => write!(f, "l1 entry table too large: {}", count),
             TooManyRefcounts(count) => write!(f, "ref count table too large: {}", count),
+            TooManySnapshots(count) => write!(f, "too many snapshots: {}", count),
+            UnsupportedCompressionType(t) => write!(f, "unsupported compression type: {}", t),
             UnsupportedRefcountOrder => write!(f, "unsupported refcount order"),
             UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
             WritingData(e) => write!(f, "failed to write data: {}", e),
             WritingHeader(e) => write!(f, "failed to write header: {}", e),
+            WritingSnapshots(e) => write!(f, "failed to write snapshots: {}", e),
         }
     }
 }
@@ -146,9 +197,32 @@ const MAX_RAM_POINTER_TABLE_SIZE: u64 = 35_000_000;
 // Only support 2 byte refcounts, 2^refcount_order bits.
 const DEFAULT_REFCOUNT_ORDER: u32 = 4;
 
+// The qcow2 specification limits backing file names to 1023 bytes.
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+// Maximum length of a chain of backing files, this stops images referencing each other from
+// recursing forever.
+const MAX_NESTING_DEPTH: u32 = 10;
+
+// The qcow2 specification limits the number of internal snapshots to 65536.
+const MAX_SNAPSHOTS: u32 = 65536;
+// Offset of the number of snapshots in the header, followed by the offset of the snapshot table.
+const NB_SNAPSHOTS_OFFSET: u64 = 60;
+// Offsets of the virtual size and of the L1 table size in the header, the latter being followed by
+// the offset of the L1 table.
+const SIZE_OFFSET: u64 = 24;
+const L1_SIZE_OFFSET: u64 = 36;
+// Offset of the incompatible features in a v3 header.
+const INCOMPATIBLE_FEATURES_OFFSET: u64 = 72;
+
 const V2_BARE_HEADER_SIZE: u32 = 72;
 const V3_BARE_HEADER_SIZE: u32 = 104;
 
+// Header extensions follow the header, each one made of its type, its length and its data padded
+// to 8 bytes. The list ends with an extension of type 0 and length 0.
+const HEADER_EXTENSION_END: u32 = 0;
+const HEADER_EXTENSION_HEADER_SIZE: u64 = 8;
+const HEADER_EXTENSION_ALIGNMENT: u64 = 8;
+
 // bits 0-8 and 56-63 are reserved.
 const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
@@ -156,6 +230,17 @@ const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1;
+// The refcounts may be inconsistent, the image wasn't closed cleanly.
+const INCOMPATIBLE_FEATURES_DIRTY: u64 = 1;
+// The metadata were found to be corrupted.
+const INCOMPATIBLE_FEATURES_CORRUPT: u64 = 1 << 1;
+
+// Compression methods of compressed clusters, zlib is the only one available before the
+// compression type field was added to the v3 header.
+const COMPRESSION_TYPE_ZLIB: u8 = 0;
+const COMPRESSION_TYPE_ZSTD: u8 = 1;
+// The size of compressed clusters is counted in 512 bytes sectors.
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
 
 /// Contains the information from the header of a qcow file.
 #[derive(Copy, Clone, Debug)]
@@ -185,6 +270,7 @@ pub struct QcowHeader {
     pub autoclear_features: u64,
     pub refcount_order: u32,
     pub header_size: u32,
+    pub compression_type: u8,
 }
 
 impl QcowHeader {
@@ -208,7 +294,7 @@ impl QcowHeader {
 
         let version = read_u32_from_file(f)?;
 
-        Ok(QcowHeader {
+        let mut header = QcowHeader {
             magic,
             version,
             backing_file_offset: read_u64_from_file(f)?,
@@ -247,7 +333,15 @@ impl QcowHeader {
             } else {
                 read_u32_from_file(f)?
             },
-        })
+            compression_type: COMPRESSION_TYPE_ZLIB,
+        };
+
+        // The compression type is only present in headers longer than the bare v3 one.
+        if header.header_size > V3_BARE_HEADER_SIZE {
+            header.compression_type = f.read_u8().map_err(Error::ReadingHeader)?;
+        }
+
+        Ok(header)
     }
 
     /// Create a header for the given `size`.
@@ -298,6 +392,7 @@ impl QcowHeader {
             } else {
                 V3_BARE_HEADER_SIZE
             },
+            compression_type: COMPRESSION_TYPE_ZLIB,
         }
     }
 
@@ -333,6 +428,10 @@ impl QcowHeader {
         write_u64_to_file(file, self.autoclear_features)?;
         write_u32_to_file(file, self.refcount_order)?;
         write_u32_to_file(file, self.header_size)?;
+        if self.header_size > V3_BARE_HEADER_SIZE {
+            file.write_u8(self.compression_type)
+                .map_err(Error::WritingHeader)?;
+        }
 
         // Set the file length by seeking and writing a zero to the last byte. This avoids needing
         // a `File` instead of anything that implements seek as the `file` argument.
@@ -347,6 +446,55 @@ impl QcowHeader {
 
         Ok(())
     }
+
+    /// Reads the header extensions following the header, as pairs of type and data, up to the
+    /// end-of-extensions marker.
+    pub fn read_extensions(&self, f: &mut RawFile) -> Result<Vec<(u32, Vec<u8>)>> {
+        // The extensions stop at the backing file name if any, or else at the end of the first
+        // cluster.
+        let end = if self.backing_file_offset != 0 {
+            self.backing_file_offset
+        } else {
+            0x01u64 << self.cluster_bits
+        };
+
+        let mut extensions = Vec::new();
+        let mut offset = u64::from(self.header_size);
+        loop {
+            if offset + HEADER_EXTENSION_HEADER_SIZE > end {
+                return Err(Error::InvalidHeaderExtension(offset));
+            }
+            f.seek(SeekFrom::Start(offset))
+                .map_err(Error::ReadingHeader)?;
+            let extension_type = f.read_u32::<BigEndian>().map_err(Error::ReadingHeader)?;
+            let len = f.read_u32::<BigEndian>().map_err(Error::ReadingHeader)?;
+            if extension_type == HEADER_EXTENSION_END {
+                return Ok(extensions);
+            }
+
+            let data_offset = offset + HEADER_EXTENSION_HEADER_SIZE;
+            if data_offset + u64::from(len) > end {
+                return Err(Error::InvalidHeaderExtension(offset));
+            }
+            let mut data = vec![0u8; len as usize];
+            f.read_exact(&mut data).map_err(Error::ReadingHeader)?;
+            extensions.push((extension_type, data));
+
+            offset = data_offset
+                + div_round_up_u64(u64::from(len), HEADER_EXTENSION_ALIGNMENT)
+                    * HEADER_EXTENSION_ALIGNMENT;
+        }
+    }
+
+    /// Returns true if the image wasn't closed cleanly, leaving its refcounts inconsistent.
+    pub fn is_dirty(&self) -> bool {
+        self.incompatible_features & INCOMPATIBLE_FEATURES_DIRTY != 0
+    }
+
+    /// Returns true if the image was flagged as having corrupted metadata.
+    pub fn is_corrupt(&self) -> bool {
+        self.incompatible_features & INCOMPATIBLE_FEATURES_CORRUPT != 0
+    }
 }
 
 fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u32) -> u64 {
@@ -360,6 +508,223 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
     for_data + for_refcounts
 }
 
+/// Controls how the backing file referenced from a qcow2 header is resolved.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackingFilePolicy {
+    /// Refuse to open images that reference a backing file.
+    Forbid,
+    /// Open the backing file named in the image header.
+    Header,
+    /// Open the given file in place of the one named in the image header.
+    Override(PathBuf),
+}
+
+impl BackingFilePolicy {
+    // Policy applied further down the chain of backing files. The paths named in the headers of
+    // an overridden backing file are no more trusted than the one which was overridden.
+    fn nested(&self) -> BackingFilePolicy {
+        match self {
+            BackingFilePolicy::Override(_) => BackingFilePolicy::Forbid,
+            policy => policy.clone(),
+        }
+    }
+}
+
+/// Read-only parent of a qcow2 image. Clusters that aren't allocated in the image are read from
+/// it, and copied from it when they are first written.
+#[derive(Clone, Debug)]
+enum BackingFile {
+    Raw { file: RawFile, size: u64 },
+    Qcow(Box<QcowFile>),
+}
+
+impl BackingFile {
+    // Opens the image at `path` read-only, resolving its own backing file according to `policy`.
+    // `depth` is the position of the image in the chain of backing files.
+    fn open(path: &Path, policy: &BackingFilePolicy, depth: u32) -> Result<BackingFile> {
+        if depth > MAX_NESTING_DEPTH {
+            return Err(Error::MaxNestingDepthExceeded);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(Error::BackingFileIo)?;
+        let mut raw_file = RawFile::new(file, false);
+        match detect_image_type(&mut raw_file)? {
+            ImageType::Qcow2 => {
+                let qcow =
+                    QcowFile::from_with_nesting_depth(raw_file, policy, &policy.nested(), depth)
+                        .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+                Ok(BackingFile::Qcow(Box::new(qcow)))
+            }
+            ImageType::Raw => {
+                let size = raw_file.metadata().map_err(Error::GettingFileSize)?.len();
+                Ok(BackingFile::Raw {
+                    file: raw_file,
+                    size,
+                })
+            }
+        }
+    }
+
+    fn virtual_size(&self) -> u64 {
+        match self {
+            BackingFile::Raw { size, .. } => *size,
+            BackingFile::Qcow(qcow) => qcow.virtual_size(),
+        }
+    }
+
+    // Fills `buf` with the content found at `address`. The backing file can be smaller than the
+    // image using it, anything past its end reads as zeros.
+    fn read_at(&mut self, address: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let size = self.virtual_size();
+        let count = if address >= size {
+            0
+        } else {
+            min(buf.len() as u64, size - address) as usize
+        };
+        let (data, zeros) = buf.split_at_mut(count);
+
+        if !data.is_empty() {
+            match self {
+                BackingFile::Raw { file, .. } => {
+                    file.seek(SeekFrom::Start(address))?;
+                    file.read_exact(data)?;
+                }
+                BackingFile::Qcow(qcow) => {
+                    qcow.seek(SeekFrom::Start(address))?;
+                    qcow.read_exact(data)?;
+                }
+            }
+        }
+        for b in zeros {
+            *b = 0;
+        }
+
+        Ok(())
+    }
+
+    // Returns true if any data is stored in the `length` bytes starting at `address`.
+    fn range_allocated(&mut self, address: u64, length: u64) -> std::io::Result<bool> {
+        if address >= self.virtual_size() {
+            return Ok(false);
+        }
+
+        match self {
+            BackingFile::Raw { .. } => Ok(true),
+            BackingFile::Qcow(qcow) => qcow.range_allocated(address, length),
+        }
+    }
+}
+
+/// Inconsistencies found in the metadata of an image by `QcowFile::check()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CheckResult {
+    /// Clusters with a refcount higher than the number of references to them. They waste space
+    /// but are otherwise harmless.
+    pub leaks: u64,
+    /// Clusters with a refcount lower than the number of references to them, which can get them
+    /// reused while still in use, and clusters referenced for conflicting purposes, such as an L2
+    /// table overlapping a refcount block.
+    pub corruptions: u64,
+    /// Table entries pointing past the end of the file or to an unaligned cluster.
+    pub dangling_pointers: u64,
+}
+
+impl CheckResult {
+    /// Returns true if no inconsistency was found.
+    pub fn is_clean(&self) -> bool {
+        *self == CheckResult::default()
+    }
+
+    // Accounts for the outcome of `ClusterRefs::add()`. Returns false if the pointer is invalid.
+    fn record(&mut self, outcome: std::result::Result<(), BadPointer>) -> bool {
+        match outcome {
+            Ok(()) => true,
+            Err(BadPointer::Dangling) => {
+                self.dangling_pointers += 1;
+                false
+            }
+            Err(BadPointer::Overlap) => {
+                self.corruptions += 1;
+                false
+            }
+        }
+    }
+}
+
+// Content of a host cluster, as found while walking the metadata of an image.
+#[derive(Clone, Copy, PartialEq)]
+enum ClusterKind {
+    Unused,
+    Header,
+    RefcountTable,
+    RefcountBlock,
+    SnapshotTable,
+    L1Table,
+    L2Table,
+    Data,
+}
+
+// Reasons for a pointer found in the metadata of an image to be invalid.
+enum BadPointer {
+    // The pointer isn't aligned to a cluster or points past the end of the file.
+    Dangling,
+    // The cluster pointed to already holds another kind of content.
+    Overlap,
+}
+
+// References to the host clusters of an image, collected by `QcowFile::check()`.
+struct ClusterRefs {
+    cluster_size: u64,
+    file_size: u64,
+    kinds: Vec<ClusterKind>,
+    counts: Vec<u64>,
+}
+
+impl ClusterRefs {
+    fn new(cluster_size: u64, file_size: u64) -> Self {
+        let clusters = div_round_up_u64(file_size, cluster_size) as usize;
+        ClusterRefs {
+            cluster_size,
+            file_size,
+            kinds: vec![ClusterKind::Unused; clusters],
+            counts: vec![0; clusters],
+        }
+    }
+
+    // Records a reference to the cluster at `address`, holding content of the given `kind`. L2
+    // tables and data clusters can be shared between snapshots, other clusters can only be
+    // referenced once.
+    fn add(&mut self, address: u64, kind: ClusterKind) -> std::result::Result<(), BadPointer> {
+        if address % self.cluster_size != 0 || address >= self.file_size {
+            return Err(BadPointer::Dangling);
+        }
+        let index = (address / self.cluster_size) as usize;
+        match self.kinds[index] {
+            ClusterKind::Unused => self.kinds[index] = kind,
+            shared @ (ClusterKind::L2Table | ClusterKind::Data) if shared == kind => {}
+            _ => return Err(BadPointer::Overlap),
+        }
+        self.counts[index] += 1;
+        Ok(())
+    }
+
+    // Records a reference to each of the clusters holding the `size` bytes at `address`.
+    fn add_range(
+        &mut self,
+        address: u64,
+        size: u64,
+        kind: ClusterKind,
+    ) -> std::result::Result<(), BadPointer> {
+        for i in 0..div_round_up_u64(size, self.cluster_size) {
+            self.add(address + i * self.cluster_size, kind)?;
+        }
+        Ok(())
+    }
+}
+
 /// Represents a qcow2 file. This is a sparse file format maintained by the qemu project.
 /// Full documentation of the format can be found in the qemu repository.
 ///
@@ -390,12 +755,39 @@ pub struct QcowFile {
     // List of unreferenced clusters available to be used. unref clusters become available once the
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
-    //TODO(dgreid) Add support for backing files. - backing_file: Option<Box<QcowFile<T>>>,
+    backing_file: Option<BackingFile>,
+    backing_file_path: Option<PathBuf>,
+    // The last cluster read from compressed data, with the L2 entry it was decompressed from.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
+    snapshots: Vec<QcowSnapshot>,
 }
 
 impl QcowFile {
     /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
-    pub fn from(mut file: RawFile) -> Result<QcowFile> {
+    ///
+    /// Images referencing a backing file are rejected, use `from_with_backing_file()` to open
+    /// them.
+    pub fn from(file: RawFile) -> Result<QcowFile> {
+        Self::from_with_backing_file(file, &BackingFilePolicy::Forbid)
+    }
+
+    /// Creates a QcowFile from `file`, resolving its backing file according to `policy`.
+    ///
+    /// The backing file is opened read-only and can be either a raw or a qcow2 image, in which
+    /// case its own backing file is opened as well. Going down the chain, `policy` keeps
+    /// applying, except for an overridden backing file which must not have one of its own.
+    pub fn from_with_backing_file(file: RawFile, policy: &BackingFilePolicy) -> Result<QcowFile> {
+        Self::from_with_nesting_depth(file, policy, &policy.nested(), 0)
+    }
+
+    // `policy` resolves the backing file of this image, and `backing_policy` the one of its
+    // backing file.
+    fn from_with_nesting_depth(
+        mut file: RawFile,
+        policy: &BackingFilePolicy,
+        backing_policy: &BackingFilePolicy,
+        depth: u32,
+    ) -> Result<QcowFile> {
         let header = QcowHeader::new(&mut file)?;
 
         // Only v2 and v3 files are supported.
@@ -419,9 +811,26 @@ impl QcowFile {
             return Err(Error::FileTooBig(header.size));
         }
 
-        // No current support for backing files.
-        if header.backing_file_offset != 0 {
-            return Err(Error::BackingFilesNotSupported);
+        let backing_file_path = if header.backing_file_offset != 0 {
+            match policy {
+                BackingFilePolicy::Forbid => return Err(Error::BackingFilesNotSupported),
+                BackingFilePolicy::Header => {
+                    Some(Self::read_backing_file_path(&mut file, &header)?)
+                }
+                BackingFilePolicy::Override(path) => Some(path.clone()),
+            }
+        } else {
+            None
+        };
+        let backing_file = match &backing_file_path {
+            Some(path) => Some(BackingFile::open(path, backing_policy, depth + 1)?),
+            None => None,
+        };
+
+        if header.compression_type != COMPRESSION_TYPE_ZLIB
+            && header.compression_type != COMPRESSION_TYPE_ZSTD
+        {
+            return Err(Error::UnsupportedCompressionType(header.compression_type));
         }
 
         // Only support two byte refcounts.
@@ -437,9 +846,11 @@ impl QcowFile {
         if header.refcount_table_clusters == 0 {
             return Err(Error::NoRefcountClusters);
         }
-        offset_is_cluster_boundary(header.backing_file_offset, header.cluster_bits)?;
         offset_is_cluster_boundary(header.l1_table_offset, header.cluster_bits)?;
         offset_is_cluster_boundary(header.snapshots_offset, header.cluster_bits)?;
+        if header.nb_snapshots > MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots(header.nb_snapshots));
+        }
         // refcount table must be a cluster boundary, and within the file's virtual or actual size.
         offset_is_cluster_boundary(header.refcount_table_offset, header.cluster_bits)?;
         let file_size = file.metadata().map_err(Error::GettingFileSize)?.len();
@@ -516,18 +927,13 @@ impl QcowFile {
 
         let l2_entries = cluster_size / size_of::<u64>() as u64;
 
-        // Check for compressed blocks
-        for l2_addr_disk in l1_table.get_values() {
-            if *l2_addr_disk != 0 {
-                if let Err(e) = Self::read_l2_cluster(&mut raw_file, *l2_addr_disk) {
-                    if let Some(os_error) = e.raw_os_error() {
-                        if os_error == ENOTSUP {
-                            return Err(Error::CompressedBlocksNotSupported);
-                        }
-                    }
-                }
-            }
-        }
+        let snapshots = read_snapshot_table(
+            raw_file.file_mut(),
+            header.snapshots_offset,
+            header.nb_snapshots,
+            header.size,
+        )
+        .map_err(Error::ReadingSnapshots)?;
 
         let mut qcow = QcowFile {
             raw_file,
@@ -539,6 +945,10 @@ impl QcowFile {
             current_offset: 0,
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
+            backing_file,
+            backing_file_path,
+            decompressed_cluster: None,
+            snapshots,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -557,29 +967,62 @@ impl QcowFile {
     }
 
     /// Creates a new QcowFile at the given path.
-    pub fn new(mut file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
+    pub fn new(file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
         let header = QcowHeader::create_for_size(version, virtual_size);
+        Self::new_from_header(file, header, None)
+    }
+
+    /// Creates a new QcowFile at the given path, on top of the image at `backing_file_path`.
+    /// The virtual size of the new image is the size of the backing file.
+    pub fn new_from_backing(
+        file: RawFile,
+        version: u32,
+        backing_file_path: &Path,
+    ) -> Result<QcowFile> {
+        let name_len = backing_file_path.as_os_str().len();
+        if name_len > MAX_BACKING_FILE_SIZE as usize {
+            return Err(Error::BackingFileTooLong(name_len as u32));
+        }
+        let backing_file = BackingFile::open(backing_file_path, &BackingFilePolicy::Header, 1)?;
+
+        let mut header = QcowHeader::create_for_size(version, backing_file.virtual_size());
+        // The backing file name is stored in the first cluster, after the end of the header
+        // extensions.
+        header.backing_file_offset = u64::from(header.header_size) + HEADER_EXTENSION_HEADER_SIZE;
+        header.backing_file_size = name_len as u32;
+        Self::new_from_header(file, header, Some(backing_file_path))
+    }
+
+    fn new_from_header(
+        mut file: RawFile,
+        header: QcowHeader,
+        backing_file_path: Option<&Path>,
+    ) -> Result<QcowFile> {
         file.seek(SeekFrom::Start(0)).map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
 
-        let mut qcow = Self::from(file)?;
-
-        // Set the refcount for each refcount table cluster.
-        let cluster_size = 0x01u64 << qcow.header.cluster_bits;
-        let refcount_table_base = qcow.header.refcount_table_offset as u64;
-        let end_cluster_addr =
-            refcount_table_base + u64::from(qcow.header.refcount_table_clusters) * cluster_size;
+        // The new image has no header extension, only the end-of-extensions marker.
+        file.seek(SeekFrom::Start(u64::from(header.header_size)))
+            .map_err(Error::SeekingFile)?;
+        file.write_u32::<BigEndian>(HEADER_EXTENSION_END)
+            .map_err(Error::WritingHeader)?;
+        file.write_u32::<BigEndian>(0)
+            .map_err(Error::WritingHeader)?;
 
-        let mut cluster_addr = 0;
-        while cluster_addr < end_cluster_addr {
-            let mut unref_clusters = qcow
-                .set_cluster_refcount(cluster_addr, 1)
-                .map_err(Error::SettingRefcountRefcount)?;
-            qcow.unref_clusters.append(&mut unref_clusters);
-            cluster_addr += cluster_size;
-        }
+        let policy = if let Some(path) = backing_file_path {
+            file.seek(SeekFrom::Start(header.backing_file_offset))
+                .map_err(Error::SeekingFile)?;
+            file.write_all(path.as_os_str().as_bytes())
+                .map_err(Error::WritingHeader)?;
+            BackingFilePolicy::Override(path.to_path_buf())
+        } else {
+            BackingFilePolicy::Forbid
+        };
 
-        Ok(qcow)
+        // The refcount table of the new file is empty, opening it rebuilds the refcounts of the
+        // header, L1 table and refcount table clusters. The backing file was picked on purpose,
+        // along with its own chain of backing files.
+        Self::from_with_nesting_depth(file, &policy, &BackingFilePolicy::Header, 0)
     }
 
     /// Returns the `QcowHeader` for this file.
@@ -587,6 +1030,57 @@ impl QcowFile {
         &self.header
     }
 
+    /// Returns the path of the backing file opened along with this file, if any.
+    pub fn backing_file_path(&self) -> Option<&Path> {
+        self.backing_file_path.as_deref()
+    }
+
+    /// Returns the chain of backing files opened along with this file, from the one of this file
+    /// to the last one, along with their path.
+    pub fn backing_files(&self) -> Vec<(&Path, &File)> {
+        let mut files = Vec::new();
+        let mut qcow = self;
+        while let (Some(path), Some(backing_file)) = (&qcow.backing_file_path, &qcow.backing_file) {
+            match backing_file {
+                BackingFile::Raw { file, .. } => {
+                    files.push((path.as_path(), file.file()));
+                    break;
+                }
+                BackingFile::Qcow(backing_qcow) => {
+                    files.push((path.as_path(), backing_qcow.raw_file.file().file()));
+                    qcow = backing_qcow;
+                }
+            }
+        }
+        files
+    }
+
+    // Reads the name of the backing file from the image. Relative names are resolved against the
+    // directory holding the image rather than the current directory.
+    fn read_backing_file_path(file: &mut RawFile, header: &QcowHeader) -> Result<PathBuf> {
+        if header.backing_file_size > MAX_BACKING_FILE_SIZE {
+            return Err(Error::BackingFileTooLong(header.backing_file_size));
+        }
+
+        let mut name = vec![0u8; header.backing_file_size as usize];
+        file.seek(SeekFrom::Start(header.backing_file_offset))
+            .map_err(Error::SeekingFile)?;
+        file.read_exact(&mut name).map_err(Error::ReadingHeader)?;
+
+        let path = PathBuf::from(OsString::from_vec(name));
+        if path.is_relative() {
+            if let Ok(image_path) =
+                std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            {
+                if let Some(image_dir) = image_path.parent() {
+                    return Ok(image_dir.join(path));
+                }
+            }
+        }
+
+        Ok(path)
+    }
+
     /// Returns the L1 lookup table for this file. This is only useful for debugging.
     pub fn l1_table(&self) -> &[u64] {
         self.l1_table.get_values()
@@ -607,15 +1101,12 @@ impl QcowFile {
                 Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)
                     .map_err(Error::ReadingPointers)?,
             );
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache
                 .insert(l1_index, table, |index, evicted| {
-                    raw_file.write_pointer_table(
-                        l1_table[index],
-                        evicted.get_values(),
-                        CLUSTER_USED_FLAG,
-                    )
+                    raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
                 })
                 .map_err(Error::EvictingCache)?;
         }
@@ -660,6 +1151,588 @@ impl QcowFile {
         Ok(None)
     }
 
+    /// Returns the offset in the image file of the guest data at `address`, along with the number
+    /// of bytes, up to `count`, stored contiguously from there. Data not stored uncompressed in
+    /// this image, because it is unallocated, compressed or in the backing file, has no offset and
+    /// must be read through `Read`.
+    pub fn map_read(
+        &mut self,
+        address: u64,
+        count: usize,
+    ) -> std::io::Result<(Option<u64>, usize)> {
+        let l2_entry = self.l2_entry(address)?;
+        let count = self.limit_range_cluster(address, self.limit_range_file(address, count));
+
+        if l2_entry == 0 || l2_entry & COMPRESSED_FLAG != 0 {
+            Ok((None, count))
+        } else {
+            Ok((
+                Some(l2_entry + self.raw_file.cluster_offset(address)),
+                count,
+            ))
+        }
+    }
+
+    /// Returns the offset in the image file where the guest data at `address` must be written,
+    /// along with the number of bytes, up to `count`, stored contiguously from there. The cluster
+    /// is allocated, or copied if it can't be modified in place, as needed.
+    pub fn map_write(&mut self, address: u64, count: usize) -> std::io::Result<(u64, usize)> {
+        let offset = self.file_offset_write(address)?;
+        let count = self.limit_range_cluster(address, self.limit_range_file(address, count));
+
+        Ok((offset, count))
+    }
+
+    /// Grows the virtual disk to `new_size` bytes. The new space reads as zeros, or from the
+    /// backing file where it covers it.
+    ///
+    /// The L1 table is moved to the end of the file when it doesn't fit in its clusters anymore.
+    /// The refcount table is never moved, it must already have room for the new clusters.
+    pub fn resize(&mut self, new_size: u64) -> Result<()> {
+        if new_size < self.header.size {
+            return Err(Error::CannotShrink(new_size));
+        }
+        if new_size > MAX_QCOW_FILE_SIZE {
+            return Err(Error::FileTooBig(new_size));
+        }
+
+        let cluster_size = self.raw_file.cluster_size();
+        let num_clusters = div_round_up_u64(new_size, cluster_size);
+        let num_l2_clusters = div_round_up_u64(num_clusters, self.l2_entries);
+        let l1_clusters = div_round_up_u64(num_l2_clusters, cluster_size);
+        let header_clusters = div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size);
+        if num_l2_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::TooManyL1Entries(num_l2_clusters));
+        }
+        let refcount_clusters = max_refcount_clusters(
+            self.header.refcount_order,
+            cluster_size as u32,
+            (num_clusters + l1_clusters + num_l2_clusters + header_clusters) as u32,
+        );
+        if l1_clusters + refcount_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::TooManyRefcounts(refcount_clusters));
+        }
+        if refcount_clusters * size_of::<u64>() as u64
+            > u64::from(self.header.refcount_table_clusters) * cluster_size
+        {
+            return Err(Error::NotEnoughSpaceForRefcounts);
+        }
+
+        self.sync_caches().map_err(Error::ResizingImage)?;
+
+        // The entries of the refcount table past the current ones are zeros, so that the table
+        // can cover the new clusters in place.
+        self.refcounts = RefCount::new(
+            &mut self.raw_file,
+            self.header.refcount_table_offset,
+            refcount_clusters,
+            self.refcounts.refcounts_per_block(),
+            cluster_size,
+        )
+        .map_err(Error::ReadingRefCounts)?;
+
+        let previous_l1_size = max(u64::from(self.header.l1_size), self.l1_table.len() as u64);
+        let previous_l1_clusters =
+            div_round_up_u64(previous_l1_size * size_of::<u64>() as u64, cluster_size);
+        let mut l1_table = self.l1_table.get_values().to_vec();
+        l1_table.resize(num_l2_clusters as usize, 0);
+
+        let l1_table_offset =
+            if num_l2_clusters * size_of::<u64>() as u64 <= previous_l1_clusters * cluster_size {
+                self.header.l1_table_offset
+            } else {
+                self.append_clusters(num_l2_clusters * size_of::<u64>() as u64)
+                    .map_err(Error::ResizingImage)?
+            };
+        self.raw_file
+            .write_pointer_table(l1_table_offset, &l1_table, 0)
+            .map_err(Error::ResizingImage)?;
+        self.sync_caches().map_err(Error::ResizingImage)?;
+
+        // Switch to the new size and L1 table once everything they refer to is on disk.
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(SIZE_OFFSET))
+            .map_err(Error::SeekingFile)?;
+        file.write_u64::<BigEndian>(new_size)
+            .map_err(Error::WritingHeader)?;
+        file.seek(SeekFrom::Start(L1_SIZE_OFFSET))
+            .map_err(Error::SeekingFile)?;
+        file.write_u32::<BigEndian>(num_l2_clusters as u32)
+            .map_err(Error::WritingHeader)?;
+        file.write_u64::<BigEndian>(l1_table_offset)
+            .map_err(Error::WritingHeader)?;
+        file.sync_data().map_err(Error::WritingHeader)?;
+
+        let previous_l1_table_offset = self.header.l1_table_offset;
+        self.header.size = new_size;
+        self.header.l1_size = num_l2_clusters as u32;
+        self.header.l1_table_offset = l1_table_offset;
+        self.l1_table = VecCache::from_vec(l1_table);
+        if l1_table_offset != previous_l1_table_offset {
+            self.unref_clusters_range(
+                previous_l1_table_offset,
+                previous_l1_clusters * cluster_size,
+            )
+            .map_err(Error::ResizingImage)?;
+        }
+        self.sync_caches().map_err(Error::ResizingImage)
+    }
+
+    /// Checks the consistency of the metadata of the image. Every cluster must have a refcount
+    /// matching the number of references to it, and every table entry must point to a cluster of
+    /// the file which isn't used for anything else.
+    ///
+    /// With `repair`, invalid L1 and L2 entries are cleared, dropping the content they pointed to,
+    /// the refcounts are rebuilt and the image isn't flagged as dirty or corrupt anymore. The
+    /// inconsistencies found before the repair are returned, an error is returned if some of them
+    /// couldn't be fixed.
+    pub fn check(&mut self, repair: bool) -> Result<CheckResult> {
+        let result = self.check_metadata(repair).map_err(Error::CheckingImage)?;
+        if !repair {
+            return Ok(result);
+        }
+
+        if result.leaks != 0 || result.corruptions != 0 {
+            QcowFile::rebuild_refcounts(&mut self.raw_file, self.header)?;
+            self.raw_file
+                .file_mut()
+                .sync_data()
+                .map_err(Error::RebuildingRefCounts)?;
+        }
+        if self.header.is_dirty() || self.header.is_corrupt() {
+            let features = self.header.incompatible_features
+                & !(INCOMPATIBLE_FEATURES_DIRTY | INCOMPATIBLE_FEATURES_CORRUPT);
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(INCOMPATIBLE_FEATURES_OFFSET))
+                .map_err(Error::SeekingFile)?;
+            file.write_u64::<BigEndian>(features)
+                .map_err(Error::WritingHeader)?;
+            file.sync_data().map_err(Error::WritingHeader)?;
+            self.header.incompatible_features = features;
+        }
+        self.reload_metadata()?;
+
+        let remaining = self.check_metadata(false).map_err(Error::CheckingImage)?;
+        if !remaining.is_clean() {
+            return Err(Error::RepairIncomplete(remaining));
+        }
+        Ok(result)
+    }
+
+    // Walks the metadata of the image and compares the references found to the refcounts.
+    // Invalid L1 and L2 entries are cleared on disk if `repair` is set.
+    fn check_metadata(&mut self, repair: bool) -> std::io::Result<CheckResult> {
+        // The tables are checked as they are on disk.
+        self.sync_caches()?;
+
+        let cluster_size = self.raw_file.cluster_size();
+        let file_size = self.raw_file.file_mut().metadata()?.len();
+        let mut refs = ClusterRefs::new(cluster_size, file_size);
+        let mut result = CheckResult::default();
+
+        // Clusters at fixed locations come first, so that the L1 and L2 entries overlapping them
+        // are the ones flagged.
+        result.record(refs.add(0, ClusterKind::Header));
+        let ref_table_size = u64::from(self.header.refcount_table_clusters) * cluster_size;
+        if result.record(refs.add_range(
+            self.header.refcount_table_offset,
+            ref_table_size,
+            ClusterKind::RefcountTable,
+        )) {
+            let ref_table = self.raw_file.read_pointer_table(
+                self.header.refcount_table_offset,
+                ref_table_size / size_of::<u64>() as u64,
+                None,
+            )?;
+            for refblock_addr in ref_table.into_iter().filter(|addr| *addr != 0) {
+                result.record(refs.add(refblock_addr, ClusterKind::RefcountBlock));
+            }
+        }
+        if !self.snapshots.is_empty() {
+            result.record(refs.add_range(
+                self.header.snapshots_offset,
+                snapshot_table_size(&self.snapshots),
+                ClusterKind::SnapshotTable,
+            ));
+        }
+
+        let mut l1_tables = vec![(self.header.l1_table_offset, self.header.l1_size)];
+        l1_tables.extend(
+            self.snapshots
+                .iter()
+                .map(|s| (s.l1_table_offset, s.l1_size)),
+        );
+        l1_tables.retain(|(l1_table_offset, l1_size)| {
+            result.record(refs.add_range(
+                *l1_table_offset,
+                u64::from(*l1_size) * size_of::<u64>() as u64,
+                ClusterKind::L1Table,
+            ))
+        });
+        for (l1_table_offset, l1_size) in l1_tables {
+            self.check_tree(&mut refs, &mut result, l1_table_offset, l1_size, repair)?;
+        }
+        if repair {
+            self.raw_file.file_mut().sync_data()?;
+        }
+
+        for (index, count) in refs.counts.iter().enumerate() {
+            let refcount = match self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, index as u64 * cluster_size)
+            {
+                Ok(refcount) => u64::from(refcount),
+                // Clusters past the end of the refcount table, or covered by a refcount block
+                // past the end of the file, have no refcount.
+                Err(refcount::Error::InvalidIndex) => 0,
+                Err(refcount::Error::ReadingRefCounts(e))
+                    if e.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    0
+                }
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("failed to get cluster refcount: {}", e),
+                    ))
+                }
+            };
+            match refcount.cmp(count) {
+                Ordering::Greater => result.leaks += 1,
+                Ordering::Less => result.corruptions += 1,
+                Ordering::Equal => {}
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Records the references held by the L1 table at `l1_table_offset` and by the L2 tables it
+    // points to. Invalid entries are cleared on disk if `repair` is set.
+    fn check_tree(
+        &mut self,
+        refs: &mut ClusterRefs,
+        result: &mut CheckResult,
+        l1_table_offset: u64,
+        l1_size: u32,
+        repair: bool,
+    ) -> std::io::Result<()> {
+        let mut l1_table = self.raw_file.read_pointer_table(
+            l1_table_offset,
+            u64::from(l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        let mut l1_modified = false;
+        for l2_addr_disk in l1_table.iter_mut().filter(|addr| **addr != 0) {
+            if !result.record(refs.add(*l2_addr_disk, ClusterKind::L2Table)) {
+                *l2_addr_disk = 0;
+                l1_modified = true;
+                continue;
+            }
+
+            let mut l2_table = self.raw_file.read_pointer_cluster(*l2_addr_disk, None)?;
+            let mut l2_modified = false;
+            for l2_entry in l2_table.iter_mut() {
+                let valid = if *l2_entry & COMPRESSED_FLAG != 0 {
+                    compressed_host_clusters(
+                        *l2_entry & !CLUSTER_USED_FLAG,
+                        self.header.cluster_bits,
+                    )
+                    .all(|cluster_addr| result.record(refs.add(cluster_addr, ClusterKind::Data)))
+                } else {
+                    let data_cluster_addr = *l2_entry & L2_TABLE_OFFSET_MASK;
+                    data_cluster_addr == 0
+                        || result.record(refs.add(data_cluster_addr, ClusterKind::Data))
+                };
+                if !valid {
+                    *l2_entry = 0;
+                    l2_modified = true;
+                }
+            }
+            if repair && l2_modified {
+                self.raw_file
+                    .write_pointer_table(*l2_addr_disk, &l2_table, 0)?;
+            }
+        }
+        if repair && l1_modified {
+            self.raw_file
+                .write_pointer_table(l1_table_offset, &l1_table, 0)?;
+        }
+        Ok(())
+    }
+
+    // Reloads the tables held in memory after their copy on disk was modified behind them.
+    fn reload_metadata(&mut self) -> Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        self.l1_table = VecCache::from_vec(
+            self.raw_file
+                .read_pointer_table(
+                    self.header.l1_table_offset,
+                    self.l1_table.len() as u64,
+                    Some(L1_TABLE_OFFSET_MASK),
+                )
+                .map_err(Error::ReadingHeader)?,
+        );
+        self.l2_cache = CacheMap::new(100);
+        self.decompressed_cluster = None;
+        self.refcounts = RefCount::new(
+            &mut self.raw_file,
+            self.header.refcount_table_offset,
+            self.refcounts.ref_table().len() as u64,
+            self.refcounts.refcounts_per_block(),
+            cluster_size,
+        )
+        .map_err(Error::ReadingRefCounts)?;
+        self.unref_clusters.clear();
+        self.avail_clusters.clear();
+        self.find_avail_clusters()
+    }
+
+    /// Returns the internal snapshots of this file.
+    pub fn snapshots(&self) -> &[QcowSnapshot] {
+        &self.snapshots
+    }
+
+    /// Creates an internal snapshot named `name`, holding the current content of the disk.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS as usize {
+            return Err(Error::TooManySnapshots(self.snapshots.len() as u32));
+        }
+
+        // The L2 tables must be on disk as they are about to be shared with the snapshot.
+        self.sync_caches().map_err(Error::WritingSnapshots)?;
+
+        // The snapshot gets its own copy of the L1 table, then holds a reference to everything
+        // reachable from it.
+        let l1_table = self.l1_table.get_values().to_vec();
+        let l1_table_offset = self
+            .append_clusters(l1_table.len() as u64 * size_of::<u64>() as u64)
+            .map_err(Error::WritingSnapshots)?;
+        self.raw_file
+            .write_pointer_table(l1_table_offset, &l1_table, 0)
+            .map_err(Error::WritingSnapshots)?;
+        self.update_tree_refcounts(&l1_table, true)
+            .map_err(Error::WritingSnapshots)?;
+
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(QcowSnapshot::new(
+            id.to_string(),
+            name.to_string(),
+            date.as_secs() as u32,
+            date.subsec_nanos(),
+            self.header.size,
+            l1_table_offset,
+            l1_table.len() as u32,
+        ));
+        self.update_snapshot_table(snapshots)
+            .map_err(Error::WritingSnapshots)
+    }
+
+    /// Reverts the disk to the content it had when the snapshot identified by `id_or_name` was
+    /// created. The snapshot is kept.
+    pub fn apply_snapshot(&mut self, id_or_name: &str) -> Result<()> {
+        let snapshot = self.snapshots[self.find_snapshot(id_or_name)?].clone();
+        if snapshot.disk_size != self.header.size {
+            return Err(Error::SnapshotSizeMismatch(snapshot.disk_size));
+        }
+        if snapshot.l1_size as usize > self.l1_table.len() {
+            return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+        }
+
+        self.sync_caches().map_err(Error::WritingSnapshots)?;
+
+        let mut l1_table = self
+            .raw_file
+            .read_pointer_table(
+                snapshot.l1_table_offset,
+                u64::from(snapshot.l1_size),
+                Some(L1_TABLE_OFFSET_MASK),
+            )
+            .map_err(Error::ReadingSnapshots)?;
+        l1_table.resize(self.l1_table.len(), 0);
+
+        // Reference the content of the snapshot before switching to it, and only drop the
+        // references of the previous content once the new L1 table is on disk. Clusters shared
+        // between both are never freed along the way.
+        self.update_tree_refcounts(&l1_table, true)
+            .map_err(Error::WritingSnapshots)?;
+        self.sync_caches().map_err(Error::WritingSnapshots)?;
+        self.raw_file
+            .write_pointer_table(self.header.l1_table_offset, &l1_table, 0)
+            .map_err(Error::WritingSnapshots)?;
+        self.raw_file
+            .file_mut()
+            .sync_data()
+            .map_err(Error::WritingSnapshots)?;
+
+        let previous_l1_table = std::mem::replace(&mut self.l1_table, VecCache::from_vec(l1_table));
+        // The cached L2 tables belong to the previous L1 table, they are all clean by now.
+        self.l2_cache = CacheMap::new(100);
+        self.decompressed_cluster = None;
+        self.update_tree_refcounts(previous_l1_table.get_values(), false)
+            .map_err(Error::WritingSnapshots)?;
+        self.sync_caches().map_err(Error::WritingSnapshots)
+    }
+
+    /// Deletes the snapshot identified by `id_or_name`, freeing the clusters only it uses.
+    pub fn delete_snapshot(&mut self, id_or_name: &str) -> Result<()> {
+        let index = self.find_snapshot(id_or_name)?;
+
+        self.sync_caches().map_err(Error::WritingSnapshots)?;
+
+        // Remove the snapshot from the table first, a failure past this point only leaks
+        // clusters.
+        let mut snapshots = self.snapshots.clone();
+        let snapshot = snapshots.remove(index);
+        self.update_snapshot_table(snapshots)
+            .map_err(Error::WritingSnapshots)?;
+
+        let l1_table = self
+            .raw_file
+            .read_pointer_table(
+                snapshot.l1_table_offset,
+                u64::from(snapshot.l1_size),
+                Some(L1_TABLE_OFFSET_MASK),
+            )
+            .map_err(Error::ReadingSnapshots)?;
+        self.update_tree_refcounts(&l1_table, false)
+            .map_err(Error::WritingSnapshots)?;
+        self.unref_clusters_range(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+        )
+        .map_err(Error::WritingSnapshots)?;
+        self.sync_caches().map_err(Error::WritingSnapshots)
+    }
+
+    // Returns the index of the snapshot identified by `id_or_name`, IDs take precedence.
+    fn find_snapshot(&self, id_or_name: &str) -> Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|s| s.id == id_or_name)
+            .or_else(|| self.snapshots.iter().position(|s| s.name == id_or_name))
+            .ok_or_else(|| Error::SnapshotNotFound(id_or_name.to_string()))
+    }
+
+    // Writes `snapshots` to a new snapshot table, points the header at it and frees the previous
+    // table.
+    fn update_snapshot_table(&mut self, snapshots: Vec<QcowSnapshot>) -> std::io::Result<()> {
+        let offset = if snapshots.is_empty() {
+            0
+        } else {
+            let offset = self.append_clusters(snapshot_table_size(&snapshots))?;
+            write_snapshot_table(self.raw_file.file_mut(), offset, &snapshots)?;
+            offset
+        };
+        // Everything the new table refers to must be on disk before the header points to it.
+        self.sync_caches()?;
+
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(NB_SNAPSHOTS_OFFSET))?;
+        file.write_u32::<BigEndian>(snapshots.len() as u32)?;
+        file.write_u64::<BigEndian>(offset)?;
+        file.sync_data()?;
+
+        let previous_offset = self.header.snapshots_offset;
+        let previous_size = snapshot_table_size(&self.snapshots);
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = offset;
+        self.snapshots = snapshots;
+        if previous_size != 0 {
+            self.unref_clusters_range(previous_offset, previous_size)?;
+        }
+        self.sync_caches()
+    }
+
+    // Adds (or drops if `increment` is false) one reference to each of the L2 tables referenced
+    // by `l1_table`, and to each of the data clusters referenced by those L2 tables.
+    fn update_tree_refcounts(&mut self, l1_table: &[u64], increment: bool) -> std::io::Result<()> {
+        for l2_addr_disk in l1_table.iter().filter(|addr| **addr != 0) {
+            let l2_table = Self::read_l2_cluster(&mut self.raw_file, *l2_addr_disk)?;
+            for l2_entry in l2_table.into_iter().filter(|entry| *entry != 0) {
+                if l2_entry & COMPRESSED_FLAG != 0 {
+                    if increment {
+                        for cluster_addr in
+                            compressed_host_clusters(l2_entry, self.header.cluster_bits)
+                        {
+                            self.ref_cluster(cluster_addr)?;
+                        }
+                    } else {
+                        self.unref_compressed_cluster(l2_entry)?;
+                    }
+                } else if increment {
+                    self.ref_cluster(l2_entry)?;
+                } else {
+                    self.unref_cluster(l2_entry)?;
+                }
+            }
+            if increment {
+                self.ref_cluster(*l2_addr_disk)?;
+            } else {
+                self.unref_cluster(*l2_addr_disk)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Drops one reference to each of the clusters holding the `size` bytes at `address`.
+    fn unref_clusters_range(&mut self, address: u64, size: u64) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for i in 0..div_round_up_u64(size, cluster_size) {
+            self.unref_cluster(address + i * cluster_size)?;
+        }
+        Ok(())
+    }
+
+    // Allocates enough contiguous clusters at the end of the file to hold `size` bytes. Returns
+    // the offset of the first cluster.
+    fn append_clusters(&mut self, size: u64) -> std::io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let count = max(div_round_up_u64(size, cluster_size), 1);
+        let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+
+        // Reserve all the clusters before setting their refcounts, as that can require new
+        // clusters for the refcount blocks.
+        let mut first_cluster = None;
+        for _ in 0..count {
+            let cluster = self
+                .raw_file
+                .add_cluster_end(max_valid_cluster_offset)?
+                .ok_or_else(|| std::io::Error::from_raw_os_error(ENOSPC))?;
+            first_cluster.get_or_insert(cluster);
+        }
+        // 'unwrap' is OK because at least one cluster was allocated.
+        let first_cluster = first_cluster.unwrap();
+        for i in 0..count {
+            let mut newly_unref = self.set_cluster_refcount(first_cluster + i * cluster_size, 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        Ok(first_cluster)
+    }
+
+    // Flags set on the L2 entries written to disk. With snapshots, data clusters can be shared
+    // and this L2 table can't be flagged as their only user.
+    fn l2_entry_flags(&self) -> u64 {
+        if self.snapshots.is_empty() {
+            CLUSTER_USED_FLAG
+        } else {
+            0
+        }
+    }
+
     fn find_avail_clusters(&mut self) -> Result<()> {
         let cluster_size = self.raw_file.cluster_size();
 
@@ -705,7 +1778,10 @@ impl QcowFile {
             header: QcowHeader,
             cluster_size: u64,
         ) -> Result<()> {
-            let l1_clusters = div_round_up_u64(u64::from(header.l1_size), cluster_size);
+            let l1_clusters = div_round_up_u64(
+                u64::from(header.l1_size) * size_of::<u64>() as u64,
+                cluster_size,
+            );
             let l1_table_offset = header.l1_table_offset;
             for i in 0..l1_clusters {
                 add_ref(refcounts, cluster_size, l1_table_offset + i * cluster_size)?;
@@ -713,21 +1789,24 @@ impl QcowFile {
             Ok(())
         }
 
-        // Traverse the L1 and L2 tables to find all reachable data clusters.
+        // Traverse the L1 table at `l1_table_offset` and its L2 tables to find all reachable data
+        // clusters.
         fn set_data_refcounts(
             refcounts: &mut [u16],
             header: QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
             cluster_size: u64,
             raw_file: &mut QcowRawFile,
         ) -> Result<()> {
             let l1_table = raw_file
                 .read_pointer_table(
-                    header.l1_table_offset,
-                    u64::from(header.l1_size),
+                    l1_table_offset,
+                    u64::from(l1_size),
                     Some(L1_TABLE_OFFSET_MASK),
                 )
                 .map_err(Error::ReadingPointers)?;
-            for l1_index in 0..header.l1_size as usize {
+            for l1_index in 0..l1_size as usize {
                 let l2_addr_disk = *l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
                 if l2_addr_disk != 0 {
                     // Add a reference to the L2 table cluster itself.
@@ -738,12 +1817,22 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for l2_entry in l2_table {
+                        if l2_entry & COMPRESSED_FLAG != 0 {
+                            // Compressed data holds a reference to every cluster it spans.
+                            for cluster_addr in
+                                compressed_host_clusters(l2_entry, header.cluster_bits)
+                            {
+                                add_ref(refcounts, cluster_size, cluster_addr)?;
+                            }
+                        } else {
+                            let data_cluster_addr = l2_entry & L2_TABLE_OFFSET_MASK;
+                            if data_cluster_addr != 0 {
+                                add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                            }
                         }
                     }
                 }
@@ -752,6 +1841,57 @@ impl QcowFile {
             Ok(())
         }
 
+        // Add references to the snapshot table, and to the L1 tables of the snapshots and
+        // everything they point to.
+        fn set_snapshot_refcounts(
+            refcounts: &mut [u16],
+            header: QcowHeader,
+            cluster_size: u64,
+            raw_file: &mut QcowRawFile,
+        ) -> Result<()> {
+            let snapshots = read_snapshot_table(
+                raw_file.file_mut(),
+                header.snapshots_offset,
+                header.nb_snapshots,
+                header.size,
+            )
+            .map_err(Error::ReadingSnapshots)?;
+            if snapshots.is_empty() {
+                return Ok(());
+            }
+
+            let table_clusters = div_round_up_u64(snapshot_table_size(&snapshots), cluster_size);
+            for i in 0..table_clusters {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    header.snapshots_offset + i * cluster_size,
+                )?;
+            }
+            for snapshot in snapshots {
+                let l1_clusters = div_round_up_u64(
+                    u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+                    cluster_size,
+                );
+                for i in 0..l1_clusters {
+                    add_ref(
+                        refcounts,
+                        cluster_size,
+                        snapshot.l1_table_offset + i * cluster_size,
+                    )?;
+                }
+                set_data_refcounts(
+                    refcounts,
+                    header,
+                    snapshot.l1_table_offset,
+                    snapshot.l1_size,
+                    cluster_size,
+                    raw_file,
+                )?;
+            }
+            Ok(())
+        }
+
         // Add references to the top-level refcount table clusters.
         fn set_refcount_table_refcounts(
             refcounts: &mut [u16],
@@ -842,9 +1982,13 @@ impl QcowFile {
                 }
             }
 
-            // Rewrite the top-level refcount table.
+            // Rewrite the top-level refcount table, clearing any stale entry past the new ones.
+            let table_entries = u64::from(header.refcount_table_clusters) * raw_file.cluster_size()
+                / size_of::<u64>() as u64;
+            let mut ref_table = ref_table.to_vec();
+            ref_table.resize(max(ref_table.len(), table_entries as usize), 0);
             raw_file
-                .write_pointer_table(header.refcount_table_offset, ref_table, 0)
+                .write_pointer_table(header.refcount_table_offset, &ref_table, 0)
                 .map_err(Error::WritingHeader)?;
 
             // Rewrite the header again, now with lazy refcounts disabled.
@@ -902,7 +2046,15 @@ impl QcowFile {
         // Find all references clusters and rebuild refcounts.
         set_header_refcount(&mut refcounts, cluster_size)?;
         set_l1_refcounts(&mut refcounts, header, cluster_size)?;
-        set_data_refcounts(&mut refcounts, header, cluster_size, raw_file)?;
+        set_data_refcounts(
+            &mut refcounts,
+            header,
+            header.l1_table_offset,
+            header.l1_size,
+            cluster_size,
+            raw_file,
+        )?;
+        set_snapshot_refcounts(&mut refcounts, header, cluster_size, raw_file)?;
         set_refcount_table_refcounts(&mut refcounts, header, cluster_size)?;
 
         // Allocate clusters to store the new reference count blocks.
@@ -959,9 +2111,10 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Gets the L2 entry of the cluster containing the given guest address. This is either the
+    // offset of the cluster in the host file or, if COMPRESSED_FLAG is set, the descriptor of its
+    // compressed data. If L1, L2, or data clusters have yet to be allocated, return 0.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -974,7 +2127,7 @@ impl QcowFile {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(0);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -984,22 +2137,34 @@ impl QcowFile {
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
 
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         };
 
-        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
-            return Ok(None);
+        Ok(self.l2_cache.get(l1_index).unwrap()[l2_index])
+    }
+
+    // Returns the content of the compressed cluster described by `l2_entry`.
+    fn decompress_cluster(&mut self, l2_entry: u64) -> std::io::Result<&[u8]> {
+        let cached = matches!(&self.decompressed_cluster, Some((entry, _)) if *entry == l2_entry);
+        if !cached {
+            let (offset, size) = compressed_cluster_extent(l2_entry, self.header.cluster_bits);
+            // The last sector of compressed data can be truncated at the end of the file.
+            let mut compressed = Vec::with_capacity(size as usize);
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(size).read_to_end(&mut compressed)?;
+
+            let mut cluster = vec![0u8; self.raw_file.cluster_size() as usize];
+            decompress(self.header.compression_type, &compressed, &mut cluster)?;
+            self.decompressed_cluster = Some((l2_entry, cluster));
         }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        // 'unwrap' is OK because the cluster was just decompressed.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
@@ -1031,22 +2196,60 @@ impl QcowFile {
             } else {
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
             };
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         }
 
-        let cluster_addr = match self.l2_cache.get(l1_index).unwrap()[l2_index] {
+        let l2_entry = self.l2_cache.get(l1_index).unwrap()[l2_index];
+        let cluster_addr = match l2_entry {
             0 => {
                 // Need to allocate a data cluster
                 let cluster_addr = self.append_data_cluster()?;
                 self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                // Copy the content of the cluster from the backing file, the part of it that
+                // isn't about to be written must stay visible.
+                if let Some(backing_file) = self.backing_file.as_mut() {
+                    let cluster_size = self.raw_file.cluster_size();
+                    let cluster_begin = address - self.raw_file.cluster_offset(address);
+                    let mut cluster_data = vec![0u8; cluster_size as usize];
+                    backing_file.read_at(cluster_begin, &mut cluster_data)?;
+                    self.raw_file
+                        .file_mut()
+                        .seek(SeekFrom::Start(cluster_addr))?;
+                    self.raw_file.file_mut().write_all(&cluster_data)?;
+                }
+                cluster_addr
+            }
+            a if a & COMPRESSED_FLAG != 0 => {
+                // Compressed clusters can't be modified in place, their content is moved to a
+                // newly allocated cluster which replaces them.
+                let cluster_data = self.decompress_cluster(a)?.to_vec();
+                let cluster_addr = self.append_data_cluster()?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.raw_file
+                    .file_mut()
+                    .seek(SeekFrom::Start(cluster_addr))?;
+                self.raw_file.file_mut().write_all(&cluster_data)?;
+                self.unref_compressed_cluster(a)?;
+                cluster_addr
+            }
+            a if !self.snapshots.is_empty() && self.cluster_refcount(a)? > 1 => {
+                // The cluster is shared with a snapshot, it has to be copied before being
+                // modified.
+                let mut cluster_data = vec![0u8; self.raw_file.cluster_size() as usize];
+                self.raw_file.file_mut().seek(SeekFrom::Start(a))?;
+                self.raw_file.file_mut().read_exact(&mut cluster_data)?;
+                let cluster_addr = self.append_data_cluster()?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.raw_file
+                    .file_mut()
+                    .seek(SeekFrom::Start(cluster_addr))?;
+                self.raw_file.file_mut().write_all(&cluster_data)?;
+                self.unref_cluster(a)?;
                 cluster_addr
             }
             a => a,
@@ -1069,14 +2272,18 @@ impl QcowFile {
         set_refcounts: &mut Vec<(u64, u16)>,
     ) -> io::Result<()> {
         if !self.l2_cache.get(l1_index).unwrap().dirty() {
-            // Free the previously used cluster if one exists. Modified tables are always
-            // witten to new clusters so the L1 table can be committed to disk after they
-            // are and L1 never points at an invalid table.
+            // Drop the reference to the previously used cluster if one exists, it is freed
+            // unless a snapshot still uses it. Modified tables are always written to new
+            // clusters so the L1 table can be committed to disk after they are and L1 never
+            // points at an invalid table.
             // The index must be valid from when it was inserted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                let refcount = self.cluster_refcount(addr)?;
+                if refcount <= 1 {
+                    self.unref_clusters.push(addr);
+                }
+                set_refcounts.push((addr, refcount.saturating_sub(1)));
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1122,41 +2329,36 @@ impl QcowFile {
 
     // Returns true if the cluster containing `address` is already allocated.
     fn cluster_allocated(&mut self, address: u64) -> std::io::Result<bool> {
-        if address >= self.virtual_size() as u64 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        // If the L2 entry isn't 0, the cluster is allocated, compressed or not.
+        if self.l2_entry(address)? != 0 {
+            return Ok(true);
         }
+        self.backing_cluster_allocated(address)
+    }
 
-        let l1_index = self.l1_table_index(address) as usize;
-        let l2_addr_disk = *self
-            .l1_table
-            .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
-        let l2_index = self.l2_table_index(address) as usize;
-
-        if l2_addr_disk == 0 {
-            // The whole L2 table for this address is not allocated yet,
-            // so the cluster must also be unallocated.
-            return Ok(false);
+    // Returns true if the backing file holds data for the cluster containing `address`.
+    fn backing_cluster_allocated(&mut self, address: u64) -> std::io::Result<bool> {
+        let cluster_size = self.raw_file.cluster_size();
+        let cluster_begin = address - self.raw_file.cluster_offset(address);
+        match self.backing_file.as_mut() {
+            Some(backing_file) => backing_file.range_allocated(cluster_begin, cluster_size),
+            None => Ok(false),
         }
+    }
 
-        if !self.l2_cache.contains_key(l1_index) {
-            // Not in the cache.
-            let table =
-                VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
-            let l1_table = &self.l1_table;
-            let raw_file = &mut self.raw_file;
-            self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
-            })?;
+    // Returns true if any cluster overlapping the `length` bytes starting at `address` is
+    // allocated.
+    fn range_allocated(&mut self, address: u64, length: u64) -> std::io::Result<bool> {
+        let end = min(address.saturating_add(length), self.virtual_size());
+        let cluster_size = self.raw_file.cluster_size();
+        let mut cluster_addr = address - self.raw_file.cluster_offset(address);
+        while cluster_addr < end {
+            if self.cluster_allocated(cluster_addr)? {
+                return Ok(true);
+            }
+            cluster_addr += cluster_size;
         }
-
-        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
-        // If cluster_addr != 0, the cluster is allocated.
-        Ok(cluster_addr != 0)
+        Ok(false)
     }
 
     // Find the first guest address greater than or equal to `address` whose allocation state
@@ -1215,14 +2417,11 @@ impl QcowFile {
             // Not in the cache.
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         }
 
@@ -1232,16 +2431,50 @@ impl QcowFile {
             return Ok(());
         }
 
-        // Decrement the refcount.
-        let refcount = self
-            .refcounts
+        // Rewrite the L2 entry to remove the cluster mapping. The L2 table may be shared with a
+        // snapshot, so it goes through the same path as any other update.
+        let mut set_refcounts = Vec::new();
+        self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+
+        if cluster_addr & COMPRESSED_FLAG != 0 {
+            self.unref_compressed_cluster(cluster_addr)
+        } else {
+            self.unref_cluster(cluster_addr)
+        }
+    }
+
+    // Returns the refcount of the host cluster at `cluster_addr`.
+    fn cluster_refcount(&mut self, cluster_addr: u64) -> std::io::Result<u16> {
+        self.refcounts
             .get_cluster_refcount(&mut self.raw_file, cluster_addr)
             .map_err(|e| {
                 io::Error::new(
                     io::ErrorKind::InvalidData,
                     format!("failed to get cluster refcount: {}", e),
                 )
-            })?;
+            })
+    }
+
+    // Adds one reference to the host cluster at `cluster_addr`.
+    fn ref_cluster(&mut self, cluster_addr: u64) -> std::io::Result<()> {
+        let refcount = self.cluster_refcount(cluster_addr)?;
+        if refcount == u16::MAX {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+        let mut newly_unref = self.set_cluster_refcount(cluster_addr, refcount + 1)?;
+        self.unref_clusters.append(&mut newly_unref);
+        Ok(())
+    }
+
+    // Drops one reference to the host cluster at `cluster_addr`, releasing its storage once it
+    // isn't referenced anymore.
+    fn unref_cluster(&mut self, cluster_addr: u64) -> std::io::Result<()> {
+        // Decrement the refcount.
+        let refcount = self.cluster_refcount(cluster_addr)?;
         if refcount == 0 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1250,10 +2483,6 @@ impl QcowFile {
         let mut newly_unref = self.set_cluster_refcount(cluster_addr, new_refcount)?;
         self.unref_clusters.append(&mut newly_unref);
 
-        // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(l1_index).unwrap()[l2_index] = 0;
-
         if new_refcount == 0 {
             let cluster_size = self.raw_file.cluster_size();
             // This cluster is no longer in use; deallocate the storage.
@@ -1268,6 +2497,15 @@ impl QcowFile {
         Ok(())
     }
 
+    // Drops the references the compressed cluster described by `l2_entry` holds on the host
+    // clusters its data is stored in.
+    fn unref_compressed_cluster(&mut self, l2_entry: u64) -> std::io::Result<()> {
+        for cluster_addr in compressed_host_clusters(l2_entry, self.header.cluster_bits) {
+            self.unref_cluster(cluster_addr)?;
+        }
+        Ok(())
+    }
+
     // Deallocate the storage for `length` bytes starting at `address`.
     // Any future reads of this range will return all zeroes.
     fn deallocate_bytes(&mut self, address: u64, length: usize) -> std::io::Result<()> {
@@ -1278,15 +2516,22 @@ impl QcowFile {
             let curr_addr = address + nwritten as u64;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
-            if count == self.raw_file.cluster_size() as usize {
+            if self.backing_file.is_some() {
+                // Dropping the cluster would expose the content of the backing file, zeros have
+                // to be stored in this image instead.
+                let offset = self.file_offset_write(curr_addr)?;
+                self.raw_file.file_mut().write_zeroes_at(offset, count)?;
+            } else if count == self.raw_file.cluster_size() as usize {
                 // Full cluster - deallocate the storage.
                 self.deallocate_cluster(curr_addr)?;
             } else {
                 // Partial cluster - zero out the relevant bytes if it was allocated.
                 // Any space in unallocated clusters can be left alone, since
                 // unallocated clusters already read back as zeroes.
-                if let Some(offset) = self.file_offset_read(curr_addr)? {
-                    // Partial cluster - zero it out.
+                if self.l2_entry(curr_addr)? != 0 {
+                    // Partial cluster - zero it out. Compressed clusters are moved to a regular
+                    // cluster first.
+                    let offset = self.file_offset_write(curr_addr)?;
                     self.raw_file.file_mut().write_zeroes_at(offset, count)?;
                 }
             }
@@ -1296,16 +2541,19 @@ impl QcowFile {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // Reads an L2 cluster from the disk, returning an error if the file can't be read. Entries of
+    // compressed clusters keep their COMPRESSED_FLAG and the descriptor of the compressed data.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| {
+                if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & L2_TABLE_OFFSET_MASK
+                }
+            })
             .collect())
     }
 
@@ -1360,23 +2608,27 @@ impl QcowFile {
             }
         }
 
+        let mut freed_clusters = Vec::new();
         for addr in added_clusters {
-            self.set_cluster_refcount(addr, 1)?;
+            freed_clusters.append(&mut self.set_cluster_refcount(addr, 1)?);
         }
+        // The refcount blocks moved along the way left their previous location unused.
+        for addr in unref_clusters.iter() {
+            freed_clusters.append(&mut self.set_cluster_refcount(*addr, 0)?);
+        }
+        unref_clusters.append(&mut freed_clusters);
         Ok(unref_clusters)
     }
 
     fn sync_caches(&mut self) -> std::io::Result<()> {
         // Write out all dirty L2 tables.
+        let l2_flags = self.l2_entry_flags();
         for (l1_index, l2_table) in self.l2_cache.iter_mut().filter(|(_k, v)| v.dirty()) {
             // The index must be valid from when we inserted it.
             let addr = self.l1_table[*l1_index];
             if addr != 0 {
-                self.raw_file.write_pointer_table(
-                    addr,
-                    l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
-                )?;
+                self.raw_file
+                    .write_pointer_table(addr, l2_table.get_values(), l2_flags)?;
             } else {
                 return Err(std::io::Error::from_raw_os_error(EINVAL));
             }
@@ -1422,14 +2674,22 @@ impl Read for QcowFile {
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let l2_entry = self.l2_entry(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
-            if let Some(offset) = file_offset {
+            if l2_entry & COMPRESSED_FLAG != 0 {
+                let offset = self.raw_file.cluster_offset(curr_addr) as usize;
+                let cluster = self.decompress_cluster(l2_entry)?;
+                buf[nread..(nread + count)].copy_from_slice(&cluster[offset..(offset + count)]);
+            } else if l2_entry != 0 {
+                let offset = l2_entry + self.raw_file.cluster_offset(curr_addr);
                 self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
                 self.raw_file
                     .file_mut()
                     .read_exact(&mut buf[nread..(nread + count)])?;
+            } else if let Some(backing_file) = self.backing_file.as_mut() {
+                // Not allocated in this image, the data comes from the backing file.
+                backing_file.read_at(curr_addr, &mut buf[nread..(nread + count)])?;
             } else {
                 // Previously unwritten region, return zeros
                 for b in &mut buf[nread..(nread + count)] {
@@ -1547,6 +2807,12 @@ impl WriteZeroesAt for QcowFile {
     }
 }
 
+impl AsRawFd for QcowFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.raw_file.file().as_raw_fd()
+    }
+}
+
 impl SeekHole for QcowFile {
     fn seek_hole(&mut self, offset: u64) -> io::Result<Option<u64>> {
         match self.find_allocated_cluster(offset, false) {
@@ -1585,6 +2851,53 @@ fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     Ok(())
 }
 
+// Splits the L2 entry of a compressed cluster into the offset of its compressed data in the host
+// file and the maximum size of that data.
+fn compressed_cluster_extent(l2_entry: u64, cluster_bits: u32) -> (u64, u64) {
+    let size_shift = 62 - (cluster_bits - 8);
+    let offset = l2_entry & ((1 << size_shift) - 1);
+    // The number of sectors doesn't include the one holding the start of the data.
+    let sectors = ((l2_entry >> size_shift) & ((1 << (cluster_bits - 8)) - 1)) + 1;
+    let size = sectors * COMPRESSED_SECTOR_SIZE - (offset % COMPRESSED_SECTOR_SIZE);
+    (offset, size)
+}
+
+// Returns the addresses of the host clusters holding the data of a compressed cluster.
+fn compressed_host_clusters(l2_entry: u64, cluster_bits: u32) -> impl Iterator<Item = u64> {
+    let cluster_size = 0x01u64 << cluster_bits;
+    let (offset, size) = compressed_cluster_extent(l2_entry, cluster_bits);
+    let first_cluster = offset / cluster_size;
+    let last_cluster = (offset + size - 1) / cluster_size;
+    (first_cluster..=last_cluster).map(move |cluster| cluster * cluster_size)
+}
+
+// Decompresses the data of a compressed cluster into `cluster`, which must be exactly one cluster
+// long. The compressed data is padded to a sector boundary, anything after the end of the
+// compressed stream is ignored.
+fn decompress(compression_type: u8, compressed: &[u8], cluster: &mut [u8]) -> io::Result<()> {
+    match compression_type {
+        COMPRESSION_TYPE_ZLIB => {
+            // Compressed clusters are raw deflate streams, without zlib header.
+            let mut decompressor = Decompress::new(false);
+            decompressor
+                .decompress(compressed, cluster, FlushDecompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if decompressor.total_out() != cluster.len() as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compressed cluster is too short",
+                ));
+            }
+            Ok(())
+        }
+        COMPRESSION_TYPE_ZSTD => {
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(compressed)?.single_frame();
+            decoder.read_exact(cluster)
+        }
+        _ => Err(io::Error::from_raw_os_error(ENOTSUP)),
+    }
+}
+
 // Ceiling of the division of `dividend`/`divisor`.
 fn div_round_up_u64(dividend: u64, divisor: u64) -> u64 {
     dividend / divisor + if dividend % divisor != 0 { 1 } else { 0 }
@@ -1979,6 +3292,36 @@ mod tests {
         });
     }
 
+    #[test]
+    fn map_read_write() {
+        with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
+            let mut q = QcowFile::from(disk_file).unwrap();
+            let cluster_size = q.raw_file.cluster_size();
+
+            // Unallocated data has no location in the file.
+            let (offset, count) = q.map_read(0x1000, 0x20000).unwrap();
+            assert_eq!(offset, None);
+            assert_eq!(count as u64, cluster_size - 0x1000);
+
+            let (write_offset, count) = q.map_write(0x1000, 0x200).unwrap();
+            assert_eq!(count, 0x200);
+            q.raw_file
+                .file_mut()
+                .seek(SeekFrom::Start(write_offset))
+                .unwrap();
+            q.raw_file.file_mut().write_all(&[0x55u8; 0x200]).unwrap();
+
+            let (read_offset, count) = q.map_read(0x1000, 0x20000).unwrap();
+            assert_eq!(read_offset, Some(write_offset));
+            assert_eq!(count as u64, cluster_size - 0x1000);
+
+            let mut buf = [0u8; 0x200];
+            q.seek(SeekFrom::Start(0x1000)).unwrap();
+            q.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == 0x55));
+        });
+    }
+
     #[test]
     fn write_zeroes_read() {
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
@@ -2480,7 +3823,12 @@ mod tests {
                 }
             }
 
-            assert_eq!(qcow_file.first_zero_refcount().unwrap(), None);
+            // The only free clusters are the previous locations of the refcount blocks, moved on
+            // their first update and waiting to be reused.
+            if let Some(cluster_addr) = qcow_file.first_zero_refcount().unwrap() {
+                assert!(qcow_file.unref_clusters.contains(&cluster_addr));
+            }
+            assert!(qcow_file.check(false).unwrap().is_clean());
         });
     }
 
@@ -2624,4 +3972,630 @@ mod tests {
                 .expect("Failed to rebuild recounts.");
         });
     }
+
+    #[test]
+    fn backing_file_read_write() {
+        let backing_tmp = TempFile::new().unwrap();
+        {
+            let mut backing = QcowFile::new(
+                RawFile::new(backing_tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+            backing.write_all(&[0x55u8; 0x1000]).unwrap();
+        }
+
+        let overlay_tmp = TempFile::new().unwrap();
+        {
+            let mut q = QcowFile::new_from_backing(
+                RawFile::new(overlay_tmp.as_file().try_clone().unwrap(), false),
+                3,
+                backing_tmp.as_path(),
+            )
+            .unwrap();
+            assert_eq!(q.virtual_size(), 0x10_0000);
+
+            // Unallocated clusters are read from the backing file.
+            let mut buf = [0u8; 0x1000];
+            q.seek(SeekFrom::Start(0)).unwrap();
+            q.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|b| *b == 0x55));
+
+            // A partial write keeps the rest of the cluster from the backing file.
+            q.seek(SeekFrom::Start(0x10)).unwrap();
+            q.write_all(&[0xaau8; 0x10]).unwrap();
+            q.seek(SeekFrom::Start(0)).unwrap();
+            q.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[0xf], 0x55);
+            assert_eq!(buf[0x10], 0xaa);
+            assert_eq!(buf[0x1f], 0xaa);
+            assert_eq!(buf[0x20], 0x55);
+
+            // Zeroing must not expose the backing file content.
+            q.seek(SeekFrom::Start(0)).unwrap();
+            q.write_zeroes(0x20).unwrap();
+            q.seek(SeekFrom::Start(0)).unwrap();
+            q.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[0x1f], 0);
+            assert_eq!(buf[0x20], 0x55);
+        }
+
+        // The backing file isn't opened unless explicitly allowed.
+        QcowFile::from(RawFile::new(
+            overlay_tmp.as_file().try_clone().unwrap(),
+            false,
+        ))
+        .expect_err("Opened an image with a backing file.");
+
+        let mut q = QcowFile::from_with_backing_file(
+            RawFile::new(overlay_tmp.as_file().try_clone().unwrap(), false),
+            &BackingFilePolicy::Header,
+        )
+        .unwrap();
+        let mut buf = [0u8; 0x20];
+        q.seek(SeekFrom::Start(0x20)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0x55));
+        let backing_files = q.backing_files();
+        assert_eq!(backing_files.len(), 1);
+        assert_eq!(backing_files[0].0, backing_tmp.as_path());
+    }
+
+    #[test]
+    fn backing_file_chain() {
+        let base_tmp = TempFile::new().unwrap();
+        {
+            let mut base = QcowFile::new(
+                RawFile::new(base_tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+            base.write_all(&[0x55u8; 0x1000]).unwrap();
+        }
+
+        // Images created on top of an overlay keep its whole chain.
+        let middle_tmp = TempFile::new().unwrap();
+        QcowFile::new_from_backing(
+            RawFile::new(middle_tmp.as_file().try_clone().unwrap(), false),
+            3,
+            base_tmp.as_path(),
+        )
+        .unwrap();
+        let top_tmp = TempFile::new().unwrap();
+        QcowFile::new_from_backing(
+            RawFile::new(top_tmp.as_file().try_clone().unwrap(), false),
+            3,
+            middle_tmp.as_path(),
+        )
+        .unwrap();
+
+        let mut q = QcowFile::from_with_backing_file(
+            RawFile::new(top_tmp.as_file().try_clone().unwrap(), false),
+            &BackingFilePolicy::Header,
+        )
+        .unwrap();
+        let mut buf = [0u8; 0x1000];
+        q.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0x55));
+        assert_eq!(q.backing_files().len(), 2);
+
+        // An overridden backing file must not reference one of its own.
+        let e = QcowFile::from_with_backing_file(
+            RawFile::new(top_tmp.as_file().try_clone().unwrap(), false),
+            &BackingFilePolicy::Override(middle_tmp.as_path().to_path_buf()),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e,
+            Error::BackingFileOpen(e) if matches!(*e, Error::BackingFilesNotSupported)
+        ));
+
+        let q = QcowFile::from_with_backing_file(
+            RawFile::new(middle_tmp.as_file().try_clone().unwrap(), false),
+            &BackingFilePolicy::Override(base_tmp.as_path().to_path_buf()),
+        )
+        .unwrap();
+        assert_eq!(q.backing_files().len(), 1);
+    }
+
+    #[test]
+    fn backing_file_header_extensions() {
+        let backing_tmp = TempFile::new().unwrap();
+        QcowFile::new(
+            RawFile::new(backing_tmp.as_file().try_clone().unwrap(), false),
+            3,
+            0x10_0000,
+        )
+        .unwrap();
+
+        for version in [2, 3] {
+            let overlay_tmp = TempFile::new().unwrap();
+            QcowFile::new_from_backing(
+                RawFile::new(overlay_tmp.as_file().try_clone().unwrap(), false),
+                version,
+                backing_tmp.as_path(),
+            )
+            .unwrap();
+
+            // The backing file name follows the end of the header extensions, instead of being
+            // read as an extension.
+            let mut raw_file = RawFile::new(overlay_tmp.as_file().try_clone().unwrap(), false);
+            let header = QcowHeader::new(&mut raw_file).unwrap();
+            assert_eq!(
+                header.backing_file_offset,
+                u64::from(header.header_size) + HEADER_EXTENSION_HEADER_SIZE
+            );
+            assert!(header.read_extensions(&mut raw_file).unwrap().is_empty());
+
+            let mut name = vec![0u8; header.backing_file_size as usize];
+            raw_file
+                .seek(SeekFrom::Start(header.backing_file_offset))
+                .unwrap();
+            raw_file.read_exact(&mut name).unwrap();
+            assert_eq!(name, backing_tmp.as_path().as_os_str().as_bytes());
+        }
+    }
+
+    #[test]
+    fn header_extensions_read() {
+        let mut header = valid_header_v3();
+        // An extension of 5 bytes padded to 8, then the end marker.
+        header.extend_from_slice(&[0x68, 0x03, 0xf8, 0x57, 0, 0, 0, 5]);
+        header.extend_from_slice(&[1, 2, 3, 4, 5, 0, 0, 0]);
+        header.extend_from_slice(&[0; 8]);
+        with_basic_file(&header, |mut disk_file: RawFile| {
+            let header = QcowHeader::new(&mut disk_file).unwrap();
+            assert_eq!(
+                header.read_extensions(&mut disk_file).unwrap(),
+                vec![(0x6803_f857, vec![1, 2, 3, 4, 5])]
+            );
+        });
+
+        // An extension overflowing the first cluster is rejected.
+        let mut header = valid_header_v3();
+        header.extend_from_slice(&[0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff]);
+        with_basic_file(&header, |mut disk_file: RawFile| {
+            let header = QcowHeader::new(&mut disk_file).unwrap();
+            assert!(matches!(
+                header.read_extensions(&mut disk_file),
+                Err(Error::InvalidHeaderExtension(104))
+            ));
+        });
+    }
+
+    // Stores `data` as the compressed content of the first cluster of `q`, compressed with the
+    // compression type of its header.
+    fn write_compressed_cluster(q: &mut QcowFile, data: &[u8]) {
+        let compressed = if q.header.compression_type == COMPRESSION_TYPE_ZSTD {
+            zstd::bulk::compress(data, 0).unwrap()
+        } else {
+            let mut compressor = flate2::Compress::new(flate2::Compression::default(), false);
+            let mut compressed = vec![0u8; data.len() * 2];
+            compressor
+                .compress(data, &mut compressed, flate2::FlushCompress::Finish)
+                .unwrap();
+            compressed.truncate(compressor.total_out() as usize);
+            compressed
+        };
+
+        // Use an unaligned offset in a fresh cluster, like clusters packed together do.
+        let host_offset = q.append_data_cluster().unwrap() + 0x200 + 0x10;
+        q.raw_file
+            .file_mut()
+            .seek(SeekFrom::Start(host_offset))
+            .unwrap();
+        q.raw_file.file_mut().write_all(&compressed).unwrap();
+
+        let size_shift = 62 - (q.header.cluster_bits - 8);
+        let sectors = div_round_up_u64(0x10 + compressed.len() as u64, COMPRESSED_SECTOR_SIZE) - 1;
+        let l2_entry = COMPRESSED_FLAG | (sectors << size_shift) | host_offset;
+        let mut set_refcounts = Vec::new();
+        // Load the L2 table shared with the next cluster.
+        q.file_offset_write(q.raw_file.cluster_size()).unwrap();
+        q.update_cluster_addr(0, 0, l2_entry, &mut set_refcounts)
+            .unwrap();
+        for (addr, count) in set_refcounts {
+            q.set_cluster_refcount(addr, count).unwrap();
+        }
+    }
+
+    #[test]
+    fn compressed_cluster_read_write() {
+        let tmp = TempFile::new().unwrap();
+        let cluster_size = 1 << DEFAULT_CLUSTER_BITS;
+        let data: Vec<u8> = (0..cluster_size).map(|i| (i / 0x100) as u8).collect();
+        {
+            let mut q = QcowFile::new(
+                RawFile::new(tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+            write_compressed_cluster(&mut q, &data);
+
+            let mut buf = vec![0u8; cluster_size];
+            q.seek(SeekFrom::Start(0)).unwrap();
+            q.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data);
+            q.flush().unwrap();
+        }
+
+        // The compressed cluster is still there once the image is reopened.
+        let mut q =
+            QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        let mut buf = vec![0u8; 0x100];
+        q.seek(SeekFrom::Start(0x1000)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[0x1000..0x1100]);
+
+        // Writing moves the cluster to uncompressed storage, keeping the rest of its content.
+        q.seek(SeekFrom::Start(0x10)).unwrap();
+        q.write_all(&[0xaau8; 0x10]).unwrap();
+        assert_eq!(q.l2_entry(0).unwrap() & COMPRESSED_FLAG, 0);
+        let mut buf = vec![0u8; cluster_size];
+        q.seek(SeekFrom::Start(0)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0..0x10], data[0..0x10]);
+        assert!(buf[0x10..0x20].iter().all(|b| *b == 0xaa));
+        assert_eq!(buf[0x20..], data[0x20..]);
+    }
+
+    #[test]
+    fn compressed_cluster_read_zstd() {
+        let tmp = TempFile::new().unwrap();
+        let cluster_size = 1 << DEFAULT_CLUSTER_BITS;
+        let data: Vec<u8> = (0..cluster_size).map(|i| (i / 0x100) as u8).collect();
+        {
+            let mut q = QcowFile::new(
+                RawFile::new(tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+
+            // Extend the header with the compression type field, selecting zstd.
+            q.header.header_size = V3_BARE_HEADER_SIZE + 8;
+            q.header.compression_type = COMPRESSION_TYPE_ZSTD;
+            let file = q.raw_file.file_mut();
+            file.seek(SeekFrom::Start(u64::from(V3_BARE_HEADER_SIZE) - 4))
+                .unwrap();
+            file.write_all(&(V3_BARE_HEADER_SIZE + 8).to_be_bytes())
+                .unwrap();
+            file.write_all(&[COMPRESSION_TYPE_ZSTD]).unwrap();
+
+            write_compressed_cluster(&mut q, &data);
+            q.flush().unwrap();
+        }
+
+        // The compression type is read from the header when the image is reopened.
+        let mut q =
+            QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        assert_eq!(q.header.header_size, V3_BARE_HEADER_SIZE + 8);
+        assert_eq!(q.header.compression_type, COMPRESSION_TYPE_ZSTD);
+        assert_ne!(q.l2_entry(0).unwrap() & COMPRESSED_FLAG, 0);
+
+        let mut buf = vec![0u8; cluster_size];
+        q.seek(SeekFrom::Start(0)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn unsupported_compression_type() {
+        let mut header = valid_header_v3();
+        header[103] = 0x70;
+        header.extend_from_slice(&[0x02, 0, 0, 0, 0, 0, 0, 0]);
+        with_basic_file(&header, |disk_file: RawFile| {
+            assert!(matches!(
+                QcowFile::from(disk_file),
+                Err(Error::UnsupportedCompressionType(2))
+            ));
+        });
+    }
+
+    // Reads the byte at the start of the cluster at `cluster_index`.
+    fn read_cluster_byte(q: &mut QcowFile, cluster_index: u64) -> u8 {
+        let mut buf = [0u8; 1];
+        q.seek(SeekFrom::Start(cluster_index * q.raw_file.cluster_size()))
+            .unwrap();
+        q.read_exact(&mut buf).unwrap();
+        buf[0]
+    }
+
+    // Fills the cluster at `cluster_index` with `value`.
+    fn fill_cluster(q: &mut QcowFile, cluster_index: u64, value: u8) {
+        let cluster_size = q.raw_file.cluster_size();
+        q.seek(SeekFrom::Start(cluster_index * cluster_size))
+            .unwrap();
+        q.write_all(&vec![value; cluster_size as usize]).unwrap();
+    }
+
+    #[test]
+    fn snapshot_create_apply_delete() {
+        let tmp = TempFile::new().unwrap();
+        {
+            let mut q = QcowFile::new(
+                RawFile::new(tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+            fill_cluster(&mut q, 0, 0x11);
+            fill_cluster(&mut q, 1, 0x11);
+
+            q.create_snapshot("first").unwrap();
+            assert_eq!(q.snapshots().len(), 1);
+            assert_eq!(q.snapshots()[0].id, "1");
+            assert_eq!(q.snapshots()[0].name, "first");
+            assert_eq!(q.snapshots()[0].disk_size, 0x10_0000);
+            assert!(matches!(
+                q.create_snapshot("first"),
+                Err(Error::SnapshotExists(_))
+            ));
+
+            // Clusters shared with the snapshot are copied on write.
+            let shared_cluster = q.l2_entry(0).unwrap();
+            assert_eq!(q.cluster_refcount(shared_cluster).unwrap(), 2);
+            fill_cluster(&mut q, 0, 0x22);
+            assert_ne!(q.l2_entry(0).unwrap(), shared_cluster);
+            assert_eq!(q.cluster_refcount(shared_cluster).unwrap(), 1);
+            assert_eq!(read_cluster_byte(&mut q, 0), 0x22);
+        }
+
+        let mut q =
+            QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        assert_eq!(q.snapshots().len(), 1);
+        assert_eq!(read_cluster_byte(&mut q, 0), 0x22);
+
+        q.apply_snapshot("first").unwrap();
+        assert_eq!(read_cluster_byte(&mut q, 0), 0x11);
+        assert_eq!(read_cluster_byte(&mut q, 1), 0x11);
+
+        fill_cluster(&mut q, 1, 0x33);
+        assert!(matches!(
+            q.delete_snapshot("second"),
+            Err(Error::SnapshotNotFound(_))
+        ));
+        q.delete_snapshot("1").unwrap();
+        assert!(q.snapshots().is_empty());
+        assert_eq!(q.header.nb_snapshots, 0);
+        assert_eq!(read_cluster_byte(&mut q, 0), 0x11);
+        assert_eq!(read_cluster_byte(&mut q, 1), 0x33);
+
+        // Nothing is shared anymore.
+        let file_size = q.raw_file.file_mut().metadata().unwrap().len();
+        let cluster_size = q.raw_file.cluster_size();
+        for cluster_addr in (0..file_size).step_by(cluster_size as usize) {
+            assert!(q.cluster_refcount(cluster_addr).unwrap() <= 1);
+        }
+    }
+
+    #[test]
+    fn snapshot_rebuild_refcounts() {
+        let tmp = TempFile::new().unwrap();
+        let shared_cluster = {
+            let mut q = QcowFile::new(
+                RawFile::new(tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+            fill_cluster(&mut q, 0, 0x11);
+            q.create_snapshot("first").unwrap();
+            fill_cluster(&mut q, 1, 0x22);
+            q.flush().unwrap();
+            q.l2_entry(0).unwrap()
+        };
+
+        // Force the refcounts to be rebuilt on the next open.
+        let mut header =
+            QcowHeader::new(&mut RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        header.compatible_features |= COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
+        let mut file = RawFile::new(tmp.as_file().try_clone().unwrap(), false);
+        file.seek(SeekFrom::Start(80)).unwrap();
+        file.write_u64::<BigEndian>(header.compatible_features)
+            .unwrap();
+
+        let mut q =
+            QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        let header = QcowHeader::new(&mut file).unwrap();
+        assert_eq!(
+            header.compatible_features & COMPATIBLE_FEATURES_LAZY_REFCOUNTS,
+            0
+        );
+        assert_eq!(q.cluster_refcount(shared_cluster).unwrap(), 2);
+        q.apply_snapshot("first").unwrap();
+        assert_eq!(read_cluster_byte(&mut q, 0), 0x11);
+        assert_eq!(read_cluster_byte(&mut q, 1), 0);
+    }
+
+    #[test]
+    fn resize_grow() {
+        let tmp = TempFile::new().unwrap();
+        // Past 4 TiB, the L1 table doesn't fit in its single cluster anymore.
+        let new_size = 5 << 40;
+        let last_cluster = (new_size >> 16) - 1;
+        {
+            let mut q = QcowFile::new(
+                RawFile::new(tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+            fill_cluster(&mut q, 1, 0x11);
+            let l1_table_offset = q.header.l1_table_offset;
+
+            assert!(matches!(q.resize(0x1000), Err(Error::CannotShrink(_))));
+            q.resize(new_size).unwrap();
+            assert_ne!(q.header.l1_table_offset, l1_table_offset);
+            assert_eq!(q.cluster_refcount(l1_table_offset).unwrap(), 0);
+            assert_eq!(read_cluster_byte(&mut q, 1), 0x11);
+            assert_eq!(read_cluster_byte(&mut q, last_cluster), 0);
+            fill_cluster(&mut q, last_cluster, 0x22);
+        }
+
+        let mut q =
+            QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        assert_eq!(q.virtual_size(), new_size);
+        assert_eq!(q.header.l1_size, 10240);
+        assert_eq!(read_cluster_byte(&mut q, 1), 0x11);
+        assert_eq!(read_cluster_byte(&mut q, last_cluster), 0x22);
+        assert!(q.check(false).unwrap().is_clean());
+    }
+
+    // Points the L2 entry of the cluster at `cluster_index` to `l2_entry`, behind the back of the
+    // file opened with `tmp`.
+    fn corrupt_l2_entry(tmp: &TempFile, cluster_index: u64, l2_entry: u64) {
+        let q = QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        let l2_addr_disk = q.l1_table[0];
+        drop(q);
+        let mut file = RawFile::new(tmp.as_file().try_clone().unwrap(), false);
+        file.seek(SeekFrom::Start(l2_addr_disk + cluster_index * 8))
+            .unwrap();
+        file.write_u64::<BigEndian>(l2_entry).unwrap();
+    }
+
+    #[test]
+    fn check_clean_image() {
+        with_default_file(0x10_0000, false, |mut q| {
+            fill_cluster(&mut q, 0, 0x11);
+            q.create_snapshot("first").unwrap();
+            fill_cluster(&mut q, 1, 0x22);
+            assert!(q.check(false).unwrap().is_clean());
+            assert!(q.check(true).unwrap().is_clean());
+            assert_eq!(read_cluster_byte(&mut q, 0), 0x11);
+            assert_eq!(read_cluster_byte(&mut q, 1), 0x22);
+        });
+    }
+
+    #[test]
+    fn check_repair_leak() {
+        with_default_file(0x10_0000, false, |mut q| {
+            fill_cluster(&mut q, 0, 0x11);
+            let leaked_cluster = q.append_clusters(0x1_0000).unwrap();
+
+            let result = q.check(false).unwrap();
+            assert_eq!(
+                result,
+                CheckResult {
+                    leaks: 1,
+                    ..Default::default()
+                }
+            );
+            assert_eq!(q.check(true).unwrap(), result);
+            assert!(q.check(false).unwrap().is_clean());
+            assert_eq!(q.cluster_refcount(leaked_cluster).unwrap(), 0);
+            assert_eq!(read_cluster_byte(&mut q, 0), 0x11);
+        });
+    }
+
+    #[test]
+    fn check_repair_refcount() {
+        with_default_file(0x10_0000, false, |mut q| {
+            fill_cluster(&mut q, 0, 0x11);
+            let data_cluster = q.l2_entry(0).unwrap();
+            q.set_cluster_refcount(data_cluster, 0).unwrap();
+
+            assert_eq!(
+                q.check(true).unwrap(),
+                CheckResult {
+                    corruptions: 1,
+                    ..Default::default()
+                }
+            );
+            assert!(q.check(false).unwrap().is_clean());
+            assert_eq!(q.cluster_refcount(data_cluster).unwrap(), 1);
+            assert_eq!(read_cluster_byte(&mut q, 0), 0x11);
+        });
+    }
+
+    #[test]
+    fn check_repair_dangling_pointer() {
+        let tmp = TempFile::new().unwrap();
+        {
+            let mut q = QcowFile::new(
+                RawFile::new(tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+            fill_cluster(&mut q, 0, 0x11);
+            fill_cluster(&mut q, 1, 0x22);
+        }
+        corrupt_l2_entry(&tmp, 1, CLUSTER_USED_FLAG | 1 << 40);
+
+        let mut q =
+            QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        // The data cluster previously referenced is leaked.
+        let expected = CheckResult {
+            leaks: 1,
+            dangling_pointers: 1,
+            ..Default::default()
+        };
+        assert_eq!(q.check(false).unwrap(), expected);
+        assert_eq!(q.check(true).unwrap(), expected);
+        assert!(q.check(false).unwrap().is_clean());
+        assert_eq!(read_cluster_byte(&mut q, 0), 0x11);
+        assert_eq!(read_cluster_byte(&mut q, 1), 0);
+    }
+
+    #[test]
+    fn check_repair_overlap() {
+        let tmp = TempFile::new().unwrap();
+        let refcount_table_offset = {
+            let mut q = QcowFile::new(
+                RawFile::new(tmp.as_file().try_clone().unwrap(), false),
+                3,
+                0x10_0000,
+            )
+            .unwrap();
+            fill_cluster(&mut q, 0, 0x11);
+            fill_cluster(&mut q, 1, 0x22);
+            q.header.refcount_table_offset
+        };
+        // Writing to this cluster would overwrite the refcount table.
+        corrupt_l2_entry(&tmp, 1, CLUSTER_USED_FLAG | refcount_table_offset);
+
+        let mut q =
+            QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        let expected = CheckResult {
+            leaks: 1,
+            corruptions: 1,
+            ..Default::default()
+        };
+        assert_eq!(q.check(false).unwrap(), expected);
+        assert_eq!(q.check(true).unwrap(), expected);
+        assert!(q.check(false).unwrap().is_clean());
+        assert_eq!(read_cluster_byte(&mut q, 0), 0x11);
+        assert_eq!(read_cluster_byte(&mut q, 1), 0);
+    }
+
+    #[test]
+    fn check_clears_dirty_flag() {
+        let tmp = TempFile::new().unwrap();
+        QcowFile::new(
+            RawFile::new(tmp.as_file().try_clone().unwrap(), false),
+            3,
+            0x10_0000,
+        )
+        .unwrap();
+        let mut file = RawFile::new(tmp.as_file().try_clone().unwrap(), false);
+        file.seek(SeekFrom::Start(INCOMPATIBLE_FEATURES_OFFSET))
+            .unwrap();
+        file.write_u64::<BigEndian>(INCOMPATIBLE_FEATURES_DIRTY | INCOMPATIBLE_FEATURES_CORRUPT)
+            .unwrap();
+
+        let mut q =
+            QcowFile::from(RawFile::new(tmp.as_file().try_clone().unwrap(), false)).unwrap();
+        assert!(q.header().is_dirty());
+        assert!(q.header().is_corrupt());
+        assert!(q.check(true).unwrap().is_clean());
+        assert!(!q.header().is_dirty());
+        assert!(!q.header().is_corrupt());
+
+        let header = QcowHeader::new(&mut file).unwrap();
+        assert_eq!(header.incompatible_features, 0);
+    }
 }
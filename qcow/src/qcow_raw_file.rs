@@ -2,7 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
-use super::RawFile;
+use super::{RawFile, COMPRESSED_FLAG};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{self, BufWriter, Seek, SeekFrom};
 use std::mem::size_of;
@@ -57,7 +57,8 @@ impl QcowRawFile {
     }
 
     /// Writes `table` of u64 pointers to `offset` in the file.
-    /// `non_zero_flags` will be ORed with all non-zero values in `table`.
+    /// `non_zero_flags` will be ORed with all non-zero values in `table`, except for compressed
+    /// cluster descriptors which are written unchanged.
     /// writing.
     pub fn write_pointer_table(
         &mut self,
@@ -68,8 +69,8 @@ impl QcowRawFile {
         self.file.seek(SeekFrom::Start(offset))?;
         let mut buffer = BufWriter::with_capacity(table.len() * size_of::<u64>(), &mut self.file);
         for addr in table {
-            let val = if *addr == 0 {
-                0
+            let val = if *addr == 0 || *addr & COMPRESSED_FLAG != 0 {
+                *addr
             } else {
                 *addr | non_zero_flags
             };
@@ -115,6 +116,10 @@ impl QcowRawFile {
     }
 
     /// Returns a mutable reference to the underlying file.
+    pub fn file(&self) -> &RawFile {
+        &self.file
+    }
+
     pub fn file_mut(&mut self) -> &mut RawFile {
         &mut self.file
     }
@@ -109,6 +109,11 @@ impl RawFile {
         self.file.sync_all()
     }
 
+    /// Returns the underlying file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
     pub fn sync_data(&self) -> std::io::Result<()> {
         self.file.sync_data()
     }
@@ -351,3 +356,9 @@ impl Clone for RawFile {
         }
     }
 }
+
+impl AsRawFd for RawFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// Size of the fixed part of a snapshot table entry.
+const SNAPSHOT_ENTRY_HEADER_SIZE: u64 = 40;
+// Size of the extra data written for new snapshots, holding the 64 bits VM state size and the
+// disk size.
+const SNAPSHOT_EXTRA_DATA_SIZE: u32 = 16;
+// Same limit as qemu, it avoids allocating huge buffers for corrupt images.
+const MAX_SNAPSHOT_EXTRA_DATA_SIZE: u32 = 1024;
+
+/// An internal snapshot of a qcow image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QcowSnapshot {
+    /// Unique identifier of the snapshot in the image.
+    pub id: String,
+    /// Name given to the snapshot when it was created.
+    pub name: String,
+    /// Seconds part of the time the snapshot was created at, since the epoch.
+    pub date_sec: u32,
+    /// Nanoseconds part of the time the snapshot was created at.
+    pub date_nsec: u32,
+    /// Time the guest had been running for when the snapshot was created, in nanoseconds.
+    pub vm_clock_nsec: u64,
+    /// Size of the VM state saved along the snapshot.
+    pub vm_state_size: u64,
+    /// Virtual size of the disk when the snapshot was created.
+    pub disk_size: u64,
+    pub(crate) l1_table_offset: u64,
+    pub(crate) l1_size: u32,
+    // Extra data past the fields known to this implementation, kept as is.
+    extra_data: Vec<u8>,
+}
+
+impl QcowSnapshot {
+    pub(crate) fn new(
+        id: String,
+        name: String,
+        date_sec: u32,
+        date_nsec: u32,
+        disk_size: u64,
+        l1_table_offset: u64,
+        l1_size: u32,
+    ) -> QcowSnapshot {
+        QcowSnapshot {
+            id,
+            name,
+            date_sec,
+            date_nsec,
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            disk_size,
+            l1_table_offset,
+            l1_size,
+            extra_data: Vec::new(),
+        }
+    }
+
+    // Size of the entry of this snapshot in the snapshot table, without padding.
+    fn unpadded_entry_size(&self) -> u64 {
+        SNAPSHOT_ENTRY_HEADER_SIZE
+            + u64::from(SNAPSHOT_EXTRA_DATA_SIZE)
+            + self.extra_data.len() as u64
+            + self.id.len() as u64
+            + self.name.len() as u64
+    }
+
+    // Size of the entry of this snapshot in the snapshot table. Entries are 8 bytes aligned.
+    fn entry_size(&self) -> u64 {
+        align_entry_size(self.unpadded_entry_size())
+    }
+
+    // Reads a snapshot table entry, returns the snapshot along with the size of the entry.
+    fn read_from<F: Read>(f: &mut F, disk_size: u64) -> io::Result<(QcowSnapshot, u64)> {
+        let l1_table_offset = f.read_u64::<BigEndian>()?;
+        let l1_size = f.read_u32::<BigEndian>()?;
+        let id_size = f.read_u16::<BigEndian>()?;
+        let name_size = f.read_u16::<BigEndian>()?;
+        let date_sec = f.read_u32::<BigEndian>()?;
+        let date_nsec = f.read_u32::<BigEndian>()?;
+        let vm_clock_nsec = f.read_u64::<BigEndian>()?;
+        let vm_state_size = f.read_u32::<BigEndian>()?;
+        let extra_data_size = f.read_u32::<BigEndian>()?;
+        if extra_data_size > MAX_SNAPSHOT_EXTRA_DATA_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("snapshot extra data is too large: {}", extra_data_size),
+            ));
+        }
+
+        let mut extra_data = vec![0u8; extra_data_size as usize];
+        f.read_exact(&mut extra_data)?;
+        let mut id = vec![0u8; id_size as usize];
+        f.read_exact(&mut id)?;
+        let mut name = vec![0u8; name_size as usize];
+        f.read_exact(&mut name)?;
+
+        // Older images lack the extra data, the 32 bits VM state size and the current disk size
+        // apply then.
+        let mut snapshot = QcowSnapshot {
+            id: String::from_utf8_lossy(&id).into_owned(),
+            name: String::from_utf8_lossy(&name).into_owned(),
+            date_sec,
+            date_nsec,
+            vm_clock_nsec,
+            vm_state_size: u64::from(vm_state_size),
+            disk_size,
+            l1_table_offset,
+            l1_size,
+            extra_data: Vec::new(),
+        };
+        let mut extra_data = &extra_data[..];
+        if extra_data.len() >= 8 {
+            snapshot.vm_state_size = extra_data.read_u64::<BigEndian>()?;
+        }
+        if extra_data.len() >= 8 {
+            snapshot.disk_size = extra_data.read_u64::<BigEndian>()?;
+        }
+        // Anything shorter than the known fields is dropped, the entry is rewritten with the full
+        // extra data.
+        if extra_data_size >= SNAPSHOT_EXTRA_DATA_SIZE {
+            snapshot.extra_data = extra_data.to_vec();
+        }
+
+        let entry_size = align_entry_size(
+            SNAPSHOT_ENTRY_HEADER_SIZE
+                + u64::from(extra_data_size)
+                + u64::from(id_size)
+                + u64::from(name_size),
+        );
+        Ok((snapshot, entry_size))
+    }
+
+    fn write_to<F: Write>(&self, f: &mut F) -> io::Result<()> {
+        f.write_u64::<BigEndian>(self.l1_table_offset)?;
+        f.write_u32::<BigEndian>(self.l1_size)?;
+        f.write_u16::<BigEndian>(self.id.len() as u16)?;
+        f.write_u16::<BigEndian>(self.name.len() as u16)?;
+        f.write_u32::<BigEndian>(self.date_sec)?;
+        f.write_u32::<BigEndian>(self.date_nsec)?;
+        f.write_u64::<BigEndian>(self.vm_clock_nsec)?;
+        f.write_u32::<BigEndian>(self.vm_state_size.min(u64::from(u32::MAX)) as u32)?;
+        f.write_u32::<BigEndian>(SNAPSHOT_EXTRA_DATA_SIZE + self.extra_data.len() as u32)?;
+        f.write_u64::<BigEndian>(self.vm_state_size)?;
+        f.write_u64::<BigEndian>(self.disk_size)?;
+        f.write_all(&self.extra_data)?;
+        f.write_all(self.id.as_bytes())?;
+        f.write_all(self.name.as_bytes())?;
+
+        let padding = [0u8; 8];
+        f.write_all(&padding[..(self.entry_size() - self.unpadded_entry_size()) as usize])
+    }
+}
+
+fn align_entry_size(size: u64) -> u64 {
+    (size + 7) & !7
+}
+
+/// Reads the `count` entries of the snapshot table found at `offset`. `disk_size` is used for
+/// snapshots which don't record the size of the disk.
+pub(crate) fn read_snapshot_table<F: Read + Seek>(
+    f: &mut F,
+    offset: u64,
+    count: u32,
+    disk_size: u64,
+) -> io::Result<Vec<QcowSnapshot>> {
+    let mut snapshots = Vec::with_capacity(count as usize);
+    let mut entry_offset = offset;
+    for _ in 0..count {
+        f.seek(SeekFrom::Start(entry_offset))?;
+        let (snapshot, entry_size) = QcowSnapshot::read_from(f, disk_size)?;
+        entry_offset += entry_size;
+        snapshots.push(snapshot);
+    }
+    Ok(snapshots)
+}
+
+/// Writes `snapshots` as a snapshot table at `offset`.
+pub(crate) fn write_snapshot_table<F: Write + Seek>(
+    f: &mut F,
+    offset: u64,
+    snapshots: &[QcowSnapshot],
+) -> io::Result<()> {
+    f.seek(SeekFrom::Start(offset))?;
+    for snapshot in snapshots {
+        snapshot.write_to(f)?;
+    }
+    Ok(())
+}
+
+/// Returns the size in bytes of the snapshot table holding `snapshots`.
+pub(crate) fn snapshot_table_size(snapshots: &[QcowSnapshot]) -> u64 {
+    snapshots.iter().map(|s| s.entry_size()).sum()
+}
@@ -0,0 +1,476 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! # Rate Limiter Group
+//!
+//! Provides a `RateLimiter` shared by several users, each of them consuming
+//! tokens from the same buckets through its own `RateLimiterGroupHandle`.
+//! This allows a single budget to be enforced on a set of devices, whatever
+//! the threads running them.
+//!
+//! The group runs a thread waiting for the timer of the shared `RateLimiter`.
+//! Every time the buckets are refilled, all the handles blocked by the limiter
+//! are woken up through the FD provided by their `AsRawFd` trait
+//! implementation, and compete for the new tokens. The group doesn't share
+//! the budget fairly between its users.
+
+use crate::{BucketUpdate, Error, RateLimiter, TokenType};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+// Notifier of a handle, along with whether the handle is waiting for the
+// limiter to be unblocked.
+struct Waiter {
+    evt: EventFd,
+    waiting: AtomicBool,
+}
+
+struct RateLimiterGroupInner {
+    id: String,
+    rate_limiter: Mutex<RateLimiter>,
+    waiters: Mutex<Vec<Arc<Waiter>>>,
+}
+
+impl RateLimiterGroupInner {
+    fn refill(&self) {
+        // The limiter lock is held while the waiters are picked, so that a
+        // handle getting blocked concurrently is either unblocked already or
+        // flagged as waiting.
+        let mut rate_limiter = self.rate_limiter.lock().unwrap();
+        if let Err(e) = rate_limiter.event_handler() {
+            error!("Failed refilling rate limit group {}: {:?}", self.id, e);
+            return;
+        }
+
+        for waiter in self.waiters.lock().unwrap().iter() {
+            if waiter.waiting.swap(false, Ordering::AcqRel) {
+                if let Err(e) = waiter.evt.write(1) {
+                    error!(
+                        "Failed waking up user of rate limit group {}: {:?}",
+                        self.id, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Rate limiter whose buckets are shared by all the handles created from it.
+pub struct RateLimiterGroup {
+    inner: Arc<RateLimiterGroupInner>,
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl RateLimiterGroup {
+    /// Creates a new group identified by `id`, along with the thread
+    /// refilling its buckets.
+    ///
+    /// The buckets are described by the same arguments as for
+    /// `RateLimiter::new()`.
+    ///
+    /// # Errors
+    ///
+    /// If the timerfd, the eventfd or the thread creation fails, an error is
+    /// returned.
+    pub fn new(
+        id: &str,
+        bytes_total_capacity: u64,
+        bytes_one_time_burst: u64,
+        bytes_complete_refill_time_ms: u64,
+        ops_total_capacity: u64,
+        ops_one_time_burst: u64,
+        ops_complete_refill_time_ms: u64,
+    ) -> io::Result<Self> {
+        let rate_limiter = RateLimiter::new(
+            bytes_total_capacity,
+            bytes_one_time_burst,
+            bytes_complete_refill_time_ms,
+            ops_total_capacity,
+            ops_one_time_burst,
+            ops_complete_refill_time_ms,
+        )?;
+        let timer_fd = rate_limiter.as_raw_fd();
+        let inner = Arc::new(RateLimiterGroupInner {
+            id: id.to_owned(),
+            rate_limiter: Mutex::new(rate_limiter),
+            waiters: Mutex::new(Vec::new()),
+        });
+
+        let kill_evt = EventFd::new(EFD_NONBLOCK)?;
+        let thread_kill_evt = kill_evt.try_clone()?;
+        let thread_inner = inner.clone();
+        let thread = thread::Builder::new()
+            .name(format!("rate-limit-{}", id))
+            .spawn(move || {
+                let mut fds = [
+                    libc::pollfd {
+                        fd: timer_fd,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    },
+                    libc::pollfd {
+                        fd: thread_kill_evt.as_raw_fd(),
+                        events: libc::POLLIN,
+                        revents: 0,
+                    },
+                ];
+                loop {
+                    // Safe because the file descriptors are valid as long
+                    // as the thread runs, and the array is large enough.
+                    let ret =
+                        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+                    if ret < 0 {
+                        let e = io::Error::last_os_error();
+                        if e.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        error!(
+                            "Failed waiting for rate limit group {}: {:?}",
+                            thread_inner.id, e
+                        );
+                        break;
+                    }
+                    if fds[1].revents != 0 {
+                        break;
+                    }
+                    if fds[0].revents != 0 {
+                        thread_inner.refill();
+                    }
+                }
+            })?;
+
+        Ok(RateLimiterGroup {
+            inner,
+            kill_evt,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the identifier of the group.
+    pub fn id(&self) -> &str {
+        &self.inner.id
+    }
+
+    /// Creates a new handle consuming tokens from the buckets of the group.
+    ///
+    /// # Errors
+    ///
+    /// If the eventfd creation fails, an error is returned.
+    pub fn new_handle(&self) -> io::Result<RateLimiterGroupHandle> {
+        let waiter = Arc::new(Waiter {
+            evt: EventFd::new(EFD_NONBLOCK)?,
+            waiting: AtomicBool::new(false),
+        });
+        self.inner.waiters.lock().unwrap().push(waiter.clone());
+
+        Ok(RateLimiterGroupHandle {
+            inner: self.inner.clone(),
+            waiter,
+        })
+    }
+
+    /// Updates the parameters of the token buckets shared by the group.
+    pub fn update_buckets(&self, bytes: BucketUpdate, ops: BucketUpdate) {
+        self.inner
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .update_buckets(bytes, ops)
+    }
+}
+
+impl Drop for RateLimiterGroup {
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Failed joining rate limit group {} thread", self.inner.id);
+            }
+        }
+    }
+}
+
+/// Handle through which a single user consumes tokens from the buckets of a
+/// `RateLimiterGroup`.
+///
+/// The handle provides the same interface as a `RateLimiter`: the user shall
+/// call the `event_handler()` method on every event on the FD provided by its
+/// `AsRawFd` trait implementation.
+pub struct RateLimiterGroupHandle {
+    inner: Arc<RateLimiterGroupInner>,
+    waiter: Arc<Waiter>,
+}
+
+impl RateLimiterGroupHandle {
+    /// Attempts to consume tokens from the group and returns whether that is
+    /// possible.
+    ///
+    /// If rate limiting is disabled on provided `token_type`, this function
+    /// will always succeed.
+    pub fn consume(&mut self, tokens: u64, token_type: TokenType) -> bool {
+        let mut rate_limiter = self.inner.rate_limiter.lock().unwrap();
+        let consumed = rate_limiter.consume(tokens, token_type);
+        // The limiter might also be blocked after consuming more tokens than
+        // the size of a bucket.
+        if rate_limiter.is_blocked() {
+            self.waiter.waiting.store(true, Ordering::Release);
+        }
+        consumed
+    }
+
+    /// Adds tokens of `token_type` to their respective bucket of the group.
+    pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
+        self.inner
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .manual_replenish(tokens, token_type)
+    }
+
+    /// Returns whether the group is blocked, in which case an event will be
+    /// generated on the exported FD when the group 'unblocks'.
+    pub fn is_blocked(&self) -> bool {
+        let rate_limiter = self.inner.rate_limiter.lock().unwrap();
+        let blocked = rate_limiter.is_blocked();
+        if blocked {
+            self.waiter.waiting.store(true, Ordering::Release);
+        }
+        blocked
+    }
+
+    /// This function needs to be called every time there is an event on the
+    /// FD provided by this object's `AsRawFd` trait implementation.
+    ///
+    /// # Errors
+    ///
+    /// If the handle hasn't been woken up, an error is returned.
+    pub fn event_handler(&mut self) -> Result<(), Error> {
+        match self.waiter.evt.read() {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(Error::SpuriousRateLimiterEvent(
+                    "Rate limit group event handler called without a refill",
+                ))
+            }
+            Err(e) => Err(Error::EventFdReadError(e)),
+        }
+    }
+
+    /// Returns the identifier of the group the handle belongs to.
+    pub fn group_id(&self) -> &str {
+        &self.inner.id
+    }
+}
+
+impl AsRawFd for RateLimiterGroupHandle {
+    /// Provides a FD which needs to be monitored for POLLIN events.
+    ///
+    /// This object's `event_handler()` method must be called on such events.
+    fn as_raw_fd(&self) -> RawFd {
+        self.waiter.evt.as_raw_fd()
+    }
+}
+
+impl Drop for RateLimiterGroupHandle {
+    fn drop(&mut self) {
+        self.inner
+            .waiters
+            .lock()
+            .unwrap()
+            .retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+    }
+}
+
+/// Rate limiter of a single user, which either has token buckets of its own,
+/// or consumes tokens from the buckets of a `RateLimiterGroup`.
+pub enum QueueRateLimiter {
+    /// Rate limiter with its own token buckets.
+    Own(RateLimiter),
+    /// Handle on the token buckets of a rate limit group.
+    Group(RateLimiterGroupHandle),
+}
+
+impl QueueRateLimiter {
+    /// Attempts to consume tokens and returns whether that is possible.
+    ///
+    /// If rate limiting is disabled on provided `token_type`, this function
+    /// will always succeed.
+    pub fn consume(&mut self, tokens: u64, token_type: TokenType) -> bool {
+        match self {
+            QueueRateLimiter::Own(rate_limiter) => rate_limiter.consume(tokens, token_type),
+            QueueRateLimiter::Group(handle) => handle.consume(tokens, token_type),
+        }
+    }
+
+    /// Adds tokens of `token_type` to their respective bucket.
+    pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
+        match self {
+            QueueRateLimiter::Own(rate_limiter) => {
+                rate_limiter.manual_replenish(tokens, token_type)
+            }
+            QueueRateLimiter::Group(handle) => handle.manual_replenish(tokens, token_type),
+        }
+    }
+
+    /// Returns whether the rate limiter is blocked, in which case an event
+    /// will be generated on the exported FD when it 'unblocks'.
+    pub fn is_blocked(&self) -> bool {
+        match self {
+            QueueRateLimiter::Own(rate_limiter) => rate_limiter.is_blocked(),
+            QueueRateLimiter::Group(handle) => handle.is_blocked(),
+        }
+    }
+
+    /// This function needs to be called every time there is an event on the
+    /// FD provided by this object's `AsRawFd` trait implementation.
+    ///
+    /// # Errors
+    ///
+    /// If the rate limiter isn't unblocked, an error is returned.
+    pub fn event_handler(&mut self) -> Result<(), Error> {
+        match self {
+            QueueRateLimiter::Own(rate_limiter) => rate_limiter.event_handler(),
+            QueueRateLimiter::Group(handle) => handle.event_handler(),
+        }
+    }
+}
+
+impl AsRawFd for QueueRateLimiter {
+    /// Provides a FD which needs to be monitored for POLLIN events.
+    ///
+    /// This object's `event_handler()` method must be called on such events.
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            QueueRateLimiter::Own(rate_limiter) => rate_limiter.as_raw_fd(),
+            QueueRateLimiter::Group(handle) => handle.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Waits up to `timeout_ms` for the FD to be readable.
+    fn wait_readable(fd: RawFd, timeout_ms: i32) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because the FD is valid and only one pollfd is passed.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        assert!(ret >= 0);
+        ret == 1 && pollfd.revents & libc::POLLIN != 0
+    }
+
+    #[test]
+    fn test_rate_limiter_group_shared_budget() {
+        let group = RateLimiterGroup::new("group0", 1000, 0, 1000, 10, 0, 1000).unwrap();
+        assert_eq!(group.id(), "group0");
+        let mut handle0 = group.new_handle().unwrap();
+        let mut handle1 = group.new_handle().unwrap();
+        assert_eq!(handle1.group_id(), "group0");
+
+        // Both handles consume from the same buckets.
+        assert!(handle0.consume(600, TokenType::Bytes));
+        assert!(!handle1.consume(600, TokenType::Bytes));
+        assert!(handle0.is_blocked());
+        assert!(handle1.is_blocked());
+
+        // Tokens given back to the group can't be consumed until it unblocks.
+        handle1.manual_replenish(600, TokenType::Bytes);
+        assert!(!handle0.consume(1, TokenType::Ops));
+    }
+
+    #[test]
+    fn test_rate_limiter_group_wake_up() {
+        let group = RateLimiterGroup::new("group0", 1000, 0, 1000, 0, 0, 0).unwrap();
+        let mut handle0 = group.new_handle().unwrap();
+        let mut handle1 = group.new_handle().unwrap();
+        let mut handle2 = group.new_handle().unwrap();
+
+        assert!(handle0.consume(1000, TokenType::Bytes));
+        assert!(!handle0.consume(100, TokenType::Bytes));
+        // The second handle waits for the group to unblock, while the third
+        // one isn't interested.
+        assert!(handle1.is_blocked());
+        assert!(handle0.event_handler().is_err());
+
+        // The refill timer fires after 100ms, waking up the handles waiting
+        // for the group.
+        assert!(wait_readable(handle0.as_raw_fd(), 5000));
+        assert!(wait_readable(handle1.as_raw_fd(), 5000));
+        assert!(!wait_readable(handle2.as_raw_fd(), 0));
+        assert!(!handle2.is_blocked());
+        assert!(handle0.event_handler().is_ok());
+        assert!(handle1.event_handler().is_ok());
+        assert!(handle2.event_handler().is_err());
+        assert!(handle0.consume(100, TokenType::Bytes));
+
+        // A dropped handle isn't woken up anymore.
+        drop(handle1);
+        assert_eq!(group.inner.waiters.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_rate_limiter_group_update_buckets() {
+        let group = RateLimiterGroup::new("group0", 1000, 0, 1000, 0, 0, 0).unwrap();
+        let mut handle = group.new_handle().unwrap();
+
+        assert!(handle.consume(1000, TokenType::Bytes));
+        group.update_buckets(BucketUpdate::Disabled, BucketUpdate::None);
+        assert!(handle.consume(1000, TokenType::Bytes));
+    }
+
+    #[test]
+    fn test_queue_rate_limiter() {
+        let group = RateLimiterGroup::new("group0", 1000, 0, 1000, 0, 0, 0).unwrap();
+        let mut own = QueueRateLimiter::Own(RateLimiter::new(1000, 0, 1000, 0, 0, 0).unwrap());
+        let mut shared = QueueRateLimiter::Group(group.new_handle().unwrap());
+
+        for rate_limiter in [&mut own, &mut shared] {
+            assert!(rate_limiter.consume(1000, TokenType::Bytes));
+            assert!(rate_limiter.consume(1, TokenType::Ops));
+            assert!(!rate_limiter.consume(100, TokenType::Bytes));
+            assert!(rate_limiter.is_blocked());
+            assert!(wait_readable(rate_limiter.as_raw_fd(), 5000));
+            assert!(rate_limiter.event_handler().is_ok());
+            assert!(!rate_limiter.is_blocked());
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_group_update_unlimited() {
+        // A group created without any limit can be limited later on.
+        let group = RateLimiterGroup::new("group0", 0, 0, 0, 0, 0, 0).unwrap();
+        let mut handle = group.new_handle().unwrap();
+        assert!(handle.consume(u64::MAX, TokenType::Bytes));
+        assert!(!handle.is_blocked());
+
+        for _ in 0..2 {
+            group.update_buckets(
+                BucketUpdate::Update(crate::TokenBucket::new(1000, 0, 1000).unwrap()),
+                BucketUpdate::None,
+            );
+            assert!(handle.consume(1000, TokenType::Bytes));
+            assert!(!handle.consume(1000, TokenType::Bytes));
+            assert!(handle.is_blocked());
+
+            // The handle waiting for the group is woken up by the next
+            // refill once the limit is removed.
+            group.update_buckets(BucketUpdate::Disabled, BucketUpdate::None);
+            assert!(wait_readable(handle.as_raw_fd(), 5000));
+            assert!(handle.event_handler().is_ok());
+            assert!(!handle.is_blocked());
+            assert!(handle.consume(u64::MAX, TokenType::Bytes));
+        }
+    }
+}
@@ -51,6 +51,8 @@ use std::time::{Duration, Instant};
 use std::{fmt, io};
 use vmm_sys_util::timerfd::TimerFd;
 
+pub mod group;
+
 #[derive(Debug)]
 /// Describes the errors that may occur while handling rate limiter events.
 pub enum Error {
@@ -58,6 +60,8 @@ pub enum Error {
     SpuriousRateLimiterEvent(&'static str),
     /// The event handler encounters while TimerFd::wait()
     TimerFdWaitError(std::io::Error),
+    /// The event handler encounters while EventFd::read()
+    EventFdReadError(std::io::Error),
 }
 
 // Interval at which the refill timer will run when limiter is at capacity.
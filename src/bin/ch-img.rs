@@ -0,0 +1,577 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+#[macro_use(crate_authors)]
+extern crate clap;
+
+use block_util::backup::{BackupReader, BACKUP_EXTENT_MAX_SIZE};
+use block_util::dynamic_vhd::DynamicVhd;
+use block_util::vhd::VhdFooter;
+use block_util::{detect_image_type, ImageType};
+use clap::{Arg, ArgMatches, Command};
+use option_parser::{ByteSized, ByteSizedParseError};
+use qcow::{BackingFilePolicy, QcowFile, RawFile};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use vhdx::vhdx::{ParentPolicy, Vhdx, VhdxError};
+
+// Size of the chunks images are copied and checked by.
+const CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Debug)]
+enum Error {
+    BackingFileNotSupported(String),
+    CreateImage(io::Error),
+    DetectImageType(io::Error),
+    InconsistentImage,
+    InvalidSize(ByteSizedParseError),
+    MissingSize,
+    OpenImage(PathBuf, io::Error),
+    Qcow(qcow::Error),
+    ReadBackup(io::Error),
+    ReadImage(io::Error),
+    ResizeNotSupported(&'static str),
+    ShrinkNotSupported,
+    UnsupportedFormat(String),
+    Vhd(io::Error),
+    Vhdx(VhdxError),
+    WriteImage(io::Error),
+    WriteNotSupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            BackingFileNotSupported(format) => {
+                write!(f, "Backing files are not supported by {} images", format)
+            }
+            CreateImage(e) => write!(f, "Error creating image: {}", e),
+            DetectImageType(e) => write!(f, "Error detecting image format: {}", e),
+            InconsistentImage => write!(
+                f,
+                "Inconsistencies were found on the image, use --repair to fix them"
+            ),
+            InvalidSize(e) => write!(f, "Error parsing size: {:?}", e),
+            MissingSize => write!(f, "Image size is required without a backing file"),
+            OpenImage(path, e) => write!(f, "Error opening {}: {}", path.display(), e),
+            Qcow(e) => write!(f, "Error handling qcow2 image: {}", e),
+            ReadBackup(e) => write!(f, "Error reading backup: {}", e),
+            ReadImage(e) => write!(f, "Error reading image: {}", e),
+            ResizeNotSupported(format) => write!(f, "Can't resize {} images", format),
+            ShrinkNotSupported => write!(f, "Shrinking images is not supported"),
+            UnsupportedFormat(format) => write!(f, "Unsupported image format: {}", format),
+            Vhd(e) => write!(f, "Error handling VHD image: {}", e),
+            Vhdx(e) => write!(f, "Error handling VHDx image: {}", e),
+            WriteImage(e) => write!(f, "Error writing image: {}", e),
+            WriteNotSupported(format) => write!(f, "Can't write to {} images", format),
+        }
+    }
+}
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+trait WriteSeek: Write + Seek {}
+impl<T: Write + Seek> WriteSeek for T {}
+
+/// Disk image opened through the same code the VMM uses, along with its backing files.
+enum Image {
+    Raw(File),
+    Qcow2(QcowFile),
+    Vhdx(Vhdx),
+    FixedVhd(File, u64),
+    DynamicVhd(DynamicVhd),
+}
+
+impl Image {
+    fn open(path: &Path, writable: bool) -> Result<Image, Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .map_err(|e| Error::OpenImage(path.to_path_buf(), e))?;
+
+        Ok(
+            match detect_image_type(&mut file).map_err(Error::DetectImageType)? {
+                ImageType::Raw => Image::Raw(file),
+                ImageType::Qcow2 => Image::Qcow2(
+                    QcowFile::from_with_backing_file(
+                        RawFile::new(file, false),
+                        &BackingFilePolicy::Header,
+                    )
+                    .map_err(Error::Qcow)?,
+                ),
+                ImageType::Vhdx => Image::Vhdx(
+                    Vhdx::with_parent_policy(file, &ParentPolicy::Locator).map_err(Error::Vhdx)?,
+                ),
+                ImageType::FixedVhd => {
+                    let size = VhdFooter::new(&mut file)
+                        .map_err(Error::Vhd)?
+                        .current_size();
+                    Image::FixedVhd(file, size)
+                }
+                ImageType::DynamicVhd => Image::DynamicVhd(
+                    DynamicVhd::new(file, false, &BackingFilePolicy::Header).map_err(Error::Vhd)?,
+                ),
+            },
+        )
+    }
+
+    fn format(&self) -> &'static str {
+        match self {
+            Image::Raw(_) => "raw",
+            Image::Qcow2(_) => "qcow2",
+            Image::Vhdx(_) => "vhdx",
+            Image::FixedVhd(..) | Image::DynamicVhd(_) => "vhd",
+        }
+    }
+
+    fn virtual_size(&mut self) -> Result<u64, Error> {
+        Ok(match self {
+            Image::Raw(file) => file.metadata().map_err(Error::ReadImage)?.len(),
+            Image::Qcow2(qcow) => qcow.header().size,
+            Image::Vhdx(vhdx) => vhdx.virtual_disk_size(),
+            Image::FixedVhd(_, size) => *size,
+            Image::DynamicVhd(vhd) => vhd.virtual_disk_size(),
+        })
+    }
+
+    fn backing_file(&self) -> Option<&Path> {
+        match self {
+            Image::Qcow2(qcow) => qcow.backing_file_path(),
+            Image::Vhdx(vhdx) => vhdx.parent_path(),
+            Image::DynamicVhd(vhd) => vhd.parent_path(),
+            Image::Raw(_) | Image::FixedVhd(..) => None,
+        }
+    }
+
+    fn reader(&mut self) -> &mut dyn ReadSeek {
+        match self {
+            // The footer of fixed VHDs follows the data, it is never reached when reading the
+            // virtual disk.
+            Image::Raw(file) | Image::FixedVhd(file, _) => file,
+            Image::Qcow2(qcow) => qcow,
+            Image::Vhdx(vhdx) => vhdx,
+            Image::DynamicVhd(vhd) => vhd,
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut dyn WriteSeek, Error> {
+        match self {
+            Image::Raw(file) => Ok(file),
+            Image::Qcow2(qcow) => Ok(qcow),
+            Image::Vhdx(vhdx) => Ok(vhdx),
+            Image::FixedVhd(..) | Image::DynamicVhd(_) => {
+                Err(Error::WriteNotSupported(self.format()))
+            }
+        }
+    }
+
+    fn resize(&mut self, size: u64) -> Result<(), Error> {
+        if size < self.virtual_size()? {
+            return Err(Error::ShrinkNotSupported);
+        }
+
+        match self {
+            Image::Raw(file) => file.set_len(size).map_err(Error::WriteImage),
+            Image::Qcow2(qcow) => qcow.resize(size).map_err(Error::Qcow),
+            Image::Vhdx(vhdx) => vhdx.resize(size).map_err(Error::Vhdx),
+            Image::FixedVhd(..) | Image::DynamicVhd(_) => {
+                Err(Error::ResizeNotSupported(self.format()))
+            }
+        }
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64, Error> {
+    Ok(size.parse::<ByteSized>().map_err(Error::InvalidSize)?.0)
+}
+
+// Creates the image file, refusing to overwrite an existing one.
+fn create_file(path: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(Error::CreateImage)
+}
+
+// Creates an empty image of `size` bytes and returns it for writing its content.
+fn create_image(path: &Path, format: &str, size: u64) -> Result<Box<dyn WriteSeek>, Error> {
+    match format {
+        "raw" => {
+            let file = create_file(path)?;
+            file.set_len(size).map_err(Error::CreateImage)?;
+            Ok(Box::new(file))
+        }
+        "qcow2" => Ok(Box::new(
+            QcowFile::new(RawFile::new(create_file(path)?, false), 3, size).map_err(Error::Qcow)?,
+        )),
+        "vhdx" => Ok(Box::new(
+            Vhdx::create(create_file(path)?, size).map_err(Error::Vhdx)?,
+        )),
+        format => Err(Error::UnsupportedFormat(format.to_string())),
+    }
+}
+
+fn create_command(
+    path: &Path,
+    format: &str,
+    size: Option<&str>,
+    backing_file: Option<&str>,
+) -> Result<(), Error> {
+    let size = size.map(parse_size).transpose()?;
+
+    match backing_file {
+        Some(backing_file) => {
+            if format != "qcow2" {
+                return Err(Error::BackingFileNotSupported(format.to_string()));
+            }
+            let mut qcow = QcowFile::new_from_backing(
+                RawFile::new(create_file(path)?, false),
+                3,
+                Path::new(backing_file),
+            )
+            .map_err(Error::Qcow)?;
+            if let Some(size) = size {
+                if size < qcow.header().size {
+                    return Err(Error::ShrinkNotSupported);
+                }
+                qcow.resize(size).map_err(Error::Qcow)?;
+            }
+        }
+        None => {
+            create_image(path, format, size.ok_or(Error::MissingSize)?)?;
+        }
+    }
+
+    File::open(path)
+        .and_then(|f| f.sync_all())
+        .map_err(Error::CreateImage)
+}
+
+fn info_command(path: &Path, json: bool) -> Result<(), Error> {
+    let mut images = Vec::new();
+    let mut next = Some(path.to_path_buf());
+    while let Some(path) = next {
+        let mut image = Image::open(&path, false)?;
+        let disk_size = File::open(&path)
+            .and_then(|f| f.metadata())
+            .map_err(|e| Error::OpenImage(path.clone(), e))?
+            .blocks()
+            * 512;
+
+        next = image.backing_file().map(Path::to_path_buf);
+        images.push(serde_json::json!({
+            "filename": path,
+            "format": image.format(),
+            "virtual-size": image.virtual_size()?,
+            "actual-size": disk_size,
+            "backing-filename": next,
+        }));
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&images).unwrap());
+        return Ok(());
+    }
+
+    for (i, image) in images.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("image: {}", image["filename"].as_str().unwrap_or_default());
+        println!(
+            "file format: {}",
+            image["format"].as_str().unwrap_or_default()
+        );
+        println!("virtual size: {} bytes", image["virtual-size"]);
+        println!("disk size: {} bytes", image["actual-size"]);
+        if let Some(backing_file) = image["backing-filename"].as_str() {
+            println!("backing file: {}", backing_file);
+        }
+    }
+
+    Ok(())
+}
+
+fn convert_command(source: &Path, destination: &Path, format: &str) -> Result<(), Error> {
+    let mut source = Image::open(source, false)?;
+    let size = source.virtual_size()?;
+    let mut destination_image = create_image(destination, format, size)?;
+
+    let reader = source.reader();
+    reader.seek(SeekFrom::Start(0)).map_err(Error::ReadImage)?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let count = std::cmp::min(size - offset, CHUNK_SIZE as u64) as usize;
+        reader
+            .read_exact(&mut buf[..count])
+            .map_err(Error::ReadImage)?;
+
+        // The destination is created empty, zeroes are left unallocated.
+        if buf[..count].iter().any(|b| *b != 0) {
+            destination_image
+                .seek(SeekFrom::Start(offset))
+                .map_err(Error::WriteImage)?;
+            destination_image
+                .write_all(&buf[..count])
+                .map_err(Error::WriteImage)?;
+        }
+        offset += count as u64;
+    }
+
+    // Dropping the image writes its cached metadata.
+    drop(destination_image);
+    File::open(destination)
+        .and_then(|f| f.sync_all())
+        .map_err(Error::WriteImage)
+}
+
+fn resize_command(path: &Path, size: &str) -> Result<(), Error> {
+    let mut image = Image::open(path, true)?;
+    let size = match size.strip_prefix('+') {
+        Some(increment) => image.virtual_size()? + parse_size(increment)?,
+        None => parse_size(size)?,
+    };
+    image.resize(size)?;
+
+    drop(image);
+    File::open(path)
+        .and_then(|f| f.sync_all())
+        .map_err(Error::WriteImage)
+}
+
+fn check_command(path: &Path, repair: bool) -> Result<(), Error> {
+    let mut image = Image::open(path, repair)?;
+    let size = image.virtual_size()?;
+
+    // The refcounts of qcow2 images aren't exercised by reading the virtual disk.
+    if let Image::Qcow2(qcow) = &mut image {
+        let result = qcow.check(repair).map_err(Error::Qcow)?;
+        if result.leaks > 0 {
+            println!("{} leaked clusters were found on the image.", result.leaks);
+        }
+        if result.corruptions > 0 {
+            println!(
+                "{} corrupted clusters were found on the image.",
+                result.corruptions
+            );
+        }
+        if result.dangling_pointers > 0 {
+            println!(
+                "{} dangling pointers were found on the image.",
+                result.dangling_pointers
+            );
+        }
+        if !result.is_clean() {
+            if !repair {
+                return Err(Error::InconsistentImage);
+            }
+            println!("The inconsistencies were repaired.");
+        }
+    }
+
+    // Reading the whole virtual disk goes through every table of the image and of its backing
+    // files.
+    let reader = image.reader();
+    reader.seek(SeekFrom::Start(0)).map_err(Error::ReadImage)?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let count = std::cmp::min(size - offset, CHUNK_SIZE as u64) as usize;
+        reader
+            .read_exact(&mut buf[..count])
+            .map_err(Error::ReadImage)?;
+        offset += count as u64;
+    }
+
+    println!("No errors were found on the image.");
+    Ok(())
+}
+
+// Writes the extents of a disk backup to the image.
+fn write_extents(
+    backup: &mut BackupReader<impl Read>,
+    image: &mut dyn WriteSeek,
+) -> Result<(), Error> {
+    let mut buf = vec![0u8; BACKUP_EXTENT_MAX_SIZE as usize];
+    while let Some((offset, length)) = backup.next_extent(&mut buf).map_err(Error::ReadBackup)? {
+        image
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::WriteImage)?;
+        image.write_all(&buf[..length]).map_err(Error::WriteImage)?;
+    }
+
+    Ok(())
+}
+
+fn apply_backup_command(backup: &Path, path: &Path, format: &str) -> Result<(), Error> {
+    let file = File::open(backup).map_err(|e| Error::OpenImage(backup.to_path_buf(), e))?;
+    let mut backup = BackupReader::new(BufReader::new(file)).map_err(Error::ReadBackup)?;
+    let header = backup.header();
+
+    // Full backups make new images, incremental ones apply on top of the image restored from
+    // the previous backups. Dropping the image writes its cached metadata.
+    if header.full {
+        let mut image = create_image(path, format, header.disk_size)?;
+        write_extents(&mut backup, image.as_mut())?;
+    } else {
+        let mut image = Image::open(path, true)?;
+        if image.virtual_size()? < header.disk_size {
+            image.resize(header.disk_size)?;
+        }
+        write_extents(&mut backup, image.writer()?)?;
+    }
+
+    File::open(path)
+        .and_then(|f| f.sync_all())
+        .map_err(Error::WriteImage)
+}
+
+fn do_command(matches: &ArgMatches) -> Result<(), Error> {
+    match matches.subcommand() {
+        Some(("create", matches)) => create_command(
+            Path::new(matches.value_of("filename").unwrap()),
+            matches.value_of("format").unwrap(),
+            matches.value_of("size"),
+            matches.value_of("backing_file"),
+        ),
+        Some(("info", matches)) => info_command(
+            Path::new(matches.value_of("filename").unwrap()),
+            matches.is_present("json"),
+        ),
+        Some(("convert", matches)) => convert_command(
+            Path::new(matches.value_of("source").unwrap()),
+            Path::new(matches.value_of("destination").unwrap()),
+            matches.value_of("output_format").unwrap(),
+        ),
+        Some(("resize", matches)) => resize_command(
+            Path::new(matches.value_of("filename").unwrap()),
+            matches.value_of("size").unwrap(),
+        ),
+        Some(("check", matches)) => check_command(
+            Path::new(matches.value_of("filename").unwrap()),
+            matches.is_present("repair"),
+        ),
+        Some(("apply-backup", matches)) => apply_backup_command(
+            Path::new(matches.value_of("backup").unwrap()),
+            Path::new(matches.value_of("filename").unwrap()),
+            matches.value_of("format").unwrap(),
+        ),
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let app = Command::new("ch-img")
+        .author(crate_authors!())
+        .subcommand_required(true)
+        .about("Create, inspect and convert disk images.")
+        .subcommand(
+            Command::new("create")
+                .about("Create a disk image")
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .help("Image format")
+                        .takes_value(true)
+                        .possible_values(["raw", "qcow2", "vhdx"])
+                        .default_value("raw"),
+                )
+                .arg(
+                    Arg::new("backing_file")
+                        .short('b')
+                        .long("backing-file")
+                        .help("Backing file of a qcow2 image")
+                        .takes_value(true),
+                )
+                .arg(Arg::new("filename").index(1).required(true))
+                .arg(
+                    Arg::new("size")
+                        .index(2)
+                        .help("Virtual size, defaults to the size of the backing file"),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Display information about a disk image and its backing files")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Output in JSON format")
+                        .takes_value(false),
+                )
+                .arg(Arg::new("filename").index(1).required(true)),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Convert a disk image to another format")
+                .arg(
+                    Arg::new("output_format")
+                        .short('O')
+                        .long("output-format")
+                        .help("Format of the new image")
+                        .takes_value(true)
+                        .possible_values(["raw", "qcow2", "vhdx"])
+                        .default_value("raw"),
+                )
+                .arg(Arg::new("source").index(1).required(true))
+                .arg(Arg::new("destination").index(2).required(true)),
+        )
+        .subcommand(
+            Command::new("resize")
+                .about("Grow the virtual size of a disk image")
+                .arg(Arg::new("filename").index(1).required(true))
+                .arg(
+                    Arg::new("size")
+                        .index(2)
+                        .required(true)
+                        .help("New virtual size, or increment when prefixed with '+'"),
+                ),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Check the consistency of a disk image and its backing files")
+                .arg(
+                    Arg::new("repair")
+                        .short('r')
+                        .long("repair")
+                        .help("Repair the inconsistencies found in a qcow2 image")
+                        .takes_value(false),
+                )
+                .arg(Arg::new("filename").index(1).required(true)),
+        )
+        .subcommand(
+            Command::new("apply-backup")
+                .about("Restore a disk image from a disk backup")
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .help("Format of the image created from a full backup")
+                        .takes_value(true)
+                        .possible_values(["raw", "qcow2", "vhdx"])
+                        .default_value("raw"),
+                )
+                .arg(Arg::new("backup").index(1).required(true))
+                .arg(Arg::new("filename").index(2).required(true)),
+        );
+
+    let matches = app.get_matches();
+
+    if let Err(e) = do_command(&matches) {
+        eprintln!("Error running command: {}", e);
+        process::exit(1)
+    };
+}
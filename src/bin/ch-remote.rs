@@ -22,6 +22,10 @@ enum Error {
     InvalidCpuCount(std::num::ParseIntError),
     InvalidMemorySize(ByteSizedParseError),
     InvalidBalloonSize(ByteSizedParseError),
+    InvalidDiskSize(ByteSizedParseError),
+    InvalidCaptureSnaplen(std::num::ParseIntError),
+    InvalidCaptureFileSize(ByteSizedParseError),
+    InvalidCaptureFiles(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
@@ -29,7 +33,9 @@ enum Error {
     AddNetConfig(vmm::config::Error),
     AddUserDeviceConfig(vmm::config::Error),
     AddVdpaConfig(vmm::config::Error),
+    AddScsiLunConfig(vmm::config::Error),
     AddVsockConfig(vmm::config::Error),
+    UpdateRateLimiterConfig(vmm::config::Error),
     Restore(vmm::config::Error),
 }
 
@@ -42,6 +48,10 @@ impl fmt::Display for Error {
             InvalidCpuCount(e) => write!(f, "Error parsing CPU count: {}", e),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {:?}", e),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {:?}", e),
+            InvalidDiskSize(e) => write!(f, "Error parsing disk size: {:?}", e),
+            InvalidCaptureSnaplen(e) => write!(f, "Error parsing capture snaplen: {}", e),
+            InvalidCaptureFileSize(e) => write!(f, "Error parsing capture file size: {:?}", e),
+            InvalidCaptureFiles(e) => write!(f, "Error parsing capture files count: {}", e),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {}", e),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {}", e),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {}", e),
@@ -49,7 +59,9 @@ impl fmt::Display for Error {
             AddNetConfig(e) => write!(f, "Error parsing network syntax: {}", e),
             AddUserDeviceConfig(e) => write!(f, "Error parsing user device syntax: {}", e),
             AddVdpaConfig(e) => write!(f, "Error parsing vDPA device syntax: {}", e),
+            AddScsiLunConfig(e) => write!(f, "Error parsing SCSI logical unit syntax: {}", e),
             AddVsockConfig(e) => write!(f, "Error parsing vsock syntax: {}", e),
+            UpdateRateLimiterConfig(e) => write!(f, "Error parsing rate limiter syntax: {}", e),
             Restore(e) => write!(f, "Error parsing restore syntax: {}", e),
         }
     }
@@ -122,6 +134,201 @@ fn resize_zone_api_command(socket: &mut UnixStream, id: &str, size: &str) -> Res
     .map_err(Error::ApiClient)
 }
 
+fn resize_disk_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    size: Option<&str>,
+) -> Result<(), Error> {
+    let desired_size: Option<u64> = if let Some(size) = size {
+        Some(size.parse::<ByteSized>().map_err(Error::InvalidDiskSize)?.0)
+    } else {
+        None
+    };
+
+    let resize_disk = vmm::api::VmResizeDiskData {
+        id: id.to_owned(),
+        desired_size,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "resize-disk",
+        Some(&serde_json::to_string(&resize_disk).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn backup_disk_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    url: &str,
+    incremental: bool,
+) -> Result<(), Error> {
+    let backup_disk = vmm::api::VmBackupDiskData {
+        id: id.to_owned(),
+        destination_url: String::from(url),
+        incremental,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "backup-disk",
+        Some(&serde_json::to_string(&backup_disk).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn mirror_disk_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    destination: &str,
+    format: Option<&str>,
+    no_pivot: bool,
+) -> Result<(), Error> {
+    let mirror_disk = vmm::api::VmMirrorDiskData {
+        id: id.to_owned(),
+        destination: destination.into(),
+        format: format.map(String::from),
+        no_pivot,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "mirror-disk",
+        Some(&serde_json::to_string(&mirror_disk).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn cancel_disk_mirror_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let cancel_disk_mirror = vmm::api::VmCancelDiskMirrorData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "cancel-disk-mirror",
+        Some(&serde_json::to_string(&cancel_disk_mirror).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn change_media_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    path: Option<&str>,
+    readonly: Option<&str>,
+) -> Result<(), Error> {
+    let change_media = vmm::api::VmChangeMediaData {
+        id: id.to_owned(),
+        path: path.map(Into::into),
+        readonly: readonly.map(|readonly| readonly == "on"),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "change-media",
+        Some(&serde_json::to_string(&change_media).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn set_link_api_command(socket: &mut UnixStream, id: &str, state: &str) -> Result<(), Error> {
+    let set_link = vmm::api::VmSetLinkData {
+        id: id.to_owned(),
+        up: state == "up",
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-link",
+        Some(&serde_json::to_string(&set_link).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn capture_net_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    path: Option<&str>,
+    snaplen: Option<&str>,
+    file_size: Option<&str>,
+    files: Option<&str>,
+) -> Result<(), Error> {
+    let snaplen: Option<u32> = if let Some(snaplen) = snaplen {
+        Some(snaplen.parse().map_err(Error::InvalidCaptureSnaplen)?)
+    } else {
+        None
+    };
+
+    let file_size: Option<u64> = if let Some(file_size) = file_size {
+        Some(
+            file_size
+                .parse::<ByteSized>()
+                .map_err(Error::InvalidCaptureFileSize)?
+                .0,
+        )
+    } else {
+        None
+    };
+
+    let files: Option<u32> = if let Some(files) = files {
+        Some(files.parse().map_err(Error::InvalidCaptureFiles)?)
+    } else {
+        None
+    };
+
+    let capture_net = vmm::api::VmCaptureNetData {
+        id: id.to_owned(),
+        path: path.map(Into::into),
+        snaplen,
+        file_size,
+        files,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "capture-net",
+        Some(&serde_json::to_string(&capture_net).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn update_rate_limiter_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    config: Option<&str>,
+) -> Result<(), Error> {
+    // The limits share the syntax of the rate limit groups.
+    let rate_limiter_config = if let Some(config) = config {
+        Some(
+            vmm::config::RateLimitGroupConfig::parse(&format!("id={},{}", id, config))
+                .map_err(Error::UpdateRateLimiterConfig)?
+                .rate_limiter_config,
+        )
+    } else {
+        None
+    };
+
+    let update_rate_limiter = vmm::api::VmUpdateRateLimiterData {
+        id: id.to_owned(),
+        rate_limiter_config,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "update-rate-limiter",
+        Some(&serde_json::to_string(&update_rate_limiter).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
 fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
 
@@ -160,13 +367,18 @@ fn remove_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Er
 }
 
 fn add_disk_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
-    let disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
+    let mut disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
 
-    simple_api_command(
+    // The file descriptor of the passphrase is sent along the request, its
+    // value wouldn't make sense in the server side process.
+    let fds = disk_config.key_fd.take().into_iter().collect();
+
+    simple_api_command_with_fds(
         socket,
         "PUT",
         "add-disk",
         Some(&serde_json::to_string(&disk_config).unwrap()),
+        fds,
     )
     .map_err(Error::ApiClient)
 }
@@ -226,6 +438,19 @@ fn add_vdpa_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Err
     .map_err(Error::ApiClient)
 }
 
+fn add_scsi_lun_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let scsi_lun_config =
+        vmm::config::ScsiLunConfig::parse(config).map_err(Error::AddScsiLunConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-scsi-lun",
+        Some(&serde_json::to_string(&scsi_lun_config).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
 fn add_vsock_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let vsock_config = vmm::config::VsockConfig::parse(config).map_err(Error::AddVsockConfig)?;
 
@@ -238,9 +463,16 @@ fn add_vsock_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Er
     .map_err(Error::ApiClient)
 }
 
-fn snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+fn snapshot_api_command(
+    socket: &mut UnixStream,
+    url: &str,
+    disk_snapshot_name: Option<&str>,
+    disk_backup: bool,
+) -> Result<(), Error> {
     let snapshot_config = vmm::api::VmSnapshotConfig {
         destination_url: String::from(url),
+        disk_snapshot_name: disk_snapshot_name.map(String::from),
+        disk_backup,
     };
 
     simple_api_command(
@@ -334,6 +566,129 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("size")
                 .unwrap(),
         ),
+        Some("resize-disk") => resize_disk_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("resize-disk")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("resize-disk")
+                .unwrap()
+                .value_of("size"),
+        ),
+        Some("backup-disk") => backup_disk_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("backup-disk")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("backup-disk")
+                .unwrap()
+                .value_of("backup_config")
+                .unwrap(),
+            matches
+                .subcommand_matches("backup-disk")
+                .unwrap()
+                .is_present("incremental"),
+        ),
+        Some("mirror-disk") => mirror_disk_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("mirror-disk")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("mirror-disk")
+                .unwrap()
+                .value_of("destination")
+                .unwrap(),
+            matches
+                .subcommand_matches("mirror-disk")
+                .unwrap()
+                .value_of("format"),
+            matches
+                .subcommand_matches("mirror-disk")
+                .unwrap()
+                .is_present("no_pivot"),
+        ),
+        Some("cancel-disk-mirror") => cancel_disk_mirror_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("cancel-disk-mirror")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+        ),
+        Some("change-media") => change_media_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("change-media")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("change-media")
+                .unwrap()
+                .value_of("path"),
+            matches
+                .subcommand_matches("change-media")
+                .unwrap()
+                .value_of("readonly"),
+        ),
+        Some("set-link") => set_link_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-link")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("set-link")
+                .unwrap()
+                .value_of("state")
+                .unwrap(),
+        ),
+        Some("capture-net") => capture_net_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("capture-net")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("capture-net")
+                .unwrap()
+                .value_of("path"),
+            matches
+                .subcommand_matches("capture-net")
+                .unwrap()
+                .value_of("snaplen"),
+            matches
+                .subcommand_matches("capture-net")
+                .unwrap()
+                .value_of("file_size"),
+            matches
+                .subcommand_matches("capture-net")
+                .unwrap()
+                .value_of("files"),
+        ),
+        Some("update-rate-limiter") => update_rate_limiter_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("update-rate-limiter")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("update-rate-limiter")
+                .unwrap()
+                .value_of("rate_limiter_config"),
+        ),
         Some("add-device") => add_device_api_command(
             &mut socket,
             matches
@@ -398,6 +753,14 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("vdpa_config")
                 .unwrap(),
         ),
+        Some("add-scsi-lun") => add_scsi_lun_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-scsi-lun")
+                .unwrap()
+                .value_of("scsi_lun_config")
+                .unwrap(),
+        ),
         Some("add-vsock") => add_vsock_api_command(
             &mut socket,
             matches
@@ -413,6 +776,14 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("snapshot_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .value_of("disk_snapshot"),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .is_present("disk_backup"),
         ),
         Some("restore") => restore_api_command(
             &mut socket,
@@ -515,6 +886,15 @@ fn main() {
                     .help(vmm::config::VdpaConfig::SYNTAX),
             ),
         )
+        .subcommand(
+            Command::new("add-scsi-lun")
+                .about("Add SCSI logical unit")
+                .arg(
+                    Arg::new("scsi_lun_config")
+                        .index(1)
+                        .help(vmm::config::ScsiLunConfig::SYNTAX),
+                ),
+        )
         .subcommand(
             Command::new("add-vsock").about("Add vsock device").arg(
                 Arg::new("vsock_config")
@@ -575,6 +955,173 @@ fn main() {
                         .number_of_values(1),
                 ),
         )
+        .subcommand(
+            Command::new("resize-disk")
+                .about("Resize a disk")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Disk identifier")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .help("New disk size in bytes (supports K/M/G suffix)")
+                        .takes_value(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            Command::new("backup-disk")
+                .about("Back up a disk to a file or a UNIX socket")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Disk identifier")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("incremental")
+                        .long("incremental")
+                        .help("Only back up the ranges written since the previous backup")
+                        .takes_value(false),
+                )
+                .arg(Arg::new("backup_config").index(1).help("<destination_url>")),
+        )
+        .subcommand(
+            Command::new("mirror-disk")
+                .about("Mirror a disk to a new image")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Disk identifier")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .help("Format of the new image: raw (default), qcow2 or vhdx")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("no_pivot")
+                        .long("no-pivot")
+                        .help("Keep the image in sync instead of switching the disk over to it")
+                        .takes_value(false),
+                )
+                .arg(Arg::new("destination").index(1).help("<destination_path>")),
+        )
+        .subcommand(
+            Command::new("cancel-disk-mirror")
+                .about("Cancel the mirror of a disk")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Disk identifier")
+                        .takes_value(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            Command::new("change-media")
+                .about("Change the media of a disk, or eject it")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Disk identifier")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("readonly")
+                        .long("readonly")
+                        .help("Whether the new media is read-only, as the current one by default")
+                        .possible_values(["on", "off"])
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("path")
+                        .index(1)
+                        .help("<media_path>, the media is ejected when omitted"),
+                ),
+        )
+        .subcommand(
+            Command::new("set-link")
+                .about("Set the link of a network device up or down")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Network device identifier")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("state")
+                        .index(1)
+                        .help("<link_state>")
+                        .possible_values(["up", "down"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("capture-net")
+                .about("Start or stop capturing the frames of a network device")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Network device identifier")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("snaplen")
+                        .long("snaplen")
+                        .help("Maximum number of bytes kept from each frame")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("file_size")
+                        .long("file-size")
+                        .help("Size after which the capture file is rotated")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("files")
+                        .long("files")
+                        .help("Number of capture files kept when rotating")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("path")
+                        .index(1)
+                        .help("<capture_path>, the capture is stopped when omitted"),
+                ),
+        )
+        .subcommand(
+            Command::new("update-rate-limiter")
+                .about("Update the rate limiter of a device or a rate limit group")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Device or rate limit group identifier")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(Arg::new("rate_limiter_config").index(1).help(
+                    "<bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
+                             ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>>, \
+                             no limit when omitted",
+                )),
+        )
         .subcommand(Command::new("resume").about("Resume the VM"))
         .subcommand(Command::new("shutdown").about("Shutdown the VM"))
         .subcommand(
@@ -584,6 +1131,19 @@ fn main() {
                     Arg::new("snapshot_config")
                         .index(1)
                         .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::new("disk_snapshot")
+                        .long("disk-snapshot")
+                        .help("Internal snapshot name for disks with internal_snapshot=on")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::new("disk_backup")
+                        .long("disk-backup")
+                        .help("Back up the disks with a dirty_bitmap into the snapshot")
+                        .takes_value(false),
                 ),
         )
         .subcommand(
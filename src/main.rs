@@ -192,7 +192,7 @@ fn create_app<'a>(
                      host_numa_node=<node_id>,\
                      id=<zone_identifier>,hotplug_size=<hotpluggable_memory_size>,\
                      hotplugged_size=<hotplugged_memory_size>,\
-                     prefault=on|off\"",
+                     prefault=on|off,lock=on|off\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -222,6 +222,14 @@ fn create_app<'a>(
                 .takes_value(true)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("rate-limit-group")
+                .long("rate-limit-group")
+                .help(config::RateLimitGroupConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("disk")
                 .long("disk")
@@ -310,6 +318,22 @@ fn create_app<'a>(
                 .min_values(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("scsi")
+                .long("scsi")
+                .help(config::ScsiConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("scsi-lun")
+                .long("scsi-lun")
+                .help(config::ScsiLunConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("vsock")
                 .long("vsock")
@@ -701,6 +725,7 @@ mod unit_tests {
             cmdline: CmdlineConfig {
                 args: String::from(""),
             },
+            rate_limit_groups: None,
             disks: None,
             net: None,
             rng: RngConfig {
@@ -723,6 +748,8 @@ mod unit_tests {
             devices: None,
             user_devices: None,
             vdpa: None,
+            scsi: None,
+            scsi_luns: None,
             vsock: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
@@ -1572,6 +1599,114 @@ mod unit_tests {
         });
     }
 
+    #[test]
+    fn test_valid_vm_config_scsi() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--scsi",
+                    "id=scsi0,num_queues=1",
+                    "--scsi-lun",
+                    "controller=scsi0,path=/path/to/disk/1",
+                    "controller=scsi0,lun=1,cdrom=on",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "scsi": [
+                        {"id": "scsi0"}
+                    ],
+                    "scsi_luns": [
+                        {"controller": "scsi0", "path": "/path/to/disk/1"},
+                        {"controller": "scsi0", "lun": 1, "cdrom": true}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--scsi",
+                    "id=scsi0",
+                    "--scsi-lun",
+                    "controller=scsi0,path=/path/to/disk/1,readonly=on",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "scsi": [
+                        {"id": "scsi0"}
+                    ],
+                    "scsi_luns": [
+                        {"controller": "scsi0", "path": "/path/to/disk/1"}
+                    ]
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_rate_limit_group() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--rate-limit-group",
+                    "id=group0,bw_size=1000,bw_refill_time=100",
+                    "--disk",
+                    "path=/path/to/disk/1,rate_limit_group=group0",
+                    "path=/path/to/disk/2,rate_limit_group=group0",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "rate_limit_groups": [
+                        {"id": "group0", "rate_limiter_config": {"bandwidth": {"size": 1000, "one_time_burst": 0, "refill_time": 100}}}
+                    ],
+                    "disks": [
+                        {"path": "/path/to/disk/1", "rate_limit_group": "group0"},
+                        {"path": "/path/to/disk/2", "rate_limit_group": "group0"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--rate-limit-group",
+                    "id=group0,bw_size=1000,bw_refill_time=100",
+                    "--disk",
+                    "path=/path/to/disk/1,rate_limit_group=group0",
+                ],
+                r#"{
+                    "kernel": {"path": "/path/to/kernel"},
+                    "rate_limit_groups": [
+                        {"id": "group0", "rate_limiter_config": {"bandwidth": {"size": 1000, "one_time_burst": 0, "refill_time": 100}}}
+                    ],
+                    "disks": [
+                        {"path": "/path/to/disk/1"}
+                    ]
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
     #[test]
     fn test_valid_vm_config_vsock() {
         vec![
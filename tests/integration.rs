@@ -22,6 +22,7 @@ use std::io::BufRead;
 use std::io::Read;
 use std::io::Seek;
 use std::io::Write;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::process::{Child, Command, Stdio};
@@ -322,6 +323,53 @@ fn resize_zone_command(api_socket: &str, id: &str, desired_size: &str) -> bool {
     cmd.status().expect("Failed to launch ch-remote").success()
 }
 
+fn resize_disk_command(api_socket: &str, id: &str, desired_size: Option<&str>) -> bool {
+    let mut cmd = Command::new(clh_command("ch-remote"));
+    cmd.args(&[
+        &format!("--api-socket={}", api_socket),
+        "resize-disk",
+        &format!("--id={}", id),
+    ]);
+
+    if let Some(desired_size) = desired_size {
+        cmd.arg(format!("--size={}", desired_size));
+    }
+
+    cmd.status().expect("Failed to launch ch-remote").success()
+}
+
+fn backup_disk_command(api_socket: &str, id: &str, url: &str, incremental: bool) -> bool {
+    let mut cmd = Command::new(clh_command("ch-remote"));
+    cmd.args(&[
+        &format!("--api-socket={}", api_socket),
+        "backup-disk",
+        &format!("--id={}", id),
+        url,
+    ]);
+
+    if incremental {
+        cmd.arg("--incremental");
+    }
+
+    cmd.status().expect("Failed to launch ch-remote").success()
+}
+
+fn mirror_disk_command(api_socket: &str, id: &str, destination: &str, no_pivot: bool) -> bool {
+    let mut cmd = Command::new(clh_command("ch-remote"));
+    cmd.args(&[
+        &format!("--api-socket={}", api_socket),
+        "mirror-disk",
+        &format!("--id={}", id),
+        destination,
+    ]);
+
+    if no_pivot {
+        cmd.arg("--no-pivot");
+    }
+
+    cmd.status().expect("Failed to launch ch-remote").success()
+}
+
 // setup OVS-DPDK bridge and ports
 fn setup_ovs_dpdk() {
     // setup OVS-DPDK
@@ -2876,6 +2924,222 @@ mod parallel {
         handle_child_output(r, &output);
     }
 
+    #[test]
+    fn test_virtio_block_nbd() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+        let kernel_path = direct_kernel_boot_path();
+
+        let disk_path = guest.tmp_dir.as_path().join("nbd.img");
+        fs::File::create(&disk_path)
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+        let socket_path = guest.tmp_dir.as_path().join("nbd.sock");
+
+        // Export the image to several connections, one per queue.
+        let mut nbd_server = Command::new("qemu-nbd")
+            .args(&[
+                "--format=raw",
+                "--export-name=disk0",
+                "--shared=4",
+                "--persistent",
+                "--socket",
+                socket_path.to_str().unwrap(),
+                disk_path.to_str().unwrap(),
+            ])
+            .spawn()
+            .expect("Failed to launch qemu-nbd");
+        thread::sleep(std::time::Duration::new(2, 0));
+
+        let mut child = GuestCommand::new(&guest)
+            .args(&["--cpus", "boot=2"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", kernel_path.to_str().unwrap()])
+            .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .args(&[
+                "--disk",
+                format!(
+                    "path={}",
+                    guest.disk_config.disk(DiskType::OperatingSystem).unwrap()
+                )
+                .as_str(),
+                format!(
+                    "path={}",
+                    guest.disk_config.disk(DiskType::CloudInit).unwrap()
+                )
+                .as_str(),
+                format!(
+                    "nbd=unix:{},export=disk0,num_queues=2",
+                    socket_path.to_str().unwrap()
+                )
+                .as_str(),
+            ])
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            // Check both if /dev/vdc exists and if the block size is 64 MiB.
+            assert_eq!(
+                guest
+                    .ssh_command("lsblk | grep vdc | grep -c 64M")
+                    .unwrap()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap_or_default(),
+                1
+            );
+
+            // The data written by the guest lands in the exported image.
+            guest
+                .ssh_command("sudo dd if=/dev/urandom of=/dev/vdc bs=1M count=16 oflag=direct")
+                .unwrap();
+            guest.ssh_command("sync").unwrap();
+            let guest_checksum = guest
+                .ssh_command("sudo dd if=/dev/vdc bs=1M count=16 iflag=direct | md5sum")
+                .unwrap();
+            let host_checksum = exec_host_command_output(&format!(
+                "dd if={} bs=1M count=16 | md5sum",
+                disk_path.to_str().unwrap()
+            ));
+            assert_eq!(
+                guest_checksum.split_whitespace().next(),
+                String::from_utf8_lossy(&host_checksum.stdout)
+                    .split_whitespace()
+                    .next()
+            );
+        });
+
+        let _ = child.kill();
+        let output = child.wait_with_output().unwrap();
+        let _ = nbd_server.kill();
+        let _ = nbd_server.wait();
+
+        handle_child_output(r, &output);
+    }
+
+    #[test]
+    fn test_virtio_scsi() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+        let kernel_path = direct_kernel_boot_path();
+        let api_socket = temp_api_path(&guest.tmp_dir);
+
+        let disk_path = guest.tmp_dir.as_path().join("scsi0.img");
+        fs::File::create(&disk_path)
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+        let hotplug_disk_path = guest.tmp_dir.as_path().join("scsi1.img");
+        fs::File::create(&hotplug_disk_path)
+            .unwrap()
+            .set_len(32 << 20)
+            .unwrap();
+
+        let mut child = GuestCommand::new(&guest)
+            .args(&["--api-socket", &api_socket])
+            .args(&["--cpus", "boot=2"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", kernel_path.to_str().unwrap()])
+            .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .default_net()
+            .args(&["--scsi", "id=scsi0,num_queues=2"])
+            .args(&[
+                "--scsi-lun",
+                format!(
+                    "controller=scsi0,path={},discard=on,id=lun0",
+                    disk_path.to_str().unwrap()
+                )
+                .as_str(),
+            ])
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            // Check both if /dev/sda exists and if the block size is 64 MiB.
+            assert_eq!(
+                guest
+                    .ssh_command("lsblk | grep sda | grep -c 64M")
+                    .unwrap()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap_or_default(),
+                1
+            );
+            // The data written by the guest lands in the image.
+            guest
+                .ssh_command("sudo dd if=/dev/urandom of=/dev/sda bs=1M count=16 oflag=direct")
+                .unwrap();
+            guest.ssh_command("sync").unwrap();
+            let guest_checksum = guest
+                .ssh_command("sudo dd if=/dev/sda bs=1M count=16 iflag=direct | md5sum")
+                .unwrap();
+            let host_checksum = exec_host_command_output(&format!(
+                "dd if={} bs=1M count=16 | md5sum",
+                disk_path.to_str().unwrap()
+            ));
+            assert_eq!(
+                guest_checksum.split_whitespace().next(),
+                String::from_utf8_lossy(&host_checksum.stdout)
+                    .split_whitespace()
+                    .next()
+            );
+
+            // Discarding the disk punches holes in the image.
+            guest.ssh_command("sudo blkdiscard /dev/sda").unwrap();
+            assert!(disk_path.metadata().unwrap().blocks() < 16 << 11);
+
+            // Add a logical unit to the running controller.
+            assert!(remote_command(
+                &api_socket,
+                "add-scsi-lun",
+                Some(
+                    format!(
+                        "controller=scsi0,lun=1,path={},id=lun1",
+                        hotplug_disk_path.to_str().unwrap()
+                    )
+                    .as_str()
+                ),
+            ));
+            thread::sleep(std::time::Duration::new(5, 0));
+            assert_eq!(
+                guest
+                    .ssh_command("lsblk | grep sdb | grep -c 32M")
+                    .unwrap()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap_or_default(),
+                1
+            );
+
+            // And remove it.
+            assert!(remote_command(&api_socket, "remove-device", Some("lun1")));
+            thread::sleep(std::time::Duration::new(5, 0));
+            assert_eq!(
+                guest
+                    .ssh_command("lsblk | grep -c sdb || true")
+                    .unwrap()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap_or(1),
+                0
+            );
+        });
+
+        let _ = child.kill();
+        let output = child.wait_with_output().unwrap();
+
+        handle_child_output(r, &output);
+    }
+
     fn vhdx_image_size(disk_name: &str) -> u64 {
         std::fs::File::open(disk_name)
             .unwrap()
@@ -4609,6 +4873,512 @@ mod parallel {
         handle_child_output(r, &output);
     }
 
+    #[test]
+    fn test_disk_resize() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+
+        #[cfg(target_arch = "x86_64")]
+        let kernel_path = direct_kernel_boot_path();
+        #[cfg(target_arch = "aarch64")]
+        let kernel_path = edk2_path();
+
+        let api_socket = temp_api_path(&guest.tmp_dir);
+
+        let disk_path = guest.tmp_dir.as_path().join("resize.img");
+        let disk_file = fs::File::create(&disk_path).unwrap();
+        disk_file.set_len(16 << 20).unwrap();
+
+        let mut child = GuestCommand::new(&guest)
+            .args(&["--api-socket", &api_socket])
+            .args(&["--cpus", "boot=1"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", kernel_path.to_str().unwrap()])
+            .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            let (cmd_success, _) = remote_command_w_output(
+                &api_socket,
+                "add-disk",
+                Some(format!("path={},id=test0", disk_path.to_str().unwrap()).as_str()),
+            );
+            assert!(cmd_success);
+
+            thread::sleep(std::time::Duration::new(10, 0));
+
+            let guest_disk_size = || {
+                guest
+                    .ssh_command("lsblk -b -d -n -o SIZE /dev/vdc")
+                    .unwrap()
+                    .trim()
+                    .parse::<u64>()
+                    .unwrap_or_default()
+            };
+            assert_eq!(guest_disk_size(), 16 << 20);
+
+            // Grow the image through the VMM.
+            assert!(resize_disk_command(&api_socket, "test0", Some("32M")));
+            thread::sleep(std::time::Duration::new(5, 0));
+            assert_eq!(disk_path.metadata().unwrap().len(), 32 << 20);
+            assert_eq!(guest_disk_size(), 32 << 20);
+
+            // Grow the image from the host, the VMM picks up its new size.
+            disk_file.set_len(64 << 20).unwrap();
+            assert!(resize_disk_command(&api_socket, "test0", None));
+            thread::sleep(std::time::Duration::new(5, 0));
+            assert_eq!(guest_disk_size(), 64 << 20);
+
+            // The new end of the disk can be written.
+            guest
+                .ssh_command("sudo dd if=/dev/zero of=/dev/vdc bs=1M seek=63 count=1 oflag=direct")
+                .unwrap();
+
+            // Disks can't shrink.
+            assert!(!resize_disk_command(&api_socket, "test0", Some("16M")));
+            assert_eq!(guest_disk_size(), 64 << 20);
+        });
+
+        let _ = child.kill();
+        let output = child.wait_with_output().unwrap();
+
+        handle_child_output(r, &output);
+    }
+
+    #[test]
+    fn test_disk_backup() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+
+        #[cfg(target_arch = "x86_64")]
+        let kernel_path = direct_kernel_boot_path();
+        #[cfg(target_arch = "aarch64")]
+        let kernel_path = edk2_path();
+
+        let api_socket = temp_api_path(&guest.tmp_dir);
+
+        let disk_path = guest.tmp_dir.as_path().join("backup.img");
+        fs::File::create(&disk_path)
+            .unwrap()
+            .set_len(16 << 20)
+            .unwrap();
+        let bitmap_path = guest.tmp_dir.as_path().join("backup.bitmap");
+        let full_backup_path = guest.tmp_dir.as_path().join("full.backup");
+        let incremental_backup_path = guest.tmp_dir.as_path().join("incremental.backup");
+        let restored_path = guest.tmp_dir.as_path().join("restored.img");
+
+        let mut child = GuestCommand::new(&guest)
+            .args(&["--api-socket", &api_socket])
+            .args(&["--cpus", "boot=1"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", kernel_path.to_str().unwrap()])
+            .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            let (cmd_success, _) = remote_command_w_output(
+                &api_socket,
+                "add-disk",
+                Some(
+                    format!(
+                        "path={},id=test0,dirty_bitmap={}",
+                        disk_path.to_str().unwrap(),
+                        bitmap_path.to_str().unwrap()
+                    )
+                    .as_str(),
+                ),
+            );
+            assert!(cmd_success);
+
+            thread::sleep(std::time::Duration::new(10, 0));
+
+            guest
+                .ssh_command("sudo dd if=/dev/urandom of=/dev/vdc bs=1M count=4 oflag=direct")
+                .unwrap();
+            assert!(backup_disk_command(
+                &api_socket,
+                "test0",
+                &format!("file://{}", full_backup_path.to_str().unwrap()),
+                false
+            ));
+
+            // Only the ranges written since the full backup are saved.
+            guest
+                .ssh_command(
+                    "sudo dd if=/dev/urandom of=/dev/vdc bs=1M seek=8 count=1 oflag=direct",
+                )
+                .unwrap();
+            assert!(backup_disk_command(
+                &api_socket,
+                "test0",
+                &format!("file://{}", incremental_backup_path.to_str().unwrap()),
+                true
+            ));
+            assert!(incremental_backup_path.metadata().unwrap().len() < 2 << 20);
+
+            // The image restored from the backups matches the disk.
+            for backup_path in [&full_backup_path, &incremental_backup_path] {
+                assert!(Command::new(clh_command("ch-img"))
+                    .args(&[
+                        "apply-backup",
+                        backup_path.to_str().unwrap(),
+                        restored_path.to_str().unwrap(),
+                    ])
+                    .status()
+                    .expect("Failed to launch ch-img")
+                    .success());
+            }
+            assert_eq!(
+                fs::read(&restored_path).unwrap(),
+                fs::read(&disk_path).unwrap()
+            );
+        });
+
+        let _ = child.kill();
+        let output = child.wait_with_output().unwrap();
+
+        handle_child_output(r, &output);
+    }
+
+    #[test]
+    fn test_disk_mirror() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+
+        #[cfg(target_arch = "x86_64")]
+        let kernel_path = direct_kernel_boot_path();
+        #[cfg(target_arch = "aarch64")]
+        let kernel_path = edk2_path();
+
+        let api_socket = temp_api_path(&guest.tmp_dir);
+
+        let disk_path = guest.tmp_dir.as_path().join("source.img");
+        fs::File::create(&disk_path)
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+        let mirror_path = guest.tmp_dir.as_path().join("mirror.img");
+
+        let mut child = GuestCommand::new(&guest)
+            .args(&["--api-socket", &api_socket])
+            .args(&["--cpus", "boot=1"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", kernel_path.to_str().unwrap()])
+            .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            let (cmd_success, _) = remote_command_w_output(
+                &api_socket,
+                "add-disk",
+                Some(format!("path={},id=test0", disk_path.to_str().unwrap()).as_str()),
+            );
+            assert!(cmd_success);
+
+            thread::sleep(std::time::Duration::new(10, 0));
+
+            guest
+                .ssh_command("sudo dd if=/dev/urandom of=/dev/vdc bs=1M count=32 oflag=direct")
+                .unwrap();
+            assert!(mirror_disk_command(
+                &api_socket,
+                "test0",
+                mirror_path.to_str().unwrap(),
+                false
+            ));
+
+            // Writes made while the disk is copied reach the mirror too.
+            guest
+                .ssh_command(
+                    "sudo dd if=/dev/urandom of=/dev/vdc bs=1M seek=16 count=8 oflag=direct",
+                )
+                .unwrap();
+
+            // The configuration points to the mirror once the disk switched
+            // over to it.
+            let mut pivoted = false;
+            for _ in 0..30 {
+                let (cmd_success, cmd_output) = remote_command_w_output(&api_socket, "info", None);
+                assert!(cmd_success);
+                let info: serde_json::Value =
+                    serde_json::from_slice(&cmd_output).unwrap_or_default();
+                pivoted = info["config"]["disks"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|disk| {
+                        disk["id"] == "test0" && disk["path"] == mirror_path.to_str().unwrap()
+                    });
+                if pivoted {
+                    break;
+                }
+                thread::sleep(std::time::Duration::new(1, 0));
+            }
+            assert!(pivoted);
+
+            // Writes made afterwards only go to the mirror, which matches
+            // the disk seen by the guest.
+            guest
+                .ssh_command(
+                    "sudo dd if=/dev/urandom of=/dev/vdc bs=1M seek=48 count=4 oflag=direct",
+                )
+                .unwrap();
+            let guest_checksum = guest.ssh_command("sudo md5sum /dev/vdc").unwrap();
+            let host_checksum =
+                exec_host_command_output(&format!("md5sum {}", mirror_path.to_str().unwrap()));
+            assert_eq!(
+                guest_checksum.split_whitespace().next(),
+                String::from_utf8_lossy(&host_checksum.stdout)
+                    .split_whitespace()
+                    .next()
+            );
+        });
+
+        let _ = child.kill();
+        let output = child.wait_with_output().unwrap();
+
+        handle_child_output(r, &output);
+    }
+
+    #[test]
+    fn test_disk_error_policy_stop() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+
+        #[cfg(target_arch = "x86_64")]
+        let kernel_path = direct_kernel_boot_path();
+        #[cfg(target_arch = "aarch64")]
+        let kernel_path = edk2_path();
+
+        let api_socket = temp_api_path(&guest.tmp_dir);
+
+        // The disk lives on a filesystem too small to hold it, for writes to
+        // fail with ENOSPC.
+        let tmpfs_path = guest.tmp_dir.as_path().join("tmpfs");
+        fs::create_dir(&tmpfs_path).unwrap();
+        assert!(exec_host_command_status(&format!(
+            "sudo mount -t tmpfs -o size=16M,mode=1777 tmpfs {}",
+            tmpfs_path.to_str().unwrap()
+        ))
+        .success());
+        let disk_path = tmpfs_path.join("disk.img");
+        fs::File::create(&disk_path)
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+
+        let mut child = GuestCommand::new(&guest)
+            .args(&["--api-socket", &api_socket])
+            .args(&["--cpus", "boot=1"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", kernel_path.to_str().unwrap()])
+            .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let get_state = || {
+            let (cmd_success, cmd_output) = remote_command_w_output(&api_socket, "info", None);
+            assert!(cmd_success);
+            let info: serde_json::Value = serde_json::from_slice(&cmd_output).unwrap_or_default();
+            info["state"].as_str().unwrap_or_default().to_owned()
+        };
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            let (cmd_success, _) = remote_command_w_output(
+                &api_socket,
+                "add-disk",
+                Some(format!("path={},id=test0,werror=stop", disk_path.to_str().unwrap()).as_str()),
+            );
+            assert!(cmd_success);
+
+            thread::sleep(std::time::Duration::new(10, 0));
+
+            guest
+                .ssh_command(
+                    "(sudo dd if=/dev/urandom of=/dev/vdc bs=1M count=32 oflag=direct; \
+                     echo $? > dd_status) > /dev/null 2>&1 &",
+                )
+                .unwrap();
+
+            // The VM is paused instead of the guest seeing the error.
+            let mut paused = false;
+            for _ in 0..30 {
+                if get_state() == "Paused" {
+                    paused = true;
+                    break;
+                }
+                thread::sleep(std::time::Duration::new(1, 0));
+            }
+            assert!(paused);
+
+            // Once the filesystem grew, the failed write is retried on resume.
+            assert!(exec_host_command_status(&format!(
+                "sudo mount -o remount,size=128M {}",
+                tmpfs_path.to_str().unwrap()
+            ))
+            .success());
+            assert!(remote_command(&api_socket, "resume", None));
+            assert_eq!(get_state(), "Running");
+
+            let mut dd_status = String::new();
+            for _ in 0..30 {
+                dd_status = guest
+                    .ssh_command("cat dd_status 2>/dev/null || true")
+                    .unwrap();
+                if !dd_status.trim().is_empty() {
+                    break;
+                }
+                thread::sleep(std::time::Duration::new(1, 0));
+            }
+            assert_eq!(dd_status.trim(), "0");
+
+            let guest_checksum = guest.ssh_command("sudo md5sum /dev/vdc").unwrap();
+            let host_checksum =
+                exec_host_command_output(&format!("md5sum {}", disk_path.to_str().unwrap()));
+            assert_eq!(
+                guest_checksum.split_whitespace().next(),
+                String::from_utf8_lossy(&host_checksum.stdout)
+                    .split_whitespace()
+                    .next()
+            );
+        });
+
+        let _ = child.kill();
+        let output = child.wait_with_output().unwrap();
+
+        exec_host_command_status(&format!("sudo umount {}", tmpfs_path.to_str().unwrap()));
+
+        handle_child_output(r, &output);
+    }
+
+    #[test]
+    fn test_disk_luks_encryption() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+
+        #[cfg(target_arch = "x86_64")]
+        let kernel_path = direct_kernel_boot_path();
+        #[cfg(target_arch = "aarch64")]
+        let kernel_path = edk2_path();
+
+        let api_socket = temp_api_path(&guest.tmp_dir);
+
+        let key_path = guest.tmp_dir.as_path().join("disk.key");
+        fs::write(&key_path, "passphrase").unwrap();
+        let wrong_key_path = guest.tmp_dir.as_path().join("wrong.key");
+        fs::write(&wrong_key_path, "wrong").unwrap();
+        let disk_path = guest.tmp_dir.as_path().join("disk.luks");
+        fs::File::create(&disk_path)
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+        assert!(exec_host_command_status(&format!(
+            "cryptsetup luksFormat --batch-mode --type luks2 --pbkdf pbkdf2 \
+             --pbkdf-force-iterations 1000 --key-file {} {}",
+            key_path.to_str().unwrap(),
+            disk_path.to_str().unwrap()
+        ))
+        .success());
+
+        let mut child = GuestCommand::new(&guest)
+            .args(&["--api-socket", &api_socket])
+            .args(&["--cpus", "boot=1"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", kernel_path.to_str().unwrap()])
+            .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            // The disk can't be added without the right passphrase.
+            let (cmd_success, _) = remote_command_w_output(
+                &api_socket,
+                "add-disk",
+                Some(
+                    format!(
+                        "path={},id=test0,key_file={}",
+                        disk_path.to_str().unwrap(),
+                        wrong_key_path.to_str().unwrap()
+                    )
+                    .as_str(),
+                ),
+            );
+            assert!(!cmd_success);
+
+            let (cmd_success, _) = remote_command_w_output(
+                &api_socket,
+                "add-disk",
+                Some(
+                    format!(
+                        "path={},id=test0,key_file={}",
+                        disk_path.to_str().unwrap(),
+                        key_path.to_str().unwrap()
+                    )
+                    .as_str(),
+                ),
+            );
+            assert!(cmd_success);
+
+            thread::sleep(std::time::Duration::new(10, 0));
+
+            guest
+                .ssh_command("sudo dd if=/dev/urandom of=/dev/vdc bs=1M count=16 oflag=direct")
+                .unwrap();
+            let guest_checksum = guest.ssh_command("sudo md5sum /dev/vdc").unwrap();
+
+            // The data is written encrypted, as dm-crypt reads it.
+            assert!(remote_command(&api_socket, "remove-device", Some("test0")));
+            thread::sleep(std::time::Duration::new(5, 0));
+            assert!(exec_host_command_status(&format!(
+                "sudo cryptsetup open --key-file {} {} ch-luks-test",
+                key_path.to_str().unwrap(),
+                disk_path.to_str().unwrap()
+            ))
+            .success());
+            let host_checksum = exec_host_command_output("sudo md5sum /dev/mapper/ch-luks-test");
+            exec_host_command_status("sudo cryptsetup close ch-luks-test");
+            assert_eq!(
+                guest_checksum.split_whitespace().next(),
+                String::from_utf8_lossy(&host_checksum.stdout)
+                    .split_whitespace()
+                    .next()
+            );
+        });
+
+        let _ = child.kill();
+        let output = child.wait_with_output().unwrap();
+
+        handle_child_output(r, &output);
+    }
+
     #[test]
     fn test_disk_hotplug() {
         let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
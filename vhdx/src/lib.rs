@@ -3,6 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use byteorder::{BigEndian, ByteOrder};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
 use uuid::Uuid;
 
 macro_rules! div_round_up {
@@ -15,6 +17,7 @@ pub mod vhdx;
 mod vhdx_bat;
 mod vhdx_header;
 mod vhdx_io;
+mod vhdx_log;
 mod vhdx_metadata;
 
 pub(crate) fn uuid_from_guid(buf: &[u8]) -> Uuid {
@@ -28,3 +31,11 @@ pub(crate) fn uuid_from_guid(buf: &[u8]) -> Uuid {
         buf[8..16].try_into().unwrap(),
     )
 }
+
+/// Whether the file was opened read-only, in which case the image must not be
+/// modified, not even its headers.
+pub(crate) fn is_read_only(f: &File) -> bool {
+    // SAFETY: FFI call with a valid file descriptor
+    let flags = unsafe { libc::fcntl(f.as_raw_fd(), libc::F_GETFL) };
+    flags >= 0 && (flags & libc::O_ACCMODE) == libc::O_RDONLY
+}
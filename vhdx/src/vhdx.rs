@@ -5,18 +5,39 @@
 use crate::vhdx_bat::{BatEntry, VhdxBatError};
 use crate::vhdx_header::{self, RegionInfo, RegionTableEntry, VhdxHeader, VhdxHeaderError};
 use crate::vhdx_io::{self, VhdxIoError};
-use crate::vhdx_metadata::{DiskSpec, VhdxMetadataError};
+use crate::vhdx_log::{self, VhdxLogError};
+use crate::vhdx_metadata::{DiskSpec, ParentLocator, VhdxMetadataError};
 use remain::sorted;
 use std::collections::btree_map::BTreeMap;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
+use uuid::Uuid;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
 
 #[sorted]
 #[derive(Error, Debug)]
 pub enum VhdxError {
+    #[error("Failed discarding sectors on disk {0}")]
+    DiscardFailed(#[source] VhdxIoError),
+    #[error("Invalid virtual disk size {0}")]
+    InvalidDiskSize(u64),
+    #[error("Parent image doesn't match the differencing image")]
+    InvalidParent,
+    #[error("Maximum disk nesting depth exceeded")]
+    MaxNestingDepthExceeded,
     #[error("Not a VHDx file {0}")]
     NotVhdx(#[source] VhdxHeaderError),
+    #[error("Failed to open parent image {0}")]
+    OpenParent(#[source] std::io::Error),
+    #[error("Differencing images are not allowed")]
+    ParentForbidden,
+    #[error("Parent image could not be found from the parent locator")]
+    ParentNotFound,
+    #[error("Failed to parse parent image {0}")]
+    ParseParent(#[source] Box<VhdxError>),
     #[error("Failed to parse VHDx header {0}")]
     ParseVhdxHeader(#[source] VhdxHeaderError),
     #[error("Failed to parse VHDx metadata {0}")]
@@ -27,12 +48,56 @@ pub enum VhdxError {
     ReadBatEntry(#[source] VhdxBatError),
     #[error("Failed reading sector from disk {0}")]
     ReadFailed(#[source] VhdxIoError),
+    #[error("Failed to replay VHDx log {0}")]
+    ReplayLog(#[source] VhdxLogError),
+    #[error("Failed to resize VHDx file {0}")]
+    ResizeFile(#[source] std::io::Error),
+    #[error("Shrinking VHDx images is not supported")]
+    ShrinkNotSupported,
+    #[error("Failed to update VHDx header {0}")]
+    UpdateHeader(#[source] VhdxHeaderError),
+    #[error("Failed to update VHDx metadata {0}")]
+    UpdateMetadata(#[source] VhdxMetadataError),
+    #[error("Failed to update VHDx region table {0}")]
+    UpdateRegionTable(#[source] VhdxHeaderError),
+    #[error("Failed writing BAT to disk {0}")]
+    WriteBat(#[source] VhdxBatError),
     #[error("Failed writing to sector on disk {0}")]
     WriteFailed(#[source] VhdxIoError),
 }
 
 pub type Result<T> = std::result::Result<T, VhdxError>;
 
+// Maximum nesting depth of differencing images
+const MAX_NESTING_DEPTH: u32 = 10;
+
+// Maximum virtual disk size allowed by the spec, 64 TiB
+const MAX_DISK_SIZE: u64 = 64 << 40;
+
+// Layout of the images created from scratch: the log, the metadata region and
+// the BAT follow the headers, 1 MiB aligned.
+const LOG_OFFSET: u64 = 1 << 20;
+const LOG_LENGTH: u32 = 1 << 20;
+const METADATA_OFFSET: u64 = 2 << 20;
+const METADATA_LENGTH: u32 = 1 << 20;
+const BAT_OFFSET: u64 = 3 << 20;
+const DEFAULT_BLOCK_SIZE: u32 = 32 << 20;
+const DEFAULT_LOGICAL_SECTOR_SIZE: u32 = 512;
+const DEFAULT_PHYSICAL_SECTOR_SIZE: u32 = 4096;
+// Regions are sized and aligned on 1 MiB
+const REGION_ALIGNMENT: u64 = 1 << 20;
+
+/// How the parent of a differencing image is opened
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParentPolicy {
+    /// Refuse to open differencing images.
+    Forbid,
+    /// Look for the parent using the paths stored in the parent locator.
+    Locator,
+    /// Open the given file as the parent, ignoring the parent locator.
+    Override(PathBuf),
+}
+
 #[derive(Debug)]
 pub struct Vhdx {
     file: File,
@@ -42,15 +107,88 @@ pub struct Vhdx {
     mdr_entry: RegionTableEntry,
     disk_spec: DiskSpec,
     bat_entries: Vec<BatEntry>,
+    parent: Option<Box<Vhdx>>,
+    parent_path: Option<PathBuf>,
     current_offset: u64,
     first_write: bool,
 }
 
 impl Vhdx {
     /// Parse the Vhdx header, BAT, and metadata from a file and store info
-    // in Vhdx structure.
-    pub fn new(mut file: File) -> Result<Vhdx> {
-        let vhdx_header = VhdxHeader::new(&mut file).map_err(VhdxError::ParseVhdxHeader)?;
+    // in Vhdx structure. Differencing images are refused, their parent has
+    // to be opened through `with_parent_policy()`.
+    pub fn new(file: File) -> Result<Vhdx> {
+        Vhdx::with_parent_policy(file, &ParentPolicy::Forbid)
+    }
+
+    /// Same as `new()`, with `parent_policy` deciding how the parent of a
+    /// differencing image is opened.
+    pub fn with_parent_policy(file: File, parent_policy: &ParentPolicy) -> Result<Vhdx> {
+        Vhdx::open(file, parent_policy, 0)
+    }
+
+    /// Create a dynamic VHDx image of `virtual_disk_size` bytes in `file`,
+    /// which is expected to be empty, and open it.
+    pub fn create(mut file: File, virtual_disk_size: u64) -> Result<Vhdx> {
+        if virtual_disk_size == 0
+            || virtual_disk_size > MAX_DISK_SIZE
+            || virtual_disk_size % DEFAULT_LOGICAL_SECTOR_SIZE as u64 != 0
+        {
+            return Err(VhdxError::InvalidDiskSize(virtual_disk_size));
+        }
+
+        let chunk_ratio = DiskSpec::chunk_ratio(DEFAULT_BLOCK_SIZE, DEFAULT_LOGICAL_SECTOR_SIZE)
+            .map_err(VhdxError::UpdateMetadata)?;
+        let bat_entry_count =
+            BatEntry::calculate_entries(DEFAULT_BLOCK_SIZE, virtual_disk_size, chunk_ratio, false);
+        let bat_length = Vhdx::bat_length(bat_entry_count);
+
+        // The BAT is all zeroes, none of the blocks being present.
+        file.set_len(BAT_OFFSET + bat_length as u64)
+            .map_err(VhdxError::ResizeFile)?;
+        VhdxHeader::create(&mut file, LOG_OFFSET, LOG_LENGTH).map_err(VhdxError::UpdateHeader)?;
+        vhdx_header::create_region_tables(
+            &mut file,
+            BAT_OFFSET,
+            bat_length,
+            METADATA_OFFSET,
+            METADATA_LENGTH,
+        )
+        .map_err(VhdxError::UpdateRegionTable)?;
+        let (mdr_entry, _) = Vhdx::regions(&mut file)?;
+        DiskSpec::create(
+            &mut file,
+            &mdr_entry,
+            DEFAULT_BLOCK_SIZE,
+            virtual_disk_size,
+            DEFAULT_LOGICAL_SECTOR_SIZE,
+            DEFAULT_PHYSICAL_SECTOR_SIZE,
+        )
+        .map_err(VhdxError::UpdateMetadata)?;
+        file.sync_all().map_err(VhdxError::ResizeFile)?;
+
+        Vhdx::with_parent_policy(file, &ParentPolicy::Forbid)
+    }
+
+    fn open(mut file: File, parent_policy: &ParentPolicy, depth: u32) -> Result<Vhdx> {
+        let mut vhdx_header = VhdxHeader::new(&mut file).map_err(VhdxError::ParseVhdxHeader)?;
+
+        // The log may hold updates of any of the structures parsed below, so
+        // it must be replayed first.
+        let read_only = crate::is_read_only(&file);
+        if vhdx_log::replay(
+            &mut file,
+            vhdx_header.log_guid(),
+            vhdx_header.log_offset(),
+            vhdx_header.log_length() as u64,
+            read_only,
+        )
+        .map_err(VhdxError::ReplayLog)?
+        {
+            vhdx_header
+                .clear_log(&mut file)
+                .map_err(VhdxError::UpdateHeader)?;
+        }
 
         let collected_entries = RegionInfo::new(
             &mut file,
@@ -67,6 +205,13 @@ impl Vhdx {
         let bat_entries = BatEntry::collect_bat_entries(&mut file, &disk_spec, &bat_entry)
             .map_err(VhdxError::ReadBatEntry)?;
 
+        let (parent, parent_path) = if disk_spec.has_parent {
+            let (parent, path) = Vhdx::open_parent(&file, &disk_spec, parent_policy, depth)?;
+            (Some(Box::new(parent)), Some(path))
+        } else {
+            (None, None)
+        };
+
         Ok(Vhdx {
             file,
             vhdx_header,
@@ -75,14 +220,283 @@ impl Vhdx {
             mdr_entry,
             disk_spec,
             bat_entries,
+            parent,
+            parent_path,
             current_offset: 0,
             first_write: true,
         })
     }
 
+    // Returns the metadata region and the BAT region entries of a newly
+    // created image.
+    fn regions(file: &mut File) -> Result<(RegionTableEntry, RegionTableEntry)> {
+        let collected_entries = RegionInfo::new(file, vhdx_header::REGION_TABLE_1_START, 2)
+            .map_err(VhdxError::ParseVhdxRegionEntry)?;
+        Ok((collected_entries.mdr_entry, collected_entries.bat_entry))
+    }
+
+    // Size of the BAT region holding `entry_count` entries
+    fn bat_length(entry_count: u64) -> u32 {
+        (div_round_up!(
+            entry_count * std::mem::size_of::<u64>() as u64,
+            REGION_ALIGNMENT
+        ) * REGION_ALIGNMENT) as u32
+    }
+
+    /// Open the parent of a differencing image read-only, and check it is the
+    /// image the differencing image was created from.
+    fn open_parent(
+        file: &File,
+        disk_spec: &DiskSpec,
+        parent_policy: &ParentPolicy,
+        depth: u32,
+    ) -> Result<(Vhdx, PathBuf)> {
+        if depth >= MAX_NESTING_DEPTH {
+            return Err(VhdxError::MaxNestingDepthExceeded);
+        }
+
+        let locator = disk_spec
+            .parent_locator
+            .as_ref()
+            .ok_or(VhdxError::ParentNotFound)?;
+        let path = match parent_policy {
+            ParentPolicy::Forbid => return Err(VhdxError::ParentForbidden),
+            ParentPolicy::Locator => Vhdx::find_parent(file, locator)?,
+            ParentPolicy::Override(path) => path.clone(),
+        };
+
+        let parent_file = OpenOptions::new()
+            .read(true)
+            .open(&path)
+            .map_err(VhdxError::OpenParent)?;
+        let parent = Vhdx::open(parent_file, &ParentPolicy::Locator, depth + 1)
+            .map_err(|e| VhdxError::ParseParent(Box::new(e)))?;
+
+        // The parent linkage is the data write GUID the parent had when the
+        // differencing image was created. It changes whenever the content of
+        // the parent is modified.
+        let data_write_guid =
+            crate::uuid_from_guid(&parent.vhdx_header.data_write_guid().to_le_bytes());
+        let linked = std::iter::once(&locator.parent_linkage)
+            .chain(locator.parent_linkage2.as_ref())
+            .any(|linkage| Uuid::parse_str(linkage).map_or(false, |guid| guid == data_write_guid));
+        if !linked || parent.disk_spec.logical_sector_size != disk_spec.logical_sector_size {
+            return Err(VhdxError::InvalidParent);
+        }
+
+        Ok((parent, path))
+    }
+
+    /// Look for the parent from the paths of the parent locator. Relative
+    /// paths are resolved from the directory holding the image. Absolute
+    /// Windows paths rarely exist on the host, so the parent is also looked
+    /// for under the same name next to the image.
+    fn find_parent(file: &File, locator: &ParentLocator) -> Result<PathBuf> {
+        let image_dir = std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            .ok()
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+            .unwrap_or_default();
+
+        let mut candidates = Vec::new();
+        if let Some(path) = &locator.relative_path {
+            candidates.push(image_dir.join(path.replace('\\', "/")));
+        }
+        if let Some(path) = &locator.absolute_win32_path {
+            if let Some(name) = Path::new(&path.replace('\\', "/")).file_name() {
+                candidates.push(image_dir.join(name));
+            }
+        }
+
+        candidates
+            .into_iter()
+            .find(|path| path.is_file())
+            .ok_or(VhdxError::ParentNotFound)
+    }
+
     pub fn virtual_disk_size(&self) -> u64 {
         self.disk_spec.virtual_disk_size
     }
+
+    /// Path of the parent opened along with a differencing image
+    pub fn parent_path(&self) -> Option<&Path> {
+        self.parent_path.as_deref()
+    }
+
+    /// Chain of parents opened along with a differencing image, from its
+    /// parent to the last one, along with their path
+    pub fn parent_files(&self) -> Vec<(&Path, &File)> {
+        let mut files = Vec::new();
+        let mut vhdx = self;
+        while let (Some(path), Some(parent)) = (&vhdx.parent_path, &vhdx.parent) {
+            files.push((path.as_path(), &parent.file));
+            vhdx = parent;
+        }
+        files
+    }
+
+    /// Grow the virtual disk to `virtual_disk_size` bytes. The BAT is moved
+    /// to the end of the file when its region has no room for the new
+    /// blocks, the previous region being left unused.
+    pub fn resize(&mut self, virtual_disk_size: u64) -> Result<()> {
+        if virtual_disk_size < self.disk_spec.virtual_disk_size {
+            return Err(VhdxError::ShrinkNotSupported);
+        }
+        if virtual_disk_size > MAX_DISK_SIZE
+            || virtual_disk_size % self.disk_spec.logical_sector_size as u64 != 0
+        {
+            return Err(VhdxError::InvalidDiskSize(virtual_disk_size));
+        }
+
+        if self.first_write {
+            self.first_write = false;
+            self.vhdx_header
+                .update(&mut self.file)
+                .map_err(VhdxError::UpdateHeader)?;
+        }
+
+        let entry_count = BatEntry::calculate_entries(
+            self.disk_spec.block_size,
+            virtual_disk_size,
+            self.disk_spec.chunk_ratio,
+            self.disk_spec.has_parent,
+        );
+        let mut bat_entries = self.bat_entries.clone();
+        bat_entries.resize(entry_count as usize, BatEntry(0));
+
+        if entry_count * std::mem::size_of::<u64>() as u64 > self.bat_entry.length as u64 {
+            let bat_offset =
+                div_round_up!(self.disk_spec.image_size, REGION_ALIGNMENT) * REGION_ALIGNMENT;
+            let bat_length = Vhdx::bat_length(entry_count);
+            let image_size = bat_offset + bat_length as u64;
+            self.file
+                .set_len(image_size)
+                .map_err(VhdxError::ResizeFile)?;
+            BatEntry::write_bat_entries(&mut self.file, bat_offset, &bat_entries)
+                .map_err(VhdxError::WriteBat)?;
+            // The new BAT must be on disk before the region tables refer to
+            // it.
+            self.file.sync_data().map_err(VhdxError::ResizeFile)?;
+            vhdx_header::update_bat_region(&mut self.file, bat_offset, bat_length)
+                .map_err(VhdxError::UpdateRegionTable)?;
+
+            let previous_bat_offset = self.bat_entry.file_offset;
+            self.region_entries.remove(&previous_bat_offset);
+            self.region_entries.insert(bat_offset, image_size);
+            self.bat_entry.file_offset = bat_offset;
+            self.bat_entry.length = bat_length;
+            self.disk_spec.image_size = image_size;
+        } else {
+            BatEntry::write_bat_entries(&mut self.file, self.bat_entry.file_offset, &bat_entries)
+                .map_err(VhdxError::WriteBat)?;
+        }
+
+        DiskSpec::write_virtual_disk_size(&mut self.file, &self.mdr_entry, virtual_disk_size)
+            .map_err(VhdxError::UpdateMetadata)?;
+        self.file.sync_data().map_err(VhdxError::ResizeFile)?;
+
+        self.bat_entries = bat_entries;
+        self.disk_spec.virtual_disk_size = virtual_disk_size;
+        self.disk_spec.total_sectors =
+            virtual_disk_size / self.disk_spec.logical_sector_size as u64;
+
+        Ok(())
+    }
+
+    /// Discard `length` bytes starting at `offset`, which read back as zeroes
+    /// afterwards, including on differencing images where they stop being
+    /// read from the parent. Whole blocks are released from the BAT.
+    pub fn discard(&mut self, offset: u64, length: u64) -> Result<()> {
+        if self.first_write {
+            self.first_write = false;
+            self.vhdx_header
+                .update(&mut self.file)
+                .map_err(VhdxError::UpdateHeader)?;
+        }
+
+        vhdx_io::discard(
+            &mut self.file,
+            &mut self.disk_spec,
+            self.bat_entry.file_offset,
+            &mut self.bat_entries,
+            self.parent.as_deref_mut(),
+            offset,
+            length,
+        )
+        .map_err(VhdxError::DiscardFailed)
+    }
+
+    /// Returns the offset in the file of the data at `offset`, along with the
+    /// number of bytes, up to `length`, stored contiguously from there. Data
+    /// that isn't stored in a fully present block has no offset and must be
+    /// read through `Read`.
+    pub fn map_read(&mut self, offset: u64, length: u64) -> Result<(Option<u64>, u64)> {
+        let sector_size = self.disk_spec.logical_sector_size as u64;
+        let (file_offset, sector_count) = vhdx_io::map_read(
+            &self.disk_spec,
+            &self.bat_entries,
+            offset / sector_size,
+            div_round_up!(length, sector_size),
+        )
+        .map_err(VhdxError::ReadFailed)?;
+
+        Ok(Vhdx::map_range(
+            offset,
+            length,
+            sector_size,
+            file_offset,
+            sector_count,
+        ))
+    }
+
+    /// Returns the offset in the file where the data at `offset` must be
+    /// written, along with the number of bytes, up to `length`, stored
+    /// contiguously from there. Blocks are allocated as needed. Data without
+    /// offset must be written through `Write`.
+    pub fn map_write(&mut self, offset: u64, length: u64) -> Result<(Option<u64>, u64)> {
+        if self.first_write {
+            self.first_write = false;
+            self.vhdx_header
+                .update(&mut self.file)
+                .map_err(VhdxError::UpdateHeader)?;
+        }
+
+        let sector_size = self.disk_spec.logical_sector_size as u64;
+        let (file_offset, sector_count) = vhdx_io::map_write(
+            &mut self.file,
+            &mut self.disk_spec,
+            self.bat_entry.file_offset,
+            &mut self.bat_entries,
+            offset / sector_size,
+            div_round_up!(length, sector_size),
+        )
+        .map_err(VhdxError::WriteFailed)?;
+
+        Ok(Vhdx::map_range(
+            offset,
+            length,
+            sector_size,
+            file_offset,
+            sector_count,
+        ))
+    }
+
+    // Converts the sectors mapped from the one holding `offset` to a byte
+    // range. Ranges not starting on a sector boundary are left unmapped.
+    fn map_range(
+        offset: u64,
+        length: u64,
+        sector_size: u64,
+        file_offset: Option<u64>,
+        sector_count: u64,
+    ) -> (Option<u64>, u64) {
+        let skip = offset % sector_size;
+        let count = std::cmp::min(sector_count * sector_size - skip, length);
+        if skip == 0 {
+            (file_offset, count)
+        } else {
+            (None, count)
+        }
+    }
 }
 
 impl Read for Vhdx {
@@ -93,11 +507,12 @@ impl Read for Vhdx {
             div_round_up!(buf.len() as u64, self.disk_spec.logical_sector_size as u64);
         let sector_index = self.current_offset / self.disk_spec.logical_sector_size as u64;
 
-        vhdx_io::read(
+        let count = vhdx_io::read(
             &mut self.file,
             buf,
             &self.disk_spec,
             &self.bat_entries,
+            self.parent.as_deref_mut(),
             sector_index,
             sector_count,
         )
@@ -109,7 +524,10 @@ impl Read for Vhdx {
                     sector_count, sector_index, e
                 ),
             )
-        })
+        })?;
+        self.current_offset += count as u64;
+
+        Ok(count)
     }
 }
 
@@ -135,7 +553,7 @@ impl Write for Vhdx {
             })?;
         }
 
-        vhdx_io::write(
+        let count = vhdx_io::write(
             &mut self.file,
             buf,
             &mut self.disk_spec,
@@ -152,7 +570,27 @@ impl Write for Vhdx {
                     sector_count, sector_index, e
                 ),
             )
-        })
+        })?;
+        self.current_offset += count as u64;
+
+        Ok(count)
+    }
+}
+
+impl PunchHole for Vhdx {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        self.discard(offset, length)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+}
+
+impl WriteZeroesAt for Vhdx {
+    /// Discarded ranges read back as zeroes and hide the parent of
+    /// differencing images, so zeroes don't need to be written out.
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> std::io::Result<usize> {
+        self.discard(offset, length as u64)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(length)
     }
 }
 
@@ -195,6 +633,12 @@ impl Seek for Vhdx {
     }
 }
 
+impl AsRawFd for Vhdx {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
 impl Clone for Vhdx {
     fn clone(&self) -> Self {
         Vhdx {
@@ -205,8 +649,358 @@ impl Clone for Vhdx {
             mdr_entry: self.mdr_entry,
             disk_spec: self.disk_spec.clone(),
             bat_entries: self.bat_entries.clone(),
+            parent: self.parent.clone(),
+            parent_path: self.parent_path.clone(),
             current_offset: self.current_offset,
             first_write: self.first_write,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhdx_header::tests::set_log_guid;
+    use crate::vhdx_log::tests::log_entry;
+    use crate::vhdx_metadata::tests::{make_differencing, parent_locator};
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempdir::TempDir;
+
+    const DISK_SIZE: u64 = 64 << 20;
+
+    fn open_rw(path: &Path) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap()
+    }
+
+    fn create_image(path: &Path) -> Vhdx {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .unwrap();
+        Vhdx::create(file, DISK_SIZE).unwrap()
+    }
+
+    // Creates a differencing image whose parent locator holds `entries`.
+    fn create_differencing(path: &Path, entries: &[(&str, &str)]) {
+        let mut vhdx = create_image(path);
+        make_differencing(&mut vhdx.file, &vhdx.mdr_entry, &parent_locator(entries));
+    }
+
+    // The parent linkage of the differencing images created from `path`
+    fn parent_linkage(path: &Path) -> String {
+        let parent = Vhdx::new(File::open(path).unwrap()).unwrap();
+        crate::uuid_from_guid(&parent.vhdx_header.data_write_guid().to_le_bytes())
+            .braced()
+            .to_string()
+    }
+
+    // The error which prevented the innermost parent from being opened
+    fn parent_error(mut e: VhdxError) -> VhdxError {
+        while let VhdxError::ParseParent(parent_error) = e {
+            e = *parent_error;
+        }
+        e
+    }
+
+    // Opens an image, looking for its parent through the parent locator
+    fn open_with_locator(file: File) -> Result<Vhdx> {
+        Vhdx::with_parent_policy(file, &ParentPolicy::Locator)
+    }
+
+    fn read_at(vhdx: &mut Vhdx, offset: u64, length: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; length];
+        vhdx.seek(SeekFrom::Start(offset)).unwrap();
+        vhdx.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_at(vhdx: &mut Vhdx, offset: u64, buf: &[u8]) {
+        vhdx.seek(SeekFrom::Start(offset)).unwrap();
+        vhdx.write_all(buf).unwrap();
+    }
+
+    #[test]
+    fn test_replay_log() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let path = dir.as_path().join("disk.vhdx");
+        drop(create_image(&path));
+
+        // Log an update of the virtual disk size, the first item of the
+        // metadata region after the file parameters.
+        let mut f = open_rw(&path);
+        let sector_offset = METADATA_OFFSET + (64 << 10);
+        let mut sector = vec![0u8; 4096];
+        f.read_exact_at(&mut sector, sector_offset).unwrap();
+        assert_eq!(LittleEndian::read_u64(&sector[8..16]), DISK_SIZE);
+        LittleEndian::write_u64(&mut sector[8..16], 2 * DISK_SIZE);
+
+        let log_guid = Uuid::new_v4().as_u128();
+        let file_size = f.metadata().unwrap().len();
+        let entry = log_entry(
+            log_guid,
+            1,
+            0,
+            file_size,
+            file_size,
+            &[(sector_offset, sector)],
+            &[],
+        );
+        f.write_all_at(&entry, LOG_OFFSET).unwrap();
+        set_log_guid(&mut f, log_guid);
+        drop(f);
+
+        // The log can't be replayed on a read-only image.
+        assert!(matches!(
+            Vhdx::new(File::open(&path).unwrap()),
+            Err(VhdxError::ReplayLog(VhdxLogError::ReadOnly))
+        ));
+
+        let vhdx = Vhdx::new(open_rw(&path)).unwrap();
+        assert_eq!(vhdx.virtual_disk_size(), 2 * DISK_SIZE);
+        assert_eq!(vhdx.vhdx_header.log_guid(), 0);
+        drop(vhdx);
+
+        // Once replayed, the log is empty.
+        let vhdx = Vhdx::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(vhdx.virtual_disk_size(), 2 * DISK_SIZE);
+    }
+
+    #[test]
+    fn test_differencing_read() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let base_path = dir.as_path().join("base.vhdx");
+        let mut base = create_image(&base_path);
+        write_at(&mut base, 0, &[0x55; 8192]);
+        write_at(&mut base, u64::from(DEFAULT_BLOCK_SIZE), &[0x66; 4096]);
+        drop(base);
+
+        let path = dir.as_path().join("disk.vhdx");
+        create_differencing(
+            &path,
+            &[
+                ("parent_linkage", &parent_linkage(&base_path)),
+                ("relative_path", ".\\base.vhdx"),
+            ],
+        );
+
+        // The parent locator is only followed when asked for.
+        assert!(matches!(
+            Vhdx::new(open_rw(&path)),
+            Err(VhdxError::ParentForbidden)
+        ));
+
+        let mut vhdx = open_with_locator(open_rw(&path)).unwrap();
+        assert_eq!(
+            vhdx.parent_path().unwrap(),
+            base_path.canonicalize().unwrap()
+        );
+
+        // Sectors not written in the differencing image are read from the
+        // parent, whether their block is allocated or not.
+        write_at(&mut vhdx, 4096, &[0xaa; 512]);
+        let buf = read_at(&mut vhdx, 0, 12288);
+        assert!(buf[..4096].iter().all(|b| *b == 0x55));
+        assert!(buf[4096..4608].iter().all(|b| *b == 0xaa));
+        assert!(buf[4608..8192].iter().all(|b| *b == 0x55));
+        assert!(buf[8192..].iter().all(|b| *b == 0));
+
+        let buf = read_at(&mut vhdx, u64::from(DEFAULT_BLOCK_SIZE), 8192);
+        assert!(buf[..4096].iter().all(|b| *b == 0x66));
+        assert!(buf[4096..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_differencing_write_zeroes() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let base_path = dir.as_path().join("base.vhdx");
+        let mut base = create_image(&base_path);
+        write_at(&mut base, 0, &[0x55; 8192]);
+        write_at(&mut base, u64::from(DEFAULT_BLOCK_SIZE), &[0x66; 4096]);
+        drop(base);
+
+        let path = dir.as_path().join("disk.vhdx");
+        create_differencing(
+            &path,
+            &[
+                ("parent_linkage", &parent_linkage(&base_path)),
+                ("relative_path", ".\\base.vhdx"),
+            ],
+        );
+
+        // Zeroes written to a differencing image hide the parent, whether
+        // they cover whole blocks, whole sectors or parts of a sector.
+        let mut vhdx = open_with_locator(open_rw(&path)).unwrap();
+        vhdx.write_zeroes_at(1024, 2048).unwrap();
+        vhdx.write_zeroes_at(5000, 100).unwrap();
+        vhdx.write_zeroes_at(u64::from(DEFAULT_BLOCK_SIZE), DEFAULT_BLOCK_SIZE as usize)
+            .unwrap();
+
+        let buf = read_at(&mut vhdx, 0, 8192);
+        assert!(buf[..1024].iter().all(|b| *b == 0x55));
+        assert!(buf[1024..3072].iter().all(|b| *b == 0));
+        assert!(buf[3072..5000].iter().all(|b| *b == 0x55));
+        assert!(buf[5000..5100].iter().all(|b| *b == 0));
+        assert!(buf[5100..].iter().all(|b| *b == 0x55));
+
+        let buf = read_at(&mut vhdx, u64::from(DEFAULT_BLOCK_SIZE), 4096);
+        assert!(buf.iter().all(|b| *b == 0));
+        drop(vhdx);
+
+        // The zeroes are persisted in the BAT and the sector bitmap.
+        let mut vhdx = open_with_locator(open_rw(&path)).unwrap();
+        let buf = read_at(&mut vhdx, 0, 8192);
+        assert!(buf[1024..3072].iter().all(|b| *b == 0));
+        assert!(buf[3072..5000].iter().all(|b| *b == 0x55));
+        let buf = read_at(&mut vhdx, u64::from(DEFAULT_BLOCK_SIZE), 4096);
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_parent_locator_resolution() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let base_path = dir.as_path().join("base.vhdx");
+        drop(create_image(&base_path));
+        let linkage = parent_linkage(&base_path);
+
+        // Absolute Windows paths fall back to the same name next to the image.
+        let path = dir.as_path().join("win32.vhdx");
+        create_differencing(
+            &path,
+            &[
+                ("parent_linkage", &linkage),
+                ("relative_path", ".\\missing.vhdx"),
+                ("absolute_win32_path", "\\\\?\\C:\\images\\base.vhdx"),
+            ],
+        );
+        let vhdx = open_with_locator(File::open(&path).unwrap()).unwrap();
+        assert_eq!(
+            vhdx.parent_path().unwrap(),
+            base_path.canonicalize().unwrap()
+        );
+
+        let path = dir.as_path().join("missing.vhdx");
+        create_differencing(
+            &path,
+            &[
+                ("parent_linkage", &linkage),
+                ("relative_path", ".\\other.vhdx"),
+                ("absolute_win32_path", "C:\\images\\other.vhdx"),
+            ],
+        );
+        assert!(matches!(
+            open_with_locator(File::open(&path).unwrap()),
+            Err(VhdxError::ParentNotFound)
+        ));
+
+        // The parent given by the user is used whatever the locator says.
+        let vhdx = Vhdx::with_parent_policy(
+            File::open(&path).unwrap(),
+            &ParentPolicy::Override(base_path.clone()),
+        )
+        .unwrap();
+        assert_eq!(vhdx.parent_path(), Some(base_path.as_path()));
+
+        assert!(matches!(
+            Vhdx::with_parent_policy(File::open(&path).unwrap(), &ParentPolicy::Forbid),
+            Err(VhdxError::ParentForbidden)
+        ));
+    }
+
+    #[test]
+    fn test_max_nesting_depth() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let mut parent_path = dir.as_path().join("disk0.vhdx");
+        drop(create_image(&parent_path));
+
+        for depth in 1..=MAX_NESTING_DEPTH + 1 {
+            let path = dir.as_path().join(format!("disk{}.vhdx", depth));
+            create_differencing(
+                &path,
+                &[
+                    ("parent_linkage", &parent_linkage(&parent_path)),
+                    ("relative_path", &format!("disk{}.vhdx", depth - 1)),
+                ],
+            );
+            parent_path = path;
+        }
+
+        let path = dir
+            .as_path()
+            .join(format!("disk{}.vhdx", MAX_NESTING_DEPTH));
+        open_with_locator(File::open(path).unwrap()).unwrap();
+        let e = open_with_locator(File::open(&parent_path).unwrap()).unwrap_err();
+        assert!(matches!(
+            parent_error(e),
+            VhdxError::MaxNestingDepthExceeded
+        ));
+    }
+
+    #[test]
+    fn test_parent_loop() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let linkage = Uuid::new_v4().braced().to_string();
+        let path_a = dir.as_path().join("a.vhdx");
+        let path_b = dir.as_path().join("b.vhdx");
+        create_differencing(
+            &path_a,
+            &[("parent_linkage", &linkage), ("relative_path", "b.vhdx")],
+        );
+        create_differencing(
+            &path_b,
+            &[("parent_linkage", &linkage), ("relative_path", "a.vhdx")],
+        );
+
+        let e = open_with_locator(File::open(&path_a).unwrap()).unwrap_err();
+        assert!(matches!(
+            parent_error(e),
+            VhdxError::MaxNestingDepthExceeded
+        ));
+    }
+
+    #[test]
+    fn test_parent_data_write_guid_mismatch() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let base_path = dir.as_path().join("base.vhdx");
+        drop(create_image(&base_path));
+
+        let path = dir.as_path().join("random.vhdx");
+        create_differencing(
+            &path,
+            &[
+                ("parent_linkage", &Uuid::new_v4().braced().to_string()),
+                ("relative_path", "base.vhdx"),
+            ],
+        );
+        assert!(matches!(
+            open_with_locator(File::open(&path).unwrap()),
+            Err(VhdxError::InvalidParent)
+        ));
+
+        let path = dir.as_path().join("disk.vhdx");
+        create_differencing(
+            &path,
+            &[
+                ("parent_linkage", &parent_linkage(&base_path)),
+                ("relative_path", "base.vhdx"),
+            ],
+        );
+        open_with_locator(File::open(&path).unwrap()).unwrap();
+
+        // Modifying the parent changes its data write GUID, breaking the
+        // link with the differencing image.
+        let mut base = Vhdx::new(open_rw(&base_path)).unwrap();
+        write_at(&mut base, 0, &[0x55; 4096]);
+        drop(base);
+        assert!(matches!(
+            open_with_locator(File::open(&path).unwrap()),
+            Err(VhdxError::InvalidParent)
+        ));
+    }
+}
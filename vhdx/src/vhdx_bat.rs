@@ -19,6 +19,10 @@ pub const PAYLOAD_BLOCK_UNMAPPED: u64 = 3;
 pub const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
 pub const PAYLOAD_BLOCK_PARTIALLY_PRESENT: u64 = 7;
 
+// Sector Bitmap BAT Entry States
+pub const SB_BLOCK_NOT_PRESENT: u64 = 0;
+pub const SB_BLOCK_PRESENT: u64 = 6;
+
 // Mask for the BAT state
 pub const BAT_STATE_BIT_MASK: u64 = 0x07;
 // Mask for the offset within the file in units of 1 MB
@@ -53,6 +57,7 @@ impl BatEntry {
             disk_spec.block_size,
             disk_spec.virtual_disk_size,
             disk_spec.chunk_ratio,
+            disk_spec.has_parent,
         );
         if entry_count as usize > (bat_entry.length as usize / size_of::<BatEntry>()) {
             return Err(VhdxBatError::InvalidEntryCount);
@@ -75,9 +80,54 @@ impl BatEntry {
     }
 
     // Calculate the number of entries in the BAT
-    fn calculate_entries(block_size: u32, virtual_disk_size: u64, chunk_ratio: u64) -> u64 {
+    pub fn calculate_entries(
+        block_size: u32,
+        virtual_disk_size: u64,
+        chunk_ratio: u64,
+        has_parent: bool,
+    ) -> u64 {
         let data_blocks_count = div_round_up!(virtual_disk_size, block_size as u64);
-        data_blocks_count + (data_blocks_count - 1) / chunk_ratio
+        if has_parent {
+            // Differencing images have a sector bitmap entry after each chunk,
+            // including the last one.
+            div_round_up!(data_blocks_count, chunk_ratio) * (chunk_ratio + 1)
+        } else {
+            data_blocks_count + data_blocks_count.saturating_sub(1) / chunk_ratio
+        }
+    }
+
+    /// Index in the BAT of the payload block entry for `block`, a sector
+    /// bitmap entry being interleaved after every chunk of blocks.
+    pub fn payload_index(disk_spec: &DiskSpec, block: u64) -> u64 {
+        block + block / disk_spec.chunk_ratio
+    }
+
+    /// Index in the BAT of the sector bitmap entry covering `block`
+    pub fn bitmap_index(disk_spec: &DiskSpec, block: u64) -> u64 {
+        (block / disk_spec.chunk_ratio) * (disk_spec.chunk_ratio + 1) + disk_spec.chunk_ratio
+    }
+
+    // Routine for writing a single BAT entry, at `index`, to the disk
+    pub fn write_bat_entry(
+        f: &mut File,
+        bat_offset: u64,
+        bat_entries: &[BatEntry],
+        index: u64,
+    ) -> Result<()> {
+        let bat_entry = match bat_entries.get(index as usize) {
+            Some(entry) => entry.0,
+            None => {
+                return Err(VhdxBatError::InvalidBatEntry);
+            }
+        };
+
+        f.seek(SeekFrom::Start(
+            bat_offset + index * size_of::<u64>() as u64,
+        ))
+        .map_err(VhdxBatError::WriteBat)?;
+        f.write_u64::<LittleEndian>(bat_entry)
+            .map_err(VhdxBatError::WriteBat)?;
+        Ok(())
     }
 
     // Routine for writing BAT entries to the disk
@@ -4,7 +4,7 @@
 
 extern crate log;
 
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use remain::sorted;
 use std::collections::btree_map::BTreeMap;
 use std::convert::TryInto;
@@ -29,6 +29,11 @@ const REGION_SIZE: u64 = 64 * 1024; // Each region size is 64 KiB
 
 const REGION_ENTRY_REQUIRED: u32 = 1;
 
+const HEADER_VERSION: u16 = 1;
+// Name of the application that created the file, stored as UTF-16 after the
+// signature.
+const CREATOR: &str = "Cloud Hypervisor";
+
 const BAT_GUID: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08"; // BAT GUID
 const MDR_GUID: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E"; // Metadata GUID
 
@@ -85,6 +90,8 @@ pub enum VhdxHeaderError {
     UnrecognizedRegionEntry,
     #[error("Failed to write header {0}")]
     WriteHeader(#[source] io::Error),
+    #[error("Failed to write region table {0}")]
+    WriteRegionTable(#[source] io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, VhdxHeaderError>;
@@ -108,6 +115,19 @@ impl FileTypeIdentifier {
 
         Ok(FileTypeIdentifier { signature })
     }
+
+    /// Writes the File Type Identifier structure of a new VHDx file
+    fn create(f: &mut File) -> Result<()> {
+        let mut buffer = vec![0u8; (HEADER_1_START - FILE_START) as usize];
+        LittleEndian::write_u64(&mut buffer[0..8], VHDX_SIGN);
+        for (i, unit) in CREATOR.encode_utf16().enumerate() {
+            LittleEndian::write_u16(&mut buffer[8 + 2 * i..10 + 2 * i], unit);
+        }
+
+        f.seek(SeekFrom::Start(FILE_START))
+            .map_err(VhdxHeaderError::WriteHeader)?;
+        f.write_all(&buffer).map_err(VhdxHeaderError::WriteHeader)
+    }
 }
 
 #[repr(packed)]
@@ -243,6 +263,80 @@ impl RegionTableHeader {
     }
 }
 
+/// Writes both region tables of a new VHDx file, describing the BAT and the
+/// metadata region.
+pub fn create_region_tables(
+    f: &mut File,
+    bat_offset: u64,
+    bat_length: u32,
+    metadata_offset: u64,
+    metadata_length: u32,
+) -> Result<()> {
+    let mut buffer = vec![0u8; REGION_SIZE as usize];
+    LittleEndian::write_u32(&mut buffer[0..4], REGION_SIGN);
+    LittleEndian::write_u32(&mut buffer[8..12], 2);
+
+    let entries = [
+        (BAT_GUID, bat_offset, bat_length),
+        (MDR_GUID, metadata_offset, metadata_length),
+    ];
+    let mut offset = size_of::<RegionTableHeader>();
+    for (guid, file_offset, length) in entries {
+        let guid = Uuid::parse_str(guid).map_err(VhdxHeaderError::InvalidUuid)?;
+        let mut entry = &mut buffer[offset..offset + size_of::<RegionTableEntry>()];
+        entry.write_all(&guid.to_bytes_le()).unwrap();
+        entry.write_u64::<LittleEndian>(file_offset).unwrap();
+        entry.write_u32::<LittleEndian>(length).unwrap();
+        entry
+            .write_u32::<LittleEndian>(REGION_ENTRY_REQUIRED)
+            .unwrap();
+        offset += size_of::<RegionTableEntry>();
+    }
+
+    write_region_table(f, REGION_TABLE_1_START, &mut buffer)?;
+    write_region_table(f, REGION_TABLE_2_START, &mut buffer)
+}
+
+/// Points the BAT entry of both region tables to the `length` bytes at
+/// `file_offset`, the other entries being kept as they are.
+pub fn update_bat_region(f: &mut File, file_offset: u64, length: u32) -> Result<()> {
+    let bat_guid = Uuid::parse_str(BAT_GUID).map_err(VhdxHeaderError::InvalidUuid)?;
+    for start in [REGION_TABLE_1_START, REGION_TABLE_2_START] {
+        let region_table_header = RegionTableHeader::new(f, start)?;
+
+        let mut buffer = vec![0u8; REGION_SIZE as usize];
+        f.seek(SeekFrom::Start(start))
+            .map_err(VhdxHeaderError::SeekRegionTableEntries)?;
+        f.read_exact(&mut buffer)
+            .map_err(VhdxHeaderError::ReadRegionTableEntries)?;
+
+        for i in 0..region_table_header.entry_count as usize {
+            let offset = size_of::<RegionTableHeader>() + i * size_of::<RegionTableEntry>();
+            if crate::uuid_from_guid(&buffer[offset..offset + 16]) == bat_guid {
+                LittleEndian::write_u64(&mut buffer[offset + 16..offset + 24], file_offset);
+                LittleEndian::write_u32(&mut buffer[offset + 24..offset + 28], length);
+            }
+        }
+
+        write_region_table(f, start, &mut buffer)?;
+    }
+
+    Ok(())
+}
+
+/// Computes the checksum of the region table in `buffer` and writes it at
+/// `start`.
+fn write_region_table(f: &mut File, start: u64, buffer: &mut [u8]) -> Result<()> {
+    LittleEndian::write_u32(&mut buffer[4..8], 0);
+    let checksum = crc32c::crc32c(buffer);
+    LittleEndian::write_u32(&mut buffer[4..8], checksum);
+
+    f.seek(SeekFrom::Start(start))
+        .map_err(VhdxHeaderError::WriteRegionTable)?;
+    f.write_all(buffer)
+        .map_err(VhdxHeaderError::WriteRegionTable)
+}
+
 pub struct RegionInfo {
     pub bat_entry: RegionTableEntry,
     pub mdr_entry: RegionTableEntry,
@@ -374,14 +468,20 @@ impl VhdxHeader {
         let header_1 = Header::new(f, HEADER_1_START);
         let header_2 = Header::new(f, HEADER_2_START);
 
-        let mut file_write_guid: u128 = 0;
-        let metadata = f.metadata().map_err(VhdxHeaderError::ReadMetadata)?;
-        if !metadata.permissions().readonly() {
-            file_write_guid = Uuid::new_v4().as_u128();
-        }
+        // Images opened read-only, such as the parents of differencing
+        // images, are left untouched.
+        let (header_1, header_2) = if crate::is_read_only(f) {
+            let (_, current_header) = VhdxHeader::current_header(header_1, header_2)?;
+            (current_header, current_header)
+        } else {
+            let mut file_write_guid: u128 = 0;
+            let metadata = f.metadata().map_err(VhdxHeaderError::ReadMetadata)?;
+            if !metadata.permissions().readonly() {
+                file_write_guid = Uuid::new_v4().as_u128();
+            }
 
-        let (header_1, header_2) =
-            VhdxHeader::update_headers(f, header_1, header_2, file_write_guid)?;
+            VhdxHeader::update_headers(f, header_1, header_2, file_write_guid)?
+        };
         Ok(VhdxHeader {
             _file_type_identifier,
             header_1,
@@ -391,6 +491,30 @@ impl VhdxHeader {
         })
     }
 
+    /// Writes the File Type Identifier and both headers of a new VHDx file,
+    /// with an empty log of `log_length` bytes at `log_offset`.
+    pub fn create(f: &mut File, log_offset: u64, log_length: u32) -> Result<()> {
+        FileTypeIdentifier::create(f)?;
+
+        let header = Header {
+            signature: HEADER_SIGN,
+            checksum: 0,
+            sequence_number: 0,
+            file_write_guid: 0,
+            data_write_guid: 0,
+            log_guid: 0,
+            log_version: 0,
+            version: HEADER_VERSION,
+            log_length,
+            log_offset,
+        };
+        let header_1 =
+            Header::update_header(f, &header, true, Uuid::new_v4().as_u128(), HEADER_1_START)?;
+        Header::update_header(f, &header_1, false, 0, HEADER_2_START)?;
+
+        Ok(())
+    }
+
     /// Identify the current header and return both headers along with an
     /// integer indicating the current header.
     fn current_header(
@@ -464,9 +588,41 @@ impl VhdxHeader {
         Ok(())
     }
 
+    /// Marks the log as empty once it has been replayed
+    pub fn clear_log(&mut self, f: &mut File) -> Result<()> {
+        self.header_1.log_guid = 0;
+        self.header_2.log_guid = 0;
+        self.update(f)
+    }
+
     pub fn region_entry_count(&self) -> u32 {
         self.region_table_1.entry_count
     }
+
+    // The header with the highest sequence number is the current one
+    fn current(&self) -> Header {
+        if self.header_1.sequence_number >= self.header_2.sequence_number {
+            self.header_1
+        } else {
+            self.header_2
+        }
+    }
+
+    pub fn data_write_guid(&self) -> u128 {
+        self.current().data_write_guid
+    }
+
+    pub fn log_guid(&self) -> u128 {
+        self.current().log_guid
+    }
+
+    pub fn log_length(&self) -> u32 {
+        self.current().log_length
+    }
+
+    pub fn log_offset(&self) -> u64 {
+        self.current().log_offset
+    }
 }
 
 /// Calculates the checksum of a buffer that itself containts its checksum
@@ -487,3 +643,17 @@ pub fn calculate_checksum(buffer: &mut [u8], csum_offset: usize) -> Result<u32>
 
     Ok(new_csum)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Marks the log of the image as holding the entries of `log_guid`.
+    pub(crate) fn set_log_guid(f: &mut File, log_guid: u128) {
+        for start in [HEADER_1_START, HEADER_2_START] {
+            let mut header = Header::new(f, start).unwrap();
+            header.log_guid = log_guid;
+            Header::update_header(f, &header, false, 0, start).unwrap();
+        }
+    }
+}
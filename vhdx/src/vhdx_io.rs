@@ -2,14 +2,18 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::vhdx::Vhdx;
 use crate::vhdx_bat::{self, BatEntry, VhdxBatError};
 use crate::vhdx_metadata::{self, DiskSpec};
 use remain::sorted;
+use std::cmp::min;
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use thiserror::Error;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
 
-const SECTOR_SIZE: u64 = 512;
+// Each sector bitmap block is 1 MiB
+const SECTOR_BITMAP_BLOCK_SIZE: u64 = 1024 * 1024;
 
 #[sorted]
 #[derive(Error, Debug)]
@@ -20,14 +24,22 @@ pub enum VhdxIoError {
     InvalidBatIndex,
     #[error("Invalid disk size")]
     InvalidDiskSize,
+    #[error("Differencing image has no parent")]
+    MissingParent,
+    #[error("Failed reading sectors from parent {0}")]
+    ReadParent(#[source] io::Error),
+    #[error("Failed reading sector bitmap from file {0}")]
+    ReadSectorBitmap(#[source] io::Error),
     #[error("Failed reading sector blocks from file {0}")]
     ReadSectorBlock(#[source] io::Error),
     #[error("Failed changing file length {0}")]
     ResizeFile(#[source] io::Error),
-    #[error("Differencing mode is not supported yet")]
-    UnsupportedMode,
     #[error("Failed writing BAT to file {0}")]
     WriteBat(#[source] VhdxBatError),
+    #[error("Failed writing sector bitmap to file {0}")]
+    WriteSectorBitmap(#[source] io::Error),
+    #[error("Failed zeroing sector blocks in file {0}")]
+    ZeroSectorBlock(#[source] io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, VhdxIoError>;
@@ -37,8 +49,7 @@ macro_rules! align {
         if $align > $n {
             $align
         } else {
-            let rem = $n % $align;
-            (($n / $align) + rem) * $align
+            div_round_up!($n, $align) * $align
         }
     }};
 }
@@ -63,7 +74,8 @@ impl Sector {
     ) -> Result<Sector> {
         let mut sector = Sector::default();
 
-        sector.bat_index = sector_index / disk_spec.sectors_per_block as u64;
+        sector.bat_index =
+            BatEntry::payload_index(disk_spec, sector_index / disk_spec.sectors_per_block as u64);
         sector.block_offset = sector_index % disk_spec.sectors_per_block as u64;
         sector.free_sectors = disk_spec.sectors_per_block as u64 - sector.block_offset;
         if sector.free_sectors > sector_count {
@@ -88,56 +100,318 @@ impl Sector {
     }
 }
 
+/// Bits of the sector bitmap of a differencing image for a range of sectors,
+/// telling which ones are present in the image rather than in its parent.
+struct SectorBitmap {
+    file_offset: u64,
+    bytes: Vec<u8>,
+    first_bit: u64,
+}
+
+impl SectorBitmap {
+    /// Read the bits for `sector_count` sectors from `sector_index`, from the
+    /// sector bitmap block at `block_offset` in the file.
+    fn read(
+        f: &mut File,
+        disk_spec: &DiskSpec,
+        block_offset: u64,
+        sector_index: u64,
+        sector_count: u64,
+    ) -> Result<SectorBitmap> {
+        let chunk_sectors = disk_spec.sectors_per_block as u64 * disk_spec.chunk_ratio;
+        let bit = sector_index % chunk_sectors;
+        let first_bit = bit % 8;
+        let file_offset = block_offset + bit / 8;
+
+        let mut bytes = vec![0u8; div_round_up!(first_bit + sector_count, 8) as usize];
+        f.seek(SeekFrom::Start(file_offset))
+            .map_err(VhdxIoError::ReadSectorBitmap)?;
+        f.read_exact(&mut bytes)
+            .map_err(VhdxIoError::ReadSectorBitmap)?;
+
+        Ok(SectorBitmap {
+            file_offset,
+            bytes,
+            first_bit,
+        })
+    }
+
+    fn is_present(&self, sector: u64) -> bool {
+        let bit = self.first_bit + sector;
+        self.bytes[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+    }
+
+    fn set_present(&mut self, sector: u64, count: u64) {
+        for bit in self.first_bit + sector..self.first_bit + sector + count {
+            self.bytes[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    fn write(&self, f: &mut File) -> Result<()> {
+        f.seek(SeekFrom::Start(self.file_offset))
+            .map_err(VhdxIoError::WriteSectorBitmap)?;
+        f.write_all(&self.bytes)
+            .map_err(VhdxIoError::WriteSectorBitmap)
+    }
+}
+
+/// Offset in the file of the sector bitmap block covering `sector_index`, if
+/// it has been allocated.
+fn sector_bitmap_offset(
+    disk_spec: &DiskSpec,
+    bat: &[BatEntry],
+    sector_index: u64,
+) -> Result<Option<u64>> {
+    let block = sector_index / disk_spec.sectors_per_block as u64;
+    let bat_entry = match bat.get(BatEntry::bitmap_index(disk_spec, block) as usize) {
+        Some(entry) => entry.0,
+        None => {
+            return Err(VhdxIoError::InvalidBatIndex);
+        }
+    };
+
+    match bat_entry & vhdx_bat::BAT_STATE_BIT_MASK {
+        vhdx_bat::SB_BLOCK_NOT_PRESENT => Ok(None),
+        vhdx_bat::SB_BLOCK_PRESENT => Ok(Some(bat_entry & vhdx_bat::BAT_FILE_OFF_MASK)),
+        _ => Err(VhdxIoError::InvalidBatEntryState),
+    }
+}
+
+/// Allocate `size` bytes at the end of the file, returning their offset.
+fn allocate(f: &mut File, disk_spec: &mut DiskSpec, size: u64) -> Result<u64> {
+    let file_offset = align!(disk_spec.image_size, vhdx_metadata::BLOCK_SIZE_MIN as u64);
+    let new_size = file_offset
+        .checked_add(size)
+        .ok_or(VhdxIoError::InvalidDiskSize)?;
+
+    f.set_len(new_size).map_err(VhdxIoError::ResizeFile)?;
+    disk_spec.image_size = new_size;
+
+    Ok(file_offset)
+}
+
+/// Read from the parent of a differencing image, starting at `sector_index`.
+/// The part beyond the end of the parent reads as zeroes.
+fn read_parent(
+    parent: Option<&mut Vhdx>,
+    disk_spec: &DiskSpec,
+    sector_index: u64,
+    buf: &mut [u8],
+) -> Result<()> {
+    let parent = parent.ok_or(VhdxIoError::MissingParent)?;
+    let offset = sector_index * disk_spec.logical_sector_size as u64;
+    let len = min(
+        buf.len() as u64,
+        parent.virtual_disk_size().saturating_sub(offset),
+    ) as usize;
+
+    if len > 0 {
+        parent
+            .seek(SeekFrom::Start(offset))
+            .map_err(VhdxIoError::ReadParent)?;
+        parent
+            .read_exact(&mut buf[..len])
+            .map_err(VhdxIoError::ReadParent)?;
+    }
+    buf[len..].fill(0);
+
+    Ok(())
+}
+
+/// Allocate the payload block at `bat_index`, holding `sector_index`, and
+/// return its offset in the file. Partially present blocks also get a sector
+/// bitmap block if they don't have one yet.
+fn allocate_block(
+    f: &mut File,
+    disk_spec: &mut DiskSpec,
+    bat_offset: u64,
+    bat: &mut [BatEntry],
+    bat_index: u64,
+    sector_index: u64,
+    partial: bool,
+) -> Result<u64> {
+    let new_state = if partial {
+        // Make sure the sector bitmap exists before the block refers to it.
+        if sector_bitmap_offset(disk_spec, bat, sector_index)?.is_none() {
+            let bitmap_offset = allocate(f, disk_spec, SECTOR_BITMAP_BLOCK_SIZE)?;
+            let block = sector_index / disk_spec.sectors_per_block as u64;
+            let bitmap_index = BatEntry::bitmap_index(disk_spec, block);
+            bat[bitmap_index as usize] = BatEntry(bitmap_offset | vhdx_bat::SB_BLOCK_PRESENT);
+            BatEntry::write_bat_entry(f, bat_offset, bat, bitmap_index)
+                .map_err(VhdxIoError::WriteBat)?;
+        }
+        vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT
+    } else {
+        vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT
+    };
+
+    let file_offset = allocate(f, disk_spec, disk_spec.block_size as u64)?;
+    let new_bat_entry = file_offset | (new_state & vhdx_bat::BAT_STATE_BIT_MASK);
+    bat[bat_index as usize] = BatEntry(new_bat_entry);
+    BatEntry::write_bat_entry(f, bat_offset, bat, bat_index).map_err(VhdxIoError::WriteBat)?;
+
+    Ok(file_offset)
+}
+
+/// Offset in the file of the data at `sector_index`, if it is stored in a
+/// fully present block, along with the number of sectors, up to
+/// `sector_count`, stored contiguously from there. Other data must go
+/// through the read routine.
+pub fn map_read(
+    disk_spec: &DiskSpec,
+    bat: &[BatEntry],
+    sector_index: u64,
+    sector_count: u64,
+) -> Result<(Option<u64>, u64)> {
+    let sector = Sector::new(disk_spec, bat, sector_index, sector_count)?;
+    let state = bat[sector.bat_index as usize].0 & vhdx_bat::BAT_STATE_BIT_MASK;
+
+    if state == vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT
+        && sector.file_offset >= vhdx_metadata::BLOCK_SIZE_MIN as u64
+    {
+        Ok((Some(sector.file_offset), sector.free_sectors))
+    } else {
+        Ok((None, sector.free_sectors))
+    }
+}
+
+/// Offset in the file where the data at `sector_index` must be written,
+/// along with the number of sectors, up to `sector_count`, stored
+/// contiguously from there. Blocks are allocated as needed, except the ones
+/// of differencing images which are partially present: their sector bitmap
+/// must be updated through the write routine.
+pub fn map_write(
+    f: &mut File,
+    disk_spec: &mut DiskSpec,
+    bat_offset: u64,
+    bat: &mut [BatEntry],
+    sector_index: u64,
+    sector_count: u64,
+) -> Result<(Option<u64>, u64)> {
+    let sector = Sector::new(disk_spec, bat, sector_index, sector_count)?;
+    let state = bat[sector.bat_index as usize].0 & vhdx_bat::BAT_STATE_BIT_MASK;
+
+    let file_offset = match state {
+        vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT | vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT
+            if disk_spec.has_parent =>
+        {
+            return Ok((None, sector.free_sectors));
+        }
+        vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT
+        | vhdx_bat::PAYLOAD_BLOCK_UNDEFINED
+        | vhdx_bat::PAYLOAD_BLOCK_UNMAPPED
+        | vhdx_bat::PAYLOAD_BLOCK_ZERO => {
+            allocate_block(
+                f,
+                disk_spec,
+                bat_offset,
+                bat,
+                sector.bat_index,
+                sector_index,
+                false,
+            )? + sector.block_offset
+        }
+        vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT => sector.file_offset,
+        _ => {
+            return Err(VhdxIoError::InvalidBatEntryState);
+        }
+    };
+
+    if file_offset < vhdx_metadata::BLOCK_SIZE_MIN as u64 {
+        return Err(VhdxIoError::InvalidBatEntryState);
+    }
+
+    Ok((Some(file_offset), sector.free_sectors))
+}
+
 /// VHDx IO read routine: requires relative sector index and count for the
-/// requested data.
+/// requested data. Sectors that aren't present in a differencing image are
+/// read from its parent.
 pub fn read(
     f: &mut File,
     buf: &mut [u8],
     disk_spec: &DiskSpec,
     bat: &[BatEntry],
+    mut parent: Option<&mut Vhdx>,
     mut sector_index: u64,
     mut sector_count: u64,
 ) -> Result<usize> {
     let mut read_count: usize = 0;
+    let sector_size = disk_spec.logical_sector_size as usize;
 
     while sector_count > 0 {
-        if disk_spec.has_parent {
-            return Err(VhdxIoError::UnsupportedMode);
-        } else {
-            let sector = Sector::new(disk_spec, bat, sector_index, sector_count)?;
+        let sector = Sector::new(disk_spec, bat, sector_index, sector_count)?;
 
-            let bat_entry = match bat.get(sector.bat_index as usize) {
-                Some(entry) => entry.0,
-                None => {
-                    return Err(VhdxIoError::InvalidBatIndex);
-                }
-            };
-
-            match bat_entry & vhdx_bat::BAT_STATE_BIT_MASK {
-                vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT
-                | vhdx_bat::PAYLOAD_BLOCK_UNDEFINED
-                | vhdx_bat::PAYLOAD_BLOCK_UNMAPPED
-                | vhdx_bat::PAYLOAD_BLOCK_ZERO => {}
-                vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT => {
-                    f.seek(SeekFrom::Start(sector.file_offset))
-                        .map_err(VhdxIoError::ReadSectorBlock)?;
-                    f.read_exact(
-                        &mut buf[read_count
-                            ..(read_count + (sector.free_sectors * SECTOR_SIZE) as usize)],
-                    )
+        let bat_entry = match bat.get(sector.bat_index as usize) {
+            Some(entry) => entry.0,
+            None => {
+                return Err(VhdxIoError::InvalidBatIndex);
+            }
+        };
+
+        let end = min(read_count + sector.free_bytes as usize, buf.len());
+        let data = &mut buf[read_count..end];
+
+        match bat_entry & vhdx_bat::BAT_STATE_BIT_MASK {
+            vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT if disk_spec.has_parent => {
+                read_parent(parent.as_deref_mut(), disk_spec, sector_index, data)?;
+            }
+            vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT
+            | vhdx_bat::PAYLOAD_BLOCK_UNDEFINED
+            | vhdx_bat::PAYLOAD_BLOCK_UNMAPPED
+            | vhdx_bat::PAYLOAD_BLOCK_ZERO => data.fill(0),
+            vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT => {
+                f.seek(SeekFrom::Start(sector.file_offset))
                     .map_err(VhdxIoError::ReadSectorBlock)?;
+                f.read_exact(data).map_err(VhdxIoError::ReadSectorBlock)?;
+            }
+            vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT if disk_spec.has_parent => {
+                let bitmap_offset = sector_bitmap_offset(disk_spec, bat, sector_index)?
+                    .ok_or(VhdxIoError::InvalidBatEntryState)?;
+                let bitmap = SectorBitmap::read(
+                    f,
+                    disk_spec,
+                    bitmap_offset,
+                    sector_index,
+                    sector.free_sectors,
+                )?;
+
+                // Read runs of sectors from the image or from the parent.
+                let mut start = 0;
+                while start < sector.free_sectors {
+                    let present = bitmap.is_present(start);
+                    let mut next = start + 1;
+                    while next < sector.free_sectors && bitmap.is_present(next) == present {
+                        next += 1;
+                    }
+
+                    let range = min(start as usize * sector_size, data.len())
+                        ..min(next as usize * sector_size, data.len());
+                    if present {
+                        f.seek(SeekFrom::Start(
+                            sector.file_offset + start * sector_size as u64,
+                        ))
+                        .map_err(VhdxIoError::ReadSectorBlock)?;
+                        f.read_exact(&mut data[range])
+                            .map_err(VhdxIoError::ReadSectorBlock)?;
+                    } else {
+                        read_parent(
+                            parent.as_deref_mut(),
+                            disk_spec,
+                            sector_index + start,
+                            &mut data[range],
+                        )?;
+                    }
+                    start = next;
                 }
-                vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT => {
-                    return Err(VhdxIoError::UnsupportedMode);
-                }
-                _ => {
-                    return Err(VhdxIoError::InvalidBatEntryState);
-                }
-            };
-            sector_count -= sector.free_sectors;
-            sector_index += sector.free_sectors;
-            read_count = sector.free_bytes as usize;
+            }
+            _ => {
+                return Err(VhdxIoError::InvalidBatEntryState);
+            }
         };
+        sector_count -= sector.free_sectors;
+        sector_index += sector.free_sectors;
+        read_count = end;
     }
     Ok(read_count)
 }
@@ -156,74 +430,209 @@ pub fn write(
     let mut write_count: usize = 0;
 
     while sector_count > 0 {
-        if disk_spec.has_parent {
-            return Err(VhdxIoError::UnsupportedMode);
-        } else {
-            let sector = Sector::new(disk_spec, bat, sector_index, sector_count)?;
+        let sector = Sector::new(disk_spec, bat, sector_index, sector_count)?;
 
-            let bat_entry = match bat.get(sector.bat_index as usize) {
-                Some(entry) => entry.0,
-                None => {
-                    return Err(VhdxIoError::InvalidBatIndex);
-                }
-            };
-
-            match bat_entry & vhdx_bat::BAT_STATE_BIT_MASK {
-                vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT
-                | vhdx_bat::PAYLOAD_BLOCK_UNDEFINED
-                | vhdx_bat::PAYLOAD_BLOCK_UNMAPPED
-                | vhdx_bat::PAYLOAD_BLOCK_ZERO => {
-                    let file_offset =
-                        align!(disk_spec.image_size, vhdx_metadata::BLOCK_SIZE_MIN as u64);
-                    let new_size = file_offset
-                        .checked_add(disk_spec.block_size as u64)
-                        .ok_or(VhdxIoError::InvalidDiskSize)?;
-
-                    f.set_len(new_size).map_err(VhdxIoError::ResizeFile)?;
-                    disk_spec.image_size = new_size;
-
-                    let new_bat_entry = file_offset
-                        | (vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT & vhdx_bat::BAT_STATE_BIT_MASK);
-                    bat[sector.bat_index as usize] = BatEntry(new_bat_entry);
-                    BatEntry::write_bat_entries(f, bat_offset, bat)
-                        .map_err(VhdxIoError::WriteBat)?;
-
-                    if file_offset < vhdx_metadata::BLOCK_SIZE_MIN as u64 {
-                        break;
-                    }
+        let bat_entry = match bat.get(sector.bat_index as usize) {
+            Some(entry) => entry.0,
+            None => {
+                return Err(VhdxIoError::InvalidBatIndex);
+            }
+        };
 
-                    f.seek(SeekFrom::Start(file_offset))
-                        .map_err(VhdxIoError::ReadSectorBlock)?;
-                    f.write_all(
-                        &buf[write_count
-                            ..(write_count + (sector.free_sectors * SECTOR_SIZE) as usize)],
-                    )
-                    .map_err(VhdxIoError::ReadSectorBlock)?;
-                }
-                vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT => {
-                    if sector.file_offset < vhdx_metadata::BLOCK_SIZE_MIN as u64 {
-                        break;
-                    }
+        let state = bat_entry & vhdx_bat::BAT_STATE_BIT_MASK;
+        // Blocks of a differencing image that aren't present are only
+        // partially written, the other sectors are still read from the
+        // parent.
+        let partial = disk_spec.has_parent
+            && (state == vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT
+                || state == vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT);
 
-                    f.seek(SeekFrom::Start(sector.file_offset))
-                        .map_err(VhdxIoError::ReadSectorBlock)?;
-                    f.write_all(
-                        &buf[write_count
-                            ..(write_count + (sector.free_sectors * SECTOR_SIZE) as usize)],
-                    )
-                    .map_err(VhdxIoError::ReadSectorBlock)?;
-                }
-                vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT => {
-                    return Err(VhdxIoError::UnsupportedMode);
-                }
-                _ => {
-                    return Err(VhdxIoError::InvalidBatEntryState);
-                }
-            };
-            sector_count -= sector.free_sectors;
-            sector_index += sector.free_sectors;
-            write_count = sector.free_bytes as usize;
+        let file_offset = match state {
+            vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT
+            | vhdx_bat::PAYLOAD_BLOCK_UNDEFINED
+            | vhdx_bat::PAYLOAD_BLOCK_UNMAPPED
+            | vhdx_bat::PAYLOAD_BLOCK_ZERO => {
+                allocate_block(
+                    f,
+                    disk_spec,
+                    bat_offset,
+                    bat,
+                    sector.bat_index,
+                    sector_index,
+                    partial,
+                )? + sector.block_offset
+            }
+            vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT => sector.file_offset,
+            vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT if partial => sector.file_offset,
+            _ => {
+                return Err(VhdxIoError::InvalidBatEntryState);
+            }
         };
+
+        if file_offset < vhdx_metadata::BLOCK_SIZE_MIN as u64 {
+            break;
+        }
+
+        let end = min(write_count + sector.free_bytes as usize, buf.len());
+        f.seek(SeekFrom::Start(file_offset))
+            .map_err(VhdxIoError::ReadSectorBlock)?;
+        f.write_all(&buf[write_count..end])
+            .map_err(VhdxIoError::ReadSectorBlock)?;
+
+        if partial {
+            let bitmap_offset = sector_bitmap_offset(disk_spec, bat, sector_index)?
+                .ok_or(VhdxIoError::InvalidBatEntryState)?;
+            let mut bitmap = SectorBitmap::read(
+                f,
+                disk_spec,
+                bitmap_offset,
+                sector_index,
+                sector.free_sectors,
+            )?;
+            bitmap.set_present(0, sector.free_sectors);
+            bitmap.write(f)?;
+        }
+
+        sector_count -= sector.free_sectors;
+        sector_index += sector.free_sectors;
+        write_count = end;
     }
     Ok(write_count)
 }
+
+/// Zero a range of a differencing image through the write routine, so that
+/// the sectors stop being read from the parent. Sectors only partly covered
+/// keep the rest of their content.
+fn zero_sectors(
+    f: &mut File,
+    disk_spec: &mut DiskSpec,
+    bat_offset: u64,
+    bat: &mut [BatEntry],
+    mut parent: Option<&mut Vhdx>,
+    offset: u64,
+    length: u64,
+) -> Result<()> {
+    let sector_size = disk_spec.logical_sector_size as u64;
+    let sector_index = offset / sector_size;
+    let sector_count = div_round_up!(offset + length, sector_size) - sector_index;
+    let start = (offset - sector_index * sector_size) as usize;
+    let end = start + length as usize;
+
+    let mut buffer = vec![0u8; (sector_count * sector_size) as usize];
+    if start % sector_size as usize != 0 {
+        read(
+            f,
+            &mut buffer[..sector_size as usize],
+            disk_spec,
+            bat,
+            parent.as_deref_mut(),
+            sector_index,
+            1,
+        )?;
+    }
+    if end % sector_size as usize != 0 {
+        let last = buffer.len() - sector_size as usize;
+        read(
+            f,
+            &mut buffer[last..],
+            disk_spec,
+            bat,
+            parent,
+            sector_index + sector_count - 1,
+            1,
+        )?;
+    }
+    buffer[start..end].fill(0);
+
+    write(
+        f,
+        &buffer,
+        disk_spec,
+        bat_offset,
+        bat,
+        sector_index,
+        sector_count,
+    )?;
+
+    Ok(())
+}
+
+/// VHDx IO discard routine: requires the offset and length in bytes of the
+/// range to discard. Fully covered blocks are released through the BAT,
+/// partially covered ones are zeroed in place.
+pub fn discard(
+    f: &mut File,
+    disk_spec: &mut DiskSpec,
+    bat_offset: u64,
+    bat: &mut [BatEntry],
+    mut parent: Option<&mut Vhdx>,
+    mut offset: u64,
+    mut length: u64,
+) -> Result<()> {
+    let block_size = disk_spec.block_size as u64;
+
+    while length > 0 {
+        let bat_index = BatEntry::payload_index(disk_spec, offset / block_size);
+        let block_offset = offset % block_size;
+        let count = min(block_size - block_offset, length);
+
+        let bat_entry = match bat.get(bat_index as usize) {
+            Some(entry) => entry.0,
+            None => {
+                return Err(VhdxIoError::InvalidBatIndex);
+            }
+        };
+
+        match bat_entry & vhdx_bat::BAT_STATE_BIT_MASK {
+            vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT | vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT
+                if count == block_size =>
+            {
+                let file_offset = bat_entry & vhdx_bat::BAT_FILE_OFF_MASK;
+                bat[bat_index as usize] = BatEntry(vhdx_bat::PAYLOAD_BLOCK_ZERO);
+                BatEntry::write_bat_entry(f, bat_offset, bat, bat_index)
+                    .map_err(VhdxIoError::WriteBat)?;
+
+                // The block isn't referenced anymore, its storage can be
+                // given back. Not all filesystems support punching holes,
+                // which is fine since the block reads as zeroes anyway.
+                let _ = f.punch_hole(file_offset, block_size);
+            }
+            vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT if count == block_size && disk_spec.has_parent => {
+                // Zero blocks hide the content of the parent.
+                bat[bat_index as usize] = BatEntry(vhdx_bat::PAYLOAD_BLOCK_ZERO);
+                BatEntry::write_bat_entry(f, bat_offset, bat, bat_index)
+                    .map_err(VhdxIoError::WriteBat)?;
+            }
+            vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT => {
+                let file_offset = bat_entry & vhdx_bat::BAT_FILE_OFF_MASK;
+                f.write_all_zeroes_at(file_offset + block_offset, count as usize)
+                    .map_err(VhdxIoError::ZeroSectorBlock)?;
+            }
+            vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT | vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT
+                if disk_spec.has_parent =>
+            {
+                zero_sectors(
+                    f,
+                    disk_spec,
+                    bat_offset,
+                    bat,
+                    parent.as_deref_mut(),
+                    offset,
+                    count,
+                )?;
+            }
+            vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT
+            | vhdx_bat::PAYLOAD_BLOCK_UNDEFINED
+            | vhdx_bat::PAYLOAD_BLOCK_UNMAPPED
+            | vhdx_bat::PAYLOAD_BLOCK_ZERO => {}
+            _ => {
+                return Err(VhdxIoError::InvalidBatEntryState);
+            }
+        };
+
+        offset += count;
+        length -= count;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,504 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use byteorder::{ByteOrder, LittleEndian};
+use remain::sorted;
+use std::cmp::min;
+use std::collections::btree_map::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use thiserror::Error;
+use vmm_sys_util::write_zeroes::WriteZeroesAt;
+
+const LOG_ENTRY_SIGN: u32 = 0x6567_6F6C; // "loge"
+const ZERO_DESCRIPTOR_SIGN: u32 = 0x6F72_657A; // "zero"
+const DATA_DESCRIPTOR_SIGN: u32 = 0x6373_6564; // "desc"
+const DATA_SECTOR_SIGN: u32 = 0x6174_6164; // "data"
+
+const LOG_SECTOR_SIZE: u64 = 4 * 1024; // The log is made of 4 KiB sectors
+const LOG_ENTRY_HEADER_SIZE: u64 = 64;
+const LOG_DESCRIPTOR_SIZE: u64 = 32;
+const FILE_SIZE_ALIGN: u64 = 1024 * 1024; // The file grows by 1 MiB steps
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum VhdxLogError {
+    #[error("Log region is outside of the file")]
+    InvalidLogRegion,
+    #[error("Log entries don't form a valid sequence")]
+    InvalidSequence,
+    #[error("Failed to read log {0}")]
+    ReadLog(#[source] io::Error),
+    #[error("Log must be replayed but the image is read-only")]
+    ReadOnly,
+    #[error("Failed changing file length {0}")]
+    ResizeFile(#[source] io::Error),
+    #[error("File is smaller than recorded in the log")]
+    TruncatedFile,
+    #[error("Failed writing log entries to file {0}")]
+    WriteFile(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, VhdxLogError>;
+
+enum Descriptor {
+    Zero { file_offset: u64, length: u64 },
+    Data { file_offset: u64, data: Vec<u8> },
+}
+
+struct LogEntry {
+    length: u64,
+    tail: u64,
+    sequence_number: u64,
+    flushed_file_offset: u64,
+    last_file_offset: u64,
+    descriptors: Vec<Descriptor>,
+}
+
+/// Location of the log in the file
+struct Log {
+    guid: u128,
+    offset: u64,
+    length: u64,
+}
+
+impl Log {
+    /// Read `length` bytes at `offset` of the log, wrapping around its end
+    fn read(&self, f: &mut File, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; length as usize];
+        let mut done = 0;
+        while done < length {
+            let pos = (offset + done) % self.length;
+            let count = min(length - done, self.length - pos);
+            f.seek(SeekFrom::Start(self.offset + pos))
+                .map_err(VhdxLogError::ReadLog)?;
+            f.read_exact(&mut buffer[done as usize..(done + count) as usize])
+                .map_err(VhdxLogError::ReadLog)?;
+            done += count;
+        }
+
+        Ok(buffer)
+    }
+}
+
+impl LogEntry {
+    /// Parse the log entry at `offset` of the log. Returns None if there is no
+    /// valid entry at this offset.
+    fn new(f: &mut File, log: &Log, offset: u64) -> Result<Option<LogEntry>> {
+        let header = log.read(f, offset, LOG_SECTOR_SIZE)?;
+        if LittleEndian::read_u32(&header[0..4]) != LOG_ENTRY_SIGN
+            || LittleEndian::read_u128(&header[32..48]) != log.guid
+        {
+            return Ok(None);
+        }
+
+        let length = LittleEndian::read_u32(&header[8..12]) as u64;
+        let tail = LittleEndian::read_u32(&header[12..16]) as u64;
+        let sequence_number = LittleEndian::read_u64(&header[16..24]);
+        let descriptor_count = LittleEndian::read_u32(&header[24..28]) as u64;
+        let descriptors_size = div_round_up!(
+            LOG_ENTRY_HEADER_SIZE + descriptor_count * LOG_DESCRIPTOR_SIZE,
+            LOG_SECTOR_SIZE
+        ) * LOG_SECTOR_SIZE;
+        if length == 0
+            || length % LOG_SECTOR_SIZE != 0
+            || length > log.length
+            || tail % LOG_SECTOR_SIZE != 0
+            || tail >= log.length
+            || descriptors_size > length
+        {
+            return Ok(None);
+        }
+
+        // The checksum covers the whole entry, with the checksum field zeroed.
+        let mut buffer = log.read(f, offset, length)?;
+        let checksum = LittleEndian::read_u32(&buffer[4..8]);
+        LittleEndian::write_u32(&mut buffer[4..8], 0);
+        if crc32c::crc32c(&buffer) != checksum {
+            return Ok(None);
+        }
+
+        let mut entry = LogEntry {
+            length,
+            tail,
+            sequence_number,
+            flushed_file_offset: LittleEndian::read_u64(&buffer[48..56]),
+            last_file_offset: LittleEndian::read_u64(&buffer[56..64]),
+            descriptors: Vec::new(),
+        };
+
+        // Data sectors follow the descriptors, in the same order.
+        let mut data_sector = descriptors_size;
+        for i in 0..descriptor_count {
+            let start = (LOG_ENTRY_HEADER_SIZE + i * LOG_DESCRIPTOR_SIZE) as usize;
+            let desc = &buffer[start..start + LOG_DESCRIPTOR_SIZE as usize];
+            let file_offset = LittleEndian::read_u64(&desc[16..24]);
+            if LittleEndian::read_u64(&desc[24..32]) != sequence_number
+                || file_offset % LOG_SECTOR_SIZE != 0
+            {
+                return Ok(None);
+            }
+
+            let descriptor = match LittleEndian::read_u32(&desc[0..4]) {
+                ZERO_DESCRIPTOR_SIGN => {
+                    let length = LittleEndian::read_u64(&desc[8..16]);
+                    if length % LOG_SECTOR_SIZE != 0 {
+                        return Ok(None);
+                    }
+                    Descriptor::Zero {
+                        file_offset,
+                        length,
+                    }
+                }
+                DATA_DESCRIPTOR_SIGN => {
+                    if data_sector + LOG_SECTOR_SIZE > length {
+                        return Ok(None);
+                    }
+                    let sector =
+                        &buffer[data_sector as usize..(data_sector + LOG_SECTOR_SIZE) as usize];
+                    let sector_sequence_number = (LittleEndian::read_u32(&sector[4..8]) as u64)
+                        << 32
+                        | LittleEndian::read_u32(&sector[4092..4096]) as u64;
+                    if LittleEndian::read_u32(&sector[0..4]) != DATA_SECTOR_SIGN
+                        || sector_sequence_number != sequence_number
+                    {
+                        return Ok(None);
+                    }
+                    data_sector += LOG_SECTOR_SIZE;
+
+                    // The first 8 and last 4 bytes of the sector are stored in
+                    // the descriptor, in place of the signature and sequence
+                    // number.
+                    let mut data = Vec::with_capacity(LOG_SECTOR_SIZE as usize);
+                    data.extend_from_slice(&desc[8..16]);
+                    data.extend_from_slice(&sector[8..4092]);
+                    data.extend_from_slice(&desc[4..8]);
+                    Descriptor::Data { file_offset, data }
+                }
+                _ => return Ok(None),
+            };
+
+            // Nothing can be written past the end of the file recorded in the
+            // entry.
+            let end = match &descriptor {
+                Descriptor::Zero {
+                    file_offset,
+                    length,
+                } => file_offset.checked_add(*length),
+                Descriptor::Data { file_offset, .. } => file_offset.checked_add(LOG_SECTOR_SIZE),
+            };
+            if end.map_or(true, |end| end > entry.last_file_offset) {
+                return Ok(None);
+            }
+
+            entry.descriptors.push(descriptor);
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Apply the entry to the file
+    fn replay(&self, f: &mut File) -> Result<()> {
+        let file_size = f.metadata().map_err(VhdxLogError::ReadLog)?.len();
+        if file_size < self.flushed_file_offset {
+            return Err(VhdxLogError::TruncatedFile);
+        }
+
+        for descriptor in self.descriptors.iter() {
+            match descriptor {
+                Descriptor::Zero {
+                    file_offset,
+                    length,
+                } => {
+                    f.write_all_zeroes_at(*file_offset, *length as usize)
+                        .map_err(VhdxLogError::WriteFile)?;
+                }
+                Descriptor::Data { file_offset, data } => {
+                    f.seek(SeekFrom::Start(*file_offset))
+                        .map_err(VhdxLogError::WriteFile)?;
+                    f.write_all(data).map_err(VhdxLogError::WriteFile)?;
+                }
+            }
+        }
+
+        let file_size = f.metadata().map_err(VhdxLogError::ReadLog)?.len();
+        if file_size < self.last_file_offset {
+            f.set_len(div_round_up!(self.last_file_offset, FILE_SIZE_ALIGN) * FILE_SIZE_ALIGN)
+                .map_err(VhdxLogError::ResizeFile)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replay the log of an image which wasn't closed cleanly. Returns whether
+/// entries were replayed, in which case the log must then be marked as empty.
+pub fn replay(
+    f: &mut File,
+    log_guid: u128,
+    log_offset: u64,
+    log_length: u64,
+    read_only: bool,
+) -> Result<bool> {
+    if log_guid == 0 || log_length == 0 {
+        return Ok(false);
+    }
+
+    let file_size = f.metadata().map_err(VhdxLogError::ReadLog)?.len();
+    if log_length % LOG_SECTOR_SIZE != 0
+        || log_offset
+            .checked_add(log_length)
+            .map_or(true, |end| end > file_size)
+    {
+        return Err(VhdxLogError::InvalidLogRegion);
+    }
+
+    let log = Log {
+        guid: log_guid,
+        offset: log_offset,
+        length: log_length,
+    };
+
+    // Entries can start on any sector of the log.
+    let mut entries = BTreeMap::new();
+    for offset in (0..log_length).step_by(LOG_SECTOR_SIZE as usize) {
+        if let Some(entry) = LogEntry::new(f, &log, offset)? {
+            entries.insert(offset, entry);
+        }
+    }
+
+    // The entry with the highest sequence number is the head of the active
+    // sequence, and its tail is the first entry which must be replayed.
+    let head = match entries.values().max_by_key(|e| e.sequence_number) {
+        Some(head) => head,
+        None => return Ok(false),
+    };
+
+    let mut sequence: Vec<&LogEntry> = Vec::new();
+    let mut offset = head.tail;
+    loop {
+        let entry = entries.get(&offset).ok_or(VhdxLogError::InvalidSequence)?;
+        if let Some(previous) = sequence.last() {
+            if entry.sequence_number != previous.sequence_number + 1 {
+                return Err(VhdxLogError::InvalidSequence);
+            }
+        }
+        sequence.push(entry);
+
+        if entry.sequence_number == head.sequence_number {
+            break;
+        }
+        offset = (offset + entry.length) % log_length;
+    }
+
+    if read_only {
+        return Err(VhdxLogError::ReadOnly);
+    }
+
+    for entry in sequence {
+        entry.replay(f)?;
+    }
+    f.sync_all().map_err(VhdxLogError::WriteFile)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const LOG_OFFSET: u64 = 1 << 20;
+    const LOG_LENGTH: u64 = 64 * 1024;
+    const FILE_SIZE: u64 = 2 << 20;
+    const GUID: u128 = 0x1234_5678_9abc_def0_0fed_cba9_8765_4321;
+
+    /// Builds the log entry `sequence_number`, writing each of the `data`
+    /// sectors at its file offset and zeroing the `zeroes` ranges.
+    pub(crate) fn log_entry(
+        guid: u128,
+        sequence_number: u64,
+        tail: u64,
+        flushed_file_offset: u64,
+        last_file_offset: u64,
+        data: &[(u64, Vec<u8>)],
+        zeroes: &[(u64, u64)],
+    ) -> Vec<u8> {
+        let descriptor_count = (data.len() + zeroes.len()) as u64;
+        let descriptors_size = div_round_up!(
+            LOG_ENTRY_HEADER_SIZE + descriptor_count * LOG_DESCRIPTOR_SIZE,
+            LOG_SECTOR_SIZE
+        ) * LOG_SECTOR_SIZE;
+        let length = descriptors_size + data.len() as u64 * LOG_SECTOR_SIZE;
+
+        let mut entry = vec![0u8; length as usize];
+        LittleEndian::write_u32(&mut entry[0..4], LOG_ENTRY_SIGN);
+        LittleEndian::write_u32(&mut entry[8..12], length as u32);
+        LittleEndian::write_u32(&mut entry[12..16], tail as u32);
+        LittleEndian::write_u64(&mut entry[16..24], sequence_number);
+        LittleEndian::write_u32(&mut entry[24..28], descriptor_count as u32);
+        LittleEndian::write_u128(&mut entry[32..48], guid);
+        LittleEndian::write_u64(&mut entry[48..56], flushed_file_offset);
+        LittleEndian::write_u64(&mut entry[56..64], last_file_offset);
+
+        let mut desc = LOG_ENTRY_HEADER_SIZE as usize;
+        let mut sector = descriptors_size as usize;
+        for (file_offset, bytes) in data {
+            let d = &mut entry[desc..desc + LOG_DESCRIPTOR_SIZE as usize];
+            LittleEndian::write_u32(&mut d[0..4], DATA_DESCRIPTOR_SIGN);
+            d[4..8].copy_from_slice(&bytes[4092..4096]);
+            d[8..16].copy_from_slice(&bytes[0..8]);
+            LittleEndian::write_u64(&mut d[16..24], *file_offset);
+            LittleEndian::write_u64(&mut d[24..32], sequence_number);
+
+            let s = &mut entry[sector..sector + LOG_SECTOR_SIZE as usize];
+            LittleEndian::write_u32(&mut s[0..4], DATA_SECTOR_SIGN);
+            LittleEndian::write_u32(&mut s[4..8], (sequence_number >> 32) as u32);
+            s[8..4092].copy_from_slice(&bytes[8..4092]);
+            LittleEndian::write_u32(&mut s[4092..4096], sequence_number as u32);
+
+            desc += LOG_DESCRIPTOR_SIZE as usize;
+            sector += LOG_SECTOR_SIZE as usize;
+        }
+        for (file_offset, length) in zeroes {
+            let d = &mut entry[desc..desc + LOG_DESCRIPTOR_SIZE as usize];
+            LittleEndian::write_u32(&mut d[0..4], ZERO_DESCRIPTOR_SIGN);
+            LittleEndian::write_u64(&mut d[8..16], *length);
+            LittleEndian::write_u64(&mut d[16..24], *file_offset);
+            LittleEndian::write_u64(&mut d[24..32], sequence_number);
+            desc += LOG_DESCRIPTOR_SIZE as usize;
+        }
+
+        let checksum = crc32c::crc32c(&entry);
+        LittleEndian::write_u32(&mut entry[4..8], checksum);
+        entry
+    }
+
+    // Creates a file of FILE_SIZE bytes filled with 0xff, holding `entries`
+    // one after the other from the start of its log.
+    fn create_file(entries: &[Vec<u8>]) -> TempFile {
+        let tmp = TempFile::new().unwrap();
+        let f = tmp.as_file();
+        f.write_all_at(&vec![0xffu8; FILE_SIZE as usize], 0)
+            .unwrap();
+        f.write_all_at(&vec![0u8; LOG_LENGTH as usize], LOG_OFFSET)
+            .unwrap();
+        let mut offset = LOG_OFFSET;
+        for entry in entries {
+            f.write_all_at(entry, offset).unwrap();
+            offset += entry.len() as u64;
+        }
+        tmp
+    }
+
+    fn read_file(f: &File, offset: u64, length: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; length];
+        f.read_exact_at(&mut buf, offset).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_replay() {
+        let data: Vec<u8> = (0..LOG_SECTOR_SIZE).map(|i| i as u8).collect();
+        let first = log_entry(GUID, 10, 0, FILE_SIZE, FILE_SIZE, &[(0, data.clone())], &[]);
+        // The second entry grows the file.
+        let second = log_entry(
+            GUID,
+            11,
+            0,
+            FILE_SIZE,
+            FILE_SIZE + 1,
+            &[(2 * LOG_SECTOR_SIZE, data.clone())],
+            &[(LOG_SECTOR_SIZE, LOG_SECTOR_SIZE)],
+        );
+        let tmp = create_file(&[first, second]);
+        let mut f = tmp.as_file().try_clone().unwrap();
+
+        // A read-only image can't be replayed, and is left untouched.
+        assert!(matches!(
+            replay(&mut f, GUID, LOG_OFFSET, LOG_LENGTH, true),
+            Err(VhdxLogError::ReadOnly)
+        ));
+        assert_eq!(read_file(&f, 0, 8), vec![0xff; 8]);
+
+        assert!(replay(&mut f, GUID, LOG_OFFSET, LOG_LENGTH, false).unwrap());
+        assert_eq!(read_file(&f, 0, data.len()), data);
+        assert_eq!(
+            read_file(&f, LOG_SECTOR_SIZE, LOG_SECTOR_SIZE as usize),
+            vec![0; LOG_SECTOR_SIZE as usize]
+        );
+        assert_eq!(read_file(&f, 2 * LOG_SECTOR_SIZE, data.len()), data);
+        assert_eq!(read_file(&f, 3 * LOG_SECTOR_SIZE, 8), vec![0xff; 8]);
+        assert_eq!(f.metadata().unwrap().len(), FILE_SIZE + FILE_SIZE_ALIGN);
+    }
+
+    #[test]
+    fn test_replay_ignores_invalid_entries() {
+        let data = vec![0x55u8; LOG_SECTOR_SIZE as usize];
+
+        // Entries of another log
+        let tmp = create_file(&[log_entry(
+            !GUID,
+            1,
+            0,
+            FILE_SIZE,
+            FILE_SIZE,
+            &[(0, data.clone())],
+            &[],
+        )]);
+        let mut f = tmp.as_file().try_clone().unwrap();
+        assert!(!replay(&mut f, GUID, LOG_OFFSET, LOG_LENGTH, false).unwrap());
+
+        // Entries with a wrong checksum
+        let mut entry = log_entry(GUID, 1, 0, FILE_SIZE, FILE_SIZE, &[(0, data.clone())], &[]);
+        entry[100] ^= 0xff;
+        let tmp = create_file(&[entry]);
+        let mut f = tmp.as_file().try_clone().unwrap();
+        assert!(!replay(&mut f, GUID, LOG_OFFSET, LOG_LENGTH, false).unwrap());
+
+        // Entries writing past the end of the file they record
+        let tmp = create_file(&[log_entry(
+            GUID,
+            1,
+            0,
+            FILE_SIZE,
+            LOG_SECTOR_SIZE,
+            &[(LOG_SECTOR_SIZE, data)],
+            &[],
+        )]);
+        let mut f = tmp.as_file().try_clone().unwrap();
+        assert!(!replay(&mut f, GUID, LOG_OFFSET, LOG_LENGTH, false).unwrap());
+        assert_eq!(read_file(&f, 0, 8), vec![0xff; 8]);
+    }
+
+    #[test]
+    fn test_replay_invalid_sequence() {
+        let data = vec![0x55u8; LOG_SECTOR_SIZE as usize];
+        let first = log_entry(GUID, 5, 0, FILE_SIZE, FILE_SIZE, &[(0, data.clone())], &[]);
+        // The head refers to the first entry, which doesn't precede it.
+        let second = log_entry(GUID, 11, 0, FILE_SIZE, FILE_SIZE, &[(0, data)], &[]);
+        let tmp = create_file(&[first, second]);
+        let mut f = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            replay(&mut f, GUID, LOG_OFFSET, LOG_LENGTH, false),
+            Err(VhdxLogError::InvalidSequence)
+        ));
+        assert_eq!(read_file(&f, 0, 8), vec![0xff; 8]);
+    }
+
+    #[test]
+    fn test_replay_invalid_log_region() {
+        let tmp = create_file(&[]);
+        let mut f = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            replay(&mut f, GUID, FILE_SIZE, LOG_LENGTH, false),
+            Err(VhdxLogError::InvalidLogRegion)
+        ));
+        assert!(matches!(
+            replay(&mut f, GUID, LOG_OFFSET, LOG_LENGTH + 1, false),
+            Err(VhdxLogError::InvalidLogRegion)
+        ));
+    }
+}
@@ -3,10 +3,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::vhdx_header::RegionTableEntry;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 use remain::sorted;
 use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
 use thiserror::Error;
 use uuid::Uuid;
@@ -17,7 +17,10 @@ const METADATA_MAX_ENTRIES: u16 = 2047;
 // The size including the table header and entries
 const METADATA_TABLE_MAX_SIZE: usize = METADATA_ENTRY_SIZE * (METADATA_MAX_ENTRIES as usize + 1);
 
+const METADATA_FLAGS_IS_VIRTUAL_DISK: u32 = 0x02;
 const METADATA_FLAGS_IS_REQUIRED: u32 = 0x04;
+// Metadata items are stored after the table
+const METADATA_ITEMS_OFFSET: u32 = 64 * 1024;
 
 pub const BLOCK_SIZE_MIN: u32 = 1 << 20; // 1 MiB
 const BLOCK_SIZE_MAX: u32 = 256 << 20; // 256 MiB
@@ -33,6 +36,11 @@ const METADATA_LOGICAL_SECTOR_SIZE: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F
 const METADATA_PHYSICAL_SECTOR_SIZE: &str = "CDA348C7-445D-4471-9CC9-E9885251C556";
 const METADATA_PARENT_LOCATOR: &str = "A8D35F2D-B30B-454D-ABF7-D3D84834AB0C";
 
+// Type of the parent locators referencing VHDx parents
+const PARENT_LOCATOR_TYPE_VHDX: &str = "B04AEFB7-D19E-4A81-B789-25B8E9445913";
+const PARENT_LOCATOR_HEADER_SIZE: usize = 20;
+const PARENT_LOCATOR_ENTRY_SIZE: usize = 12;
+
 const METADATA_FILE_PARAMETER_PRESENT: u16 = 0x01;
 const METADATA_VIRTUAL_DISK_SIZE_PRESENT: u16 = 0x02;
 const METADATA_VIRTUAL_DISK_ID_PRESENT: u16 = 0x04;
@@ -63,6 +71,8 @@ pub enum VhdxMetadataError {
     InvalidMetadataLength,
     #[error("Metadata sign doesn't match")]
     InvalidMetadataSign,
+    #[error("Invalid parent locator")]
+    InvalidParentLocator,
     #[error("Invalid physical sector size")]
     InvalidPhysicalSectorSize,
     #[error("Invalid UUID")]
@@ -77,6 +87,8 @@ pub enum VhdxMetadataError {
     ReservedIsNonZero,
     #[error("This implementation doesn't support this metadata flag")]
     UnsupportedFlag,
+    #[error("Failed to write metadata {0}")]
+    WriteMetadata(#[source] io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, VhdxMetadataError>;
@@ -93,6 +105,7 @@ pub struct DiskSpec {
     pub physical_sector_size: u32,
     pub chunk_ratio: u64,
     pub total_sectors: u64,
+    pub parent_locator: Option<ParentLocator>,
 }
 
 impl DiskSpec {
@@ -201,6 +214,11 @@ impl DiskSpec {
                 == Uuid::parse_str(METADATA_PARENT_LOCATOR)
                     .map_err(VhdxMetadataError::InvalidUuid)?
             {
+                let mut locator = vec![0u8; metadata_entry.length as usize];
+                f.read_exact(&mut locator)
+                    .map_err(VhdxMetadataError::ReadMetadata)?;
+                disk_spec.parent_locator = Some(ParentLocator::new(&locator)?);
+
                 metadata_presence |= METADATA_PARENT_LOCATOR_PRESENT;
             } else {
                 return Err(VhdxMetadataError::InvalidMetadataItem);
@@ -212,8 +230,11 @@ impl DiskSpec {
             offset += size_of::<MetadataTableEntry>();
         }
 
-        // Check if all required metadata are present
-        if metadata_presence != METADATA_ALL_PRESENT {
+        // Check if all required metadata are present, differencing images
+        // also need a parent locator.
+        if metadata_presence & METADATA_ALL_PRESENT != METADATA_ALL_PRESENT
+            || (disk_spec.has_parent && disk_spec.parent_locator.is_none())
+        {
             return Err(VhdxMetadataError::MissingMetadata);
         }
         // Check if the virtual disk size is a multiple of the logical sector
@@ -236,6 +257,110 @@ impl DiskSpec {
         Ok(disk_spec)
     }
 
+    /// Writes the metadata region of a new VHDx file, describing a virtual
+    /// disk of `virtual_disk_size` bytes.
+    pub fn create(
+        f: &mut File,
+        metadata_region: &RegionTableEntry,
+        block_size: u32,
+        virtual_disk_size: u64,
+        logical_sector_size: u32,
+        physical_sector_size: u32,
+    ) -> Result<()> {
+        let disk_id = Uuid::new_v4();
+        let mut file_parameters = [0u8; 8];
+        LittleEndian::write_u32(&mut file_parameters[0..4], block_size);
+        let items: [(&str, u32, Vec<u8>); 5] = [
+            (
+                METADATA_FILE_PARAMETER,
+                METADATA_FLAGS_IS_REQUIRED,
+                file_parameters.to_vec(),
+            ),
+            (
+                METADATA_VIRTUAL_DISK_SIZE,
+                METADATA_FLAGS_IS_VIRTUAL_DISK | METADATA_FLAGS_IS_REQUIRED,
+                virtual_disk_size.to_le_bytes().to_vec(),
+            ),
+            (
+                METADATA_VIRTUAL_DISK_ID,
+                METADATA_FLAGS_IS_VIRTUAL_DISK | METADATA_FLAGS_IS_REQUIRED,
+                disk_id.to_bytes_le().to_vec(),
+            ),
+            (
+                METADATA_LOGICAL_SECTOR_SIZE,
+                METADATA_FLAGS_IS_VIRTUAL_DISK | METADATA_FLAGS_IS_REQUIRED,
+                logical_sector_size.to_le_bytes().to_vec(),
+            ),
+            (
+                METADATA_PHYSICAL_SECTOR_SIZE,
+                METADATA_FLAGS_IS_VIRTUAL_DISK | METADATA_FLAGS_IS_REQUIRED,
+                physical_sector_size.to_le_bytes().to_vec(),
+            ),
+        ];
+
+        let mut buffer = vec![0u8; metadata_region.length as usize];
+        LittleEndian::write_u64(&mut buffer[0..8], METADATA_SIGN);
+        LittleEndian::write_u16(&mut buffer[10..12], items.len() as u16);
+
+        let mut entry_offset = size_of::<MetadataTableHeader>();
+        let mut item_offset = METADATA_ITEMS_OFFSET;
+        for (item_id, flag_bits, data) in items.iter() {
+            let item_id = Uuid::parse_str(item_id).map_err(VhdxMetadataError::InvalidUuid)?;
+            let entry = &mut buffer[entry_offset..entry_offset + METADATA_ENTRY_SIZE];
+            entry[0..16].copy_from_slice(&item_id.to_bytes_le());
+            LittleEndian::write_u32(&mut entry[16..20], item_offset);
+            LittleEndian::write_u32(&mut entry[20..24], data.len() as u32);
+            LittleEndian::write_u32(&mut entry[24..28], *flag_bits);
+
+            let start = item_offset as usize;
+            buffer[start..start + data.len()].copy_from_slice(data);
+
+            entry_offset += METADATA_ENTRY_SIZE;
+            item_offset += data.len() as u32;
+        }
+
+        f.seek(SeekFrom::Start(metadata_region.file_offset))
+            .map_err(VhdxMetadataError::WriteMetadata)?;
+        f.write_all(&buffer)
+            .map_err(VhdxMetadataError::WriteMetadata)
+    }
+
+    /// Updates the virtual disk size stored in the metadata region.
+    pub fn write_virtual_disk_size(
+        f: &mut File,
+        metadata_region: &RegionTableEntry,
+        virtual_disk_size: u64,
+    ) -> Result<()> {
+        let mut buffer = [0u8; METADATA_TABLE_MAX_SIZE];
+        f.seek(SeekFrom::Start(metadata_region.file_offset))
+            .map_err(VhdxMetadataError::ReadMetadata)?;
+        f.read_exact(&mut buffer)
+            .map_err(VhdxMetadataError::ReadMetadata)?;
+
+        let metadata_header =
+            MetadataTableHeader::new(&buffer[0..size_of::<MetadataTableHeader>()])?;
+        let item_id =
+            Uuid::parse_str(METADATA_VIRTUAL_DISK_SIZE).map_err(VhdxMetadataError::InvalidUuid)?;
+
+        let mut offset = size_of::<MetadataTableHeader>();
+        for _ in 0..metadata_header.entry_count {
+            let metadata_entry =
+                MetadataTableEntry::new(&buffer[offset..offset + size_of::<MetadataTableEntry>()])?;
+            if metadata_entry.item_id == item_id {
+                f.seek(SeekFrom::Start(
+                    metadata_region.file_offset + metadata_entry.offset as u64,
+                ))
+                .map_err(VhdxMetadataError::WriteMetadata)?;
+                return f
+                    .write_all(&virtual_disk_size.to_le_bytes())
+                    .map_err(VhdxMetadataError::WriteMetadata);
+            }
+            offset += size_of::<MetadataTableEntry>();
+        }
+
+        Err(VhdxMetadataError::MissingMetadata)
+    }
+
     /// Calculates the number of sectors per block
     fn sectors_per_block(block_size: u32, logical_sector_size: u32) -> Result<u32> {
         let sectors_per_block = block_size / logical_sector_size;
@@ -248,7 +373,7 @@ impl DiskSpec {
     }
 
     /// Calculate the chunk ratio
-    fn chunk_ratio(block_size: u32, logical_sector_size: u32) -> Result<u64> {
+    pub fn chunk_ratio(block_size: u32, logical_sector_size: u32) -> Result<u64> {
         let chunk_ratio = (MAX_SECTORS_PER_BLOCK * logical_sector_size as u64) / block_size as u64;
 
         if !chunk_ratio.is_power_of_two() {
@@ -321,3 +446,239 @@ impl MetadataTableEntry {
         Ok(metadata_table_entry)
     }
 }
+
+/// Parent locator of a differencing image, made of key-value pairs telling
+/// where the parent image can be found.
+#[derive(Default, Clone, Debug)]
+pub struct ParentLocator {
+    pub parent_linkage: String,
+    pub parent_linkage2: Option<String>,
+    pub relative_path: Option<String>,
+    pub volume_path: Option<String>,
+    pub absolute_win32_path: Option<String>,
+}
+
+impl ParentLocator {
+    /// Parse the parent locator metadata item
+    fn new(buffer: &[u8]) -> Result<ParentLocator> {
+        if buffer.len() < PARENT_LOCATOR_HEADER_SIZE
+            || crate::uuid_from_guid(buffer)
+                != Uuid::parse_str(PARENT_LOCATOR_TYPE_VHDX)
+                    .map_err(VhdxMetadataError::InvalidUuid)?
+        {
+            return Err(VhdxMetadataError::InvalidParentLocator);
+        }
+
+        // Keys and values are UTF-16 strings, their offsets are relative to
+        // the start of the item.
+        let read_string = |offset: u32, length: u16| -> Result<String> {
+            let start = offset as usize;
+            let end = start + length as usize;
+            if length % 2 != 0 || end > buffer.len() {
+                return Err(VhdxMetadataError::InvalidParentLocator);
+            }
+            let units: Vec<u16> = buffer[start..end]
+                .chunks_exact(2)
+                .map(LittleEndian::read_u16)
+                .collect();
+            Ok(String::from_utf16_lossy(&units))
+        };
+
+        let mut locator = ParentLocator::default();
+        let mut parent_linkage = None;
+        let count = LittleEndian::read_u16(&buffer[18..20]) as usize;
+        for i in 0..count {
+            let start = PARENT_LOCATOR_HEADER_SIZE + i * PARENT_LOCATOR_ENTRY_SIZE;
+            let entry = buffer
+                .get(start..start + PARENT_LOCATOR_ENTRY_SIZE)
+                .ok_or(VhdxMetadataError::InvalidParentLocator)?;
+            let key = read_string(
+                LittleEndian::read_u32(&entry[0..4]),
+                LittleEndian::read_u16(&entry[8..10]),
+            )?;
+            let value = read_string(
+                LittleEndian::read_u32(&entry[4..8]),
+                LittleEndian::read_u16(&entry[10..12]),
+            )?;
+
+            match key.as_str() {
+                "parent_linkage" => parent_linkage = Some(value),
+                "parent_linkage2" => locator.parent_linkage2 = Some(value),
+                "relative_path" => locator.relative_path = Some(value),
+                "volume_path" => locator.volume_path = Some(value),
+                "absolute_win32_path" => locator.absolute_win32_path = Some(value),
+                // Unknown keys are allowed by the spec.
+                _ => {}
+            }
+        }
+
+        locator.parent_linkage = parent_linkage.ok_or(VhdxMetadataError::InvalidParentLocator)?;
+
+        Ok(locator)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a parent locator item holding the `entries` key-value pairs.
+    pub(crate) fn parent_locator(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut buffer =
+            vec![0u8; PARENT_LOCATOR_HEADER_SIZE + entries.len() * PARENT_LOCATOR_ENTRY_SIZE];
+        buffer[0..16].copy_from_slice(
+            &Uuid::parse_str(PARENT_LOCATOR_TYPE_VHDX)
+                .unwrap()
+                .to_bytes_le(),
+        );
+        LittleEndian::write_u16(&mut buffer[18..20], entries.len() as u16);
+
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let mut strings = [(0u32, 0u16); 2];
+            for (string, s) in [key, value].iter().zip(strings.iter_mut()) {
+                let offset = buffer.len();
+                for unit in string.encode_utf16() {
+                    buffer.extend_from_slice(&unit.to_le_bytes());
+                }
+                *s = (offset as u32, (buffer.len() - offset) as u16);
+            }
+
+            let start = PARENT_LOCATOR_HEADER_SIZE + i * PARENT_LOCATOR_ENTRY_SIZE;
+            let entry = &mut buffer[start..start + PARENT_LOCATOR_ENTRY_SIZE];
+            LittleEndian::write_u32(&mut entry[0..4], strings[0].0);
+            LittleEndian::write_u32(&mut entry[4..8], strings[1].0);
+            LittleEndian::write_u16(&mut entry[8..10], strings[0].1);
+            LittleEndian::write_u16(&mut entry[10..12], strings[1].1);
+        }
+
+        buffer
+    }
+
+    /// Turns the image created with the `metadata_region` into a differencing
+    /// image, whose parent is described by the `locator` item.
+    pub(crate) fn make_differencing(
+        f: &mut File,
+        metadata_region: &RegionTableEntry,
+        locator: &[u8],
+    ) {
+        let mut buffer = vec![0u8; metadata_region.length as usize];
+        f.seek(SeekFrom::Start(metadata_region.file_offset))
+            .unwrap();
+        f.read_exact(&mut buffer).unwrap();
+
+        let file_parameter = Uuid::parse_str(METADATA_FILE_PARAMETER).unwrap();
+        let entry_count = LittleEndian::read_u16(&buffer[10..12]) as usize;
+        let mut item_end = METADATA_ITEMS_OFFSET as usize;
+        for i in 0..entry_count {
+            let start = size_of::<MetadataTableHeader>() + i * METADATA_ENTRY_SIZE;
+            let entry = &buffer[start..start + METADATA_ENTRY_SIZE];
+            let offset = LittleEndian::read_u32(&entry[16..20]) as usize;
+            let length = LittleEndian::read_u32(&entry[20..24]) as usize;
+            item_end = item_end.max(offset + length);
+            if crate::uuid_from_guid(entry) == file_parameter {
+                LittleEndian::write_u32(&mut buffer[offset + 4..offset + 8], BLOCK_HAS_PARENT);
+            }
+        }
+
+        let start = size_of::<MetadataTableHeader>() + entry_count * METADATA_ENTRY_SIZE;
+        let entry = &mut buffer[start..start + METADATA_ENTRY_SIZE];
+        entry[0..16].copy_from_slice(
+            &Uuid::parse_str(METADATA_PARENT_LOCATOR)
+                .unwrap()
+                .to_bytes_le(),
+        );
+        LittleEndian::write_u32(&mut entry[16..20], item_end as u32);
+        LittleEndian::write_u32(&mut entry[20..24], locator.len() as u32);
+        LittleEndian::write_u32(&mut entry[24..28], METADATA_FLAGS_IS_REQUIRED);
+        buffer[item_end..item_end + locator.len()].copy_from_slice(locator);
+        LittleEndian::write_u16(&mut buffer[10..12], entry_count as u16 + 1);
+
+        f.seek(SeekFrom::Start(metadata_region.file_offset))
+            .unwrap();
+        f.write_all(&buffer).unwrap();
+    }
+
+    #[test]
+    fn test_parent_locator() {
+        let locator = ParentLocator::new(&parent_locator(&[
+            ("parent_linkage", "{83ecfc4c-4c8c-4a4a-9d38-5a8d3b3c0d9e}"),
+            ("parent_linkage2", "{00000000-0000-0000-0000-000000000001}"),
+            ("relative_path", ".\\base.vhdx"),
+            ("volume_path", "\\\\?\\Volume{1234}\\base.vhdx"),
+            ("absolute_win32_path", "\\\\?\\C:\\images\\base.vhdx"),
+            // Unknown keys are ignored.
+            ("unknown", "value"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            locator.parent_linkage,
+            "{83ecfc4c-4c8c-4a4a-9d38-5a8d3b3c0d9e}"
+        );
+        assert_eq!(
+            locator.parent_linkage2.as_deref(),
+            Some("{00000000-0000-0000-0000-000000000001}")
+        );
+        assert_eq!(locator.relative_path.as_deref(), Some(".\\base.vhdx"));
+        assert_eq!(
+            locator.volume_path.as_deref(),
+            Some("\\\\?\\Volume{1234}\\base.vhdx")
+        );
+        assert_eq!(
+            locator.absolute_win32_path.as_deref(),
+            Some("\\\\?\\C:\\images\\base.vhdx")
+        );
+
+        let locator = ParentLocator::new(&parent_locator(&[(
+            "parent_linkage",
+            "{83ecfc4c-4c8c-4a4a-9d38-5a8d3b3c0d9e}",
+        )]))
+        .unwrap();
+        assert!(locator.parent_linkage2.is_none());
+        assert!(locator.relative_path.is_none());
+        assert!(locator.volume_path.is_none());
+        assert!(locator.absolute_win32_path.is_none());
+    }
+
+    #[test]
+    fn test_parent_locator_invalid() {
+        // The parent linkage is required.
+        let buffer = parent_locator(&[("relative_path", ".\\base.vhdx")]);
+        assert!(matches!(
+            ParentLocator::new(&buffer),
+            Err(VhdxMetadataError::InvalidParentLocator)
+        ));
+
+        // Unknown locator type
+        let mut buffer = parent_locator(&[("parent_linkage", "{}")]);
+        buffer[0] ^= 0xff;
+        assert!(matches!(
+            ParentLocator::new(&buffer),
+            Err(VhdxMetadataError::InvalidParentLocator)
+        ));
+
+        // Strings past the end of the item
+        let mut buffer = parent_locator(&[("parent_linkage", "{}")]);
+        let length = buffer.len() as u32;
+        LittleEndian::write_u32(&mut buffer[PARENT_LOCATOR_HEADER_SIZE + 4..], length);
+        assert!(matches!(
+            ParentLocator::new(&buffer),
+            Err(VhdxMetadataError::InvalidParentLocator)
+        ));
+
+        // Odd string lengths
+        let mut buffer = parent_locator(&[("parent_linkage", "{}")]);
+        LittleEndian::write_u16(&mut buffer[PARENT_LOCATOR_HEADER_SIZE + 10..], 3);
+        assert!(matches!(
+            ParentLocator::new(&buffer),
+            Err(VhdxMetadataError::InvalidParentLocator)
+        ));
+
+        // Entries past the end of the item
+        let mut buffer = parent_locator(&[("parent_linkage", "{}")]);
+        LittleEndian::write_u16(&mut buffer[18..20], 100);
+        assert!(matches!(
+            ParentLocator::new(&buffer),
+            Err(VhdxMetadataError::InvalidParentLocator)
+        ));
+    }
+}
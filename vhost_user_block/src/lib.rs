@@ -8,11 +8,12 @@
 //
 // SPDX-License-Identifier: (Apache-2.0 AND BSD-3-Clause)
 
+use block_util::luks::{read_key_fd, LuksError, LuksFile};
 use block_util::{build_disk_image_id, Request, VirtioBlockConfig};
 use libc::EFD_NONBLOCK;
 use log::*;
 use option_parser::{OptionParser, OptionParserError, Toggle};
-use qcow::{self, ImageType, QcowFile};
+use qcow::{self, BackingFilePolicy, ImageType, QcowFile};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
@@ -22,7 +23,7 @@ use std::os::unix::fs::OpenOptionsExt;
 use std::path::PathBuf;
 use std::process;
 use std::result;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
 use std::time::Instant;
 use std::vec::Vec;
@@ -33,6 +34,7 @@ use vhost_user_backend::{VhostUserBackendMut, VhostUserDaemon, VringRwLock, Vrin
 use virtio_bindings::bindings::virtio_blk::*;
 use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{bitmap::AtomicBitmap, ByteValued, Bytes, GuestMemoryAtomic};
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
@@ -45,24 +47,36 @@ const BLK_SIZE: u32 = 512;
 // and the overhead of the emulation layer.
 const POLL_QUEUE_US: u128 = 50;
 
-trait DiskFile: Read + Seek + Write + Send + Sync {}
-impl<D: Read + Seek + Write + Send + Sync> DiskFile for D {}
+trait DiskFile: Read + Seek + Write + PunchHole + WriteZeroesAt + Send + Sync {}
+impl<D: Read + Seek + Write + PunchHole + WriteZeroesAt + Send + Sync> DiskFile for D {}
 
 type Result<T> = std::result::Result<T, Error>;
 type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
 
 #[derive(Debug)]
 enum Error {
+    /// Backing file override provided without enabling backing files
+    BackingFileWithoutBackingFiles,
     /// Failed to create kill eventfd
     CreateKillEventFd(io::Error),
+    /// Discard enabled on an encrypted image
+    DiscardWithKeyFile,
     /// Failed to parse configuration string
     FailedConfigParse(OptionParserError),
+    /// Both a passphrase file and a passphrase file descriptor provided
+    KeyFileAndFd,
     /// Failed to handle event other than input event.
     HandleEventNotEpollIn,
     /// Failed to handle unknown event.
     HandleEventUnknownEvent,
     /// No path provided
     PathParameterMissing,
+    /// Failed to open the encrypted image
+    OpenLuksImage(LuksError),
+    /// Failed to read the passphrase of the encrypted image
+    ReadKeyFile(io::Error),
+    /// Passphrase file descriptor is one of the standard streams
+    ReservedKeyFd,
     /// No socket provided
     SocketParameterMissing,
 }
@@ -70,7 +84,8 @@ enum Error {
 pub const SYNTAX: &str = "vhost-user-block backend parameters \
  \"path=<image_path>,socket=<socket_path>,num_queues=<number_of_queues>,\
  queue_size=<size_of_each_queue>,readonly=true|false,direct=true|false,\
- poll_queue=true|false\"";
+ poll_queue=true|false,backing_files=true|false,backing_file=<backing_file_path>,\
+ discard=true|false,key_file=<luks_passphrase_path>,key_fd=<luks_passphrase_fd>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -89,7 +104,7 @@ impl convert::From<Error> for io::Error {
 struct VhostUserBlkThread {
     disk_image: Arc<Mutex<dyn DiskFile>>,
     disk_image_id: Vec<u8>,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     event_idx: bool,
     kill_evt: EventFd,
     writeback: Arc<AtomicBool>,
@@ -99,7 +114,7 @@ impl VhostUserBlkThread {
     fn new(
         disk_image: Arc<Mutex<dyn DiskFile>>,
         disk_image_id: Vec<u8>,
-        disk_nsectors: u64,
+        disk_nsectors: Arc<AtomicU64>,
         writeback: Arc<AtomicBool>,
     ) -> Result<Self> {
         Ok(VhostUserBlkThread {
@@ -127,7 +142,7 @@ impl VhostUserBlkThread {
                     request.set_writeback(self.writeback.load(Ordering::Acquire));
                     let status = match request.execute(
                         &mut self.disk_image.lock().unwrap().deref_mut(),
-                        self.disk_nsectors,
+                        self.disk_nsectors.load(Ordering::Acquire),
                         desc_chain.memory(),
                         &self.disk_image_id,
                     ) {
@@ -183,8 +198,11 @@ impl VhostUserBlkThread {
 
 struct VhostUserBlkBackend {
     threads: Vec<Mutex<VhostUserBlkThread>>,
+    disk_image: Arc<Mutex<dyn DiskFile>>,
+    disk_nsectors: Arc<AtomicU64>,
     config: VirtioBlockConfig,
     rdonly: bool,
+    discard: bool,
     poll_queue: bool,
     queues_per_thread: Vec<u64>,
     queue_size: usize,
@@ -193,6 +211,7 @@ struct VhostUserBlkBackend {
 }
 
 impl VhostUserBlkBackend {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         image_path: String,
         num_queues: usize,
@@ -200,6 +219,10 @@ impl VhostUserBlkBackend {
         direct: bool,
         poll_queue: bool,
         queue_size: usize,
+        backing_file_policy: BackingFilePolicy,
+        discard: bool,
+        key_file: Option<PathBuf>,
+        key_fd: Option<i32>,
     ) -> Result<Self> {
         let mut options = OpenOptions::new();
         options.read(true);
@@ -212,15 +235,30 @@ impl VhostUserBlkBackend {
 
         let image_id = build_disk_image_id(&PathBuf::from(&image_path));
         let image_type = qcow::detect_image_type(&mut raw_img).unwrap();
-        let image = match image_type {
-            ImageType::Raw => Arc::new(Mutex::new(raw_img)) as Arc<Mutex<dyn DiskFile>>,
-            ImageType::Qcow2 => {
-                Arc::new(Mutex::new(QcowFile::from(raw_img).unwrap())) as Arc<Mutex<dyn DiskFile>>
-            }
+        let key = match (key_file, key_fd) {
+            (Some(key_file), _) => Some(std::fs::read(key_file).map_err(Error::ReadKeyFile)?),
+            (None, Some(key_fd)) => Some(read_key_fd(key_fd).map_err(Error::ReadKeyFile)?),
+            (None, None) => None,
+        };
+        let image = match (image_type, key) {
+            (ImageType::Raw, None) => Arc::new(Mutex::new(raw_img)) as Arc<Mutex<dyn DiskFile>>,
+            (ImageType::Raw, Some(key)) => Arc::new(Mutex::new(
+                LuksFile::new(raw_img, &key).map_err(Error::OpenLuksImage)?,
+            )) as Arc<Mutex<dyn DiskFile>>,
+            (ImageType::Qcow2, None) => Arc::new(Mutex::new(
+                QcowFile::from_with_backing_file(raw_img, &backing_file_policy).unwrap(),
+            )) as Arc<Mutex<dyn DiskFile>>,
+            (ImageType::Qcow2, Some(key)) => Arc::new(Mutex::new(
+                LuksFile::new(
+                    QcowFile::from_with_backing_file(raw_img, &backing_file_policy).unwrap(),
+                    &key,
+                )
+                .map_err(Error::OpenLuksImage)?,
+            )) as Arc<Mutex<dyn DiskFile>>,
         };
 
         let nsectors = (image.lock().unwrap().seek(SeekFrom::End(0)).unwrap() as u64) / SECTOR_SIZE;
-        let config = VirtioBlockConfig {
+        let mut config = VirtioBlockConfig {
             capacity: nsectors,
             blk_size: BLK_SIZE,
             size_max: 65535,
@@ -231,15 +269,25 @@ impl VhostUserBlkBackend {
             writeback: 1,
             ..Default::default()
         };
+        let discard = discard && !rdonly;
+        if discard {
+            config.max_discard_sectors = u32::MAX;
+            config.max_discard_seg = 1;
+            config.discard_sector_alignment = BLK_SIZE / SECTOR_SIZE as u32;
+            config.max_write_zeroes_sectors = u32::MAX;
+            config.max_write_zeroes_seg = 1;
+            config.write_zeroes_may_unmap = 1;
+        }
 
         let mut queues_per_thread = Vec::new();
         let mut threads = Vec::new();
         let writeback = Arc::new(AtomicBool::new(true));
+        let disk_nsectors = Arc::new(AtomicU64::new(nsectors));
         for i in 0..num_queues {
             let thread = Mutex::new(VhostUserBlkThread::new(
                 image.clone(),
                 image_id.clone(),
-                nsectors,
+                disk_nsectors.clone(),
                 writeback.clone(),
             )?);
             threads.push(thread);
@@ -248,8 +296,11 @@ impl VhostUserBlkBackend {
 
         Ok(VhostUserBlkBackend {
             threads,
+            disk_image: image,
+            disk_nsectors,
             config,
             rdonly,
+            discard,
             poll_queue,
             queues_per_thread,
             queue_size,
@@ -305,6 +356,9 @@ impl VhostUserBackendMut<VringRwLock<GuestMemoryAtomic<GuestMemoryMmap>>, Atomic
         if self.rdonly {
             avail_features |= 1 << VIRTIO_BLK_F_RO;
         }
+        if self.discard {
+            avail_features |= 1 << VIRTIO_BLK_F_DISCARD | 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+        }
         avail_features
     }
 
@@ -379,7 +433,18 @@ impl VhostUserBackendMut<VringRwLock<GuestMemoryAtomic<GuestMemoryMmap>>, Atomic
     }
 
     fn get_config(&self, _offset: u32, _size: u32) -> Vec<u8> {
-        self.config.as_slice().to_vec()
+        // The image may have been grown since it was opened, the VMM reads the
+        // configuration again to pick up the new capacity.
+        match self.disk_image.lock().unwrap().seek(SeekFrom::End(0)) {
+            Ok(size) => self
+                .disk_nsectors
+                .store(size / SECTOR_SIZE, Ordering::Release),
+            Err(e) => error!("Failed getting disk size: {:?}", e),
+        }
+
+        let mut config = self.config;
+        config.capacity = self.disk_nsectors.load(Ordering::Acquire);
+        config.as_slice().to_vec()
     }
 
     fn set_config(&mut self, offset: u32, data: &[u8]) -> result::Result<(), io::Error> {
@@ -427,6 +492,10 @@ struct VhostUserBlkBackendConfig {
     readonly: bool,
     direct: bool,
     poll_queue: bool,
+    backing_file_policy: BackingFilePolicy,
+    discard: bool,
+    key_file: Option<PathBuf>,
+    key_fd: Option<i32>,
 }
 
 impl VhostUserBlkBackendConfig {
@@ -439,7 +508,12 @@ impl VhostUserBlkBackendConfig {
             .add("num_queues")
             .add("queue_size")
             .add("socket")
-            .add("poll_queue");
+            .add("poll_queue")
+            .add("backing_files")
+            .add("backing_file")
+            .add("discard")
+            .add("key_file")
+            .add("key_fd");
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
         let path = parser.get("path").ok_or(Error::PathParameterMissing)?;
@@ -467,6 +541,36 @@ impl VhostUserBlkBackendConfig {
             .convert("queue_size")
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(1024);
+        let backing_files = parser
+            .convert::<Toggle>("backing_files")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let backing_file_policy = match (backing_files, parser.get("backing_file")) {
+            (false, None) => BackingFilePolicy::Forbid,
+            (false, Some(_)) => return Err(Error::BackingFileWithoutBackingFiles),
+            (true, None) => BackingFilePolicy::Header,
+            (true, Some(backing_file)) => BackingFilePolicy::Override(PathBuf::from(backing_file)),
+        };
+        let discard = parser
+            .convert::<Toggle>("discard")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let key_file = parser.get("key_file").map(PathBuf::from);
+        let key_fd = parser
+            .convert::<i32>("key_fd")
+            .map_err(Error::FailedConfigParse)?;
+        if key_file.is_some() && key_fd.is_some() {
+            return Err(Error::KeyFileAndFd);
+        }
+        if matches!(key_fd, Some(fd) if fd <= 2) {
+            return Err(Error::ReservedKeyFd);
+        }
+        // Discarded sectors would read as garbage once decrypted.
+        if discard && (key_file.is_some() || key_fd.is_some()) {
+            return Err(Error::DiscardWithKeyFile);
+        }
 
         Ok(VhostUserBlkBackendConfig {
             path,
@@ -476,6 +580,10 @@ impl VhostUserBlkBackendConfig {
             readonly,
             direct,
             poll_queue,
+            backing_file_policy,
+            discard,
+            key_file,
+            key_fd,
         })
     }
 }
@@ -497,6 +605,10 @@ pub fn start_block_backend(backend_command: &str) {
             backend_config.direct,
             backend_config.poll_queue,
             backend_config.queue_size,
+            backend_config.backing_file_policy,
+            backend_config.discard,
+            backend_config.key_file,
+            backend_config.key_fd,
         )
         .unwrap(),
     ));
@@ -9,14 +9,16 @@
 use libc::{self, EFD_NONBLOCK};
 use log::*;
 use net_util::{
-    open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
+    open_tap, CaptureConfig, MacAddr, NetCounters, NetQueuePair, OpenTapError, PacketCapture,
+    RxVirtio, Tap, TxVirtio,
 };
-use option_parser::Toggle;
+use option_parser::{ByteSized, Toggle};
 use option_parser::{OptionParser, OptionParserError};
 use std::fmt;
 use std::io::{self};
 use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
 use std::process;
 use std::sync::{Arc, Mutex, RwLock};
 use std::vec::Vec;
@@ -52,11 +54,15 @@ pub enum Error {
     NetQueuePair(net_util::NetQueuePairError),
     /// Failed to register the TAP listener.
     RegisterTapListener(io::Error),
+    /// Failed to start capturing the frames.
+    StartCapture(io::Error),
 }
 
 pub const SYNTAX: &str = "vhost-user-net backend parameters \
 \"ip=<ip_addr>,mask=<net_mask>,socket=<socket_path>,client=on|off,\
-num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>\"";
+num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>,\
+capture=<capture_path>,capture_snaplen=<bytes>,capture_file_size=<bytes>,\
+capture_files=<number_of_files>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -79,7 +85,7 @@ struct VhostUserNetThread {
 
 impl VhostUserNetThread {
     /// Create a new virtio network device with the given TAP interface.
-    fn new(tap: Tap) -> Result<Self> {
+    fn new(tap: Tap, capture: Arc<PacketCapture>) -> Result<Self> {
         Ok(VhostUserNetThread {
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
             net: NetQueuePair {
@@ -96,6 +102,10 @@ impl VhostUserNetThread {
                 rx_desc_avail: false,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rx_filter: None,
+                l2_socket: None,
+                capture: Some(capture),
+                status: None,
                 access_platform: None,
             },
         })
@@ -121,6 +131,7 @@ impl VhostUserNetBackend {
         num_queues: usize,
         queue_size: u16,
         ifname: Option<&str>,
+        capture_config: Option<&CaptureConfig>,
     ) -> Result<Self> {
         let mut taps = open_tap(
             ifname,
@@ -132,10 +143,15 @@ impl VhostUserNetBackend {
         )
         .map_err(Error::OpenTap)?;
 
+        let capture = Arc::new(PacketCapture::new(ifname.unwrap_or_default()));
+        if let Some(capture_config) = capture_config {
+            capture.start(capture_config).map_err(Error::StartCapture)?;
+        }
+
         let mut queues_per_thread = Vec::new();
         let mut threads = Vec::new();
         for (i, tap) in taps.drain(..).enumerate() {
-            let thread = Mutex::new(VhostUserNetThread::new(tap)?);
+            let thread = Mutex::new(VhostUserNetThread::new(tap, capture.clone())?);
             threads.push(thread);
             queues_per_thread.push(0b11 << (i * 2));
         }
@@ -270,6 +286,7 @@ pub struct VhostUserNetBackendConfig {
     pub queue_size: u16,
     pub tap: Option<String>,
     pub client: bool,
+    pub capture: Option<CaptureConfig>,
 }
 
 impl VhostUserNetBackendConfig {
@@ -284,7 +301,11 @@ impl VhostUserNetBackendConfig {
             .add("queue_size")
             .add("num_queues")
             .add("socket")
-            .add("client");
+            .add("client")
+            .add("capture")
+            .add("capture_snaplen")
+            .add("capture_file_size")
+            .add("capture_files");
 
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
@@ -315,6 +336,22 @@ impl VhostUserNetBackendConfig {
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(Toggle(false))
             .0;
+        let capture_snaplen = parser
+            .convert("capture_snaplen")
+            .map_err(Error::FailedConfigParse)?;
+        let capture_file_size = parser
+            .convert::<ByteSized>("capture_file_size")
+            .map_err(Error::FailedConfigParse)?
+            .map(|v| v.0);
+        let capture_files = parser
+            .convert("capture_files")
+            .map_err(Error::FailedConfigParse)?;
+        let capture = parser.get("capture").map(|path| CaptureConfig {
+            path: PathBuf::from(path),
+            snaplen: capture_snaplen,
+            file_size: capture_file_size,
+            files: capture_files,
+        });
 
         Ok(VhostUserNetBackendConfig {
             ip,
@@ -325,6 +362,7 @@ impl VhostUserNetBackendConfig {
             queue_size,
             tap,
             client,
+            capture,
         })
     }
 }
@@ -348,6 +386,7 @@ pub fn start_net_backend(backend_command: &str) {
             backend_config.num_queues,
             backend_config.queue_size,
             tap,
+            backend_config.capture.as_ref(),
         )
         .unwrap(),
     ));
@@ -10,28 +10,35 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler,
-    RateLimiterConfig, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
-    EPOLL_HELPER_EVENT_LAST,
+    own_rate_limiter, queue_rate_limiter, ActivateError, ActivateResult, EpollHelper,
+    EpollHelperError, EpollHelperHandler, RateLimiterConfig, RateLimiterUpdate, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
 use crate::GuestMemoryMmap;
 use crate::VirtioInterrupt;
+use anyhow::anyhow;
 use block_util::{
-    async_io::AsyncIo, async_io::AsyncIoError, async_io::DiskFile, build_disk_image_id, Request,
+    async_io::AsyncIo, async_io::AsyncIoError, async_io::DiskFile, async_io::DiskFileError,
+    async_io::DiskFileResult, backup::BackupHeader, backup::DiskBackup, build_disk_image_id,
+    dirty_bitmap::DirtyBitmap, dirty_bitmap::DirtyBitmapState, mirror::DiskMirror, Request,
     RequestType, VirtioBlockConfig,
 };
+use rate_limiter::group::{QueueRateLimiter, RateLimiterGroup};
 use rate_limiter::{RateLimiter, TokenType};
 use seccompiler::SeccompAction;
-use std::io;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
 use std::num::Wrapping;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Barrier};
-use std::{collections::HashMap, convert::TryInto};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_bindings::bindings::virtio_blk::*;
@@ -51,19 +58,24 @@ const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // New 'wake up' event from the rate limiter
 const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The disk was mirrored to a new image to switch over to.
+const PIVOT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// The VM was resumed, the requests stopped on error can be retried.
+const RETRY_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
+// The limits of the disk were updated at runtime.
+const RATE_LIMITER_UPDATE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// The connection to a disk image reached over the network should be
+// established again.
+const RECONNECT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
 
 #[derive(Debug)]
 pub enum Error {
     /// Failed to parse the request.
     RequestParsing(block_util::Error),
-    /// Failed to execute the request.
-    RequestExecuting(block_util::ExecuteError),
     /// Failed to complete the request.
     RequestCompleting(block_util::Error),
     /// Missing the expected entry in the list of requests.
     MissingEntryRequestList,
-    /// The asynchronous request returned with failure.
-    AsyncRequestFailure,
     /// Failed synchronizing the file
     Fsync(AsyncIoError),
     /// Failed adding used index
@@ -74,6 +86,41 @@ pub enum Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Action taken when a request fails on the disk image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorPolicy {
+    /// The error is reported to the guest.
+    Report,
+    /// The VM is paused, and the request retried once the VM is resumed.
+    Stop,
+    /// The request completes as if it succeeded.
+    Ignore,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        ErrorPolicy::Report
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseErrorPolicyError {
+    InvalidValue(String),
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = ParseErrorPolicyError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "report" => Ok(ErrorPolicy::Report),
+            "stop" => Ok(ErrorPolicy::Stop),
+            "ignore" => Ok(ErrorPolicy::Ignore),
+            _ => Err(ParseErrorPolicyError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct BlockCounters {
     read_bytes: Arc<AtomicU64>,
@@ -82,12 +129,125 @@ pub struct BlockCounters {
     write_ops: Arc<AtomicU64>,
 }
 
+// Mirror of the disk, shared with the epoll handlers which replay the guest
+// writes on it.
+struct MirrorJob {
+    mirror: Arc<DiskMirror>,
+    destination: PathBuf,
+    pivot: bool,
+    // Image the device switches over to once the copy completes.
+    pivot_image: Option<Box<dyn DiskFile>>,
+}
+
+// Hands an epoll handler the image the disk was mirrored to, or the new media
+// of the disk. The handler switches over once its requests in flight
+// completed on the previous image, then drops the sender to let the mirror
+// know, and its reference to the previous media to close it.
+struct DiskPivot {
+    evt: EventFd,
+    queue_size: u16,
+    disk_image: Mutex<Option<Box<dyn AsyncIo>>>,
+    done: Mutex<Option<mpsc::Sender<()>>>,
+    previous_media: Mutex<Option<Arc<Box<dyn DiskFile>>>>,
+    // Whether the new media is read-only, unless the disk was mirrored.
+    read_only: Mutex<Option<bool>>,
+}
+
+// Applies the error policies of the disk to the requests which failed.
+struct IoErrorHandler {
+    id: String,
+    read_policy: ErrorPolicy,
+    write_policy: ErrorPolicy,
+    // Lets the VMM know the VM must be paused.
+    stop_evt: EventFd,
+    // Requests stopped on error, retried once the VM is resumed.
+    stopped_requests: Vec<(u16, Request)>,
+    // Number of requests stopped on error on all the queues of the device.
+    stopped_count: Arc<AtomicUsize>,
+}
+
+impl IoErrorHandler {
+    fn is_stopped(&self) -> bool {
+        !self.stopped_requests.is_empty()
+    }
+
+    fn stop(&mut self, desc_index: u16, request: Request) {
+        self.stopped_requests.push((desc_index, request));
+        self.stopped_count.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn take_stopped(&mut self) -> Vec<(u16, Request)> {
+        let stopped_requests = std::mem::take(&mut self.stopped_requests);
+        self.stopped_count
+            .fetch_sub(stopped_requests.len(), Ordering::AcqRel);
+        stopped_requests
+    }
+
+    // Returns the status to complete the request with, unless the request was
+    // stopped on error.
+    fn request_failed(
+        &mut self,
+        desc_index: u16,
+        request: Request,
+        error: &dyn fmt::Debug,
+    ) -> Option<u32> {
+        let policy = match request.request_type {
+            RequestType::In => self.read_policy,
+            RequestType::Out
+            | RequestType::Flush
+            | RequestType::Discard
+            | RequestType::WriteZeroes => self.write_policy,
+            _ => ErrorPolicy::Report,
+        };
+
+        match policy {
+            ErrorPolicy::Report => {
+                error!("Request failed on disk {}: {:?}", self.id, error);
+                Some(VIRTIO_BLK_S_IOERR)
+            }
+            ErrorPolicy::Ignore => {
+                warn!("Ignoring failed request on disk {}: {:?}", self.id, error);
+                Some(VIRTIO_BLK_S_OK)
+            }
+            ErrorPolicy::Stop => {
+                error!(
+                    "Request failed on disk {}, stopping the VM: {:?}",
+                    self.id, error
+                );
+                if !self.is_stopped() {
+                    event!(
+                        "virtio-device",
+                        "io-error-stop",
+                        "id",
+                        &self.id,
+                        "error",
+                        format!("{:?}", error)
+                    );
+                    if let Err(e) = self.stop_evt.write(1) {
+                        error!("Failed to trigger stop event: {:?}", e);
+                    }
+                }
+                self.stop(desc_index, request);
+                None
+            }
+        }
+    }
+}
+
+// Requests modifying the content of the disk, which fail on read-only media.
+fn is_write_request(request_type: RequestType) -> bool {
+    matches!(
+        request_type,
+        RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+    )
+}
+
 struct BlockEpollHandler {
     queue_index: u16,
     queue: Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     disk_image: Box<dyn AsyncIo>,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     disk_image_id: Vec<u8>,
     kill_evt: EventFd,
@@ -96,12 +256,28 @@ struct BlockEpollHandler {
     counters: BlockCounters,
     queue_evt: EventFd,
     request_list: HashMap<u16, Request>,
-    rate_limiter: Option<RateLimiter>,
+    rate_limiter: Option<QueueRateLimiter>,
+    rate_limiter_update: Arc<RateLimiterUpdate<Option<RateLimiter>>>,
     access_platform: Option<Arc<dyn AccessPlatform>>,
+    dirty_bitmap: Option<Arc<DirtyBitmap>>,
+    backup: Arc<Mutex<Option<Arc<DiskBackup>>>>,
+    mirror: Arc<Mutex<Option<MirrorJob>>>,
+    pivot: Arc<DiskPivot>,
+    pivoting: bool,
+    errors: IoErrorHandler,
+    retry_evt: EventFd,
+    read_only: bool,
 }
 
 impl BlockEpollHandler {
     fn process_queue_submit(&mut self) -> Result<bool> {
+        // The requests stay on the queue until the ones stopped on error
+        // were retried.
+        if self.errors.is_stopped() {
+            return Ok(false);
+        }
+
+        let backup = self.backup.lock().unwrap().clone();
         let queue = &mut self.queue;
 
         let mut used_desc_heads = Vec::new();
@@ -144,31 +320,50 @@ impl BlockEpollHandler {
             }
 
             request.set_writeback(self.writeback.load(Ordering::Acquire));
+            request.set_dirty_bitmap(self.dirty_bitmap.clone());
+            request.set_backup(backup.clone());
 
-            if request
-                .execute_async(
+            let status_addr = request.status_addr;
+            let status = if self.read_only && is_write_request(request.request_type) {
+                VIRTIO_BLK_S_IOERR
+            } else {
+                match request.execute_async(
                     desc_chain.memory(),
-                    self.disk_nsectors,
+                    self.disk_nsectors.load(Ordering::Acquire),
                     self.disk_image.as_mut(),
                     &self.disk_image_id,
                     desc_chain.head_index() as u64,
-                )
-                .map_err(Error::RequestExecuting)?
-            {
-                self.request_list.insert(desc_chain.head_index(), request);
-            } else {
-                // We use unwrap because the request parsing process already
-                // checked that the status_addr was valid.
-                desc_chain
-                    .memory()
-                    .write_obj(VIRTIO_BLK_S_OK, request.status_addr)
-                    .unwrap();
+                ) {
+                    Ok(true) => {
+                        self.request_list.insert(desc_chain.head_index(), request);
+                        continue;
+                    }
+                    Ok(false) => VIRTIO_BLK_S_OK,
+                    Err(e) => {
+                        request.complete_async().map_err(Error::RequestCompleting)?;
+                        if !e.is_disk_error() {
+                            error!("Invalid request: {:?}", e);
+                            e.status()
+                        } else if let Some(status) =
+                            self.errors
+                                .request_failed(desc_chain.head_index(), request, &e)
+                        {
+                            status
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            };
 
-                // If no asynchronous operation has been submitted, we can
-                // simply return the used descriptor.
-                used_desc_heads.push((desc_chain.head_index(), 0));
-                used_count += 1;
-            }
+            // We use unwrap because the request parsing process already
+            // checked that the status_addr was valid.
+            desc_chain.memory().write_obj(status, status_addr).unwrap();
+
+            // If no asynchronous operation has been submitted, we can
+            // simply return the used descriptor.
+            used_desc_heads.push((desc_chain.head_index(), 0));
+            used_count += 1;
         }
 
         for &(desc_index, len) in used_desc_heads.iter() {
@@ -198,8 +393,25 @@ impl BlockEpollHandler {
                 .request_list
                 .remove(&desc_index)
                 .ok_or(Error::MissingEntryRequestList)?;
+            if result >= 0 {
+                let mirror = self
+                    .mirror
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(|job| job.mirror.clone());
+                if let Some(mirror) = mirror.filter(|mirror| !mirror.is_cancelled()) {
+                    if let Err(e) =
+                        request.mirror(&mem, self.disk_nsectors.load(Ordering::Acquire), &mirror)
+                    {
+                        error!("Failed mirroring request, stopping the mirror: {}", e);
+                        mirror.cancel();
+                    }
+                }
+            }
             request.complete_async().map_err(Error::RequestCompleting)?;
 
+            let status_addr = request.status_addr;
             let (status, len) = if result >= 0 {
                 match request.request_type {
                     RequestType::In => {
@@ -222,16 +434,16 @@ impl BlockEpollHandler {
 
                 (VIRTIO_BLK_S_OK, result as u32)
             } else {
-                error!(
-                    "Request failed: {:?}",
-                    io::Error::from_raw_os_error(-result)
-                );
-                return Err(Error::AsyncRequestFailure);
+                let error = io::Error::from_raw_os_error(-result);
+                match self.errors.request_failed(desc_index, request, &error) {
+                    Some(status) => (status, 0),
+                    None => continue,
+                }
             };
 
             // We use unwrap because the request parsing process already
             // checked that the status_addr was valid.
-            mem.write_obj(status, request.status_addr).unwrap();
+            mem.write_obj(status, status_addr).unwrap();
 
             used_desc_heads.push((desc_index as u16, len));
             used_count += 1;
@@ -260,6 +472,74 @@ impl BlockEpollHandler {
         Ok(used_count > 0)
     }
 
+    // Submits again the requests stopped on error, then the ones queued
+    // meanwhile.
+    fn retry_stopped_requests(&mut self) -> Result<bool> {
+        let mem = self.mem.memory();
+        let mut used_desc_heads = Vec::new();
+
+        for (desc_index, mut request) in self.errors.take_stopped() {
+            if self.errors.is_stopped() {
+                // Failed again, the remaining requests wait for the next
+                // resume.
+                self.errors.stop(desc_index, request);
+                continue;
+            }
+
+            let status_addr = request.status_addr;
+            let status = if self.read_only && is_write_request(request.request_type) {
+                VIRTIO_BLK_S_IOERR
+            } else {
+                match request.execute_async(
+                    &mem,
+                    self.disk_nsectors.load(Ordering::Acquire),
+                    self.disk_image.as_mut(),
+                    &self.disk_image_id,
+                    desc_index as u64,
+                ) {
+                    Ok(true) => {
+                        self.request_list.insert(desc_index, request);
+                        continue;
+                    }
+                    Ok(false) => VIRTIO_BLK_S_OK,
+                    Err(e) => {
+                        request.complete_async().map_err(Error::RequestCompleting)?;
+                        if !e.is_disk_error() {
+                            error!("Invalid request: {:?}", e);
+                            e.status()
+                        } else if let Some(status) =
+                            self.errors.request_failed(desc_index, request, &e)
+                        {
+                            status
+                        } else {
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            // We use unwrap because the request parsing process already
+            // checked that the status_addr was valid.
+            mem.write_obj(status, status_addr).unwrap();
+            used_desc_heads.push(desc_index);
+        }
+
+        for desc_index in used_desc_heads.iter() {
+            self.queue
+                .add_used(*desc_index, 0)
+                .map_err(Error::QueueAddUsed)?;
+        }
+
+        let rate_limit_reached = self.rate_limiter.as_ref().map_or(false, |r| r.is_blocked());
+        let needs_notification = if !rate_limit_reached && !self.pivoting {
+            self.process_queue_submit()?
+        } else {
+            false
+        };
+
+        Ok(needs_notification || !used_desc_heads.is_empty())
+    }
+
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(self.queue_index))
@@ -269,6 +549,111 @@ impl BlockEpollHandler {
             })
     }
 
+    // Switches over to the image the disk was mirrored to, once the requests
+    // in flight completed on the previous one. Returns false on error.
+    fn try_pivot(&mut self, helper: &mut EpollHelper) -> bool {
+        if !self.request_list.is_empty() {
+            return true;
+        }
+
+        if let Some(disk_image) = self.pivot.disk_image.lock().unwrap().take() {
+            if let Err(e) = helper
+                .del_event_custom(
+                    self.disk_image.notifier().as_raw_fd(),
+                    COMPLETION_EVENT,
+                    epoll::Events::EPOLLIN,
+                )
+                .and_then(|_| helper.add_event(disk_image.notifier().as_raw_fd(), COMPLETION_EVENT))
+                .and_then(|_| match self.disk_image.reconnect_fd() {
+                    Some(fd) => {
+                        helper.del_event_custom(fd, RECONNECT_EVENT, epoll::Events::EPOLLIN)
+                    }
+                    None => Ok(()),
+                })
+                .and_then(|_| match disk_image.reconnect_fd() {
+                    Some(fd) => helper.add_event(fd, RECONNECT_EVENT),
+                    None => Ok(()),
+                })
+            {
+                error!("Failed switching to the mirrored disk image: {:?}", e);
+                return false;
+            }
+            self.disk_image = disk_image;
+        }
+        if let Some(read_only) = self.pivot.read_only.lock().unwrap().take() {
+            self.read_only = read_only;
+        }
+        self.pivoting = false;
+        self.pivot.done.lock().unwrap().take();
+        self.pivot.previous_media.lock().unwrap().take();
+
+        // Process the requests queued while switching over.
+        match self.process_queue_submit() {
+            Ok(needs_notification) => {
+                if needs_notification {
+                    if let Err(e) = self.signal_used_queue() {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return false;
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to process queue (submit): {:?}", e);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Replaces the rate limiter of the queue with the one handed over by the
+    // device, if any. Returns false on error.
+    fn update_rate_limiter(&mut self, helper: &mut EpollHelper) -> bool {
+        let rate_limiter = match self.rate_limiter_update.take() {
+            Some(rate_limiter) => rate_limiter.map(QueueRateLimiter::Own),
+            None => return true,
+        };
+
+        if let Some(previous) = &self.rate_limiter {
+            if let Err(e) = helper.del_event_custom(
+                previous.as_raw_fd(),
+                RATE_LIMITER_EVENT,
+                epoll::Events::EPOLLIN,
+            ) {
+                error!("Failed removing the previous rate limiter: {:?}", e);
+                return false;
+            }
+        }
+        if let Some(rate_limiter) = &rate_limiter {
+            if let Err(e) = helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT) {
+                error!("Failed adding the new rate limiter: {:?}", e);
+                return false;
+            }
+        }
+        self.rate_limiter = rate_limiter;
+
+        // Process the requests held back by the previous limits.
+        if self.pivoting {
+            return true;
+        }
+        match self.process_queue_submit() {
+            Ok(needs_notification) => {
+                if needs_notification {
+                    if let Err(e) = self.signal_used_queue() {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return false;
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to process queue (submit): {:?}", e);
+                return false;
+            }
+        }
+
+        true
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -277,9 +662,18 @@ impl BlockEpollHandler {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
         helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)?;
+        if let Some(fd) = self.disk_image.reconnect_fd() {
+            helper.add_event(fd, RECONNECT_EVENT)?;
+        }
         if let Some(rate_limiter) = &self.rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT)?;
         }
+        helper.add_event(self.pivot.evt.as_raw_fd(), PIVOT_EVENT)?;
+        helper.add_event(self.retry_evt.as_raw_fd(), RETRY_EVENT)?;
+        helper.add_event(
+            self.rate_limiter_update.evt.as_raw_fd(),
+            RATE_LIMITER_UPDATE_EVENT,
+        )?;
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -287,7 +681,7 @@ impl BlockEpollHandler {
 }
 
 impl EpollHelperHandler for BlockEpollHandler {
-    fn handle_event(&mut self, _helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+    fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool {
         let ev_type = event.data as u16;
         match ev_type {
             QUEUE_AVAIL_EVENT => {
@@ -299,8 +693,9 @@ impl EpollHelperHandler for BlockEpollHandler {
                 let rate_limit_reached =
                     self.rate_limiter.as_ref().map_or(false, |r| r.is_blocked());
 
-                // Process the queue only when the rate limit is not reached
-                if !rate_limit_reached {
+                // Process the queue only when the rate limit is not reached,
+                // and not while switching over to a mirrored image.
+                if !rate_limit_reached && !self.pivoting {
                     match self.process_queue_submit() {
                         Ok(needs_notification) => {
                             if needs_notification {
@@ -337,12 +732,16 @@ impl EpollHelperHandler for BlockEpollHandler {
                         return true;
                     }
                 }
+
+                if self.pivoting && !self.try_pivot(helper) {
+                    return true;
+                }
             }
             RATE_LIMITER_EVENT => {
                 if let Some(rate_limiter) = &mut self.rate_limiter {
                     // Upon rate limiter event, call the rate limiter handler
                     // and restart processing the queue.
-                    if rate_limiter.event_handler().is_ok() {
+                    if rate_limiter.event_handler().is_ok() && !self.pivoting {
                         match self.process_queue_submit() {
                             Ok(needs_notification) => {
                                 if needs_notification {
@@ -363,6 +762,53 @@ impl EpollHelperHandler for BlockEpollHandler {
                     return true;
                 }
             }
+            PIVOT_EVENT => {
+                if let Err(e) = self.pivot.evt.read() {
+                    error!("Failed to get pivot event: {:?}", e);
+                    return true;
+                }
+
+                self.pivoting = true;
+                if !self.try_pivot(helper) {
+                    return true;
+                }
+            }
+            RETRY_EVENT => {
+                if let Err(e) = self.retry_evt.read() {
+                    error!("Failed to get retry event: {:?}", e);
+                    return true;
+                }
+
+                match self.retry_stopped_requests() {
+                    Ok(needs_notification) => {
+                        if needs_notification {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                return true;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to retry the stopped requests: {:?}", e);
+                        return true;
+                    }
+                }
+            }
+            RATE_LIMITER_UPDATE_EVENT => {
+                if let Err(e) = self.rate_limiter_update.evt.read() {
+                    error!("Failed to get rate limiter update event: {:?}", e);
+                    return true;
+                }
+
+                if !self.update_rate_limiter(helper) {
+                    return true;
+                }
+            }
+            RECONNECT_EVENT => {
+                // Requests fail until the connection is established again,
+                // going through the error policies of the disk.
+                self.disk_image.reconnect();
+            }
             _ => {
                 error!("Unexpected event: {}", ev_type);
                 return true;
@@ -376,15 +822,26 @@ impl EpollHelperHandler for BlockEpollHandler {
 pub struct Block {
     common: VirtioCommon,
     id: String,
-    disk_image: Box<dyn DiskFile>,
+    disk_image: Arc<Mutex<Box<dyn DiskFile>>>,
     disk_path: PathBuf,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     config: VirtioBlockConfig,
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
     seccomp_action: SeccompAction,
     rate_limiter_config: Option<RateLimiterConfig>,
+    rate_limit_group: Option<Arc<RateLimiterGroup>>,
+    rate_limiter_updates: Vec<Arc<RateLimiterUpdate<Option<RateLimiter>>>>,
     exit_evt: EventFd,
+    dirty_bitmap: Option<Arc<DirtyBitmap>>,
+    backup: Arc<Mutex<Option<Arc<DiskBackup>>>>,
+    mirror: Arc<Mutex<Option<MirrorJob>>>,
+    pivots: Arc<Mutex<Vec<Arc<DiskPivot>>>>,
+    read_error_policy: ErrorPolicy,
+    write_error_policy: ErrorPolicy,
+    stop_evt: EventFd,
+    retry_evts: Vec<EventFd>,
+    stopped_requests: Arc<AtomicUsize>,
 }
 
 #[derive(Versionize)]
@@ -394,6 +851,7 @@ pub struct BlockState {
     pub avail_features: u64,
     pub acked_features: u64,
     pub config: VirtioBlockConfig,
+    pub dirty_bitmap: Option<DirtyBitmapState>,
 }
 
 impl VersionMapped for BlockState {}
@@ -411,7 +869,13 @@ impl Block {
         queue_size: u16,
         seccomp_action: SeccompAction,
         rate_limiter_config: Option<RateLimiterConfig>,
+        rate_limit_group: Option<Arc<RateLimiterGroup>>,
         exit_evt: EventFd,
+        discard: bool,
+        dirty_bitmap_path: Option<PathBuf>,
+        read_error_policy: ErrorPolicy,
+        write_error_policy: ErrorPolicy,
+        stop_evt: EventFd,
     ) -> io::Result<Self> {
         let disk_size = disk_image.size().map_err(|e| {
             io::Error::new(
@@ -427,6 +891,10 @@ impl Block {
             );
         }
 
+        let dirty_bitmap = dirty_bitmap_path
+            .map(|path| DirtyBitmap::open(&path, disk_size).map(Arc::new))
+            .transpose()?;
+
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
             | (1u64 << VIRTIO_BLK_F_FLUSH)
             | (1u64 << VIRTIO_BLK_F_CONFIG_WCE)
@@ -441,6 +909,10 @@ impl Block {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
         }
 
+        if discard && !is_disk_read_only {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        }
+
         let topology = disk_image.topology();
         info!("Disk topology: {:?}", topology);
 
@@ -474,6 +946,17 @@ impl Block {
             config.num_queues = num_queues as u16;
         }
 
+        if avail_features & (1u64 << VIRTIO_BLK_F_DISCARD) != 0 {
+            // Each request covers a single segment, as large as the guest
+            // wants it to be.
+            config.max_discard_sectors = u32::MAX;
+            config.max_discard_seg = 1;
+            config.discard_sector_alignment = (logical_block_size / SECTOR_SIZE) as u32;
+            config.max_write_zeroes_sectors = u32::MAX;
+            config.max_write_zeroes_seg = 1;
+            config.write_zeroes_may_unmap = 1;
+        }
+
         Ok(Block {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::Block as u32,
@@ -484,34 +967,51 @@ impl Block {
                 ..Default::default()
             },
             id,
-            disk_image,
+            disk_image: Arc::new(Mutex::new(disk_image)),
             disk_path,
-            disk_nsectors,
+            disk_nsectors: Arc::new(AtomicU64::new(disk_nsectors)),
             config,
             writeback: Arc::new(AtomicBool::new(true)),
             counters: BlockCounters::default(),
             seccomp_action,
             rate_limiter_config,
+            rate_limit_group,
+            rate_limiter_updates: Vec::new(),
             exit_evt,
+            dirty_bitmap,
+            backup: Arc::new(Mutex::new(None)),
+            mirror: Arc::new(Mutex::new(None)),
+            pivots: Arc::new(Mutex::new(Vec::new())),
+            read_error_policy,
+            write_error_policy,
+            stop_evt,
+            retry_evts: Vec::new(),
+            stopped_requests: Arc::new(AtomicUsize::new(0)),
         })
     }
 
     fn state(&self) -> BlockState {
         BlockState {
             disk_path: self.disk_path.to_str().unwrap().to_owned(),
-            disk_nsectors: self.disk_nsectors,
+            disk_nsectors: self.disk_nsectors.load(Ordering::Acquire),
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
             config: self.config,
+            dirty_bitmap: self.dirty_bitmap.as_ref().map(|b| b.state()),
         }
     }
 
     fn set_state(&mut self, state: &BlockState) {
         self.disk_path = state.disk_path.clone().into();
-        self.disk_nsectors = state.disk_nsectors;
+        self.disk_nsectors
+            .store(state.disk_nsectors, Ordering::Release);
         self.common.avail_features = state.avail_features;
         self.common.acked_features = state.acked_features;
         self.config = state.config;
+        // Writes tracked before the snapshot still have to be backed up.
+        if let (Some(dirty_bitmap), Some(state)) = (&self.dirty_bitmap, &state.dirty_bitmap) {
+            dirty_bitmap.merge_state(state);
+        }
     }
 
     fn update_writeback(&mut self) {
@@ -533,6 +1033,464 @@ impl Block {
         );
         self.writeback.store(writeback, Ordering::Release);
     }
+
+    /// Creates an internal snapshot named `name` in the disk image. The
+    /// device must be paused so that no request is in flight.
+    pub fn create_snapshot(&mut self, name: &str) -> DiskFileResult<()> {
+        self.disk_image.lock().unwrap().create_snapshot(name)
+    }
+
+    /// Deletes the internal snapshot named `name` from the disk image.
+    pub fn delete_snapshot(&mut self, name: &str) -> DiskFileResult<()> {
+        self.disk_image.lock().unwrap().delete_snapshot(name)
+    }
+
+    /// Grows the disk image to `size` bytes, or picks up the size of an image
+    /// grown from outside when `size` is `None`. The guest is notified of the
+    /// new capacity through a configuration change interrupt.
+    pub fn resize(&mut self, size: Option<u64>) -> io::Result<()> {
+        let disk_size_error = |e: DiskFileError| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed getting disk size: {}", e),
+            )
+        };
+
+        if self.mirror.lock().unwrap().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Disk can't be resized while it's mirrored",
+            ));
+        }
+
+        if self.backup.lock().unwrap().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Disk can't be resized while it's backed up",
+            ));
+        }
+
+        let mut disk_image = self.disk_image.lock().unwrap();
+        let current_size = disk_image.size().map_err(disk_size_error)?;
+        if let Some(size) = size {
+            if size < current_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Disk can't shrink from {} to {} bytes", current_size, size),
+                ));
+            }
+            if size > current_size {
+                disk_image.resize(size).map_err(|e| {
+                    io::Error::new(io::ErrorKind::Other, format!("Failed resizing disk: {}", e))
+                })?;
+            }
+        }
+
+        let disk_nsectors = disk_image.size().map_err(disk_size_error)? / SECTOR_SIZE;
+        drop(disk_image);
+        if disk_nsectors < self.disk_nsectors.load(Ordering::Acquire) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Disk image is smaller than the capacity exposed to the guest",
+            ));
+        }
+
+        info!("Resizing disk {} to {} sectors", self.id, disk_nsectors);
+        self.disk_nsectors.store(disk_nsectors, Ordering::Release);
+        self.config.capacity = disk_nsectors;
+        if let Some(dirty_bitmap) = &self.dirty_bitmap {
+            dirty_bitmap.resize(disk_nsectors * SECTOR_SIZE);
+        }
+
+        // Until the device is activated, the guest has yet to read the capacity.
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb.trigger(VirtioInterruptType::Config)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the limits enforced on each queue of the disk, or removes them
+    /// when `rate_limiter_config` is `None`. Disks drawing from a rate limit
+    /// group follow the limits of the group instead.
+    pub fn update_rate_limiter(
+        &mut self,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> io::Result<()> {
+        if self.rate_limit_group.is_none() {
+            for rate_limiter_update in self.rate_limiter_updates.iter() {
+                rate_limiter_update.hand_over(own_rate_limiter(rate_limiter_config)?)?;
+            }
+        }
+        self.rate_limiter_config = rate_limiter_config;
+
+        Ok(())
+    }
+
+    /// Replaces the media of the disk with `disk_image`, found at
+    /// `disk_path`, as a CD-ROM drive would. The guest is notified of the new
+    /// capacity through a configuration change interrupt, while the read-only
+    /// flag is only picked up by drivers initializing the device. Writes to
+    /// read-only media fail meanwhile.
+    pub fn change_media(
+        &mut self,
+        mut disk_image: Box<dyn DiskFile>,
+        disk_path: PathBuf,
+        read_only: bool,
+    ) -> io::Result<()> {
+        if self.mirror.lock().unwrap().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Media can't be changed while the disk is mirrored",
+            ));
+        }
+
+        if self.backup.lock().unwrap().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Media can't be changed while the disk is backed up",
+            ));
+        }
+
+        if self.dirty_bitmap.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Media of a disk with a dirty bitmap can't be changed",
+            ));
+        }
+
+        let disk_size = disk_image.size().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed getting disk size: {}", e),
+            )
+        })?;
+
+        // The images the epoll handlers switch over to are created upfront,
+        // the handlers being restricted by seccomp.
+        let pivots = self.pivots.lock().unwrap();
+        let async_ios = pivots
+            .iter()
+            .map(|disk_pivot| disk_image.new_async_io(disk_pivot.queue_size as u32))
+            .collect::<DiskFileResult<Vec<_>>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        info!(
+            "Changing media of disk {} to {:?}",
+            self.id,
+            disk_path.as_path()
+        );
+        // The previous media is kept open until all the handlers using it
+        // switched over, while the ones which have yet to switch over from
+        // an older media keep on holding it instead.
+        let previous_media = Arc::new(std::mem::replace(
+            &mut *self.disk_image.lock().unwrap(),
+            disk_image,
+        ));
+        for (disk_pivot, async_io) in pivots.iter().zip(async_ios) {
+            *disk_pivot.disk_image.lock().unwrap() = Some(async_io);
+            *disk_pivot.read_only.lock().unwrap() = Some(read_only);
+            disk_pivot
+                .previous_media
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| previous_media.clone());
+            if let Err(e) = disk_pivot.evt.write(1) {
+                error!("Failed to trigger pivot event: {:?}", e);
+            }
+        }
+        drop(pivots);
+
+        let disk_nsectors = disk_size / SECTOR_SIZE;
+        self.disk_path = disk_path;
+        self.disk_nsectors.store(disk_nsectors, Ordering::Release);
+        self.config.capacity = disk_nsectors;
+        if read_only {
+            self.common.avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            self.common.avail_features &= !(1u64 << VIRTIO_BLK_F_RO);
+        }
+
+        // Until the device is activated, the guest has yet to read the capacity.
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb.trigger(VirtioInterruptType::Config)?;
+        }
+        event!("virtio-device", "media-changed", "id", &self.id);
+
+        Ok(())
+    }
+
+    /// Returns the size of the disk exposed to the guest, in bytes.
+    pub fn disk_size(&self) -> u64 {
+        self.disk_nsectors.load(Ordering::Acquire) * SECTOR_SIZE
+    }
+
+    pub fn has_dirty_bitmap(&self) -> bool {
+        self.dirty_bitmap.is_some()
+    }
+
+    /// Starts writing a backup of the disk to `writer`, returning a receiver
+    /// for its result. Incremental backups hold the ranges written since the
+    /// previous backup, as tracked by the dirty bitmap. The device is expected
+    /// to be paused while the backup starts. The disk is then copied in the
+    /// background, while the guest writes save the ranges they overwrite
+    /// first, so that the backup is a consistent copy.
+    pub fn start_backup(
+        &mut self,
+        writer: Box<dyn Write + Send>,
+        incremental: bool,
+    ) -> io::Result<mpsc::Receiver<io::Result<()>>> {
+        let mut backup = self.backup.lock().unwrap();
+        if backup.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Disk is already backed up",
+            ));
+        }
+        // The backup reads the image the device would switch away from.
+        if self
+            .mirror
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(false, |job| job.pivot)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Disk can't be backed up while it's mirrored",
+            ));
+        }
+
+        let disk_size = self.disk_size();
+        let dirty_ranges = match &self.dirty_bitmap {
+            Some(dirty_bitmap) => dirty_bitmap.checkpoint(),
+            None if incremental => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Incremental backups require a dirty bitmap",
+                ))
+            }
+            None => Vec::new(),
+        };
+        let ranges = if incremental {
+            dirty_ranges.clone()
+        } else {
+            vec![(0, disk_size)]
+        };
+        // Bitmaps start with the whole disk dirty, making the first
+        // incremental backup a full one.
+        let full = ranges == [(0, disk_size)];
+
+        info!(
+            "Writing {} backup of disk {}",
+            if full { "full" } else { "incremental" },
+            self.id
+        );
+        let disk_backup = self
+            .disk_image
+            .lock()
+            .unwrap()
+            .new_async_io(1)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .and_then(|source| {
+                DiskBackup::new(source, BackupHeader { disk_size, full }, &ranges, writer)
+            });
+        let disk_backup = match disk_backup {
+            Ok(disk_backup) => Arc::new(disk_backup),
+            Err(e) => {
+                if let Some(dirty_bitmap) = &self.dirty_bitmap {
+                    dirty_bitmap.restore_ranges(&dirty_ranges);
+                }
+                return Err(e);
+            }
+        };
+        *backup = Some(disk_backup.clone());
+        drop(backup);
+
+        let (result_tx, result_rx) = mpsc::channel();
+        let worker = BackupWorker {
+            backup: disk_backup,
+            job: self.backup.clone(),
+            dirty_bitmap: self.dirty_bitmap.clone(),
+            dirty_ranges: dirty_ranges.clone(),
+            result_tx,
+        };
+        let mut threads = Vec::new();
+        spawn_virtio_thread(
+            &format!("{}_backup", self.id),
+            &self.seccomp_action,
+            Thread::VirtioBlock,
+            &mut threads,
+            &self.exit_evt,
+            move || worker.run(),
+        )
+        .map_err(|e| {
+            if let Some(disk_backup) = self.backup.lock().unwrap().take() {
+                disk_backup.cancel();
+            }
+            if let Some(dirty_bitmap) = &self.dirty_bitmap {
+                dirty_bitmap.restore_ranges(&dirty_ranges);
+            }
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed spawning backup thread: {:?}", e),
+            )
+        })?;
+
+        Ok(result_rx)
+    }
+
+    /// Writes a backup of the disk to `writer`, waiting for it to complete.
+    /// The device must be paused.
+    pub fn backup(&mut self, writer: Box<dyn Write + Send>, incremental: bool) -> io::Result<()> {
+        self.start_backup(writer, incremental)?
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Disk backup thread exited"))?
+    }
+
+    /// Starts mirroring the disk to the empty `destination` image found at
+    /// `destination_path`. The copy runs in the background while the guest
+    /// writes are replayed on the destination. Once the copy completes, the
+    /// device switches over to the destination if `pivot` is set, calling
+    /// `on_pivot`. Otherwise the destination is kept in sync until the mirror
+    /// is cancelled.
+    pub fn start_mirror(
+        &mut self,
+        destination: Box<dyn DiskFile>,
+        destination_path: PathBuf,
+        pivot: bool,
+        on_pivot: Box<dyn FnOnce() + Send>,
+    ) -> io::Result<()> {
+        let new_async_io_error = |e: DiskFileError| io::Error::new(io::ErrorKind::Other, e);
+
+        let mut job = self.mirror.lock().unwrap();
+        if job.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Disk is already mirrored",
+            ));
+        }
+        if pivot && self.backup.lock().unwrap().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Disk can't switch over to a mirror while it's backed up",
+            ));
+        }
+
+        let disk_size = self.disk_size();
+        let source = self
+            .disk_image
+            .lock()
+            .unwrap()
+            .new_async_io(1)
+            .map_err(new_async_io_error)?;
+        let mirror = Arc::new(DiskMirror::new(
+            destination.new_async_io(1).map_err(new_async_io_error)?,
+            disk_size,
+        ));
+        // The images the epoll handlers switch over to are created upfront,
+        // the handlers and the mirror thread being restricted by seccomp.
+        if pivot {
+            for disk_pivot in self.pivots.lock().unwrap().iter() {
+                *disk_pivot.disk_image.lock().unwrap() = Some(
+                    destination
+                        .new_async_io(disk_pivot.queue_size as u32)
+                        .map_err(new_async_io_error)?,
+                );
+            }
+        }
+
+        info!(
+            "Mirroring disk {} to {:?}",
+            self.id,
+            destination_path.as_path()
+        );
+        *job = Some(MirrorJob {
+            mirror: mirror.clone(),
+            destination: destination_path,
+            pivot,
+            pivot_image: if pivot { Some(destination) } else { None },
+        });
+        drop(job);
+
+        let worker = MirrorWorker {
+            id: self.id.clone(),
+            mirror,
+            source,
+            job: self.mirror.clone(),
+            pivots: self.pivots.clone(),
+            disk_image: self.disk_image.clone(),
+            on_pivot,
+        };
+        let mut threads = Vec::new();
+        spawn_virtio_thread(
+            &format!("{}_mirror", self.id),
+            &self.seccomp_action,
+            Thread::VirtioBlock,
+            &mut threads,
+            &self.exit_evt,
+            move || worker.run(),
+        )
+        .map_err(|e| {
+            self.mirror.lock().unwrap().take();
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed spawning mirror thread: {:?}", e),
+            )
+        })
+    }
+
+    /// Stops mirroring the disk, leaving the destination image behind. A
+    /// mirror can't be cancelled once the device started switching over to
+    /// the destination.
+    pub fn cancel_mirror(&mut self) -> io::Result<()> {
+        let mut job = self.mirror.lock().unwrap();
+        match job.as_ref() {
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Disk isn't mirrored",
+            )),
+            Some(job) if job.pivot && job.mirror.is_ready() => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Disk is already switching over to its mirror",
+            )),
+            Some(_) => {
+                info!("Cancelling the mirror of disk {}", self.id);
+                job.take().unwrap().mirror.cancel();
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the destination of the mirror kept in sync with the disk, if
+    /// any. Mirrors still copying or which failed are reported as errors.
+    pub fn mirror_destination(&self) -> io::Result<Option<PathBuf>> {
+        match self.mirror.lock().unwrap().as_ref() {
+            None => Ok(None),
+            Some(job) if job.mirror.is_cancelled() => {
+                Err(io::Error::new(io::ErrorKind::Other, "Disk mirror failed"))
+            }
+            Some(job) if job.pivot || !job.mirror.is_ready() => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Disk mirror isn't ready",
+            )),
+            Some(job) => Ok(Some(job.destination.clone())),
+        }
+    }
+
+    /// Flushes the writes replayed on the mirror of the disk, if any.
+    pub fn flush_mirror(&self) -> io::Result<()> {
+        let mirror = self
+            .mirror
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|job| job.mirror.clone());
+        match mirror {
+            Some(mirror) => mirror.flush(),
+            None => Ok(()),
+        }
+    }
 }
 
 impl Drop for Block {
@@ -541,6 +1499,129 @@ impl Drop for Block {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        if let Some(job) = self.mirror.lock().unwrap().take() {
+            job.mirror.cancel();
+        }
+        if let Some(backup) = self.backup.lock().unwrap().take() {
+            backup.cancel();
+        }
+    }
+}
+
+// Copies the disk to its backup from a dedicated thread.
+struct BackupWorker {
+    backup: Arc<DiskBackup>,
+    job: Arc<Mutex<Option<Arc<DiskBackup>>>>,
+    dirty_bitmap: Option<Arc<DirtyBitmap>>,
+    // Ranges cleared from the dirty bitmap when the backup started.
+    dirty_ranges: Vec<(u64, u64)>,
+    result_tx: mpsc::Sender<io::Result<()>>,
+}
+
+impl BackupWorker {
+    fn run(self) {
+        let mut result = self.backup.copy();
+
+        let mut job = self.job.lock().unwrap();
+        if job
+            .as_ref()
+            .map_or(false, |backup| Arc::ptr_eq(backup, &self.backup))
+        {
+            job.take();
+        }
+        drop(job);
+
+        if let Some(dirty_bitmap) = &self.dirty_bitmap {
+            // The next backup has to hold what this one failed to save.
+            if result.is_err() {
+                dirty_bitmap.restore_ranges(&self.dirty_ranges);
+            }
+            if let Err(e) = dirty_bitmap.sync() {
+                result = result.and(Err(e));
+            }
+        }
+
+        let _ = self.result_tx.send(result);
+    }
+}
+
+// Copies the disk to its mirror from a dedicated thread, then either switches
+// the device over to the mirror or keeps it in sync.
+struct MirrorWorker {
+    id: String,
+    mirror: Arc<DiskMirror>,
+    source: Box<dyn AsyncIo>,
+    job: Arc<Mutex<Option<MirrorJob>>>,
+    pivots: Arc<Mutex<Vec<Arc<DiskPivot>>>>,
+    disk_image: Arc<Mutex<Box<dyn DiskFile>>>,
+    on_pivot: Box<dyn FnOnce() + Send>,
+}
+
+impl MirrorWorker {
+    // Returns whether the mirror wasn't cancelled and replaced meanwhile.
+    fn is_current(&self, job: &Option<MirrorJob>) -> bool {
+        job.as_ref()
+            .map_or(false, |job| Arc::ptr_eq(&job.mirror, &self.mirror))
+    }
+
+    fn run(mut self) {
+        let result = self.mirror.copy(self.source.as_mut());
+
+        let mut job = self.job.lock().unwrap();
+        if !self.is_current(&job) {
+            info!("Mirror of disk {} cancelled", self.id);
+            return;
+        }
+        if let Err(e) = result {
+            // Failed mirrors are kept until cancelled, for the failure to be
+            // reported.
+            error!("Failed mirroring disk {}: {}", self.id, e);
+            self.mirror.cancel();
+            event!("virtio-device", "disk-mirror-failed", "id", &self.id);
+            return;
+        }
+
+        self.mirror.set_ready();
+        let pivot_image = match job.as_mut().unwrap().pivot_image.take() {
+            Some(pivot_image) => pivot_image,
+            None => {
+                info!("Mirror of disk {} is ready", self.id);
+                event!("virtio-device", "disk-mirror-ready", "id", &self.id);
+                return;
+            }
+        };
+
+        // Devices activated from now on use the new image, while the epoll
+        // handlers running switch over once their requests in flight
+        // completed. The previous image is kept open until then.
+        let (done_tx, done_rx) = mpsc::channel();
+        let pivots = self.pivots.lock().unwrap();
+        let previous_image = std::mem::replace(&mut *self.disk_image.lock().unwrap(), pivot_image);
+        for disk_pivot in pivots.iter() {
+            *disk_pivot.done.lock().unwrap() = Some(done_tx.clone());
+            if let Err(e) = disk_pivot.evt.write(1) {
+                error!("Failed to trigger pivot event: {:?}", e);
+            }
+        }
+        drop(done_tx);
+        drop(pivots);
+        drop(job);
+
+        // The handlers drop their sender once switched over, or when they're
+        // stopped.
+        let _ = done_rx.recv();
+        drop(previous_image);
+        if self.mirror.is_cancelled() {
+            error!(
+                "Disk {} switched over to a mirror which missed some writes",
+                self.id
+            );
+        }
+
+        self.job.lock().unwrap().take();
+        (self.on_pivot)();
+        info!("Disk {} switched over to its mirror", self.id);
+        event!("virtio-device", "disk-mirror-pivoted", "id", &self.id);
     }
 }
 
@@ -595,6 +1676,16 @@ impl VirtioDevice for Block {
         let disk_image_id = build_disk_image_id(&self.disk_path);
         self.update_writeback();
 
+        // The mirror, if any, can't switch over to its destination until all
+        // the epoll handlers are set up to follow.
+        let job = self.mirror.lock().unwrap();
+        let mut pivots = self.pivots.lock().unwrap();
+        let disk_image = self.disk_image.lock().unwrap();
+        let pivot_image = job.as_ref().and_then(|job| job.pivot_image.as_ref());
+        pivots.clear();
+        self.retry_evts.clear();
+        self.rate_limiter_updates.clear();
+
         let mut epoll_threads = Vec::new();
         for i in 0..queues.len() {
             let queue_evt = queue_evts.remove(0);
@@ -602,24 +1693,52 @@ impl VirtioDevice for Block {
             let queue_size = queue.state.size;
             let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
-            let rate_limiter: Option<RateLimiter> = self
-                .rate_limiter_config
-                .map(RateLimiterConfig::try_into)
-                .transpose()
-                .map_err(ActivateError::CreateRateLimiter)?;
+            let disk_pivot = Arc::new(DiskPivot {
+                evt: EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                    error!("failed to create pivot EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?,
+                queue_size,
+                disk_image: Mutex::new(
+                    pivot_image
+                        .map(|image| image.new_async_io(queue_size as u32))
+                        .transpose()
+                        .map_err(|e| {
+                            error!("failed to create new AsyncIo: {}", e);
+                            ActivateError::BadActivate
+                        })?,
+                ),
+                done: Mutex::new(None),
+                previous_media: Mutex::new(None),
+                read_only: Mutex::new(None),
+            });
+            pivots.push(disk_pivot.clone());
+
+            let retry_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                error!("failed to create retry EventFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+            self.retry_evts.push(retry_evt.try_clone().map_err(|e| {
+                error!("failed to clone retry EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+
+            let rate_limiter =
+                queue_rate_limiter(self.rate_limiter_config, self.rate_limit_group.as_ref())
+                    .map_err(ActivateError::CreateRateLimiter)?;
+            let rate_limiter_update =
+                Arc::new(RateLimiterUpdate::new().map_err(ActivateError::CreateRateLimiter)?);
+            self.rate_limiter_updates.push(rate_limiter_update.clone());
 
             let mut handler = BlockEpollHandler {
                 queue_index: i as u16,
                 queue,
                 mem: mem.clone(),
-                disk_image: self
-                    .disk_image
-                    .new_async_io(queue_size as u32)
-                    .map_err(|e| {
-                        error!("failed to create new AsyncIo: {}", e);
-                        ActivateError::BadActivate
-                    })?,
-                disk_nsectors: self.disk_nsectors,
+                disk_image: disk_image.new_async_io(queue_size as u32).map_err(|e| {
+                    error!("failed to create new AsyncIo: {}", e);
+                    ActivateError::BadActivate
+                })?,
+                disk_nsectors: self.disk_nsectors.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 disk_image_id: disk_image_id.clone(),
                 kill_evt,
@@ -629,7 +1748,26 @@ impl VirtioDevice for Block {
                 queue_evt,
                 request_list: HashMap::with_capacity(queue_size.into()),
                 rate_limiter,
+                rate_limiter_update,
                 access_platform: self.common.access_platform.clone(),
+                dirty_bitmap: self.dirty_bitmap.clone(),
+                backup: self.backup.clone(),
+                mirror: self.mirror.clone(),
+                pivot: disk_pivot,
+                pivoting: false,
+                errors: IoErrorHandler {
+                    id: self.id.clone(),
+                    read_policy: self.read_error_policy,
+                    write_policy: self.write_error_policy,
+                    stop_evt: self.stop_evt.try_clone().map_err(|e| {
+                        error!("failed to clone stop EventFd: {}", e);
+                        ActivateError::BadActivate
+                    })?,
+                    stopped_requests: Vec::new(),
+                    stopped_count: self.stopped_requests.clone(),
+                },
+                retry_evt,
+                read_only: self.common.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0,
             };
 
             let paused = self.common.paused.clone();
@@ -657,6 +1795,10 @@ impl VirtioDevice for Block {
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         let result = self.common.reset();
+        self.pivots.lock().unwrap().clear();
+        self.retry_evts.clear();
+        self.rate_limiter_updates.clear();
+        self.stopped_requests.store(0, Ordering::Release);
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
@@ -681,6 +1823,11 @@ impl VirtioDevice for Block {
             Wrapping(self.counters.write_ops.load(Ordering::Acquire)),
         );
 
+        if let Some(job) = self.mirror.lock().unwrap().as_ref() {
+            counters.insert("mirror_copied_bytes", Wrapping(job.mirror.copied()));
+            counters.insert("mirror_total_bytes", Wrapping(job.mirror.size()));
+        }
+
         Some(counters)
     }
 
@@ -695,7 +1842,16 @@ impl Pausable for Block {
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
-        self.common.resume()
+        self.common.resume()?;
+
+        // Retry the requests stopped on error, if any.
+        for retry_evt in self.retry_evts.iter() {
+            retry_evt.write(1).map_err(|e| {
+                MigratableError::Resume(anyhow!("Failed to trigger retry event: {:?}", e))
+            })?;
+        }
+
+        Ok(())
     }
 }
 
@@ -705,6 +1861,14 @@ impl Snapshottable for Block {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        // The requests stopped on error only live in the queue handlers.
+        if self.stopped_requests.load(Ordering::Acquire) > 0 {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Requests of disk {} are stopped on error, the VM must be resumed first",
+                self.id
+            )));
+        }
+
         Snapshot::new_from_versioned_state(&self.id(), &self.state())
     }
 
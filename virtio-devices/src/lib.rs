@@ -15,9 +15,12 @@ extern crate event_monitor;
 #[macro_use]
 extern crate log;
 
+use rate_limiter::group::{QueueRateLimiter, RateLimiterGroup};
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::io;
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
 
 #[macro_use]
 mod device;
@@ -30,6 +33,7 @@ pub mod mem;
 pub mod net;
 mod pmem;
 mod rng;
+pub mod scsi;
 pub mod seccomp_filters;
 mod thread_helper;
 pub mod transport;
@@ -48,6 +52,7 @@ pub use self::mem::*;
 pub use self::net::*;
 pub use self::pmem::*;
 pub use self::rng::*;
+pub use self::scsi::*;
 pub use self::vdpa::*;
 pub use self::vsock::*;
 pub use self::watchdog::*;
@@ -154,6 +159,93 @@ impl TryInto<rate_limiter::RateLimiter> for RateLimiterConfig {
     }
 }
 
+impl RateLimiterConfig {
+    /// Creates the rate limit group `id` enforcing this configuration.
+    pub fn new_group(&self, id: &str) -> io::Result<rate_limiter::group::RateLimiterGroup> {
+        let bw = self.bandwidth.unwrap_or_default();
+        let ops = self.ops.unwrap_or_default();
+        rate_limiter::group::RateLimiterGroup::new(
+            id,
+            bw.size,
+            bw.one_time_burst.unwrap_or(0),
+            bw.refill_time,
+            ops.size,
+            ops.one_time_burst.unwrap_or(0),
+            ops.refill_time,
+        )
+    }
+
+    /// Replaces the token buckets of the rate limit group `group` with the
+    /// ones of this configuration.
+    pub fn update_group(&self, group: &rate_limiter::group::RateLimiterGroup) {
+        group.update_buckets(bucket_update(self.bandwidth), bucket_update(self.ops))
+    }
+}
+
+fn bucket_update(tb_config: Option<TokenBucketConfig>) -> rate_limiter::BucketUpdate {
+    tb_config
+        .and_then(|tb| {
+            rate_limiter::TokenBucket::new(tb.size, tb.one_time_burst.unwrap_or(0), tb.refill_time)
+        })
+        .map_or(
+            rate_limiter::BucketUpdate::Disabled,
+            rate_limiter::BucketUpdate::Update,
+        )
+}
+
+// Creates the rate limiter of a queue, drawing from the rate limit group of
+// the device if any, or enforcing the limits of the device on its own.
+fn queue_rate_limiter(
+    rate_limiter_config: Option<RateLimiterConfig>,
+    rate_limit_group: Option<&Arc<RateLimiterGroup>>,
+) -> io::Result<Option<QueueRateLimiter>> {
+    if let Some(rate_limit_group) = rate_limit_group {
+        return rate_limit_group
+            .new_handle()
+            .map(|handle| Some(QueueRateLimiter::Group(handle)));
+    }
+
+    own_rate_limiter(rate_limiter_config)
+        .map(|rate_limiter| rate_limiter.map(QueueRateLimiter::Own))
+}
+
+// Creates a rate limiter enforcing `rate_limiter_config` on its own, if any.
+fn own_rate_limiter(
+    rate_limiter_config: Option<RateLimiterConfig>,
+) -> io::Result<Option<rate_limiter::RateLimiter>> {
+    rate_limiter_config
+        .map(RateLimiterConfig::try_into)
+        .transpose()
+}
+
+// Rate limiters handed over to the epoll handler of a queue when the limits of
+// the device are updated at runtime. They're created by the VMM thread, as the
+// queue threads aren't allowed to create timerfds.
+struct RateLimiterUpdate<T> {
+    evt: EventFd,
+    rate_limiters: Mutex<Option<T>>,
+}
+
+impl<T> RateLimiterUpdate<T> {
+    fn new() -> io::Result<Self> {
+        Ok(RateLimiterUpdate {
+            evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            rate_limiters: Mutex::new(None),
+        })
+    }
+
+    // Only the latest rate limiters are kept if the epoll handler has yet to
+    // pick up the previous ones.
+    fn hand_over(&self, rate_limiters: T) -> io::Result<()> {
+        *self.rate_limiters.lock().unwrap() = Some(rate_limiters);
+        self.evt.write(1)
+    }
+
+    fn take(&self) -> Option<T> {
+        self.rate_limiters.lock().unwrap().take()
+    }
+}
+
 /// Convert an absolute address into an address space (GuestMemory)
 /// to a host pointer and verify that the provided size define a valid
 /// range within a single memory region.
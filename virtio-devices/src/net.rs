@@ -7,9 +7,9 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler,
-    RateLimiterConfig, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
-    EPOLL_HELPER_EVENT_LAST,
+    own_rate_limiter, queue_rate_limiter, ActivateError, ActivateResult, EpollHelper,
+    EpollHelperError, EpollHelperHandler, RateLimiterConfig, RateLimiterUpdate, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
@@ -18,19 +18,23 @@ use crate::VirtioInterrupt;
 use net_util::CtrlQueue;
 use net_util::{
     build_net_config_space, build_net_config_space_with_mq, open_tap,
-    virtio_features_to_tap_offload, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio,
-    Tap, TapError, TxVirtio, VirtioNetConfig,
+    virtio_features_to_tap_offload, CaptureConfig, L2Socket, L2SocketConfig, L2SocketError,
+    MacAddr, NetCounters, NetQueuePair, OpenTapError, PacketCapture, RxFilter, RxFilterState,
+    RxVirtio, Tap, TapError, TxVirtio, UserNet, UserNetConfig, UserNetError, VirtioNetConfig,
 };
+use rate_limiter::group::{QueueRateLimiter, RateLimiterGroup};
+use rate_limiter::RateLimiter;
 use seccompiler::SeccompAction;
+use std::collections::HashMap;
+use std::io;
 use std::net::Ipv4Addr;
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use std::vec::Vec;
-use std::{collections::HashMap, convert::TryInto};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_bindings::bindings::virtio_net::*;
@@ -134,6 +138,10 @@ pub const TX_TAP_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 pub const RX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
 // New 'wake up' event from the tx rate limiter
 pub const TX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// The user-mode network stack has events to process.
+pub const USER_NET_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
+// The limits of the device were updated at runtime.
+pub const RATE_LIMITER_UPDATE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 8;
 
 #[derive(Debug)]
 pub enum Error {
@@ -145,6 +153,15 @@ pub enum Error {
 
     // Error calling dup() on tap fd
     DuplicateTapFd(std::io::Error),
+
+    /// Failed to create the user-mode network stack.
+    UserNet(UserNetError),
+
+    /// Failed to open the socket to the peer.
+    L2Socket(L2SocketError),
+
+    /// Failed to duplicate the socket to the peer.
+    DuplicateL2Socket(std::io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -157,6 +174,10 @@ struct NetEpollHandler {
     queue_index_base: u16,
     queue_pair: Vec<Queue<GuestMemoryAtomic<GuestMemoryMmap>>>,
     queue_evt_pair: Vec<EventFd>,
+    // RX and TX rate limiters handed over when the limits are updated
+    rate_limiter_update: Arc<RateLimiterUpdate<(Option<RateLimiter>, Option<RateLimiter>)>>,
+    // User-mode network stack standing behind the tap, if any
+    user_net: Option<Arc<Mutex<UserNet>>>,
     // Always generate interrupts until the driver has signalled to the device.
     // This mitigates a problem with interrupts from tap events being "lost" upon
     // a restore as the vCPU thread isn't ready to handle the interrupt. This causes
@@ -247,6 +268,75 @@ impl NetEpollHandler {
         Ok(())
     }
 
+    // Replaces the rate limiter of a queue, moving the epoll registration
+    // `event` over to the new one.
+    fn replace_rate_limiter(
+        helper: &mut EpollHelper,
+        rate_limiter: &mut Option<QueueRateLimiter>,
+        new_rate_limiter: Option<RateLimiter>,
+        event: u16,
+    ) -> result::Result<(), EpollHelperError> {
+        if let Some(previous) = rate_limiter.as_ref() {
+            helper.del_event_custom(previous.as_raw_fd(), event, epoll::Events::EPOLLIN)?;
+        }
+        *rate_limiter = new_rate_limiter.map(QueueRateLimiter::Own);
+        if let Some(rate_limiter) = rate_limiter.as_ref() {
+            helper.add_event(rate_limiter.as_raw_fd(), event)?;
+        }
+
+        Ok(())
+    }
+
+    // Replaces the RX and TX rate limiters with the ones handed over by the
+    // device, if any, and resumes the traffic held back by the previous
+    // limits. Returns false on error.
+    fn update_rate_limiters(&mut self, helper: &mut EpollHelper) -> bool {
+        let (rx_rate_limiter, tx_rate_limiter) = match self.rate_limiter_update.take() {
+            Some(rate_limiters) => rate_limiters,
+            None => return true,
+        };
+
+        if let Err(e) = Self::replace_rate_limiter(
+            helper,
+            &mut self.net.rx_rate_limiter,
+            rx_rate_limiter,
+            RX_RATE_LIMITER_EVENT,
+        )
+        .and_then(|_| {
+            Self::replace_rate_limiter(
+                helper,
+                &mut self.net.tx_rate_limiter,
+                tx_rate_limiter,
+                TX_RATE_LIMITER_EVENT,
+            )
+        }) {
+            error!("Failed replacing the rate limiters: {:?}", e);
+            return false;
+        }
+
+        if !self.net.rx_tap_listening && self.net.rx_desc_avail {
+            if let Err(e) = net_util::register_listener(
+                self.net.epoll_fd.unwrap(),
+                self.net.tap.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                u64::from(self.net.tap_rx_event_id),
+            ) {
+                error!(
+                    "Error register_listener with `RATE_LIMITER_UPDATE_EVENT`: {:?}",
+                    e
+                );
+                return false;
+            }
+            self.net.rx_tap_listening = true;
+        }
+        if let Err(e) = self.handle_tx_event() {
+            error!("Error processing TX queue: {:?}", e);
+            return false;
+        }
+
+        true
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -261,6 +351,13 @@ impl NetEpollHandler {
         if let Some(rate_limiter) = &self.net.tx_rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), TX_RATE_LIMITER_EVENT)?;
         }
+        helper.add_event(
+            self.rate_limiter_update.evt.as_raw_fd(),
+            RATE_LIMITER_UPDATE_EVENT,
+        )?;
+        if let Some(user_net) = &self.user_net {
+            helper.add_event(user_net.lock().unwrap().as_raw_fd(), USER_NET_EVENT)?;
+        }
 
         // If there are some already available descriptors on the RX queue,
         // then we can start the thread while listening onto the TAP.
@@ -286,7 +383,7 @@ impl NetEpollHandler {
 }
 
 impl EpollHelperHandler for NetEpollHandler {
-    fn handle_event(&mut self, _helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+    fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool {
         let ev_type = event.data as u16;
         match ev_type {
             RX_QUEUE_EVENT => {
@@ -370,6 +467,26 @@ impl EpollHelperHandler for NetEpollHandler {
                     return true;
                 }
             }
+            RATE_LIMITER_UPDATE_EVENT => {
+                if let Err(e) = self.rate_limiter_update.evt.read() {
+                    error!("Failed to get rate limiter update event: {:?}", e);
+                    return true;
+                }
+                if !self.update_rate_limiters(helper) {
+                    return true;
+                }
+            }
+            USER_NET_EVENT => {
+                if let Some(user_net) = &self.user_net {
+                    if let Err(e) = user_net.lock().unwrap().process() {
+                        error!("Error processing user-mode network: {:?}", e);
+                        return true;
+                    }
+                } else {
+                    error!("Unexpected USER_NET_EVENT");
+                    return true;
+                }
+            }
             _ => {
                 error!("Unknown event: {}", ev_type);
                 return true;
@@ -388,6 +505,17 @@ pub struct Net {
     counters: NetCounters,
     seccomp_action: SeccompAction,
     rate_limiter_config: Option<RateLimiterConfig>,
+    rate_limit_group: Option<Arc<RateLimiterGroup>>,
+    rate_limiter_updates: Vec<Arc<RateLimiterUpdate<(Option<RateLimiter>, Option<RateLimiter>)>>>,
+    rx_filter: Arc<RxFilter>,
+    // Status field of the configuration, shared with the control queue
+    status: Arc<AtomicU16>,
+    // User-mode network stack replacing the tap, if any
+    user_net: Option<Arc<Mutex<UserNet>>>,
+    // Socket to the peer replacing the tap, if any
+    l2_socket: Option<Arc<Mutex<L2Socket>>>,
+    // Capture of the frames, shared with the queue pairs
+    capture: Arc<PacketCapture>,
     exit_evt: EventFd,
 }
 
@@ -397,6 +525,7 @@ pub struct NetState {
     pub acked_features: u64,
     pub config: VirtioNetConfig,
     pub queue_size: Vec<u16>,
+    pub rx_filter: RxFilterState,
 }
 
 impl VersionMapped for NetState {}
@@ -413,10 +542,14 @@ impl Net {
         queue_size: u16,
         seccomp_action: SeccompAction,
         rate_limiter_config: Option<RateLimiterConfig>,
+        rate_limit_group: Option<Arc<RateLimiterGroup>>,
         exit_evt: EventFd,
     ) -> Result<Self> {
         let mut avail_features = 1 << VIRTIO_NET_F_CSUM
             | 1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
+            | 1 << VIRTIO_NET_F_CTRL_MAC_ADDR
+            | 1 << VIRTIO_NET_F_CTRL_RX
+            | 1 << VIRTIO_NET_F_CTRL_VLAN
             | 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_GUEST_ECN
             | 1 << VIRTIO_NET_F_GUEST_TSO4
@@ -433,7 +566,8 @@ impl Net {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
-        avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
+        avail_features |=
+            1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE | 1 << VIRTIO_NET_F_STATUS;
         let queue_num = num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
@@ -443,6 +577,8 @@ impl Net {
             build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
         }
 
+        let capture = Arc::new(PacketCapture::new(&id));
+
         Ok(Net {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::Net as u32,
@@ -459,6 +595,13 @@ impl Net {
             counters: NetCounters::default(),
             seccomp_action,
             rate_limiter_config,
+            rate_limit_group,
+            rate_limiter_updates: Vec::new(),
+            rx_filter: Arc::new(RxFilter::new(guest_mac)),
+            status: Arc::new(AtomicU16::new(VIRTIO_NET_S_LINK_UP as u16)),
+            user_net: None,
+            l2_socket: None,
+            capture,
             exit_evt,
         })
     }
@@ -478,6 +621,7 @@ impl Net {
         queue_size: u16,
         seccomp_action: SeccompAction,
         rate_limiter_config: Option<RateLimiterConfig>,
+        rate_limit_group: Option<Arc<RateLimiterGroup>>,
         exit_evt: EventFd,
     ) -> Result<Self> {
         let taps = open_tap(if_name, ip_addr, netmask, host_mac, num_queues / 2, None)
@@ -492,6 +636,7 @@ impl Net {
             queue_size,
             seccomp_action,
             rate_limiter_config,
+            rate_limit_group,
             exit_evt,
         )
     }
@@ -505,6 +650,7 @@ impl Net {
         queue_size: u16,
         seccomp_action: SeccompAction,
         rate_limiter_config: Option<RateLimiterConfig>,
+        rate_limit_group: Option<Arc<RateLimiterGroup>>,
         exit_evt: EventFd,
     ) -> Result<Self> {
         let mut taps: Vec<Tap> = Vec::new();
@@ -530,16 +676,111 @@ impl Net {
             queue_size,
             seccomp_action,
             rate_limiter_config,
+            rate_limit_group,
             exit_evt,
         )
     }
 
+    /// Create a new virtio network device backed by a user-mode network
+    /// stack, with a single queue pair. The stack produces no segmentation
+    /// offloaded frames, hence the offloads towards the guest are not
+    /// offered.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_user_net(
+        id: String,
+        config: &UserNetConfig,
+        guest_mac: MacAddr,
+        iommu: bool,
+        queue_size: u16,
+        seccomp_action: SeccompAction,
+        rate_limiter_config: Option<RateLimiterConfig>,
+        rate_limit_group: Option<Arc<RateLimiterGroup>>,
+        exit_evt: EventFd,
+    ) -> Result<Self> {
+        let (user_net, tap) = UserNet::new(config, guest_mac).map_err(Error::UserNet)?;
+
+        let mut net = Self::new_with_tap(
+            id,
+            vec![tap],
+            Some(guest_mac),
+            iommu,
+            2,
+            queue_size,
+            seccomp_action,
+            rate_limiter_config,
+            rate_limit_group,
+            exit_evt,
+        )?;
+        net.common.avail_features &= !(1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
+            | 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_ECN
+            | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
+            | 1 << VIRTIO_NET_F_GUEST_UFO);
+        net.user_net = Some(Arc::new(Mutex::new(user_net)));
+
+        Ok(net)
+    }
+
+    /// Create a new virtio network device exchanging its frames with a peer
+    /// over a socket, with a single queue pair. The virtio-net header of the
+    /// frames isn't carried, hence no offload is offered.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_l2_socket(
+        id: String,
+        config: &L2SocketConfig,
+        guest_mac: Option<MacAddr>,
+        iommu: bool,
+        queue_size: u16,
+        seccomp_action: SeccompAction,
+        rate_limiter_config: Option<RateLimiterConfig>,
+        rate_limit_group: Option<Arc<RateLimiterGroup>>,
+        exit_evt: EventFd,
+    ) -> Result<Self> {
+        let l2_socket = L2Socket::open(config).map_err(Error::L2Socket)?;
+        // The tap stands for the socket when polling it.
+        let tap = l2_socket
+            .try_clone_tap()
+            .map_err(Error::DuplicateL2Socket)?;
+
+        let mut net = Self::new_with_tap(
+            id,
+            vec![tap],
+            guest_mac,
+            iommu,
+            2,
+            queue_size,
+            seccomp_action,
+            rate_limiter_config,
+            rate_limit_group,
+            exit_evt,
+        )?;
+        net.common.avail_features &= !(1 << VIRTIO_NET_F_CSUM
+            | 1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
+            | 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_ECN
+            | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
+            | 1 << VIRTIO_NET_F_GUEST_UFO
+            | 1 << VIRTIO_NET_F_HOST_ECN
+            | 1 << VIRTIO_NET_F_HOST_TSO4
+            | 1 << VIRTIO_NET_F_HOST_TSO6
+            | 1 << VIRTIO_NET_F_HOST_UFO);
+        net.l2_socket = Some(Arc::new(Mutex::new(l2_socket)));
+
+        Ok(net)
+    }
+
     fn state(&self) -> NetState {
+        let mut config = self.config;
+        config.status = self.status.load(Ordering::Acquire);
+
         NetState {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
-            config: self.config,
+            config,
             queue_size: self.common.queue_sizes.clone(),
+            rx_filter: self.rx_filter.state(),
         }
     }
 
@@ -547,7 +788,103 @@ impl Net {
         self.common.avail_features = state.avail_features;
         self.common.acked_features = state.acked_features;
         self.config = state.config;
+        self.status.store(state.config.status, Ordering::Release);
         self.common.queue_sizes = state.queue_size.clone();
+        self.rx_filter.set_state(&state.rx_filter);
+    }
+
+    /// Sets the link of the device up or down, as if the cable was plugged
+    /// or pulled, notifying the guest.
+    pub fn set_link(&mut self, up: bool) -> io::Result<()> {
+        if up {
+            self.status
+                .fetch_or(VIRTIO_NET_S_LINK_UP as u16, Ordering::AcqRel);
+        } else {
+            self.status
+                .fetch_and(!(VIRTIO_NET_S_LINK_UP as u16), Ordering::AcqRel);
+        }
+
+        // Until the device is activated, the guest has yet to read the status.
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb.trigger(VirtioInterruptType::Config)?;
+        }
+        event!(
+            "virtio-device",
+            "link-changed",
+            "id",
+            &self.id,
+            "up",
+            up.to_string()
+        );
+
+        Ok(())
+    }
+
+    /// Replaces the limits enforced on each queue of the device, or removes
+    /// them when `rate_limiter_config` is `None`. Devices drawing from a rate
+    /// limit group follow the limits of the group instead.
+    pub fn update_rate_limiter(
+        &mut self,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> io::Result<()> {
+        if self.rate_limit_group.is_none() {
+            for rate_limiter_update in self.rate_limiter_updates.iter() {
+                rate_limiter_update.hand_over((
+                    own_rate_limiter(rate_limiter_config)?,
+                    own_rate_limiter(rate_limiter_config)?,
+                ))?;
+            }
+        }
+        self.rate_limiter_config = rate_limiter_config;
+
+        Ok(())
+    }
+
+    /// Starts capturing the frames of the device to a pcapng file, in place
+    /// of any capture in progress.
+    pub fn start_capture(&mut self, config: &CaptureConfig) -> io::Result<()> {
+        self.capture.start(config)?;
+        event!(
+            "virtio-device",
+            "capture-started",
+            "id",
+            &self.id,
+            "path",
+            config.path.to_string_lossy()
+        );
+
+        Ok(())
+    }
+
+    /// Stops capturing the frames of the device, if they were.
+    pub fn stop_capture(&mut self) {
+        if self.capture.path().is_some() {
+            self.capture.stop();
+            event!("virtio-device", "capture-stopped", "id", &self.id);
+        }
+    }
+
+    /// Requests the guest to announce itself on the network, by sending
+    /// gratuitous ARPs, such as after it was migrated. Guests which didn't
+    /// negotiate the announce feature are left alone.
+    pub fn announce(&mut self) -> io::Result<()> {
+        let interrupt_cb = match &self.common.interrupt_cb {
+            Some(interrupt_cb)
+                if self
+                    .common
+                    .feature_acked(VIRTIO_NET_F_GUEST_ANNOUNCE.into()) =>
+            {
+                interrupt_cb
+            }
+            _ => return Ok(()),
+        };
+
+        self.status
+            .fetch_or(VIRTIO_NET_S_ANNOUNCE as u16, Ordering::AcqRel);
+        interrupt_cb.trigger(VirtioInterruptType::Config)?;
+        event!("virtio-device", "announce", "id", &self.id);
+
+        Ok(())
     }
 }
 
@@ -578,7 +915,14 @@ impl VirtioDevice for Net {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+        // The guest may have changed the MAC address through the control
+        // queue.
+        let mut config = self.config;
+        if let Some(mac) = self.rx_filter.mac() {
+            config.mac.copy_from_slice(mac.get_bytes());
+        }
+        config.status = self.status.load(Ordering::Acquire);
+        self.read_config_from_slice(config.as_slice(), offset, data);
     }
 
     fn activate(
@@ -592,6 +936,8 @@ impl VirtioDevice for Net {
 
         let num_queues = queues.len();
         let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
+        self.rx_filter
+            .set_vlan_filtering(self.common.feature_acked(VIRTIO_NET_F_CTRL_VLAN.into()));
         if self.common.feature_acked(VIRTIO_NET_F_CTRL_VQ.into()) && num_queues % 2 != 0 {
             let ctrl_queue_index = num_queues - 1;
             let mut ctrl_queue = queues.remove(ctrl_queue_index);
@@ -603,7 +949,26 @@ impl VirtioDevice for Net {
             let mut ctrl_handler = NetCtrlEpollHandler {
                 kill_evt,
                 pause_evt,
-                ctrl_q: CtrlQueue::new(self.taps.clone()),
+                // The offloads of the user-mode network stack or of the
+                // socket can't change.
+                ctrl_q: CtrlQueue::new(
+                    if self.user_net.is_some() || self.l2_socket.is_some() {
+                        Vec::new()
+                    } else {
+                        self.taps.clone()
+                    },
+                    Some(self.rx_filter.clone()),
+                    // Only guests which negotiated the announce feature can
+                    // acknowledge one.
+                    if self
+                        .common
+                        .feature_acked(VIRTIO_NET_F_GUEST_ANNOUNCE.into())
+                    {
+                        Some(self.status.clone())
+                    } else {
+                        None
+                    },
+                ),
                 queue: ctrl_queue,
                 queue_evt: ctrl_queue_evt,
                 access_platform: self.common.access_platform.clone(),
@@ -636,6 +1001,7 @@ impl VirtioDevice for Net {
 
         let mut epoll_threads = Vec::new();
         let mut taps = self.taps.clone();
+        self.rate_limiter_updates.clear();
         for i in 0..queues.len() / 2 {
             let rx = RxVirtio::new();
             let tx = TxVirtio::new();
@@ -649,24 +1015,26 @@ impl VirtioDevice for Net {
 
             let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
-            let rx_rate_limiter: Option<rate_limiter::RateLimiter> = self
-                .rate_limiter_config
-                .map(RateLimiterConfig::try_into)
-                .transpose()
-                .map_err(ActivateError::CreateRateLimiter)?;
-
-            let tx_rate_limiter: Option<rate_limiter::RateLimiter> = self
-                .rate_limiter_config
-                .map(RateLimiterConfig::try_into)
-                .transpose()
-                .map_err(ActivateError::CreateRateLimiter)?;
+            // Unless the device is part of a rate limit group, the RX and TX
+            // queues of each pair are throttled independently.
+            let rx_rate_limiter =
+                queue_rate_limiter(self.rate_limiter_config, self.rate_limit_group.as_ref())
+                    .map_err(ActivateError::CreateRateLimiter)?;
+            let tx_rate_limiter =
+                queue_rate_limiter(self.rate_limiter_config, self.rate_limit_group.as_ref())
+                    .map_err(ActivateError::CreateRateLimiter)?;
+            let rate_limiter_update =
+                Arc::new(RateLimiterUpdate::new().map_err(ActivateError::CreateRateLimiter)?);
+            self.rate_limiter_updates.push(rate_limiter_update.clone());
 
             let tap = taps.remove(0);
-            tap.set_offload(virtio_features_to_tap_offload(self.common.acked_features))
-                .map_err(|e| {
-                    error!("Error programming tap offload: {:?}", e);
-                    ActivateError::BadActivate
-                })?;
+            if self.user_net.is_none() && self.l2_socket.is_none() {
+                tap.set_offload(virtio_features_to_tap_offload(self.common.acked_features))
+                    .map_err(|e| {
+                        error!("Error programming tap offload: {:?}", e);
+                        ActivateError::BadActivate
+                    })?;
+            }
 
             let mut handler = NetEpollHandler {
                 net: NetQueuePair {
@@ -683,11 +1051,17 @@ impl VirtioDevice for Net {
                     rx_desc_avail: false,
                     rx_rate_limiter,
                     tx_rate_limiter,
+                    rx_filter: Some(self.rx_filter.clone()),
+                    l2_socket: self.l2_socket.clone(),
+                    capture: Some(self.capture.clone()),
+                    status: Some(self.status.clone()),
                     access_platform: self.common.access_platform.clone(),
                 },
                 queue_index_base: (i * 2) as u16,
                 queue_pair,
                 queue_evt_pair,
+                rate_limiter_update,
+                user_net: self.user_net.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 kill_evt,
                 pause_evt,
@@ -700,7 +1074,11 @@ impl VirtioDevice for Net {
             spawn_virtio_thread(
                 &format!("{}_qp{}", self.id.clone(), i),
                 &self.seccomp_action,
-                Thread::VirtioNet,
+                if self.user_net.is_some() {
+                    Thread::VirtioNetUser
+                } else {
+                    Thread::VirtioNet
+                },
                 &mut epoll_threads,
                 &self.exit_evt,
                 move || {
@@ -719,6 +1097,15 @@ impl VirtioDevice for Net {
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         let result = self.common.reset();
+        self.rate_limiter_updates.clear();
+        self.rx_filter.reset();
+        // The link state is up to the host, only a pending announce is
+        // dropped.
+        self.status
+            .fetch_and(!(VIRTIO_NET_S_ANNOUNCE as u16), Ordering::AcqRel);
+        if let Some(user_net) = &self.user_net {
+            user_net.lock().unwrap().reset();
+        }
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
@@ -782,3 +1169,83 @@ impl Snapshottable for Net {
 }
 impl Transportable for Net {}
 impl Migratable for Net {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::EFD_NONBLOCK;
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Default)]
+    struct CountingInterrupt {
+        config: AtomicUsize,
+    }
+
+    impl VirtioInterrupt for CountingInterrupt {
+        fn trigger(
+            &self,
+            int_type: VirtioInterruptType,
+        ) -> std::result::Result<(), std::io::Error> {
+            if let VirtioInterruptType::Config = int_type {
+                self.config.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    }
+
+    fn create_net() -> Net {
+        Net::new_with_tap(
+            "net0".to_string(),
+            Vec::new(),
+            None,
+            false,
+            2,
+            256,
+            SeccompAction::Allow,
+            None,
+            None,
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn link_up(net: &Net) -> bool {
+        net.status.load(Ordering::Acquire) & VIRTIO_NET_S_LINK_UP as u16 != 0
+    }
+
+    #[test]
+    fn test_set_link_status() {
+        let mut net = create_net();
+        assert!(link_up(&net));
+
+        // Not activated yet, only the status changes.
+        net.set_link(false).unwrap();
+        assert!(!link_up(&net));
+        net.set_link(true).unwrap();
+        assert!(link_up(&net));
+
+        // Other status bits are left untouched.
+        net.status
+            .fetch_or(VIRTIO_NET_S_ANNOUNCE as u16, Ordering::AcqRel);
+        net.set_link(false).unwrap();
+        assert_eq!(
+            net.status.load(Ordering::Acquire),
+            VIRTIO_NET_S_ANNOUNCE as u16
+        );
+    }
+
+    #[test]
+    fn test_set_link_config_interrupt() {
+        let mut net = create_net();
+        let interrupt = Arc::new(CountingInterrupt::default());
+        net.common.interrupt_cb = Some(interrupt.clone());
+
+        net.set_link(false).unwrap();
+        assert!(!link_up(&net));
+        assert_eq!(interrupt.config.load(Ordering::SeqCst), 1);
+
+        net.set_link(true).unwrap();
+        assert!(link_up(&net));
+        assert_eq!(interrupt.config.load(Ordering::SeqCst), 2);
+    }
+}
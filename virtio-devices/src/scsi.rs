@@ -0,0 +1,1331 @@
+// Copyright © 2022 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::GuestMemoryMmap;
+use crate::VirtioInterrupt;
+use block_util::scsi::{execute_target_command, ScsiCompletion, ScsiLun, ScsiLunIo};
+use block_util::scsi_reservations::PersistentReservationsState;
+use seccompiler::SeccompAction;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io;
+use std::num::Wrapping;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use virtio_queue::{DescriptorChain, Queue};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
+    GuestMemoryError, GuestMemoryLoadGuard,
+};
+use vm_migration::VersionMapped;
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::{AccessPlatform, Translatable};
+use vmm_sys_util::eventfd::EventFd;
+
+// The control and event queues come before the request queues.
+const CONTROL_QUEUE: usize = 0;
+const EVENT_QUEUE: usize = 1;
+const REQUEST_QUEUES_OFFSET: usize = 2;
+
+// New descriptors are pending on the virtio queue.
+const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// Logical units were added to or removed from the controller.
+const LUN_UPDATE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// New buffers are available on the event queue.
+const EVENT_QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// Events are pending for the driver.
+const PENDING_EVENTS_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// Commands completed on a logical unit, the slot of the logical unit being
+// added to the base.
+const LUN_COMPLETION_EVENT_BASE: u16 = EPOLL_HELPER_EVENT_LAST + 16;
+
+// Feature bits
+const VIRTIO_SCSI_F_HOTPLUG: u64 = 1;
+
+const VIRTIO_SCSI_CDB_DEFAULT_SIZE: u32 = 32;
+const VIRTIO_SCSI_SENSE_DEFAULT_SIZE: u32 = 96;
+// Largest CDB and sense sizes the driver can set.
+const VIRTIO_SCSI_CDB_MAX_SIZE: u32 = 255;
+const VIRTIO_SCSI_SENSE_MAX_SIZE: u32 = 255;
+
+const MAX_TARGET: u16 = 255;
+const MAX_LUN: u32 = 16383;
+const MAX_SECTORS: u32 = 0xffff;
+const SEG_MAX: u32 = 254;
+
+// Size of the request header before the CDB, and of the response before the
+// sense data.
+const REQUEST_HEADER_SIZE: usize = 19;
+const RESPONSE_HEADER_SIZE: usize = 12;
+
+// Response codes
+const VIRTIO_SCSI_S_OK: u8 = 0;
+const VIRTIO_SCSI_S_OVERRUN: u8 = 1;
+const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
+const VIRTIO_SCSI_S_FUNCTION_COMPLETE: u8 = 0;
+const VIRTIO_SCSI_S_FUNCTION_REJECTED: u8 = 11;
+
+// Control request types
+const VIRTIO_SCSI_T_TMF: u32 = 0;
+const VIRTIO_SCSI_T_AN_QUERY: u32 = 1;
+const VIRTIO_SCSI_T_AN_SUBSCRIBE: u32 = 2;
+
+// Task management functions
+const VIRTIO_SCSI_T_TMF_ABORT_TASK: u32 = 0;
+const VIRTIO_SCSI_T_TMF_ABORT_TASK_SET: u32 = 1;
+const VIRTIO_SCSI_T_TMF_CLEAR_TASK_SET: u32 = 3;
+const VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET: u32 = 4;
+const VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET: u32 = 5;
+const VIRTIO_SCSI_T_TMF_QUERY_TASK: u32 = 6;
+const VIRTIO_SCSI_T_TMF_QUERY_TASK_SET: u32 = 7;
+
+// Events
+const VIRTIO_SCSI_T_TRANSPORT_RESET: u32 = 1;
+const VIRTIO_SCSI_T_EVENTS_MISSED: u32 = 0x8000_0000;
+const VIRTIO_SCSI_EVT_RESET_RESCAN: u32 = 1;
+const VIRTIO_SCSI_EVT_RESET_REMOVED: u32 = 2;
+
+// Events kept while the driver doesn't provide buffers for them, the driver
+// being told some were missed beyond.
+const MAX_PENDING_EVENTS: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed accessing guest memory.
+    GuestMemory(GuestMemoryError),
+    /// Failed adding used index
+    QueueAddUsed(virtio_queue::Error),
+    /// Failed creating an iterator over the queue
+    QueueIterator(virtio_queue::Error),
+    /// Failed registering the completion notifier of a logical unit.
+    RegisterLun(EpollHelperError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Copy, Clone, Debug, Default, Versionize)]
+#[repr(C, packed)]
+pub struct VirtioScsiConfig {
+    pub num_queues: u32,
+    pub seg_max: u32,
+    pub max_sectors: u32,
+    pub cmd_per_lun: u32,
+    pub event_info_size: u32,
+    pub sense_size: u32,
+    pub cdb_size: u32,
+    pub max_channel: u16,
+    pub max_target: u16,
+    pub max_lun: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioScsiConfig {}
+
+#[derive(Default, Clone)]
+pub struct ScsiCounters {
+    read_bytes: Arc<AtomicU64>,
+    read_ops: Arc<AtomicU64>,
+    write_bytes: Arc<AtomicU64>,
+    write_ops: Arc<AtomicU64>,
+}
+
+// Returns the address of a logical unit, as found in the requests.
+fn lun_address(target: u8, lun: u16) -> [u8; 8] {
+    // Peripheral addressing below 256, flat space above.
+    let (high, low) = if lun < 256 {
+        (0, lun as u8)
+    } else {
+        (0x40 | (lun >> 8) as u8, lun as u8)
+    };
+    [1, target, high, low, 0, 0, 0, 0]
+}
+
+// Returns the target and logical unit addressed by a request, if valid.
+fn parse_lun_address(address: &[u8]) -> Option<(u8, u16)> {
+    if address[0] != 1 {
+        return None;
+    }
+    Some((
+        address[1],
+        (((address[2] & 0x3f) as u16) << 8) | address[3] as u16,
+    ))
+}
+
+// Guest buffers of a request, either read or written by the device.
+type Buffers = Vec<(GuestAddress, u32)>;
+
+fn buffers_len(buffers: &[(GuestAddress, u32)]) -> u64 {
+    buffers.iter().map(|(_, len)| *len as u64).sum()
+}
+
+// Splits the buffers after `len` bytes.
+fn split_buffers(buffers: &[(GuestAddress, u32)], mut len: u64) -> (Buffers, Buffers) {
+    let mut head = Vec::new();
+    let mut tail = Vec::new();
+    for (addr, buffer_len) in buffers.iter().copied() {
+        if len >= buffer_len as u64 {
+            head.push((addr, buffer_len));
+            len -= buffer_len as u64;
+        } else if len > 0 {
+            head.push((addr, len as u32));
+            tail.push((addr.unchecked_add(len), buffer_len - len as u32));
+            len = 0;
+        } else {
+            tail.push((addr, buffer_len));
+        }
+    }
+    (head, tail)
+}
+
+fn read_buffers(mem: &GuestMemoryMmap, buffers: &[(GuestAddress, u32)]) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; buffers_len(buffers) as usize];
+    let mut offset = 0;
+    for (addr, len) in buffers {
+        mem.read_slice(&mut data[offset..offset + *len as usize], *addr)
+            .map_err(Error::GuestMemory)?;
+        offset += *len as usize;
+    }
+    Ok(data)
+}
+
+// Writes `data` to the buffers, truncated to their size.
+fn write_buffers(
+    mem: &GuestMemoryMmap,
+    buffers: &[(GuestAddress, u32)],
+    mut data: &[u8],
+) -> Result<()> {
+    for (addr, len) in buffers {
+        if data.is_empty() {
+            break;
+        }
+        let count = std::cmp::min(*len as usize, data.len());
+        mem.write_slice(&data[..count], *addr)
+            .map_err(Error::GuestMemory)?;
+        data = &data[count..];
+    }
+    Ok(())
+}
+
+fn host_iovecs(mem: &GuestMemoryMmap, buffers: &[(GuestAddress, u32)]) -> Result<Vec<libc::iovec>> {
+    buffers
+        .iter()
+        .map(|(addr, len)| {
+            let slice = mem
+                .get_slice(*addr, *len as usize)
+                .map_err(Error::GuestMemory)?;
+            Ok(libc::iovec {
+                iov_base: slice.as_ptr() as *mut libc::c_void,
+                iov_len: *len as usize,
+            })
+        })
+        .collect()
+}
+
+// Returns the buffers of a descriptor chain, the ones the device reads and
+// the ones it writes.
+fn chain_buffers(
+    desc_chain: &mut DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>,
+    access_platform: Option<&Arc<dyn AccessPlatform>>,
+) -> (Buffers, Buffers) {
+    let mut readable = Vec::new();
+    let mut writable = Vec::new();
+    for desc in desc_chain {
+        let buffer = (
+            desc.addr()
+                .translate_gva(access_platform, desc.len() as usize),
+            desc.len(),
+        );
+        if desc.is_write_only() {
+            writable.push(buffer);
+        } else {
+            readable.push(buffer);
+        }
+    }
+    (readable, writable)
+}
+
+// Events for the driver, delivered by the control thread as buffers are
+// made available on the event queue.
+struct PendingEvents {
+    evt: EventFd,
+    events: Mutex<VecDeque<[u8; 16]>>,
+    missed: AtomicBool,
+}
+
+impl PendingEvents {
+    fn push(&self, event: u32, target: u8, lun: u16, reason: u32) {
+        let mut data = [0u8; 16];
+        data[0..4].copy_from_slice(&event.to_le_bytes());
+        data[4..12].copy_from_slice(&lun_address(target, lun));
+        data[12..16].copy_from_slice(&reason.to_le_bytes());
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() < MAX_PENDING_EVENTS {
+            events.push_back(data);
+        } else {
+            self.missed.store(true, Ordering::Release);
+        }
+        if let Err(e) = self.evt.write(1) {
+            error!("Failed to trigger SCSI event: {:?}", e);
+        }
+    }
+}
+
+struct LunEntry {
+    id: String,
+    target: u8,
+    lun: u16,
+    scsi_lun: Arc<ScsiLun>,
+}
+
+enum LunUpdate {
+    Add { target: u8, lun: u16, io: ScsiLunIo },
+    Remove { target: u8, lun: u16 },
+}
+
+// Hands the logical units added and removed to a request queue thread,
+// the objects executing the commands being created on the VMM thread.
+struct LunUpdates {
+    evt: EventFd,
+    updates: Mutex<Vec<LunUpdate>>,
+}
+
+struct ScsiControlEpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    control_queue: Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
+    control_queue_evt: EventFd,
+    event_queue: Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
+    event_queue_evt: EventFd,
+    luns: Arc<Mutex<Vec<LunEntry>>>,
+    events: Arc<PendingEvents>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+}
+
+impl ScsiControlEpollHandler {
+    // Executes a task management function, returning the response.
+    fn task_management(&self, request: &[u8]) -> u8 {
+        let subtype = u32::from_le_bytes(request[4..8].try_into().unwrap());
+        let (target, lun) = match parse_lun_address(&request[8..16]) {
+            Some(address) => address,
+            None => return VIRTIO_SCSI_S_BAD_TARGET,
+        };
+        let luns = self.luns.lock().unwrap();
+        if !luns.iter().any(|entry| entry.target == target) {
+            return VIRTIO_SCSI_S_BAD_TARGET;
+        }
+
+        match subtype {
+            // The commands can't be aborted once submitted, and complete
+            // on their own.
+            VIRTIO_SCSI_T_TMF_ABORT_TASK
+            | VIRTIO_SCSI_T_TMF_ABORT_TASK_SET
+            | VIRTIO_SCSI_T_TMF_CLEAR_TASK_SET
+            | VIRTIO_SCSI_T_TMF_QUERY_TASK
+            | VIRTIO_SCSI_T_TMF_QUERY_TASK_SET => VIRTIO_SCSI_S_FUNCTION_COMPLETE,
+            VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET => {
+                for entry in luns
+                    .iter()
+                    .filter(|entry| entry.target == target && entry.lun == lun)
+                {
+                    entry.scsi_lun.reset();
+                }
+                VIRTIO_SCSI_S_FUNCTION_COMPLETE
+            }
+            VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET => {
+                for entry in luns.iter().filter(|entry| entry.target == target) {
+                    entry.scsi_lun.reset();
+                }
+                VIRTIO_SCSI_S_FUNCTION_COMPLETE
+            }
+            _ => VIRTIO_SCSI_S_FUNCTION_REJECTED,
+        }
+    }
+
+    fn process_control_queue(&mut self) -> Result<bool> {
+        let mut chains = Vec::new();
+        for mut desc_chain in self.control_queue.iter().map_err(Error::QueueIterator)? {
+            let (readable, writable) =
+                chain_buffers(&mut desc_chain, self.access_platform.as_ref());
+            chains.push((desc_chain.head_index(), readable, writable));
+        }
+
+        let mem = self.mem.memory();
+        let mut used_descs = Vec::new();
+        for (head_index, readable, writable) in chains {
+            let request = read_buffers(&mem, &readable)?;
+            if request.len() < 4 {
+                error!("Invalid SCSI control request");
+                used_descs.push((head_index, 0));
+                continue;
+            }
+
+            let response = match u32::from_le_bytes(request[0..4].try_into().unwrap()) {
+                VIRTIO_SCSI_T_TMF if request.len() >= 24 => {
+                    vec![self.task_management(&request)]
+                }
+                // Asynchronous notifications aren't supported.
+                VIRTIO_SCSI_T_AN_QUERY | VIRTIO_SCSI_T_AN_SUBSCRIBE if request.len() >= 16 => {
+                    vec![0, 0, 0, 0, VIRTIO_SCSI_S_OK]
+                }
+                request_type => {
+                    error!("Unsupported SCSI control request {}", request_type);
+                    vec![VIRTIO_SCSI_S_FUNCTION_REJECTED]
+                }
+            };
+            if buffers_len(&writable) < response.len() as u64 {
+                error!("SCSI control response buffer too small");
+                used_descs.push((head_index, 0));
+                continue;
+            }
+            write_buffers(&mem, &writable, &response)?;
+            used_descs.push((head_index, response.len() as u32));
+        }
+
+        for (desc_index, len) in used_descs.iter() {
+            self.control_queue
+                .add_used(*desc_index, *len)
+                .map_err(Error::QueueAddUsed)?;
+        }
+        Ok(!used_descs.is_empty())
+    }
+
+    // Delivers the pending events in the buffers available on the event
+    // queue.
+    fn process_event_queue(&mut self) -> Result<bool> {
+        let mut events = self.events.events.lock().unwrap();
+        let mut used_descs = Vec::new();
+        let mut avail_iter = self.event_queue.iter().map_err(Error::QueueIterator)?;
+
+        while !events.is_empty() || self.events.missed.load(Ordering::Acquire) {
+            let mut desc_chain = match avail_iter.next() {
+                Some(desc_chain) => desc_chain,
+                None => break,
+            };
+            let head_index = desc_chain.head_index();
+            let (_, writable) = chain_buffers(&mut desc_chain, self.access_platform.as_ref());
+            if buffers_len(&writable) < 16 {
+                error!("SCSI event buffer too small");
+                used_descs.push((head_index, 0));
+                continue;
+            }
+
+            let mut event = events.pop_front().unwrap_or_default();
+            if self.events.missed.swap(false, Ordering::AcqRel) {
+                let flags = u32::from_le_bytes(event[0..4].try_into().unwrap())
+                    | VIRTIO_SCSI_T_EVENTS_MISSED;
+                event[0..4].copy_from_slice(&flags.to_le_bytes());
+            }
+            write_buffers(desc_chain.memory(), &writable, &event)?;
+            used_descs.push((head_index, event.len() as u32));
+        }
+
+        drop(avail_iter);
+        for (desc_index, len) in used_descs.iter() {
+            self.event_queue
+                .add_used(*desc_index, *len)
+                .map_err(Error::QueueAddUsed)?;
+        }
+        Ok(!used_descs.is_empty())
+    }
+
+    fn signal(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(queue_index as u16))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.control_queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.event_queue_evt.as_raw_fd(), EVENT_QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.events.evt.as_raw_fd(), PENDING_EVENTS_EVENT)?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for ScsiControlEpollHandler {
+    fn handle_event(&mut self, _helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+        let ev_type = event.data as u16;
+        let (queue_index, result) = match ev_type {
+            QUEUE_AVAIL_EVENT => {
+                if let Err(e) = self.control_queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+                (CONTROL_QUEUE, self.process_control_queue())
+            }
+            EVENT_QUEUE_AVAIL_EVENT | PENDING_EVENTS_EVENT => {
+                let evt = if ev_type == EVENT_QUEUE_AVAIL_EVENT {
+                    &self.event_queue_evt
+                } else {
+                    &self.events.evt
+                };
+                if let Err(e) = evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+                (EVENT_QUEUE, self.process_event_queue())
+            }
+            _ => {
+                error!("Unexpected event: {}", ev_type);
+                return true;
+            }
+        };
+
+        match result {
+            Ok(true) => {
+                if let Err(e) = self.signal(queue_index) {
+                    error!("Failed to signal used queue: {:?}", e);
+                    return true;
+                }
+            }
+            Ok(false) => {}
+            Err(e) => {
+                error!("Failed to process queue: {:?}", e);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+struct LunSlot {
+    target: u8,
+    lun: u16,
+    io: ScsiLunIo,
+    // Removed from the controller, the slot being freed once the commands
+    // in flight completed.
+    removed: bool,
+}
+
+// Command submitted to a logical unit.
+struct PendingRequest {
+    slot: usize,
+    response: Buffers,
+    data_in_len: u64,
+    data_out_len: u64,
+}
+
+struct ScsiRequestEpollHandler {
+    queue_index: u16,
+    queue: Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    queue_evt: EventFd,
+    slots: Vec<Option<LunSlot>>,
+    pending: HashMap<u64, PendingRequest>,
+    updates: Arc<LunUpdates>,
+    cdb_size: usize,
+    sense_size: usize,
+    counters: ScsiCounters,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+}
+
+impl ScsiRequestEpollHandler {
+    fn add_slot(&mut self, helper: &mut EpollHelper, slot: LunSlot) -> Result<()> {
+        let index = match self.slots.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+        };
+        if let Some(notifier) = slot.io.notifier() {
+            helper
+                .add_event(
+                    notifier.as_raw_fd(),
+                    LUN_COMPLETION_EVENT_BASE + index as u16,
+                )
+                .map_err(Error::RegisterLun)?;
+        }
+        self.slots[index] = Some(slot);
+        Ok(())
+    }
+
+    // Frees the slot of a removed logical unit once its commands completed.
+    fn release_slot(&mut self, helper: &mut EpollHelper, index: usize) -> Result<()> {
+        match &self.slots[index] {
+            Some(slot) if slot.removed && !slot.io.has_pending_commands() => {
+                if let Some(notifier) = slot.io.notifier() {
+                    helper
+                        .del_event_custom(
+                            notifier.as_raw_fd(),
+                            LUN_COMPLETION_EVENT_BASE + index as u16,
+                            epoll::Events::EPOLLIN,
+                        )
+                        .map_err(Error::RegisterLun)?;
+                }
+                self.slots[index] = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn apply_updates(&mut self, helper: &mut EpollHelper) -> Result<()> {
+        let updates: Vec<LunUpdate> = self.updates.updates.lock().unwrap().drain(..).collect();
+        for update in updates {
+            match update {
+                LunUpdate::Add { target, lun, io } => self.add_slot(
+                    helper,
+                    LunSlot {
+                        target,
+                        lun,
+                        io,
+                        removed: false,
+                    },
+                )?,
+                LunUpdate::Remove { target, lun } => {
+                    let index = self.find_slot(target, lun);
+                    if let Some(index) = index {
+                        self.slots[index].as_mut().unwrap().removed = true;
+                        self.release_slot(helper, index)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn find_slot(&self, target: u8, lun: u16) -> Option<usize> {
+        self.slots.iter().position(|slot| {
+            slot.as_ref().map_or(false, |slot| {
+                !slot.removed && slot.target == target && slot.lun == lun
+            })
+        })
+    }
+
+    // Returns the response to a command, along with the number of bytes
+    // written to the driver buffers.
+    fn response(&self, completion: &ScsiCompletion, request: &PendingRequest) -> (Vec<u8>, u32) {
+        let sense = completion
+            .sense
+            .map(|sense| sense.to_fixed_format().to_vec())
+            .unwrap_or_default();
+        let sense_len = std::cmp::min(sense.len(), self.sense_size);
+        let buffer_len = request.data_in_len + request.data_out_len;
+        let residual = buffer_len - std::cmp::min(completion.transferred, buffer_len);
+
+        let mut response = Vec::with_capacity(RESPONSE_HEADER_SIZE + sense_len);
+        response.extend_from_slice(&(sense_len as u32).to_le_bytes());
+        response.extend_from_slice(&(residual as u32).to_le_bytes());
+        response.extend_from_slice(&[0, 0]);
+        response.push(completion.status);
+        response.push(if completion.overrun {
+            VIRTIO_SCSI_S_OVERRUN
+        } else {
+            VIRTIO_SCSI_S_OK
+        });
+        response.extend_from_slice(&sense[..sense_len]);
+
+        let mut used_len = buffers_len(&request.response) as u32;
+        if request.data_in_len > 0 {
+            used_len += completion.transferred as u32;
+        }
+        (response, used_len)
+    }
+
+    fn complete_request(
+        &self,
+        mem: &GuestMemoryMmap,
+        completion: &ScsiCompletion,
+        request: &PendingRequest,
+    ) -> Result<u32> {
+        if request.data_in_len > 0 {
+            self.counters
+                .read_bytes
+                .fetch_add(completion.transferred, Ordering::AcqRel);
+            self.counters.read_ops.fetch_add(1, Ordering::AcqRel);
+        } else if request.data_out_len > 0 {
+            self.counters
+                .write_bytes
+                .fetch_add(completion.transferred, Ordering::AcqRel);
+            self.counters.write_ops.fetch_add(1, Ordering::AcqRel);
+        }
+
+        let (response, used_len) = self.response(completion, request);
+        write_buffers(mem, &request.response, &response)?;
+        Ok(used_len)
+    }
+
+    fn process_queue_submit(&mut self, helper: &mut EpollHelper) -> Result<bool> {
+        self.apply_updates(helper)?;
+
+        let mut chains = Vec::new();
+        for mut desc_chain in self.queue.iter().map_err(Error::QueueIterator)? {
+            let (readable, writable) =
+                chain_buffers(&mut desc_chain, self.access_platform.as_ref());
+            chains.push((desc_chain.head_index(), readable, writable));
+        }
+
+        let mem = self.mem.memory();
+        let mut used_descs = Vec::new();
+        for (head_index, readable, writable) in chains {
+            let (header, data_out) =
+                split_buffers(&readable, (REQUEST_HEADER_SIZE + self.cdb_size) as u64);
+            let (response, data_in) =
+                split_buffers(&writable, (RESPONSE_HEADER_SIZE + self.sense_size) as u64);
+            let header = read_buffers(&mem, &header)?;
+            if header.len() < REQUEST_HEADER_SIZE + self.cdb_size
+                || (buffers_len(&response) as usize) < RESPONSE_HEADER_SIZE
+            {
+                error!("Invalid SCSI request");
+                used_descs.push((head_index, 0));
+                continue;
+            }
+
+            let request = PendingRequest {
+                slot: 0,
+                response,
+                data_in_len: buffers_len(&data_in),
+                data_out_len: buffers_len(&data_out),
+            };
+            let cdb = &header[REQUEST_HEADER_SIZE..];
+            let address = parse_lun_address(&header[0..8]);
+            let slot = address.and_then(|(target, lun)| self.find_slot(target, lun));
+
+            let completion = match (address, slot) {
+                (Some(_), Some(slot)) => {
+                    let data_out = host_iovecs(&mem, &data_out)?;
+                    let data_in = host_iovecs(&mem, &data_in)?;
+                    let io = &mut self.slots[slot].as_mut().unwrap().io;
+                    match io.execute(cdb, &data_out, &data_in, head_index as u64) {
+                        Some(completion) => completion,
+                        None => {
+                            self.pending
+                                .insert(head_index as u64, PendingRequest { slot, ..request });
+                            continue;
+                        }
+                    }
+                }
+                (Some((target, _)), None) => {
+                    let luns: Vec<u16> = self
+                        .slots
+                        .iter()
+                        .flatten()
+                        .filter(|slot| !slot.removed && slot.target == target)
+                        .map(|slot| slot.lun)
+                        .collect();
+                    if luns.is_empty() {
+                        write_buffers(&mem, &request.response, &bad_target_response())?;
+                        used_descs.push((head_index, RESPONSE_HEADER_SIZE as u32));
+                        continue;
+                    }
+                    execute_target_command(cdb, &luns, &host_iovecs(&mem, &data_in)?)
+                }
+                (None, _) => {
+                    write_buffers(&mem, &request.response, &bad_target_response())?;
+                    used_descs.push((head_index, RESPONSE_HEADER_SIZE as u32));
+                    continue;
+                }
+            };
+
+            let used_len = self.complete_request(&mem, &completion, &request)?;
+            used_descs.push((head_index, used_len));
+        }
+
+        for (desc_index, len) in used_descs.iter() {
+            self.queue
+                .add_used(*desc_index, *len)
+                .map_err(Error::QueueAddUsed)?;
+        }
+        Ok(!used_descs.is_empty())
+    }
+
+    fn process_queue_complete(&mut self, helper: &mut EpollHelper, index: usize) -> Result<bool> {
+        let completions = match self.slots[index].as_mut() {
+            Some(slot) => slot.io.complete(),
+            None => return Ok(false),
+        };
+
+        let mem = self.mem.memory();
+        let mut used_descs = Vec::new();
+        for (user_data, completion) in completions {
+            let request = match self.pending.remove(&user_data) {
+                Some(request) => request,
+                None => {
+                    error!("Unexpected completion of SCSI request {}", user_data);
+                    continue;
+                }
+            };
+            let used_len = self.complete_request(&mem, &completion, &request)?;
+            used_descs.push((user_data as u16, used_len));
+        }
+
+        for (desc_index, len) in used_descs.iter() {
+            self.queue
+                .add_used(*desc_index, *len)
+                .map_err(Error::QueueAddUsed)?;
+        }
+        self.release_slot(helper, index)?;
+        Ok(!used_descs.is_empty())
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(self.queue_index))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.updates.evt.as_raw_fd(), LUN_UPDATE_EVENT)?;
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(notifier) = slot.as_ref().and_then(|slot| slot.io.notifier()) {
+                helper.add_event(
+                    notifier.as_raw_fd(),
+                    LUN_COMPLETION_EVENT_BASE + index as u16,
+                )?;
+            }
+        }
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+fn bad_target_response() -> Vec<u8> {
+    let mut response = vec![0u8; RESPONSE_HEADER_SIZE];
+    response[11] = VIRTIO_SCSI_S_BAD_TARGET;
+    response
+}
+
+impl EpollHelperHandler for ScsiRequestEpollHandler {
+    fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+        let ev_type = event.data as u16;
+        let result = match ev_type {
+            QUEUE_AVAIL_EVENT => {
+                if let Err(e) = self.queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+                self.process_queue_submit(helper)
+            }
+            LUN_UPDATE_EVENT => {
+                if let Err(e) = self.updates.evt.read() {
+                    error!("Failed to get LUN update event: {:?}", e);
+                    return true;
+                }
+                self.apply_updates(helper).map(|_| false)
+            }
+            _ if ev_type >= LUN_COMPLETION_EVENT_BASE => {
+                let index = (ev_type - LUN_COMPLETION_EVENT_BASE) as usize;
+                if let Some(notifier) = self
+                    .slots
+                    .get(index)
+                    .and_then(|slot| slot.as_ref())
+                    .and_then(|slot| slot.io.notifier())
+                {
+                    if let Err(e) = notifier.read() {
+                        error!("Failed to get completion event: {:?}", e);
+                        return true;
+                    }
+                }
+                self.process_queue_complete(helper, index)
+            }
+            _ => {
+                error!("Unexpected event: {}", ev_type);
+                return true;
+            }
+        };
+
+        match result {
+            Ok(true) => {
+                if let Err(e) = self.signal_used_queue() {
+                    error!("Failed to signal used queue: {:?}", e);
+                    return true;
+                }
+            }
+            Ok(false) => {}
+            Err(e) => {
+                error!("Failed to process queue: {:?}", e);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Virtio SCSI controller exposing disk and CD-ROM logical units emulated on
+/// top of disk images.
+pub struct Scsi {
+    common: VirtioCommon,
+    id: String,
+    config: VirtioScsiConfig,
+    luns: Arc<Mutex<Vec<LunEntry>>>,
+    // Logical units updates for the request queue threads running.
+    lun_updates: Vec<(u16, Arc<LunUpdates>)>,
+    events: Option<Arc<PendingEvents>>,
+    counters: ScsiCounters,
+    seccomp_action: SeccompAction,
+    exit_evt: EventFd,
+}
+
+#[derive(Versionize)]
+pub struct ScsiLunState {
+    pub id: String,
+    pub reservations: PersistentReservationsState,
+}
+
+#[derive(Versionize)]
+pub struct ScsiState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub config: VirtioScsiConfig,
+    // Logical units with persistent reservations only known to this VM
+    pub luns: Vec<ScsiLunState>,
+}
+
+impl VersionMapped for ScsiState {}
+
+impl Scsi {
+    /// Create a new virtio SCSI controller with `num_queues` request queues.
+    pub fn new(
+        id: String,
+        num_queues: usize,
+        queue_size: u16,
+        iommu: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+    ) -> io::Result<Scsi> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_SCSI_F_HOTPLUG;
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        let config = VirtioScsiConfig {
+            num_queues: num_queues as u32,
+            seg_max: SEG_MAX,
+            max_sectors: MAX_SECTORS,
+            cmd_per_lun: queue_size as u32,
+            event_info_size: 16,
+            sense_size: VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
+            cdb_size: VIRTIO_SCSI_CDB_DEFAULT_SIZE,
+            max_channel: 0,
+            max_target: MAX_TARGET,
+            max_lun: MAX_LUN,
+        };
+
+        Ok(Scsi {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Scsi as u32,
+                queue_sizes: vec![queue_size; num_queues + REQUEST_QUEUES_OFFSET],
+                // One thread per request queue, and the control thread.
+                paused_sync: Some(Arc::new(Barrier::new(num_queues + 2))),
+                avail_features,
+                min_queues: REQUEST_QUEUES_OFFSET as u16 + 1,
+                ..Default::default()
+            },
+            id,
+            config,
+            luns: Arc::new(Mutex::new(Vec::new())),
+            lun_updates: Vec::new(),
+            events: None,
+            counters: ScsiCounters::default(),
+            seccomp_action,
+            exit_evt,
+        })
+    }
+
+    fn state(&self) -> ScsiState {
+        ScsiState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            config: self.config,
+            luns: self
+                .luns
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|entry| {
+                    Some(ScsiLunState {
+                        id: entry.id.clone(),
+                        reservations: entry.scsi_lun.reservations_state()?,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn set_state(&mut self, state: &ScsiState) {
+        self.common.avail_features = state.avail_features;
+        self.common.acked_features = state.acked_features;
+        self.config = state.config;
+
+        // The logical units are added from the configuration beforehand.
+        let luns = self.luns.lock().unwrap();
+        for lun_state in state.luns.iter() {
+            match luns.iter().find(|entry| entry.id == lun_state.id) {
+                Some(entry) => entry
+                    .scsi_lun
+                    .set_reservations_state(&lun_state.reservations),
+                None => warn!(
+                    "Persistent reservations of unknown SCSI logical unit {}",
+                    lun_state.id
+                ),
+            }
+        }
+    }
+
+    fn notify_hotplug(&self, target: u8, lun: u16, reason: u32) {
+        if !self.common.feature_acked(VIRTIO_SCSI_F_HOTPLUG) {
+            return;
+        }
+        if let Some(events) = self.events.as_ref() {
+            events.push(VIRTIO_SCSI_T_TRANSPORT_RESET, target, lun, reason);
+        }
+    }
+
+    /// Adds the logical unit `id` at `target` and `lun`.
+    pub fn add_lun(
+        &mut self,
+        id: String,
+        target: u8,
+        lun: u16,
+        scsi_lun: Arc<ScsiLun>,
+    ) -> io::Result<()> {
+        let mut luns = self.luns.lock().unwrap();
+        if luns
+            .iter()
+            .any(|entry| entry.target == target && entry.lun == lun)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("SCSI target {} LUN {} already in use", target, lun),
+            ));
+        }
+
+        // The threads running need their own objects executing the commands.
+        let mut ios = Vec::new();
+        for (queue_size, _) in self.lun_updates.iter() {
+            ios.push(
+                scsi_lun
+                    .new_io(*queue_size as u32)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+            );
+        }
+        for ((_, lun_updates), io) in self.lun_updates.iter().zip(ios) {
+            lun_updates
+                .updates
+                .lock()
+                .unwrap()
+                .push(LunUpdate::Add { target, lun, io });
+            lun_updates.evt.write(1)?;
+        }
+
+        luns.push(LunEntry {
+            id,
+            target,
+            lun,
+            scsi_lun,
+        });
+        drop(luns);
+        self.notify_hotplug(target, lun, VIRTIO_SCSI_EVT_RESET_RESCAN);
+
+        Ok(())
+    }
+
+    /// Removes the logical unit `id`, returning whether it was found.
+    pub fn remove_lun(&mut self, id: &str) -> io::Result<bool> {
+        let mut luns = self.luns.lock().unwrap();
+        let index = match luns.iter().position(|entry| entry.id == id) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        let entry = luns.remove(index);
+        drop(luns);
+
+        for (_, lun_updates) in self.lun_updates.iter() {
+            lun_updates.updates.lock().unwrap().push(LunUpdate::Remove {
+                target: entry.target,
+                lun: entry.lun,
+            });
+            lun_updates.evt.write(1)?;
+        }
+        self.notify_hotplug(entry.target, entry.lun, VIRTIO_SCSI_EVT_RESET_REMOVED);
+
+        Ok(true)
+    }
+
+    /// Returns the ids of the logical units of the controller.
+    pub fn lun_ids(&self) -> Vec<String> {
+        self.luns
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.id.clone())
+            .collect()
+    }
+}
+
+impl Drop for Scsi {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Scsi {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // The sense and CDB sizes are the only mutable fields.
+        let sense_size_offset =
+            (&self.config.sense_size as *const _ as u64) - (&self.config as *const _ as u64);
+        let cdb_size_offset =
+            (&self.config.cdb_size as *const _ as u64) - (&self.config as *const _ as u64);
+        if (offset != sense_size_offset && offset != cdb_size_offset) || data.len() != 4 {
+            error!(
+                "Attempt to write to read-only field: offset {:x} length {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        let value = u32::from_le_bytes(data.try_into().unwrap());
+        if offset == sense_size_offset {
+            self.config.sense_size = std::cmp::min(value, VIRTIO_SCSI_SENSE_MAX_SIZE);
+        } else {
+            self.config.cdb_size = value.clamp(1, VIRTIO_SCSI_CDB_MAX_SIZE);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue<GuestMemoryAtomic<GuestMemoryMmap>>>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
+        let mut epoll_threads = Vec::new();
+
+        let events = Arc::new(PendingEvents {
+            evt: EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                error!("failed to create event EventFd: {}", e);
+                ActivateError::BadActivate
+            })?,
+            events: Mutex::new(VecDeque::new()),
+            missed: AtomicBool::new(false),
+        });
+        self.events = Some(events.clone());
+
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+        let mut control_handler = ScsiControlEpollHandler {
+            mem: mem.clone(),
+            control_queue: queues.remove(0),
+            control_queue_evt: queue_evts.remove(0),
+            event_queue: queues.remove(0),
+            event_queue_evt: queue_evts.remove(0),
+            luns: self.luns.clone(),
+            events,
+            interrupt_cb: interrupt_cb.clone(),
+            kill_evt,
+            pause_evt,
+            access_platform: self.common.access_platform.clone(),
+        };
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        spawn_virtio_thread(
+            &format!("{}_ctrl", self.id),
+            &self.seccomp_action,
+            Thread::VirtioScsi,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || {
+                if let Err(e) = control_handler.run(paused, paused_sync.unwrap()) {
+                    error!("Error running worker: {:?}", e);
+                }
+            },
+        )?;
+
+        // The logical units can't be updated until all the threads are set
+        // up to follow.
+        let luns = self.luns.lock().unwrap();
+        self.lun_updates.clear();
+        for i in 0..queues.len() {
+            let queue_evt = queue_evts.remove(0);
+            let queue = queues.remove(0);
+            let queue_size = queue.state.size;
+            let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+            let mut slots = Vec::new();
+            for entry in luns.iter() {
+                slots.push(Some(LunSlot {
+                    target: entry.target,
+                    lun: entry.lun,
+                    io: entry.scsi_lun.new_io(queue_size as u32).map_err(|e| {
+                        error!("failed to create new AsyncIo: {}", e);
+                        ActivateError::BadActivate
+                    })?,
+                    removed: false,
+                }));
+            }
+
+            let updates = Arc::new(LunUpdates {
+                evt: EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                    error!("failed to create LUN update EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?,
+                updates: Mutex::new(Vec::new()),
+            });
+            self.lun_updates.push((queue_size, updates.clone()));
+
+            let mut handler = ScsiRequestEpollHandler {
+                queue_index: (i + REQUEST_QUEUES_OFFSET) as u16,
+                queue,
+                mem: mem.clone(),
+                queue_evt,
+                slots,
+                pending: HashMap::with_capacity(queue_size.into()),
+                updates,
+                cdb_size: self.config.cdb_size as usize,
+                sense_size: self.config.sense_size as usize,
+                counters: self.counters.clone(),
+                interrupt_cb: interrupt_cb.clone(),
+                kill_evt,
+                pause_evt,
+                access_platform: self.common.access_platform.clone(),
+            };
+
+            let paused = self.common.paused.clone();
+            let paused_sync = self.common.paused_sync.clone();
+            spawn_virtio_thread(
+                &format!("{}_q{}", self.id, i),
+                &self.seccomp_action,
+                Thread::VirtioScsi,
+                &mut epoll_threads,
+                &self.exit_evt,
+                move || {
+                    if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
+                        error!("Error running worker: {:?}", e);
+                    }
+                },
+            )?;
+        }
+        drop(luns);
+
+        self.common.epoll_threads = Some(epoll_threads);
+        event!("virtio-device", "activated", "id", &self.id);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        let result = self.common.reset();
+        self.lun_updates.clear();
+        self.events = None;
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+
+        counters.insert(
+            "read_bytes",
+            Wrapping(self.counters.read_bytes.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "write_bytes",
+            Wrapping(self.counters.write_bytes.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "read_ops",
+            Wrapping(self.counters.read_ops.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "write_ops",
+            Wrapping(self.counters.write_ops.load(Ordering::Acquire)),
+        );
+
+        Some(counters)
+    }
+
+    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
+        self.common.set_access_platform(access_platform)
+    }
+}
+
+impl Pausable for Scsi {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Scsi {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_versioned_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_versioned_state(&self.id)?);
+        Ok(())
+    }
+}
+impl Transportable for Scsi {}
+impl Migratable for Scsi {}
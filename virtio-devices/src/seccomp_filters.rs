@@ -18,8 +18,10 @@ pub enum Thread {
     VirtioMem,
     VirtioNet,
     VirtioNetCtl,
+    VirtioNetUser,
     VirtioPmem,
     VirtioRng,
+    VirtioScsi,
     VirtioVhostBlock,
     VirtioVhostFs,
     VirtioVhostNet,
@@ -81,6 +83,8 @@ fn virtio_balloon_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 
 fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
+        (libc::SYS_clock_nanosleep, vec![]),
+        (libc::SYS_connect, vec![]),
         (libc::SYS_fallocate, vec![]),
         (libc::SYS_fdatasync, vec![]),
         (libc::SYS_fsync, vec![]),
@@ -89,13 +93,22 @@ fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_io_uring_enter, vec![]),
         (libc::SYS_lseek, vec![]),
         (libc::SYS_mprotect, vec![]),
+        (libc::SYS_nanosleep, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_poll, vec![]),
+        #[cfg(target_arch = "aarch64")]
+        (libc::SYS_ppoll, vec![]),
         (libc::SYS_prctl, vec![]),
         (libc::SYS_pread64, vec![]),
         (libc::SYS_preadv, vec![]),
         (libc::SYS_pwritev, vec![]),
         (libc::SYS_pwrite64, vec![]),
+        (libc::SYS_recvfrom, vec![]),
         (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_sendto, vec![]),
         (libc::SYS_set_robust_list, vec![]),
+        (libc::SYS_setsockopt, vec![]),
+        (libc::SYS_socket, vec![]),
         (libc::SYS_timerfd_settime, vec![]),
     ]
 }
@@ -129,11 +142,34 @@ fn virtio_mem_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 fn virtio_net_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
         (libc::SYS_readv, vec![]),
+        (libc::SYS_sendmsg, vec![]),
         (libc::SYS_timerfd_settime, vec![]),
         (libc::SYS_writev, vec![]),
     ]
 }
 
+fn create_virtio_net_user_ioctl_seccomp_rule() -> Vec<SeccompRule> {
+    or![and![Cond::new(1, ArgLen::Dword, Eq, FIONBIO).unwrap()],]
+}
+
+fn virtio_net_user_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    let mut rules = virtio_net_thread_rules();
+    // Host sockets relaying the traffic of the guest
+    rules.append(&mut vec![
+        (libc::SYS_accept4, vec![]),
+        (libc::SYS_bind, vec![]),
+        (libc::SYS_connect, vec![]),
+        (libc::SYS_fcntl, vec![]),
+        (libc::SYS_getsockopt, vec![]),
+        (libc::SYS_ioctl, create_virtio_net_user_ioctl_seccomp_rule()),
+        (libc::SYS_recvfrom, vec![]),
+        (libc::SYS_sendto, vec![]),
+        (libc::SYS_shutdown, vec![]),
+        (libc::SYS_socket, vec![]),
+    ]);
+    rules
+}
+
 fn create_virtio_net_ctl_ioctl_seccomp_rule() -> Vec<SeccompRule> {
     or![and![Cond::new(1, ArgLen::Dword, Eq, TUNSETOFFLOAD).unwrap()],]
 }
@@ -189,6 +225,13 @@ fn virtio_vhost_net_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     ]
 }
 
+fn virtio_scsi_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    let mut rules = virtio_block_thread_rules();
+    // Persistent reservations shared with other VMs
+    rules.push((libc::SYS_flock, vec![]));
+    rules
+}
+
 fn virtio_vhost_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![]
 }
@@ -226,8 +269,10 @@ fn get_seccomp_rules(thread_type: Thread) -> Vec<(i64, Vec<SeccompRule>)> {
         Thread::VirtioMem => virtio_mem_thread_rules(),
         Thread::VirtioNet => virtio_net_thread_rules(),
         Thread::VirtioNetCtl => virtio_net_ctl_thread_rules(),
+        Thread::VirtioNetUser => virtio_net_user_thread_rules(),
         Thread::VirtioPmem => virtio_pmem_thread_rules(),
         Thread::VirtioRng => virtio_rng_thread_rules(),
+        Thread::VirtioScsi => virtio_scsi_thread_rules(),
         Thread::VirtioVhostBlock => virtio_vhost_block_thread_rules(),
         Thread::VirtioVhostFs => virtio_vhost_fs_thread_rules(),
         Thread::VirtioVhostNet => virtio_vhost_net_thread_rules(),
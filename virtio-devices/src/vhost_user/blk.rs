@@ -8,8 +8,8 @@ use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
 use crate::vhost_user::VhostUserCommon;
 use crate::{GuestMemoryMmap, GuestRegionMmap};
-use crate::{VirtioInterrupt, VIRTIO_F_IOMMU_PLATFORM};
-use block_util::VirtioBlockConfig;
+use crate::{VirtioInterrupt, VirtioInterruptType, VIRTIO_F_IOMMU_PLATFORM};
+use block_util::{VirtioBlockConfig, SECTOR_SIZE};
 use seccompiler::SeccompAction;
 use std::mem;
 use std::result;
@@ -223,6 +223,48 @@ impl Blk {
             );
         }
     }
+
+    /// Picks up the capacity of the disk image, which the backend owns and
+    /// must have grown already, and notifies the guest of the change through
+    /// a configuration change interrupt. When `size` is set, the backend must
+    /// expose a disk of that size.
+    pub fn resize(&mut self, size: Option<u64>) -> Result<()> {
+        let vu = self.vu_common.vu.as_ref().ok_or(Error::VhostUserConnect)?;
+        let config_len = mem::size_of::<VirtioBlockConfig>();
+        let config_space: Vec<u8> = vec![0u8; config_len as usize];
+        let (_, config_space) = vu
+            .lock()
+            .unwrap()
+            .socket_handle()
+            .get_config(
+                VHOST_USER_CONFIG_OFFSET,
+                config_len as u32,
+                VhostUserConfigFlags::WRITABLE,
+                config_space.as_slice(),
+            )
+            .map_err(Error::VhostUserGetConfig)?;
+        let capacity = VirtioBlockConfig::from_slice(config_space.as_slice())
+            .map(|backend_config| backend_config.capacity)
+            .unwrap_or(self.config.capacity);
+
+        if let Some(size) = size {
+            if capacity * SECTOR_SIZE != size {
+                return Err(Error::UnexpectedDiskSize(capacity * SECTOR_SIZE));
+            }
+        }
+
+        info!("Resizing disk {} to {} sectors", self.id, capacity);
+        self.config.capacity = capacity;
+
+        // Until the device is activated, the guest has yet to read the capacity.
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb
+                .trigger(VirtioInterruptType::Config)
+                .map_err(Error::FailedSignalingConfigChange)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for Blk {
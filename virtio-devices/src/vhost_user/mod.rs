@@ -142,6 +142,10 @@ pub enum Error {
     NewMmapRegion(MmapRegionError),
     /// Could not find the shm log region
     MissingShmLogRegion,
+    /// The size exposed by the backend doesn't match the requested one.
+    UnexpectedDiskSize(u64),
+    /// Failed signaling the configuration change.
+    FailedSignalingConfigChange(io::Error),
 }
 type Result<T> = std::result::Result<T, Error>;
 
@@ -33,6 +33,7 @@ pub enum VirtioDeviceType {
     Console = 3,
     Rng = 4,
     Balloon = 5,
+    Scsi = 8,
     Fs9P = 9,
     Gpu = 16,
     Input = 18,
@@ -53,6 +54,7 @@ impl From<u32> for VirtioDeviceType {
             3 => VirtioDeviceType::Console,
             4 => VirtioDeviceType::Rng,
             5 => VirtioDeviceType::Balloon,
+            8 => VirtioDeviceType::Scsi,
             9 => VirtioDeviceType::Fs9P,
             16 => VirtioDeviceType::Gpu,
             18 => VirtioDeviceType::Input,
@@ -78,6 +80,7 @@ impl fmt::Display for VirtioDeviceType {
             VirtioDeviceType::Console => "console",
             VirtioDeviceType::Rng => "rng",
             VirtioDeviceType::Balloon => "balloon",
+            VirtioDeviceType::Scsi => "scsi",
             VirtioDeviceType::Gpu => "gpu",
             VirtioDeviceType::Fs9P => "9p",
             VirtioDeviceType::Input => "input",
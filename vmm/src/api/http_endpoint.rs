@@ -5,13 +5,15 @@
 
 use crate::api::http::{error_response, EndpointHandler, HttpError};
 use crate::api::{
-    vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_user_device,
-    vm_add_vdpa, vm_add_vsock, vm_boot, vm_counters, vm_create, vm_delete, vm_info, vm_pause,
-    vm_power_button, vm_reboot, vm_receive_migration, vm_remove_device, vm_resize, vm_resize_zone,
-    vm_restore, vm_resume, vm_send_migration, vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown,
-    ApiRequest, VmAction, VmConfig,
+    vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_scsi_lun,
+    vm_add_user_device, vm_add_vdpa, vm_add_vsock, vm_backup_disk, vm_boot, vm_cancel_disk_mirror,
+    vm_capture_net, vm_change_media, vm_counters, vm_create, vm_delete, vm_info, vm_mirror_disk,
+    vm_pause, vm_power_button, vm_reboot, vm_receive_migration, vm_remove_device, vm_resize,
+    vm_resize_disk, vm_resize_zone, vm_restore, vm_resume, vm_send_migration, vm_set_link,
+    vm_shutdown, vm_snapshot, vm_update_rate_limiter, vmm_ping, vmm_shutdown, ApiRequest, VmAction,
+    VmConfig,
 };
-use crate::config::NetConfig;
+use crate::config::{DiskConfig, NetConfig};
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use std::fs::File;
 use std::os::unix::io::IntoRawFd;
@@ -86,11 +88,15 @@ impl EndpointHandler for VmActionHandler {
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
-                AddDisk(_) => vm_add_disk(
-                    api_notifier,
-                    api_sender,
-                    Arc::new(serde_json::from_slice(body.raw())?),
-                ),
+                AddDisk(_) => {
+                    let mut disk_cfg: DiskConfig = serde_json::from_slice(body.raw())?;
+                    // Update disk config with the optional passphrase file
+                    // that might have been sent through control message.
+                    if let Some(file) = files.drain(..).next() {
+                        disk_cfg.key_fd = Some(file.into_raw_fd());
+                    }
+                    vm_add_disk(api_notifier, api_sender, Arc::new(disk_cfg))
+                }
                 AddFs(_) => vm_add_fs(
                     api_notifier,
                     api_sender,
@@ -116,6 +122,11 @@ impl EndpointHandler for VmActionHandler {
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
+                AddScsiLun(_) => vm_add_scsi_lun(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
                 AddVsock(_) => vm_add_vsock(
                     api_notifier,
                     api_sender,
@@ -141,6 +152,46 @@ impl EndpointHandler for VmActionHandler {
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
+                ResizeDisk(_) => vm_resize_disk(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                UpdateRateLimiter(_) => vm_update_rate_limiter(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                BackupDisk(_) => vm_backup_disk(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                MirrorDisk(_) => vm_mirror_disk(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                CancelDiskMirror(_) => vm_cancel_disk_mirror(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                ChangeMedia(_) => vm_change_media(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                SetLink(_) => vm_set_link(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                CaptureNet(_) => vm_capture_net(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
                 Restore(_) => vm_restore(
                     api_notifier,
                     api_sender,
@@ -35,16 +35,18 @@ pub mod http;
 pub mod http_endpoint;
 
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, UserDeviceConfig,
-    VdpaConfig, VmConfig, VsockConfig,
+    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, ScsiLunConfig,
+    UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
 };
 use crate::device_tree::DeviceTree;
 use crate::vm::{Error as VmError, VmState};
 use micro_http::Body;
 use serde::{Deserialize, Serialize};
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use virtio_devices::RateLimiterConfig;
 use vm_migration::MigratableError;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -108,6 +110,30 @@ pub enum ApiError {
     /// The memory zone could not be resized.
     VmResizeZone(VmError),
 
+    /// The disk could not be resized.
+    VmResizeDisk(VmError),
+
+    /// The rate limiter could not be updated.
+    VmUpdateRateLimiter(VmError),
+
+    /// The disk could not be backed up.
+    VmBackupDisk(VmError),
+
+    /// The disk could not be mirrored.
+    VmMirrorDisk(VmError),
+
+    /// The disk mirror could not be cancelled.
+    VmCancelDiskMirror(VmError),
+
+    /// The media of the disk could not be changed.
+    VmChangeMedia(VmError),
+
+    /// The link of the network device could not be set.
+    VmSetLink(VmError),
+
+    /// The capture of the network device could not be started or stopped.
+    VmCaptureNet(VmError),
+
     /// The device could not be added to the VM.
     VmAddDevice(VmError),
 
@@ -138,6 +164,9 @@ pub enum ApiError {
     /// The vDPA device could not be added to the VM.
     VmAddVdpa(VmError),
 
+    /// The SCSI logical unit could not be added to the VM.
+    VmAddScsiLun(VmError),
+
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
 
@@ -178,6 +207,88 @@ pub struct VmResizeZoneData {
     pub desired_ram: u64,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmResizeDiskData {
+    pub id: String,
+    /// New size of the disk image, or none to pick up the size of an image
+    /// grown from outside of the VMM
+    #[serde(default)]
+    pub desired_size: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmUpdateRateLimiterData {
+    /// Identifier of the disk, network device or rate limit group
+    pub id: String,
+    /// New limits, or none to stop rate limiting
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmBackupDiskData {
+    pub id: String,
+    /// The backup destination URL, a file or a UNIX socket
+    pub destination_url: String,
+    /// Only back up the ranges written since the previous backup
+    #[serde(default)]
+    pub incremental: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmMirrorDiskData {
+    pub id: String,
+    /// Path of the image to create and mirror the disk to
+    pub destination: PathBuf,
+    /// Format of the image, raw by default
+    #[serde(default)]
+    pub format: Option<String>,
+    /// Keep the image in sync with the disk once copied, rather than
+    /// switching the disk over to it
+    #[serde(default)]
+    pub no_pivot: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmCancelDiskMirrorData {
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmChangeMediaData {
+    pub id: String,
+    /// Path of the new media, or none to eject the current one
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Whether the new media is read-only, as the current one by default
+    #[serde(default)]
+    pub readonly: Option<bool>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmSetLinkData {
+    pub id: String,
+    /// Whether the link is up, or down as if the cable was pulled
+    pub up: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmCaptureNetData {
+    pub id: String,
+    /// Path of the pcapng file, or none to stop the current capture
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Maximum number of bytes kept from each frame
+    #[serde(default)]
+    pub snaplen: Option<u32>,
+    /// Size in bytes after which the file is rotated
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    /// Number of files kept when rotating, current one included
+    #[serde(default)]
+    pub files: Option<u32>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmRemoveDeviceData {
     pub id: String,
@@ -187,6 +298,14 @@ pub struct VmRemoveDeviceData {
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
     pub destination_url: String,
+    /// Name of the internal snapshot to create on the disks which enabled
+    /// internal snapshots
+    #[serde(default)]
+    pub disk_snapshot_name: Option<String>,
+    /// Back up the disks tracking their writes with a dirty bitmap next to
+    /// the snapshot, incrementally from their previous backup
+    #[serde(default)]
+    pub disk_backup: bool,
 }
 
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
@@ -277,6 +396,30 @@ pub enum ApiRequest {
     /// Resize the memory zone.
     VmResizeZone(Arc<VmResizeZoneData>, Sender<ApiResponse>),
 
+    /// Resize a disk.
+    VmResizeDisk(Arc<VmResizeDiskData>, Sender<ApiResponse>),
+
+    /// Update the rate limiter of a device or a rate limit group.
+    VmUpdateRateLimiter(Arc<VmUpdateRateLimiterData>, Sender<ApiResponse>),
+
+    /// Back up a disk.
+    VmBackupDisk(Arc<VmBackupDiskData>, Sender<ApiResponse>),
+
+    /// Mirror a disk to a new image.
+    VmMirrorDisk(Arc<VmMirrorDiskData>, Sender<ApiResponse>),
+
+    /// Cancel the mirror of a disk.
+    VmCancelDiskMirror(Arc<VmCancelDiskMirrorData>, Sender<ApiResponse>),
+
+    /// Change the media of a disk.
+    VmChangeMedia(Arc<VmChangeMediaData>, Sender<ApiResponse>),
+
+    /// Set the link of a network device up or down.
+    VmSetLink(Arc<VmSetLinkData>, Sender<ApiResponse>),
+
+    /// Start or stop capturing the frames of a network device.
+    VmCaptureNet(Arc<VmCaptureNetData>, Sender<ApiResponse>),
+
     /// Add a device to the VM.
     VmAddDevice(Arc<DeviceConfig>, Sender<ApiResponse>),
 
@@ -301,6 +444,9 @@ pub enum ApiRequest {
     /// Add a vDPA device to the VM.
     VmAddVdpa(Arc<VdpaConfig>, Sender<ApiResponse>),
 
+    /// Add a SCSI logical unit to the VM.
+    VmAddScsiLun(Arc<ScsiLunConfig>, Sender<ApiResponse>),
+
     /// Add a vsock device to the VM.
     VmAddVsock(Arc<VsockConfig>, Sender<ApiResponse>),
 
@@ -381,6 +527,9 @@ pub enum VmAction {
     /// Add vdpa
     AddVdpa(Arc<VdpaConfig>),
 
+    /// Add SCSI logical unit
+    AddScsiLun(Arc<ScsiLunConfig>),
+
     /// Add vsock
     AddVsock(Arc<VsockConfig>),
 
@@ -396,6 +545,30 @@ pub enum VmAction {
     /// Resize memory zone
     ResizeZone(Arc<VmResizeZoneData>),
 
+    /// Resize disk
+    ResizeDisk(Arc<VmResizeDiskData>),
+
+    /// Update rate limiter
+    UpdateRateLimiter(Arc<VmUpdateRateLimiterData>),
+
+    /// Back up disk
+    BackupDisk(Arc<VmBackupDiskData>),
+
+    /// Mirror disk
+    MirrorDisk(Arc<VmMirrorDiskData>),
+
+    /// Cancel disk mirror
+    CancelDiskMirror(Arc<VmCancelDiskMirrorData>),
+
+    /// Change disk media
+    ChangeMedia(Arc<VmChangeMediaData>),
+
+    /// Set network link
+    SetLink(Arc<VmSetLinkData>),
+
+    /// Capture network frames
+    CaptureNet(Arc<VmCaptureNetData>),
+
     /// Restore VM
     Restore(Arc<RestoreConfig>),
 
@@ -434,11 +607,20 @@ fn vm_action(
         AddPmem(v) => ApiRequest::VmAddPmem(v, response_sender),
         AddNet(v) => ApiRequest::VmAddNet(v, response_sender),
         AddVdpa(v) => ApiRequest::VmAddVdpa(v, response_sender),
+        AddScsiLun(v) => ApiRequest::VmAddScsiLun(v, response_sender),
         AddVsock(v) => ApiRequest::VmAddVsock(v, response_sender),
         AddUserDevice(v) => ApiRequest::VmAddUserDevice(v, response_sender),
         RemoveDevice(v) => ApiRequest::VmRemoveDevice(v, response_sender),
         Resize(v) => ApiRequest::VmResize(v, response_sender),
         ResizeZone(v) => ApiRequest::VmResizeZone(v, response_sender),
+        ResizeDisk(v) => ApiRequest::VmResizeDisk(v, response_sender),
+        UpdateRateLimiter(v) => ApiRequest::VmUpdateRateLimiter(v, response_sender),
+        BackupDisk(v) => ApiRequest::VmBackupDisk(v, response_sender),
+        MirrorDisk(v) => ApiRequest::VmMirrorDisk(v, response_sender),
+        CancelDiskMirror(v) => ApiRequest::VmCancelDiskMirror(v, response_sender),
+        ChangeMedia(v) => ApiRequest::VmChangeMedia(v, response_sender),
+        SetLink(v) => ApiRequest::VmSetLink(v, response_sender),
+        CaptureNet(v) => ApiRequest::VmCaptureNet(v, response_sender),
         Restore(v) => ApiRequest::VmRestore(v, response_sender),
         Snapshot(v) => ApiRequest::VmSnapshot(v, response_sender),
         ReceiveMigration(v) => ApiRequest::VmReceiveMigration(v, response_sender),
@@ -589,6 +771,70 @@ pub fn vm_resize_zone(
     vm_action(api_evt, api_sender, VmAction::ResizeZone(data))
 }
 
+pub fn vm_resize_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResizeDiskData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ResizeDisk(data))
+}
+
+pub fn vm_update_rate_limiter(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmUpdateRateLimiterData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::UpdateRateLimiter(data))
+}
+
+pub fn vm_backup_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmBackupDiskData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::BackupDisk(data))
+}
+
+pub fn vm_mirror_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmMirrorDiskData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::MirrorDisk(data))
+}
+
+pub fn vm_cancel_disk_mirror(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmCancelDiskMirrorData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::CancelDiskMirror(data))
+}
+
+pub fn vm_change_media(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmChangeMediaData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ChangeMedia(data))
+}
+
+pub fn vm_set_link(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSetLinkData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetLink(data))
+}
+
+pub fn vm_capture_net(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmCaptureNetData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::CaptureNet(data))
+}
+
 pub fn vm_add_device(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -653,6 +899,14 @@ pub fn vm_add_vdpa(
     vm_action(api_evt, api_sender, VmAction::AddVdpa(data))
 }
 
+pub fn vm_add_scsi_lun(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<ScsiLunConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AddScsiLun(data))
+}
+
 pub fn vm_add_vsock(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
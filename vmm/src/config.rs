@@ -4,7 +4,7 @@
 //
 
 use clap::ArgMatches;
-use net_util::MacAddr;
+use net_util::{CaptureConfig, HostForward, L2SocketAddr, L2SocketConfig, MacAddr, UserNetConfig};
 use option_parser::{
     ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, Tuple,
 };
@@ -17,7 +17,7 @@ use std::path::PathBuf;
 use std::result;
 use std::str::FromStr;
 use thiserror::Error;
-use virtio_devices::{RateLimiterConfig, TokenBucketConfig};
+use virtio_devices::{ErrorPolicy, RateLimiterConfig, TokenBucketConfig};
 
 pub const DEFAULT_VCPUS: u8 = 1;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
@@ -35,6 +35,8 @@ pub const DEFAULT_QUEUE_SIZE_VUBLK: u16 = 128;
 
 pub const DEFAULT_NUM_PCI_SEGMENTS: u16 = 1;
 const MAX_NUM_PCI_SEGMENTS: u16 = 16;
+// Highest LUN addressable through the flat space addressing of virtio-scsi.
+const SCSI_MAX_LUN: u16 = 16383;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug, Error)]
@@ -65,6 +67,8 @@ pub enum Error {
     ParseDisk(OptionParserError),
     /// Error parsing network options
     ParseNetwork(OptionParserError),
+    /// Invalid port forwarded to the guest
+    ParseNetworkHostForward(String),
     /// Error parsing RNG options
     ParseRng(OptionParserError),
     /// Error parsing balloon options
@@ -111,6 +115,16 @@ pub enum Error {
     ParseVdpa(OptionParserError),
     /// Missing path for vDPA device
     ParseVdpaPathMissing,
+    /// Failed parsing SCSI controller
+    ParseScsi(OptionParserError),
+    /// Failed parsing SCSI logical unit
+    ParseScsiLun(OptionParserError),
+    /// Missing controller for SCSI logical unit
+    ParseScsiLunControllerMissing,
+    /// Failed parsing rate limit group
+    ParseRateLimitGroup(OptionParserError),
+    /// Missing identifier for rate limit group
+    ParseRateLimitGroupIdMissing,
 }
 
 #[derive(Debug, PartialEq, Error)]
@@ -175,6 +189,88 @@ pub enum ValidationError {
     InvalidIdentifier(String),
     /// Placing the device behind a virtual IOMMU is not supported
     IommuNotSupported,
+    /// Overriding the backing file requires backing files to be enabled
+    BackingFileOverrideWithoutBackingFiles,
+    /// Internal disk snapshots can't be taken on a read-only disk
+    InternalSnapshotReadOnly,
+    /// Internal disk snapshots aren't supported with vhost-user
+    InternalSnapshotVhostUser,
+    /// Disk images can't be repaired on a read-only disk
+    RepairReadOnly,
+    /// Disk images can't be repaired by the VMM with vhost-user
+    RepairVhostUser,
+    /// Writes to vhost-user disks can't be tracked by the VMM
+    DirtyBitmapVhostUser,
+    /// Both NBD server and path specified
+    DiskNbdAndPath,
+    /// NBD disks are served by the VMM, not a vhost-user backend
+    NbdVhostUser,
+    /// NBD export specified without any NBD server
+    NbdExportWithoutServer,
+    /// I/O errors on vhost-user disks are handled by the backend
+    ErrorPolicyVhostUser,
+    /// Both LUKS passphrase file and file descriptor specified
+    DiskKeyFileAndFd,
+    /// Reserved fd number for the LUKS passphrase
+    DiskKeyReservedFd,
+    /// vhost-user disks are decrypted by the backend
+    DiskKeyVhostUser,
+    /// Encrypted disks must be disk images
+    DiskKeyNbd,
+    /// Encrypted disks can't be discarded
+    DiskKeyDiscard,
+    /// SCSI logical unit on a controller which doesn't exist
+    ScsiControllerUnknown(String),
+    /// SCSI logical unit address used twice on the same controller
+    ScsiLunNotUnique(String, u8, u16),
+    /// SCSI logical unit number beyond what virtio-scsi can address
+    ScsiLunOutOfRange(u16),
+    /// SCSI disk without any image
+    ScsiLunPathMissing,
+    /// Device referring to a rate limit group which doesn't exist
+    RateLimitGroupUnknown(String),
+    /// Rate limit group identifier used twice
+    RateLimitGroupNotUnique(String),
+    /// Device with both its own rate limiter and a rate limit group
+    RateLimitGroupAndConfig,
+    /// vhost-user devices are rate limited by the backend
+    RateLimitGroupVhostUser,
+    /// No disk, network device or rate limit group with this identifier
+    RateLimiterUnknownId(String),
+    /// vhost-user devices are rate limited by the backend
+    RateLimiterVhostUser,
+    /// No disk with this identifier
+    DiskUnknownId(String),
+    /// Media of vhost-user disks are managed by the backend
+    MediaChangeVhostUser,
+    /// Dirty bitmaps track the writes to a single image
+    MediaChangeDirtyBitmap,
+    /// User-mode networking replaces the tap or vhost-user backend
+    UserNetAndBackend,
+    /// User-mode networking is served by a single queue pair
+    UserNetQueues,
+    /// The network has no room for the guest after the gateway
+    UserNetNoGuestAddress,
+    /// Ports can only be forwarded with user-mode networking
+    HostForwardWithoutUserNet,
+    /// Only user-mode networking reaches the host loopback through the gateway
+    HostLoopbackWithoutUserNet,
+    /// A socket to the peer replaces any other backend
+    L2SocketAndBackend,
+    /// A socket to the peer is served by a single queue pair
+    L2SocketQueues,
+    /// Only stream and seqpacket sockets wait for the peer
+    L2SocketServerWithoutStream,
+    /// The local address of a datagram socket doesn't match the remote one
+    L2SocketDgramLocal,
+    /// No network device with this identifier
+    NetUnknownId(String),
+    /// Frames of vhost-user devices are captured by the backend
+    CaptureVhostUser,
+    /// Capture options provided without a capture file
+    CaptureOptionsWithoutPath,
+    /// Capture files are rotated from a given size, keeping at least one
+    CaptureFiles,
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -270,6 +366,144 @@ impl fmt::Display for ValidationError {
             IommuNotSupported => {
                 write!(f, "Device does not support being placed behind IOMMU")
             }
+            BackingFileOverrideWithoutBackingFiles => {
+                write!(f, "Overriding the backing file requires backing_files=on")
+            }
+            InternalSnapshotReadOnly => {
+                write!(f, "Internal snapshots can't be taken on a read-only disk")
+            }
+            InternalSnapshotVhostUser => {
+                write!(
+                    f,
+                    "Internal snapshots aren't supported with vhost-user disks"
+                )
+            }
+            RepairReadOnly => {
+                write!(f, "Disk images can't be repaired on a read-only disk")
+            }
+            RepairVhostUser => {
+                write!(f, "Disk images can't be repaired with vhost-user disks")
+            }
+            DirtyBitmapVhostUser => {
+                write!(f, "Dirty bitmaps aren't supported with vhost-user disks")
+            }
+            DiskNbdAndPath => write!(f, "Disk path and NBD server both provided"),
+            NbdVhostUser => write!(f, "NBD servers can't be used with vhost-user disks"),
+            NbdExportWithoutServer => write!(f, "NBD export provided without an NBD server"),
+            ErrorPolicyVhostUser => {
+                write!(
+                    f,
+                    "I/O error policies aren't supported with vhost-user disks"
+                )
+            }
+            DiskKeyFileAndFd => write!(f, "Disk key file and key fd both provided"),
+            DiskKeyReservedFd => write!(f, "Reserved fd number (<= 2) for the disk key"),
+            DiskKeyVhostUser => {
+                write!(
+                    f,
+                    "Encrypted disks are opened by the backend with vhost-user disks"
+                )
+            }
+            DiskKeyNbd => write!(f, "NBD disks can't be encrypted"),
+            DiskKeyDiscard => write!(f, "Encrypted disks don't support discard"),
+            ScsiControllerUnknown(s) => write!(f, "SCSI controller {} doesn't exist", s),
+            ScsiLunNotUnique(s, target, lun) => {
+                write!(
+                    f,
+                    "SCSI target {} LUN {} used twice on controller {}",
+                    target, lun, s
+                )
+            }
+            ScsiLunOutOfRange(lun) => {
+                write!(f, "SCSI LUN {} greater than {}", lun, SCSI_MAX_LUN)
+            }
+            ScsiLunPathMissing => write!(f, "Path missing for SCSI disk"),
+            RateLimitGroupUnknown(s) => write!(f, "Rate limit group {} doesn't exist", s),
+            RateLimitGroupNotUnique(s) => {
+                write!(f, "Rate limit group identifier {} used twice", s)
+            }
+            RateLimitGroupAndConfig => {
+                write!(
+                    f,
+                    "Rate limiter parameters and rate limit group both provided"
+                )
+            }
+            RateLimitGroupVhostUser => {
+                write!(
+                    f,
+                    "Rate limit groups aren't supported with vhost-user devices"
+                )
+            }
+            RateLimiterUnknownId(s) => {
+                write!(f, "No disk, network device or rate limit group {}", s)
+            }
+            RateLimiterVhostUser => {
+                write!(f, "Rate limiters aren't supported with vhost-user devices")
+            }
+            DiskUnknownId(s) => write!(f, "No disk {}", s),
+            MediaChangeVhostUser => {
+                write!(f, "Media of vhost-user disks can't be changed")
+            }
+            MediaChangeDirtyBitmap => {
+                write!(f, "Media of disks with a dirty bitmap can't be changed")
+            }
+            UserNetAndBackend => {
+                write!(
+                    f,
+                    "User-mode networking can't be used with a tap, fds or vhost-user"
+                )
+            }
+            UserNetQueues => {
+                write!(f, "User-mode networking supports a single queue pair")
+            }
+            UserNetNoGuestAddress => {
+                write!(
+                    f,
+                    "No address left for the guest after the gateway address in the network"
+                )
+            }
+            HostForwardWithoutUserNet => {
+                write!(f, "Host forwards provided without user-mode networking")
+            }
+            HostLoopbackWithoutUserNet => {
+                write!(f, "Host loopback enabled without user-mode networking")
+            }
+            L2SocketAndBackend => {
+                write!(
+                    f,
+                    "A single stream, seqpacket or dgram socket can be used, without a tap, fds, \
+                    vhost-user or user-mode networking"
+                )
+            }
+            L2SocketQueues => {
+                write!(f, "Socket networking supports a single queue pair")
+            }
+            L2SocketServerWithoutStream => {
+                write!(
+                    f,
+                    "Server mode provided without a stream or seqpacket socket"
+                )
+            }
+            L2SocketDgramLocal => {
+                write!(
+                    f,
+                    "The local address of the dgram socket is missing or doesn't match its remote \
+                    address"
+                )
+            }
+            NetUnknownId(s) => write!(f, "No network device {}", s),
+            CaptureVhostUser => {
+                write!(f, "Frames of vhost-user devices can't be captured")
+            }
+            CaptureOptionsWithoutPath => {
+                write!(f, "Capture options provided without a capture file")
+            }
+            CaptureFiles => {
+                write!(
+                    f,
+                    "Capture files require a capture file size, and keep at least one file"
+                )
+            }
         }
     }
 }
@@ -298,6 +532,9 @@ impl fmt::Display for Error {
             ParseMemoryZone(o) => write!(f, "Error parsing --memory-zone: {}", o),
             ParseMemoryZoneIdMissing => write!(f, "Error parsing --memory-zone: id missing"),
             ParseNetwork(o) => write!(f, "Error parsing --net: {}", o),
+            ParseNetworkHostForward(o) => {
+                write!(f, "Error parsing --net: invalid host forward {}", o)
+            }
             ParseDisk(o) => write!(f, "Error parsing --disk: {}", o),
             ParseRng(o) => write!(f, "Error parsing --rng: {}", o),
             ParseBalloon(o) => write!(f, "Error parsing --balloon: {}", o),
@@ -322,6 +559,15 @@ impl fmt::Display for Error {
             ParsePlatform(o) => write!(f, "Error parsing --platform: {}", o),
             ParseVdpa(o) => write!(f, "Error parsing --vdpa: {}", o),
             ParseVdpaPathMissing => write!(f, "Error parsing --vdpa: path missing"),
+            ParseScsi(o) => write!(f, "Error parsing --scsi: {}", o),
+            ParseScsiLun(o) => write!(f, "Error parsing --scsi-lun: {}", o),
+            ParseScsiLunControllerMissing => {
+                write!(f, "Error parsing --scsi-lun: controller missing")
+            }
+            ParseRateLimitGroup(o) => write!(f, "Error parsing --rate-limit-group: {}", o),
+            ParseRateLimitGroupIdMissing => {
+                write!(f, "Error parsing --rate-limit-group: id missing")
+            }
         }
     }
 }
@@ -343,6 +589,7 @@ pub struct VmParams<'a> {
     pub kernel: Option<&'a str>,
     pub initramfs: Option<&'a str>,
     pub cmdline: Option<&'a str>,
+    pub rate_limit_groups: Option<Vec<&'a str>>,
     pub disks: Option<Vec<&'a str>>,
     pub net: Option<Vec<&'a str>>,
     pub rng: &'a str,
@@ -354,6 +601,8 @@ pub struct VmParams<'a> {
     pub devices: Option<Vec<&'a str>>,
     pub user_devices: Option<Vec<&'a str>>,
     pub vdpa: Option<Vec<&'a str>>,
+    pub scsi: Option<Vec<&'a str>>,
+    pub scsi_luns: Option<Vec<&'a str>>,
     pub vsock: Option<&'a str>,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
@@ -379,6 +628,8 @@ impl<'a> VmParams<'a> {
         let initramfs = args.value_of("initramfs");
         let cmdline = args.value_of("cmdline");
 
+        let rate_limit_groups: Option<Vec<&str>> =
+            args.values_of("rate-limit-group").map(|x| x.collect());
         let disks: Option<Vec<&str>> = args.values_of("disk").map(|x| x.collect());
         let net: Option<Vec<&str>> = args.values_of("net").map(|x| x.collect());
         let console = args.value_of("console").unwrap();
@@ -388,6 +639,8 @@ impl<'a> VmParams<'a> {
         let devices: Option<Vec<&str>> = args.values_of("device").map(|x| x.collect());
         let user_devices: Option<Vec<&str>> = args.values_of("user-device").map(|x| x.collect());
         let vdpa: Option<Vec<&str>> = args.values_of("vdpa").map(|x| x.collect());
+        let scsi: Option<Vec<&str>> = args.values_of("scsi").map(|x| x.collect());
+        let scsi_luns: Option<Vec<&str>> = args.values_of("scsi-lun").map(|x| x.collect());
         let vsock: Option<&str> = args.value_of("vsock");
         #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args.values_of("sgx-epc").map(|x| x.collect());
@@ -405,6 +658,7 @@ impl<'a> VmParams<'a> {
             kernel,
             initramfs,
             cmdline,
+            rate_limit_groups,
             disks,
             net,
             rng,
@@ -416,6 +670,8 @@ impl<'a> VmParams<'a> {
             devices,
             user_devices,
             vdpa,
+            scsi,
+            scsi_luns,
             vsock,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
@@ -711,6 +967,12 @@ pub struct MemoryZoneConfig {
     pub hotplugged_size: Option<u64>,
     #[serde(default)]
     pub prefault: bool,
+    #[serde(default = "default_lock")]
+    pub lock: bool,
+}
+
+fn default_lock() -> bool {
+    true
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -808,7 +1070,8 @@ impl MemoryConfig {
                     .add("host_numa_node")
                     .add("hotplug_size")
                     .add("hotplugged_size")
-                    .add("prefault");
+                    .add("prefault")
+                    .add("lock");
                 parser.parse(memory_zone).map_err(Error::ParseMemoryZone)?;
 
                 let id = parser.get("id").ok_or(Error::ParseMemoryZoneIdMissing)?;
@@ -849,6 +1112,11 @@ impl MemoryConfig {
                     .map_err(Error::ParseMemoryZone)?
                     .unwrap_or(Toggle(false))
                     .0;
+                let lock = parser
+                    .convert::<Toggle>("lock")
+                    .map_err(Error::ParseMemoryZone)?
+                    .unwrap_or_else(|| Toggle(default_lock()))
+                    .0;
 
                 zones.push(MemoryZoneConfig {
                     id,
@@ -861,6 +1129,7 @@ impl MemoryConfig {
                     hotplug_size,
                     hotplugged_size,
                     prefault,
+                    lock,
                 });
             }
             Some(zones)
@@ -943,6 +1212,117 @@ impl CmdlineConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RateLimitGroupConfig {
+    pub id: String,
+    pub rate_limiter_config: RateLimiterConfig,
+}
+
+impl RateLimitGroupConfig {
+    pub const SYNTAX: &'static str = "Rate limit group parameters \
+         \"id=<group_id>,bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
+         ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>\"";
+
+    pub fn parse(rate_limit_group: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("id")
+            .add("bw_size")
+            .add("bw_one_time_burst")
+            .add("bw_refill_time")
+            .add("ops_size")
+            .add("ops_one_time_burst")
+            .add("ops_refill_time");
+        parser
+            .parse(rate_limit_group)
+            .map_err(Error::ParseRateLimitGroup)?;
+
+        let id = parser
+            .get("id")
+            .ok_or(Error::ParseRateLimitGroupIdMissing)?;
+        let bw_size = parser
+            .convert("bw_size")
+            .map_err(Error::ParseRateLimitGroup)?
+            .unwrap_or_default();
+        let bw_one_time_burst = parser
+            .convert("bw_one_time_burst")
+            .map_err(Error::ParseRateLimitGroup)?
+            .unwrap_or_default();
+        let bw_refill_time = parser
+            .convert("bw_refill_time")
+            .map_err(Error::ParseRateLimitGroup)?
+            .unwrap_or_default();
+        let ops_size = parser
+            .convert("ops_size")
+            .map_err(Error::ParseRateLimitGroup)?
+            .unwrap_or_default();
+        let ops_one_time_burst = parser
+            .convert("ops_one_time_burst")
+            .map_err(Error::ParseRateLimitGroup)?
+            .unwrap_or_default();
+        let ops_refill_time = parser
+            .convert("ops_refill_time")
+            .map_err(Error::ParseRateLimitGroup)?
+            .unwrap_or_default();
+        let bw_tb_config = if bw_size != 0 && bw_refill_time != 0 {
+            Some(TokenBucketConfig {
+                size: bw_size,
+                one_time_burst: Some(bw_one_time_burst),
+                refill_time: bw_refill_time,
+            })
+        } else {
+            None
+        };
+        let ops_tb_config = if ops_size != 0 && ops_refill_time != 0 {
+            Some(TokenBucketConfig {
+                size: ops_size,
+                one_time_burst: Some(ops_one_time_burst),
+                refill_time: ops_refill_time,
+            })
+        } else {
+            None
+        };
+
+        Ok(RateLimitGroupConfig {
+            id,
+            rate_limiter_config: RateLimiterConfig {
+                bandwidth: bw_tb_config,
+                ops: ops_tb_config,
+            },
+        })
+    }
+}
+
+fn validate_rate_limit_group(
+    rate_limit_group: &Option<String>,
+    rate_limiter_config: &Option<RateLimiterConfig>,
+    vhost_user: bool,
+    vm_config: &VmConfig,
+) -> ValidationResult<()> {
+    if let Some(rate_limit_group) = rate_limit_group {
+        if rate_limiter_config.is_some() {
+            return Err(ValidationError::RateLimitGroupAndConfig);
+        }
+
+        if vhost_user {
+            return Err(ValidationError::RateLimitGroupVhostUser);
+        }
+
+        if !vm_config
+            .rate_limit_groups
+            .iter()
+            .flatten()
+            .any(|group| &group.id == rate_limit_group)
+        {
+            return Err(ValidationError::RateLimitGroupUnknown(
+                rate_limit_group.clone(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct DiskConfig {
     pub path: Option<PathBuf>,
@@ -964,12 +1344,41 @@ pub struct DiskConfig {
     #[serde(default)]
     pub rate_limiter_config: Option<RateLimiterConfig>,
     #[serde(default)]
+    pub rate_limit_group: Option<String>,
+    #[serde(default)]
     pub id: Option<String>,
     // For testing use only. Not exposed in API.
     #[serde(default)]
     pub disable_io_uring: bool,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub backing_files: bool,
+    #[serde(default)]
+    pub backing_file: Option<PathBuf>,
+    #[serde(default)]
+    pub internal_snapshot: bool,
+    #[serde(default)]
+    pub discard: bool,
+    #[serde(default)]
+    pub repair: bool,
+    #[serde(default)]
+    pub dirty_bitmap: Option<PathBuf>,
+    #[serde(default)]
+    pub nbd: Option<String>,
+    #[serde(default)]
+    pub nbd_export: Option<String>,
+    #[serde(default)]
+    pub rerror: ErrorPolicy,
+    #[serde(default)]
+    pub werror: ErrorPolicy,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    // Not exposed in the API, the file descriptor is sent along the request.
+    #[serde(default)]
+    pub key_fd: Option<i32>,
+    #[serde(default = "default_lock")]
+    pub lock: bool,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -999,7 +1408,21 @@ impl Default for DiskConfig {
             id: None,
             disable_io_uring: false,
             rate_limiter_config: None,
+            rate_limit_group: None,
             pci_segment: 0,
+            backing_files: false,
+            backing_file: None,
+            internal_snapshot: false,
+            discard: false,
+            repair: false,
+            dirty_bitmap: None,
+            nbd: None,
+            nbd_export: None,
+            rerror: ErrorPolicy::default(),
+            werror: ErrorPolicy::default(),
+            key_file: None,
+            key_fd: None,
+            lock: default_lock(),
         }
     }
 }
@@ -1011,7 +1434,12 @@ impl DiskConfig {
          vhost_user=on|off,socket=<vhost_user_socket_path>,poll_queue=on|off,\
          bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
          ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
-         id=<device_id>,pci_segment=<segment_id>\"";
+         rate_limit_group=<group_id>,id=<device_id>,pci_segment=<segment_id>,backing_files=on|off,\
+         backing_file=<backing_file_path>,internal_snapshot=on|off,\
+         discard=on|off,repair=on|off,dirty_bitmap=<dirty_bitmap_path>,\
+         nbd=unix:<socket_path>|tcp:<host>:<port>,export=<nbd_export_name>,\
+         rerror=report|stop|ignore,werror=report|stop|ignore,\
+         key_file=<luks_passphrase_path>,key_fd=<luks_passphrase_fd>,lock=on|off\"";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1031,9 +1459,23 @@ impl DiskConfig {
             .add("ops_size")
             .add("ops_one_time_burst")
             .add("ops_refill_time")
+            .add("rate_limit_group")
             .add("id")
             .add("_disable_io_uring")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("backing_files")
+            .add("backing_file")
+            .add("internal_snapshot")
+            .add("discard")
+            .add("repair")
+            .add("dirty_bitmap")
+            .add("nbd")
+            .add("export")
+            .add("rerror")
+            .add("werror")
+            .add("key_file")
+            .add("key_fd")
+            .add("lock");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
         let path = parser.get("path").map(PathBuf::from);
@@ -1071,6 +1513,7 @@ impl DiskConfig {
             .map_err(Error::ParseDisk)?
             .unwrap_or_else(|| Toggle(default_diskconfig_poll_queue()))
             .0;
+        let rate_limit_group = parser.get("rate_limit_group");
         let id = parser.get("id");
         let disable_io_uring = parser
             .convert::<Toggle>("_disable_io_uring")
@@ -1081,6 +1524,45 @@ impl DiskConfig {
             .convert("pci_segment")
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
+        let backing_files = parser
+            .convert::<Toggle>("backing_files")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let backing_file = parser.get("backing_file").map(PathBuf::from);
+        let internal_snapshot = parser
+            .convert::<Toggle>("internal_snapshot")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let discard = parser
+            .convert::<Toggle>("discard")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let repair = parser
+            .convert::<Toggle>("repair")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let dirty_bitmap = parser.get("dirty_bitmap").map(PathBuf::from);
+        let nbd = parser.get("nbd");
+        let nbd_export = parser.get("export");
+        let rerror = parser
+            .convert("rerror")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let werror = parser
+            .convert("werror")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let key_file = parser.get("key_file").map(PathBuf::from);
+        let key_fd = parser.convert("key_fd").map_err(Error::ParseDisk)?;
+        let lock = parser
+            .convert::<Toggle>("lock")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_else(|| Toggle(default_lock()))
+            .0;
         let bw_size = parser
             .convert("bw_size")
             .map_err(Error::ParseDisk)?
@@ -1147,9 +1629,23 @@ impl DiskConfig {
             vhost_socket,
             poll_queue,
             rate_limiter_config,
+            rate_limit_group,
             id,
             disable_io_uring,
             pci_segment,
+            backing_files,
+            backing_file,
+            internal_snapshot,
+            discard,
+            repair,
+            dirty_bitmap,
+            nbd,
+            nbd_export,
+            rerror,
+            werror,
+            key_file,
+            key_fd,
+            lock,
         })
     }
 
@@ -1158,10 +1654,81 @@ impl DiskConfig {
             return Err(ValidationError::TooManyQueues);
         }
 
+        if self.backing_file.is_some() && !self.backing_files {
+            return Err(ValidationError::BackingFileOverrideWithoutBackingFiles);
+        }
+
+        if self.internal_snapshot && self.readonly {
+            return Err(ValidationError::InternalSnapshotReadOnly);
+        }
+
+        if self.internal_snapshot && self.vhost_user {
+            return Err(ValidationError::InternalSnapshotVhostUser);
+        }
+
+        if self.repair && self.readonly {
+            return Err(ValidationError::RepairReadOnly);
+        }
+
+        if self.repair && self.vhost_user {
+            return Err(ValidationError::RepairVhostUser);
+        }
+
+        if self.dirty_bitmap.is_some() && self.vhost_user {
+            return Err(ValidationError::DirtyBitmapVhostUser);
+        }
+
         if self.vhost_user && self.iommu {
             return Err(ValidationError::IommuNotSupported);
         }
 
+        if self.nbd.is_some() && self.path.is_some() {
+            return Err(ValidationError::DiskNbdAndPath);
+        }
+
+        if self.nbd.is_some() && self.vhost_user {
+            return Err(ValidationError::NbdVhostUser);
+        }
+
+        if self.nbd_export.is_some() && self.nbd.is_none() {
+            return Err(ValidationError::NbdExportWithoutServer);
+        }
+
+        if self.vhost_user
+            && (self.rerror != ErrorPolicy::default() || self.werror != ErrorPolicy::default())
+        {
+            return Err(ValidationError::ErrorPolicyVhostUser);
+        }
+
+        if self.key_file.is_some() || self.key_fd.is_some() {
+            if self.key_file.is_some() && self.key_fd.is_some() {
+                return Err(ValidationError::DiskKeyFileAndFd);
+            }
+
+            if matches!(self.key_fd, Some(fd) if fd <= 2) {
+                return Err(ValidationError::DiskKeyReservedFd);
+            }
+
+            if self.vhost_user {
+                return Err(ValidationError::DiskKeyVhostUser);
+            }
+
+            if self.nbd.is_some() {
+                return Err(ValidationError::DiskKeyNbd);
+            }
+
+            if self.discard {
+                return Err(ValidationError::DiskKeyDiscard);
+            }
+        }
+
+        validate_rate_limit_group(
+            &self.rate_limit_group,
+            &self.rate_limiter_config,
+            self.vhost_user,
+            vm_config,
+        )?;
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1176,6 +1743,21 @@ impl DiskConfig {
 
         Ok(())
     }
+
+    /// Replaces the media of the disk with the image at `path`, or ejects it
+    /// when `path` is `None`, keeping the read-only flag unless `readonly`
+    /// is given. The new media is a plain image, served neither over NBD nor
+    /// encrypted.
+    pub fn change_media(&mut self, path: Option<PathBuf>, readonly: Option<bool>) {
+        self.path = path;
+        self.readonly = readonly.unwrap_or(self.readonly);
+        self.nbd = None;
+        self.nbd_export = None;
+        self.backing_file = None;
+        self.repair = false;
+        self.key_file = None;
+        self.key_fd = None;
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -1237,7 +1819,33 @@ pub struct NetConfig {
     #[serde(default)]
     pub rate_limiter_config: Option<RateLimiterConfig>,
     #[serde(default)]
+    pub rate_limit_group: Option<String>,
+    #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub user: bool,
+    #[serde(default)]
+    pub host_forwards: Option<Vec<HostForward>>,
+    #[serde(default)]
+    pub host_loopback: bool,
+    #[serde(default)]
+    pub stream: Option<L2SocketAddr>,
+    #[serde(default)]
+    pub seqpacket: Option<PathBuf>,
+    #[serde(default)]
+    pub dgram: Option<L2SocketAddr>,
+    #[serde(default)]
+    pub dgram_local: Option<L2SocketAddr>,
+    #[serde(default)]
+    pub server: bool,
+    #[serde(default)]
+    pub capture: Option<PathBuf>,
+    #[serde(default)]
+    pub capture_snaplen: Option<u32>,
+    #[serde(default)]
+    pub capture_file_size: Option<u64>,
+    #[serde(default)]
+    pub capture_files: Option<u32>,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -1281,7 +1889,20 @@ impl Default for NetConfig {
             id: None,
             fds: None,
             rate_limiter_config: None,
+            rate_limit_group: None,
             pci_segment: 0,
+            user: false,
+            host_forwards: None,
+            host_loopback: false,
+            stream: None,
+            seqpacket: None,
+            dgram: None,
+            dgram_local: None,
+            server: false,
+            capture: None,
+            capture_snaplen: None,
+            capture_file_size: None,
+            capture_files: None,
         }
     }
 }
@@ -1292,7 +1913,13 @@ impl NetConfig {
     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,id=<device_id>,\
     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,vhost_mode=client|server,\
     bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
-    ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,pci_segment=<segment_id>\"";
+    ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
+    rate_limit_group=<group_id>,pci_segment=<segment_id>,user=on|off,\
+    hostfwd=[<[tcp|udp]:[host_addr]:host_port-[guest_addr]:guest_port>,...],host_loopback=on|off,\
+    stream=unix:<socket_path>|<ip>:<port>,seqpacket=<socket_path>,\
+    dgram=unix:<socket_path>|<ip>:<port>,dgram_local=unix:<socket_path>|<ip>:<port>,\
+    server=on|off,capture=<capture_path>,capture_snaplen=<bytes>,capture_file_size=<bytes>,\
+    capture_files=<number_of_files>\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1317,7 +1944,20 @@ impl NetConfig {
             .add("ops_size")
             .add("ops_one_time_burst")
             .add("ops_refill_time")
-            .add("pci_segment");
+            .add("rate_limit_group")
+            .add("pci_segment")
+            .add("user")
+            .add("hostfwd")
+            .add("host_loopback")
+            .add("stream")
+            .add("seqpacket")
+            .add("dgram")
+            .add("dgram_local")
+            .add("server")
+            .add("capture")
+            .add("capture_snaplen")
+            .add("capture_file_size")
+            .add("capture_files");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
@@ -1416,6 +2056,49 @@ impl NetConfig {
         } else {
             None
         };
+        let rate_limit_group = parser.get("rate_limit_group");
+        let user = parser
+            .convert::<Toggle>("user")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let host_forwards = parser
+            .convert::<StringList>("hostfwd")
+            .map_err(Error::ParseNetwork)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|s| {
+                        s.parse()
+                            .map_err(|_| Error::ParseNetworkHostForward(s.to_owned()))
+                    })
+                    .collect::<Result<Vec<HostForward>>>()
+            })
+            .transpose()?;
+        let host_loopback = parser
+            .convert::<Toggle>("host_loopback")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let stream = parser.convert("stream").map_err(Error::ParseNetwork)?;
+        let seqpacket = parser.get("seqpacket").map(PathBuf::from);
+        let dgram = parser.convert("dgram").map_err(Error::ParseNetwork)?;
+        let dgram_local = parser.convert("dgram_local").map_err(Error::ParseNetwork)?;
+        let server = parser
+            .convert::<Toggle>("server")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let capture = parser.get("capture").map(PathBuf::from);
+        let capture_snaplen = parser
+            .convert("capture_snaplen")
+            .map_err(Error::ParseNetwork)?;
+        let capture_file_size = parser
+            .convert::<ByteSized>("capture_file_size")
+            .map_err(Error::ParseNetwork)?
+            .map(|v| v.0);
+        let capture_files = parser
+            .convert("capture_files")
+            .map_err(Error::ParseNetwork)?;
 
         let config = NetConfig {
             tap,
@@ -1432,7 +2115,20 @@ impl NetConfig {
             id,
             fds,
             rate_limiter_config,
+            rate_limit_group,
             pci_segment,
+            user,
+            host_forwards,
+            host_loopback,
+            stream,
+            seqpacket,
+            dgram,
+            dgram_local,
+            server,
+            capture,
+            capture_snaplen,
+            capture_file_size,
+            capture_files,
         };
         Ok(config)
     }
@@ -1462,24 +2158,156 @@ impl NetConfig {
             return Err(ValidationError::IommuNotSupported);
         }
 
-        if let Some(platform_config) = vm_config.platform.as_ref() {
-            if self.pci_segment >= platform_config.num_pci_segments {
-                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+        if self.user {
+            if self.tap.is_some() || self.fds.is_some() || self.vhost_user {
+                return Err(ValidationError::UserNetAndBackend);
             }
-
-            if let Some(iommu_segments) = platform_config.iommu_segments.as_ref() {
-                if iommu_segments.contains(&self.pci_segment) && !self.iommu {
-                    return Err(ValidationError::OnIommuSegment(self.pci_segment));
-                }
+            if self.num_queues != 2 {
+                return Err(ValidationError::UserNetQueues);
+            }
+            if self.user_net_config().guest_addr().is_none() {
+                return Err(ValidationError::UserNetNoGuestAddress);
             }
+        } else if self.host_forwards.is_some() {
+            return Err(ValidationError::HostForwardWithoutUserNet);
+        } else if self.host_loopback {
+            return Err(ValidationError::HostLoopbackWithoutUserNet);
         }
 
-        Ok(())
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct RngConfig {
+        let l2_sockets = [
+            self.stream.is_some(),
+            self.seqpacket.is_some(),
+            self.dgram.is_some(),
+        ];
+        match l2_sockets.iter().filter(|s| **s).count() {
+            0 => {
+                if self.server {
+                    return Err(ValidationError::L2SocketServerWithoutStream);
+                }
+                if self.dgram_local.is_some() {
+                    return Err(ValidationError::L2SocketDgramLocal);
+                }
+            }
+            1 => {
+                if self.tap.is_some() || self.fds.is_some() || self.vhost_user || self.user {
+                    return Err(ValidationError::L2SocketAndBackend);
+                }
+                if self.num_queues != 2 {
+                    return Err(ValidationError::L2SocketQueues);
+                }
+                if self.server && self.dgram.is_some() {
+                    return Err(ValidationError::L2SocketServerWithoutStream);
+                }
+                match (&self.dgram, &self.dgram_local) {
+                    (None, Some(_))
+                    | (Some(L2SocketAddr::Unix(_)), None)
+                    | (Some(L2SocketAddr::Unix(_)), Some(L2SocketAddr::Inet(_)))
+                    | (Some(L2SocketAddr::Inet(_)), Some(L2SocketAddr::Unix(_))) => {
+                        return Err(ValidationError::L2SocketDgramLocal)
+                    }
+                    (Some(L2SocketAddr::Inet(remote)), Some(L2SocketAddr::Inet(local)))
+                        if remote.is_ipv4() != local.is_ipv4() =>
+                    {
+                        return Err(ValidationError::L2SocketDgramLocal)
+                    }
+                    _ => {}
+                }
+            }
+            _ => return Err(ValidationError::L2SocketAndBackend),
+        }
+
+        if self.capture.is_some() && self.vhost_user {
+            return Err(ValidationError::CaptureVhostUser);
+        }
+
+        if self.capture.is_none()
+            && (self.capture_snaplen.is_some()
+                || self.capture_file_size.is_some()
+                || self.capture_files.is_some())
+        {
+            return Err(ValidationError::CaptureOptionsWithoutPath);
+        }
+
+        if let Some(capture_files) = self.capture_files {
+            if capture_files == 0 || self.capture_file_size.is_none() {
+                return Err(ValidationError::CaptureFiles);
+            }
+        }
+
+        validate_rate_limit_group(
+            &self.rate_limit_group,
+            &self.rate_limiter_config,
+            self.vhost_user,
+            vm_config,
+        )?;
+
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+
+            if let Some(iommu_segments) = platform_config.iommu_segments.as_ref() {
+                if iommu_segments.contains(&self.pci_segment) && !self.iommu {
+                    return Err(ValidationError::OnIommuSegment(self.pci_segment));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Network of the user-mode stack, behind the gateway at `ip`.
+    pub fn user_net_config(&self) -> UserNetConfig {
+        UserNetConfig {
+            gateway: self.ip,
+            netmask: self.mask,
+            host_forwards: self.host_forwards.clone().unwrap_or_default(),
+            host_loopback: self.host_loopback,
+        }
+    }
+
+    /// Capture of the frames of the device, if any.
+    pub fn capture_config(&self) -> Option<CaptureConfig> {
+        self.capture.as_ref().map(|path| CaptureConfig {
+            path: path.clone(),
+            snaplen: self.capture_snaplen,
+            file_size: self.capture_file_size,
+            files: self.capture_files,
+        })
+    }
+
+    /// Replaces the capture of the frames of the device, stopping it when
+    /// `capture` is `None`.
+    pub fn set_capture(&mut self, capture: Option<CaptureConfig>) {
+        self.capture = capture.as_ref().map(|capture| capture.path.clone());
+        self.capture_snaplen = capture.as_ref().and_then(|capture| capture.snaplen);
+        self.capture_file_size = capture.as_ref().and_then(|capture| capture.file_size);
+        self.capture_files = capture.as_ref().and_then(|capture| capture.files);
+    }
+
+    /// Socket to the peer replacing the tap, if any.
+    pub fn l2_socket_config(&self) -> Option<L2SocketConfig> {
+        if let Some(addr) = &self.stream {
+            Some(L2SocketConfig::Stream {
+                addr: addr.clone(),
+                server: self.server,
+            })
+        } else if let Some(path) = &self.seqpacket {
+            Some(L2SocketConfig::SeqPacket {
+                path: path.clone(),
+                server: self.server,
+            })
+        } else {
+            self.dgram.as_ref().map(|remote| L2SocketConfig::Dgram {
+                remote: remote.clone(),
+                local: self.dgram_local.clone(),
+            })
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RngConfig {
     pub src: PathBuf,
     #[serde(default)]
     pub iommu: bool,
@@ -1667,7 +2495,7 @@ impl FsConfig {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct PmemConfig {
     pub file: PathBuf,
     #[serde(default)]
@@ -1680,12 +2508,28 @@ pub struct PmemConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default = "default_lock")]
+    pub lock: bool,
+}
+
+impl Default for PmemConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::new(),
+            size: None,
+            iommu: false,
+            discard_writes: false,
+            id: None,
+            pci_segment: 0,
+            lock: default_lock(),
+        }
+    }
 }
 
 impl PmemConfig {
     pub const SYNTAX: &'static str = "Persistent memory parameters \
     \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
-    discard_writes=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+    discard_writes=on|off,id=<device_id>,pci_segment=<segment_id>,lock=on|off\"";
     pub fn parse(pmem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
@@ -1694,7 +2538,8 @@ impl PmemConfig {
             .add("iommu")
             .add("discard_writes")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("lock");
         parser.parse(pmem).map_err(Error::ParsePersistentMemory)?;
 
         let file = PathBuf::from(parser.get("file").ok_or(Error::ParsePmemFileMissing)?);
@@ -1717,6 +2562,11 @@ impl PmemConfig {
             .convert("pci_segment")
             .map_err(Error::ParsePersistentMemory)?
             .unwrap_or_default();
+        let lock = parser
+            .convert::<Toggle>("lock")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or_else(|| Toggle(default_lock()))
+            .0;
 
         Ok(PmemConfig {
             file,
@@ -1725,6 +2575,7 @@ impl PmemConfig {
             discard_writes,
             id,
             pci_segment,
+            lock,
         })
     }
 
@@ -2012,6 +2863,242 @@ impl VdpaConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct ScsiConfig {
+    #[serde(default = "default_scsiconfig_num_queues")]
+    pub num_queues: usize,
+    #[serde(default = "default_scsiconfig_queue_size")]
+    pub queue_size: u16,
+    #[serde(default)]
+    pub initiator: Option<u64>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+fn default_scsiconfig_num_queues() -> usize {
+    1
+}
+
+fn default_scsiconfig_queue_size() -> u16 {
+    128
+}
+
+impl ScsiConfig {
+    pub const SYNTAX: &'static str = "virtio-scsi controller \
+        \"num_queues=<number_of_request_queues>,queue_size=<size_of_each_queue>,\
+        initiator=<persistent_reservations_initiator_id>,iommu=on|off,\
+        id=<device_id>,pci_segment=<segment_id>\"";
+    pub fn parse(scsi: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("num_queues")
+            .add("queue_size")
+            .add("initiator")
+            .add("iommu")
+            .add("id")
+            .add("pci_segment");
+        parser.parse(scsi).map_err(Error::ParseScsi)?;
+
+        let num_queues = parser
+            .convert("num_queues")
+            .map_err(Error::ParseScsi)?
+            .unwrap_or_else(default_scsiconfig_num_queues);
+        let queue_size = parser
+            .convert("queue_size")
+            .map_err(Error::ParseScsi)?
+            .unwrap_or_else(default_scsiconfig_queue_size);
+        let initiator = parser.convert("initiator").map_err(Error::ParseScsi)?;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseScsi)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseScsi)?
+            .unwrap_or_default();
+
+        Ok(ScsiConfig {
+            num_queues,
+            queue_size,
+            initiator,
+            iommu,
+            id,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.num_queues > vm_config.cpus.boot_vcpus as usize {
+            return Err(ValidationError::TooManyQueues);
+        }
+
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+
+            if let Some(iommu_segments) = platform_config.iommu_segments.as_ref() {
+                if iommu_segments.contains(&self.pci_segment) && !self.iommu {
+                    return Err(ValidationError::OnIommuSegment(self.pci_segment));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ScsiLunConfig {
+    pub controller: String,
+    #[serde(default)]
+    pub target: u8,
+    #[serde(default)]
+    pub lun: u16,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub readonly: bool,
+    #[serde(default)]
+    pub direct: bool,
+    #[serde(default)]
+    pub cdrom: bool,
+    #[serde(default)]
+    pub discard: bool,
+    #[serde(default)]
+    pub reservations: Option<PathBuf>,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default = "default_lock")]
+    pub lock: bool,
+}
+
+impl Default for ScsiLunConfig {
+    fn default() -> Self {
+        Self {
+            controller: String::new(),
+            target: 0,
+            lun: 0,
+            path: None,
+            readonly: false,
+            direct: false,
+            cdrom: false,
+            discard: false,
+            reservations: None,
+            id: None,
+            lock: default_lock(),
+        }
+    }
+}
+
+impl ScsiLunConfig {
+    pub const SYNTAX: &'static str = "SCSI logical unit \
+        \"controller=<controller_id>,target=<target_number>,lun=<lun_number>,\
+        path=<disk_image_path>,readonly=on|off,direct=on|off,cdrom=on|off,\
+        discard=on|off,reservations=<persistent_reservations_state_file>,\
+        id=<device_id>,lock=on|off\"";
+    pub fn parse(scsi_lun: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("controller")
+            .add("target")
+            .add("lun")
+            .add("path")
+            .add("readonly")
+            .add("direct")
+            .add("cdrom")
+            .add("discard")
+            .add("reservations")
+            .add("id")
+            .add("lock");
+        parser.parse(scsi_lun).map_err(Error::ParseScsiLun)?;
+
+        let controller = parser
+            .get("controller")
+            .ok_or(Error::ParseScsiLunControllerMissing)?;
+        let target = parser
+            .convert("target")
+            .map_err(Error::ParseScsiLun)?
+            .unwrap_or_default();
+        let lun = parser
+            .convert("lun")
+            .map_err(Error::ParseScsiLun)?
+            .unwrap_or_default();
+        let path = parser.get("path").map(PathBuf::from);
+        let readonly = parser
+            .convert::<Toggle>("readonly")
+            .map_err(Error::ParseScsiLun)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let direct = parser
+            .convert::<Toggle>("direct")
+            .map_err(Error::ParseScsiLun)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let cdrom = parser
+            .convert::<Toggle>("cdrom")
+            .map_err(Error::ParseScsiLun)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let discard = parser
+            .convert::<Toggle>("discard")
+            .map_err(Error::ParseScsiLun)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let reservations = parser.get("reservations").map(PathBuf::from);
+        let id = parser.get("id");
+        let lock = parser
+            .convert::<Toggle>("lock")
+            .map_err(Error::ParseScsiLun)?
+            .unwrap_or_else(|| Toggle(default_lock()))
+            .0;
+
+        Ok(ScsiLunConfig {
+            controller,
+            target,
+            lun,
+            path,
+            readonly,
+            direct,
+            cdrom,
+            discard,
+            reservations,
+            id,
+            lock,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if !vm_config
+            .scsi
+            .iter()
+            .flatten()
+            .any(|scsi| scsi.id.as_ref() == Some(&self.controller))
+        {
+            return Err(ValidationError::ScsiControllerUnknown(
+                self.controller.clone(),
+            ));
+        }
+
+        if self.lun > SCSI_MAX_LUN {
+            return Err(ValidationError::ScsiLunOutOfRange(self.lun));
+        }
+
+        // Only CD-ROM drives can be left without medium.
+        if !self.cdrom && self.path.is_none() {
+            return Err(ValidationError::ScsiLunPathMissing);
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct VsockConfig {
     pub cid: u64,
@@ -2259,6 +3346,7 @@ pub struct VmConfig {
     pub initramfs: Option<InitramfsConfig>,
     #[serde(default)]
     pub cmdline: CmdlineConfig,
+    pub rate_limit_groups: Option<Vec<RateLimitGroupConfig>>,
     pub disks: Option<Vec<DiskConfig>>,
     pub net: Option<Vec<NetConfig>>,
     #[serde(default)]
@@ -2273,6 +3361,8 @@ pub struct VmConfig {
     pub devices: Option<Vec<DeviceConfig>>,
     pub user_devices: Option<Vec<UserDeviceConfig>>,
     pub vdpa: Option<Vec<VdpaConfig>>,
+    pub scsi: Option<Vec<ScsiConfig>>,
+    pub scsi_luns: Option<Vec<ScsiLunConfig>>,
     pub vsock: Option<VsockConfig>,
     #[serde(default)]
     pub iommu: bool,
@@ -2306,6 +3396,128 @@ impl VmConfig {
         Ok(())
     }
 
+    /// Replaces the rate limiter of the disk, network device or rate limit
+    /// group `id`, or removes it when `rate_limiter_config` is `None`.
+    pub fn update_rate_limiter(
+        &mut self,
+        id: &str,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> ValidationResult<()> {
+        if let Some(rate_limit_group) = self
+            .rate_limit_groups
+            .iter_mut()
+            .flatten()
+            .find(|rate_limit_group| rate_limit_group.id == id)
+        {
+            rate_limit_group.rate_limiter_config = rate_limiter_config.unwrap_or_default();
+            return Ok(());
+        }
+
+        let (vhost_user, rate_limit_group, device_rate_limiter_config) = if let Some(disk) = self
+            .disks
+            .iter_mut()
+            .flatten()
+            .find(|disk| disk.id.as_deref() == Some(id))
+        {
+            (
+                disk.vhost_user,
+                &disk.rate_limit_group,
+                &mut disk.rate_limiter_config,
+            )
+        } else if let Some(net) = self
+            .net
+            .iter_mut()
+            .flatten()
+            .find(|net| net.id.as_deref() == Some(id))
+        {
+            (
+                net.vhost_user,
+                &net.rate_limit_group,
+                &mut net.rate_limiter_config,
+            )
+        } else {
+            return Err(ValidationError::RateLimiterUnknownId(id.to_owned()));
+        };
+
+        if vhost_user {
+            return Err(ValidationError::RateLimiterVhostUser);
+        }
+
+        if rate_limit_group.is_some() {
+            return Err(ValidationError::RateLimitGroupAndConfig);
+        }
+
+        // A limiter without any bucket is the same as no limiter
+        *device_rate_limiter_config = rate_limiter_config.filter(|rate_limiter_config| {
+            rate_limiter_config.bandwidth.is_some() || rate_limiter_config.ops.is_some()
+        });
+
+        Ok(())
+    }
+
+    /// Replaces the media of the disk `id` with the image at `path`, or
+    /// ejects it when `path` is `None`.
+    pub fn change_disk_media(
+        &mut self,
+        id: &str,
+        path: Option<PathBuf>,
+        readonly: Option<bool>,
+    ) -> ValidationResult<()> {
+        let mut disk = self
+            .disks
+            .iter()
+            .flatten()
+            .find(|disk| disk.id.as_deref() == Some(id))
+            .cloned()
+            .ok_or_else(|| ValidationError::DiskUnknownId(id.to_owned()))?;
+
+        if disk.vhost_user {
+            return Err(ValidationError::MediaChangeVhostUser);
+        }
+
+        if disk.dirty_bitmap.is_some() {
+            return Err(ValidationError::MediaChangeDirtyBitmap);
+        }
+
+        disk.change_media(path, readonly);
+        disk.validate(self)?;
+        for current_disk in self.disks.iter_mut().flatten() {
+            if current_disk.id.as_deref() == Some(id) {
+                *current_disk = disk;
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the capture of the frames of the network device `id`,
+    /// stopping it when `capture` is `None`.
+    pub fn capture_net(
+        &mut self,
+        id: &str,
+        capture: Option<CaptureConfig>,
+    ) -> ValidationResult<()> {
+        let mut net = self
+            .net
+            .iter()
+            .flatten()
+            .find(|net| net.id.as_deref() == Some(id))
+            .cloned()
+            .ok_or_else(|| ValidationError::NetUnknownId(id.to_owned()))?;
+
+        net.set_capture(capture);
+        net.validate(self)?;
+        for current_net in self.net.iter_mut().flatten() {
+            if current_net.id.as_deref() == Some(id) {
+                *current_net = net;
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
     // Also enables virtio-iommu if the config needs it
     // Returns the list of unique identifiers provided through the
     // configuration.
@@ -2343,6 +3555,17 @@ impl VmConfig {
             return Err(ValidationError::CpusMaxLowerThanBoot);
         }
 
+        if let Some(rate_limit_groups) = &self.rate_limit_groups {
+            let mut group_ids = BTreeSet::new();
+            for rate_limit_group in rate_limit_groups {
+                if !group_ids.insert(&rate_limit_group.id) {
+                    return Err(ValidationError::RateLimitGroupNotUnique(
+                        rate_limit_group.id.clone(),
+                    ));
+                }
+            }
+        }
+
         if let Some(disks) = &self.disks {
             for disk in disks {
                 if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
@@ -2449,17 +3672,42 @@ impl VmConfig {
             }
         }
 
-        if let Some(balloon) = &self.balloon {
-            let mut ram_size = self.memory.size;
+        if let Some(scsi_controllers) = &self.scsi {
+            for scsi in scsi_controllers {
+                scsi.validate(self)?;
+                self.iommu |= scsi.iommu;
 
-            if let Some(zones) = &self.memory.zones {
-                for zone in zones {
-                    ram_size += zone.size;
-                }
+                Self::validate_identifier(&mut id_list, &scsi.id)?;
             }
+        }
 
-            if balloon.size >= ram_size {
-                return Err(ValidationError::BalloonLargerThanRam(
+        if let Some(scsi_luns) = &self.scsi_luns {
+            let mut addresses = BTreeSet::new();
+            for scsi_lun in scsi_luns {
+                scsi_lun.validate(self)?;
+                if !addresses.insert((&scsi_lun.controller, scsi_lun.target, scsi_lun.lun)) {
+                    return Err(ValidationError::ScsiLunNotUnique(
+                        scsi_lun.controller.clone(),
+                        scsi_lun.target,
+                        scsi_lun.lun,
+                    ));
+                }
+
+                Self::validate_identifier(&mut id_list, &scsi_lun.id)?;
+            }
+        }
+
+        if let Some(balloon) = &self.balloon {
+            let mut ram_size = self.memory.size;
+
+            if let Some(zones) = &self.memory.zones {
+                for zone in zones {
+                    ram_size += zone.size;
+                }
+            }
+
+            if balloon.size >= ram_size {
+                return Err(ValidationError::BalloonLargerThanRam(
                     balloon.size,
                     ram_size,
                 ));
@@ -2526,6 +3774,15 @@ impl VmConfig {
     }
 
     pub fn parse(vm_params: VmParams) -> Result<Self> {
+        let mut rate_limit_groups: Option<Vec<RateLimitGroupConfig>> = None;
+        if let Some(rate_limit_group_list) = &vm_params.rate_limit_groups {
+            let mut rate_limit_group_config_list = Vec::new();
+            for item in rate_limit_group_list.iter() {
+                rate_limit_group_config_list.push(RateLimitGroupConfig::parse(item)?);
+            }
+            rate_limit_groups = Some(rate_limit_group_config_list);
+        }
+
         let mut disks: Option<Vec<DiskConfig>> = None;
         if let Some(disk_list) = &vm_params.disks {
             let mut disk_config_list = Vec::new();
@@ -2605,6 +3862,26 @@ impl VmConfig {
             vdpa = Some(vdpa_config_list);
         }
 
+        let mut scsi: Option<Vec<ScsiConfig>> = None;
+        if let Some(scsi_list) = &vm_params.scsi {
+            let mut scsi_config_list = Vec::new();
+            for item in scsi_list.iter() {
+                let scsi_config = ScsiConfig::parse(item)?;
+                scsi_config_list.push(scsi_config);
+            }
+            scsi = Some(scsi_config_list);
+        }
+
+        let mut scsi_luns: Option<Vec<ScsiLunConfig>> = None;
+        if let Some(scsi_lun_list) = &vm_params.scsi_luns {
+            let mut scsi_lun_config_list = Vec::new();
+            for item in scsi_lun_list.iter() {
+                let scsi_lun_config = ScsiLunConfig::parse(item)?;
+                scsi_lun_config_list.push(scsi_lun_config);
+            }
+            scsi_luns = Some(scsi_lun_config_list);
+        }
+
         let mut vsock: Option<VsockConfig> = None;
         if let Some(vs) = &vm_params.vsock {
             let vsock_config = VsockConfig::parse(vs)?;
@@ -2663,6 +3940,7 @@ impl VmConfig {
             kernel,
             initramfs,
             cmdline: CmdlineConfig::parse(vm_params.cmdline)?,
+            rate_limit_groups,
             disks,
             net,
             rng,
@@ -2674,6 +3952,8 @@ impl VmConfig {
             devices,
             user_devices,
             vdpa,
+            scsi,
+            scsi_luns,
             vsock,
             iommu: false, // updated in VmConfig::validate()
             #[cfg(target_arch = "x86_64")]
@@ -2917,6 +4197,99 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,backing_files=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                backing_files: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,backing_files=on,backing_file=/path/to_backing_file"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                backing_files: true,
+                backing_file: Some(PathBuf::from("/path/to_backing_file")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,internal_snapshot=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                internal_snapshot: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,discard=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                discard: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,repair=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                repair: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,dirty_bitmap=/path/to_bitmap")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                dirty_bitmap: Some(PathBuf::from("/path/to_bitmap")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("nbd=unix:/path/to/socket,export=disk0")?,
+            DiskConfig {
+                nbd: Some("unix:/path/to/socket".to_owned()),
+                nbd_export: Some("disk0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,werror=stop,rerror=ignore")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                rerror: ErrorPolicy::Ignore,
+                werror: ErrorPolicy::Stop,
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,werror=retry").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,key_file=/path/to_key")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                key_file: Some(PathBuf::from("/path/to_key")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,key_fd=3")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                key_fd: Some(3),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,lock=off")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                lock: false,
+                ..Default::default()
+            }
+        );
 
         Ok(())
     }
@@ -2992,6 +4365,69 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,user=on,hostfwd=[tcp::2222-:22,udp:127.0.0.1:5353-:53]"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                user: true,
+                host_forwards: Some(vec![
+                    "tcp::2222-:22".parse().unwrap(),
+                    "udp:127.0.0.1:5353-:53".parse().unwrap()
+                ]),
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("user=on,hostfwd=[tcp::2222]").is_err());
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,user=on,host_loopback=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                user: true,
+                host_loopback: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,stream=unix:/tmp/net.sock,server=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                stream: Some(L2SocketAddr::Unix(PathBuf::from("/tmp/net.sock"))),
+                server: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,dgram=230.0.0.1:5000,dgram_local=192.168.1.1:5000"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                dgram: Some(L2SocketAddr::Inet("230.0.0.1:5000".parse().unwrap())),
+                dgram_local: Some(L2SocketAddr::Inet("192.168.1.1:5000".parse().unwrap())),
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("stream=/tmp/net.sock").is_err());
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,capture=/tmp/net0.pcapng,capture_snaplen=128,\
+                capture_file_size=1M,capture_files=4"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                capture: Some(PathBuf::from("/tmp/net0.pcapng")),
+                capture_snaplen: Some(128),
+                capture_file_size: Some(1 << 20),
+                capture_files: Some(4),
+                ..Default::default()
+            }
+        );
+
         Ok(())
     }
 
@@ -3090,6 +4526,14 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,lock=off")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                lock: false,
+                ..Default::default()
+            }
+        );
 
         Ok(())
     }
@@ -3219,6 +4663,127 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_rate_limit_group_parsing() -> Result<()> {
+        // id is required
+        assert!(RateLimitGroupConfig::parse("bw_size=1000,bw_refill_time=100").is_err());
+        assert_eq!(
+            RateLimitGroupConfig::parse("id=group0,bw_size=1000,bw_refill_time=100")?,
+            RateLimitGroupConfig {
+                id: "group0".to_owned(),
+                rate_limiter_config: RateLimiterConfig {
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 1000,
+                        one_time_burst: Some(0),
+                        refill_time: 100,
+                    }),
+                    ops: None,
+                },
+            }
+        );
+        assert_eq!(
+            RateLimitGroupConfig::parse(
+                "id=group0,ops_size=10,ops_one_time_burst=5,ops_refill_time=1000"
+            )?,
+            RateLimitGroupConfig {
+                id: "group0".to_owned(),
+                rate_limiter_config: RateLimiterConfig {
+                    bandwidth: None,
+                    ops: Some(TokenBucketConfig {
+                        size: 10,
+                        one_time_burst: Some(5),
+                        refill_time: 1000,
+                    }),
+                },
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,rate_limit_group=group0")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                rate_limit_group: Some("group0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,rate_limit_group=group0")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                rate_limit_group: Some("group0".to_owned()),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scsi_parsing() -> Result<()> {
+        assert_eq!(
+            ScsiConfig::parse("")?,
+            ScsiConfig {
+                num_queues: 1,
+                queue_size: 128,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            ScsiConfig::parse("num_queues=2,queue_size=256,initiator=42,id=myscsi0")?,
+            ScsiConfig {
+                num_queues: 2,
+                queue_size: 256,
+                initiator: Some(42),
+                id: Some("myscsi0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(ScsiConfig::parse("initiator=-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scsi_lun_parsing() -> Result<()> {
+        // controller is required
+        assert!(ScsiLunConfig::parse("path=/path/to_file").is_err());
+        assert_eq!(
+            ScsiLunConfig::parse("controller=myscsi0,path=/path/to_file")?,
+            ScsiLunConfig {
+                controller: "myscsi0".to_owned(),
+                path: Some(PathBuf::from("/path/to_file")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            ScsiLunConfig::parse(
+                "controller=myscsi0,target=1,lun=2,path=/path/to_file,direct=on,discard=on,\
+                 reservations=/path/to_pr,id=mylun0"
+            )?,
+            ScsiLunConfig {
+                controller: "myscsi0".to_owned(),
+                target: 1,
+                lun: 2,
+                path: Some(PathBuf::from("/path/to_file")),
+                direct: true,
+                discard: true,
+                reservations: Some(PathBuf::from("/path/to_pr")),
+                id: Some("mylun0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            ScsiLunConfig::parse("controller=myscsi0,cdrom=on,readonly=on,lock=off")?,
+            ScsiLunConfig {
+                controller: "myscsi0".to_owned(),
+                cdrom: true,
+                readonly: true,
+                lock: false,
+                ..Default::default()
+            }
+        );
+        // target is a single byte
+        assert!(ScsiLunConfig::parse("controller=myscsi0,target=256").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_vsock_parsing() -> Result<()> {
         // socket and cid is required
@@ -3273,6 +4838,7 @@ mod tests {
             cmdline: CmdlineConfig {
                 args: String::from(""),
             },
+            rate_limit_groups: None,
             disks: None,
             net: None,
             rng: RngConfig {
@@ -3295,6 +4861,8 @@ mod tests {
             devices: None,
             user_devices: None,
             vdpa: None,
+            scsi: None,
+            scsi_luns: None,
             vsock: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
@@ -3367,91 +4935,418 @@ mod tests {
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.memory.shared = true;
         invalid_config.disks = Some(vec![DiskConfig {
-            vhost_user: true,
+            path: Some(PathBuf::from("/path/to/image")),
+            backing_file: Some(PathBuf::from("/path/to/backing_image")),
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserMissingSocket)
+            Err(ValidationError::BackingFileOverrideWithoutBackingFiles)
         );
 
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
-            vhost_user: true,
-            vhost_socket: Some("/path/to/sock".to_owned()),
+            path: Some(PathBuf::from("/path/to/image")),
+            readonly: true,
+            internal_snapshot: true,
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserRequiresSharedMemory)
+            Err(ValidationError::InternalSnapshotReadOnly)
         );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.disks = Some(vec![DiskConfig {
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
             vhost_user: true,
             vhost_socket: Some("/path/to/sock".to_owned()),
+            internal_snapshot: true,
             ..Default::default()
         }]);
-        still_valid_config.memory.shared = true;
-        assert!(still_valid_config.validate().is_ok());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InternalSnapshotVhostUser)
+        );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.net = Some(vec![NetConfig {
-            vhost_user: true,
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            readonly: true,
+            repair: true,
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserRequiresSharedMemory)
+            Err(ValidationError::RepairReadOnly)
         );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.net = Some(vec![NetConfig {
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
             vhost_user: true,
             vhost_socket: Some("/path/to/sock".to_owned()),
+            repair: true,
             ..Default::default()
         }]);
-        still_valid_config.memory.shared = true;
-        assert!(still_valid_config.validate().is_ok());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RepairVhostUser)
+        );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.net = Some(vec![NetConfig {
-            fds: Some(vec![0]),
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            dirty_bitmap: Some(PathBuf::from("/path/to/bitmap")),
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VnetReservedFd)
+            Err(ValidationError::DirtyBitmapVhostUser)
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.fs = Some(vec![FsConfig {
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            nbd: Some("unix:/path/to/socket".to_owned()),
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserRequiresSharedMemory)
+            Err(ValidationError::DiskNbdAndPath)
         );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.memory.shared = true;
-        assert!(still_valid_config.validate().is_ok());
-
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.memory.hugepages = true;
-        assert!(still_valid_config.validate().is_ok());
-
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.memory.hugepages = true;
-        still_valid_config.memory.hugepage_size = Some(2 << 20);
-        assert!(still_valid_config.validate().is_ok());
-
         let mut invalid_config = valid_config.clone();
-        invalid_config.memory.hugepages = false;
-        invalid_config.memory.hugepage_size = Some(2 << 20);
-        assert_eq!(
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            nbd_export: Some("disk0".to_owned()),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NbdExportWithoutServer)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            werror: ErrorPolicy::Stop,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ErrorPolicyVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            key_file: Some(PathBuf::from("/path/to/key")),
+            key_fd: Some(3),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyFileAndFd)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            key_fd: Some(1),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyReservedFd)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            key_file: Some(PathBuf::from("/path/to/key")),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            nbd: Some("unix:/path/to/sock".to_owned()),
+            key_file: Some(PathBuf::from("/path/to/key")),
+            ..Default::default()
+        }]);
+        assert_eq!(invalid_config.validate(), Err(ValidationError::DiskKeyNbd));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            key_file: Some(PathBuf::from("/path/to/key")),
+            discard: true,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskKeyDiscard)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserMissingSocket)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            fds: Some(vec![0]),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VnetReservedFd)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![NetConfig {
+            user: true,
+            host_forwards: Some(vec!["tcp::2222-:22".parse().unwrap()]),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            user: true,
+            tap: Some("tap0".to_owned()),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::UserNetAndBackend)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            user: true,
+            ip: Ipv4Addr::new(192, 168, 249, 254),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::UserNetNoGuestAddress)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            host_forwards: Some(vec!["tcp::2222-:22".parse().unwrap()]),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::HostForwardWithoutUserNet)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            host_loopback: true,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::HostLoopbackWithoutUserNet)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![NetConfig {
+            seqpacket: Some(PathBuf::from("/path/to/sock")),
+            server: true,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            stream: Some("127.0.0.1:5000".parse().unwrap()),
+            dgram: Some("127.0.0.1:5000".parse().unwrap()),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::L2SocketAndBackend)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            stream: Some("127.0.0.1:5000".parse().unwrap()),
+            num_queues: 4,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::L2SocketQueues)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            dgram: Some("127.0.0.1:5000".parse().unwrap()),
+            server: true,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::L2SocketServerWithoutStream)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            dgram: Some("unix:/path/to/sock".parse().unwrap()),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::L2SocketDgramLocal)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            capture_snaplen: Some(128),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::CaptureOptionsWithoutPath)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            capture: Some(PathBuf::from("/path/to/capture")),
+            capture_files: Some(4),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::CaptureFiles)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            capture: Some(PathBuf::from("/path/to/capture")),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::CaptureVhostUser)
+        );
+
+        let mut updated_config = valid_config.clone();
+        updated_config.net = Some(vec![NetConfig {
+            id: Some("net0".to_owned()),
+            ..Default::default()
+        }]);
+        let capture = CaptureConfig {
+            path: PathBuf::from("/path/to/capture"),
+            snaplen: Some(128),
+            file_size: None,
+            files: None,
+        };
+        assert_eq!(
+            updated_config.capture_net("net1", Some(capture.clone())),
+            Err(ValidationError::NetUnknownId("net1".to_owned()))
+        );
+        assert!(updated_config
+            .capture_net("net0", Some(capture.clone()))
+            .is_ok());
+        assert_eq!(
+            updated_config.net.as_ref().unwrap()[0].capture_config(),
+            Some(capture)
+        );
+        assert!(updated_config.capture_net("net0", None).is_ok());
+        let net = &updated_config.net.as_ref().unwrap()[0];
+        assert_eq!(net.capture, None);
+        assert_eq!(net.capture_snaplen, None);
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.fs = Some(vec![FsConfig {
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.hugepages = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.hugepages = true;
+        still_valid_config.memory.hugepage_size = Some(2 << 20);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hugepages = false;
+        invalid_config.memory.hugepage_size = Some(2 << 20);
+        assert_eq!(
             invalid_config.validate(),
             Err(ValidationError::HugePageSizeWithoutHugePages)
         );
@@ -3676,6 +5571,190 @@ mod tests {
             Err(ValidationError::OnIommuSegment(1))
         );
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.scsi = Some(vec![ScsiConfig {
+            num_queues: 1,
+            id: Some("scsi0".to_owned()),
+            ..Default::default()
+        }]);
+        still_valid_config.scsi_luns = Some(vec![
+            ScsiLunConfig {
+                controller: "scsi0".to_owned(),
+                path: Some(PathBuf::from("/path/to/image")),
+                ..Default::default()
+            },
+            ScsiLunConfig {
+                controller: "scsi0".to_owned(),
+                lun: 1,
+                cdrom: true,
+                ..Default::default()
+            },
+        ]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.scsi_luns.as_mut().unwrap()[1].controller = "scsi1".to_owned();
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ScsiControllerUnknown("scsi1".to_owned()))
+        );
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.scsi_luns.as_mut().unwrap()[1].lun = 0;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ScsiLunNotUnique("scsi0".to_owned(), 0, 0))
+        );
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.scsi_luns.as_mut().unwrap()[1].lun = 16384;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ScsiLunOutOfRange(16384))
+        );
+
+        let mut invalid_config = still_valid_config;
+        invalid_config.scsi_luns.as_mut().unwrap()[1].cdrom = false;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ScsiLunPathMissing)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.rate_limit_groups = Some(vec![RateLimitGroupConfig {
+            id: "group0".to_owned(),
+            rate_limiter_config: RateLimiterConfig::default(),
+        }]);
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            rate_limit_group: Some("group0".to_owned()),
+            ..Default::default()
+        }]);
+        still_valid_config.net = Some(vec![NetConfig {
+            rate_limit_group: Some("group0".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.net.as_mut().unwrap()[0].rate_limit_group = Some("group1".to_owned());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RateLimitGroupUnknown("group1".to_owned()))
+        );
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].rate_limiter_config =
+            Some(RateLimiterConfig::default());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RateLimitGroupAndConfig)
+        );
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.net.as_mut().unwrap()[0].vhost_user = true;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RateLimitGroupVhostUser)
+        );
+
+        let mut updated_config = still_valid_config.clone();
+        updated_config.disks.as_mut().unwrap()[0].id = Some("disk0".to_owned());
+        let rate_limiter_config = RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(TokenBucketConfig {
+                size: 10,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+        };
+        assert!(updated_config
+            .update_rate_limiter("group0", Some(rate_limiter_config))
+            .is_ok());
+        assert_eq!(
+            updated_config.rate_limit_groups.as_ref().unwrap()[0].rate_limiter_config,
+            rate_limiter_config
+        );
+        assert_eq!(
+            updated_config.update_rate_limiter("disk0", Some(rate_limiter_config)),
+            Err(ValidationError::RateLimitGroupAndConfig)
+        );
+        assert_eq!(
+            updated_config.update_rate_limiter("disk1", None),
+            Err(ValidationError::RateLimiterUnknownId("disk1".to_owned()))
+        );
+        updated_config.disks.as_mut().unwrap()[0].rate_limit_group = None;
+        assert!(updated_config
+            .update_rate_limiter("disk0", Some(rate_limiter_config))
+            .is_ok());
+        assert_eq!(
+            updated_config.disks.as_ref().unwrap()[0].rate_limiter_config,
+            Some(rate_limiter_config)
+        );
+        assert!(updated_config
+            .update_rate_limiter("disk0", Some(RateLimiterConfig::default()))
+            .is_ok());
+        assert_eq!(
+            updated_config.disks.as_ref().unwrap()[0].rate_limiter_config,
+            None
+        );
+
+        let mut invalid_config = still_valid_config;
+        invalid_config
+            .rate_limit_groups
+            .as_mut()
+            .unwrap()
+            .push(RateLimitGroupConfig {
+                id: "group0".to_owned(),
+                rate_limiter_config: RateLimiterConfig::default(),
+            });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RateLimitGroupNotUnique(
+                "group0".to_owned()
+            ))
+        );
+
+        let mut updated_config = valid_config.clone();
+        updated_config.disks = Some(vec![DiskConfig {
+            id: Some("disk0".to_owned()),
+            nbd: Some("unix:/path/to/socket".to_owned()),
+            nbd_export: Some("export".to_owned()),
+            backing_files: true,
+            backing_file: Some(PathBuf::from("/path/to/backing")),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            updated_config.change_disk_media("disk1", None, None),
+            Err(ValidationError::DiskUnknownId("disk1".to_owned()))
+        );
+        assert!(updated_config
+            .change_disk_media("disk0", Some(PathBuf::from("/path/to/iso")), Some(true))
+            .is_ok());
+        let disk = &updated_config.disks.as_ref().unwrap()[0];
+        assert_eq!(disk.path, Some(PathBuf::from("/path/to/iso")));
+        assert!(disk.readonly);
+        assert_eq!(disk.nbd, None);
+        assert_eq!(disk.nbd_export, None);
+        assert_eq!(disk.backing_file, None);
+        assert!(updated_config
+            .change_disk_media("disk0", None, None)
+            .is_ok());
+        let disk = &updated_config.disks.as_ref().unwrap()[0];
+        assert_eq!(disk.path, None);
+        assert!(disk.readonly);
+        updated_config.disks.as_mut().unwrap()[0].internal_snapshot = true;
+        assert_eq!(
+            updated_config.change_disk_media("disk0", None, None),
+            Err(ValidationError::InternalSnapshotReadOnly)
+        );
+        updated_config.disks.as_mut().unwrap()[0].dirty_bitmap = Some(PathBuf::from("/bitmap"));
+        assert_eq!(
+            updated_config.change_disk_media("disk0", None, Some(false)),
+            Err(ValidationError::MediaChangeDirtyBitmap)
+        );
+
         let mut invalid_config = valid_config;
         invalid_config.memory.shared = true;
         invalid_config.platform = Some(PlatformConfig {
@@ -10,8 +10,8 @@
 //
 
 use crate::config::{
-    ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, UserDeviceConfig,
-    VdpaConfig, VhostMode, VmConfig, VsockConfig,
+    ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, ScsiConfig,
+    ScsiLunConfig, UserDeviceConfig, VdpaConfig, VhostMode, VmConfig, VsockConfig,
 };
 use crate::device_tree::{DeviceNode, DeviceTree};
 use crate::interrupt::LegacyUserspaceInterruptManager;
@@ -38,9 +38,14 @@ use arch::NumaNodes;
 #[cfg(target_arch = "aarch64")]
 use arch::{DeviceType, MmioDeviceInfo};
 use block_util::{
-    async_io::DiskFile, block_io_uring_is_supported, detect_image_type,
-    fixed_vhd_async::FixedVhdDiskAsync, fixed_vhd_sync::FixedVhdDiskSync, qcow_sync::QcowDiskSync,
-    raw_async::RawFileDisk, raw_sync::RawFileDiskSync, vhdx_sync::VhdxDiskSync, ImageType,
+    async_io::DiskFile, async_io::DiskFileError, block_io_uring_is_supported, detect_image_type,
+    dynamic_vhd_sync::DynamicVhdDiskSync, empty_disk::EmptyDisk,
+    fixed_vhd_async::FixedVhdDiskAsync, fixed_vhd_sync::FixedVhdDiskSync, image_lock,
+    image_lock::LockError, image_lock::LockType, image_lock::PendingLock, luks::read_key_fd,
+    luks_disk::LuksDisk, nbd::NbdAddress, nbd_sync::NbdDiskSync, qcow_async::QcowDiskAsync,
+    qcow_sync::QcowDiskSync, raw_async::RawFileDisk, raw_sync::RawFileDiskSync, scsi::ScsiLun,
+    scsi::ScsiLunKind, scsi_reservations::PersistentReservations, vhdx_async::VhdxDiskAsync,
+    vhdx_sync::VhdxDiskSync, ImageType,
 };
 #[cfg(target_arch = "aarch64")]
 use devices::gic;
@@ -58,31 +63,36 @@ use libc::{
     cfmakeraw, isatty, tcgetattr, tcsetattr, termios, MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED,
     O_TMPFILE, PROT_READ, PROT_WRITE, TCSANOW,
 };
+use net_util::CaptureConfig;
 #[cfg(target_arch = "x86_64")]
 use pci::PciConfigIo;
 use pci::{
     DeviceRelocation, PciBarRegionType, PciBdf, PciDevice, VfioPciDevice, VfioUserDmaMapping,
     VfioUserPciDevice, VfioUserPciDeviceError,
 };
+use qcow::{BackingFilePolicy, QcowFile, RawFile};
+use rate_limiter::group::RateLimiterGroup;
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeSet, HashMap};
 use std::convert::TryInto;
 use std::fs::{read_link, File, OpenOptions};
-use std::io::{self, stdout, Seek, SeekFrom};
+use std::io::{self, stdout, BufWriter, Read, Seek, SeekFrom, Write};
 use std::mem::zeroed;
 use std::num::Wrapping;
-use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Instant;
 use vfio_ioctls::{VfioContainer, VfioDevice};
 use virtio_devices::transport::VirtioPciDevice;
 use virtio_devices::transport::VirtioTransport;
 use virtio_devices::vhost_user::VhostUserConfig;
-use virtio_devices::{AccessPlatformMapping, VdpaDmaMapping, VirtioMemMappingSource};
+use virtio_devices::{
+    AccessPlatformMapping, RateLimiterConfig, VdpaDmaMapping, VirtioMemMappingSource,
+};
 use virtio_devices::{Endpoint, IommuMapping};
 use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::dma_mapping::vfio::VfioDmaMapping;
@@ -126,6 +136,8 @@ const DISK_DEVICE_NAME_PREFIX: &str = "_disk";
 const FS_DEVICE_NAME_PREFIX: &str = "_fs";
 const NET_DEVICE_NAME_PREFIX: &str = "_net";
 const PMEM_DEVICE_NAME_PREFIX: &str = "_pmem";
+const SCSI_DEVICE_NAME_PREFIX: &str = "_scsi";
+const SCSI_LUN_DEVICE_NAME_PREFIX: &str = "_scsi_lun";
 const VDPA_DEVICE_NAME_PREFIX: &str = "_vdpa";
 const VSOCK_DEVICE_NAME_PREFIX: &str = "_vsock";
 const WATCHDOG_DEVICE_NAME: &str = "__watchdog";
@@ -361,9 +373,6 @@ pub enum DeviceManagerError {
     /// Incorrect device ID as it is already used by another device.
     DeviceIdAlreadyInUse,
 
-    /// No disk path was specified when one was expected
-    NoDiskPath,
-
     /// Failed to update guest memory for virtio device.
     UpdateMemoryForVirtioDevice(virtio_devices::Error),
 
@@ -419,18 +428,36 @@ pub enum DeviceManagerError {
     /// Failed to set O_DIRECT flag to file descriptor
     SetDirectIo,
 
+    /// Failed to create DynamicVhdDiskSync
+    CreateDynamicVhdDiskSync(io::Error),
+
     /// Failed to create FixedVhdDiskAsync
     CreateFixedVhdDiskAsync(io::Error),
 
     /// Failed to create FixedVhdDiskSync
     CreateFixedVhdDiskSync(io::Error),
 
+    /// Failed to create QcowDiskAsync
+    CreateQcowDiskAsync(qcow::Error),
+
     /// Failed to create QcowDiskSync
     CreateQcowDiskSync(qcow::Error),
 
+    /// Failed to create VhdxDiskAsync
+    CreateVhdxDiskAsync(vhdx::vhdx::VhdxError),
+
     /// Failed to create FixedVhdxDiskSync
     CreateFixedVhdxDiskSync(vhdx::vhdx::VhdxError),
 
+    /// Failed to create NbdDiskSync
+    CreateNbdDiskSync(block_util::nbd::NbdError),
+
+    /// Failed to read the passphrase of an encrypted disk
+    ReadDiskKey(io::Error),
+
+    /// Failed to create LuksDisk
+    CreateLuksDisk(block_util::luks::LuksError),
+
     /// Failed to add DMA mapping handler to virtio-mem device.
     AddDmaMappingHandlerVirtioMem(virtio_devices::mem::Error),
 
@@ -467,6 +494,12 @@ pub enum DeviceManagerError {
     /// Cannot hotplug device behind vIOMMU
     InvalidIommuHotplug,
 
+    /// Cannot hotplug network device waiting for its peer
+    InvalidL2SocketServerHotplug,
+
+    /// Cannot restore network device waiting for its peer
+    InvalidL2SocketServerRestore,
+
     /// Failed to create UEFI flash
     CreateUefiFlash(HypervisorVmError),
 
@@ -475,6 +508,81 @@ pub enum DeviceManagerError {
 
     /// Invalid identifier
     InvalidIdentifier(String),
+
+    /// Failed to create an internal disk snapshot
+    DiskSnapshot(String, DiskFileError),
+
+    /// Failed to resize a virtio-blk device
+    ResizeDisk(String, io::Error),
+
+    /// Failed to resize a vhost-user-blk device
+    ResizeVhostUserBlk(String, virtio_devices::vhost_user::Error),
+
+    /// Failed to back up a virtio-blk device
+    BackupDisk(String, io::Error),
+
+    /// Failed to create the image a virtio-blk device is mirrored to
+    CreateDiskMirror(String, io::Error),
+
+    /// Failed to mirror a virtio-blk device
+    MirrorDisk(String, io::Error),
+
+    /// Encrypted virtio-blk devices can't be mirrored
+    MirrorEncryptedDisk(String),
+
+    /// Failed to change the media of a virtio-blk device
+    ChangeDiskMedia(String, io::Error),
+
+    /// Failed to create virtio-scsi device
+    CreateVirtioScsi(io::Error),
+
+    /// Failed to open the persistent reservations of a SCSI logical unit
+    OpenScsiReservations(io::Error),
+
+    /// Failed to create a SCSI logical unit
+    CreateScsiLun(DiskFileError),
+
+    /// Failed to add a logical unit to a virtio-scsi device
+    AddScsiLun(io::Error),
+
+    /// Failed to remove a logical unit from a virtio-scsi device
+    RemoveScsiLun(io::Error),
+
+    /// Failed to generate the initiator id of a virtio-scsi device
+    ScsiInitiator(io::Error),
+
+    /// No virtio-scsi device with this identifier
+    UnknownScsiController(String),
+
+    /// Failed to create a rate limit group
+    CreateRateLimitGroup(io::Error),
+
+    /// No rate limit group with this identifier
+    UnknownRateLimitGroup(String),
+
+    /// No rate limiter to update for this identifier
+    NoRateLimiter(String),
+
+    /// Failed to update the rate limiter of a device
+    UpdateRateLimiter(io::Error),
+
+    /// Failed to lock an image, which is likely used by someone else
+    LockImage(PathBuf, LockError),
+
+    /// Failed to get the backing files of a disk image
+    DiskBackingFiles(DiskFileError),
+
+    /// No virtio-net device with this identifier
+    UnknownNetDevice(String),
+
+    /// Failed to change the link state of a virtio-net device
+    SetNetLink(String, io::Error),
+
+    /// The link of a vhost-user-net device is handled by its backend
+    VhostUserNetLink(String),
+
+    /// Failed to start capturing the frames of a virtio-net device
+    StartNetCapture(String, io::Error),
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
@@ -909,6 +1017,10 @@ pub struct DeviceManager {
     // activation and thus start the threads from the VMM thread
     activate_evt: EventFd,
 
+    // EventFd letting the devices ask the VMM thread to pause the VM, on I/O
+    // errors
+    stop_evt: EventFd,
+
     acpi_address: GuestAddress,
 
     selected_segment: usize,
@@ -916,6 +1028,25 @@ pub struct DeviceManager {
     // Possible handle to the virtio-mem device
     virtio_mem_devices: Vec<Arc<Mutex<virtio_devices::Mem>>>,
 
+    // virtio-blk devices with internal snapshots enabled, along with their id
+    internal_snapshot_disks: Vec<(String, Arc<Mutex<virtio_devices::Block>>)>,
+
+    // virtio-blk and vhost-user-blk devices along with their id, to resize them
+    block_devices: Vec<(String, Arc<Mutex<virtio_devices::Block>>)>,
+    vhost_user_block_devices: Vec<(String, Arc<Mutex<virtio_devices::vhost_user::Blk>>)>,
+
+    // virtio-scsi devices along with their id, to add and remove logical units
+    scsi_devices: Vec<(String, Arc<Mutex<virtio_devices::Scsi>>)>,
+
+    // virtio-net devices along with their id, to change their link state
+    net_devices: Vec<(String, Arc<Mutex<virtio_devices::Net>>)>,
+
+    // Ids of the vhost-user-net devices, whose link is handled by the backend
+    vhost_user_net_devices: Vec<String>,
+
+    // Rate limit groups shared by virtio-blk and virtio-net devices
+    rate_limit_groups: HashMap<String, Arc<RateLimiterGroup>>,
+
     #[cfg(target_arch = "aarch64")]
     // GPIO device for AArch64
     gpio_device: Option<Arc<Mutex<devices::legacy::Gpio>>>,
@@ -930,6 +1061,17 @@ pub struct DeviceManager {
     // Helps identify if the VM is currently being restored
     restoring: bool,
 
+    // Helps identify if the VM is currently being received from a live
+    // migration
+    migrating: bool,
+
+    // Locks of the images still used by the source VM of the migration
+    pending_locks: Vec<PendingLock>,
+
+    // Clones of the locked images of the virtio-block devices, by id, for
+    // their locks to be released when the same image is inserted again
+    disk_locks: Arc<Mutex<HashMap<String, File>>>,
+
     // io_uring availability if detected
     io_uring_supported: Option<bool>,
 
@@ -951,8 +1093,10 @@ impl DeviceManager {
         seccomp_action: SeccompAction,
         numa_nodes: NumaNodes,
         activate_evt: &EventFd,
+        stop_evt: &EventFd,
         force_iommu: bool,
         restoring: bool,
+        migrating: bool,
         boot_id_list: BTreeSet<String>,
         timestamp: Instant,
     ) -> DeviceManagerResult<Arc<Mutex<Self>>> {
@@ -1063,6 +1207,7 @@ impl DeviceManager {
             activate_evt: activate_evt
                 .try_clone()
                 .map_err(DeviceManagerError::EventFd)?,
+            stop_evt: stop_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
             acpi_address,
             selected_segment: 0,
             serial_pty: None,
@@ -1070,12 +1215,22 @@ impl DeviceManager {
             console_pty: None,
             console_resize_pipe: None,
             virtio_mem_devices: Vec::new(),
+            internal_snapshot_disks: Vec::new(),
+            block_devices: Vec::new(),
+            vhost_user_block_devices: Vec::new(),
+            scsi_devices: Vec::new(),
+            net_devices: Vec::new(),
+            vhost_user_net_devices: Vec::new(),
+            rate_limit_groups: HashMap::new(),
             #[cfg(target_arch = "aarch64")]
             gpio_device: None,
             #[cfg(target_arch = "aarch64")]
             uefi_flash: None,
             force_iommu,
             restoring,
+            migrating,
+            pending_locks: Vec::new(),
+            disk_locks: Arc::new(Mutex::new(HashMap::new())),
             io_uring_supported: None,
             boot_id_list,
             timestamp,
@@ -1985,6 +2140,9 @@ impl DeviceManager {
     fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices: Vec<MetaVirtioDevice> = Vec::new();
 
+        // Create the rate limit groups before the devices using them
+        self.make_rate_limit_groups()?;
+
         // Create "standard" virtio devices (net/block/rng)
         devices.append(&mut self.make_virtio_block_devices()?);
         devices.append(&mut self.make_virtio_net_devices()?);
@@ -2010,6 +2168,9 @@ impl DeviceManager {
         // Add vDPA devices if required
         devices.append(&mut self.make_vdpa_devices()?);
 
+        // Add virtio-scsi devices if required
+        devices.append(&mut self.make_virtio_scsi_devices()?);
+
         Ok(devices)
     }
 
@@ -2024,6 +2185,203 @@ impl DeviceManager {
         supported
     }
 
+    // Locks the image `path` opened as `file`. When receiving a migration,
+    // the image may still be locked by the source VM, in which case the lock
+    // is taken once the migration completes.
+    fn lock_image(
+        &mut self,
+        file: &File,
+        lock_type: LockType,
+        path: &Path,
+    ) -> DeviceManagerResult<()> {
+        match image_lock::lock(file, lock_type) {
+            Err(LockError::AlreadyLocked) if self.migrating => {
+                warn!("Image {:?} is still in use, locking it later", path);
+                let pending_lock = PendingLock::new(file, path, lock_type, 0, 0).map_err(|e| {
+                    DeviceManagerError::LockImage(path.to_owned(), LockError::Lock(e))
+                })?;
+                self.pending_locks.push(pending_lock);
+                Ok(())
+            }
+            result => result.map_err(|e| DeviceManagerError::LockImage(path.to_owned(), e)),
+        }
+    }
+
+    /// Returns the locks of the images still used by the source VM when the
+    /// migration was received.
+    pub fn take_pending_locks(&mut self) -> Vec<PendingLock> {
+        std::mem::take(&mut self.pending_locks)
+    }
+
+    fn open_disk_image(&mut self, disk_cfg: &DiskConfig) -> DeviceManagerResult<Box<dyn DiskFile>> {
+        self.open_locked_disk_image(disk_cfg)
+            .map(|(image, _)| image)
+    }
+
+    // Opens the image of `disk_cfg`, also returning a clone of its file when
+    // it's locked, which refers to the same lock.
+    fn open_locked_disk_image(
+        &mut self,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(Box<dyn DiskFile>, Option<File>)> {
+        if let Some(nbd) = &disk_cfg.nbd {
+            info!("Using synchronous NBD disk");
+            let address = NbdAddress::parse(nbd).map_err(DeviceManagerError::CreateNbdDiskSync)?;
+            return Ok((
+                Box::new(
+                    NbdDiskSync::new(address, disk_cfg.nbd_export.as_deref().unwrap_or_default())
+                        .map_err(DeviceManagerError::CreateNbdDiskSync)?,
+                ) as Box<dyn DiskFile>,
+                None,
+            ));
+        }
+
+        // Disks without any path have no media, like an empty CD-ROM drive.
+        let path = match &disk_cfg.path {
+            Some(path) => path,
+            None => {
+                info!("Using empty disk");
+                return Ok((Box::new(EmptyDisk::new()) as Box<dyn DiskFile>, None));
+            }
+        };
+
+        let mut options = OpenOptions::new();
+        options.read(true);
+        options.write(!disk_cfg.readonly);
+        if disk_cfg.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        // Open block device path
+        let mut file: File = options.open(path).map_err(DeviceManagerError::Disk)?;
+        let image_lock = if disk_cfg.lock {
+            self.lock_image(&file, LockType::new(disk_cfg.readonly), path)?;
+            Some(file.try_clone().map_err(DeviceManagerError::Disk)?)
+        } else {
+            None
+        };
+        let image_type =
+            detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
+        let backing_file_policy = if !disk_cfg.backing_files {
+            BackingFilePolicy::Forbid
+        } else if let Some(backing_file) = &disk_cfg.backing_file {
+            BackingFilePolicy::Override(backing_file.clone())
+        } else {
+            BackingFilePolicy::Header
+        };
+
+        let image = match image_type {
+            ImageType::DynamicVhd => {
+                info!("Using synchronous dynamic VHD disk file");
+                Box::new(
+                    DynamicVhdDiskSync::new(file, disk_cfg.direct, &backing_file_policy)
+                        .map_err(DeviceManagerError::CreateDynamicVhdDiskSync)?,
+                ) as Box<dyn DiskFile>
+            }
+            ImageType::FixedVhd => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if self.io_uring_is_supported() && !disk_cfg.disable_io_uring {
+                    info!("Using asynchronous fixed VHD disk file (io_uring)");
+                    Box::new(
+                        FixedVhdDiskAsync::new(file)
+                            .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
+                    ) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous fixed VHD disk file");
+                    Box::new(
+                        FixedVhdDiskSync::new(file)
+                            .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
+                    ) as Box<dyn DiskFile>
+                }
+            }
+            ImageType::Raw => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if self.io_uring_is_supported() && !disk_cfg.disable_io_uring {
+                    info!("Using asynchronous RAW disk file (io_uring)");
+                    Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous RAW disk file");
+                    Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
+                }
+            }
+            ImageType::Qcow2 => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if self.io_uring_is_supported() && !disk_cfg.disable_io_uring {
+                    info!("Using asynchronous QCOW disk file (io_uring)");
+                    Box::new(
+                        QcowDiskAsync::new(
+                            file,
+                            disk_cfg.direct,
+                            &backing_file_policy,
+                            disk_cfg.repair,
+                        )
+                        .map_err(DeviceManagerError::CreateQcowDiskAsync)?,
+                    ) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous QCOW disk file");
+                    Box::new(
+                        QcowDiskSync::new(
+                            file,
+                            disk_cfg.direct,
+                            &backing_file_policy,
+                            disk_cfg.repair,
+                        )
+                        .map_err(DeviceManagerError::CreateQcowDiskSync)?,
+                    ) as Box<dyn DiskFile>
+                }
+            }
+            ImageType::Vhdx => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if self.io_uring_is_supported() && !disk_cfg.disable_io_uring {
+                    info!("Using asynchronous VHDX disk file (io_uring)");
+                    Box::new(
+                        VhdxDiskAsync::new(file, &backing_file_policy)
+                            .map_err(DeviceManagerError::CreateVhdxDiskAsync)?,
+                    ) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous VHDX disk file");
+                    Box::new(
+                        VhdxDiskSync::new(file, &backing_file_policy)
+                            .map_err(DeviceManagerError::CreateFixedVhdxDiskSync)?,
+                    ) as Box<dyn DiskFile>
+                }
+            }
+        };
+
+        // Backing files are only read, and may be shared with other images.
+        if disk_cfg.lock {
+            for (backing_path, backing_file) in image
+                .backing_files()
+                .map_err(DeviceManagerError::DiskBackingFiles)?
+            {
+                self.lock_image(&backing_file, LockType::Shared, &backing_path)?;
+            }
+        }
+
+        // The passphrase is read every time the disk is opened, not kept in
+        // memory.
+        let key = if let Some(key_file) = &disk_cfg.key_file {
+            Some(std::fs::read(key_file).map_err(DeviceManagerError::ReadDiskKey)?)
+        } else if let Some(key_fd) = disk_cfg.key_fd {
+            Some(read_key_fd(key_fd).map_err(DeviceManagerError::ReadDiskKey)?)
+        } else {
+            None
+        };
+        if let Some(key) = key {
+            info!("Using LUKS encrypted disk");
+            return Ok((
+                Box::new(LuksDisk::new(image, &key).map_err(DeviceManagerError::CreateLuksDisk)?)
+                    as Box<dyn DiskFile>,
+                image_lock,
+            ));
+        }
+
+        Ok((image, image_lock))
+    }
+
     fn make_virtio_block_device(
         &mut self,
         disk_cfg: &mut DiskConfig,
@@ -2063,97 +2421,68 @@ impl DeviceManager {
                 },
             ));
 
+            self.vhost_user_block_devices
+                .push((id.clone(), Arc::clone(&vhost_user_block)));
+
             (
                 Arc::clone(&vhost_user_block) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
                 vhost_user_block as Arc<Mutex<dyn Migratable>>,
             )
         } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
-            // Open block device path
-            let mut file: File = options
-                .open(
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
-            let image_type =
-                detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
-
-            let image = match image_type {
-                ImageType::FixedVhd => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if self.io_uring_is_supported() && !disk_cfg.disable_io_uring {
-                        info!("Using asynchronous fixed VHD disk file (io_uring)");
-                        Box::new(
-                            FixedVhdDiskAsync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
-                        ) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous fixed VHD disk file");
-                        Box::new(
-                            FixedVhdDiskSync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
-                        ) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Raw => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if self.io_uring_is_supported() && !disk_cfg.disable_io_uring {
-                        info!("Using asynchronous RAW disk file (io_uring)");
-                        Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous RAW disk file");
-                        Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Qcow2 => {
-                    info!("Using synchronous QCOW disk file");
-                    Box::new(
-                        QcowDiskSync::new(file, disk_cfg.direct)
-                            .map_err(DeviceManagerError::CreateQcowDiskSync)?,
-                    ) as Box<dyn DiskFile>
-                }
-                ImageType::Vhdx => {
-                    info!("Using synchronous VHDX disk file");
-                    Box::new(
-                        VhdxDiskSync::new(file)
-                            .map_err(DeviceManagerError::CreateFixedVhdxDiskSync)?,
-                    ) as Box<dyn DiskFile>
-                }
+            let (image, image_lock) = self.open_locked_disk_image(disk_cfg)?;
+            let rate_limit_group = self.rate_limit_group(disk_cfg.rate_limit_group.as_ref())?;
+            // Disks without any limit have no rate limiter until limits are
+            // set on the fly.
+            let rate_limiter_config = if rate_limit_group.is_none() {
+                disk_cfg.rate_limiter_config
+            } else {
+                None
             };
 
             let virtio_block = Arc::new(Mutex::new(
                 virtio_devices::Block::new(
                     id.clone(),
                     image,
+                    // NBD disks are identified by the address of the server.
                     disk_cfg
                         .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
+                        .clone()
+                        .or_else(|| disk_cfg.nbd.as_ref().map(PathBuf::from))
+                        .unwrap_or_default(),
                     disk_cfg.readonly,
                     self.force_iommu | disk_cfg.iommu,
                     disk_cfg.num_queues,
                     disk_cfg.queue_size,
                     self.seccomp_action.clone(),
-                    disk_cfg.rate_limiter_config,
+                    rate_limiter_config,
+                    rate_limit_group,
                     self.exit_evt
                         .try_clone()
                         .map_err(DeviceManagerError::EventFd)?,
+                    disk_cfg.discard,
+                    disk_cfg.dirty_bitmap.clone(),
+                    disk_cfg.rerror,
+                    disk_cfg.werror,
+                    self.stop_evt
+                        .try_clone()
+                        .map_err(DeviceManagerError::EventFd)?,
                 )
                 .map_err(DeviceManagerError::CreateVirtioBlock)?,
             ));
 
+            if disk_cfg.internal_snapshot {
+                self.internal_snapshot_disks
+                    .push((id.clone(), Arc::clone(&virtio_block)));
+            }
+            self.block_devices
+                .push((id.clone(), Arc::clone(&virtio_block)));
+            if let Some(image_lock) = image_lock {
+                self.disk_locks
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), image_lock);
+            }
+
             (
                 Arc::clone(&virtio_block) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
                 virtio_block as Arc<Mutex<dyn Migratable>>,
@@ -2177,6 +2506,39 @@ impl DeviceManager {
         })
     }
 
+    fn make_rate_limit_groups(&mut self) -> DeviceManagerResult<()> {
+        let rate_limit_groups = self.config.lock().unwrap().rate_limit_groups.clone();
+        for rate_limit_group_cfg in rate_limit_groups.iter().flatten() {
+            info!("Creating rate limit group: {:?}", rate_limit_group_cfg);
+
+            let rate_limit_group = rate_limit_group_cfg
+                .rate_limiter_config
+                .new_group(&rate_limit_group_cfg.id)
+                .map_err(DeviceManagerError::CreateRateLimitGroup)?;
+            self.rate_limit_groups
+                .insert(rate_limit_group_cfg.id.clone(), Arc::new(rate_limit_group));
+        }
+
+        Ok(())
+    }
+
+    // Returns the rate limit group a device refers to, if any.
+    fn rate_limit_group(
+        &self,
+        rate_limit_group: Option<&String>,
+    ) -> DeviceManagerResult<Option<Arc<RateLimiterGroup>>> {
+        rate_limit_group
+            .map(|rate_limit_group| {
+                self.rate_limit_groups
+                    .get(rate_limit_group)
+                    .cloned()
+                    .ok_or_else(|| {
+                        DeviceManagerError::UnknownRateLimitGroup(rate_limit_group.clone())
+                    })
+            })
+            .transpose()
+    }
+
     fn make_virtio_block_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
@@ -2234,13 +2596,64 @@ impl DeviceManager {
                     }
                 },
             ));
+            self.vhost_user_net_devices.push(id.clone());
 
             (
                 Arc::clone(&vhost_user_net) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
                 vhost_user_net as Arc<Mutex<dyn Migratable>>,
             )
         } else {
-            let virtio_net = if let Some(ref tap_if_name) = net_cfg.tap {
+            // With its own rate limiters, the device throttles its RX and TX
+            // traffic independently, while a rate limit group is shared by
+            // both directions. Devices without any limit have no rate
+            // limiter until limits are set on the fly.
+            let rate_limit_group = self.rate_limit_group(net_cfg.rate_limit_group.as_ref())?;
+            let rate_limiter_config = if rate_limit_group.is_none() {
+                net_cfg.rate_limiter_config
+            } else {
+                None
+            };
+
+            let virtio_net = if net_cfg.user {
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::new_with_user_net(
+                        id.clone(),
+                        &net_cfg.user_net_config(),
+                        net_cfg.mac,
+                        self.force_iommu | net_cfg.iommu,
+                        net_cfg.queue_size,
+                        self.seccomp_action.clone(),
+                        rate_limiter_config,
+                        rate_limit_group,
+                        self.exit_evt
+                            .try_clone()
+                            .map_err(DeviceManagerError::EventFd)?,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(l2_socket_config) = net_cfg.l2_socket_config() {
+                // Waiting for the peer would block the VMM, which only
+                // happens before the VM boots.
+                if net_cfg.server && self.restoring {
+                    return Err(DeviceManagerError::InvalidL2SocketServerRestore);
+                }
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::new_with_l2_socket(
+                        id.clone(),
+                        &l2_socket_config,
+                        Some(net_cfg.mac),
+                        self.force_iommu | net_cfg.iommu,
+                        net_cfg.queue_size,
+                        self.seccomp_action.clone(),
+                        rate_limiter_config,
+                        rate_limit_group,
+                        self.exit_evt
+                            .try_clone()
+                            .map_err(DeviceManagerError::EventFd)?,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(ref tap_if_name) = net_cfg.tap {
                 Arc::new(Mutex::new(
                     virtio_devices::Net::new(
                         id.clone(),
@@ -2253,7 +2666,8 @@ impl DeviceManager {
                         net_cfg.num_queues,
                         net_cfg.queue_size,
                         self.seccomp_action.clone(),
-                        net_cfg.rate_limiter_config,
+                        rate_limiter_config,
+                        rate_limit_group,
                         self.exit_evt
                             .try_clone()
                             .map_err(DeviceManagerError::EventFd)?,
@@ -2269,7 +2683,8 @@ impl DeviceManager {
                         self.force_iommu | net_cfg.iommu,
                         net_cfg.queue_size,
                         self.seccomp_action.clone(),
-                        net_cfg.rate_limiter_config,
+                        rate_limiter_config,
+                        rate_limit_group,
                         self.exit_evt
                             .try_clone()
                             .map_err(DeviceManagerError::EventFd)?,
@@ -2289,7 +2704,8 @@ impl DeviceManager {
                         net_cfg.num_queues,
                         net_cfg.queue_size,
                         self.seccomp_action.clone(),
-                        net_cfg.rate_limiter_config,
+                        rate_limiter_config,
+                        rate_limit_group,
                         self.exit_evt
                             .try_clone()
                             .map_err(DeviceManagerError::EventFd)?,
@@ -2297,6 +2713,14 @@ impl DeviceManager {
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
             };
+            if let Some(capture_config) = net_cfg.capture_config() {
+                virtio_net
+                    .lock()
+                    .unwrap()
+                    .start_capture(&capture_config)
+                    .map_err(|e| DeviceManagerError::StartNetCapture(id.clone(), e))?;
+            }
+            self.net_devices.push((id.clone(), Arc::clone(&virtio_net)));
 
             (
                 Arc::clone(&virtio_net) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -2504,6 +2928,14 @@ impl DeviceManager {
             .custom_flags(custom_flags)
             .open(&pmem_cfg.file)
             .map_err(DeviceManagerError::PmemFileOpen)?;
+        // A temporary file can't be opened by anyone else.
+        if pmem_cfg.lock && !set_len {
+            self.lock_image(
+                &file,
+                LockType::new(pmem_cfg.discard_writes),
+                &pmem_cfg.file,
+            )?;
+        }
 
         let size = if let Some(size) = pmem_cfg.size {
             if set_len {
@@ -2892,6 +3324,142 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    fn make_scsi_lun(
+        &mut self,
+        scsi_lun_cfg: &mut ScsiLunConfig,
+        initiator: u64,
+    ) -> DeviceManagerResult<(String, Arc<ScsiLun>)> {
+        let id = if let Some(id) = &scsi_lun_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(SCSI_LUN_DEVICE_NAME_PREFIX)?;
+            scsi_lun_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating SCSI logical unit: {:?}", scsi_lun_cfg);
+
+        let kind = if scsi_lun_cfg.cdrom {
+            ScsiLunKind::Cdrom
+        } else {
+            ScsiLunKind::Disk
+        };
+        let disk = if let Some(path) = &scsi_lun_cfg.path {
+            Some(self.open_disk_image(&DiskConfig {
+                path: Some(path.clone()),
+                readonly: scsi_lun_cfg.readonly || scsi_lun_cfg.cdrom,
+                direct: scsi_lun_cfg.direct,
+                // Access to an image shared through persistent reservations
+                // is arbitrated by the guests.
+                lock: scsi_lun_cfg.lock && scsi_lun_cfg.reservations.is_none(),
+                ..Default::default()
+            })?)
+        } else {
+            None
+        };
+        let reservations = if let Some(path) = &scsi_lun_cfg.reservations {
+            PersistentReservations::open(path, initiator)
+                .map_err(DeviceManagerError::OpenScsiReservations)?
+        } else {
+            PersistentReservations::new(initiator)
+        };
+
+        // The id is stable across reboots and migrations, which makes it a
+        // suitable serial number for the guest to identify the unit.
+        let scsi_lun = ScsiLun::new(
+            kind,
+            disk,
+            scsi_lun_cfg.readonly,
+            scsi_lun_cfg.discard,
+            id.clone(),
+            reservations,
+        )
+        .map_err(DeviceManagerError::CreateScsiLun)?;
+
+        Ok((id, Arc::new(scsi_lun)))
+    }
+
+    fn make_virtio_scsi_device(
+        &mut self,
+        scsi_cfg: &mut ScsiConfig,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &scsi_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(SCSI_DEVICE_NAME_PREFIX)?;
+            scsi_cfg.id = Some(id.clone());
+            id
+        };
+
+        // Persistent reservations are held on behalf of the initiator, which
+        // must not change across reboots for them to be kept.
+        if scsi_cfg.initiator.is_none() {
+            let mut initiator = [0u8; 8];
+            File::open("/dev/urandom")
+                .and_then(|mut f| f.read_exact(&mut initiator))
+                .map_err(DeviceManagerError::ScsiInitiator)?;
+            scsi_cfg.initiator = Some(u64::from_le_bytes(initiator));
+        }
+
+        info!("Creating virtio-scsi device: {:?}", scsi_cfg);
+
+        let virtio_scsi_device = Arc::new(Mutex::new(
+            virtio_devices::Scsi::new(
+                id.clone(),
+                scsi_cfg.num_queues,
+                scsi_cfg.queue_size,
+                self.force_iommu | scsi_cfg.iommu,
+                self.seccomp_action.clone(),
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .map_err(DeviceManagerError::CreateVirtioScsi)?,
+        ));
+
+        self.scsi_devices
+            .push((id.clone(), Arc::clone(&virtio_scsi_device)));
+
+        // Fill the device tree with a new node. In case of restore, we
+        // know there is nothing to do, so we can simply override the
+        // existing entry.
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, virtio_scsi_device));
+
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_scsi_device)
+                as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+            iommu: scsi_cfg.iommu,
+            id,
+            pci_segment: scsi_cfg.pci_segment,
+            dma_handler: None,
+        })
+    }
+
+    fn make_virtio_scsi_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices = Vec::new();
+
+        let mut scsi_devices = self.config.lock().unwrap().scsi.clone();
+        if let Some(scsi_list_cfg) = &mut scsi_devices {
+            for scsi_cfg in scsi_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_scsi_device(scsi_cfg)?);
+            }
+        }
+        self.config.lock().unwrap().scsi = scsi_devices;
+
+        let mut scsi_luns = self.config.lock().unwrap().scsi_luns.clone();
+        if let Some(scsi_lun_list_cfg) = &mut scsi_luns {
+            for scsi_lun_cfg in scsi_lun_list_cfg.iter_mut() {
+                self.add_scsi_lun(scsi_lun_cfg)?;
+            }
+        }
+        self.config.lock().unwrap().scsi_luns = scsi_luns;
+
+        Ok(devices)
+    }
+
     fn next_device_name(&mut self, prefix: &str) -> DeviceManagerResult<String> {
         let start_id = self.device_id_cnt;
         loop {
@@ -3737,6 +4305,14 @@ impl DeviceManager {
         for child in pci_device_node.children.iter() {
             device_tree.remove(child);
         }
+        self.internal_snapshot_disks
+            .retain(|(disk_id, _)| disk_id != &id);
+        self.block_devices.retain(|(disk_id, _)| disk_id != &id);
+        self.disk_locks.lock().unwrap().remove(&id);
+        self.vhost_user_block_devices
+            .retain(|(disk_id, _)| disk_id != &id);
+        self.net_devices.retain(|(net_id, _)| net_id != &id);
+        self.vhost_user_net_devices.retain(|net_id| net_id != &id);
 
         let mut iommu_attached = false;
         if let Some((_, iommu_attached_devices)) = &self.iommu_attached_devices {
@@ -3971,6 +4547,10 @@ impl DeviceManager {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
+        if net_cfg.server {
+            return Err(DeviceManagerError::InvalidL2SocketServerHotplug);
+        }
+
         let device = self.make_virtio_net_device(net_cfg)?;
         self.hotplug_virtio_pci_device(device)
     }
@@ -3997,6 +4577,70 @@ impl DeviceManager {
         self.hotplug_virtio_pci_device(device)
     }
 
+    /// Adds a logical unit to the virtio-scsi device it refers to, the guest
+    /// being notified if the device is already running.
+    pub fn add_scsi_lun(&mut self, scsi_lun_cfg: &mut ScsiLunConfig) -> DeviceManagerResult<()> {
+        let controller = scsi_lun_cfg.controller.clone();
+        let scsi = self
+            .scsi_devices
+            .iter()
+            .find(|(id, _)| id == &controller)
+            .map(|(_, scsi)| Arc::clone(scsi))
+            .ok_or_else(|| DeviceManagerError::UnknownScsiController(controller.clone()))?;
+        // The initiator has been written back to the configuration when the
+        // controller was created.
+        let initiator = self
+            .config
+            .lock()
+            .unwrap()
+            .scsi
+            .iter()
+            .flatten()
+            .find(|scsi_cfg| scsi_cfg.id.as_ref() == Some(&controller))
+            .and_then(|scsi_cfg| scsi_cfg.initiator)
+            .ok_or_else(|| DeviceManagerError::UnknownScsiController(controller.clone()))?;
+
+        let (id, scsi_lun) = self.make_scsi_lun(scsi_lun_cfg, initiator)?;
+        scsi.lock()
+            .unwrap()
+            .add_lun(id.clone(), scsi_lun_cfg.target, scsi_lun_cfg.lun, scsi_lun)
+            .map_err(DeviceManagerError::AddScsiLun)?;
+
+        // Logical units are children of their controller in the device tree,
+        // which reserves their id.
+        let mut device_tree = self.device_tree.lock().unwrap();
+        let mut node = device_node!(id);
+        node.parent = Some(controller.clone());
+        device_tree.insert(id.clone(), node);
+        if let Some(node) = device_tree.get_mut(&controller) {
+            node.children.push(id);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the SCSI logical unit `id`, returning whether `id` refers to
+    /// one.
+    pub fn remove_scsi_lun(&mut self, id: &str) -> DeviceManagerResult<bool> {
+        for (controller, scsi) in self.scsi_devices.iter() {
+            if scsi
+                .lock()
+                .unwrap()
+                .remove_lun(id)
+                .map_err(DeviceManagerError::RemoveScsiLun)?
+            {
+                let mut device_tree = self.device_tree.lock().unwrap();
+                device_tree.remove(id);
+                if let Some(node) = device_tree.get_mut(controller) {
+                    node.children.retain(|child| child != id);
+                }
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
     pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
@@ -4023,6 +4667,408 @@ impl DeviceManager {
         Err(DeviceManagerError::MissingVirtioBalloon)
     }
 
+    /// Creates an internal snapshot named `name` on every disk configured
+    /// with `internal_snapshot=on`. The VM is expected to be paused. If one
+    /// of the disks fails, the snapshots already created on the other disks
+    /// are deleted so that no disk is left with a partial set.
+    pub fn snapshot_disks(&mut self, name: &str) -> DeviceManagerResult<()> {
+        for (index, (id, disk)) in self.internal_snapshot_disks.iter().enumerate() {
+            info!("Creating internal snapshot {} on disk {}", name, id);
+            if let Err(e) = disk.lock().unwrap().create_snapshot(name) {
+                for (id, disk) in self.internal_snapshot_disks[..index].iter() {
+                    info!("Deleting internal snapshot {} from disk {}", name, id);
+                    if let Err(e) = disk.lock().unwrap().delete_snapshot(name) {
+                        warn!(
+                            "Failed deleting internal snapshot {} from disk {}: {}",
+                            name, id, e
+                        );
+                    }
+                }
+                return Err(DeviceManagerError::DiskSnapshot(id.clone(), e));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resizes the disk `id` to `desired_size` bytes, or to the current size
+    /// of its image, and notifies the guest about the new capacity.
+    pub fn resize_disk(&mut self, id: &str, desired_size: Option<u64>) -> DeviceManagerResult<()> {
+        if let Some((_, disk)) = self.block_devices.iter().find(|(disk_id, _)| disk_id == id) {
+            info!("Resizing disk {}", id);
+            return disk
+                .lock()
+                .unwrap()
+                .resize(desired_size)
+                .map_err(|e| DeviceManagerError::ResizeDisk(id.to_owned(), e));
+        }
+
+        if let Some((_, disk)) = self
+            .vhost_user_block_devices
+            .iter()
+            .find(|(disk_id, _)| disk_id == id)
+        {
+            info!("Resizing vhost-user disk {}", id);
+            return disk
+                .lock()
+                .unwrap()
+                .resize(desired_size)
+                .map_err(|e| DeviceManagerError::ResizeVhostUserBlk(id.to_owned(), e));
+        }
+
+        Err(DeviceManagerError::UnknownDeviceId(id.to_owned()))
+    }
+
+    /// Replaces the token buckets of the rate limit group `id`, or of the
+    /// rate limiters of the queues of the device `id`, with the ones of
+    /// `rate_limiter_config`, disabling rate limiting when it is `None`.
+    pub fn update_rate_limiter(
+        &mut self,
+        id: &str,
+        rate_limiter_config: Option<&RateLimiterConfig>,
+    ) -> DeviceManagerResult<()> {
+        let rate_limiter_config = rate_limiter_config.copied();
+
+        if let Some(rate_limit_group) = self.rate_limit_groups.get(id) {
+            info!("Updating rate limit group {}", id);
+            rate_limiter_config
+                .unwrap_or_default()
+                .update_group(rate_limit_group);
+            return Ok(());
+        }
+
+        // The rate limiters of the device are created as limits are set, and
+        // dropped once they're removed.
+        if let Some((_, disk)) = self.block_devices.iter().find(|(disk_id, _)| disk_id == id) {
+            info!("Updating rate limiter of disk {}", id);
+            return disk
+                .lock()
+                .unwrap()
+                .update_rate_limiter(rate_limiter_config)
+                .map_err(DeviceManagerError::UpdateRateLimiter);
+        }
+        if let Some((_, net)) = self.net_devices.iter().find(|(net_id, _)| net_id == id) {
+            info!("Updating rate limiter of network device {}", id);
+            return net
+                .lock()
+                .unwrap()
+                .update_rate_limiter(rate_limiter_config)
+                .map_err(DeviceManagerError::UpdateRateLimiter);
+        }
+
+        // Devices handled by a vhost-user backend have no rate limiter.
+        Err(DeviceManagerError::NoRateLimiter(id.to_owned()))
+    }
+
+    /// Starts writing a backup of the disk `id` to `writer`, holding only the
+    /// ranges written since the previous backup if `incremental` is set. The
+    /// VM is expected to be paused while the backup starts, and can be resumed
+    /// while the disk is copied. The result of the backup is sent to the
+    /// receiver returned.
+    pub fn start_disk_backup(
+        &mut self,
+        id: &str,
+        writer: Box<dyn Write + Send>,
+        incremental: bool,
+    ) -> DeviceManagerResult<mpsc::Receiver<io::Result<()>>> {
+        let (_, disk) = self
+            .block_devices
+            .iter()
+            .find(|(disk_id, _)| disk_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        disk.lock()
+            .unwrap()
+            .start_backup(writer, incremental)
+            .map_err(|e| DeviceManagerError::BackupDisk(id.to_owned(), e))
+    }
+
+    /// Writes incremental backups of the disks configured with a dirty bitmap
+    /// to `<id>.backup` files in `dir`. The VM is expected to be paused.
+    pub fn backup_disks(&mut self, dir: &Path) -> DeviceManagerResult<()> {
+        for (id, disk) in self.block_devices.iter() {
+            let mut disk = disk.lock().unwrap();
+            if !disk.has_dirty_bitmap() {
+                continue;
+            }
+
+            let path = dir.join(format!("{}.backup", id));
+            info!("Backing up disk {} to {:?}", id, path);
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .map_err(|e| DeviceManagerError::BackupDisk(id.clone(), e))?;
+            disk.backup(Box::new(BufWriter::new(file)), true)
+                .map_err(|e| DeviceManagerError::BackupDisk(id.clone(), e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts mirroring the disk `id` to a new image created at `destination`
+    /// in the given `format`. Once the copy completes, the disk switches over
+    /// to the new image, unless `pivot` is false in which case the image is
+    /// kept in sync with the disk until the mirror is cancelled.
+    pub fn mirror_disk(
+        &mut self,
+        id: &str,
+        destination: &Path,
+        format: &str,
+        pivot: bool,
+    ) -> DeviceManagerResult<()> {
+        let disk = self
+            .block_devices
+            .iter()
+            .find(|(disk_id, _)| disk_id == id)
+            .map(|(_, disk)| disk.clone())
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+        let mut disk_cfg = self
+            .config
+            .lock()
+            .unwrap()
+            .disks
+            .iter()
+            .flatten()
+            .find(|disk_cfg| disk_cfg.id.as_deref() == Some(id))
+            .cloned()
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        // The copy would be written in clear to the new image.
+        if disk_cfg.key_file.is_some() || disk_cfg.key_fd.is_some() {
+            return Err(DeviceManagerError::MirrorEncryptedDisk(id.to_owned()));
+        }
+
+        // The new image holds the whole content of the disk, which doesn't
+        // depend on any backing file anymore.
+        let disk_size = disk.lock().unwrap().disk_size();
+        create_disk_image(destination, format, disk_size)
+            .map_err(|e| DeviceManagerError::CreateDiskMirror(id.to_owned(), e))?;
+        disk_cfg.path = Some(destination.to_path_buf());
+        disk_cfg.nbd = None;
+        disk_cfg.nbd_export = None;
+        disk_cfg.readonly = false;
+        disk_cfg.backing_file = None;
+        disk_cfg.repair = false;
+        let (image, image_lock) = match self.open_locked_disk_image(&disk_cfg) {
+            Ok(image) => image,
+            Err(e) => {
+                let _ = std::fs::remove_file(destination);
+                return Err(e);
+            }
+        };
+
+        let config = self.config.clone();
+        let disk_locks = self.disk_locks.clone();
+        let disk_id = id.to_owned();
+        let path = destination.to_path_buf();
+        let on_pivot = Box::new(move || {
+            let mut disk_locks = disk_locks.lock().unwrap();
+            match image_lock {
+                Some(image_lock) => disk_locks.insert(disk_id.clone(), image_lock),
+                None => disk_locks.remove(&disk_id),
+            };
+            drop(disk_locks);
+
+            let mut config = config.lock().unwrap();
+            if let Some(disk_cfg) = config
+                .disks
+                .iter_mut()
+                .flatten()
+                .find(|disk_cfg| disk_cfg.id.as_deref() == Some(&disk_id))
+            {
+                disk_cfg.path = Some(path);
+                disk_cfg.backing_file = None;
+                disk_cfg.nbd = None;
+                disk_cfg.nbd_export = None;
+            }
+        });
+        disk.lock()
+            .unwrap()
+            .start_mirror(image, destination.to_path_buf(), pivot, on_pivot)
+            .map_err(|e| {
+                let _ = std::fs::remove_file(destination);
+                DeviceManagerError::MirrorDisk(id.to_owned(), e)
+            })
+    }
+
+    pub fn cancel_disk_mirror(&mut self, id: &str) -> DeviceManagerResult<()> {
+        let (_, disk) = self
+            .block_devices
+            .iter()
+            .find(|(disk_id, _)| disk_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        disk.lock()
+            .unwrap()
+            .cancel_mirror()
+            .map_err(|e| DeviceManagerError::MirrorDisk(id.to_owned(), e))
+    }
+
+    /// Returns the images the disks are mirrored to and kept in sync with,
+    /// by disk id. Mirrors which aren't ready yet are reported as errors.
+    pub fn disk_mirrors(&self) -> DeviceManagerResult<HashMap<String, PathBuf>> {
+        let mut mirrors = HashMap::new();
+        for (id, disk) in self.block_devices.iter() {
+            if let Some(destination) = disk
+                .lock()
+                .unwrap()
+                .mirror_destination()
+                .map_err(|e| DeviceManagerError::MirrorDisk(id.clone(), e))?
+            {
+                mirrors.insert(id.clone(), destination);
+            }
+        }
+
+        Ok(mirrors)
+    }
+
+    pub fn flush_disk_mirrors(&self) -> DeviceManagerResult<()> {
+        for (id, disk) in self.block_devices.iter() {
+            disk.lock()
+                .unwrap()
+                .flush_mirror()
+                .map_err(|e| DeviceManagerError::MirrorDisk(id.clone(), e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the media of the disk `id` with the image at `path`, or
+    /// ejects it when `path` is `None`. The new media is read-only if
+    /// `readonly` is set, or as the previous one was when it's `None`.
+    pub fn change_disk_media(
+        &mut self,
+        id: &str,
+        path: Option<&Path>,
+        readonly: Option<bool>,
+    ) -> DeviceManagerResult<()> {
+        let disk = self
+            .block_devices
+            .iter()
+            .find(|(disk_id, _)| disk_id == id)
+            .map(|(_, disk)| disk.clone())
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+        let mut disk_cfg = self
+            .config
+            .lock()
+            .unwrap()
+            .disks
+            .iter()
+            .flatten()
+            .find(|disk_cfg| disk_cfg.id.as_deref() == Some(id))
+            .cloned()
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        let previous_lock_type = LockType::new(disk_cfg.readonly);
+        disk_cfg.change_media(path.map(Path::to_path_buf), readonly);
+
+        // The lock the device holds on its current media conflicts with the
+        // one taken on the same image inserted again, hence it's released
+        // first, and taken back if the media can't be changed.
+        let disk_locks = self.disk_locks.clone();
+        let released_lock = match (&disk_cfg.path, disk_locks.lock().unwrap().get(id)) {
+            (Some(path), Some(current_lock))
+                if disk_cfg.lock && is_same_file(path, current_lock) =>
+            {
+                image_lock::unlock(current_lock)
+                    .map_err(|e| DeviceManagerError::LockImage(path.clone(), e))?;
+                Some(current_lock.try_clone().map_err(DeviceManagerError::Disk)?)
+            }
+            _ => None,
+        };
+        let relock = |e: DeviceManagerError| {
+            if let Some(released_lock) = &released_lock {
+                if let Err(e) = image_lock::lock(released_lock, previous_lock_type) {
+                    error!("Failed locking the media of {} again: {}", id, e);
+                }
+            }
+            e
+        };
+
+        let (image, new_lock) = self.open_locked_disk_image(&disk_cfg).map_err(relock)?;
+        disk.lock()
+            .unwrap()
+            .change_media(
+                image,
+                disk_cfg.path.clone().unwrap_or_default(),
+                disk_cfg.readonly,
+            )
+            .map_err(|e| relock(DeviceManagerError::ChangeDiskMedia(id.to_owned(), e)))?;
+
+        let mut disk_locks = disk_locks.lock().unwrap();
+        match new_lock {
+            Some(new_lock) => disk_locks.insert(id.to_owned(), new_lock),
+            None => disk_locks.remove(id),
+        };
+
+        Ok(())
+    }
+
+    /// Sets the link of the virtio-net device `id` up or down.
+    pub fn set_net_link(&mut self, id: &str, up: bool) -> DeviceManagerResult<()> {
+        if self
+            .vhost_user_net_devices
+            .iter()
+            .any(|net_id| net_id == id)
+        {
+            return Err(DeviceManagerError::VhostUserNetLink(id.to_owned()));
+        }
+        let (_, net) = self
+            .net_devices
+            .iter()
+            .find(|(net_id, _)| net_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownNetDevice(id.to_owned()))?;
+
+        info!("Setting link of {} {}", id, if up { "up" } else { "down" });
+        net.lock()
+            .unwrap()
+            .set_link(up)
+            .map_err(|e| DeviceManagerError::SetNetLink(id.to_owned(), e))
+    }
+
+    /// Starts capturing the frames of the virtio-net device `id`, or stops
+    /// when `capture` is `None`.
+    pub fn capture_net(
+        &mut self,
+        id: &str,
+        capture: Option<&CaptureConfig>,
+    ) -> DeviceManagerResult<()> {
+        let (_, net) = self
+            .net_devices
+            .iter()
+            .find(|(net_id, _)| net_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownNetDevice(id.to_owned()))?;
+
+        let mut net = net.lock().unwrap();
+        if let Some(capture) = capture {
+            info!("Capturing frames of {} to {:?}", id, capture.path);
+            net.start_capture(capture)
+                .map_err(|e| DeviceManagerError::StartNetCapture(id.to_owned(), e))
+        } else {
+            info!("Stopping capture of the frames of {}", id);
+            net.stop_capture();
+            Ok(())
+        }
+    }
+
+    /// Requests the guest to announce itself on the network through each
+    /// virtio-net device, for the switches to learn its new location. The
+    /// vhost-user-net devices are left to their backend.
+    pub fn announce_network(&self) {
+        for (id, net) in self.net_devices.iter() {
+            if let Err(e) = net.lock().unwrap().announce() {
+                warn!("Failed to request announce on {}: {}", id, e);
+            }
+        }
+        for id in self.vhost_user_net_devices.iter() {
+            warn!(
+                "Can't request announce on {}, handled by a vhost-user backend",
+                id
+            );
+        }
+    }
+
     pub fn balloon_size(&self) -> u64 {
         if let Some(balloon) = &self.balloon {
             return balloon.lock().unwrap().get_actual();
@@ -4068,6 +5114,7 @@ impl DeviceManager {
         // The devices have been fully restored, we can now update the
         // restoring state of the DeviceManager.
         self.restoring = false;
+        self.migrating = false;
 
         Ok(())
     }
@@ -4132,6 +5179,43 @@ impl DeviceManager {
     }
 }
 
+// Creates an empty disk image of `size` bytes, refusing to overwrite an
+// existing file.
+fn create_disk_image(path: &Path, format: &str, size: u64) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    let result = match format {
+        "raw" => file.set_len(size),
+        "qcow2" => QcowFile::new(RawFile::new(file, false), 3, size)
+            .map(|_| ())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+        "vhdx" => vhdx::vhdx::Vhdx::create(file, size)
+            .map(|_| ())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        format => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported image format {}", format),
+        )),
+    };
+    if result.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+
+    result
+}
+
+// Whether `path` refers to the same file as `file`, such as through another
+// path or link.
+fn is_same_file(path: &Path, file: &File) -> bool {
+    match (std::fs::metadata(path), file.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
 fn numa_node_id_from_memory_zone_id(numa_nodes: &NumaNodes, memory_zone_id: &str) -> Option<u32> {
     for (numa_node_id, numa_node) in numa_nodes.iter() {
         if numa_node.memory_zones.contains(&memory_zone_id.to_owned()) {
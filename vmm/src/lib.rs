@@ -11,12 +11,14 @@ extern crate lazy_static;
 extern crate log;
 
 use crate::api::{
-    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmBackupDiskData,
+    VmCancelDiskMirrorData, VmCaptureNetData, VmChangeMediaData, VmInfo, VmMirrorDiskData,
+    VmReceiveMigrationData, VmSendMigrationData, VmSetLinkData, VmUpdateRateLimiterData,
+    VmmPingResponse,
 };
 use crate::config::{
     add_to_config, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig,
-    UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
+    ScsiLunConfig, UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
 };
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 use crate::migration::get_vm_snapshot;
@@ -26,6 +28,7 @@ use crate::vm::{Error as VmError, Vm, VmState};
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use memory_manager::MemoryManagerSnapshotData;
+use net_util::CaptureConfig;
 use pci::PciBdf;
 use seccompiler::{apply_filter, SeccompAction};
 use serde::ser::{SerializeStruct, Serializer};
@@ -170,6 +173,7 @@ pub enum EpollDispatch {
     Api = 2,
     ActivateVirtioDevices = 3,
     Debug = 4,
+    Stop = 5,
     Unknown,
 }
 
@@ -182,6 +186,7 @@ impl From<u64> for EpollDispatch {
             2 => Api,
             3 => ActivateVirtioDevices,
             4 => Debug,
+            5 => Stop,
             _ => Unknown,
         }
     }
@@ -358,6 +363,7 @@ pub struct Vmm {
     seccomp_action: SeccompAction,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     activate_evt: EventFd,
+    stop_evt: EventFd,
 }
 
 impl Vmm {
@@ -373,6 +379,7 @@ impl Vmm {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let activate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let stop_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
 
         epoll
             .add_event(&exit_evt, EpollDispatch::Exit)
@@ -386,6 +393,10 @@ impl Vmm {
             .add_event(&activate_evt, EpollDispatch::ActivateVirtioDevices)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&stop_evt, EpollDispatch::Stop)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
@@ -410,6 +421,7 @@ impl Vmm {
             seccomp_action,
             hypervisor,
             activate_evt,
+            stop_evt,
         })
     }
 
@@ -443,6 +455,7 @@ impl Vmm {
                 .activate_evt
                 .try_clone()
                 .map_err(VmError::EventFdClone)?;
+            let stop_evt = self.stop_evt.try_clone().map_err(VmError::EventFdClone)?;
 
             if let Some(ref vm_config) = self.vm_config {
                 let vm = Vm::new(
@@ -454,6 +467,7 @@ impl Vmm {
                     &self.seccomp_action,
                     self.hypervisor.clone(),
                     activate_evt,
+                    stop_evt,
                     None,
                     None,
                     None,
@@ -487,11 +501,26 @@ impl Vmm {
         }
     }
 
-    fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+    fn vm_snapshot(
+        &mut self,
+        destination_url: &str,
+        disk_snapshot_name: Option<&str>,
+        disk_backup: bool,
+    ) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
+            // Back the disks up first, so that the dirty bitmaps saved with
+            // the snapshot start from these backups.
+            if disk_backup {
+                vm.backup_disks(destination_url)?;
+            }
             vm.snapshot()
                 .map_err(VmError::Snapshot)
                 .and_then(|snapshot| {
+                    // The VM is paused, the disks are in sync with the state
+                    // which was just captured.
+                    if let Some(name) = disk_snapshot_name {
+                        vm.snapshot_disks(name)?;
+                    }
                     vm.send(&snapshot, destination_url)
                         .map_err(VmError::SnapshotSend)
                 })
@@ -536,6 +565,7 @@ impl Vmm {
             .activate_evt
             .try_clone()
             .map_err(VmError::EventFdClone)?;
+        let stop_evt = self.stop_evt.try_clone().map_err(VmError::EventFdClone)?;
 
         let vm = Vm::new_from_snapshot(
             &snapshot,
@@ -549,6 +579,7 @@ impl Vmm {
             &self.seccomp_action,
             self.hypervisor.clone(),
             activate_evt,
+            stop_evt,
         )?;
         self.vm = Some(vm);
 
@@ -596,6 +627,7 @@ impl Vmm {
             .activate_evt
             .try_clone()
             .map_err(VmError::EventFdClone)?;
+        let stop_evt = self.stop_evt.try_clone().map_err(VmError::EventFdClone)?;
 
         // The Linux kernel fires off an i8042 reset after doing the ACPI reset so there may be
         // an event sitting in the shared reset_evt. Without doing this we get very early reboots
@@ -614,6 +646,7 @@ impl Vmm {
             &self.seccomp_action,
             self.hypervisor.clone(),
             activate_evt,
+            stop_evt,
             serial_pty,
             console_pty,
             console_resize_pipe,
@@ -744,6 +777,172 @@ impl Vmm {
         }
     }
 
+    fn vm_resize_disk(
+        &mut self,
+        id: String,
+        desired_size: Option<u64>,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.resize_disk(id, desired_size) {
+                error!("Error when resizing disk: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_backup_disk(&mut self, backup_data: &VmBackupDiskData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.backup_disk(
+                &backup_data.id,
+                &backup_data.destination_url,
+                backup_data.incremental,
+            ) {
+                error!("Error when backing up disk: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_mirror_disk(&mut self, mirror_data: &VmMirrorDiskData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.mirror_disk(
+                &mirror_data.id,
+                &mirror_data.destination,
+                mirror_data.format.as_deref().unwrap_or("raw"),
+                !mirror_data.no_pivot,
+            ) {
+                error!("Error when mirroring disk: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_cancel_disk_mirror(
+        &mut self,
+        cancel_data: &VmCancelDiskMirrorData,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.cancel_disk_mirror(&cancel_data.id) {
+                error!("Error when cancelling disk mirror: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_change_media(&mut self, change_data: &VmChangeMediaData) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.change_disk_media(
+                &change_data.id,
+                change_data.path.as_deref(),
+                change_data.readonly,
+            ) {
+                error!("Error when changing disk media: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            // Update VmConfig so that the disk boots with the new media.
+            self.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .change_disk_media(
+                    &change_data.id,
+                    change_data.path.clone(),
+                    change_data.readonly,
+                )
+                .map_err(VmError::ConfigValidation)
+        }
+    }
+
+    fn vm_set_link(&mut self, link_data: &VmSetLinkData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.set_net_link(&link_data.id, link_data.up) {
+                error!("Error when setting network link: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_capture_net(&mut self, capture_data: &VmCaptureNetData) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        let capture = capture_data.path.as_ref().map(|path| CaptureConfig {
+            path: path.clone(),
+            snaplen: capture_data.snaplen,
+            file_size: capture_data.file_size,
+            files: capture_data.files,
+        });
+
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.capture_net(&capture_data.id, capture) {
+                error!("Error when capturing network frames: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            // Update VmConfig so that the capture starts with the device.
+            self.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .capture_net(&capture_data.id, capture)
+                .map_err(VmError::ConfigValidation)
+        }
+    }
+
+    fn vm_update_rate_limiter(
+        &mut self,
+        update_data: &VmUpdateRateLimiterData,
+    ) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.update_rate_limiter(&update_data.id, update_data.rate_limiter_config)
+            {
+                error!("Error when updating rate limiter: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            // Update VmConfig so that the new limits apply when booting.
+            self.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .update_rate_limiter(&update_data.id, update_data.rate_limiter_config)
+                .map_err(VmError::ConfigValidation)
+        }
+    }
+
     fn vm_add_device(
         &mut self,
         device_cfg: DeviceConfig,
@@ -945,6 +1144,33 @@ impl Vmm {
         }
     }
 
+    fn vm_add_scsi_lun(
+        &mut self,
+        scsi_lun_cfg: ScsiLunConfig,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+            add_to_config(&mut config.scsi_luns, scsi_lun_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            vm.add_scsi_lun(scsi_lun_cfg).map_err(|e| {
+                error!("Error when adding new SCSI logical unit to the VM: {:?}", e);
+                e
+            })?;
+        } else {
+            // Update VmConfig by adding the new logical unit.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            add_to_config(&mut config.scsi_luns, scsi_lun_cfg);
+        }
+
+        Ok(None)
+    }
+
     fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> result::Result<Option<Vec<u8>>, VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
@@ -1038,6 +1264,9 @@ impl Vmm {
         let activate_evt = self.activate_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning activate EventFd: {}", e))
         })?;
+        let stop_evt = self.stop_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning stop EventFd: {}", e))
+        })?;
 
         self.vm_config = Some(vm_migration_config.vm_config);
         let vm = Vm::new_from_migration(
@@ -1049,6 +1278,7 @@ impl Vmm {
             &self.seccomp_action,
             self.hypervisor.clone(),
             activate_evt,
+            stop_evt,
             &vm_migration_config.memory_manager_data,
             existing_memory_files,
         )
@@ -1230,7 +1460,17 @@ impl Vmm {
                     info!("Complete Command Received");
                     if let Some(ref mut vm) = self.vm.as_mut() {
                         vm.resume()?;
+                        // Let the switches learn the new location of the
+                        // guest, rather than waiting for it to send traffic.
+                        vm.announce_network();
                         Response::ok().write_to(&mut socket)?;
+                        // The source VM releases its files as it shuts down.
+                        vm.lock_migrated_files().map_err(|e| {
+                            MigratableError::MigrateReceive(anyhow!(
+                                "Error locking migrated files: {:?}",
+                                e
+                            ))
+                        })?;
                     } else {
                         warn!("VM not created yet");
                         Response::error().write_to(&mut socket)?;
@@ -1307,8 +1547,25 @@ impl Vmm {
             )));
         }
 
-        // Send config
-        let vm_config = vm.get_config();
+        // Send config. The disks mirrored to images kept in sync are handed
+        // over to the destination as these images, so that the source and
+        // destination don't need to share the storage.
+        let mut vm_config = vm.get_config();
+        let disk_mirrors = vm.disk_mirrors().map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error getting the disk mirrors: {:?}", e))
+        })?;
+        if !disk_mirrors.is_empty() {
+            let mut config = vm_config.lock().unwrap().clone();
+            for disk in config.disks.iter_mut().flatten() {
+                if let Some(path) = disk.id.as_ref().and_then(|id| disk_mirrors.get(id)) {
+                    disk.path = Some(path.clone());
+                    disk.backing_file = None;
+                    disk.nbd = None;
+                    disk.nbd_export = None;
+                }
+            }
+            vm_config = Arc::new(Mutex::new(config));
+        }
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let common_cpuid = {
             #[cfg(feature = "tdx")]
@@ -1399,6 +1656,13 @@ impl Vmm {
             // Stop logging dirty pages
             vm.stop_dirty_log()?;
         }
+
+        // The last guest writes must reach the disk mirrors before the
+        // destination takes over.
+        vm.flush_disk_mirrors().map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error flushing the disk mirrors: {:?}", e))
+        })?;
+
         // Capture snapshot and send it
         let vm_snapshot = vm.snapshot()?;
         let snapshot_data = serde_json::to_vec(&vm_snapshot).unwrap();
@@ -1588,6 +1852,26 @@ impl Vmm {
                                 .map_err(Error::ActivateVirtioDevices)?;
                         }
                     }
+                    EpollDispatch::Stop => {
+                        info!("VM stop event");
+                        // Consume the event.
+                        self.stop_evt.read().map_err(Error::EventFdRead)?;
+                        // The VM may have been paused meanwhile, by the
+                        // operator or another device.
+                        if let Some(ref mut vm) = self.vm {
+                            match vm.get_state() {
+                                Ok(VmState::Running) => {
+                                    if let Err(e) = vm.pause() {
+                                        error!("Failed to pause the VM on I/O error: {:?}", e);
+                                    }
+                                }
+                                Ok(_) => {}
+                                Err(e) => {
+                                    error!("Failed to get the VM state on I/O error: {:?}", e);
+                                }
+                            }
+                        }
+                    }
                     EpollDispatch::Api => {
                         // Consume the event.
                         self.api_evt.read().map_err(Error::EventFdRead)?;
@@ -1668,7 +1952,11 @@ impl Vmm {
                             }
                             ApiRequest::VmSnapshot(snapshot_data, sender) => {
                                 let response = self
-                                    .vm_snapshot(&snapshot_data.destination_url)
+                                    .vm_snapshot(
+                                        &snapshot_data.destination_url,
+                                        snapshot_data.disk_snapshot_name.as_deref(),
+                                        snapshot_data.disk_backup,
+                                    )
                                     .map_err(ApiError::VmSnapshot)
                                     .map(|_| ApiResponsePayload::Empty);
 
@@ -1713,6 +2001,65 @@ impl Vmm {
                                     .map(|_| ApiResponsePayload::Empty);
                                 sender.send(response).map_err(Error::ApiResponseSend)?;
                             }
+                            ApiRequest::VmResizeDisk(resize_disk_data, sender) => {
+                                let response = self
+                                    .vm_resize_disk(
+                                        resize_disk_data.id.clone(),
+                                        resize_disk_data.desired_size,
+                                    )
+                                    .map_err(ApiError::VmResizeDisk)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmBackupDisk(backup_disk_data, sender) => {
+                                let response = self
+                                    .vm_backup_disk(backup_disk_data.as_ref())
+                                    .map_err(ApiError::VmBackupDisk)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmMirrorDisk(mirror_disk_data, sender) => {
+                                let response = self
+                                    .vm_mirror_disk(mirror_disk_data.as_ref())
+                                    .map_err(ApiError::VmMirrorDisk)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmCancelDiskMirror(cancel_data, sender) => {
+                                let response = self
+                                    .vm_cancel_disk_mirror(cancel_data.as_ref())
+                                    .map_err(ApiError::VmCancelDiskMirror)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmChangeMedia(change_data, sender) => {
+                                let response = self
+                                    .vm_change_media(change_data.as_ref())
+                                    .map_err(ApiError::VmChangeMedia)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmSetLink(link_data, sender) => {
+                                let response = self
+                                    .vm_set_link(link_data.as_ref())
+                                    .map_err(ApiError::VmSetLink)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmCaptureNet(capture_data, sender) => {
+                                let response = self
+                                    .vm_capture_net(capture_data.as_ref())
+                                    .map_err(ApiError::VmCaptureNet)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmUpdateRateLimiter(update_data, sender) => {
+                                let response = self
+                                    .vm_update_rate_limiter(update_data.as_ref())
+                                    .map_err(ApiError::VmUpdateRateLimiter)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
                             ApiRequest::VmAddDevice(add_device_data, sender) => {
                                 let response = self
                                     .vm_add_device(add_device_data.as_ref().clone())
@@ -1769,6 +2116,13 @@ impl Vmm {
                                     .map(ApiResponsePayload::VmAction);
                                 sender.send(response).map_err(Error::ApiResponseSend)?;
                             }
+                            ApiRequest::VmAddScsiLun(add_scsi_lun_data, sender) => {
+                                let response = self
+                                    .vm_add_scsi_lun(add_scsi_lun_data.as_ref().clone())
+                                    .map_err(ApiError::VmAddScsiLun)
+                                    .map(ApiResponsePayload::VmAction);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
                             ApiRequest::VmAddVsock(add_vsock_data, sender) => {
                                 let response = self
                                     .vm_add_vsock(add_vsock_data.as_ref().clone())
@@ -1846,7 +2200,7 @@ mod unit_tests {
     use super::*;
     use config::{
         CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, HotplugMethod, KernelConfig,
-        MemoryConfig, RngConfig, VmConfig,
+        MemoryConfig, RngConfig, ScsiConfig, VmConfig,
     };
 
     fn create_dummy_vmm() -> Vmm {
@@ -1894,6 +2248,7 @@ mod unit_tests {
             cmdline: CmdlineConfig {
                 args: String::from(""),
             },
+            rate_limit_groups: None,
             disks: None,
             net: None,
             rng: RngConfig {
@@ -1916,6 +2271,8 @@ mod unit_tests {
             devices: None,
             user_devices: None,
             vdpa: None,
+            scsi: None,
+            scsi_luns: None,
             vsock: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
@@ -2275,6 +2632,201 @@ mod unit_tests {
         );
     }
 
+    #[test]
+    fn test_vmm_vm_cold_add_scsi_lun() {
+        let mut vmm = create_dummy_vmm();
+        let scsi_lun_config =
+            ScsiLunConfig::parse("controller=scsi0,lun=1,path=/path/to_file").unwrap();
+
+        assert!(matches!(
+            vmm.vm_add_scsi_lun(scsi_lun_config.clone()),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(matches!(
+            vmm.vm_add_scsi_lun(scsi_lun_config.clone()),
+            Err(VmError::ConfigValidation(_))
+        ));
+
+        vmm.vm_config.as_ref().unwrap().lock().unwrap().scsi =
+            Some(vec![ScsiConfig::parse("id=scsi0").unwrap()]);
+        let result = vmm.vm_add_scsi_lun(scsi_lun_config.clone());
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+        assert_eq!(
+            vmm.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .scsi_luns
+                .clone()
+                .unwrap(),
+            vec![scsi_lun_config]
+        );
+    }
+
+    #[test]
+    fn test_vmm_vm_cold_update_rate_limiter() {
+        let mut vmm = create_dummy_vmm();
+        let rate_limiter_config =
+            DiskConfig::parse("path=/path/to_file,bw_size=1000,bw_refill_time=100")
+                .unwrap()
+                .rate_limiter_config;
+        let update_data = VmUpdateRateLimiterData {
+            id: "disk0".to_owned(),
+            rate_limiter_config,
+        };
+
+        assert!(matches!(
+            vmm.vm_update_rate_limiter(&update_data),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(matches!(
+            vmm.vm_update_rate_limiter(&update_data),
+            Err(VmError::ConfigValidation(_))
+        ));
+
+        vmm.vm_config.as_ref().unwrap().lock().unwrap().disks =
+            Some(vec![
+                DiskConfig::parse("path=/path/to_file,id=disk0").unwrap()
+            ]);
+        assert!(vmm.vm_update_rate_limiter(&update_data).is_ok());
+        assert_eq!(
+            vmm.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .disks
+                .clone()
+                .unwrap()[0]
+                .rate_limiter_config,
+            rate_limiter_config
+        );
+
+        // The limit can be removed and set again.
+        let disk_rate_limiter_config = |vmm: &Vmm| {
+            vmm.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .disks
+                .as_ref()
+                .unwrap()[0]
+                .rate_limiter_config
+        };
+        let no_limit = VmUpdateRateLimiterData {
+            id: "disk0".to_owned(),
+            rate_limiter_config: None,
+        };
+        for _ in 0..2 {
+            assert!(vmm.vm_update_rate_limiter(&no_limit).is_ok());
+            assert_eq!(disk_rate_limiter_config(&vmm), None);
+            assert!(vmm.vm_update_rate_limiter(&update_data).is_ok());
+            assert_eq!(disk_rate_limiter_config(&vmm), rate_limiter_config);
+        }
+    }
+
+    #[test]
+    fn test_vmm_vm_cold_change_media() {
+        let mut vmm = create_dummy_vmm();
+        let change_data = VmChangeMediaData {
+            id: "disk0".to_owned(),
+            path: None,
+            readonly: Some(true),
+        };
+
+        assert!(matches!(
+            vmm.vm_change_media(&change_data),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(matches!(
+            vmm.vm_change_media(&change_data),
+            Err(VmError::ConfigValidation(_))
+        ));
+
+        vmm.vm_config.as_ref().unwrap().lock().unwrap().disks =
+            Some(vec![
+                DiskConfig::parse("path=/path/to_file,id=disk0").unwrap()
+            ]);
+        assert!(vmm.vm_change_media(&change_data).is_ok());
+        let disk_cfg = vmm
+            .vm_config
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .disks
+            .clone()
+            .unwrap()[0]
+            .clone();
+        assert_eq!(disk_cfg.path, None);
+        assert!(disk_cfg.readonly);
+    }
+
+    #[test]
+    fn test_vmm_vm_cold_capture_net() {
+        let mut vmm = create_dummy_vmm();
+        let capture_data = VmCaptureNetData {
+            id: "net0".to_owned(),
+            path: Some(PathBuf::from("/tmp/net0.pcapng")),
+            snaplen: Some(128),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            vmm.vm_capture_net(&capture_data),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(matches!(
+            vmm.vm_capture_net(&capture_data),
+            Err(VmError::ConfigValidation(_))
+        ));
+
+        vmm.vm_config.as_ref().unwrap().lock().unwrap().net =
+            Some(vec![NetConfig::parse("id=net0").unwrap()]);
+        assert!(vmm.vm_capture_net(&capture_data).is_ok());
+        let net_cfg = vmm
+            .vm_config
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .net
+            .clone()
+            .unwrap()[0]
+            .clone();
+        assert_eq!(net_cfg.capture, Some(PathBuf::from("/tmp/net0.pcapng")));
+        assert_eq!(net_cfg.capture_snaplen, Some(128));
+
+        let stop_data = VmCaptureNetData {
+            id: "net0".to_owned(),
+            ..Default::default()
+        };
+        assert!(vmm.vm_capture_net(&stop_data).is_ok());
+        let net_cfg = vmm
+            .vm_config
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .net
+            .clone()
+            .unwrap()[0]
+            .clone();
+        assert_eq!(net_cfg.capture, None);
+        assert_eq!(net_cfg.capture_snaplen, None);
+    }
+
     #[test]
     fn test_vmm_vm_cold_add_vsock() {
         let mut vmm = create_dummy_vmm();
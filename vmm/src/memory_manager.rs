@@ -13,6 +13,7 @@ use anyhow::anyhow;
 #[cfg(target_arch = "x86_64")]
 use arch::x86_64::{SgxEpcRegion, SgxEpcSection};
 use arch::{layout, RegionType};
+use block_util::image_lock::{self, LockError, LockType, PendingLock};
 #[cfg(target_arch = "x86_64")]
 use devices::ioapic;
 #[cfg(target_arch = "x86_64")]
@@ -178,6 +179,10 @@ pub struct MemoryManager {
     // slots that the mapping is created in.
     guest_ram_mappings: Vec<GuestRamMapping>,
 
+    // Locks of the memory files still used by the source VM of the
+    // migration.
+    pending_locks: Vec<PendingLock>,
+
     pub acpi_address: Option<GuestAddress>,
 }
 
@@ -297,6 +302,9 @@ pub enum Error {
     /// Resizing the memory zone failed.
     ResizeZone,
 
+    /// Failed to lock the backing file of a memory zone.
+    LockMemoryFile(PathBuf, LockError),
+
     /// Guest address overflow
     GuestAddressOverFlow,
 
@@ -481,6 +489,9 @@ impl MemoryManager {
                     zone.host_numa_node,
                     None,
                 )?;
+                if zone.lock {
+                    MemoryManager::lock_ram_region(&region, &zone.file, zone.shared, None)?;
+                }
 
                 // Add region to the list of regions associated with the
                 // current memory zone.
@@ -526,12 +537,14 @@ impl MemoryManager {
         Ok((mem_regions, memory_zones))
     }
 
-    // Restore both GuestMemory regions along with MemoryZone zones.
+    // Restore both GuestMemory regions along with MemoryZone zones. The locks
+    // of the files still in use are added to `pending_locks`.
     fn restore_memory_regions_and_zones(
         guest_ram_mappings: &[GuestRamMapping],
         zones_config: &[MemoryZoneConfig],
         prefault: Option<bool>,
         mut existing_memory_files: HashMap<u32, File>,
+        pending_locks: &mut Vec<PendingLock>,
     ) -> Result<(Vec<Arc<GuestRegionMmap>>, MemoryZones), Error> {
         let mut memory_regions = Vec::new();
         let mut memory_zones = HashMap::new();
@@ -558,6 +571,14 @@ impl MemoryManager {
                         zone_config.host_numa_node,
                         existing_memory_files.remove(&guest_ram_mapping.slot),
                     )?;
+                    if zone_config.lock {
+                        MemoryManager::lock_ram_region(
+                            &region,
+                            &zone_config.file,
+                            zone_config.shared,
+                            Some(&mut *pending_locks),
+                        )?;
+                    }
                     memory_regions.push(Arc::clone(&region));
                     if let Some(memory_zone) = memory_zones.get_mut(&guest_ram_mapping.zone_id) {
                         if guest_ram_mapping.virtio_mem {
@@ -684,6 +705,7 @@ impl MemoryManager {
                 hotplug_size: config.hotplug_size,
                 hotplugged_size: config.hotplugged_size,
                 prefault: config.prefault,
+                lock: true,
             }];
 
             Ok((config.size, zones, allow_mem_hotplug))
@@ -831,6 +853,7 @@ impl MemoryManager {
         #[cfg(target_arch = "x86_64")] sgx_epc_config: Option<Vec<SgxEpcConfig>>,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
         let user_provided_zones = config.size == 0;
+        let mut pending_locks = Vec::new();
 
         let mmio_address_space_size = mmio_address_space_size(phys_bits);
         debug_assert_eq!(
@@ -862,6 +885,7 @@ impl MemoryManager {
                 &zones,
                 prefault,
                 existing_memory_files.unwrap_or_default(),
+                &mut pending_locks,
             )?;
             let guest_memory =
                 GuestMemoryMmap::from_arc_regions(regions).map_err(Error::GuestMemory)?;
@@ -1070,6 +1094,7 @@ impl MemoryManager {
             snapshot_memory_ranges: MemoryRangeTable::default(),
             memory_zones,
             guest_ram_mappings: Vec::new(),
+            pending_locks,
             acpi_address,
             log_dirty: dynamic, // Cannot log dirty pages on a TD
             arch_mem_regions,
@@ -1115,6 +1140,14 @@ impl MemoryManager {
                 None,
             )?;
 
+            // Only the source VM of a migration may still use the files.
+            if let Some(pending_lock) = mm.lock().unwrap().pending_locks.first() {
+                return Err(Error::LockMemoryFile(
+                    pending_lock.path().to_owned(),
+                    LockError::AlreadyLocked,
+                ));
+            }
+
             mm.lock()
                 .unwrap()
                 .fill_saved_regions(memory_file_path, mem_snapshot.memory_ranges)?;
@@ -1234,6 +1267,52 @@ impl MemoryManager {
         Ok((f, f_off))
     }
 
+    // Locks the range of the backing file mapped by `region`, as a zone
+    // backed by a file can be split in several regions. When restoring, the
+    // file may still be locked by the source VM of a migration, in which
+    // case the lock is added to `pending_locks` to be taken later on.
+    fn lock_ram_region(
+        region: &GuestRegionMmap,
+        backing_file: &Option<PathBuf>,
+        shared: bool,
+        pending_locks: Option<&mut Vec<PendingLock>>,
+    ) -> Result<(), Error> {
+        let (path, file_offset) = match (backing_file, region.file_offset()) {
+            (Some(path), Some(file_offset)) => (path, file_offset),
+            _ => return Ok(()),
+        };
+
+        // The guest only writes to the file through a shared mapping.
+        let lock_type = LockType::new(!shared);
+        match image_lock::lock_range(
+            file_offset.file(),
+            lock_type,
+            file_offset.start(),
+            region.len(),
+        ) {
+            Err(LockError::AlreadyLocked) if pending_locks.is_some() => {
+                warn!("Memory file {:?} is still in use, locking it later", path);
+                let pending_lock = PendingLock::new(
+                    file_offset.file(),
+                    path,
+                    lock_type,
+                    file_offset.start(),
+                    region.len(),
+                )
+                .map_err(|e| Error::LockMemoryFile(path.clone(), LockError::Lock(e)))?;
+                pending_locks.unwrap().push(pending_lock);
+                Ok(())
+            }
+            result => result.map_err(|e| Error::LockMemoryFile(path.clone(), e)),
+        }
+    }
+
+    /// Returns the locks of the memory files still used by the source VM
+    /// when the migration was received.
+    pub fn take_pending_locks(&mut self) -> Vec<PendingLock> {
+        std::mem::take(&mut self.pending_locks)
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn create_ram_region(
         backing_file: &Option<PathBuf>,
@@ -14,7 +14,7 @@
 use crate::config::NumaConfig;
 use crate::config::{
     add_to_config, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig,
-    UserDeviceConfig, ValidationError, VdpaConfig, VmConfig, VsockConfig,
+    ScsiLunConfig, UserDeviceConfig, ValidationError, VdpaConfig, VmConfig, VsockConfig,
 };
 use crate::cpu;
 use crate::device_manager::{Console, DeviceManager, DeviceManagerError, PtyPair};
@@ -44,6 +44,7 @@ use arch::EntryPoint;
 #[cfg(target_arch = "aarch64")]
 use arch::PciSpaceInfo;
 use arch::{NumaNode, NumaNodes};
+use block_util::image_lock::LockError;
 #[cfg(target_arch = "aarch64")]
 use devices::interrupt_controller::{self, InterruptController};
 use devices::AcpiNotificationFlags;
@@ -56,6 +57,7 @@ use linux_loader::loader::elf::PvhBootCapability::PvhEntryPresent;
 #[cfg(target_arch = "aarch64")]
 use linux_loader::loader::pe::Error::InvalidImageMagicNumber;
 use linux_loader::loader::KernelLoader;
+use net_util::CaptureConfig;
 use seccompiler::{apply_filter, SeccompAction};
 use serde::{Deserialize, Serialize};
 use signal_hook::{
@@ -76,10 +78,12 @@ use std::num::Wrapping;
 use std::ops::Deref;
 use std::os::unix::net::UnixStream;
 use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{result, str, thread};
 use thiserror::Error;
+use virtio_devices::RateLimiterConfig;
 use vm_device::Bus;
 #[cfg(target_arch = "x86_64")]
 use vm_device::BusDevice;
@@ -202,6 +206,24 @@ pub enum Error {
     #[error("Cannot send VM snapshot: {0}")]
     SnapshotSend(#[source] MigratableError),
 
+    #[error("Cannot create internal disk snapshot: {0:?}")]
+    DiskSnapshot(DeviceManagerError),
+
+    #[error("Invalid disk backup destination URL: {0}")]
+    InvalidBackupDestinationUrl(String),
+
+    #[error("Cannot open disk backup destination: {0}")]
+    BackupDestination(#[source] io::Error),
+
+    #[error("Cannot back up disk: {0:?}")]
+    BackupDisk(DeviceManagerError),
+
+    #[error("Disks can only be backed up along a snapshot while the VM is paused")]
+    BackupDisksNotPaused,
+
+    #[error("Cannot mirror disk: {0:?}")]
+    MirrorDisk(DeviceManagerError),
+
     #[error("Invalid restore source URL")]
     InvalidRestoreSourceUrl,
 
@@ -226,6 +248,21 @@ pub enum Error {
     #[error("Failed resizing a memory zone")]
     ResizeZone,
 
+    #[error("Cannot resize disk: {0:?}")]
+    ResizeDisk(DeviceManagerError),
+
+    #[error("Cannot update rate limiter: {0:?}")]
+    UpdateRateLimiter(DeviceManagerError),
+
+    #[error("Cannot change disk media: {0:?}")]
+    ChangeDiskMedia(DeviceManagerError),
+
+    #[error("Cannot set network link: {0:?}")]
+    SetNetLink(DeviceManagerError),
+
+    #[error("Cannot capture network frames: {0:?}")]
+    CaptureNet(DeviceManagerError),
+
     #[error("Cannot activate virtio devices: {0:?}")]
     ActivateVirtioDevices(DeviceManagerError),
 
@@ -294,6 +331,9 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     #[error("Error joining kernel loading thread")]
     KernelLoadThreadJoin(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
+
+    #[error("Error spawning migration locking thread")]
+    MigrationLockThreadSpawn(#[source] std::io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -445,6 +485,12 @@ pub fn physical_bits(max_phys_bits: u8) -> u8 {
 
 pub const HANDLED_SIGNALS: [i32; 3] = [SIGWINCH, SIGTERM, SIGINT];
 
+// Delay between attempts at locking the files still used by the source VM
+// of a migration, which releases them as it shuts down.
+const MIGRATION_LOCK_RETRY_DELAY: Duration = Duration::from_millis(100);
+// Number of attempts at locking the files still used by the source VM.
+const MIGRATION_LOCK_ATTEMPTS: u32 = 100;
+
 pub struct Vm {
     #[cfg(any(target_arch = "aarch64", feature = "tdx"))]
     kernel: Option<File>,
@@ -484,7 +530,9 @@ impl Vm {
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        stop_evt: EventFd,
         restoring: bool,
+        migrating: bool,
         timestamp: Instant,
     ) -> Result<Self> {
         let kernel = config
@@ -534,8 +582,10 @@ impl Vm {
             seccomp_action.clone(),
             numa_nodes.clone(),
             &activate_evt,
+            &stop_evt,
             force_iommu,
             restoring,
+            migrating,
             boot_id_list,
             timestamp,
         )
@@ -706,6 +756,7 @@ impl Vm {
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        stop_evt: EventFd,
         serial_pty: Option<PtyPair>,
         console_pty: Option<PtyPair>,
         console_resize_pipe: Option<File>,
@@ -764,6 +815,8 @@ impl Vm {
             seccomp_action,
             hypervisor,
             activate_evt,
+            stop_evt,
+            false,
             false,
             timestamp,
         )?;
@@ -791,6 +844,7 @@ impl Vm {
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        stop_evt: EventFd,
     ) -> Result<Self> {
         let timestamp = Instant::now();
 
@@ -841,7 +895,9 @@ impl Vm {
             seccomp_action,
             hypervisor,
             activate_evt,
+            stop_evt,
             true,
+            false,
             timestamp,
         )
     }
@@ -855,6 +911,7 @@ impl Vm {
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        stop_evt: EventFd,
         memory_manager_data: &MemoryManagerSnapshotData,
         existing_memory_files: Option<HashMap<u32, File>>,
     ) -> Result<Self> {
@@ -898,6 +955,8 @@ impl Vm {
             seccomp_action,
             hypervisor,
             activate_evt,
+            stop_evt,
+            true,
             true,
             timestamp,
         )
@@ -1411,6 +1470,161 @@ impl Vm {
         Err(Error::ResizeZone)
     }
 
+    pub fn resize_disk(&mut self, id: String, desired_size: Option<u64>) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .resize_disk(&id, desired_size)
+            .map_err(Error::ResizeDisk)?;
+
+        event!("vm", "disk-resized", "id", &id);
+
+        Ok(())
+    }
+
+    pub fn update_rate_limiter(
+        &mut self,
+        id: &str,
+        rate_limiter_config: Option<RateLimiterConfig>,
+    ) -> Result<()> {
+        // Check the update is valid before applying it to the device.
+        self.config
+            .lock()
+            .unwrap()
+            .clone()
+            .update_rate_limiter(id, rate_limiter_config)
+            .map_err(Error::ConfigValidation)?;
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .update_rate_limiter(id, rate_limiter_config.as_ref())
+            .map_err(Error::UpdateRateLimiter)?;
+
+        // Update VmConfig with the new limits. This is important to ensure
+        // they would be applied in case of a reboot.
+        self.config
+            .lock()
+            .unwrap()
+            .update_rate_limiter(id, rate_limiter_config)
+            .map_err(Error::ConfigValidation)?;
+
+        event!("vm", "rate-limiter-updated", "id", id);
+
+        Ok(())
+    }
+
+    pub fn change_disk_media(
+        &mut self,
+        id: &str,
+        path: Option<&Path>,
+        readonly: Option<bool>,
+    ) -> Result<()> {
+        let path_buf = path.map(Path::to_path_buf);
+
+        // Check the new media is valid for the disk before opening it.
+        self.config
+            .lock()
+            .unwrap()
+            .clone()
+            .change_disk_media(id, path_buf.clone(), readonly)
+            .map_err(Error::ConfigValidation)?;
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .change_disk_media(id, path, readonly)
+            .map_err(Error::ChangeDiskMedia)?;
+
+        // Update VmConfig with the new media, for vm.info to report it and
+        // for the disk to keep it across a reboot.
+        self.config
+            .lock()
+            .unwrap()
+            .change_disk_media(id, path_buf, readonly)
+            .map_err(Error::ConfigValidation)?;
+
+        event!("vm", "disk-media-changed", "id", id);
+
+        Ok(())
+    }
+
+    pub fn set_net_link(&mut self, id: &str, up: bool) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_net_link(id, up)
+            .map_err(Error::SetNetLink)
+    }
+
+    pub fn capture_net(&mut self, id: &str, capture: Option<CaptureConfig>) -> Result<()> {
+        // Check the capture is valid for the device before starting it.
+        self.config
+            .lock()
+            .unwrap()
+            .clone()
+            .capture_net(id, capture.clone())
+            .map_err(Error::ConfigValidation)?;
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .capture_net(id, capture.as_ref())
+            .map_err(Error::CaptureNet)?;
+
+        // Update VmConfig with the capture, for vm.info to report it and
+        // for the device to keep capturing across a reboot.
+        self.config
+            .lock()
+            .unwrap()
+            .capture_net(id, capture)
+            .map_err(Error::ConfigValidation)
+    }
+
+    /// Requests the guest to announce itself on the network, such as once
+    /// it has been migrated.
+    pub fn announce_network(&self) {
+        self.device_manager.lock().unwrap().announce_network();
+    }
+
+    /// Locks the files which were still used by the source VM when the
+    /// migration was received, once the source VM releases them.
+    pub fn lock_migrated_files(&self) -> Result<()> {
+        let mut pending_locks = self.device_manager.lock().unwrap().take_pending_locks();
+        pending_locks.extend(self.memory_manager.lock().unwrap().take_pending_locks());
+        if pending_locks.is_empty() {
+            return Ok(());
+        }
+
+        thread::Builder::new()
+            .name("migration_lock".to_string())
+            .spawn(move || {
+                for _ in 0..MIGRATION_LOCK_ATTEMPTS {
+                    pending_locks.retain(|pending_lock| match pending_lock.lock() {
+                        Ok(()) => false,
+                        Err(LockError::AlreadyLocked) => true,
+                        Err(e) => {
+                            error!("Failed locking {:?}: {}", pending_lock.path(), e);
+                            false
+                        }
+                    });
+                    if pending_locks.is_empty() {
+                        return;
+                    }
+                    thread::sleep(MIGRATION_LOCK_RETRY_DELAY);
+                }
+                for pending_lock in pending_locks {
+                    error!(
+                        "Failed locking {:?}, still in use after the migration",
+                        pending_lock.path()
+                    );
+                }
+            })
+            .map_err(Error::MigrationLockThreadSpawn)?;
+
+        Ok(())
+    }
+
     pub fn add_device(&mut self, mut device_cfg: DeviceConfig) -> Result<PciDeviceInfo> {
         let pci_device_info = self
             .device_manager
@@ -1460,6 +1674,22 @@ impl Vm {
     }
 
     pub fn remove_device(&mut self, id: String) -> Result<()> {
+        // SCSI logical units are removed from their controller, without any
+        // PCI hotplug involved.
+        if self
+            .device_manager
+            .lock()
+            .unwrap()
+            .remove_scsi_lun(&id)
+            .map_err(Error::DeviceManager)?
+        {
+            let mut config = self.config.lock().unwrap();
+            if let Some(scsi_luns) = config.scsi_luns.as_mut() {
+                scsi_luns.retain(|dev| dev.id.as_ref() != Some(&id));
+            }
+            return Ok(());
+        }
+
         self.device_manager
             .lock()
             .unwrap()
@@ -1640,6 +1870,21 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn add_scsi_lun(&mut self, mut scsi_lun_cfg: ScsiLunConfig) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .add_scsi_lun(&mut scsi_lun_cfg)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by adding the new logical unit. This is important
+        // to ensure the logical unit would be created in case of a reboot.
+        let mut config = self.config.lock().unwrap();
+        add_to_config(&mut config.scsi_luns, scsi_lun_cfg);
+
+        Ok(())
+    }
+
     pub fn add_vsock(&mut self, mut vsock_cfg: VsockConfig) -> Result<PciDeviceInfo> {
         let pci_device_info = self
             .device_manager
@@ -2322,6 +2567,134 @@ impl Vm {
         self.device_manager.lock().unwrap().balloon_size()
     }
 
+    /// Creates an internal snapshot named `name` on the disks which opted in.
+    pub fn snapshot_disks(&mut self, name: &str) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .snapshot_disks(name)
+            .map_err(Error::DiskSnapshot)
+    }
+
+    /// Writes a backup of the disk `id` to the file or UNIX socket designated
+    /// by `destination_url`. A running VM is only paused while the backup
+    /// starts, the disk then being copied while the guest keeps running.
+    pub fn backup_disk(
+        &mut self,
+        id: &str,
+        destination_url: &str,
+        incremental: bool,
+    ) -> Result<()> {
+        let destination: Box<dyn Write + Send> =
+            if let Some(path) = destination_url.strip_prefix("file://") {
+                Box::new(
+                    OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(path)
+                        .map_err(Error::BackupDestination)?,
+                )
+            } else if let Some(path) = destination_url.strip_prefix("unix:") {
+                Box::new(UnixStream::connect(path).map_err(Error::BackupDestination)?)
+            } else {
+                return Err(Error::InvalidBackupDestinationUrl(
+                    destination_url.to_owned(),
+                ));
+            };
+        let writer = Box::new(io::BufWriter::new(destination));
+
+        let running = self.get_state()? == VmState::Running;
+        if running {
+            self.pause().map_err(Error::Pause)?;
+        }
+        let result = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .start_disk_backup(id, writer, incremental)
+            .map_err(Error::BackupDisk);
+        if running {
+            self.resume().map_err(Error::Resume)?;
+        }
+
+        result?
+            .recv()
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Disk backup thread exited",
+                ))
+            })
+            .map_err(|e| Error::BackupDisk(DeviceManagerError::BackupDisk(id.to_owned(), e)))?;
+
+        event!("vm", "disk-backed-up", "id", id);
+
+        Ok(())
+    }
+
+    /// Writes incremental backups of the disks tracking their writes to the
+    /// snapshot directory designated by `destination_url`.
+    pub fn backup_disks(&mut self, destination_url: &str) -> Result<()> {
+        if self.get_state()? != VmState::Paused {
+            return Err(Error::BackupDisksNotPaused);
+        }
+
+        let dir = url_to_path(destination_url).map_err(Error::SnapshotSend)?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .backup_disks(&dir)
+            .map_err(Error::BackupDisk)
+    }
+
+    pub fn mirror_disk(
+        &mut self,
+        id: &str,
+        destination: &Path,
+        format: &str,
+        pivot: bool,
+    ) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .mirror_disk(id, destination, format, pivot)
+            .map_err(Error::MirrorDisk)?;
+
+        event!("vm", "disk-mirror-started", "id", id);
+
+        Ok(())
+    }
+
+    pub fn cancel_disk_mirror(&mut self, id: &str) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .cancel_disk_mirror(id)
+            .map_err(Error::MirrorDisk)?;
+
+        event!("vm", "disk-mirror-cancelled", "id", id);
+
+        Ok(())
+    }
+
+    /// Returns the images the disks are mirrored to and kept in sync with,
+    /// by disk id.
+    pub fn disk_mirrors(&self) -> Result<HashMap<String, PathBuf>> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .disk_mirrors()
+            .map_err(Error::MirrorDisk)
+    }
+
+    pub fn flush_disk_mirrors(&self) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .flush_disk_mirrors()
+            .map_err(Error::MirrorDisk)
+    }
+
     pub fn receive_memory_regions<F>(
         &mut self,
         ranges: &MemoryRangeTable,